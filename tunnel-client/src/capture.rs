@@ -0,0 +1,274 @@
+//! Recent requests kept in memory for inspection (the `requests` control command).
+//!
+//! Method, path, status, timing and headers of the last CAPTURE_HISTORY
+//! requests are kept; bodies only with CAPTURE_BODIES (or after
+//! `capture-bodies on`), cut at CAPTURE_MAX_BODY_BYTES, with the oldest
+//! requests dropped to keep their paths, headers and bodies within
+//! CAPTURE_MAX_TOTAL_BYTES (counted in the stats reports). Paths, headers and
+//! bodies are redacted first (see [`tunnel_core::redact`]), so credentials
+//! are never kept. Nothing is written to disk.
+
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tunnel_core::redact::Redactor;
+use tunnel_protocol::{decode_body, encode_body, TunnelRequest, TunnelResponse};
+
+/// Requests dropped to stay within CAPTURE_MAX_TOTAL_BYTES, across connections
+static EVICTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Requests dropped to stay within CAPTURE_MAX_TOTAL_BYTES since the client started
+pub fn evictions() -> u64 {
+    EVICTIONS.load(Ordering::Relaxed)
+}
+
+/// What is captured of each request
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CaptureOptions {
+    pub history: usize,        // Requests kept, oldest dropped first (0: capture off)
+    pub bodies: bool,          // Keep request and response bodies too
+    pub max_body_bytes: usize, // Bytes kept of each body; the rest is cut
+    pub max_total_bytes: usize, // Path, header and body bytes kept across all requests, oldest dropped first
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        Self { history: 50, bodies: false, max_body_bytes: 16 * 1024, max_total_bytes: 1024 * 1024 }
+    }
+}
+
+impl CaptureOptions {
+    /// Settings read by `from_source`
+    pub const KEYS: [&'static str; 4] = ["CAPTURE_HISTORY", "CAPTURE_BODIES", "CAPTURE_MAX_BODY_BYTES", "CAPTURE_MAX_TOTAL_BYTES"];
+
+    /// Reads the capture options from a key lookup
+    pub fn from_source(get: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let mut options = Self::default();
+
+        if let Some(value) = get("CAPTURE_HISTORY") {
+            options.history = value.trim().parse()
+                .map_err(|_| format!("Invalid CAPTURE_HISTORY: {}", value))?;
+        }
+        if let Some(value) = get("CAPTURE_BODIES") {
+            options.bodies = value.trim().parse()
+                .map_err(|_| format!("Invalid CAPTURE_BODIES: {} (expected true or false)", value))?;
+        }
+        if let Some(value) = get("CAPTURE_MAX_BODY_BYTES") {
+            options.max_body_bytes = value.trim().parse()
+                .map_err(|_| format!("Invalid CAPTURE_MAX_BODY_BYTES: {}", value))?;
+        }
+        if let Some(value) = get("CAPTURE_MAX_TOTAL_BYTES") {
+            options.max_total_bytes = value.trim().parse()
+                .map_err(|_| format!("Invalid CAPTURE_MAX_TOTAL_BYTES: {}", value))?;
+        }
+
+        Ok(options)
+    }
+
+    /// Short description for the startup log
+    pub fn summary(&self) -> String {
+        match (self.history, self.bodies) {
+            (0, _) => "off".to_string(),
+            (history, false) => format!("last {} requests, no bodies ({} bytes in all)", history, self.max_total_bytes),
+            (history, true) => format!(
+                "last {} requests, bodies up to {} bytes ({} bytes in all)", history, self.max_body_bytes, self.max_total_bytes,
+            ),
+        }
+    }
+}
+
+/// A body as captured
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CapturedBody {
+    pub size: usize,            // Length of the whole body, once redacted
+    pub truncated: bool,        // Only the first max_body_bytes are in `data`
+    pub encoding: &'static str, // "utf8", or "base64" for anything else
+    pub data: String,
+}
+
+impl CapturedBody {
+    fn new(body: &[u8], max_bytes: usize) -> Self {
+        let kept = &body[..body.len().min(max_bytes)];
+        let (encoding, data) = match std::str::from_utf8(kept) {
+            Ok(text) => ("utf8", text.to_string()),
+            Err(_) => ("base64", encode_body(kept)),
+        };
+        Self { size: body.len(), truncated: kept.len() < body.len(), encoding, data }
+    }
+}
+
+impl CapturedBody {
+    /// Placeholder for a body recorded by its size only
+    fn size_only(size: usize) -> Self {
+        Self { size, truncated: true, encoding: "utf8", data: String::new() }
+    }
+}
+
+/// One request and its response
+#[derive(Debug, Clone, Serialize)]
+pub struct Exchange {
+    pub id: u64,
+    pub started_at: u64, // Unix timestamp (milliseconds)
+    pub method: String,
+    pub path: String,    // As forwarded, with the query string
+    pub status: u16,
+    pub duration_ms: u64,
+    pub request_headers: Vec<(String, String)>,
+    pub response_headers: Vec<(String, String)>,
+    pub tags: Vec<(String, String)>,  // Set by the local service with TAG_HEADER
+    pub request_body: Option<CapturedBody>,  // None: bodies not captured
+    pub response_body: Option<CapturedBody>,
+}
+
+impl Exchange {
+    /// Bytes of the path, headers, tags and bodies it holds
+    fn held_bytes(&self) -> usize {
+        let pairs = [&self.request_headers, &self.response_headers, &self.tags].into_iter().flatten();
+        let bodies = [&self.request_body, &self.response_body].into_iter().flatten();
+        self.method.len()
+            + self.path.len()
+            + pairs.map(|(name, value)| name.len() + value.len()).sum::<usize>()
+            + bodies.map(|body| body.data.len()).sum::<usize>()
+    }
+}
+
+/// What is kept of a request until its response is recorded
+pub struct Pending {
+    started_at: SystemTime,
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Option<CapturedBody>,
+}
+
+struct Log {
+    options: CaptureOptions,
+    next_id: u64,
+    exchanges: VecDeque<Exchange>,
+    held_bytes: usize,  // By `exchanges` (see `Exchange::held_bytes`)
+}
+
+/// Shared log of recent requests
+#[derive(Clone)]
+pub struct CaptureLog {
+    log: Arc<Mutex<Log>>,
+    redactor: Arc<Redactor>,
+}
+
+impl CaptureLog {
+    /// Captures what `options` ask for, redacted by `redactor`
+    pub fn new(options: CaptureOptions, redactor: Arc<Redactor>) -> Self {
+        Self { log: Arc::new(Mutex::new(Log { options, next_id: 1, exchanges: VecDeque::new(), held_bytes: 0 })), redactor }
+    }
+
+    /// Current options
+    pub fn options(&self) -> CaptureOptions {
+        self.log.lock().unwrap().options
+    }
+
+    /// Rules applied to what is captured, for other places showing requests too (e.g. the access log)
+    pub fn redactor(&self) -> &Redactor {
+        &self.redactor
+    }
+
+    /// Starts or stops keeping bodies, for requests from now on
+    pub fn set_bodies(&self, bodies: bool) {
+        self.log.lock().unwrap().options.bodies = bodies;
+    }
+
+    /// Captures `request` as it arrives, with the body of a binary frame in `raw_body` (None: capture is off)
+    ///
+    /// A streamed body is recorded by its declared size only.
+    pub fn start(&self, request: &TunnelRequest, raw_body: Option<&[u8]>) -> Option<Pending> {
+        let options = self.options();
+        if options.history == 0 {
+            return None;
+        }
+        let headers = &request.headers;
+        let body = options.bodies.then(|| {
+            if request.stream {
+                let size = headers.iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                    .and_then(|(_, value)| value.to_str()?.trim().parse().ok())
+                    .unwrap_or(0);
+                return CapturedBody::size_only(size);
+            }
+            let body = match raw_body {
+                Some(body) => Cow::Borrowed(body),
+                None => Cow::Owned(decode_body(&request.body).unwrap_or_default()),
+            };
+            CapturedBody::new(&self.redactor.body(&body), options.max_body_bytes)
+        });
+        Some(Pending {
+            started_at: SystemTime::now(),
+            method: request.method.clone(),
+            path: self.redactor.text(&request.path).into_owned(),
+            headers: self.redactor.headers(headers),
+            body,
+        })
+    }
+
+    /// Records the request captured in `pending` with its `response` and `body`
+    ///
+    /// A response body not held, spooled to disk or streamed (`spooled_len`), is recorded by its size only.
+    pub fn finish(&self, pending: Pending, response: &TunnelResponse, body: &[u8], spooled_len: Option<u64>, duration: Duration) {
+        let response_body = pending.body.is_some().then(|| match spooled_len {
+            Some(len) => CapturedBody::size_only(len as usize),
+            None => CapturedBody::new(&self.redactor.body(body), self.options().max_body_bytes),
+        });
+        let response_headers = self.redactor.headers(&response.headers);
+        let mut log = self.log.lock().unwrap();
+        let CaptureOptions { history, max_total_bytes, .. } = log.options;
+        let mut exchange = Exchange {
+            id: log.next_id,
+            started_at: pending.started_at.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0),
+            method: pending.method,
+            path: pending.path,
+            status: response.status,
+            duration_ms: duration.as_millis() as u64,
+            request_headers: pending.headers,
+            response_headers,
+            tags: response.tags(),
+            request_body: pending.body,
+            response_body,
+        };
+        log.next_id += 1;
+        // One going over CAPTURE_MAX_TOTAL_BYTES on its own keeps its body sizes only,
+        // and is not kept at all when its headers alone do
+        if exchange.held_bytes() > max_total_bytes {
+            for body in [&mut exchange.request_body, &mut exchange.response_body].into_iter().flatten() {
+                *body = CapturedBody::size_only(body.size);
+            }
+        }
+        if exchange.held_bytes() > max_total_bytes {
+            EVICTIONS.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        log.held_bytes += exchange.held_bytes();
+        log.exchanges.push_back(exchange);
+        while log.exchanges.len() > history {
+            let dropped = log.exchanges.pop_front().expect("history is not empty");
+            log.held_bytes -= dropped.held_bytes();
+        }
+        while log.held_bytes > max_total_bytes {
+            let dropped = log.exchanges.pop_front().expect("the new exchange fits on its own");
+            log.held_bytes -= dropped.held_bytes();
+            EVICTIONS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Drops every request kept, e.g. when memory runs short (MEMORY_LIMIT_BYTES)
+    pub fn clear(&self) {
+        let mut log = self.log.lock().unwrap();
+        log.exchanges.clear();
+        log.held_bytes = 0;
+    }
+
+    /// Requests kept, newest first
+    pub fn list(&self) -> Vec<Exchange> {
+        self.log.lock().unwrap().exchanges.iter().rev().cloned().collect()
+    }
+}
@@ -0,0 +1,52 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::time::sleep;
+use tracing::{error, info};
+use tunnel_core::config::ConfigSource;
+
+use crate::local::{LocalConfig, LocalService, LocalTarget};
+
+/// How often the config file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Watches the config file and swaps in a rebuilt local service whenever it changes,
+/// without touching the tunnel connection. Invalid edits are logged and ignored.
+///
+/// Only the `LOCAL_*` settings are applied live; other changes take effect on restart.
+/// A reload also undoes a `local-port` control command.
+pub async fn watch_config_file(source: ConfigSource, local: Arc<LocalTarget>) {
+    let Some(path) = source.file_path().map(Path::to_path_buf) else {
+        return;
+    };
+    let mut last_modified = modified_time(&path);
+
+    loop {
+        sleep(POLL_INTERVAL).await;
+
+        let modified = modified_time(&path);
+        if modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+
+        let reloaded = source.reload_file()
+            .and_then(|source| LocalConfig::from_source(|key| source.get(key)))
+            .and_then(|config| LocalService::new(&config).map(|service| (config, service)));
+
+        match reloaded {
+            Ok((config, service)) => {
+                info!(
+                    "Reloaded {} - forwarding to {} ({})",
+                    path.display(), service.base_urls.join(", "), config.summary()
+                );
+                local.replace(config, service);
+            }
+            Err(e) => error!("Ignoring config file change: {}", e),
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
@@ -0,0 +1,206 @@
+//! Local control socket for adjusting a running client.
+//!
+//! `CONTROL_SOCKET` names a Unix socket that accepts one command per line and
+//! answers each with a single-line JSON object:
+//!
+//! ```text
+//! log-level                 -> {"ok":true,"level":"info","configured":"info"}
+//! log-level debug           -> {"ok":true,"level":"debug","configured":"info"}
+//! log-level reset           -> {"ok":true,"level":"info","configured":"info"}
+//! status                    -> {"ok":true,"state":"connected","ready":true,"public_url":"https://example.com",...}
+//! requests                  -> {"ok":true,"requests":[{"id":2,"method":"POST","path":"/hook","status":200,...},...]}
+//! in-flight                 -> {"ok":true,"requests":[{"id":3,"method":"PUT","path":"/upload","elapsed_ms":1250,"request_bytes":65536,...},...]}
+//! capture-bodies on         -> {"ok":true,"history":50,"bodies":true,"max_body_bytes":16384}
+//! local-port                -> {"ok":true,"targets":["http://127.0.0.1:3000"]}
+//! local-port 3001,3002      -> {"ok":true,"targets":["http://127.0.0.1:3001","http://127.0.0.1:3002"]}
+//! bogus                     -> {"ok":false,"error":"Unknown command: bogus"}
+//! ```
+//!
+//! The socket is created with mode 0600, so only the user running the client
+//! can use it; as captured requests may hold secrets, connections from other
+//! users are also refused by their credentials, should the permissions be
+//! loosened (e.g. on a volume shared between containers). `tunnel-client status`
+//! sends the `status` command (see [`query`]).
+
+use serde_json::{json, Value};
+use std::fs::{self, Permissions};
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{debug, error, warn};
+use tunnel_core::logging::LogHandle;
+
+use crate::capture::CaptureLog;
+use crate::local::LocalTarget;
+use crate::status::StatusHandle;
+
+/// What control commands can act on
+pub struct ControlContext {
+    pub log: LogHandle,
+    pub status: StatusHandle,
+    pub captures: CaptureLog,
+    pub local: Arc<LocalTarget>,
+}
+
+/// Binds the control socket, replacing a stale socket left by a previous run
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    // Never delete anything that is not a socket, e.g. a mistyped path to a real file
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "path exists and is not a socket"));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Sends one command to the client listening on `path` and returns its reply
+pub async fn query(path: &Path, command: &str) -> Result<Value, String> {
+    let stream = UnixStream::connect(path).await
+        .map_err(|e| format!("Failed to connect to control socket {}: {}", path.display(), e))?;
+    let (reader, mut writer) = stream.into_split();
+    writer.write_all(format!("{}\n", command).as_bytes()).await
+        .map_err(|e| format!("Failed to send '{}': {}", command, e))?;
+    let line = BufReader::new(reader).lines().next_line().await
+        .map_err(|e| format!("Failed to read reply to '{}': {}", command, e))?
+        .ok_or_else(|| "Control socket closed without replying".to_string())?;
+    serde_json::from_str(&line).map_err(|e| format!("Invalid reply to '{}': {}", command, e))
+}
+
+/// Accepts control connections until the listener fails
+///
+/// Only the user owning the socket is served, not even root.
+pub async fn serve(listener: UnixListener, context: Arc<ControlContext>) {
+    let owner = listener.local_addr().ok()
+        .and_then(|addr| addr.as_pathname().and_then(|path| fs::metadata(path).ok()))
+        .map(|metadata| metadata.uid());
+    let Some(owner) = owner else {
+        error!("Control socket disabled: cannot tell who owns it");
+        return;
+    };
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => match stream.peer_cred() {
+                Ok(peer) if peer.uid() == owner => {
+                    tokio::spawn(handle_connection(stream, context.clone()));
+                }
+                Ok(peer) => {
+                    warn!("Refused control connection from user {}", peer.uid());
+                    tokio::spawn(refuse(stream, owner));
+                }
+                Err(e) => debug!("Refused control connection without credentials: {}", e),
+            },
+            Err(e) => {
+                error!("Control socket stopped accepting connections: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Tells a connection from another user why it is closed
+async fn refuse(mut stream: UnixStream, owner: u32) {
+    let mut reply = json!({ "ok": false, "error": format!("Control socket is reserved for user {}", owner) }).to_string();
+    reply.push('\n');
+    let _ = stream.write_all(reply.as_bytes()).await;
+}
+
+async fn handle_connection(stream: UnixStream, context: Arc<ControlContext>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => return,
+            Err(e) => {
+                debug!("Control connection failed: {}", e);
+                return;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let mut reply = execute(&context, line.trim()).to_string();
+        reply.push('\n');
+        if writer.write_all(reply.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Runs one command and returns its reply
+pub fn execute(context: &ControlContext, command: &str) -> Value {
+    let (name, argument) = match command.split_once(char::is_whitespace) {
+        Some((name, argument)) => (name, Some(argument.trim())),
+        None => (command, None),
+    };
+    let result = match name {
+        "log-level" => log_level(&context.log, argument),
+        "status" => serde_json::to_value(context.status.snapshot()).map_err(|e| e.to_string()),
+        "requests" => Ok(json!({ "requests": context.captures.list() })),
+        "in-flight" => Ok(json!({ "requests": context.status.in_flight() })),
+        "capture-bodies" => capture_bodies(&context.captures, argument),
+        "local-port" => local_port(&context.local, argument),
+        _ => Err(format!("Unknown command: {}", name)),
+    };
+    match result {
+        Ok(Value::Object(mut fields)) => {
+            fields.insert("ok".to_string(), Value::Bool(true));
+            Value::Object(fields)
+        }
+        Ok(value) => json!({ "ok": true, "result": value }),
+        Err(e) => json!({ "ok": false, "error": e }),
+    }
+}
+
+/// `log-level [<directives> | reset]`
+fn log_level(handle: &LogHandle, argument: Option<&str>) -> Result<Value, String> {
+    match argument {
+        None => {}
+        Some("reset") => {
+            handle.reset()?;
+            warn!("Log level reset to {} (control socket)", handle.configured());
+        }
+        Some(directives) => {
+            handle.set_level(directives)?;
+            warn!("Log level changed to {} (control socket)", directives);
+        }
+    }
+    Ok(json!({ "level": handle.level(), "configured": handle.configured() }))
+}
+
+/// `capture-bodies [on | off]`
+fn capture_bodies(captures: &CaptureLog, argument: Option<&str>) -> Result<Value, String> {
+    match argument {
+        None => {}
+        Some("on") => {
+            captures.set_bodies(true);
+            warn!("Body capture turned on (control socket)");
+        }
+        Some("off") => {
+            captures.set_bodies(false);
+            warn!("Body capture turned off (control socket)");
+        }
+        Some(other) => return Err(format!("Invalid argument: {} (expected on or off)", other)),
+    }
+    serde_json::to_value(captures.options()).map_err(|e| e.to_string())
+}
+
+/// `local-port [<port>[,<port>...]]`
+fn local_port(local: &LocalTarget, argument: Option<&str>) -> Result<Value, String> {
+    let targets = match argument {
+        None => local.base_urls(),
+        Some(ports) => {
+            let targets = local.set_ports(ports)?;
+            warn!("Local target changed to {} (control socket)", targets.join(", "));
+            targets
+        }
+    };
+    Ok(json!({ "targets": targets }))
+}
@@ -0,0 +1,71 @@
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use std::net::{IpAddr, SocketAddr};
+
+/// Resolves local service hostnames through a specific DNS server
+/// instead of the system resolver (useful inside Docker/k8s network namespaces)
+pub struct CustomDnsResolver {
+    resolver: TokioAsyncResolver,
+}
+
+impl CustomDnsResolver {
+    /// Creates a resolver that only queries `server` (UDP and TCP)
+    pub fn new(server: SocketAddr) -> Self {
+        let name_servers = NameServerConfigGroup::from_ips_clear(&[server.ip()], server.port(), true);
+        let config = ResolverConfig::from_parts(None, vec![], name_servers);
+        let resolver = TokioAsyncResolver::tokio(config, ResolverOpts::default());
+
+        Self { resolver }
+    }
+
+    /// Addresses of `host`, for connections reqwest does not make
+    pub async fn lookup(&self, host: &str) -> std::io::Result<Vec<IpAddr>> {
+        let lookup = self.resolver.lookup_ip(host).await.map_err(std::io::Error::other)?;
+        Ok(lookup.iter().collect())
+    }
+}
+
+impl Resolve for CustomDnsResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.resolver.clone();
+        Box::pin(async move {
+            let lookup = resolver.lookup_ip(name.as_str()).await?;
+            // Port is ignored by reqwest; the URL port is used instead
+            let addrs: Addrs = Box::new(
+                lookup.iter().map(|ip| SocketAddr::new(ip, 0)).collect::<Vec<_>>().into_iter(),
+            );
+            Ok(addrs)
+        })
+    }
+}
+
+/// Parses a DNS server address: "ip" or "ip:port" (port defaults to 53)
+pub fn parse_dns_server(value: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+
+    let ip = value.trim_start_matches('[').trim_end_matches(']');
+    ip.parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, 53))
+        .map_err(|_| format!("Invalid LOCAL_DNS_SERVER: {}", value))
+}
+
+/// Parses static resolve overrides: "host=ip[,host=ip...]"
+pub fn parse_resolve_overrides(value: &str) -> Result<Vec<(String, IpAddr)>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (host, ip) = entry.split_once('=')
+                .ok_or_else(|| format!("Invalid LOCAL_RESOLVE entry: {} (expected host=ip)", entry))?;
+            let ip = ip.trim_start_matches('[').trim_end_matches(']');
+            let ip = ip.parse::<IpAddr>()
+                .map_err(|_| format!("Invalid IP address in LOCAL_RESOLVE: {}", ip))?;
+            Ok((host.to_string(), ip))
+        })
+        .collect()
+}
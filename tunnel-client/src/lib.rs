@@ -0,0 +1,1194 @@
+//! Tunnel client: keeps a tunnel to the server open and forwards each
+//! request it receives to the local HTTP service.
+//!
+//! The binary reads its configuration from the environment; embedders and
+//! tests build a `ServerConfig` and `LocalService` themselves and call [`run`]
+//! (or [`run_until`], to shut the tunnel down gracefully, or
+//! [`run_with_status`], to also report the connection state and capture
+//! requests).
+
+pub mod capture;
+pub mod config_file;
+#[cfg(unix)]
+pub mod control;
+pub mod dns;
+pub mod local;
+pub mod memory;
+pub mod path;
+pub mod quality;
+pub mod settings;
+pub mod spool;
+pub mod stats;
+pub mod status;
+pub mod streaming;
+pub mod trailers;
+pub mod transform;
+
+use bytes::{Bytes, BytesMut};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout_at};
+use tracing::{debug, error, info, warn};
+use capture::{CaptureLog, CaptureOptions};
+use local::LocalService;
+use quality::LinkQuality;
+use spool::{SpoolWriter, SpooledBody};
+use stats::LocalStats;
+use status::{StatusHandle, TrackedRequest, Transfer};
+use streaming::StreamedRequest;
+use trailers::{ReceivedTrailers, SendError, TrailedBody};
+use tunnel_core::client::{connect_and_upgrade, ConnectError, Handshake, ServerConfig};
+use tunnel_core::error_dedup;
+use tunnel_core::framing::{send_binary_message, send_message, write_body_pieces, write_credits, BodyCredit, BodyInlet, BodyPiece, Credits, MESSAGE_BUFFERS};
+use tunnel_core::heartbeat::Heartbeat;
+use tunnel_core::logging::ACCESS_TARGET;
+use tunnel_core::stream::TunnelStream;
+use tunnel_protocol::{
+    body_sha256, classify_frame, decode_body, decode_body_frame, decode_request_frame, encode_body, format_tags, is_body_frame, read_frame_into,
+    should_compress, validate_headers, validate_method, validate_path, BodyFrame, BodySha256, CompressTypes, Compression, CompressionStats, ControlFrame, Frame, FrameWriter, HeaderLimits, HeaderValueBytes, ProtocolError,
+    StatsReport, TunnelRequest, TunnelResponse,
+    BODY_SHA256_HEADER, CLIENT_ADDR_HEADER, GOAWAY_FRAME, LATENCY_HEADER, PING_FRAME, PONG_FRAME, TUNNEL_ID_HEADER,
+};
+
+/// How long a shutting-down client waits for the server to finish the requests it queued
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Status of the answer to a request the server cancelled (as nginx's "client closed request"); the server drops it
+const CANCELLED_STATUS: u16 = 499;
+
+/// Connects to the server and serves tunnel requests, reconnecting after transient failures
+/// The current local service is read from `local_rx` for every request
+///
+/// Returns the error once connecting fails permanently (e.g. rejected credentials),
+/// since retrying cannot succeed until the server or the settings change.
+pub async fn run(server_config: ServerConfig, local_rx: watch::Receiver<Arc<LocalService>>) -> ConnectError {
+    match run_until(server_config, local_rx, std::future::pending()).await {
+        Ok(()) => unreachable!("the shutdown signal never resolves"),
+        Err(e) => e,
+    }
+}
+
+/// Like [`run`], until `shutdown` resolves
+///
+/// The tunnel is then drained: the client sends GOAWAY, so the server answers
+/// new requests with 503 and `Retry-After` instead of routing them here, and
+/// returns `Ok` once the server has closed the connection after the requests
+/// already queued (or after `DRAIN_TIMEOUT`).
+pub async fn run_until<F>(
+    server_config: ServerConfig,
+    local_rx: watch::Receiver<Arc<LocalService>>,
+    shutdown: F,
+) -> Result<(), ConnectError>
+where
+    F: Future<Output = ()>,
+{
+    let status = StatusHandle::new(&server_config);
+    let captures = CaptureLog::new(CaptureOptions { history: 0, ..CaptureOptions::default() }, Arc::default());
+    run_with_status(server_config, local_rx, status, captures, shutdown).await
+}
+
+/// Like [`run_until`], keeping `status` up to date and recording requests in
+/// `captures` (see the `status` and `requests` control commands)
+pub async fn run_with_status<F>(
+    server_config: ServerConfig,
+    local_rx: watch::Receiver<Arc<LocalService>>,
+    status: StatusHandle,
+    captures: CaptureLog,
+    shutdown: F,
+) -> Result<(), ConnectError>
+where
+    F: Future<Output = ()>,
+{
+    let result = connection_loop(&server_config, &local_rx, &status, &captures, shutdown).await;
+    status.stopped(result.as_ref().err().map(ToString::to_string));
+    result
+}
+
+async fn connection_loop<F>(
+    server_config: &ServerConfig,
+    local_rx: &watch::Receiver<Arc<LocalService>>,
+    status: &StatusHandle,
+    captures: &CaptureLog,
+    shutdown: F,
+) -> Result<(), ConnectError>
+where
+    F: Future<Output = ()>,
+{
+    tokio::pin!(shutdown);
+
+    // Connection loop with exponential backoff
+    let mut backoff_duration = Duration::from_secs(1);
+    let max_backoff = Duration::from_secs(30);
+    let mut link_quality = LinkQuality::new();
+    let mut local_stats = LocalStats::new();
+
+    loop {
+        let connected = tokio::select! {
+            connected = connect_and_upgrade(server_config) => connected,
+            () = &mut shutdown => return Ok(()),
+        };
+        match connected {
+            Ok((stream, handshake)) => {
+                link_quality.record_rtt(handshake.rtt);
+                status.connected(handshake.tunnel_id);
+                info!("Connected and upgraded to tunnel protocol ({})", link_quality.summary());
+                if let Some(max) = handshake.multiplex {
+                    info!("Handling up to {} requests at once", max);
+                }
+                if handshake.stream_bodies {
+                    info!(
+                        "Streaming bodies over {} bytes, {} chunks in flight at most{}",
+                        server_config.transport.stream_threshold_bytes, server_config.transport.stream_window_chunks,
+                        if handshake.stream_credit.is_some() { ", against credit" } else { "" },
+                    );
+                }
+                let heartbeat = match server_config.transport.heartbeat_interval.filter(|_| handshake.heartbeat) {
+                    Some(interval) => {
+                        info!("Sending heartbeats every {:?}", interval);
+                        Heartbeat::new(interval, server_config.transport.heartbeat_timeout)
+                    }
+                    None => Heartbeat::default(),
+                };
+                if let Some(compression) = handshake.compression {
+                    info!("Compressing frames with {}", compression);
+                }
+
+                // Handle tunnel connection
+                let mut stats = StatsSender { stats: &mut local_stats, interval: handshake.stats_interval, last_sent: None };
+                let context = ConnectionContext {
+                    limits: ConnectionLimits {
+                        requests: server_config.transport.header_limits,
+                        responses: handshake.header_limits,
+                        stream_above: handshake.stream_bodies.then_some(server_config.transport.stream_threshold_bytes),
+                        stream_trailers: handshake.stream_trailers,
+                        stream_window: server_config.transport.stream_window_chunks,
+                    },
+                    tunnel_headers: tunnel_headers(&handshake).into(),
+                    coalesce_bytes: server_config.transport.coalesce_bytes,
+                    max_concurrent: handshake.multiplex.unwrap_or(1),
+                    binary_frames: handshake.binary_frames,
+                    stream_bodies: handshake.stream_bodies,
+                    stream_credit: handshake.stream_credit,
+                    heartbeat,
+                    compression: handshake.compression,
+                    compress_types: server_config.compress_types.clone(),
+                    status: status.clone(),
+                    captures: captures.clone(),
+                };
+                let end = handle_tunnel_connection(
+                    stream, local_rx, &mut link_quality, &mut stats, &context, shutdown.as_mut(),
+                ).await;
+
+                info!("Disconnected from server ({})", link_quality.summary());
+                status.disconnected(None);
+                match end {
+                    ConnectionEnd::ShutDown => return Ok(()),
+                    // Reset backoff after a working connection
+                    ConnectionEnd::Lost => backoff_duration = Duration::from_secs(1),
+                    // Reconnecting likely fails the same way; keep backing off
+                    ConnectionEnd::ProtocolError => {}
+                }
+            }
+            Err(e) if e.is_retryable() => {
+                error_dedup!("Connection/upgrade failed: {}", e);
+                status.disconnected(Some(e.to_string()));
+            }
+            Err(e) => return Err(e),
+        }
+
+        // Exponential backoff
+        info!("Reconnecting in {:?}...", backoff_duration);
+        tokio::select! {
+            () = sleep(backoff_duration) => {}
+            () = &mut shutdown => return Ok(()),
+        }
+        backoff_duration = std::cmp::min(backoff_duration * 2, max_backoff);
+    }
+}
+
+/// Local request stats, and when to report them to the server on the current connection
+struct StatsSender<'a> {
+    stats: &'a mut LocalStats,
+    interval: Option<Duration>,  // Requested by the server at upgrade (None: never report)
+    last_sent: Option<Instant>,
+}
+
+impl StatsSender<'_> {
+    /// Returns a report if the server wants one now, with the `compression` of the frames written
+    ///
+    /// Reports go out ahead of responses: the first right away, then at most
+    /// once per interval.
+    fn due(&mut self, compression: Option<CompressionStats>) -> Option<ControlFrame> {
+        let interval = self.interval?;
+        if self.last_sent.is_some_and(|sent| sent.elapsed() < interval) {
+            return None;
+        }
+        self.last_sent = Some(Instant::now());
+        Some(ControlFrame::Stats(StatsReport { compression, ..self.stats.report() }))
+    }
+}
+
+/// What is fixed for the life of one tunnel connection
+struct ConnectionContext {
+    limits: ConnectionLimits,
+    tunnel_headers: Arc<[(String, HeaderValueBytes)]>,  // For LOCAL_TUNNEL_HEADERS
+    coalesce_bytes: usize,
+    max_concurrent: usize,  // Requests processed at once (1 unless the server multiplexes)
+    binary_frames: bool,  // Responses go out as binary frames (see ENCODING_HEADER)
+    stream_bodies: bool,  // Large bodies may follow their message in body frames (see STREAM_HEADER)
+    stream_credit: Option<usize>,  // Body frames of one body the server takes ahead of credit (see STREAM_CREDIT_HEADER; None: not agreed)
+    heartbeat: Heartbeat,  // PINGs to the server, if it agreed (see HEARTBEAT_HEADER)
+    compression: Option<Compression>,  // Frames worth it go out compressed (see COMPRESSION_HEADER)
+    compress_types: CompressTypes,  // Overrides of the response media types sent uncompressed (see should_compress)
+    status: StatusHandle,  // Counts the requests served
+    captures: CaptureLog,  // Recent requests, for the `requests` control command; its redactor also applies to the access log
+}
+
+/// Header limits in effect on one tunnel connection
+#[derive(Clone, Copy)]
+struct ConnectionLimits {
+    requests: HeaderLimits,           // Ours, enforced on requests from the server
+    responses: Option<HeaderLimits>,  // The server's, checked before sending a response (None: not announced)
+    stream_above: Option<usize>,      // Response bodies larger than this are streamed (None: never)
+    stream_trailers: bool,            // Streamed response bodies end with their trailers (see STREAM_TRAILERS_HEADER)
+    stream_window: usize,             // Chunks of one streamed body in flight at once
+}
+
+/// Headers describing a tunnel connection, for local services that want to log it (LOCAL_TUNNEL_HEADERS)
+/// Values the server did not announce are left out
+fn tunnel_headers(handshake: &Handshake) -> Vec<(String, HeaderValueBytes)> {
+    let mut headers = Vec::with_capacity(3);
+    if let Some(id) = handshake.tunnel_id {
+        headers.push((TUNNEL_ID_HEADER.to_string(), id.to_string().into()));
+    }
+    if let Some(addr) = handshake.client_addr {
+        headers.push((CLIENT_ADDR_HEADER.to_string(), addr.to_string().into()));
+    }
+    headers.push((LATENCY_HEADER.to_string(), handshake.rtt.as_millis().to_string().into()));
+    headers
+}
+
+/// Handles the tunnel connection by processing requests until disconnect
+///
+/// Up to `context.max_concurrent` requests are processed at once, each on its
+/// own task; responses are written as they complete. The pieces of streamed
+/// bodies are read and written in between, against credit if the server agreed
+/// to it (see `STREAM_CREDIT_HEADER`), as are heartbeats: with those on,
+/// frames are read even while at the limit, so PINGs are answered and PONGs
+/// seen however busy the local service is. Once `shutdown` resolves, sends
+/// GOAWAY, stops sending PINGs and serves requests until the server closes
+/// the connection. Returns how the connection ended.
+async fn handle_tunnel_connection<F: Future<Output = ()>>(
+    stream: TunnelStream,
+    local_rx: &watch::Receiver<Arc<LocalService>>,
+    link_quality: &mut LinkQuality,
+    stats: &mut StatsSender<'_>,
+    context: &ConnectionContext,
+    mut shutdown: Pin<&mut F>,
+) -> ConnectionEnd {
+    let (read_half, write_half) = tokio::io::split(stream);
+    let mut reader = BufReader::new(context.heartbeat.reader(read_half));
+    let mut writer = FrameWriter::new(write_half, context.coalesce_bytes).with_compression(context.compression);
+    let mut frame_buf = BytesMut::new();  // Reused for every request frame
+    let mut in_flight = JoinSet::new();
+    let mut drain_deadline = None;  // Set once GOAWAY is sent
+    // Streamed request bodies being read, by request ID
+    let mut request_bodies = HashMap::<u64, (BodyInlet, Arc<Transfer>)>::new();
+    // Credit for the streamed response bodies written, and to grant for the request bodies read
+    let credits = context.stream_credit.map(|chunks| Arc::new(Credits::new(chunks)));
+    let (grants_tx, mut grants_rx) = mpsc::unbounded_channel();
+    // Requests being processed that the server may cancel, by request ID
+    let mut cancels = HashMap::<u64, oneshot::Sender<()>>::new();
+    // Pieces of streamed response bodies, once their response was written
+    let (pieces_tx, mut pieces_rx) = mpsc::channel::<(u64, BodyPiece)>(context.limits.stream_window);
+    let mut protocol_error = false;  // The server sent a frame this client cannot read
+    let mut uncompressed = HashSet::new();  // Streamed response bodies sent without compression, by request ID
+
+    loop {
+        // Wait for the next request while there is room for one (or the next piece of a request
+        // body, or a heartbeat), a finished request, a response body piece, a heartbeat to send,
+        // or shutdown; read errors surface below
+        let readable = in_flight.len() < context.max_concurrent || !request_bodies.is_empty() || !cancels.is_empty() || context.heartbeat.is_on();
+        let event = tokio::select! {
+            _ = reader.fill_buf(), if readable => Event::Request,
+            Some(done) = in_flight.join_next() => match done {
+                Ok(done) => Event::Done(Box::new(done)),
+                Err(e) => std::panic::resume_unwind(e.into_panic()),
+            },
+            Some((id, piece)) = pieces_rx.recv() => Event::BodyPiece(id, piece),
+            Some(grant) = grants_rx.recv() => Event::Credit(grant),
+            () = shutdown.as_mut(), if drain_deadline.is_none() => Event::Shutdown,
+            () = sleep_until_deadline(drain_deadline) => Event::DrainTimeout,
+            () = context.heartbeat.pong_due() => Event::Pong,
+            () = context.heartbeat.ping_due(), if drain_deadline.is_none() => Event::Ping,
+            () = context.heartbeat.expired(), if drain_deadline.is_none() => Event::Unresponsive,
+        };
+
+        match event {
+            Event::Shutdown => {
+                info!("Shutting down; draining the tunnel");
+                drain_deadline = Some(tokio::time::Instant::now() + DRAIN_TIMEOUT);
+                let sent = match writer.write_frame(GOAWAY_FRAME).await {
+                    Ok(()) => writer.flush().await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = sent {
+                    link_quality.record_error();
+                    error!("Failed to send GOAWAY: {}", e);
+                    break;
+                }
+            }
+            Event::DrainTimeout => {
+                warn!("Server did not close the tunnel within {:?} of GOAWAY; closing it", DRAIN_TIMEOUT);
+                break;
+            }
+            Event::Ping | Event::Pong => {
+                let frame = if matches!(event, Event::Ping) {
+                    context.heartbeat.ping_sent();
+                    PING_FRAME
+                } else {
+                    PONG_FRAME
+                };
+                let sent = match writer.write_frame(frame).await {
+                    Ok(()) => writer.flush().await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = sent {
+                    link_quality.record_error();
+                    error!("Failed to send heartbeat: {}", e);
+                    break;
+                }
+            }
+            Event::Unresponsive => {
+                link_quality.record_error();
+                warn!("Server stopped answering heartbeats ({}); dropping the connection", context.heartbeat.expired_error());
+                break;
+            }
+            Event::Request => {
+                // Read tunnel request
+                let read = match drain_deadline {
+                    Some(deadline) => timeout_at(deadline, read_frame_into(&mut reader, &mut frame_buf, context.compression)).await,
+                    None => Ok(read_frame_into(&mut reader, &mut frame_buf, context.compression).await),
+                };
+                let Ok(read) = read else {
+                    warn!("Server did not close the tunnel within {:?} of GOAWAY; closing it", DRAIN_TIMEOUT);
+                    break;
+                };
+                if let Err(e) = read {
+                    match &e {
+                        // A clean close by the server also surfaces as EOF; only count real errors
+                        ProtocolError::Closed(eof) if eof.kind() == std::io::ErrorKind::UnexpectedEof => {
+                            if drain_deadline.is_some() {
+                                info!("Tunnel drained");
+                                break;
+                            }
+                        }
+                        e if e.is_peer_fault() => {
+                            link_quality.record_error();
+                            protocol_error = true;
+                            error!("Server sent a frame this client cannot read: {}; is it running a different version?", e);
+                            break;
+                        }
+                        _ => link_quality.record_error(),
+                    }
+                    error!("Failed to read frame: {}", e);
+                    break;
+                }
+
+                // A PING is answered once the loop comes round; a PONG answers ours
+                if let Ok(Frame::Control(control)) = classify_frame(&frame_buf) {
+                    match control {
+                        ControlFrame::Ping {} => context.heartbeat.ping_received(),
+                        ControlFrame::Pong {} => context.heartbeat.pong_received(),
+                        // Answered like any other request once its task ends; a response body being sent ends there
+                        ControlFrame::Cancel { id } => {
+                            if let Some(cancel) = cancels.remove(&id) {
+                                debug!("Server cancelled request {}", id);
+                                request_bodies.remove(&id);
+                                let _ = cancel.send(());
+                            }
+                        }
+                        ControlFrame::Credit { id, chunks } => match &credits {
+                            Some(credits) => credits.grant(id, chunks),
+                            None => debug!("Ignoring CREDIT from the server"),
+                        },
+                        other => debug!("Ignoring {:?} from the server", other),
+                    }
+                    continue;
+                }
+
+                // A piece of a streamed request body; the request may have been answered already
+                if context.stream_bodies && is_body_frame(&frame_buf) {
+                    let piece = match decode_body_frame(&frame_buf) {
+                        Ok(BodyFrame::Chunk { id, data }) => (id, BodyPiece::Data(Bytes::copy_from_slice(data))),
+                        Ok(BodyFrame::End { id, complete, sha256, trailers }) => (id, BodyPiece::End { complete, sha256: sha256.map(str::to_string), trailers }),
+                        Err(e) => {
+                            link_quality.record_error();
+                            protocol_error = true;
+                            error!("Failed to decode body frame: {}", e);
+                            break;
+                        }
+                    };
+                    let (id, piece) = piece;
+                    let end = matches!(piece, BodyPiece::End { .. });
+                    let Some((body, transfer)) = request_bodies.get(&id) else {
+                        // Given up on; its pieces still count against the server's credit
+                        if credits.is_some() && !end {
+                            let _ = grants_tx.send((id, 1));
+                        }
+                        continue;
+                    };
+                    if let BodyPiece::Data(data) = &piece {
+                        transfer.add_request_bytes(data.len() as u64);
+                    }
+                    if let Err(e) = body.put(piece).await {
+                        link_quality.record_error();
+                        protocol_error = true;
+                        error!("Server sent a body frame past its credit: {}", e);
+                        break;
+                    }
+                    if end {
+                        request_bodies.remove(&id);
+                    }
+                    continue;
+                }
+
+                // Deserialize tunnel request; a binary frame carries the body as is
+                let (tunnel_req, raw_body) = match decode_request_frame(&frame_buf) {
+                    Ok((r, raw_body)) => (r, raw_body.map(Bytes::copy_from_slice)),
+                    Err(e) => {
+                        link_quality.record_error();
+                        protocol_error = true;
+                        error!("Failed to deserialize request: {}", e);
+                        break;
+                    }
+                };
+
+                // Process the request on a task of its own
+                let local_service = local_rx.borrow().clone();
+                // A frame big enough to be spooled is not held while the local service works on it
+                if local_service.spool_threshold.is_some_and(|threshold| frame_buf.capacity() > threshold) {
+                    frame_buf = BytesMut::new();
+                }
+                let path = context.captures.redactor().text(tunnel_req.path.split('?').next().unwrap_or_default()).into_owned();
+                let tracked = context.status.track(tunnel_req.id, &tunnel_req.method, &path, content_length(&tunnel_req.headers));
+                let transfer = tracked.transfer().clone();
+                let request = InFlight {
+                    id: tunnel_req.id,
+                    started: Instant::now(),
+                    method: tunnel_req.method.clone(),
+                    path,
+                    capture: context.captures.start(&tunnel_req, raw_body.as_deref()),
+                    local_service: local_service.clone(),
+                    tracked,
+                };
+                // Its body follows in body frames
+                let streamed = match tunnel_req.id.filter(|_| context.stream_bodies && tunnel_req.stream) {
+                    Some(id) => {
+                        let (inlet, body_rx) = match credits {
+                            Some(_) => BodyInlet::credited(id, context.limits.stream_window, grants_tx.clone()),
+                            None => BodyInlet::channel(context.limits.stream_window),
+                        };
+                        request_bodies.insert(id, (inlet, transfer.clone()));
+                        Some(body_rx)
+                    }
+                    None => None,
+                };
+                let (cancel, cancelled) = oneshot::channel();
+                if let Some(id) = tunnel_req.id {
+                    cancels.insert(id, cancel);
+                }
+                let (limits, tunnel_headers) = (context.limits, context.tunnel_headers.clone());
+                in_flight.spawn(async move {
+                    // Dropping the request to the local service aborts it
+                    let reply = tokio::select! {
+                        reply = process_request(tunnel_req, raw_body, streamed, &local_service, &limits, &tunnel_headers, &transfer) => reply,
+                        Ok(()) = cancelled => error_response(CANCELLED_STATUS, "Request cancelled by the server"),
+                    };
+                    (request, reply)
+                });
+            }
+            Event::Done(done) => {
+                let (request, Reply { response: mut tunnel_resp, body }) = *done;
+                if let Some(id) = request.id {
+                    cancels.remove(&id);
+                }
+                tunnel_resp.id = request.id;
+                let elapsed = request.started.elapsed();
+                if let Some(capture) = request.capture {
+                    let (held, spooled_len) = match &body {
+                        ReplyBody::Held(body) => (&body[..], None),
+                        ReplyBody::Spooled(body) => (&[][..], Some(body.len())),
+                        ReplyBody::Streamed(_) => (&[][..], Some(declared_length(&tunnel_resp))),
+                    };
+                    context.captures.finish(capture, &tunnel_resp, held, spooled_len, elapsed);
+                }
+                let tags = tunnel_resp.tags();
+                stats.stats.record(tunnel_resp.status, elapsed, &tags);
+                context.status.record_request(tunnel_resp.status);
+                info!(
+                    target: ACCESS_TARGET,
+                    "{} {} {} {}ms{}",
+                    request.method, request.path, tunnel_resp.status, elapsed.as_millis(), format_tags(&tags)
+                );
+
+                if let Some(report) = stats.due(writer.compression_stats()) {
+                    if let Err(e) = send_message(&mut writer, &report).await {
+                        link_quality.record_error();
+                        error!("Failed to send stats report: {}", e);
+                        break;
+                    }
+                }
+
+                // Bodies compressed already go out as they are, sparing the attempt
+                if context.compression.is_some() {
+                    let body_start = match &body {
+                        ReplyBody::Held(body) => &body[..],
+                        ReplyBody::Spooled(_) | ReplyBody::Streamed(_) => &[][..],
+                    };
+                    let headers = &tunnel_resp.headers;
+                    let content_type = header_str(headers, "content-type");
+                    if !should_compress(content_type, header_str(headers, "content-encoding"), body_start, &context.compress_types) {
+                        writer.skip_compression();
+                        if let (ReplyBody::Streamed(_), Some(id)) = (&body, tunnel_resp.id) {
+                            uncompressed.insert(id);
+                        }
+                    }
+                }
+
+                // Write tunnel response
+                let sent = match &body {
+                    ReplyBody::Streamed(_) => send_binary_message(&mut writer, &tunnel_resp, &[]).await,
+                    ReplyBody::Spooled(body) => spool::send_response(&mut writer, &tunnel_resp, body, context.binary_frames).await,
+                    ReplyBody::Held(body) if context.binary_frames => send_binary_message(&mut writer, &tunnel_resp, body).await,
+                    ReplyBody::Held(body) => {
+                        tunnel_resp.body = encode_body(body);
+                        send_message(&mut writer, &tunnel_resp).await
+                    }
+                };
+                if let Err(e) = sent {
+                    link_quality.record_error();
+                    error!("{}", e);
+                    break;
+                }
+
+                // The server is waiting for it
+                if let Err(e) = writer.flush().await {
+                    link_quality.record_error();
+                    error!("Failed to flush tunnel: {}", e);
+                    break;
+                }
+
+                // Its body follows, as its pieces come; the request is listed, and may be cancelled, until it ends
+                if let (ReplyBody::Streamed(mut body), Some(id)) = (body, tunnel_resp.id) {
+                    let pieces_tx = pieces_tx.clone();
+                    let tracked = request.tracked;
+                    let credit = credits.as_ref().map(|credits| credits.open(id));
+                    let (cancel, mut cancelled) = oneshot::channel();
+                    cancels.insert(id, cancel);
+                    tokio::spawn(async move {
+                        let _tracked = tracked;
+                        loop {
+                            let piece = tokio::select! {
+                                piece = next_body_piece(&mut body, credit.as_ref()) => piece,
+                                cancel = &mut cancelled => match cancel {
+                                    // Nobody reads it any more: it ends here, without waiting for credit
+                                    Ok(()) => Some(BodyPiece::End { complete: false, sha256: None, trailers: Vec::new() }),
+                                    Err(_) => None,  // The connection ended
+                                },
+                            };
+                            let Some(piece) = piece else {
+                                return;
+                            };
+                            let end = matches!(piece, BodyPiece::End { .. });
+                            if pieces_tx.send((id, piece)).await.is_err() || end {
+                                return;
+                            }
+                        }
+                    });
+                }
+
+                // Release memory kept between requests: the frame buffer once it outgrew
+                // LOCAL_MAX_BUFFERED_BYTES, and every pooled buffer and captured request under memory pressure
+                let pressure = memory::take_pressure();
+                if pressure || request.local_service.max_buffered_bytes.is_some_and(|max| frame_buf.capacity() > max) {
+                    frame_buf = BytesMut::new();
+                }
+                if pressure {
+                    MESSAGE_BUFFERS.clear();
+                    context.captures.clear();
+                }
+            }
+            Event::BodyPiece(id, piece) => {
+                // With the pieces queued behind it
+                if let Err(e) = write_body_pieces(&mut writer, (id, piece), &mut pieces_rx, context.limits.stream_window, &mut uncompressed).await {
+                    link_quality.record_error();
+                    error!("Failed to send response body: {}", e);
+                    break;
+                }
+                // Bodies written to their end (their pump gone, and nothing queued) are no longer cancelled
+                if pieces_rx.is_empty() {
+                    cancels.retain(|_, cancel| !cancel.is_closed());
+                }
+            }
+            Event::Credit(grant) => {
+                // With the grants queued behind it
+                if let Err(e) = write_credits(&mut writer, grant, &mut grants_rx).await {
+                    link_quality.record_error();
+                    error!("Failed to grant credit: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+    if drain_deadline.is_some() {
+        ConnectionEnd::ShutDown
+    } else if protocol_error {
+        ConnectionEnd::ProtocolError
+    } else {
+        ConnectionEnd::Lost
+    }
+}
+
+/// How `handle_tunnel_connection` ended
+enum ConnectionEnd {
+    ShutDown,       // Drained after shutdown
+    Lost,           // The connection broke or the server closed it
+    ProtocolError,  // The server sent a frame this client cannot read
+}
+
+/// Content-Length of a response, for captures of bodies not held (0: not declared)
+fn declared_length(response: &TunnelResponse) -> u64 {
+    content_length(&response.headers).unwrap_or(0)
+}
+
+/// Value of the Content-Length header among `headers` (None: not declared)
+fn content_length(headers: &[(String, HeaderValueBytes)]) -> Option<u64> {
+    header_str(headers, "content-length")?.trim().parse().ok()
+}
+
+/// First value of header `name` among `headers`, if it is UTF-8
+fn header_str<'a>(headers: &'a [(String, HeaderValueBytes)], name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .and_then(|(_, value)| value.to_str())
+}
+
+/// What woke up the loop of `handle_tunnel_connection`
+enum Event {
+    Request,              // A request frame started arriving
+    Done(Box<(InFlight, Reply)>),
+    BodyPiece(u64, BodyPiece),  // Of a streamed response body, to write
+    Credit((u64, u64)),   // For a streamed request body, to grant
+    Shutdown,
+    DrainTimeout,
+    Ping,                 // One is due
+    Pong,                 // The server sent a PING
+    Unresponsive,         // The server did not answer a PING in time
+}
+
+/// Request being processed, with what is needed to log and answer it once done
+struct InFlight {
+    id: Option<u64>,  // Copied into the response
+    started: Instant,
+    method: String,
+    path: String,  // Redacted, without the query
+    capture: Option<capture::Pending>,
+    local_service: Arc<LocalService>,
+    tracked: TrackedRequest,  // Listed by the `in-flight` control command
+}
+
+/// Waits until `deadline`, or forever without one
+async fn sleep_until_deadline(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Next piece of a streamed response body, once there is credit to send it (None: the body or the connection ended)
+async fn next_body_piece(body: &mut mpsc::Receiver<BodyPiece>, credit: Option<&BodyCredit>) -> Option<BodyPiece> {
+    let piece = body.recv().await?;
+    match credit {
+        Some(credit) => credit.spend().await.then_some(piece),
+        None => Some(piece),
+    }
+}
+
+/// Processes a tunnel request by forwarding to local HTTP service
+async fn process_request(
+    mut tunnel_req: TunnelRequest,
+    raw_body: Option<Bytes>,  // From a binary frame (None: base64 in `tunnel_req.body`)
+    streamed: Option<mpsc::Receiver<BodyPiece>>,  // Pieces of a streamed body (None: it came with the request)
+    local_service: &LocalService,
+    limits: &ConnectionLimits,
+    tunnel_headers: &[(String, HeaderValueBytes)],
+    transfer: &Arc<Transfer>,  // Counts the bytes moved (those of a streamed request body as they arrive)
+) -> Reply {
+    // Decode request body, into a spool file when it is too large to hold
+    let encoded = std::mem::take(&mut tunnel_req.body);
+    let spool_above = |len: usize| local_service.spool_threshold.is_some_and(|threshold| len > threshold);
+    let spool_dir = local_service.spool_dir.as_deref();
+    let spooled = match &raw_body {
+        Some(body) if spool_above(body.len()) => Some(spool::save_to_file(body, spool_dir).await),
+        None if spool_above(encoded.len() / 4 * 3) => Some(spool::decode_to_file(&encoded, spool_dir).await),
+        _ => None,
+    };
+    let mut request_body = match (spooled, raw_body, streamed) {
+        (_, _, Some(pieces)) => RequestBody::Streamed(StreamedRequest::new(pieces)),
+        (Some(Ok((body, sha256))), _, _) => RequestBody::Spooled { body, sha256 },
+        (Some(Err(e)), _, _) => {
+            error_dedup!("Failed to spool request body: {}", e);
+            return error_response(502, "Failed to spool request body");
+        }
+        (None, Some(body), _) => RequestBody::Held(body),
+        (None, None, _) => match decode_body(&encoded) {
+            Ok(b) => RequestBody::Held(Bytes::from(b)),
+            Err(e) => {
+                error_dedup!("Failed to decode request body: {}", e);
+                return error_response(502, "Failed to decode request body");
+            }
+        },
+    };
+    drop(encoded);
+    match &request_body {
+        RequestBody::Held(body) => transfer.add_request_bytes(body.len() as u64),
+        RequestBody::Spooled { body, .. } => transfer.add_request_bytes(body.len()),
+        RequestBody::Streamed(_) => {}
+    }
+
+    // Request and response bodies held in memory together stay within LOCAL_MAX_BUFFERED_BYTES
+    let mut max_held_response_bytes = local_service.max_body_bytes;
+    if let Some(max_buffered) = local_service.max_buffered_bytes {
+        let held = request_body.held_len();
+        if held > max_buffered {
+            error_dedup!("Request body too large: {} bytes (LOCAL_MAX_BUFFERED_BYTES {})", held, max_buffered);
+            return error_response(413, "Request body too large");
+        }
+        max_held_response_bytes = max_held_response_bytes.min(max_buffered - held);
+    }
+
+    let mut headers = std::mem::take(&mut tunnel_req.headers);
+
+    // The server vouches for the body it read; anything else means it changed in between
+    // (that of a streamed body comes with its end)
+    let checksummed = match headers.iter().find(|(name, _)| name.eq_ignore_ascii_case(BODY_SHA256_HEADER)) {
+        Some((_, expected)) if request_body.sha256().is_some_and(|sha256| expected.as_bytes() != sha256.as_bytes()) => {
+            stats::record_checksum_mismatch();
+            error_dedup!("Request body does not match its {} header", BODY_SHA256_HEADER);
+            return error_response(502, "Request body checksum mismatch");
+        }
+        Some(_) => true,
+        None => false,
+    };
+
+    // Refuse what reqwest would reject or rewrite rather than forward something else
+    let validated = validate_method(&tunnel_req.method)
+        .and_then(|()| validate_path(&tunnel_req.path))
+        .and_then(|()| validate_headers(&headers));
+    if let Err(e) = validated {
+        error_dedup!("Rejecting tunnel request: {}", e);
+        return error_response(400, &e.to_string());
+    }
+    if let Err(e) = limits.requests.check(&headers) {
+        error_dedup!("Rejecting tunnel request: {}", e);
+        return error_response(431, &e.to_string());
+    }
+    // Ours replace any the visitor sent, so the local service can trust them
+    if local_service.tunnel_headers {
+        headers.retain(|(name, _)| ![TUNNEL_ID_HEADER, CLIENT_ADDR_HEADER, LATENCY_HEADER].iter().any(|ours| name.eq_ignore_ascii_case(ours)));
+        headers.extend_from_slice(tunnel_headers);
+    }
+
+    let Some(path) = path::local_path(&tunnel_req.path, local_service.path_mode) else {
+        error_dedup!("Rejecting tunnel request: target {} would be altered on the way to the local service", tunnel_req.path);
+        return error_response(400, "Request target cannot be forwarded unchanged (see LOCAL_PATH_MODE)");
+    };
+
+    // Convert the body for a local handler expecting another format (LOCAL_TRANSFORM_RULES_FILE)
+    let content_type = headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("content-type")).map(|(_, value)| value.as_bytes());
+    if let Some(rule) = local_service.transforms.find(&path, content_type) {
+        match &request_body {
+            RequestBody::Held(body) => match rule.apply(body) {
+                Ok(converted) => {
+                    headers.retain(|(name, _)| !name.eq_ignore_ascii_case("content-type") && !name.eq_ignore_ascii_case("content-length"));
+                    headers.push(("content-type".to_string(), rule.to.content_type().into()));
+                    request_body = RequestBody::Held(Bytes::from(converted));
+                }
+                Err(e) => {
+                    error_dedup!("Cannot convert request body for {}: {}", path, e);
+                    return error_response(400, &format!("Request body cannot be converted: {}", e));
+                }
+            },
+            RequestBody::Spooled { .. } => warn!("Request body for {} is spooled; forwarding it unconverted", path),
+            RequestBody::Streamed(_) => warn!("Request body for {} is streamed; forwarding it unconverted", path),
+        }
+    }
+    let method = match reqwest::Method::from_bytes(tunnel_req.method.as_bytes()) {
+        Ok(method) => method,
+        Err(e) => {
+            error_dedup!("Rejecting tunnel request: {}", e);
+            return error_response(400, "Invalid method");
+        }
+    };
+
+    // Stop when the server stops waiting, not just at LOCAL_TIMEOUT_SECS
+    let timeout = match tunnel_req.deadline_ms {
+        Some(0) => {
+            warn!("Request reached the client after the server gave up on it; not forwarding");
+            return error_response(504, "Request deadline exceeded");
+        }
+        Some(ms) => local_service.timeout.min(Duration::from_millis(ms)),
+        None => local_service.timeout,
+    };
+
+    // Execute request; one with trailers, asking for them, or with names that must keep
+    // their case goes through hyper (see `trailers`)
+    let sent_at = Instant::now();
+    let request_trailers = std::mem::take(&mut tunnel_req.trailers);
+    let result = if trailers::wanted(&headers, &request_trailers) || local_service.trailer_client.keeps_case(&headers) {
+        send_with_trailers(local_service, &method, &path, &headers, &request_body, request_trailers, timeout).await
+    } else {
+        send_to_local(local_service, &method, &path, &headers, &request_body, timeout).await
+            .map(|response| (response, Vec::new(), ReceivedTrailers::default()))
+    };
+    if let RequestBody::Streamed(body) = &request_body {
+        if body.mismatched() {
+            error_dedup!("Streamed request body does not match its checksum");
+            return error_response(502, "Request body checksum mismatch");
+        }
+    }
+    match result {
+        Ok((response, names, trailers)) => {
+            let status = response.status().as_u16();
+            let version = format!("{:?}", response.version());
+
+            // Extract headers, named as the local service spelled them where hyper kept that
+            let mut headers = header_pairs(response.headers());
+            if names.len() == headers.len() {
+                for ((name, _), spelled) in headers.iter_mut().zip(names) {
+                    *name = spelled;
+                }
+            }
+            if let Some(Err(e)) = limits.responses.map(|server_limits| server_limits.check(&headers)) {
+                error_dedup!("Local response exceeds the server's header limits: {}", e);
+                return error_response(502, "Local response headers exceed the server's limits");
+            }
+
+            // Read response body, bounded by the configured size limits, or stream it once it is
+            // large; the server checks it against our checksum when it sent one for the request
+            let checksummed = checksummed || matches!(&request_body, RequestBody::Streamed(body) if body.checksummed());
+            let hasher = checksummed.then(BodySha256::default);
+            let (body, sha256) = match read_limited_body(response, local_service, max_held_response_bytes, limits.stream_above, hasher, transfer).await {
+                Ok((ResponseBody::Held(body), sha256)) => (ReplyBody::Held(body.freeze()), sha256),
+                Ok((ResponseBody::Spooled(body), sha256)) => (ReplyBody::Spooled(body), sha256),
+                Ok((ResponseBody::Streamed { read, rest, hasher }, _)) => {
+                    let trailers = limits.stream_trailers.then(|| trailers.clone());
+                    let pieces = streaming::send_response_body(read, *rest, local_service.max_body_bytes, hasher, trailers, limits.stream_window, transfer.clone());
+                    (ReplyBody::Streamed(pieces), None)
+                }
+                Err(reply) => return reply,
+            };
+
+            // Known once the body was read to the end, so a streamed one's go with its end
+            let trailers = match body {
+                ReplyBody::Streamed(_) => Vec::new(),
+                _ => std::mem::take(&mut *trailers.lock().unwrap()),
+            };
+
+            let tunnel_resp = TunnelResponse {
+                status,
+                headers,
+                version: Some(version),
+                trailers,
+                local_duration_ms: Some(sent_at.elapsed().as_millis() as u64),
+                body_sha256: sha256,
+                stream: matches!(body, ReplyBody::Streamed(_)),
+                ..TunnelResponse::default()
+            };
+            Reply { response: tunnel_resp, body }
+        }
+        Err(LocalError::Http(e)) if e.is_timeout() => {
+            error_dedup!("Local HTTP request timed out: {}", e);
+            error_response(504, "Local service timed out")
+        }
+        Err(LocalError::Http(e)) => {
+            error_dedup!("Local HTTP request failed: {}", e);
+            error_response(502, "Local service unavailable")
+        }
+        Err(LocalError::TimedOut) => {
+            error_dedup!("Local HTTP request timed out");
+            error_response(504, "Local service timed out")
+        }
+        Err(LocalError::Direct(e)) => {
+            error_dedup!("Local HTTP request failed: {}", e);
+            error_response(502, "Local service unavailable")
+        }
+        Err(LocalError::Spool(e)) => {
+            error_dedup!("Failed to read spooled request body: {}", e);
+            error_response(502, "Failed to read spooled request body")
+        }
+    }
+}
+
+/// Sends a request to the first local target that accepts the connection
+/// Only connection failures fail over; any response (even an error status) is final
+async fn send_to_local(
+    local_service: &LocalService,
+    method: &reqwest::Method,
+    path: &str,
+    headers: &[(String, HeaderValueBytes)],
+    body: &RequestBody,
+    timeout: Duration,
+) -> Result<reqwest::Response, LocalError> {
+    let build_request = |base_url: &str, body: reqwest::Body| {
+        let url = format!("{}{}", base_url, path);
+        let mut req_builder = local_service.client.request(method.clone(), url).timeout(timeout);
+
+        // Add headers (RequestBuilder::header appends, so repeated headers are kept in order)
+        for (name, value) in headers {
+            req_builder = req_builder.header(name, value.as_bytes());
+        }
+
+        req_builder.body(body)
+    };
+
+    let (last_url, fallbacks) = local_service.base_urls
+        .split_last()
+        .expect("LocalConfig guarantees at least one local target");
+
+    for base_url in fallbacks {
+        match build_request(base_url, body.to_reqwest().await?).send().await {
+            Err(e) if e.is_connect() => {
+                warn!("Local target {} unavailable, trying next: {}", base_url, e);
+            }
+            result => return result.map_err(LocalError::Http),
+        }
+    }
+
+    build_request(last_url, body.to_reqwest().await?).send().await.map_err(LocalError::Http)
+}
+
+/// Sends a request with `trailers`, asking for them, or keeping the case of its header names,
+/// like `send_to_local` but through hyper
+async fn send_with_trailers(
+    local_service: &LocalService,
+    method: &reqwest::Method,
+    path: &str,
+    headers: &[(String, HeaderValueBytes)],
+    body: &RequestBody,
+    trailers: Vec<(String, HeaderValueBytes)>,
+    timeout: Duration,
+) -> Result<(reqwest::Response, Vec<String>, ReceivedTrailers), LocalError> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut base_urls = local_service.base_urls.iter().peekable();
+    loop {
+        let base_url = base_urls.next().expect("LocalConfig guarantees at least one local target");
+        let body = TrailedBody::new(body.to_hyper().await?, trailers.clone());
+        let sent = local_service.trailer_client.send(base_url, method.as_str(), path, headers, body, deadline);
+        match timeout_at(deadline, sent).await {
+            Ok(Err(SendError::Connect(e))) if base_urls.peek().is_some() => {
+                warn!("Local target {} unavailable, trying next: {}", base_url, e);
+            }
+            Ok(result) => return result.map_err(LocalError::Direct),
+            Err(_) => return Err(LocalError::TimedOut),
+        }
+    }
+}
+
+/// Reads a local response body chunk by chunk, giving up once it exceeds LOCAL_MAX_BODY_BYTES
+///
+/// A body growing past LOCAL_SPOOL_THRESHOLD_BYTES moves to a spool file;
+/// one kept in memory may not exceed `max_held_bytes`. With `hasher`, the
+/// body's `body_sha256` is computed on the way and returned with it.
+///
+/// With `stream_above`, a body growing past it (or the spool threshold, if
+/// lower) is returned with the part read so far and the hasher, to be streamed
+/// rather than spooled.
+async fn read_limited_body(
+    mut response: reqwest::Response,
+    local_service: &LocalService,
+    max_held_bytes: usize,
+    stream_above: Option<usize>,
+    mut hasher: Option<BodySha256>,
+    transfer: &Transfer,
+) -> Result<(ResponseBody, Option<String>), Reply> {
+    let max_bytes = local_service.max_body_bytes;
+    let spooled_above = |len: usize| local_service.spool_threshold.is_some_and(|threshold| len > threshold);
+    let stream_above = stream_above.map(|threshold| threshold.min(local_service.spool_threshold.unwrap_or(usize::MAX)));
+    let streamed_above = |len: usize| stream_above.is_some_and(|threshold| len > threshold);
+
+    // Reject early when the declared length is already too large
+    if let Some(len) = response.content_length() {
+        let limit = if spooled_above(len as usize) || streamed_above(len as usize) { max_bytes } else { max_held_bytes };
+        if len > limit as u64 {
+            error_dedup!("Local response body too large: {} bytes (limit {})", len, limit);
+            return Err(error_response(502, "Local response body too large"));
+        }
+        if streamed_above(len as usize) {
+            return Ok((ResponseBody::Streamed { read: Bytes::new(), rest: Box::new(response), hasher }, None));
+        }
+    }
+
+    let declared = response.content_length().unwrap_or(0) as usize;
+    let mut body = BytesMut::with_capacity(if spooled_above(declared) { 0 } else { declared });
+    let mut spool: Option<SpoolWriter> = None;
+    let mut len = 0;
+    loop {
+        match response.chunk().await {
+            Ok(Some(chunk)) => {
+                if let Some(hasher) = &mut hasher {
+                    hasher.update(&chunk);
+                }
+                len += chunk.len();
+                transfer.add_response_bytes(chunk.len() as u64);
+                if len <= max_bytes && streamed_above(len) {
+                    body.extend_from_slice(&chunk);
+                    return Ok((ResponseBody::Streamed { read: body.freeze(), rest: Box::new(response), hasher }, None));
+                }
+                if len > max_bytes || (spool.is_none() && !spooled_above(len) && len > max_held_bytes) {
+                    error_dedup!("Local response body exceeded {} bytes", if len > max_bytes { max_bytes } else { max_held_bytes });
+                    return Err(error_response(502, "Local response body too large"));
+                }
+                if spool.is_none() && spooled_above(len) {
+                    spool = Some(SpoolWriter::create(local_service.spool_dir.as_deref()).map_err(spool_failed)?);
+                }
+                match &mut spool {
+                    Some(writer) => {
+                        if !body.is_empty() {
+                            writer.write(&body).await.map_err(spool_failed)?;
+                            body = BytesMut::new();
+                        }
+                        writer.write(&chunk).await.map_err(spool_failed)?;
+                    }
+                    None => body.extend_from_slice(&chunk),
+                }
+            }
+            Ok(None) => {
+                let body = match spool {
+                    Some(writer) => ResponseBody::Spooled(writer.finish().await.map_err(spool_failed)?),
+                    None => ResponseBody::Held(body),
+                };
+                return Ok((body, hasher.map(BodySha256::finish)));
+            }
+            Err(e) if e.is_timeout() => {
+                error_dedup!("Timed out reading response body: {}", e);
+                return Err(error_response(504, "Local service timed out"));
+            }
+            Err(e) => {
+                error_dedup!("Failed to read response body: {}", e);
+                return Err(error_response(502, "Failed to read response body"));
+            }
+        }
+    }
+}
+
+/// Logs a failure to write a response body to its spool file, returning the response for it
+fn spool_failed(e: std::io::Error) -> Reply {
+    error_dedup!("Failed to spool response body: {}", e);
+    error_response(502, "Failed to spool response body")
+}
+
+/// Response to a tunnel request, with its body kept apart until it is written
+/// in the connection's encoding
+struct Reply {
+    response: TunnelResponse,  // With an empty `body`
+    body: ReplyBody,
+}
+
+/// Body of a `Reply`
+enum ReplyBody {
+    Held(Bytes),
+    Spooled(SpooledBody),  // Too large to hold
+    Streamed(mpsc::Receiver<BodyPiece>),  // Too large to hold, on a connection that streams bodies
+}
+
+/// Decoded request body, on its way to the local service
+enum RequestBody {
+    Held(Bytes),
+    Spooled { body: SpooledBody, sha256: String },  // With its `body_sha256`, computed while spooling
+    Streamed(StreamedRequest),  // Passed on as its pieces arrive
+}
+
+impl RequestBody {
+    /// Bytes held in memory
+    fn held_len(&self) -> usize {
+        match self {
+            RequestBody::Held(body) => body.len(),
+            RequestBody::Spooled { .. } | RequestBody::Streamed(_) => 0,
+        }
+    }
+
+    /// Its `body_sha256` (None: streamed, not known until its end)
+    fn sha256(&self) -> Option<String> {
+        match self {
+            RequestBody::Held(body) => Some(body_sha256(body)),
+            RequestBody::Spooled { sha256, .. } => Some(sha256.clone()),
+            RequestBody::Streamed(_) => None,
+        }
+    }
+
+    /// The body for one attempt at the local service
+    async fn to_reqwest(&self) -> std::io::Result<reqwest::Body> {
+        match self {
+            RequestBody::Held(body) => Ok(reqwest::Body::from(body.clone())),
+            RequestBody::Spooled { body, .. } => Ok(reqwest::Body::from(body.open().await?)),
+            RequestBody::Streamed(body) => Ok(body.to_reqwest()),
+        }
+    }
+
+    /// The body for one attempt through hyper (see `trailers`)
+    async fn to_hyper(&self) -> std::io::Result<hyper::Body> {
+        match self {
+            RequestBody::Held(body) => Ok(hyper::Body::from(body.clone())),
+            RequestBody::Spooled { body, .. } => body.to_hyper().await,
+            RequestBody::Streamed(body) => Ok(body.to_hyper()),
+        }
+    }
+}
+
+/// Local response body as read
+enum ResponseBody {
+    Held(BytesMut),
+    Spooled(SpooledBody),
+    Streamed { read: Bytes, rest: Box<reqwest::Response>, hasher: Option<BodySha256> },  // To be streamed from here
+}
+
+/// Why a request to the local service failed
+enum LocalError {
+    Http(reqwest::Error),
+    Direct(SendError),  // Through hyper (see `trailers`)
+    TimedOut,           // Through hyper, before the response head arrived
+    Spool(std::io::Error),  // The spooled request body could not be read back
+}
+
+impl From<std::io::Error> for LocalError {
+    fn from(e: std::io::Error) -> Self {
+        LocalError::Spool(e)
+    }
+}
+
+/// Converts a header map into name-value pairs with raw values
+/// Every value of a repeated header (e.g. Set-Cookie) is kept, in the order it was received
+fn header_pairs(headers: &reqwest::header::HeaderMap) -> Vec<(String, HeaderValueBytes)> {
+    headers
+        .iter()
+        .map(|(name, value)| (name.as_str().to_string(), value.as_bytes().into()))
+        .collect()
+}
+
+/// Creates an error response for tunnel communication
+fn error_response(status: u16, message: &str) -> Reply {
+    let response = TunnelResponse {
+        status,
+        headers: vec![("content-type".to_string(), "text/plain".into())],
+        ..TunnelResponse::default()
+    };
+    Reply { response, body: ReplyBody::Held(Bytes::copy_from_slice(message.as_bytes())) }
+}
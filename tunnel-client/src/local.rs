@@ -0,0 +1,360 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use serde::Serialize;
+use std::time::Duration;
+use tokio::sync::watch;
+use tunnel_core::config::{serialize_opt_secs, serialize_secs};
+
+use crate::dns;
+use crate::path::{parse_local_path_mode, LocalPathMode};
+use crate::trailers::{self, TrailerClient};
+use crate::transform::Transforms;
+
+/// HTTP version used when talking to the local service
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LocalHttpVersion {
+    Auto,   // HTTP/1.1, or HTTP/2 when negotiated via ALPN over https
+    Http1,  // HTTP/1.1 only
+    Http2,  // HTTP/2 with prior knowledge (h2c over http, h2 over https)
+}
+
+/// Parses LOCAL_HTTP_VERSION value
+fn parse_local_http_version(value: &str) -> Result<LocalHttpVersion, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "auto" => Ok(LocalHttpVersion::Auto),
+        "http1" | "http/1.1" => Ok(LocalHttpVersion::Http1),
+        "http2" | "h2" | "h2c" => Ok(LocalHttpVersion::Http2),
+        other => Err(format!("Invalid LOCAL_HTTP_VERSION: {} (expected auto, http1 or http2)", other)),
+    }
+}
+
+/// Configuration for the local HTTP service
+#[derive(Serialize)]
+pub struct LocalConfig {
+    pub scheme: String,                      // http or https
+    pub host: String,                        // Local service hostname or IP
+    pub ports: Vec<u16>,                     // Local service ports, tried in order on connection refusal
+    pub resolve_overrides: Vec<(String, IpAddr)>,  // Fixed hostname -> IP mappings (like curl --resolve)
+    pub dns_server: Option<SocketAddr>,      // DNS server used instead of the system resolver
+    pub http_version: LocalHttpVersion,      // HTTP version spoken to the local service
+    pub path_mode: LocalPathMode,            // Whether request targets are forwarded unchanged or normalized
+    pub tunnel_headers: bool,                // Add X-Tunnel-Id, X-Tunnel-Client-Addr and X-Tunnel-Latency-Ms to requests
+    #[serde(rename = "timeout_secs", serialize_with = "serialize_secs")]
+    pub timeout: Duration,                   // Wall-clock budget for one local request, body included
+    pub max_body_bytes: usize,               // Largest local response body buffered in memory
+    pub max_buffered_bytes: Option<usize>,   // Request plus response body bytes held for one request (None: no limit)
+    pub spool_threshold: Option<usize>,      // Bodies larger than this go to temp files instead of memory (None: never)
+    pub spool_dir: Option<PathBuf>,          // Where spooled bodies are written (None: the system temp directory)
+    #[serde(rename = "connect_timeout_secs", serialize_with = "serialize_opt_secs")]
+    pub connect_timeout: Option<Duration>,   // TCP/TLS connect budget (None: bounded only by `timeout`)
+    pub pool_max_idle: Option<usize>,        // Idle keep-alive connections kept per target (None: unlimited)
+    #[serde(rename = "pool_idle_timeout_secs", serialize_with = "serialize_secs")]
+    pub pool_idle_timeout: Duration,         // How long an idle pooled connection is kept
+    #[serde(rename = "tcp_keepalive_secs", serialize_with = "serialize_opt_secs")]
+    pub tcp_keepalive: Option<Duration>,     // SO_KEEPALIVE interval on local connections (None: off)
+    pub ca_cert_path: Option<PathBuf>,       // Extra PEM CA bundle trusted for https local targets
+    pub transform_rules_file: Option<PathBuf>, // Request body conversions per path (None: bodies pass as is)
+}
+
+impl LocalConfig {
+    /// Settings read by `from_source`
+    pub const KEYS: [&'static str; 19] = [
+        "LOCAL_PORT",
+        "LOCAL_SCHEME",
+        "LOCAL_HOST",
+        "LOCAL_RESOLVE",
+        "LOCAL_DNS_SERVER",
+        "LOCAL_HTTP_VERSION",
+        "LOCAL_PATH_MODE",
+        "LOCAL_TUNNEL_HEADERS",
+        "LOCAL_TIMEOUT_SECS",
+        "LOCAL_MAX_BODY_BYTES",
+        "LOCAL_MAX_BUFFERED_BYTES",
+        "LOCAL_SPOOL_THRESHOLD_BYTES",
+        "LOCAL_SPOOL_DIR",
+        "LOCAL_CONNECT_TIMEOUT_SECS",
+        "LOCAL_POOL_MAX_IDLE",
+        "LOCAL_POOL_IDLE_TIMEOUT_SECS",
+        "LOCAL_TCP_KEEPALIVE_SECS",
+        "LOCAL_CA_CERT",
+        "LOCAL_TRANSFORM_RULES_FILE",
+    ];
+
+    /// Reads local service options from a key lookup (environment variables, config file)
+    pub fn from_source(get: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let ports_str = get("LOCAL_PORT").unwrap_or_else(|| "3000".to_string());
+        let ports = parse_local_ports(&ports_str)?;
+
+        let scheme = get("LOCAL_SCHEME").unwrap_or_else(|| "http".to_string());
+        if scheme != "http" && scheme != "https" {
+            return Err(format!("Invalid LOCAL_SCHEME: {} (expected http or https)", scheme));
+        }
+
+        let host = get("LOCAL_HOST").unwrap_or_else(|| "127.0.0.1".to_string());
+
+        let resolve_overrides = match get("LOCAL_RESOLVE") {
+            Some(value) => dns::parse_resolve_overrides(&value)?,
+            None => Vec::new(),
+        };
+
+        let dns_server = match get("LOCAL_DNS_SERVER") {
+            Some(value) => Some(dns::parse_dns_server(&value)?),
+            None => None,
+        };
+
+        let http_version = match get("LOCAL_HTTP_VERSION") {
+            Some(value) => parse_local_http_version(&value)?,
+            None => LocalHttpVersion::Auto,
+        };
+
+        let path_mode = match get("LOCAL_PATH_MODE") {
+            Some(value) => parse_local_path_mode(&value)?,
+            None => LocalPathMode::Preserve,
+        };
+
+        let tunnel_headers = match get("LOCAL_TUNNEL_HEADERS") {
+            Some(value) => value.trim().parse()
+                .map_err(|_| format!("Invalid LOCAL_TUNNEL_HEADERS: {} (expected true or false)", value))?,
+            None => false,
+        };
+
+        let timeout_secs = parse_opt::<u64>(&get, "LOCAL_TIMEOUT_SECS")?.unwrap_or(30);
+        let max_body_bytes = parse_opt::<usize>(&get, "LOCAL_MAX_BODY_BYTES")?.unwrap_or(100 * 1024 * 1024);
+        let max_buffered_bytes = parse_opt::<usize>(&get, "LOCAL_MAX_BUFFERED_BYTES")?.filter(|bytes| *bytes > 0);
+        let spool_threshold = parse_opt::<usize>(&get, "LOCAL_SPOOL_THRESHOLD_BYTES")?.filter(|bytes| *bytes > 0);
+        let spool_dir = get("LOCAL_SPOOL_DIR").map(PathBuf::from);
+        let connect_timeout = parse_opt::<u64>(&get, "LOCAL_CONNECT_TIMEOUT_SECS")?.map(Duration::from_secs);
+        let pool_max_idle = parse_opt::<usize>(&get, "LOCAL_POOL_MAX_IDLE")?;
+        let pool_idle_timeout_secs = parse_opt::<u64>(&get, "LOCAL_POOL_IDLE_TIMEOUT_SECS")?.unwrap_or(90);
+
+        // 0 disables keepalive, which is also the default
+        let tcp_keepalive = parse_opt::<u64>(&get, "LOCAL_TCP_KEEPALIVE_SECS")?
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs);
+
+        let ca_cert_path = get("LOCAL_CA_CERT").map(PathBuf::from);
+        let transform_rules_file = get("LOCAL_TRANSFORM_RULES_FILE").map(PathBuf::from);
+
+        Ok(Self {
+            scheme,
+            host,
+            ports,
+            resolve_overrides,
+            dns_server,
+            http_version,
+            path_mode,
+            tunnel_headers,
+            timeout: Duration::from_secs(timeout_secs),
+            max_body_bytes,
+            max_buffered_bytes,
+            spool_threshold,
+            spool_dir,
+            connect_timeout,
+            pool_max_idle,
+            pool_idle_timeout: Duration::from_secs(pool_idle_timeout_secs),
+            tcp_keepalive,
+            ca_cert_path,
+            transform_rules_file,
+        })
+    }
+
+    /// One-line summary of the effective settings for startup/reload logs
+    pub fn summary(&self) -> String {
+        format!(
+            "http_version={:?} path_mode={:?} tunnel_headers={} timeout={:?} max_body={}B max_buffered={} spool={} connect_timeout={} pool_max_idle={} pool_idle_timeout={:?} tcp_keepalive={} extra_ca={} transform_rules={}",
+            self.http_version,
+            self.path_mode,
+            self.tunnel_headers,
+            self.timeout,
+            self.max_body_bytes,
+            self.max_buffered_bytes.map(|n| format!("{}B", n)).unwrap_or_else(|| "unlimited".to_string()),
+            self.spool_threshold.map(|n| format!(">{}B", n)).unwrap_or_else(|| "off".to_string()),
+            self.connect_timeout.map(|d| format!("{:?}", d)).unwrap_or_else(|| "none".to_string()),
+            self.pool_max_idle.map(|n| n.to_string()).unwrap_or_else(|| "unlimited".to_string()),
+            self.pool_idle_timeout,
+            self.tcp_keepalive.map(|d| format!("{:?}", d)).unwrap_or_else(|| "off".to_string()),
+            self.ca_cert_path.as_ref().map(|p| p.display().to_string()).unwrap_or_else(|| "none".to_string()),
+            self.transform_rules_file.as_ref().map(|p| p.display().to_string()).unwrap_or_else(|| "none".to_string()),
+        )
+    }
+}
+
+/// Local HTTP service that tunnel requests are forwarded to
+pub struct LocalService {
+    pub client: reqwest::Client,
+    pub trailer_client: TrailerClient,  // For requests that carry or ask for trailers
+    pub base_urls: Vec<String>, // scheme://host:port per local target, in failover order
+    pub max_body_bytes: usize,  // Largest local response body buffered in memory
+    pub max_buffered_bytes: Option<usize>,  // Request plus response body bytes held for one request
+    pub spool_threshold: Option<usize>,  // Bodies larger than this go to temp files (None: never)
+    pub spool_dir: Option<PathBuf>,  // Where spooled bodies are written (None: the system temp directory)
+    pub timeout: Duration,      // Wall-clock budget for one local request (LOCAL_TIMEOUT_SECS)
+    pub path_mode: LocalPathMode,  // Whether request targets are forwarded unchanged or normalized
+    pub tunnel_headers: bool,   // Add headers describing the tunnel connection to requests
+    pub transforms: Transforms, // Request body conversions per path (LOCAL_TRANSFORM_RULES_FILE)
+}
+
+impl LocalService {
+    pub fn new(config: &LocalConfig) -> Result<Self, String> {
+        let mut builder = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .pool_idle_timeout(config.pool_idle_timeout)
+            .tcp_keepalive(config.tcp_keepalive);
+
+        if let Some(connect_timeout) = config.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+
+        if let Some(max_idle) = config.pool_max_idle {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+
+        let mut ca_certs = Vec::new();
+        if let Some(path) = &config.ca_cert_path {
+            let pem = std::fs::read(path)
+                .map_err(|e| format!("Failed to read LOCAL_CA_CERT {}: {}", path.display(), e))?;
+            let certs = reqwest::Certificate::from_pem_bundle(&pem)
+                .map_err(|e| format!("Invalid certificate in LOCAL_CA_CERT {}: {}", path.display(), e))?;
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+            ca_certs = trailers::pem_certificates(&pem)
+                .map_err(|e| format!("Invalid certificate in LOCAL_CA_CERT {}: {}", path.display(), e))?;
+        }
+
+        builder = match config.http_version {
+            LocalHttpVersion::Auto => builder,
+            LocalHttpVersion::Http1 => builder.http1_only(),
+            LocalHttpVersion::Http2 => builder.http2_prior_knowledge(),
+        };
+
+        // Port is ignored by reqwest for overrides; the URL port is used instead
+        for (host, ip) in &config.resolve_overrides {
+            builder = builder.resolve(host, SocketAddr::new(*ip, 0));
+        }
+
+        let dns = config.dns_server.map(|server| Arc::new(dns::CustomDnsResolver::new(server)));
+        if let Some(dns) = &dns {
+            builder = builder.dns_resolver(dns.clone());
+        }
+
+        let client = builder.build()
+            .map_err(|e| format!("Failed to build local HTTP client: {}", e))?;
+        let trailer_client = TrailerClient::new(config, ca_certs, dns)?;
+
+        if let Some(dir) = &config.spool_dir {
+            if !dir.is_dir() {
+                return Err(format!("LOCAL_SPOOL_DIR {} is not a directory", dir.display()));
+            }
+        }
+
+        let transforms = match &config.transform_rules_file {
+            Some(path) => Transforms::load(path)?,
+            None => Transforms::default(),
+        };
+
+        Ok(Self {
+            client,
+            trailer_client,
+            base_urls: config.ports
+                .iter()
+                .map(|port| format!("{}://{}:{}", config.scheme, url_host(&config.host), port))
+                .collect(),
+            max_body_bytes: config.max_body_bytes,
+            max_buffered_bytes: config.max_buffered_bytes,
+            spool_threshold: config.spool_threshold,
+            spool_dir: config.spool_dir.clone(),
+            timeout: config.timeout,
+            path_mode: config.path_mode,
+            tunnel_headers: config.tunnel_headers,
+            transforms,
+        })
+    }
+}
+
+/// The local service requests are forwarded to, with the settings it was built from
+///
+/// The tunnel reads the current service for every request, so replacing it
+/// (a config file reload, or the `local-port` control command) redirects new
+/// requests without dropping the tunnel; requests under way finish against the old one.
+pub struct LocalTarget {
+    config: Mutex<LocalConfig>,
+    service: watch::Sender<Arc<LocalService>>,
+}
+
+impl LocalTarget {
+    /// Shares `service`, built from `config`, returning the receiver the tunnel reads it from
+    pub fn new(config: LocalConfig, service: LocalService) -> (Arc<Self>, watch::Receiver<Arc<LocalService>>) {
+        let (service, service_rx) = watch::channel(Arc::new(service));
+        (Arc::new(Self { config: Mutex::new(config), service }), service_rx)
+    }
+
+    /// Swaps in `service`, built from `config`
+    pub fn replace(&self, config: LocalConfig, service: LocalService) {
+        let mut current = self.config.lock().unwrap();
+        *current = config;
+        self.service.send_replace(Arc::new(service));
+    }
+
+    /// Forwards to `ports` (as in LOCAL_PORT) from now on, keeping every other setting
+    ///
+    /// # Returns
+    /// The new local targets, in failover order
+    pub fn set_ports(&self, ports: &str) -> Result<Vec<String>, String> {
+        let ports = parse_local_ports(ports)?;
+        let mut config = self.config.lock().unwrap();
+        let previous = std::mem::replace(&mut config.ports, ports);
+        match LocalService::new(&config) {
+            Ok(service) => {
+                let base_urls = service.base_urls.clone();
+                self.service.send_replace(Arc::new(service));
+                Ok(base_urls)
+            }
+            Err(e) => {
+                config.ports = previous;
+                Err(e)
+            }
+        }
+    }
+
+    /// Where requests are forwarded, in failover order
+    pub fn base_urls(&self) -> Vec<String> {
+        self.service.borrow().base_urls.clone()
+    }
+}
+
+/// Parses an optional numeric setting, naming the key in the error
+fn parse_opt<T: FromStr>(get: &impl Fn(&str) -> Option<String>, key: &str) -> Result<Option<T>, String> {
+    match get(key) {
+        Some(value) => value.trim().parse::<T>()
+            .map(Some)
+            .map_err(|_| format!("Invalid {}: {}", key, value)),
+        None => Ok(None),
+    }
+}
+
+/// Parses LOCAL_PORT: a single port or an ordered, comma-separated failover list ("3000,3001")
+fn parse_local_ports(value: &str) -> Result<Vec<u16>, String> {
+    let ports = value
+        .split(',')
+        .map(str::trim)
+        .map(|port| port.parse::<u16>().map_err(|e| format!("Invalid LOCAL_PORT '{}': {}", port, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if ports.is_empty() {
+        return Err("LOCAL_PORT must list at least one port".to_string());
+    }
+
+    Ok(ports)
+}
+
+/// Formats a host for use in a URL, bracketing IPv6 literals
+fn url_host(host: &str) -> String {
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => format!("[{}]", host),
+        _ => host.to_string(),
+    }
+}
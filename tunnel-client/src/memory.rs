@@ -0,0 +1,78 @@
+//! Memory watchdog for long-running clients on small devices.
+//!
+//! With `MEMORY_LIMIT_BYTES` set, [`watch`] compares the resident memory of the
+//! process against the limit every few seconds. Going over logs a warning,
+//! counts it in the stats reported to the server, and asks the connection
+//! handler to release the buffers it keeps between requests.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// How often `watch` checks memory use
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Times memory use went over the limit
+static WARNINGS: AtomicU64 = AtomicU64::new(0);
+
+/// Whether memory use was over the limit at the last check
+static OVER_LIMIT: AtomicBool = AtomicBool::new(false);
+
+/// Set when buffers should be released, cleared by `take_pressure`
+static PRESSURE: AtomicBool = AtomicBool::new(false);
+
+/// Checks memory use against `limit_bytes` until the process exits
+pub async fn watch(limit_bytes: u64) {
+    if rss_bytes().is_none() {
+        warn!("MEMORY_LIMIT_BYTES is only supported on Linux; ignoring it");
+        return;
+    }
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        check(limit_bytes);
+    }
+}
+
+/// Compares current memory use with `limit_bytes` once; returns whether it is over
+pub fn check(limit_bytes: u64) -> bool {
+    let Some(rss) = rss_bytes() else {
+        return false;
+    };
+    let over = rss > limit_bytes;
+    let was_over = OVER_LIMIT.swap(over, Ordering::Relaxed);
+    if over {
+        PRESSURE.store(true, Ordering::Relaxed);
+        if !was_over {
+            WARNINGS.fetch_add(1, Ordering::Relaxed);
+            warn!("Memory use {} bytes is over MEMORY_LIMIT_BYTES ({}); releasing buffers", rss, limit_bytes);
+        }
+    } else if was_over {
+        info!("Memory use {} bytes is back under MEMORY_LIMIT_BYTES ({})", rss, limit_bytes);
+    }
+    over
+}
+
+/// Times memory use went over the limit since the client started
+pub fn warnings() -> u64 {
+    WARNINGS.load(Ordering::Relaxed)
+}
+
+/// Whether buffers should be released; true once per check that found memory over the limit
+pub fn take_pressure() -> bool {
+    PRESSURE.swap(false, Ordering::Relaxed)
+}
+
+/// Resident memory of this process, from /proc on Linux
+#[cfg(target_os = "linux")]
+pub fn rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn rss_bytes() -> Option<u64> {
+    None
+}
@@ -0,0 +1,80 @@
+//! Request target sent to the local service.
+//!
+//! The path and query reach the local service exactly as the visitor sent
+//! them, repeated parameters and percent-encoding included, since webhook
+//! signature schemes sign them byte for byte. The local HTTP client parses
+//! every target as a URL, which resolves dot segments, turns `\` into `/` and
+//! percent-encodes a few characters (`"`, `<` and `>`; `{` and `}` in the
+//! path); a target it would change is refused instead of being forwarded
+//! altered. `LOCAL_PATH_MODE=normalize` normalizes targets instead (RFC 3986
+//! section 6.2.2) and forwards the result.
+
+use serde::Serialize;
+
+/// Origin the target is parsed against; only the path and query are kept
+const PARSE_BASE: &str = "http://localhost";
+
+/// How request targets are passed to the local service
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LocalPathMode {
+    Preserve,   // Byte for byte; targets the local client would change are refused
+    Normalize,  // Percent-encoding and dot segments normalized
+}
+
+/// Parses LOCAL_PATH_MODE value
+pub fn parse_local_path_mode(value: &str) -> Result<LocalPathMode, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "preserve" => Ok(LocalPathMode::Preserve),
+        "normalize" => Ok(LocalPathMode::Normalize),
+        other => Err(format!("Invalid LOCAL_PATH_MODE: {} (expected preserve or normalize)", other)),
+    }
+}
+
+/// Path and query to send for `target`; None if it cannot be sent unchanged in preserve mode
+pub fn local_path(target: &str, mode: LocalPathMode) -> Option<String> {
+    match mode {
+        LocalPathMode::Preserve => {
+            let sent = as_sent(target)?;
+            (sent == target).then_some(sent)
+        }
+        LocalPathMode::Normalize => as_sent(&normalize_percent_encoding(target)),
+    }
+}
+
+/// What the local HTTP client would put on the request line for `target`
+fn as_sent(target: &str) -> Option<String> {
+    let url = reqwest::Url::parse(&format!("{}{}", PARSE_BASE, target)).ok()?;
+    url.as_str().strip_prefix(PARSE_BASE).map(str::to_string)
+}
+
+/// Decodes percent-encoded unreserved characters and uppercases the hex digits of the rest
+fn normalize_percent_encoding(target: &str) -> String {
+    let bytes = target.as_bytes();
+    let mut normalized = String::with_capacity(target.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes.get(i + 1..i + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .filter(|_| bytes[i] == b'%');
+        match escape {
+            Some(hex) => {
+                let hex = std::str::from_utf8(hex).unwrap_or_default().to_ascii_uppercase();
+                let byte = u8::from_str_radix(&hex, 16).unwrap_or_default();
+                if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+                    normalized.push(byte as char);
+                } else {
+                    normalized.push('%');
+                    normalized.push_str(&hex);
+                }
+                i += 3;
+            }
+            None => {
+                // Targets are ASCII (validate_path), so every byte is a char
+                normalized.push(bytes[i] as char);
+                i += 1;
+            }
+        }
+    }
+    normalized
+}
@@ -0,0 +1,95 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How far back frame errors count towards the quality grade
+const ERROR_WINDOW: Duration = Duration::from_secs(600);
+
+/// Tracks tunnel link health (round-trip time and recent frame errors) so that
+/// "my app is slow" can be told apart from "my link to the server is bad"
+#[derive(Default)]
+pub struct LinkQuality {
+    last_rtt: Option<Duration>,
+    recent_errors: VecDeque<Instant>,
+    total_errors: u64,
+}
+
+/// Coarse link quality grade shown in logs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QualityGrade {
+    Good,
+    Degraded,
+    Poor,
+}
+
+impl fmt::Display for QualityGrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QualityGrade::Good => write!(f, "good"),
+            QualityGrade::Degraded => write!(f, "degraded"),
+            QualityGrade::Poor => write!(f, "poor"),
+        }
+    }
+}
+
+impl LinkQuality {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a measured round trip to the server
+    pub fn record_rtt(&mut self, rtt: Duration) {
+        self.last_rtt = Some(rtt);
+    }
+
+    /// Records a frame read/write/decode error on the tunnel
+    pub fn record_error(&mut self) {
+        self.total_errors += 1;
+        self.recent_errors.push_back(Instant::now());
+        self.prune();
+    }
+
+    /// Number of frame errors within the last ten minutes
+    pub fn recent_errors(&mut self) -> usize {
+        self.prune();
+        self.recent_errors.len()
+    }
+
+    /// Grades the link from the latest RTT and recent error count
+    pub fn grade(&mut self) -> QualityGrade {
+        let errors = self.recent_errors();
+        let rtt_ms = self.last_rtt.map(|rtt| rtt.as_millis()).unwrap_or(0);
+
+        if errors >= 5 || rtt_ms >= 1000 {
+            QualityGrade::Poor
+        } else if errors >= 1 || rtt_ms >= 250 {
+            QualityGrade::Degraded
+        } else {
+            QualityGrade::Good
+        }
+    }
+
+    /// One-line summary for logging
+    pub fn summary(&mut self) -> String {
+        let grade = self.grade();
+        let rtt = match self.last_rtt {
+            Some(rtt) => format!("{}ms", rtt.as_millis()),
+            None => "n/a".to_string(),
+        };
+        format!(
+            "rtt={} recent_frame_errors={} total_frame_errors={} quality={}",
+            rtt, self.recent_errors.len(), self.total_errors, grade
+        )
+    }
+
+    fn prune(&mut self) {
+        let now = Instant::now();
+        while let Some(oldest) = self.recent_errors.front() {
+            if now.duration_since(*oldest) > ERROR_WINDOW {
+                self.recent_errors.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+}
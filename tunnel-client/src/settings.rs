@@ -0,0 +1,240 @@
+//! Client settings resolved from the command line, config file and environment.
+
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tunnel_core::client::{parse_server_addr, ServerConfig};
+use tunnel_core::config::{parse_tunnel_path, parse_upgrade_secret, serialize_redacted, ConfigSource};
+use tunnel_core::logging::LogOptions;
+use tunnel_core::tls::{parse_cert_pins, CertPin, ClientCert, TlsOptions};
+use tunnel_core::transport::TransportOptions;
+use tunnel_protocol::{parse_cors_origins, parse_label, CompressTypes, Schedule, DEFAULT_TUNNEL_PATH};
+
+use crate::capture::CaptureOptions;
+use crate::local::LocalConfig;
+
+/// Default for TUNNEL_MAX_CONCURRENT
+pub const DEFAULT_MAX_CONCURRENT: usize = 32;
+
+/// Default for TUNNEL_USER_AGENT: the client's version, OS and architecture
+pub fn default_user_agent() -> String {
+    format!("speedforce-client/{} ({}; {})", env!("CARGO_PKG_VERSION"), std::env::consts::OS, std::env::consts::ARCH)
+}
+
+/// Parses TUNNEL_USER_AGENT; empty means none is sent
+fn parse_user_agent(value: &str) -> Result<Option<String>, String> {
+    let value = value.trim();
+    if !value.bytes().all(|b| b == b' ' || b.is_ascii_graphic()) {
+        return Err(format!("Invalid TUNNEL_USER_AGENT: {} (expected printable ASCII)", value.escape_debug()));
+    }
+    Ok(Some(value.to_string()).filter(|value| !value.is_empty()))
+}
+
+/// Effective client configuration
+#[derive(Serialize)]
+pub struct ClientSettings {
+    pub server_addr: String,             // Tunnel server address (see parse_server_addr)
+    pub tunnel_path: String,             // Upgrade endpoint on the server
+    #[serde(serialize_with = "serialize_redacted")]
+    pub tunnel_auth: Option<String>,     // username:password for Basic Auth
+    #[serde(serialize_with = "serialize_redacted")]
+    pub upgrade_secret: Option<String>,  // Preshared secret the server requires at upgrade
+    #[serde(serialize_with = "serialize_redacted")]
+    pub visitor_auth: Option<String>,    // username:password the server requires of visitors
+    pub https_only: bool,                // Server redirects plain-HTTP visitors to HTTPS
+    pub cors_origins: Vec<String>,       // Origins the server answers CORS for, or `*` (empty: off)
+    pub schedule: Option<Schedule>,      // Windows the server routes visitors in (None: always)
+    pub labels: Vec<(String, String)>,   // Tunnel labels sent to the server at handshake
+    pub transport: TransportOptions,
+    pub max_concurrent: usize,           // Requests handled at once when the server multiplexes (1: one at a time)
+    pub binary_frames: bool,             // Offer to exchange bodies as they are rather than base64 in JSON
+    pub stream_bodies: bool,             // Offer to stream bodies past TUNNEL_STREAM_THRESHOLD_BYTES
+    pub user_agent: Option<String>,      // Sent on the upgrade request (None: not sent)
+    pub compress_types: CompressTypes,   // Overrides of the media types sent uncompressed (see should_compress)
+    pub tls: TlsOptions,                 // Used for https:// server addresses
+    pub cert_pins: Vec<CertPin>,         // Server certificate fingerprints accepted (empty: any trusted one)
+    pub client_cert_file: Option<PathBuf>, // PEM certificate presented to servers that ask for one (None: none)
+    pub client_key_file: Option<PathBuf>,  // PEM private key matching the client certificate
+    pub local: LocalConfig,
+    pub capture: CaptureOptions,         // What is kept of recent requests for inspection (see capture)
+    pub redact_rules_file: Option<PathBuf>, // Redaction rules for captures and the access log (None: credentials only)
+    pub log: LogOptions,
+    pub control_socket: Option<PathBuf>, // Unix socket for runtime commands (see control)
+    pub memory_limit: Option<u64>,       // Resident memory above which buffers are released (see memory)
+}
+
+impl ClientSettings {
+    /// Every setting the client understands
+    pub fn keys() -> Vec<&'static str> {
+        let mut keys = vec![
+            "SERVER_ADDR", "TUNNEL_PATH", "TUNNEL_AUTH", "TUNNEL_UPGRADE_SECRET", "VISITOR_AUTH", "HTTPS_ONLY", "CORS_ORIGINS", "TUNNEL_LABELS",
+            "TUNNEL_SCHEDULE", "TUNNEL_MAX_CONCURRENT", "TUNNEL_BINARY_FRAMES", "TUNNEL_STREAM_BODIES", "CONTROL_SOCKET", "MEMORY_LIMIT_BYTES",
+            "TUNNEL_USER_AGENT", "COMPRESS_TYPES", "NO_COMPRESS_TYPES",
+        ];
+        keys.extend(TransportOptions::KEYS);
+        keys.extend(TlsOptions::KEYS);
+        keys.push("SERVER_CERT_PIN");
+        keys.push("CLIENT_CERT_FILE");
+        keys.push("CLIENT_KEY_FILE");
+        keys.extend(LocalConfig::KEYS);
+        keys.extend(CaptureOptions::KEYS);
+        keys.push("REDACT_RULES_FILE");
+        keys.extend(LogOptions::KEYS);
+        keys
+    }
+
+    /// Resolves and validates the settings
+    pub fn from_source(source: &ConfigSource) -> Result<Self, String> {
+        let tunnel_auth = source.get("TUNNEL_AUTH");
+        if let Some(auth) = &tunnel_auth {
+            if !auth.contains(':') {
+                return Err("TUNNEL_AUTH must be in format 'username:password'".to_string());
+            }
+        }
+
+        let visitor_auth = source.get("VISITOR_AUTH");
+        if let Some(auth) = &visitor_auth {
+            if !auth.contains(':') {
+                return Err("VISITOR_AUTH must be in format 'username:password'".to_string());
+            }
+        }
+
+        let https_only = match source.get("HTTPS_ONLY") {
+            Some(value) => value.trim().parse()
+                .map_err(|_| format!("Invalid HTTPS_ONLY: {} (expected true or false)", value))?,
+            None => false,
+        };
+
+        let cors_origins = match source.get("CORS_ORIGINS") {
+            Some(value) => parse_cors_origins(&value).map_err(|e| format!("Invalid CORS_ORIGINS: {}", e))?,
+            None => Vec::new(),
+        };
+
+        let schedule = source.get("TUNNEL_SCHEDULE")
+            .map(|value| Schedule::parse(&value).map_err(|e| format!("Invalid TUNNEL_SCHEDULE: {}", e)))
+            .transpose()?;
+
+        // Parse tunnel labels ("key=value,key=value")
+        let labels = source.get("TUNNEL_LABELS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|label| !label.is_empty())
+            .map(parse_label)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Invalid TUNNEL_LABELS: {}", e))?;
+
+        let cert_pins = match source.get("SERVER_CERT_PIN") {
+            Some(value) => parse_cert_pins(&value).map_err(|e| format!("Invalid SERVER_CERT_PIN: {}", e))?,
+            None => Vec::new(),
+        };
+
+        let client_cert_file = source.get("CLIENT_CERT_FILE").map(PathBuf::from);
+        let client_key_file = source.get("CLIENT_KEY_FILE").map(PathBuf::from);
+        if client_cert_file.is_some() != client_key_file.is_some() {
+            return Err("CLIENT_CERT_FILE and CLIENT_KEY_FILE must be set together".to_string());
+        }
+
+        let max_concurrent = match source.get("TUNNEL_MAX_CONCURRENT") {
+            Some(value) => value.trim().parse().ok().filter(|max| *max > 0)
+                .ok_or_else(|| format!("Invalid TUNNEL_MAX_CONCURRENT: {} (expected a positive number)", value))?,
+            None => DEFAULT_MAX_CONCURRENT,
+        };
+
+        let binary_frames = match source.get("TUNNEL_BINARY_FRAMES") {
+            Some(value) => value.trim().parse()
+                .map_err(|_| format!("Invalid TUNNEL_BINARY_FRAMES: {} (expected true or false)", value))?,
+            None => true,
+        };
+
+        let stream_bodies = match source.get("TUNNEL_STREAM_BODIES") {
+            Some(value) => value.trim().parse()
+                .map_err(|_| format!("Invalid TUNNEL_STREAM_BODIES: {} (expected true or false)", value))?,
+            None => true,
+        };
+
+        let user_agent = match source.get("TUNNEL_USER_AGENT") {
+            Some(value) => parse_user_agent(&value)?,
+            None => Some(default_user_agent()),
+        };
+
+        let compress_types = CompressTypes {
+            compress: source.get("COMPRESS_TYPES").map(|value| CompressTypes::parse_list(&value)).transpose()
+                .map_err(|e| format!("Invalid COMPRESS_TYPES: {}", e))?.unwrap_or_default(),
+            skip: source.get("NO_COMPRESS_TYPES").map(|value| CompressTypes::parse_list(&value)).transpose()
+                .map_err(|e| format!("Invalid NO_COMPRESS_TYPES: {}", e))?.unwrap_or_default(),
+        };
+
+        let memory_limit = match source.get("MEMORY_LIMIT_BYTES") {
+            Some(value) => {
+                let bytes: u64 = value.trim().parse()
+                    .map_err(|_| format!("Invalid MEMORY_LIMIT_BYTES: {}", value))?;
+                (bytes > 0).then_some(bytes)
+            }
+            None => None,
+        };
+
+        let settings = Self {
+            server_addr: source.get("SERVER_ADDR").unwrap_or_else(|| "127.0.0.1:7000".to_string()),
+            tunnel_path: source.get("TUNNEL_PATH").map_or(Ok(DEFAULT_TUNNEL_PATH.to_string()), |path| parse_tunnel_path(&path))?,
+            tunnel_auth,
+            upgrade_secret: source.get("TUNNEL_UPGRADE_SECRET").map(|secret| parse_upgrade_secret(&secret)).transpose()?,
+            visitor_auth,
+            https_only,
+            cors_origins,
+            schedule,
+            labels,
+            transport: TransportOptions::from_source(|key| source.get(key))?,
+            max_concurrent,
+            binary_frames,
+            stream_bodies,
+            user_agent,
+            compress_types,
+            tls: TlsOptions::from_source(|key| source.get(key))?,
+            cert_pins,
+            client_cert_file,
+            client_key_file,
+            local: LocalConfig::from_source(|key| source.get(key))?,
+            capture: CaptureOptions::from_source(|key| source.get(key))?,
+            redact_rules_file: source.get("REDACT_RULES_FILE").map(PathBuf::from),
+            log: LogOptions::from_source(|key| source.get(key))?,
+            control_socket: source.get("CONTROL_SOCKET").map(PathBuf::from),
+            memory_limit,
+        };
+
+        // Validate the server address up front so --check-config catches it
+        settings.server_config()?;
+        Ok(settings)
+    }
+
+    /// Builds the tunnel server connection settings
+    pub fn server_config(&self) -> Result<ServerConfig, String> {
+        let mut config = parse_server_addr(&self.server_addr, self.tunnel_auth.clone(), self.labels.clone())
+            .map_err(|e| format!("Invalid SERVER_ADDR: {}", e))?;
+        config.path = self.tunnel_path.clone();
+        config.upgrade_secret = self.upgrade_secret.clone();
+        config.visitor_auth = self.visitor_auth.clone();
+        config.https_only = self.https_only;
+        config.cors_origins = self.cors_origins.clone();
+        config.schedule = self.schedule.clone();
+        config.transport = self.transport.clone();
+        config.max_concurrent = self.max_concurrent;
+        config.binary_frames = self.binary_frames;
+        config.stream_bodies = self.stream_bodies;
+        config.heartbeat = self.transport.heartbeat_interval.is_some();
+        config.user_agent = self.user_agent.clone();
+        config.compress_types = self.compress_types.clone();
+        config.tls = self.tls.clone();
+        if !self.cert_pins.is_empty() && !config.use_tls {
+            return Err("SERVER_CERT_PIN requires an https:// SERVER_ADDR".to_string());
+        }
+        config.cert_pins = self.cert_pins.clone();
+        if let (Some(cert_path), Some(key_path)) = (&self.client_cert_file, &self.client_key_file) {
+            if !config.use_tls {
+                return Err("CLIENT_CERT_FILE requires an https:// SERVER_ADDR".to_string());
+            }
+            config.client_cert = Some(Arc::new(ClientCert::load(cert_path, key_path)?));
+        }
+        Ok(config)
+    }
+}
@@ -0,0 +1,165 @@
+//! Bodies too large to hold in memory, kept in temp files (LOCAL_SPOOL_THRESHOLD_BYTES).
+//!
+//! A request body above the threshold is decoded (or, from a binary frame, copied) into a file and streamed to
+//! the local service from there; a response body is written to a file once it
+//! grows past the threshold and streamed into the tunnel piece by piece. Only
+//! a chunk of either is in memory at a time. Files live in LOCAL_SPOOL_DIR (or
+//! the system temp directory) and are removed as soon as the request is done.
+
+use bytes::Bytes;
+use std::io;
+use std::path::Path;
+use tempfile::TempPath;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::Instrument;
+use tunnel_core::error_dedup;
+use tunnel_core::framing::MessageError;
+use tunnel_protocol::{binary_head, decode_body, encode_body, encoded_body_len, BodySha256, FrameWriter, ProtocolError, TunnelResponse};
+
+/// Body bytes read or written at a time; a multiple of 3, so each chunk encodes without padding
+const CHUNK_BYTES: usize = 48 * 1024;
+
+/// A body spooled to a temp file, removed when dropped
+pub struct SpooledBody {
+    path: TempPath,
+    len: u64,
+}
+
+impl SpooledBody {
+    /// Length of the body in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Opens the body for reading from the start
+    pub async fn open(&self) -> io::Result<tokio::fs::File> {
+        tokio::fs::File::open(&self.path).await
+    }
+
+    /// The body for a request sent with hyper (see `trailers`), read from the start as it is sent
+    pub async fn to_hyper(&self) -> io::Result<hyper::Body> {
+        let mut file = self.open().await?;
+        let (mut sender, body) = hyper::Body::channel();
+        tokio::spawn(async move {
+            let mut chunk = vec![0; CHUNK_BYTES];
+            loop {
+                match file.read(&mut chunk).await {
+                    Ok(0) => return,
+                    Ok(n) => {
+                        if sender.send_data(Bytes::copy_from_slice(&chunk[..n])).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        error_dedup!("Failed to read spooled request body: {}", e);
+                        sender.abort();
+                        return;
+                    }
+                }
+            }
+        }.in_current_span());
+        Ok(body)
+    }
+}
+
+/// A spool file being written
+pub struct SpoolWriter {
+    file: tokio::fs::File,
+    path: TempPath,
+    len: u64,
+}
+
+impl SpoolWriter {
+    /// Creates an empty spool file in `dir` (None: the system temp directory)
+    pub fn create(dir: Option<&Path>) -> io::Result<Self> {
+        let mut builder = tempfile::Builder::new();
+        builder.prefix("tunnel-spool-");
+        let file = match dir {
+            Some(dir) => builder.tempfile_in(dir)?,
+            None => builder.tempfile()?,
+        };
+        let (file, path) = file.into_parts();
+        Ok(Self { file: tokio::fs::File::from_std(file), path, len: 0 })
+    }
+
+    pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.file.write_all(chunk).await?;
+        self.len += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// Length written so far
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Flushes the file, ready to be read back
+    pub async fn finish(mut self) -> io::Result<SpooledBody> {
+        self.file.flush().await?;
+        Ok(SpooledBody { path: self.path, len: self.len })
+    }
+}
+
+/// Decodes a base64 request body into a spool file, returning it with its `body_sha256`
+pub async fn decode_to_file(encoded: &str, dir: Option<&Path>) -> io::Result<(SpooledBody, String)> {
+    let mut writer = SpoolWriter::create(dir)?;
+    let mut hasher = BodySha256::default();
+    // Base64 decodes in groups of 4 characters
+    for chunk in encoded.as_bytes().chunks(CHUNK_BYTES / 3 * 4) {
+        let chunk = std::str::from_utf8(chunk).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let decoded = decode_body(chunk).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        hasher.update(&decoded);
+        writer.write(&decoded).await?;
+    }
+    Ok((writer.finish().await?, hasher.finish()))
+}
+
+/// Writes a request body that arrived as is (in a binary frame) to a spool file, returning it with its `body_sha256`
+pub async fn save_to_file(body: &[u8], dir: Option<&Path>) -> io::Result<(SpooledBody, String)> {
+    let mut writer = SpoolWriter::create(dir)?;
+    writer.write(body).await?;
+    Ok((writer.finish().await?, tunnel_protocol::body_sha256(body)))
+}
+
+/// Writes `response` as one frame with `body` streamed from its spool file as the body,
+/// as is in a binary frame or base64 in JSON
+pub async fn send_response<W: AsyncWrite + Unpin>(
+    writer: &mut FrameWriter<W>,
+    response: &TunnelResponse,
+    body: &SpooledBody,
+    binary: bool,
+) -> Result<(), MessageError> {
+    let (head, tail) = match binary {
+        true => (binary_head(response).map_err(MessageError::Encode)?, &[][..]),
+        false => response.json_around_body().map_err(MessageError::Encode)?,
+    };
+    let body_len = if binary { body.len as usize } else { encoded_body_len(body.len as usize) };
+    let len = head.len() + body_len + tail.len();
+    writer.start_frame(len).await.map_err(MessageError::Write)?;
+    writer.write_payload(&head).await.map_err(MessageError::Write)?;
+
+    let mut file = body.open().await.map_err(|e| MessageError::Write(ProtocolError::Io(e)))?;
+    let mut chunk = vec![0; CHUNK_BYTES];
+    let mut remaining = body.len;
+    while remaining > 0 {
+        let want = CHUNK_BYTES.min(remaining as usize);
+        file.read_exact(&mut chunk[..want]).await.map_err(|e| MessageError::Write(ProtocolError::Io(e)))?;
+        let piece = &chunk[..want];
+        match binary {
+            true => writer.write_payload(piece).await,
+            false => writer.write_payload(encode_body(piece).as_bytes()).await,
+        }
+        .map_err(MessageError::Write)?;
+        remaining -= want as u64;
+    }
+
+    writer.write_payload(tail).await.map_err(MessageError::Write)
+}
@@ -0,0 +1,92 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tunnel_protocol::StatsReport;
+
+use crate::{capture, memory};
+
+/// How many recent requests the latency percentiles are computed over
+const LATENCY_SAMPLES: usize = 1024;
+
+/// How many tag keys are counted; tags with other keys are left out of the reports
+const MAX_TAG_KEYS: usize = 32;
+
+/// Request bodies that did not match their checksum, across connections
+static CHECKSUM_MISMATCHES: AtomicU64 = AtomicU64::new(0);
+
+/// Counts a request body that did not match its checksum
+pub fn record_checksum_mismatch() {
+    CHECKSUM_MISMATCHES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts requests forwarded to the local service and their latencies, for
+/// the stats reports sent to the server
+pub struct LocalStats {
+    started: Instant,
+    requests: u64,
+    errors: u64,
+    latencies_ms: VecDeque<u64>,
+    tagged: BTreeMap<String, u64>,
+}
+
+impl Default for LocalStats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            requests: 0,
+            errors: 0,
+            latencies_ms: VecDeque::with_capacity(LATENCY_SAMPLES),
+            tagged: BTreeMap::new(),
+        }
+    }
+}
+
+impl LocalStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records one request answered with `status` after `elapsed`, tagged by the local service with `tags`
+    pub fn record(&mut self, status: u16, elapsed: Duration, tags: &[(String, String)]) {
+        for (key, _) in tags {
+            if let Some(count) = self.tagged.get_mut(key) {
+                *count += 1;
+            } else if self.tagged.len() < MAX_TAG_KEYS {
+                self.tagged.insert(key.clone(), 1);
+            }
+        }
+        self.requests += 1;
+        if status >= 500 {
+            self.errors += 1;
+        }
+        if self.latencies_ms.len() == LATENCY_SAMPLES {
+            self.latencies_ms.pop_front();
+        }
+        self.latencies_ms.push_back(elapsed.as_millis() as u64);
+    }
+
+    /// Builds a report of everything recorded so far
+    pub fn report(&self) -> StatsReport {
+        let mut sorted: Vec<u64> = self.latencies_ms.iter().copied().collect();
+        sorted.sort_unstable();
+        let percentile = |p: usize| match sorted.len() {
+            0 => 0,
+            len => sorted[(len * p / 100).min(len - 1)],
+        };
+
+        StatsReport {
+            requests: self.requests,
+            errors: self.errors,
+            latency_p50_ms: percentile(50),
+            latency_p90_ms: percentile(90),
+            latency_p99_ms: percentile(99),
+            uptime_secs: self.started.elapsed().as_secs(),
+            rss_bytes: memory::rss_bytes(),
+            memory_warnings: memory::warnings(),
+            checksum_mismatches: CHECKSUM_MISMATCHES.load(Ordering::Relaxed),
+            capture_evictions: capture::evictions(),
+            compression: None,  // Set by the connection sending the report
+            tagged: self.tagged.clone(),
+        }
+    }
+}
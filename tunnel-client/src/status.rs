@@ -0,0 +1,211 @@
+//! Connection state of a running client, reported by the `status` control
+//! command so scripts can wait for the tunnel to be ready, and the requests
+//! the local service is working on, listed by the `in-flight` one with the
+//! bytes they have moved so far.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tunnel_core::client::ServerConfig;
+
+/// Where the client is in its connection loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    Connecting,    // First connection attempt
+    Connected,     // Tunnel is up and serving requests
+    Reconnecting,  // Connection lost or refused; retrying with backoff
+    Stopped,       // Shut down, or gave up after a permanent failure
+}
+
+/// Snapshot reported by the `status` control command
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    pub state: ConnectionState,
+    pub ready: bool,                 // Connected: visitors reach the local service
+    pub server_addr: String,
+    pub public_url: String,          // Where visitors reach the tunnel
+    pub tunnel_id: Option<u64>,      // Announced by the server (None: not connected, or not announced)
+    pub connected_at: Option<u64>,   // Unix timestamp (seconds) of the current connection
+    pub connections: u64,            // Tunnels established since the client started
+    pub requests: u64,               // Requests forwarded to the local service
+    pub errors: u64,                 // Of which got a 5xx
+    pub last_error: Option<String>,  // Why the last connection attempt or connection failed
+}
+
+/// Request listed by the `in-flight` control command
+#[derive(Debug, Clone, Serialize)]
+pub struct InFlightRequest {
+    pub id: Option<u64>,               // Given by the server (None: it does not number requests)
+    pub method: String,
+    pub path: String,                  // Redacted, without the query
+    pub elapsed_ms: u64,               // Since the request arrived through the tunnel
+    pub request_bytes: u64,            // Request body bytes received through the tunnel
+    pub request_total: Option<u64>,    // Request Content-Length (None: not declared)
+    pub response_bytes: u64,           // Response body bytes read from the local service
+}
+
+/// Bytes moved so far for one request, counted by whoever moves them
+#[derive(Debug, Default)]
+pub struct Transfer {
+    request_bytes: AtomicU64,
+    response_bytes: AtomicU64,
+}
+
+impl Transfer {
+    /// Counts request body bytes received through the tunnel
+    pub fn add_request_bytes(&self, bytes: u64) {
+        self.request_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Counts response body bytes read from the local service
+    pub fn add_response_bytes(&self, bytes: u64) {
+        self.response_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+struct Tracked {
+    id: Option<u64>,
+    method: String,
+    path: String,
+    started: Instant,
+    request_total: Option<u64>,
+    transfer: Arc<Transfer>,
+}
+
+#[derive(Default)]
+struct InFlight {
+    next_key: u64,
+    requests: BTreeMap<u64, Tracked>,  // By order of arrival
+}
+
+/// Keeps a request listed by the `in-flight` control command until dropped
+pub struct TrackedRequest {
+    in_flight: Arc<Mutex<InFlight>>,
+    key: u64,
+    transfer: Arc<Transfer>,
+}
+
+impl TrackedRequest {
+    /// Where the bytes moved for the request are counted
+    pub fn transfer(&self) -> &Arc<Transfer> {
+        &self.transfer
+    }
+}
+
+impl Drop for TrackedRequest {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().requests.remove(&self.key);
+    }
+}
+
+/// Shared, updatable status of one client
+#[derive(Clone)]
+pub struct StatusHandle {
+    status: Arc<Mutex<Status>>,
+    in_flight: Arc<Mutex<InFlight>>,
+}
+
+impl StatusHandle {
+    /// Starts in the `Connecting` state for `config`
+    pub fn new(config: &ServerConfig) -> Self {
+        let status = Arc::new(Mutex::new(Status {
+            state: ConnectionState::Connecting,
+            ready: false,
+            server_addr: config.addr.clone(),
+            public_url: config.public_url(),
+            tunnel_id: None,
+            connected_at: None,
+            connections: 0,
+            requests: 0,
+            errors: 0,
+            last_error: None,
+        }));
+        Self { status, in_flight: Arc::default() }
+    }
+
+    /// Current status
+    pub fn snapshot(&self) -> Status {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Records a new tunnel connection
+    pub fn connected(&self, tunnel_id: Option<u64>) {
+        let mut status = self.status.lock().unwrap();
+        status.connections += 1;
+        status.tunnel_id = tunnel_id;
+        status.connected_at = Some(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+        Self::set_state(&mut status, ConnectionState::Connected);
+    }
+
+    /// Records that the tunnel went down, or a connection attempt failed with `error`
+    pub fn disconnected(&self, error: Option<String>) {
+        let mut status = self.status.lock().unwrap();
+        status.tunnel_id = None;
+        status.connected_at = None;
+        if error.is_some() {
+            status.last_error = error;
+        }
+        let state = if status.connections == 0 { ConnectionState::Connecting } else { ConnectionState::Reconnecting };
+        Self::set_state(&mut status, state);
+    }
+
+    /// Records that the client stopped, after a permanent failure if `error` is set
+    pub fn stopped(&self, error: Option<String>) {
+        let mut status = self.status.lock().unwrap();
+        status.tunnel_id = None;
+        status.connected_at = None;
+        if error.is_some() {
+            status.last_error = error;
+        }
+        Self::set_state(&mut status, ConnectionState::Stopped);
+    }
+
+    /// Counts one request answered with `status_code`
+    pub fn record_request(&self, status_code: u16) {
+        let mut status = self.status.lock().unwrap();
+        status.requests += 1;
+        if status_code >= 500 {
+            status.errors += 1;
+        }
+    }
+
+    /// Lists a request until the returned handle is dropped
+    pub fn track(&self, id: Option<u64>, method: &str, path: &str, request_total: Option<u64>) -> TrackedRequest {
+        let transfer = Arc::new(Transfer::default());
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let key = in_flight.next_key;
+        in_flight.next_key += 1;
+        in_flight.requests.insert(key, Tracked {
+            id,
+            method: method.to_string(),
+            path: path.to_string(),
+            started: Instant::now(),
+            request_total,
+            transfer: transfer.clone(),
+        });
+        TrackedRequest { in_flight: self.in_flight.clone(), key, transfer }
+    }
+
+    /// Requests being processed, oldest first
+    pub fn in_flight(&self) -> Vec<InFlightRequest> {
+        self.in_flight.lock().unwrap().requests.values()
+            .map(|tracked| InFlightRequest {
+                id: tracked.id,
+                method: tracked.method.clone(),
+                path: tracked.path.clone(),
+                elapsed_ms: tracked.started.elapsed().as_millis() as u64,
+                request_bytes: tracked.transfer.request_bytes.load(Ordering::Relaxed),
+                request_total: tracked.request_total,
+                response_bytes: tracked.transfer.response_bytes.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    fn set_state(status: &mut Status, state: ConnectionState) {
+        status.state = state;
+        status.ready = state == ConnectionState::Connected;
+    }
+}
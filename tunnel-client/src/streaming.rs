@@ -0,0 +1,170 @@
+//! Bodies streamed through the tunnel in body frames, on connections that
+//! agreed to it (see `tunnel_protocol::STREAM_HEADER`).
+//!
+//! A streamed request body is passed on to the local service as its pieces
+//! arrive, and a local response body past TUNNEL_STREAM_THRESHOLD_BYTES is sent
+//! on as it is read rather than held or spooled. Neither can be converted by
+//! LOCAL_TRANSFORM_RULES_FILE, and captures record them by size only. Their
+//! trailers travel with their end, on connections that agreed to it (see
+//! `tunnel_protocol::STREAM_TRAILERS_HEADER`).
+
+use bytes::Bytes;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tracing::Instrument;
+use tunnel_core::error_dedup;
+use tunnel_core::framing::BodyPiece;
+use tunnel_protocol::{BodySha256, BODY_CHUNK_BYTES};
+
+use crate::stats;
+use crate::status::Transfer;
+use crate::trailers::{self, ReceivedTrailers};
+
+/// A streamed request body on its way to the local service
+///
+/// Every attempt at a local target reads from the same pieces: one whose
+/// connection failed never started reading, so the next one gets the whole body.
+pub struct StreamedRequest {
+    reader: Arc<Mutex<PieceReader>>,
+    checksummed: Arc<AtomicBool>,  // Its end carried a SHA-256
+    mismatched: Arc<AtomicBool>,   // It did not match that SHA-256
+}
+
+struct PieceReader {
+    pieces: mpsc::Receiver<BodyPiece>,
+    hasher: BodySha256,
+}
+
+impl StreamedRequest {
+    pub fn new(pieces: mpsc::Receiver<BodyPiece>) -> Self {
+        Self {
+            reader: Arc::new(Mutex::new(PieceReader { pieces, hasher: BodySha256::default() })),
+            checksummed: Arc::default(),
+            mismatched: Arc::default(),
+        }
+    }
+
+    /// The body for one attempt at the local service
+    ///
+    /// It fails, so the local service sees the request break off, if the server
+    /// gave up on the body or it does not match the SHA-256 sent with its end.
+    /// Trailers sent with its end follow it (reqwest leaves them out).
+    pub fn to_reqwest(&self) -> reqwest::Body {
+        reqwest::Body::from(self.to_hyper())
+    }
+
+    /// The body for one attempt, for a request sent with hyper (see `trailers`)
+    pub fn to_hyper(&self) -> hyper::Body {
+        let (mut sender, body) = hyper::Body::channel();
+        let (reader, checksummed, mismatched) = (self.reader.clone(), self.checksummed.clone(), self.mismatched.clone());
+        tokio::spawn(async move {
+            let mut reader = reader.lock().await;
+            loop {
+                // Taken only once the attempt reads, so none is lost with a failed connection
+                if std::future::poll_fn(|cx| sender.poll_ready(cx)).await.is_err() {
+                    return;
+                }
+                match reader.pieces.recv().await {
+                    Some(BodyPiece::Data(data)) => {
+                        reader.hasher.update(&data);
+                        if sender.try_send_data(data).is_err() {
+                            return;
+                        }
+                    }
+                    Some(BodyPiece::End { complete: true, sha256, trailers }) => {
+                        let actual = std::mem::take(&mut reader.hasher).finish();
+                        checksummed.store(sha256.is_some(), Ordering::Relaxed);
+                        if sha256.is_some_and(|expected| expected != actual) {
+                            stats::record_checksum_mismatch();
+                            mismatched.store(true, Ordering::Relaxed);
+                            sender.abort();
+                            return;
+                        }
+                        let trailers: hyper::HeaderMap = trailers.iter()
+                            .filter(|(name, value)| trailers::sendable(name, value))
+                            .filter_map(|(name, value)| Some((name.parse().ok()?, hyper::header::HeaderValue::from_bytes(value.as_bytes()).ok()?)))
+                            .collect();
+                        if !trailers.is_empty() {
+                            let _ = sender.send_trailers(trailers).await;
+                        }
+                        return;
+                    }
+                    Some(BodyPiece::End { complete: false, .. }) | None => {
+                        sender.abort();
+                        return;
+                    }
+                }
+            }
+        }.in_current_span());
+        body
+    }
+
+    /// Whether the server sent the body's SHA-256, so it wants the response's too
+    pub fn checksummed(&self) -> bool {
+        self.checksummed.load(Ordering::Relaxed)
+    }
+
+    /// Whether the body did not match the SHA-256 the server sent
+    pub fn mismatched(&self) -> bool {
+        self.mismatched.load(Ordering::Relaxed)
+    }
+}
+
+/// Sends `read`, the start of a local response body already read (and fed to
+/// `hasher`), then the rest of `response` as it arrives
+///
+/// The last piece is complete once the body ended, carrying its SHA-256 with
+/// `hasher` and, with `trailers`, those the local service sent, and incomplete
+/// if reading it failed or it grew past `max_bytes` (LOCAL_MAX_BODY_BYTES). At
+/// most `window` pieces are read ahead of the tunnel. What is read after `read`
+/// is counted in `transfer`.
+pub fn send_response_body(
+    mut read: Bytes,
+    mut response: reqwest::Response,
+    max_bytes: usize,
+    mut hasher: Option<BodySha256>,
+    trailers: Option<ReceivedTrailers>,
+    window: usize,
+    transfer: Arc<Transfer>,
+) -> mpsc::Receiver<BodyPiece> {
+    let (pieces_tx, pieces_rx) = mpsc::channel(window);
+    tokio::spawn(async move {
+        let mut len = read.len();
+        let complete = loop {
+            while !read.is_empty() {
+                let piece = read.split_to(read.len().min(BODY_CHUNK_BYTES));
+                if pieces_tx.send(BodyPiece::Data(piece)).await.is_err() {
+                    return;
+                }
+            }
+            match response.chunk().await {
+                Ok(Some(chunk)) => {
+                    len += chunk.len();
+                    transfer.add_response_bytes(chunk.len() as u64);
+                    if len > max_bytes {
+                        error_dedup!("Local response body exceeded {} bytes", max_bytes);
+                        break false;
+                    }
+                    if let Some(hasher) = &mut hasher {
+                        hasher.update(&chunk);
+                    }
+                    read = chunk;
+                }
+                Ok(None) => break true,
+                Err(e) => {
+                    error_dedup!("Failed to read response body: {}", e);
+                    break false;
+                }
+            }
+        };
+        let sha256 = hasher.filter(|_| complete).map(BodySha256::finish);
+        // Kept once the body was read to the end
+        let trailers = match trailers {
+            Some(trailers) if complete => std::mem::take(&mut *trailers.lock().unwrap()),
+            _ => Vec::new(),
+        };
+        let _ = pieces_tx.send(BodyPiece::End { complete, sha256, trailers }).await;
+    }.in_current_span());
+    pieces_rx
+}
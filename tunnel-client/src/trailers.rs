@@ -0,0 +1,518 @@
+//! Local requests that reqwest cannot send as they came.
+//!
+//! reqwest 0.11 neither sends nor reads trailers, and lowercases header names.
+//! So a request with trailers, one asking for them with `TE: trailers` (as
+//! gRPC clients do), and an HTTP/1.1 one with names spelled otherwise go to the
+//! local service on connections of their own, made with the same settings:
+//! HTTP/2 through hyper 0.14, and HTTP/1.1 through hyper 1 (hyper 0.14 neither
+//! writes nor parses HTTP/1 trailers), which keeps the case of header names
+//! both ways (see `tunnel_core::header_case`) and its connections for reuse.
+//! The response comes back as a `reqwest::Response`, read like any other, with
+//! its header names as spelled, and its trailers are kept once its body was
+//! read to the end. A streamed request body's trailers arrive with its end, so
+//! such a request goes here when its `Trailer` header announces them.
+
+use bytes::Bytes;
+use futures_core::Stream;
+use hyper::body::HttpBody;
+use hyper1::body::Body as _;
+use hyper_util::rt::TokioIo;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::{Instant, Sleep};
+use tokio_native_tls::native_tls;
+use tracing::{debug, Instrument};
+use tunnel_core::header_case::{self, HeaderNames, RecordedHeads};
+use tunnel_protocol::{allowed_in_trailers, HeaderValueBytes};
+
+use crate::dns::CustomDnsResolver;
+use crate::local::{LocalConfig, LocalHttpVersion};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// An HTTP/1.1 connection to the local service
+type Http1Sender = hyper1::client::conn::http1::SendRequest<Http1Body>;
+
+/// Most HTTP/1.1 connections kept for reuse
+const MAX_KEPT_HTTP1: usize = 32;
+
+/// Trailers of a local response, filled in once its body was read to the end
+pub type ReceivedTrailers = Arc<Mutex<Vec<(String, HeaderValueBytes)>>>;
+
+/// Whether a request goes to the local service through this module: it has
+/// trailers, its `Trailer` header announces some, or its `TE` header asks for
+/// them in the response
+pub fn wanted(headers: &[(String, HeaderValueBytes)], trailers: &[(String, HeaderValueBytes)]) -> bool {
+    !trailers.is_empty()
+        || headers.iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case("trailer")
+                || (name.eq_ignore_ascii_case("te")
+                    && value.to_str().is_some_and(|te| te.split(',').any(|coding| coding.trim().eq_ignore_ascii_case("trailers"))))
+        })
+}
+
+/// Whether a request trailer can be sent to the local service: it may follow a
+/// body and is a valid field
+pub fn sendable(name: &str, value: &HeaderValueBytes) -> bool {
+    let valid = allowed_in_trailers(name)
+        && hyper::header::HeaderName::from_bytes(name.as_bytes()).is_ok()
+        && hyper::header::HeaderValue::from_bytes(value.as_bytes()).is_ok();
+    if !valid {
+        debug!("Dropping request trailer {}: not allowed after the body", name);
+    }
+    valid
+}
+
+/// The certificates of a PEM bundle, which native-tls reads one at a time
+pub fn pem_certificates(pem: &[u8]) -> Result<Vec<native_tls::Certificate>, native_tls::Error> {
+    const END: &[u8] = b"-----END CERTIFICATE-----";
+    let mut certs = Vec::new();
+    let mut rest = pem;
+    while let Some(end) = rest.windows(END.len()).position(|window| window == END) {
+        let (cert, after) = rest.split_at(end + END.len());
+        certs.push(native_tls::Certificate::from_pem(cert)?);
+        rest = after;
+    }
+    Ok(certs)
+}
+
+/// A connection to the local service
+trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+/// Sends the requests reqwest cannot to the local service
+pub struct TrailerClient {
+    tls: Option<tokio_native_tls::TlsConnector>,  // For https local targets
+    http_version: LocalHttpVersion,
+    resolve_overrides: Vec<(String, IpAddr)>,
+    dns: Option<Arc<CustomDnsResolver>>,
+    connect_timeout: Option<Duration>,
+    kept: Mutex<Vec<(String, Http1Sender, RecordedHeads)>>,  // HTTP/1.1 connections for reuse, by base URL
+}
+
+impl TrailerClient {
+    /// `ca_certs` are trusted for https local targets on top of the system's
+    pub fn new(config: &LocalConfig, ca_certs: Vec<native_tls::Certificate>, dns: Option<Arc<CustomDnsResolver>>) -> Result<Self, String> {
+        let tls = match config.scheme.as_str() {
+            "https" => {
+                let mut builder = native_tls::TlsConnector::builder();
+                for cert in ca_certs {
+                    builder.add_root_certificate(cert);
+                }
+                builder.request_alpns(match config.http_version {
+                    LocalHttpVersion::Auto => &["h2", "http/1.1"],
+                    LocalHttpVersion::Http1 => &["http/1.1"],
+                    LocalHttpVersion::Http2 => &["h2"],
+                });
+                let connector = builder.build().map_err(|e| format!("Failed to build local TLS connector: {}", e))?;
+                Some(connector.into())
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            tls,
+            http_version: config.http_version,
+            resolve_overrides: config.resolve_overrides.clone(),
+            dns,
+            connect_timeout: config.connect_timeout,
+            kept: Mutex::default(),
+        })
+    }
+
+    /// Whether a request with `headers` goes through here to keep the case of its
+    /// header names: the local targets speak HTTP/1.1 (HTTP/2 names are lowercase)
+    /// and a name is not lowercase
+    pub fn keeps_case(&self, headers: &[(String, HeaderValueBytes)]) -> bool {
+        let http1 = match self.http_version {
+            LocalHttpVersion::Http1 => true,
+            LocalHttpVersion::Auto => self.tls.is_none(),
+            LocalHttpVersion::Http2 => false,
+        };
+        http1 && header_case::has_uppercase(headers.iter().map(|(name, _)| name.as_str()))
+    }
+
+    /// Sends a request to the local target at `base_url` (scheme://host:port)
+    ///
+    /// Returns the response, its header names as spelled (in the order of its
+    /// header map; empty over HTTP/2) and its trailers. Its body fails with
+    /// `ErrorKind::TimedOut`, which reqwest reports as a timeout, once
+    /// `deadline` passes.
+    pub async fn send(
+        &self,
+        base_url: &str,
+        method: &str,
+        path: &str,
+        headers: &[(String, HeaderValueBytes)],
+        body: TrailedBody,
+        deadline: Instant,
+    ) -> Result<(reqwest::Response, Vec<String>, ReceivedTrailers), SendError> {
+        let mut headers = headers.to_vec();
+        if !body.trailers.is_empty() {
+            // Trailers follow a chunked body, named in a Trailer header for HTTP/1.1
+            headers.retain(|(name, _)| !name.eq_ignore_ascii_case("content-length"));
+            if !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("trailer")) {
+                let names: Vec<&str> = body.trailers.iter().map(|(name, _)| name.as_str()).collect();
+                headers.push(("trailer".to_string(), names.join(", ").into()));
+            }
+        }
+
+        let (sender, heads) = match self.reuse(base_url) {
+            Some(connection) => connection,
+            None => {
+                let (io, http2) = self.connect(base_url).await.map_err(SendError::Connect)?;
+                if http2 {
+                    let (head, body) = send_http2(io, base_url, method, path, &headers, body).await.map_err(SendError::Http)?;
+                    return Ok(self.response(head, Vec::new(), body, deadline));
+                }
+                handshake_http1(io).await.map_err(SendError::Http)?
+            }
+        };
+        let (head, names, body, sender) = send_http1(sender, &heads, base_url, method, path, &headers, body).await.map_err(SendError::Http)?;
+        self.keep(base_url, sender, heads);
+        Ok(self.response(head, names, body, deadline))
+    }
+
+    /// A local response as `send` returns it
+    fn response(&self, head: hyper::Response<()>, names: Vec<String>, body: LocalBody, deadline: Instant) -> (reqwest::Response, Vec<String>, ReceivedTrailers) {
+        let received = ReceivedTrailers::default();
+        let tap = TrailerTap { body, trailers: received.clone(), deadline: Box::pin(tokio::time::sleep_until(deadline)), ended: false };
+        (reqwest::Response::from(head.map(|()| reqwest::Body::wrap_stream(tap))), names, received)
+    }
+
+    /// A kept HTTP/1.1 connection to `base_url` that is done with its last request
+    fn reuse(&self, base_url: &str) -> Option<(Http1Sender, RecordedHeads)> {
+        let mut kept = self.kept.lock().unwrap();
+        kept.retain(|(_, sender, _)| !sender.is_closed());
+        let at = kept.iter().position(|(url, sender, _)| url == base_url && sender.is_ready())?;
+        let (_, sender, heads) = kept.swap_remove(at);
+        Some((sender, heads))
+    }
+
+    /// Keeps an HTTP/1.1 connection to `base_url` for reuse once its response was read
+    fn keep(&self, base_url: &str, sender: Http1Sender, heads: RecordedHeads) {
+        let mut kept = self.kept.lock().unwrap();
+        if kept.len() < MAX_KEPT_HTTP1 {
+            kept.push((base_url.to_string(), sender, heads));
+        }
+    }
+
+    /// Connects to `base_url`, returning the connection and whether it speaks HTTP/2
+    async fn connect(&self, base_url: &str) -> io::Result<(Box<dyn Io>, bool)> {
+        let uri: hyper::Uri = base_url.parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let host = uri.host().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+        let port = uri.port_u16().unwrap_or(if self.tls.is_some() { 443 } else { 80 });
+
+        let connect = async {
+            let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", host));
+            let mut stream = None;
+            for ip in self.resolve(host, port).await? {
+                match TcpStream::connect(SocketAddr::new(ip, port)).await {
+                    Ok(tcp) => {
+                        stream = Some(tcp);
+                        break;
+                    }
+                    Err(e) => last_error = e,
+                }
+            }
+            let tcp = stream.ok_or(last_error)?;
+            let _ = tcp.set_nodelay(true);
+
+            match &self.tls {
+                None => Ok::<_, io::Error>((Box::new(tcp) as Box<dyn Io>, self.http_version == LocalHttpVersion::Http2)),
+                Some(tls) => {
+                    let stream = tls.connect(host, tcp).await.map_err(io::Error::other)?;
+                    let http2 = match stream.get_ref().negotiated_alpn() {
+                        Ok(Some(protocol)) => protocol == b"h2",
+                        _ => self.http_version == LocalHttpVersion::Http2,
+                    };
+                    Ok((Box::new(stream) as Box<dyn Io>, http2))
+                }
+            }
+        };
+
+        match self.connect_timeout {
+            Some(limit) => tokio::time::timeout(limit, connect)
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connection timed out"))?,
+            None => connect.await,
+        }
+    }
+
+    /// Addresses of `host`, as reqwest would find them (LOCAL_RESOLVE, then LOCAL_DNS_SERVER or the system)
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<IpAddr>> {
+        if let Ok(ip) = host.parse() {
+            return Ok(vec![ip]);
+        }
+        if let Some((_, ip)) = self.resolve_overrides.iter().find(|(name, _)| name.eq_ignore_ascii_case(host)) {
+            return Ok(vec![*ip]);
+        }
+        match &self.dns {
+            Some(dns) => dns.lookup(host).await,
+            None => Ok(tokio::net::lookup_host((host, port)).await?.map(|addr| addr.ip()).collect()),
+        }
+    }
+}
+
+/// Why a request with trailers failed
+#[derive(Debug)]
+pub enum SendError {
+    Connect(io::Error),  // No connection to the local target: the next one may be tried
+    Http(BoxError),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Connect(e) => write!(f, "connection failed: {}", e),
+            SendError::Http(e) => e.fmt(f),
+        }
+    }
+}
+
+/// A request body followed by trailers
+pub struct TrailedBody {
+    data: hyper::Body,
+    trailers: Vec<(String, HeaderValueBytes)>,
+}
+
+impl TrailedBody {
+    /// Leaves out trailers that are invalid or may not follow a body
+    ///
+    /// Those of a streamed body come later, with `data` (see `StreamedRequest`).
+    pub fn new(data: hyper::Body, mut trailers: Vec<(String, HeaderValueBytes)>) -> Self {
+        trailers.retain(|(name, value)| sendable(name, value));
+        Self { data, trailers }
+    }
+}
+
+/// Sends a request over HTTP/2 with hyper 0.14
+async fn send_http2(
+    io: Box<dyn Io>,
+    base_url: &str,
+    method: &str,
+    path: &str,
+    headers: &[(String, HeaderValueBytes)],
+    body: TrailedBody,
+) -> Result<(hyper::Response<()>, LocalBody), BoxError> {
+    let (mut sender, connection) = hyper::client::conn::Builder::new().http2_only(true).handshake(io).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("Local HTTP/2 connection failed: {}", e);
+        }
+    }.in_current_span());
+
+    let mut request = hyper::Request::builder().method(method).uri(format!("{}{}", base_url, path));
+    for (name, value) in headers {
+        request = request.header(name.as_str(), value.as_bytes());
+    }
+    let trailers = body.trailers.iter()
+        .filter_map(|(name, value)| Some((name.parse().ok()?, hyper::header::HeaderValue::from_bytes(value.as_bytes()).ok()?)))
+        .collect();
+    let body = Http2Body { data: body.data, trailers: Some(trailers) };
+    let (head, body) = sender.send_request(request.body(body)?).await?.into_parts();
+    Ok((hyper::Response::from_parts(head, ()), LocalBody::Http2 { body, data_done: false }))
+}
+
+/// Opens an HTTP/1.1 connection with hyper 1, keeping the case of header names
+///
+/// Returns the heads it reads too, for the names of responses as spelled.
+async fn handshake_http1(io: Box<dyn Io>) -> Result<(Http1Sender, RecordedHeads), BoxError> {
+    let (io, heads) = header_case::record(io);
+    let (sender, connection) = hyper1::client::conn::http1::Builder::new()
+        .preserve_header_case(true)
+        .handshake(TokioIo::new(io))
+        .await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("Local HTTP/1.1 connection failed: {}", e);
+        }
+    }.in_current_span());
+    Ok((sender, heads))
+}
+
+/// Sends a request over HTTP/1.1 with hyper 1, returning the response with its header names as spelled
+async fn send_http1(
+    mut sender: Http1Sender,
+    heads: &RecordedHeads,
+    base_url: &str,
+    method: &str,
+    path: &str,
+    headers: &[(String, HeaderValueBytes)],
+    body: TrailedBody,
+) -> Result<(hyper::Response<()>, Vec<String>, LocalBody, Http1Sender), BoxError> {
+    let mut request = hyper1::Request::builder().method(method).uri(path);
+    for (name, value) in headers {
+        request = request.header(name.as_str(), value.as_bytes());
+    }
+    if !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("host")) {
+        request = request.header("host", base_url.split_once("://").map_or(base_url, |(_, authority)| authority));
+    }
+    let trailers = body.trailers.iter()
+        .filter_map(|(name, value)| Some((name.parse().ok()?, hyper1::header::HeaderValue::from_bytes(value.as_bytes()).ok()?)))
+        .collect();
+    let body = Http1Body { data: body.data, trailers: Some(trailers) };
+    let mut request = request.body(body)?;
+    *request.extensions_mut() = header_case::extensions(headers.iter().map(|(name, _)| name.as_str())).await;
+    let (mut parts, body) = sender.send_request(request).await?.into_parts();
+    if let Some(names) = heads.response(parts.status.as_u16(), &parts.headers) {
+        parts.extensions.insert(HeaderNames(names));
+    }
+    let names = header_case::names(parts.version, &parts.headers, &parts.extensions).await;
+
+    // Back to the http 0.2 types reqwest uses
+    let version = match parts.version {
+        hyper1::Version::HTTP_10 => hyper::Version::HTTP_10,
+        _ => hyper::Version::HTTP_11,
+    };
+    let mut head = hyper::Response::builder().status(parts.status.as_u16()).version(version);
+    for (name, value) in &parts.headers {
+        head = head.header(name.as_str(), value.as_bytes());
+    }
+    Ok((head.body(())?, names, LocalBody::Http1(body), sender))
+}
+
+/// A `TrailedBody` as hyper 0.14 sends it
+struct Http2Body {
+    data: hyper::Body,
+    trailers: Option<hyper::HeaderMap>,
+}
+
+impl HttpBody for Http2Body {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, hyper::Error>>> {
+        Pin::new(&mut self.data).poll_data(cx)
+    }
+
+    fn poll_trailers(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Option<hyper::HeaderMap>, hyper::Error>> {
+        match self.trailers.take() {
+            Some(trailers) if !trailers.is_empty() => Poll::Ready(Ok(Some(trailers))),
+            // A streamed body's come with its end
+            _ => Pin::new(&mut self.data).poll_trailers(cx),
+        }
+    }
+}
+
+/// A `TrailedBody` as hyper 1 sends it
+struct Http1Body {
+    data: hyper::Body,
+    trailers: Option<hyper1::HeaderMap>,
+}
+
+impl hyper1::body::Body for Http1Body {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<hyper1::body::Frame<Bytes>, hyper::Error>>> {
+        match ready!(Pin::new(&mut self.data).poll_data(cx)) {
+            Some(data) => Poll::Ready(Some(data.map(hyper1::body::Frame::data))),
+            None if self.trailers.as_ref().is_some_and(|trailers| !trailers.is_empty()) => {
+                Poll::Ready(self.trailers.take().map(|trailers| Ok(hyper1::body::Frame::trailers(trailers))))
+            }
+            // A streamed body's come with its end, in the http 0.2 types of hyper 0.14
+            None if self.trailers.is_some() => {
+                let trailers = ready!(Pin::new(&mut self.data).poll_trailers(cx))?;
+                self.trailers = None;
+                let trailers = trailers.map(|trailers| {
+                    trailers.iter()
+                        .filter_map(|(name, value)| {
+                            Some((hyper1::header::HeaderName::from_bytes(name.as_str().as_bytes()).ok()?, hyper1::header::HeaderValue::from_bytes(value.as_bytes()).ok()?))
+                        })
+                        .collect()
+                });
+                Poll::Ready(trailers.map(|trailers| Ok(hyper1::body::Frame::trailers(trailers))))
+            }
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+/// A local response body as hyper gives it
+enum LocalBody {
+    Http1(hyper1::body::Incoming),
+    Http2 { body: hyper::Body, data_done: bool },
+}
+
+/// What a `LocalBody` yields next
+enum Piece {
+    Data(Bytes),
+    Trailers(Vec<(String, HeaderValueBytes)>),
+}
+
+impl LocalBody {
+    fn poll_piece(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Piece, BoxError>>> {
+        match self {
+            LocalBody::Http1(body) => Poll::Ready(match ready!(Pin::new(body).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_data() {
+                    Ok(data) => Some(Ok(Piece::Data(data))),
+                    Err(frame) => frame.into_trailers().ok()
+                        .map(|trailers| Ok(Piece::Trailers(pairs(trailers.iter().map(|(name, value)| (name.as_str(), value.as_bytes())))))),
+                },
+                Some(Err(e)) => Some(Err(e.into())),
+                None => None,
+            }),
+            LocalBody::Http2 { body, data_done } => {
+                if !*data_done {
+                    match ready!(Pin::new(&mut *body).poll_data(cx)) {
+                        Some(data) => return Poll::Ready(Some(data.map(Piece::Data).map_err(Into::into))),
+                        None => *data_done = true,
+                    }
+                }
+                Poll::Ready(match ready!(Pin::new(body).poll_trailers(cx)) {
+                    Ok(trailers) => trailers
+                        .map(|trailers| Ok(Piece::Trailers(pairs(trailers.iter().map(|(name, value)| (name.as_str(), value.as_bytes())))))),
+                    Err(e) => Some(Err(e.into())),
+                })
+            }
+        }
+    }
+}
+
+/// Header fields as name-value pairs
+fn pairs<'a>(fields: impl Iterator<Item = (&'a str, &'a [u8])>) -> Vec<(String, HeaderValueBytes)> {
+    fields.map(|(name, value)| (name.to_string(), value.into())).collect()
+}
+
+/// A local response body for reqwest, keeping its trailers in `trailers`
+struct TrailerTap {
+    body: LocalBody,
+    trailers: ReceivedTrailers,
+    deadline: Pin<Box<Sleep>>,  // LOCAL_TIMEOUT_SECS, or the server's deadline
+    ended: bool,
+}
+
+impl Stream for TrailerTap {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.ended {
+            return Poll::Ready(None);
+        }
+        if this.deadline.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Some(Err(io::Error::new(io::ErrorKind::TimedOut, "local service timed out"))));
+        }
+        loop {
+            match ready!(this.body.poll_piece(cx)) {
+                Some(Ok(Piece::Data(data))) => return Poll::Ready(Some(Ok(data))),
+                Some(Ok(Piece::Trailers(trailers))) => *this.trailers.lock().unwrap() = trailers,
+                Some(Err(e)) => return Poll::Ready(Some(Err(io::Error::other(e)))),
+                None => {
+                    this.ended = true;
+                    return Poll::Ready(None);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! Request body transforms, read from LOCAL_TRANSFORM_RULES_FILE.
+//!
+//! One rule per line: `<path-prefix> <from> <to>`, where the formats are
+//! `form` (application/x-www-form-urlencoded) and `json`. A request whose path
+//! starts with the prefix and whose Content-Type is `from` has its body
+//! converted to `to` before it is forwarded, and its Content-Type set to match.
+//! The first matching rule applies. Blank lines and lines starting with `#`
+//! are ignored.
+//!
+//! Form fields become JSON strings, and a repeated field an array of them. The
+//! other way, the body must be a JSON object of strings, numbers, booleans,
+//! nulls (empty values) and arrays of those (repeated fields).
+
+use serde_json::{Map, Value};
+use std::path::Path;
+use tunnel_core::rules;
+
+/// Body format a rule converts between
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Form,
+    Json,
+}
+
+impl Format {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "form" => Some(Format::Form),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    /// Content-Type of a converted body
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Form => "application/x-www-form-urlencoded",
+            Format::Json => "application/json",
+        }
+    }
+
+    /// Whether a Content-Type value (parameters such as charset aside) is this format
+    fn describes(self, content_type: &[u8]) -> bool {
+        let Ok(content_type) = std::str::from_utf8(content_type) else {
+            return false;
+        };
+        let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        match self {
+            Format::Form => essence == "application/x-www-form-urlencoded",
+            Format::Json => essence == "application/json" || essence.ends_with("+json"),
+        }
+    }
+}
+
+/// One line of the rules file
+#[derive(Debug)]
+pub struct TransformRule {
+    pub path_prefix: String,
+    pub from: Format,
+    pub to: Format,
+}
+
+impl TransformRule {
+    /// Converts `body` from `from` to `to`
+    pub fn apply(&self, body: &[u8]) -> Result<Vec<u8>, String> {
+        match (self.from, self.to) {
+            (Format::Form, Format::Json) => Ok(form_to_json(body).to_string().into_bytes()),
+            (Format::Json, Format::Form) => json_to_form(body).map(String::into_bytes),
+            _ => Ok(body.to_vec()),
+        }
+    }
+}
+
+/// Every request body transform the client applies
+#[derive(Debug, Default)]
+pub struct Transforms {
+    rules: Vec<TransformRule>,
+}
+
+impl Transforms {
+    /// Reads the rules from `path`
+    pub fn load(path: &Path) -> Result<Self, String> {
+        rules::load(path, "LOCAL_TRANSFORM_RULES_FILE", Self::parse)
+    }
+
+    /// Parses the contents of a rules file
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut rules = Vec::new();
+
+        for line in rules::lines(contents) {
+            let fields: Vec<&str> = line.fields().collect();
+            let [path_prefix, from, to] = fields[..] else {
+                return Err(line.error("expected '<path-prefix> <from> <to>'"));
+            };
+            if !path_prefix.starts_with('/') {
+                return Err(line.error("path prefix must start with '/'"));
+            }
+            let format = |name: &str| {
+                Format::parse(name).ok_or_else(|| line.error(format!("unknown format '{}' (expected form or json)", name)))
+            };
+            let (from, to) = (format(from)?, format(to)?);
+            if from == to {
+                return Err(line.error(format!("converts {} to itself", fields[1])));
+            }
+            rules.push(TransformRule { path_prefix: path_prefix.to_string(), from, to });
+        }
+
+        Ok(Self { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// The rule for a request to `target` (query included) with `content_type`, if any
+    pub fn find(&self, target: &str, content_type: Option<&[u8]>) -> Option<&TransformRule> {
+        let path = target.split('?').next().unwrap_or(target);
+        let content_type = content_type?;
+        self.rules.iter().find(|rule| path.starts_with(&rule.path_prefix) && rule.from.describes(content_type))
+    }
+}
+
+/// Form fields as a JSON object; a repeated field becomes an array
+fn form_to_json(body: &[u8]) -> Value {
+    let mut object = Map::new();
+    for (name, value) in form_urlencoded::parse(body) {
+        let value = Value::String(value.into_owned());
+        match object.get_mut(name.as_ref()) {
+            Some(Value::Array(values)) => values.push(value),
+            Some(first) => *first = Value::Array(vec![first.take(), value]),
+            None => {
+                object.insert(name.into_owned(), value);
+            }
+        }
+    }
+    Value::Object(object)
+}
+
+/// A flat JSON object as form fields; an array becomes a repeated field
+fn json_to_form(body: &[u8]) -> Result<String, String> {
+    let value: Value = serde_json::from_slice(body).map_err(|e| format!("invalid JSON: {}", e))?;
+    let Value::Object(object) = value else {
+        return Err("expected a JSON object".to_string());
+    };
+    let scalar = |name: &str, value: &Value| match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Null => Ok(String::new()),
+        Value::Bool(_) | Value::Number(_) => Ok(value.to_string()),
+        _ => Err(format!("field {} is nested, which forms cannot express", name)),
+    };
+
+    let mut form = form_urlencoded::Serializer::new(String::new());
+    for (name, value) in &object {
+        match value {
+            Value::Array(values) => {
+                for value in values {
+                    form.append_pair(name, &scalar(name, value)?);
+                }
+            }
+            value => {
+                form.append_pair(name, &scalar(name, value)?);
+            }
+        }
+    }
+    Ok(form.finish())
+}
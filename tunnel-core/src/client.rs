@@ -0,0 +1,687 @@
+//! Client side of the tunnel: server address parsing, TLS setup and the
+//! HTTP Upgrade handshake that turns a TCP connection into a tunnel.
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use rustls::pki_types::{InvalidDnsNameError, ServerName};
+use rustls::RootCertStore;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use thiserror::Error;
+use tracing::info;
+use tunnel_protocol::{
+    encode_body, CompressTypes, Compression, HeaderLimits, Schedule, BINARY_ENCODING, CANCEL_HEADER, CLIENT_ADDR_HEADER, COMPRESSION_HEADER, CORS_HEADER, DEFAULT_TUNNEL_PATH,
+    ENCODING_HEADER, HEADER_LIMITS_HEADER, HEARTBEAT_HEADER, HTTPS_ONLY_HEADER, LABEL_HEADER, MULTIPLEX_HEADER, SCHEDULE_HEADER, STATS_HEADER, STREAM_CREDIT_HEADER, STREAM_HEADER, STREAM_TRAILERS_HEADER, TUNNEL_ID_HEADER, UPGRADE_SECRET_HEADER,
+    VISITOR_AUTH_HEADER,
+};
+
+use crate::stream::TunnelStream;
+use crate::tls::{CertPin, ClientCert, TlsOptions};
+use crate::transport::TransportOptions;
+
+/// Configuration for server connection
+pub struct ServerConfig {
+    pub addr: String,        // Host:port for TCP connection
+    pub use_tls: bool,       // Whether to use TLS
+    pub hostname: String,    // Hostname for SNI and Host header
+    pub path: String,        // Upgrade endpoint (TUNNEL_PATH)
+    pub auth: Option<String>, // Basic Auth credentials in "username:password" format
+    pub upgrade_secret: Option<String>, // Sent in UPGRADE_SECRET_HEADER (TUNNEL_UPGRADE_SECRET)
+    pub visitor_auth: Option<String>,   // "username:password" the server requires of visitors (VISITOR_AUTH)
+    pub https_only: bool,               // Ask the server to redirect plain-HTTP visitors (HTTPS_ONLY)
+    pub cors_origins: Vec<String>,      // Origins the server should answer CORS for, or `*` (CORS_ORIGINS; empty: off)
+    pub schedule: Option<Schedule>,     // Windows the server routes visitors in (TUNNEL_SCHEDULE; None: always)
+    pub labels: Vec<(String, String)>, // Tunnel labels sent to the server at handshake
+    pub transport: TransportOptions,   // Socket and frame coalescing options
+    pub tls: TlsOptions,               // TLS protocol options (https only)
+    pub cert_pins: Vec<CertPin>,       // Server certificate fingerprints accepted (SERVER_CERT_PIN; empty: any trusted one)
+    pub client_cert: Option<Arc<ClientCert>>, // Presented when the server asks (CLIENT_CERT_FILE; None: none)
+    pub max_concurrent: usize,         // Requests handled at once, if the server multiplexes (TUNNEL_MAX_CONCURRENT; 1: one at a time)
+    pub binary_frames: bool,           // Offer binary frames (TUNNEL_BINARY_FRAMES)
+    pub stream_bodies: bool,           // Offer to stream large bodies, with binary frames and multiplexing (TUNNEL_STREAM_BODIES)
+    pub heartbeat: bool,               // Offer heartbeats (TUNNEL_HEARTBEAT_SECS set)
+    pub user_agent: Option<String>,    // Sent as User-Agent on the upgrade request (TUNNEL_USER_AGENT; None: not sent)
+    pub compress_types: CompressTypes, // Media types whose responses are (not) compressed (COMPRESS_TYPES, NO_COMPRESS_TYPES)
+    tls_connector: OnceLock<TlsConnector>, // Built from `tls` on first connect
+}
+
+impl ServerConfig {
+    /// Returns the TLS connector, building it on first use
+    ///
+    /// The connector is kept so that reconnects share its session cache and can
+    /// resume the previous TLS session. Set `tls`, `cert_pins` and `client_cert` before the first connect.
+    pub fn tls_connector(&self) -> Result<TlsConnector, ConnectError> {
+        if let Some(connector) = self.tls_connector.get() {
+            return Ok(connector.clone());
+        }
+        let connector = create_tls_connector(&self.tls, &self.cert_pins, self.client_cert.as_deref()).map_err(ConnectError::TlsConfig)?;
+        Ok(self.tls_connector.get_or_init(|| connector).clone())
+    }
+
+    /// URL visitors reach this tunnel at: the server's own address, as the
+    /// public listener also serves the upgrade endpoint
+    pub fn public_url(&self) -> String {
+        let (scheme, default_port) = if self.use_tls { ("https", ":443") } else { ("http", ":80") };
+        format!("{}://{}", scheme, self.addr.strip_suffix(default_port).unwrap_or(&self.addr))
+    }
+}
+
+/// Error from parsing a server address
+#[derive(Debug, Error)]
+pub enum AddressError {
+    #[error("Invalid port: {0}")]
+    InvalidPort(String),
+
+    #[error("Invalid IPv6 address format")]
+    InvalidIpv6,
+}
+
+/// Error from connecting to the server, before the tunnel is up
+#[derive(Debug, Error)]
+pub enum ConnectError {
+    #[error("TCP connection to {addr} failed: {source}")]
+    Tcp { addr: String, source: io::Error },
+
+    #[error("Failed to set socket options: {0}")]
+    SocketOptions(#[source] io::Error),
+
+    #[error("Invalid hostname for SNI: {hostname}")]
+    InvalidHostname { hostname: String, source: InvalidDnsNameError },
+
+    #[error("Invalid TLS configuration: {0}")]
+    TlsConfig(#[source] rustls::Error),
+
+    #[error("TLS handshake failed: {0}")]
+    Tls(#[source] io::Error),
+
+    #[error(transparent)]
+    Upgrade(#[from] UpgradeError),
+}
+
+impl ConnectError {
+    /// Whether trying again later can succeed
+    ///
+    /// Network failures are transient. Bad settings, certificate errors and
+    /// rejected credentials fail the same way every time.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ConnectError::Tcp { .. } => true,
+            ConnectError::SocketOptions(_) => false,
+            ConnectError::InvalidHostname { .. } => false,
+            ConnectError::TlsConfig(_) => false,
+            // rustls reports certificate and protocol errors as InvalidData
+            ConnectError::Tls(e) => e.kind() != io::ErrorKind::InvalidData,
+            ConnectError::Upgrade(e) => e.is_retryable(),
+        }
+    }
+}
+
+/// Error from the HTTP Upgrade handshake
+#[derive(Debug, Error)]
+pub enum UpgradeError {
+    #[error("Failed to send upgrade request: {0}")]
+    Send(#[source] io::Error),
+
+    #[error("Failed to read upgrade response: {0}")]
+    Read(#[source] io::Error),
+
+    #[error("Connection closed before receiving upgrade response")]
+    Closed,
+
+    #[error("Response headers too large")]
+    HeadersTooLarge,
+
+    #[error("Authentication failed: {}", .message.as_deref().unwrap_or("Invalid credentials"))]
+    Unauthorized { message: Option<String> },  // What the server said in its response body, if anything
+
+    #[error("Upgrade failed: {status_line}{}", .message.as_ref().map(|message| format!(" ({})", message)).unwrap_or_default())]
+    Rejected { status: Option<u16>, status_line: String, message: Option<String> },
+
+    #[error("Missing required upgrade headers in response")]
+    MissingHeaders,
+}
+
+impl UpgradeError {
+    /// Whether trying again later can succeed
+    ///
+    /// Client errors (4xx other than 408 and 429) and responses that are not a
+    /// tunnel upgrade mean the server will keep refusing this client.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            UpgradeError::Send(_) | UpgradeError::Read(_) | UpgradeError::Closed => true,
+            UpgradeError::HeadersTooLarge => true,
+            UpgradeError::Unauthorized { .. } | UpgradeError::MissingHeaders => false,
+            UpgradeError::Rejected { status, .. } => {
+                !matches!(status, Some(code @ 400..=499) if *code != 408 && *code != 429)
+            }
+        }
+    }
+}
+
+/// Parses server address from environment variable
+/// Supports: https://host, https://host:port, http://host:port, host:port
+pub fn parse_server_addr(
+    addr: &str,
+    auth: Option<String>,
+    labels: Vec<(String, String)>,
+) -> Result<ServerConfig, AddressError> {
+    if addr.starts_with("https://") {
+        let without_protocol = addr.strip_prefix("https://").unwrap();
+        let (host, port) = parse_host_port(without_protocol, 443)?;
+        Ok(ServerConfig {
+            addr: format!("{}:{}", host, port),
+            use_tls: true,
+            hostname: host,
+            path: DEFAULT_TUNNEL_PATH.to_string(),
+            auth,
+            upgrade_secret: None,
+            visitor_auth: None,
+            https_only: false,
+            cors_origins: Vec::new(),
+            schedule: None,
+            labels,
+            transport: TransportOptions::default(),
+            tls: TlsOptions::default(),
+            cert_pins: Vec::new(),
+            client_cert: None,
+            max_concurrent: 1,
+            binary_frames: false,
+            stream_bodies: false,
+            heartbeat: false,
+            user_agent: None,
+            compress_types: CompressTypes::default(),
+            tls_connector: OnceLock::new(),
+        })
+    } else if addr.starts_with("http://") {
+        let without_protocol = addr.strip_prefix("http://").unwrap();
+        let (host, port) = parse_host_port(without_protocol, 80)?;
+        Ok(ServerConfig {
+            addr: format!("{}:{}", host, port),
+            use_tls: false,
+            hostname: host,
+            path: DEFAULT_TUNNEL_PATH.to_string(),
+            auth,
+            upgrade_secret: None,
+            visitor_auth: None,
+            https_only: false,
+            cors_origins: Vec::new(),
+            schedule: None,
+            labels,
+            transport: TransportOptions::default(),
+            tls: TlsOptions::default(),
+            cert_pins: Vec::new(),
+            client_cert: None,
+            max_concurrent: 1,
+            binary_frames: false,
+            stream_bodies: false,
+            heartbeat: false,
+            user_agent: None,
+            compress_types: CompressTypes::default(),
+            tls_connector: OnceLock::new(),
+        })
+    } else {
+        // Backward compatibility: no protocol means plain TCP
+        let (host, port) = parse_host_port(addr, 7000)?;
+        Ok(ServerConfig {
+            addr: format!("{}:{}", host, port),
+            use_tls: false,
+            hostname: host,
+            path: DEFAULT_TUNNEL_PATH.to_string(),
+            auth,
+            upgrade_secret: None,
+            visitor_auth: None,
+            https_only: false,
+            cors_origins: Vec::new(),
+            schedule: None,
+            labels,
+            transport: TransportOptions::default(),
+            tls: TlsOptions::default(),
+            cert_pins: Vec::new(),
+            client_cert: None,
+            max_concurrent: 1,
+            binary_frames: false,
+            stream_bodies: false,
+            heartbeat: false,
+            user_agent: None,
+            compress_types: CompressTypes::default(),
+            tls_connector: OnceLock::new(),
+        })
+    }
+}
+
+/// Parses host and port from address string
+pub fn parse_host_port(addr: &str, default_port: u16) -> Result<(String, u16), AddressError> {
+    // Remove trailing slash if present
+    let addr = addr.trim_end_matches('/');
+
+    if let Some(colon_pos) = addr.rfind(':') {
+        // Check if this is an IPv6 address
+        if addr.starts_with('[') {
+            // IPv6 format: [host]:port or [host]
+            if let Some(bracket_pos) = addr.find(']') {
+                let host = addr[1..bracket_pos].to_string();
+                if colon_pos > bracket_pos {
+                    // Has port
+                    let port_str = &addr[colon_pos + 1..];
+                    let port = port_str.parse::<u16>()
+                        .map_err(|_| AddressError::InvalidPort(port_str.to_string()))?;
+                    Ok((host, port))
+                } else {
+                    // No port
+                    Ok((host, default_port))
+                }
+            } else {
+                Err(AddressError::InvalidIpv6)
+            }
+        } else {
+            // IPv4 or hostname: host:port
+            let host = addr[..colon_pos].to_string();
+            let port_str = &addr[colon_pos + 1..];
+            let port = port_str.parse::<u16>()
+                .map_err(|_| AddressError::InvalidPort(port_str.to_string()))?;
+            Ok((host, port))
+        }
+    } else {
+        // No port specified, use default
+        Ok((addr.to_string(), default_port))
+    }
+}
+
+/// Creates a TLS connector trusting the bundled Mozilla root certificates,
+/// and only certificates matching `pins` among them when any are given
+pub fn create_tls_connector(
+    options: &TlsOptions,
+    pins: &[CertPin],
+    client_cert: Option<&ClientCert>,
+) -> Result<TlsConnector, rustls::Error> {
+    let mut root_store = RootCertStore::empty();
+
+    // Add system root certificates
+    for cert in webpki_roots::TLS_SERVER_ROOTS.iter() {
+        root_store.roots.push(cert.clone());
+    }
+
+    let config = options.pinned_client_config(root_store, pins, client_cert)?;
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// What the client learned from a successful upgrade
+#[derive(Debug, Clone, Copy)]
+pub struct Handshake {
+    pub rtt: Duration,                     // From sending the request to receiving the full response headers
+    pub stats_interval: Option<Duration>,  // How often the server wants stats reports (None: not at all)
+    pub header_limits: Option<HeaderLimits>,  // Limits the server enforces on responses (None: not announced)
+    pub tunnel_id: Option<u64>,            // The server's ID for this connection (None: not announced)
+    pub client_addr: Option<SocketAddr>,   // Our address as the server sees it (None: not announced)
+    pub multiplex: Option<usize>,          // Requests the server sends at once (None: one at a time)
+    pub binary_frames: bool,               // Frames may be binary (see ENCODING_HEADER)
+    pub stream_bodies: bool,               // Large bodies may follow their message in body frames (see STREAM_HEADER)
+    pub stream_trailers: bool,             // The end of a streamed body may carry its trailers (see STREAM_TRAILERS_HEADER)
+    pub stream_credit: Option<usize>,      // Body frames of one body the server takes ahead of credit (see STREAM_CREDIT_HEADER; None: not agreed)
+    pub heartbeat: bool,                   // Both ends send PINGs and answer them (see HEARTBEAT_HEADER)
+    pub compression: Option<Compression>,  // Both ends compress frames with it (see COMPRESSION_HEADER; None: off)
+}
+
+/// Sends HTTP Upgrade request for `config` over any stream type
+pub async fn send_upgrade_request<S: AsyncReadExt + AsyncWriteExt + Unpin>(
+    stream: &mut S,
+    config: &ServerConfig,
+) -> Result<Handshake, UpgradeError> {
+    // Build Authorization header if credentials provided
+    let auth_header = if let Some(credentials) = &config.auth {
+        let encoded = encode_body(credentials.as_bytes());
+        Some(format!("Authorization: Basic {}\r\n", encoded))
+    } else {
+        None
+    };
+
+    // Send HTTP Upgrade request
+    let mut upgrade_request = format!(
+        "GET {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Upgrade: tunnel\r\n\
+         Connection: Upgrade\r\n",
+        config.path, config.hostname
+    );
+
+    // Lets the server's operators tell deployed client versions apart
+    if let Some(user_agent) = &config.user_agent {
+        upgrade_request.push_str(&format!("User-Agent: {}\r\n", user_agent));
+    }
+
+    // Add Authorization header if present
+    if let Some(auth) = auth_header {
+        upgrade_request.push_str(&auth);
+    }
+
+    // Without the preshared secret a server that requires one does not treat this as an upgrade
+    if let Some(secret) = &config.upgrade_secret {
+        upgrade_request.push_str(&format!("{}: {}\r\n", UPGRADE_SECRET_HEADER, secret));
+    }
+
+    // Add one label header per tunnel label
+    for (key, value) in &config.labels {
+        upgrade_request.push_str(&format!("{}: {}={}\r\n", LABEL_HEADER, key, value));
+    }
+
+    // Credentials the server should require of visitors to this tunnel
+    if let Some(credentials) = &config.visitor_auth {
+        upgrade_request.push_str(&format!("{}: {}\r\n", VISITOR_AUTH_HEADER, encode_body(credentials.as_bytes())));
+    }
+
+    // Plain-HTTP visitors to this tunnel should be sent to HTTPS
+    if config.https_only {
+        upgrade_request.push_str(&format!("{}: true\r\n", HTTPS_ONLY_HEADER));
+    }
+
+    // The server should handle CORS for these origins
+    if !config.cors_origins.is_empty() {
+        upgrade_request.push_str(&format!("{}: {}\r\n", CORS_HEADER, config.cors_origins.join(",")));
+    }
+
+    // Visitors should only be routed to this tunnel in these windows
+    if let Some(schedule) = &config.schedule {
+        upgrade_request.push_str(&format!("{}: {}\r\n", SCHEDULE_HEADER, schedule));
+    }
+
+    // Limits on the requests this client accepts
+    upgrade_request.push_str(&format!("{}: {}\r\n", HEADER_LIMITS_HEADER, config.transport.header_limits.to_header_value()));
+
+    // Requests this client can handle at once, if the server multiplexes
+    if config.max_concurrent > 1 {
+        upgrade_request.push_str(&format!("{}: {}\r\n", MULTIPLEX_HEADER, config.max_concurrent));
+        // Requests the server gave up on may be cancelled by their ID
+        upgrade_request.push_str(&format!("{}: true\r\n", CANCEL_HEADER));
+    }
+
+    // Bodies may travel as they are rather than base64 in JSON
+    if config.binary_frames {
+        upgrade_request.push_str(&format!("{}: {}\r\n", ENCODING_HEADER, BINARY_ENCODING));
+    }
+
+    // Large bodies may be sent in pieces, tagged with the request ID, end with their trailers,
+    // and go against credit of this many pieces
+    if config.stream_bodies && config.binary_frames && config.max_concurrent > 1 {
+        upgrade_request.push_str(&format!("{}: true\r\n", STREAM_HEADER));
+        upgrade_request.push_str(&format!("{}: true\r\n", STREAM_TRAILERS_HEADER));
+        upgrade_request.push_str(&format!("{}: {}\r\n", STREAM_CREDIT_HEADER, config.transport.stream_window_chunks));
+    }
+
+    // Dead connections may be noticed with PINGs
+    if config.heartbeat {
+        upgrade_request.push_str(&format!("{}: true\r\n", HEARTBEAT_HEADER));
+    }
+
+    // Frames may be compressed, with this algorithm both ways
+    if let Some(compression) = config.transport.compression {
+        upgrade_request.push_str(&format!("{}: {}\r\n", COMPRESSION_HEADER, compression));
+    }
+
+    // End of headers
+    upgrade_request.push_str("\r\n");
+
+    let sent_at = Instant::now();
+    stream.write_all(upgrade_request.as_bytes()).await.map_err(UpgradeError::Send)?;
+    stream.flush().await.map_err(UpgradeError::Send)?;
+
+    // Read HTTP response
+    let mut response_buffer = vec![0u8; 1024];
+    let mut total_read = 0;
+
+    // Read until we have the complete response headers (ending with \r\n\r\n), one byte
+    // at a time: the server may send the first request frame right behind them
+    loop {
+        if total_read >= response_buffer.len() {
+            return Err(UpgradeError::HeadersTooLarge);
+        }
+
+        let n = stream.read(&mut response_buffer[total_read..total_read + 1]).await
+            .map_err(UpgradeError::Read)?;
+
+        if n == 0 {
+            return Err(UpgradeError::Closed);
+        }
+
+        total_read += n;
+
+        // Check if we have the end of headers
+        if response_buffer[..total_read].ends_with(b"\r\n\r\n") {
+            break;
+        }
+    }
+    let rtt = sent_at.elapsed();
+
+    // Parse the HTTP response status line
+    let response_str = String::from_utf8_lossy(&response_buffer[..total_read]);
+    let first_line = response_str.lines().next().unwrap_or_default();
+    let status = first_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok());
+
+    // Refused: the body often says why (e.g. an expired token), so it is read too
+    if status != Some(101) {
+        let message = read_refusal_message(stream, &response_str).await;
+        if status == Some(401) {
+            return Err(UpgradeError::Unauthorized { message });
+        }
+        return Err(UpgradeError::Rejected { status, status_line: first_line.to_string(), message });
+    }
+
+    // Verify Upgrade and Connection headers
+    let has_upgrade = response_str.to_lowercase().contains("upgrade: tunnel");
+    let has_connection = response_str.to_lowercase().contains("connection: upgrade");
+
+    if !has_upgrade || !has_connection {
+        return Err(UpgradeError::MissingHeaders);
+    }
+
+    // Servers that predate stats reports, header limits, tunnel IDs, multiplexing, binary frames, streamed bodies, credit, heartbeats or compression do not send these headers
+    let stats_interval = header_value(&response_str, STATS_HEADER)
+        .and_then(|value| value.parse::<u64>().ok())
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs);
+    let header_limits = header_value(&response_str, HEADER_LIMITS_HEADER).map(HeaderLimits::from_header_value);
+    let tunnel_id = header_value(&response_str, TUNNEL_ID_HEADER).and_then(|value| value.parse().ok());
+    let client_addr = header_value(&response_str, CLIENT_ADDR_HEADER).and_then(|value| value.parse().ok());
+    let multiplex = header_value(&response_str, MULTIPLEX_HEADER)
+        .and_then(|value| value.parse::<usize>().ok())
+        .filter(|max| *max > 1 && *max <= config.max_concurrent);
+    let binary_frames = config.binary_frames
+        && header_value(&response_str, ENCODING_HEADER).is_some_and(|value| value.eq_ignore_ascii_case(BINARY_ENCODING));
+    let stream_bodies = binary_frames
+        && multiplex.is_some()
+        && header_value(&response_str, STREAM_HEADER).is_some_and(|value| value.eq_ignore_ascii_case("true"));
+    let stream_trailers = stream_bodies
+        && header_value(&response_str, STREAM_TRAILERS_HEADER).is_some_and(|value| value.eq_ignore_ascii_case("true"));
+    let stream_credit = header_value(&response_str, STREAM_CREDIT_HEADER)
+        .and_then(|value| value.parse::<usize>().ok())
+        .filter(|chunks| stream_bodies && *chunks > 0);
+    let heartbeat = config.heartbeat
+        && header_value(&response_str, HEARTBEAT_HEADER).is_some_and(|value| value.eq_ignore_ascii_case("true"));
+    let compression = config.transport.compression
+        .filter(|offered| header_value(&response_str, COMPRESSION_HEADER).and_then(Compression::parse) == Some(*offered));
+
+    info!("HTTP Upgrade successful");
+    Ok(Handshake { rtt, stats_interval, header_limits, tunnel_id, client_addr, multiplex, binary_frames, stream_bodies, stream_trailers, stream_credit, heartbeat, compression })
+}
+
+/// Most bytes of a refused upgrade's body read from the server, and kept once decoded
+const MAX_REFUSAL_BODY: usize = 16 * 1024;
+
+/// How long the body of a refused upgrade may take to arrive
+const REFUSAL_BODY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Most characters of the server's message shown in an error
+const MAX_REFUSAL_MESSAGE_CHARS: usize = 300;
+
+/// The message in the body of a refused upgrade whose `headers` were just read, if it has one
+///
+/// The body may be chunked and compressed (gzip or deflate), e.g. by a proxy in
+/// front of the server. One that is not framed is read until the server closes
+/// the connection or REFUSAL_BODY_TIMEOUT passes.
+async fn read_refusal_message<S: AsyncReadExt + Unpin>(stream: &mut S, headers: &str) -> Option<String> {
+    let content_length = header_value(headers, "content-length").and_then(|value| value.parse::<usize>().ok());
+    let chunked = header_value(headers, "transfer-encoding").is_some_and(|value| value.to_ascii_lowercase().contains("chunked"));
+    let limit = content_length.unwrap_or(MAX_REFUSAL_BODY).min(MAX_REFUSAL_BODY);
+
+    let mut body = Vec::new();
+    let read = async {
+        let mut buf = [0u8; 1024];
+        while body.len() < limit {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            body.extend_from_slice(&buf[..n]);
+            if chunked && body.ends_with(b"0\r\n\r\n") {
+                break;
+            }
+        }
+        io::Result::Ok(())
+    };
+    // Whatever arrived is still worth showing when the rest does not
+    let _ = tokio::time::timeout(REFUSAL_BODY_TIMEOUT, read).await;
+    body.truncate(limit);
+
+    if chunked {
+        body = dechunk(&body);
+    }
+    if let Some(encoding) = header_value(headers, "content-encoding") {
+        body = match decode_body(encoding, &body) {
+            Ok(decoded) => decoded,
+            Err(e) => return Some(format!("undecodable {} body: {}", encoding, e)),
+        };
+    }
+    refusal_message(&String::from_utf8_lossy(&body))
+}
+
+/// Decodes a body sent with `Content-Encoding: encoding` (`gzip`, `x-gzip` or
+/// `deflate`), keeping at most MAX_REFUSAL_BODY bytes; other encodings are an error
+fn decode_body(encoding: &str, body: &[u8]) -> Result<Vec<u8>, String> {
+    let decoder: Box<dyn Read + '_> = match encoding.trim().to_ascii_lowercase().as_str() {
+        "gzip" | "x-gzip" => Box::new(GzDecoder::new(body)),
+        // Meant to be zlib-wrapped, but some servers send raw DEFLATE
+        "deflate" => match body {
+            [cmf, flg, ..] if cmf & 0x0f == 8 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0 => Box::new(ZlibDecoder::new(body)),
+            _ => Box::new(DeflateDecoder::new(body)),
+        },
+        "identity" => Box::new(body),
+        other => return Err(format!("unsupported content encoding '{}'", other)),
+    };
+    let mut decoded = Vec::new();
+    decoder.take(MAX_REFUSAL_BODY as u64).read_to_end(&mut decoded).map_err(|e| e.to_string())?;
+    Ok(decoded)
+}
+
+/// Body of a chunked message; a truncated one yields what arrived
+fn dechunk(mut data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::new();
+    loop {
+        let Some(line_end) = data.windows(2).position(|pair| pair == b"\r\n") else {
+            return body;
+        };
+        let size = std::str::from_utf8(&data[..line_end]).ok()
+            .and_then(|line| usize::from_str_radix(line.split(';').next().unwrap_or_default().trim(), 16).ok());
+        let Some(size) = size.filter(|&size| size > 0) else {
+            return body;
+        };
+        data = &data[line_end + 2..];
+        let chunk = &data[..size.min(data.len())];
+        body.extend_from_slice(chunk);
+        data = data.get(chunk.len() + 2..).unwrap_or_default();
+    }
+}
+
+/// One line of text out of an error body: the `error` or `message` of a JSON
+/// object, or the text of an HTML page, or the body itself
+fn refusal_message(body: &str) -> Option<String> {
+    let json_message = serde_json::from_str::<serde_json::Value>(body).ok().and_then(|value| {
+        ["error", "message", "detail"].iter().find_map(|key| value.get(key)?.as_str().map(str::to_string))
+    });
+    let text = match json_message {
+        Some(message) => message,
+        None if body.trim_start().starts_with('<') => strip_tags(body),
+        None => body.to_string(),
+    };
+    let mut message: String = text.split_whitespace().collect::<Vec<_>>().join(" ").chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_REFUSAL_MESSAGE_CHARS + 1)
+        .collect();
+    if message.chars().count() > MAX_REFUSAL_MESSAGE_CHARS {
+        message = message.chars().take(MAX_REFUSAL_MESSAGE_CHARS).collect::<String>() + "...";
+    }
+    (!message.is_empty()).then_some(message)
+}
+
+/// Text of an HTML page, tags replaced by spaces
+fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text
+}
+
+/// Finds a header in raw response headers, ignoring case
+fn header_value<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    response
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(header, _)| header.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// Connects to the server and performs HTTP Upgrade handshake
+/// Returns the upgraded stream and what the handshake negotiated
+pub async fn connect_and_upgrade(config: &ServerConfig) -> Result<(TunnelStream, Handshake), ConnectError> {
+    // Connect TCP
+    let tcp_stream = TcpStream::connect(&config.addr).await
+        .map_err(|source| ConnectError::Tcp { addr: config.addr.clone(), source })?;
+
+    config.transport.apply_to_stream(&tcp_stream)
+        .map_err(ConnectError::SocketOptions)?;
+
+    info!("TCP connection established to {}", config.addr);
+
+    if config.use_tls {
+        // Establish TLS connection
+        info!("Establishing TLS connection to {}", config.hostname);
+
+        let tls_connector = config.tls_connector()?;
+
+        let server_name = ServerName::try_from(config.hostname.clone())
+            .map_err(|source| ConnectError::InvalidHostname { hostname: config.hostname.clone(), source })?;
+
+        let mut tls_stream = tls_connector.connect(server_name, tcp_stream).await
+            .map_err(ConnectError::Tls)?;
+
+        info!("TLS connection established");
+
+        // Send HTTP Upgrade over TLS
+        let handshake = send_upgrade_request(&mut tls_stream, config).await?;
+
+        Ok((TunnelStream::Tls(Box::new(tls_stream)), handshake))
+    } else {
+        // Plain TCP connection
+        let mut tcp_stream = tcp_stream;
+
+        // Send HTTP Upgrade over plain TCP
+        let handshake = send_upgrade_request(&mut tcp_stream, config).await?;
+
+        Ok((TunnelStream::Plain(tcp_stream), handshake))
+    }
+}
@@ -0,0 +1,342 @@
+//! Layered configuration shared by both binaries.
+//!
+//! Every setting is a flat `KEY=VALUE` pair named after its environment
+//! variable. Values are looked up, highest precedence first, in:
+//!
+//! 1. command-line flags (`--local-port 3000` or `--local-port=3000` sets `LOCAL_PORT`)
+//! 2. the config file (`--config PATH`, or the binary's `*_CONFIG` variable)
+//! 3. environment variables
+//!
+//! The file wins over the environment so that a watched file can change a
+//! setting without restarting the process.
+
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::rules;
+
+/// Where a setting's value came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Origin {
+    Env,
+    File,
+    Cli,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Env => write!(f, "environment"),
+            Origin::File => write!(f, "config file"),
+            Origin::Cli => write!(f, "command line"),
+        }
+    }
+}
+
+/// What the binary was asked to do on the command line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Run,          // Normal operation
+    CheckConfig,  // Validate and print the effective configuration, then exit
+    Help,         // Print usage, then exit
+    Status { json: bool },  // Ask the running process for its status, then exit (`status [--json]`)
+}
+
+/// Settings collected from the command line, config file and environment
+#[derive(Clone)]
+pub struct ConfigSource {
+    known_keys: Vec<&'static str>,
+    env: BTreeMap<String, String>,
+    file_path: Option<PathBuf>,
+    file: BTreeMap<String, String>,
+    cli: BTreeMap<String, String>,
+}
+
+impl ConfigSource {
+    /// Builds the layered source for a binary
+    ///
+    /// # Arguments
+    /// * `known_keys` - Every setting the binary understands; anything else in the file or on the command line is an error
+    /// * `config_key` - Environment variable naming the config file (e.g. `CLIENT_CONFIG`)
+    /// * `args` - Command-line arguments, without the program name
+    /// * `env` - Environment lookup
+    ///
+    /// # Returns
+    /// * `Ok((source, mode))` on success
+    /// * `Err` describing the unknown flag, missing value, unreadable file or unknown file key
+    pub fn load(
+        known_keys: Vec<&'static str>,
+        config_key: &str,
+        args: impl IntoIterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<(Self, Mode), String> {
+        let CliArgs { values: cli, config_path: cli_config, mode } = parse_args(&known_keys, args)?;
+
+        let env_values = known_keys
+            .iter()
+            .filter_map(|key| env(key).map(|value| (key.to_string(), value)))
+            .collect();
+
+        let file_path = cli_config.or_else(|| env(config_key).map(PathBuf::from));
+
+        let mut source = Self {
+            known_keys,
+            env: env_values,
+            file_path,
+            file: BTreeMap::new(),
+            cli,
+        };
+        source.file = source.read_file()?;
+
+        Ok((source, mode))
+    }
+
+    /// Returns a copy with the config file read again (command line and environment unchanged)
+    pub fn reload_file(&self) -> Result<Self, String> {
+        let mut reloaded = self.clone();
+        reloaded.file = self.read_file()?;
+        Ok(reloaded)
+    }
+
+    /// Path of the config file, if one is configured
+    pub fn file_path(&self) -> Option<&Path> {
+        self.file_path.as_deref()
+    }
+
+    /// Returns the effective value of a setting
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.lookup(key).map(|(value, _)| value.clone())
+    }
+
+    /// Every explicitly set value with its origin, secrets redacted, in key order
+    pub fn explicit_settings(&self) -> Vec<(&'static str, String, Origin)> {
+        self.known_keys
+            .iter()
+            .filter_map(|key| {
+                self.lookup(key).map(|(value, origin)| {
+                    let shown = if is_secret_key(key) { REDACTED.to_string() } else { value.clone() };
+                    (*key, shown, origin)
+                })
+            })
+            .collect()
+    }
+
+    fn lookup(&self, key: &str) -> Option<(&String, Origin)> {
+        self.cli.get(key).map(|v| (v, Origin::Cli))
+            .or_else(|| self.file.get(key).map(|v| (v, Origin::File)))
+            .or_else(|| self.env.get(key).map(|v| (v, Origin::Env)))
+    }
+
+    fn read_file(&self) -> Result<BTreeMap<String, String>, String> {
+        let Some(path) = &self.file_path else {
+            return Ok(BTreeMap::new());
+        };
+
+        let values = read_config_file(path)?;
+        if let Some(key) = values.keys().find(|key| !self.known_keys.contains(&key.as_str())) {
+            return Err(format!(
+                "{}: unknown setting {} (known settings: {})",
+                path.display(), key, self.known_keys.join(", ")
+            ));
+        }
+        Ok(values)
+    }
+}
+
+/// Reads a KEY=VALUE config file using the same keys as the environment variables
+/// Blank lines and lines starting with '#' are ignored; surrounding quotes are stripped
+pub fn read_config_file(path: &Path) -> Result<BTreeMap<String, String>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+
+    let mut values = BTreeMap::new();
+    for line in rules::lines(&contents) {
+        let (key, value) = line.text.split_once('=').ok_or_else(|| {
+            format!("{}:{}: expected KEY=VALUE", path.display(), line.number)
+        })?;
+        let value = value.trim().trim_matches('"');
+        values.insert(key.trim().to_string(), value.to_string());
+    }
+
+    Ok(values)
+}
+
+/// Report printed by `--check-config`: config file, explicitly set values with
+/// their origin, and the full effective configuration as JSON
+pub fn check_report<T: Serialize>(source: &ConfigSource, settings: &T) -> String {
+    let mut report = String::from("Configuration OK\n\n");
+
+    let file = source.file_path().map_or("none".to_string(), |path| path.display().to_string());
+    report.push_str(&format!("Config file: {}\n\n", file));
+
+    report.push_str("Explicitly set:\n");
+    let explicit = source.explicit_settings();
+    if explicit.is_empty() {
+        report.push_str("  (nothing, all defaults)\n");
+    }
+    for (key, value, origin) in explicit {
+        report.push_str(&format!("  {}={}  ({})\n", key, value, origin));
+    }
+
+    let effective = serde_json::to_string_pretty(settings)
+        .unwrap_or_else(|e| format!("<failed to serialize: {}>", e));
+    report.push_str(&format!("\nEffective configuration:\n{}\n", effective));
+    report
+}
+
+/// Usage text listing the common flags and one flag per known setting
+pub fn usage(binary: &str, config_key: &str, known_keys: &[&str]) -> String {
+    let mut text = format!(
+        "Usage: {} [OPTIONS]\n       {} status [--json] [OPTIONS]\n\n\
+         Options:\n  \
+         --config <PATH>        KEY=VALUE config file (or {})\n  \
+         --check-config         Validate and print the effective configuration, then exit\n  \
+         -h, --help             Print this help\n\n\
+         Settings (flag, or environment variable of the same name):\n",
+        binary, binary, config_key
+    );
+    for key in known_keys {
+        text.push_str(&format!("  --{:<28} {}\n", flag_name(key), key));
+    }
+    text
+}
+
+/// Placeholder printed instead of secret values
+pub const REDACTED: &str = "<redacted>";
+
+/// Whether a setting holds a credential that must not be printed
+pub fn is_secret_key(key: &str) -> bool {
+    ["AUTH", "SECRET", "TOKEN", "PASSWORD"].iter().any(|word| key.contains(word))
+}
+
+/// Serializes an optional secret as `"<redacted>"` (or null when unset)
+pub fn serialize_redacted<S: Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(_) => serializer.serialize_str(REDACTED),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Serializes a duration as whole seconds
+pub fn serialize_secs<S: Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(value.as_secs())
+}
+
+/// Serializes a duration as whole milliseconds
+pub fn serialize_millis<S: Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(value.as_millis() as u64)
+}
+
+/// Serializes an optional duration as whole seconds (or null when unset)
+pub fn serialize_opt_secs<S: Serializer>(value: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(duration) => serializer.serialize_some(&duration.as_secs()),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Serializes an optional duration as whole milliseconds (or null when unset)
+pub fn serialize_opt_millis<S: Serializer>(value: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(duration) => serializer.serialize_some(&(duration.as_millis() as u64)),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Parses TUNNEL_PATH: `/` followed by one or more segments of letters, digits, `-`, `.`, `_` and `~`
+pub fn parse_tunnel_path(value: &str) -> Result<String, String> {
+    let path = value.trim();
+    let valid = path.strip_prefix('/').is_some_and(|rest| {
+        rest.split('/').all(|segment| {
+            !matches!(segment, "" | "." | "..") && segment.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~'))
+        })
+    });
+    if !valid {
+        return Err(format!("Invalid TUNNEL_PATH: {} (expected e.g. /tunnel or /t/4f9a1c)", value));
+    }
+    Ok(path.to_string())
+}
+
+/// Parses TUNNEL_UPGRADE_SECRET, which travels as a header value
+pub fn parse_upgrade_secret(value: &str) -> Result<String, String> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err("TUNNEL_UPGRADE_SECRET may only contain printable ASCII characters other than spaces".to_string());
+    }
+    Ok(value.to_string())
+}
+
+/// `LOCAL_PORT` -> `local-port`
+fn flag_name(key: &str) -> String {
+    key.to_ascii_lowercase().replace('_', "-")
+}
+
+/// Parsed command line
+struct CliArgs {
+    values: BTreeMap<String, String>,  // Setting overrides keyed by setting name
+    config_path: Option<PathBuf>,      // --config
+    mode: Mode,
+}
+
+/// Splits the command line into setting overrides, an optional config path and the mode
+fn parse_args(
+    known_keys: &[&'static str],
+    args: impl IntoIterator<Item = String>,
+) -> Result<CliArgs, String> {
+    let mut values = BTreeMap::new();
+    let mut config_path = None;
+    let mut mode = Mode::Run;
+    let mut json = false;
+
+    let mut args = args.into_iter().peekable();
+    if args.next_if(|arg| arg == "status").is_some() {
+        mode = Mode::Status { json: false };
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check-config" => mode = Mode::CheckConfig,
+            "-h" | "--help" => mode = Mode::Help,
+            "--json" => json = true,
+            _ => {
+                let Some(flag) = arg.strip_prefix("--") else {
+                    return Err(format!("Unexpected argument '{}' (see --help)", arg));
+                };
+
+                // --flag=value or --flag value
+                let (name, inline_value) = match flag.split_once('=') {
+                    Some((name, value)) => (name, Some(value.to_string())),
+                    None => (flag, None),
+                };
+                let key = match name {
+                    "config" => None,
+                    _ => Some(
+                        known_keys
+                            .iter()
+                            .find(|key| flag_name(key) == name)
+                            .ok_or_else(|| format!("Unknown option --{} (see --help)", name))?,
+                    ),
+                };
+                let value = match inline_value.or_else(|| args.next()) {
+                    Some(value) => value,
+                    None => return Err(format!("Missing value for --{}", name)),
+                };
+
+                let Some(key) = key else {
+                    config_path = Some(PathBuf::from(value));
+                    continue;
+                };
+                values.insert(key.to_string(), value);
+            }
+        }
+    }
+
+    match &mut mode {
+        Mode::Status { json: status_json } => *status_json = json,
+        _ if json => return Err("--json only applies to the status command".to_string()),
+        _ => {}
+    }
+
+    Ok(CliArgs { values, config_path, mode })
+}
@@ -0,0 +1,119 @@
+//! Repeated error lines collapsed into counts.
+//!
+//! A broken tunnel fails every request the same way, and one error line per
+//! request buries everything else in the log. [`LogDedup::admit`] lets the
+//! first occurrence of a message through and counts its repeats; the next
+//! occurrence after the window is logged again with that count, and
+//! [`report_suppressed`] reports the count of a message that stopped recurring.
+//! [`error_dedup!`](crate::error_dedup) does this for `error!` lines.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+/// Default for LOG_DEDUP_SECS
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(10);
+
+/// Most distinct messages tracked at once; beyond this, new ones are always logged
+const MAX_TRACKED: usize = 1000;
+
+/// Error lines written through `error_dedup!`
+pub static ERRORS: LogDedup = LogDedup::new(DEFAULT_WINDOW);
+
+struct Seen {
+    logged_at: Instant,
+    suppressed: u64,
+}
+
+/// Tracks recently logged messages and how often each was suppressed since
+pub struct LogDedup {
+    window_ms: AtomicU64,  // 0: nothing is suppressed
+    seen: Mutex<BTreeMap<String, Seen>>,
+}
+
+impl LogDedup {
+    pub const fn new(window: Duration) -> Self {
+        Self {
+            window_ms: AtomicU64::new(window.as_millis() as u64),
+            seen: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_millis(self.window_ms.load(Ordering::Relaxed))
+    }
+
+    /// Changes how long repeats are suppressed (zero: never)
+    pub fn set_window(&self, window: Duration) {
+        self.window_ms.store(window.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Whether `message` should be logged now, and if so how many repeats were suppressed before it
+    pub fn admit(&self, message: &str) -> Option<u64> {
+        let window = self.window();
+        if window.is_zero() {
+            return Some(0);
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        let now = Instant::now();
+        match seen.get_mut(message) {
+            Some(entry) if now.duration_since(entry.logged_at) < window => {
+                entry.suppressed += 1;
+                None
+            }
+            Some(entry) => {
+                entry.logged_at = now;
+                Some(std::mem::take(&mut entry.suppressed))
+            }
+            None => {
+                if seen.len() < MAX_TRACKED {
+                    seen.insert(message.to_string(), Seen { logged_at: now, suppressed: 0 });
+                }
+                Some(0)
+            }
+        }
+    }
+
+    /// Forgets messages not logged within the window, returning those with suppressed repeats
+    pub fn take_expired(&self) -> Vec<(String, u64)> {
+        let window = self.window();
+        let mut expired = Vec::new();
+        self.seen.lock().unwrap().retain(|message, entry| {
+            if entry.logged_at.elapsed() < window {
+                return true;
+            }
+            if entry.suppressed > 0 {
+                expired.push((message.clone(), entry.suppressed));
+            }
+            false
+        });
+        expired
+    }
+}
+
+/// Logs the repeat counts of `ERRORS` messages that stopped recurring, until the process exits
+pub async fn report_suppressed() {
+    loop {
+        tokio::time::sleep(ERRORS.window().max(Duration::from_secs(1))).await;
+        for (message, suppressed) in ERRORS.take_expired() {
+            error!("{} (repeated {} times since last logged)", message, suppressed);
+        }
+    }
+}
+
+/// Like `tracing::error!`, but repeats of the same line within LOG_DEDUP_SECS
+/// are counted instead of logged
+#[macro_export]
+macro_rules! error_dedup {
+    ($($arg:tt)+) => {{
+        let message = format!($($arg)+);
+        match $crate::dedup::ERRORS.admit(&message) {
+            Some(0) => ::tracing::error!("{}", message),
+            Some(suppressed) => ::tracing::error!("{} (repeated {} times since last logged)", message, suppressed),
+            None => {}
+        }
+    }};
+}
@@ -0,0 +1,322 @@
+//! Typed message helpers on top of the length-prefixed frames in tunnel-protocol.
+//!
+//! Both binaries serialize a message to JSON (or a binary frame, see
+//! `tunnel_protocol::ENCODING_HEADER`), write it as one frame, and do the
+//! reverse on the other end; these helpers keep that plumbing in one place.
+//! A streamed body (see `tunnel_protocol::STREAM_HEADER`) is passed around as
+//! `BodyPiece`s and written as one frame per piece, up to TUNNEL_STREAM_WINDOW_CHUNKS
+//! of them at once: pieces of one body are queued at most that many deep on
+//! each side, so a slow reader stalls the sender rather than the memory growing.
+//! With credit agreed (see `tunnel_protocol::STREAM_CREDIT_HEADER`), a body is
+//! sent against its `Credits` and received through a `BodyInlet`, so a slow
+//! reader stalls only the sender of its own body.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::{Arc, Mutex, Weak};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Semaphore};
+use tunnel_protocol::{
+    body_chunk_prefix, encode_binary_into, encode_body_end, read_frame, BufferPool, ControlFrame, FrameWriter, HeaderValueBytes, ProtocolError,
+    STREAM_PREFIX_LEN,
+};
+
+/// Error from sending or receiving a typed message
+#[derive(Debug, Error)]
+pub enum MessageError {
+    #[error("Failed to serialize message: {0}")]
+    Encode(#[source] serde_json::Error),
+
+    #[error("Failed to write frame: {0}")]
+    Write(#[source] ProtocolError),
+
+    #[error("Failed to read frame: {0}")]
+    Read(#[source] ProtocolError),
+
+    #[error("Failed to deserialize message: {0}")]
+    Decode(#[source] serde_json::Error),
+}
+
+/// Scratch buffers for serializing outgoing messages
+///
+/// Buffers above 1 MiB (large bodies) are released rather than kept around.
+pub static MESSAGE_BUFFERS: BufferPool = BufferPool::new(64, 1024 * 1024);
+
+/// Serializes a message to JSON in a buffer taken from `MESSAGE_BUFFERS`.
+///
+/// Hand the buffer back with `MESSAGE_BUFFERS.put` once its contents are written.
+pub fn encode_message<T: Serialize>(message: &T) -> Result<BytesMut, MessageError> {
+    let mut buf = MESSAGE_BUFFERS.get();
+    match serde_json::to_writer((&mut buf).writer(), message) {
+        Ok(()) => Ok(buf),
+        Err(e) => {
+            MESSAGE_BUFFERS.put(buf);
+            Err(MessageError::Encode(e))
+        }
+    }
+}
+
+/// Encodes a message, whose `body` must be empty, as a binary frame carrying `body`, in a buffer taken from `MESSAGE_BUFFERS`
+///
+/// Hand the buffer back with `MESSAGE_BUFFERS.put` once its contents are written.
+pub fn encode_binary_message<T: Serialize>(message: &T, body: &[u8]) -> Result<BytesMut, MessageError> {
+    let mut buf = MESSAGE_BUFFERS.get();
+    match encode_binary_into(&mut buf, message, body) {
+        Ok(()) => Ok(buf),
+        Err(e) => {
+            MESSAGE_BUFFERS.put(buf);
+            Err(MessageError::Encode(e))
+        }
+    }
+}
+
+/// Serializes a message to JSON and writes it as a single frame.
+///
+/// Coalesced frames may stay buffered; call `flush` on the writer before waiting for the peer.
+///
+/// # Returns
+/// * `Ok(())` on success
+/// * `Err` describing whether serialization or the write failed
+pub async fn send_message<W, T>(writer: &mut FrameWriter<W>, message: &T) -> Result<(), MessageError>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let payload = encode_message(message)?;
+    let result = writer.write_frame(&payload)
+        .await
+        .map_err(MessageError::Write);
+    MESSAGE_BUFFERS.put(payload);
+    result
+}
+
+/// Writes a message, whose `body` must be empty, as a single binary frame carrying `body`
+pub async fn send_binary_message<W, T>(writer: &mut FrameWriter<W>, message: &T, body: &[u8]) -> Result<(), MessageError>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let payload = encode_binary_message(message, body)?;
+    let result = writer.write_frame(&payload)
+        .await
+        .map_err(MessageError::Write);
+    MESSAGE_BUFFERS.put(payload);
+    result
+}
+
+/// A piece of a body streamed in BODY_CHUNK frames
+#[derive(Debug, Clone, PartialEq)]
+pub enum BodyPiece {
+    Data(Bytes),
+    End { complete: bool, sha256: Option<String>, trailers: Vec<(String, HeaderValueBytes)> },  // Not complete: the sender gave up on the body
+}
+
+/// Writes one piece of the body of request `id` as a BODY_CHUNK or BODY_END frame
+pub async fn write_body_piece<W: AsyncWrite + Unpin>(writer: &mut FrameWriter<W>, id: u64, piece: &BodyPiece) -> Result<(), ProtocolError> {
+    match piece {
+        BodyPiece::Data(data) => {
+            writer.start_frame(STREAM_PREFIX_LEN + data.len()).await?;
+            writer.write_payload(&body_chunk_prefix(id)).await?;
+            writer.write_payload(data).await
+        }
+        BodyPiece::End { complete, sha256, trailers } => writer.write_frame(&encode_body_end(id, *complete, sha256.as_deref(), trailers)).await,
+    }
+}
+
+/// Writes `first`, then the pieces already queued behind it in `pieces`, up to `window` in all, and flushes once
+///
+/// So chunks ready together go out back to back rather than one flush each.
+/// Bodies in `uncompressed` are written without trying to compress them; an
+/// ID leaves the set once its body ends. Returns how many pieces were written.
+pub async fn write_body_pieces<W: AsyncWrite + Unpin>(
+    writer: &mut FrameWriter<W>,
+    first: (u64, BodyPiece),
+    pieces: &mut mpsc::Receiver<(u64, BodyPiece)>,
+    window: usize,
+    uncompressed: &mut HashSet<u64>,
+) -> Result<usize, ProtocolError> {
+    let mut next = Some(first);
+    let mut written = 0;
+    while let Some((id, piece)) = next.take() {
+        if matches!(piece, BodyPiece::End { .. }) {
+            uncompressed.remove(&id);
+        } else if uncompressed.contains(&id) {
+            writer.skip_compression();
+        }
+        write_body_piece(writer, id, &piece).await?;
+        written += 1;
+        if written < window {
+            next = pieces.try_recv().ok();
+        }
+    }
+    writer.flush().await?;
+    Ok(written)
+}
+
+/// Credit for the streamed bodies one end sends, by request ID (see `tunnel_protocol::STREAM_CREDIT_HEADER`)
+///
+/// Each body starts with the peer's window and spends a unit per piece sent;
+/// CREDIT frames from the peer add to it. Once `Credits` is dropped (the
+/// connection ended), every wait for credit fails.
+pub struct Credits {
+    window: usize,  // Body frames of one body the peer takes ahead of credit
+    bodies: Mutex<HashMap<u64, Arc<Semaphore>>>,
+}
+
+impl Credits {
+    pub fn new(window: usize) -> Self {
+        Self { window: window.max(1), bodies: Mutex::default() }
+    }
+
+    /// Credit for the body of request `id`, until the returned handle is dropped
+    pub fn open(self: &Arc<Self>, id: u64) -> BodyCredit {
+        let credit = Arc::new(Semaphore::new(self.window));
+        self.bodies.lock().unwrap().insert(id, credit.clone());
+        BodyCredit { id, credit, credits: Arc::downgrade(self) }
+    }
+
+    /// Adds the credit the peer granted for the body of request `id`; ignored once that body ended
+    ///
+    /// Never past the window, whatever the peer sends.
+    pub fn grant(&self, id: u64, chunks: u64) {
+        if let Some(credit) = self.bodies.lock().unwrap().get(&id) {
+            let room = self.window.saturating_sub(credit.available_permits());
+            credit.add_permits(usize::try_from(chunks).unwrap_or(usize::MAX).min(room));
+        }
+    }
+}
+
+impl Drop for Credits {
+    fn drop(&mut self) {
+        for credit in self.bodies.get_mut().unwrap().values() {
+            credit.close();
+        }
+    }
+}
+
+/// Credit for one body being sent (see `Credits`)
+pub struct BodyCredit {
+    id: u64,
+    credit: Arc<Semaphore>,
+    credits: Weak<Credits>,
+}
+
+impl BodyCredit {
+    /// Spends a unit of credit, waiting for the peer to grant one; false once the connection ended
+    pub async fn spend(&self) -> bool {
+        match self.credit.acquire().await {
+            Ok(permit) => {
+                permit.forget();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+impl Drop for BodyCredit {
+    fn drop(&mut self) {
+        if let Some(credits) = self.credits.upgrade() {
+            credits.bodies.lock().unwrap().remove(&self.id);
+        }
+    }
+}
+
+/// Where the reader of a connection puts the pieces of one streamed body it receives
+pub struct BodyInlet {
+    pieces: mpsc::Sender<BodyPiece>,
+    credited: bool,  // The sender keeps within its credit, so the queue never has to be waited on
+}
+
+impl BodyInlet {
+    /// An inlet queueing up to `window` pieces for the receiver, and the receiver's end
+    ///
+    /// For a peer sending without credit: a full queue is waited on.
+    pub fn channel(window: usize) -> (Self, mpsc::Receiver<BodyPiece>) {
+        let (pieces, body) = mpsc::channel(window.max(1));
+        (Self { pieces, credited: false }, body)
+    }
+
+    /// An inlet for the body of request `id` sent against credit of `window` pieces, and the receiver's end
+    ///
+    /// A task of its own hands the pieces on, putting a grant of credit on
+    /// `grants` for each BODY_CHUNK once the receiver took it. Once the receiver
+    /// went away no more credit is granted, and the pieces still put are dropped:
+    /// the body is to be cancelled (see `BodyInlet::closed`).
+    pub fn credited(id: u64, window: usize, grants: mpsc::UnboundedSender<(u64, u64)>) -> (Self, mpsc::Receiver<BodyPiece>) {
+        let (pieces, mut queued) = mpsc::channel::<BodyPiece>(window.max(1));
+        let (body_tx, body) = mpsc::channel(1);
+        tokio::spawn(async move {
+            loop {
+                let piece = tokio::select! {
+                    piece = queued.recv() => piece,
+                    () = body_tx.closed() => return,
+                };
+                let Some(piece) = piece else {
+                    return;
+                };
+                let chunk = matches!(piece, BodyPiece::Data(_));
+                if body_tx.send(piece).await.is_err() || (chunk && grants.send((id, 1)).is_err()) {
+                    return;
+                }
+            }
+        });
+        (Self { pieces, credited: true }, body)
+    }
+
+    /// Resolves once nobody takes the pieces of the body any more: its receiver went away
+    pub fn closed(&self) -> impl Future<Output = ()> + Send + 'static {
+        let pieces = self.pieces.clone();
+        async move { pieces.closed().await }
+    }
+
+    /// Puts the next piece of the body; fails if the sender went past its credit
+    pub async fn put(&self, piece: BodyPiece) -> Result<(), ProtocolError> {
+        if !self.credited {
+            // Nobody reads the body once its receiver went away
+            let _ = self.pieces.send(piece).await;
+            return Ok(());
+        }
+        match self.pieces.try_send(piece) {
+            Ok(()) | Err(TrySendError::Closed(_)) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(ProtocolError::Unexpected("body frame past the credit granted".to_string())),
+        }
+    }
+}
+
+/// Writes `first` and the grants queued behind it in `grants` as CREDIT frames, one per body, and flushes once
+pub async fn write_credits<W: AsyncWrite + Unpin>(
+    writer: &mut FrameWriter<W>,
+    first: (u64, u64),
+    grants: &mut mpsc::UnboundedReceiver<(u64, u64)>,
+) -> Result<(), ProtocolError> {
+    let mut granted = HashMap::from([first]);
+    while let Ok((id, chunks)) = grants.try_recv() {
+        *granted.entry(id).or_default() += chunks;
+    }
+    for (id, chunks) in granted {
+        writer.write_frame(&ControlFrame::Credit { id, chunks }.encode()).await?;
+    }
+    writer.flush().await
+}
+
+/// Reads a single frame and deserializes its JSON payload.
+///
+/// # Returns
+/// * `Ok(message)` on success
+/// * `Err` describing whether the read or deserialization failed
+pub async fn recv_message<R, T>(reader: &mut R) -> Result<T, MessageError>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let payload = read_frame(reader)
+        .await
+        .map_err(MessageError::Read)?;
+    serde_json::from_slice(&payload).map_err(MessageError::Decode)
+}
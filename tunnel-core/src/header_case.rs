@@ -0,0 +1,289 @@
+//! Header names spelled as sent over HTTP/1.1.
+//!
+//! HTTP/1.1 names are case-insensitive, but some receivers (legacy services,
+//! signatures covering the raw head) still care how they are spelled. hyper
+//! lowercases names; a connection built with `preserve_header_case` keeps the
+//! spellings it read in an extension of the message and writes a message's
+//! names as its extension spells them. That extension is private to hyper, so
+//! these functions let hyper convert it: [`names`] has hyper write a head
+//! carrying it and reads the names back, and [`extensions`] has hyper read a
+//! head spelled as wanted.
+//!
+//! Both take a round trip through hyper, so a connection wrapped with
+//! [`record`] keeps what it read instead and finds the names in the head
+//! itself; [`names`] takes them from a [`HeaderNames`] extension when present.
+
+use bytes::Bytes;
+use http_body_util::Empty;
+use hyper::body::Incoming;
+use hyper::http::Extensions;
+use hyper::service::service_fn;
+use hyper::{HeaderMap, Request, Response, Version};
+use hyper_util::rt::TokioIo;
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Room for a message head on its way through hyper
+const HEAD_BUFFER_BYTES: usize = 64 * 1024;
+
+/// Most bytes a recorded connection keeps of what it read last
+const RECORDED_BYTES: usize = 64 * 1024;
+
+/// Most spellings [`extensions`] keeps the extensions of
+const MAX_BUILT: usize = 64;
+
+/// Extensions built for the spellings seen lately
+static BUILT: LazyLock<Mutex<HashMap<Vec<String>, Extensions>>> = LazyLock::new(Mutex::default);
+
+/// Header names of a message, spelled as it was read, in the order of its header map
+#[derive(Clone, Debug)]
+pub struct HeaderNames(pub Vec<String>);
+
+/// A connection that keeps the bytes it reads for [`RecordedHeads`] to find heads in
+pub struct Recorded<I> {
+    io: I,
+    read: Arc<Mutex<Vec<u8>>>,
+}
+
+/// The heads a [`Recorded`] connection read; a head found is forgotten with all read before it
+#[derive(Clone)]
+pub struct RecordedHeads(Arc<Mutex<Vec<u8>>>);
+
+/// Wraps an HTTP/1 connection to keep what it reads while its heads are still wanted
+///
+/// Only the last [`RECORDED_BYTES`] or so are kept, and nothing once every
+/// `RecordedHeads` is dropped (say after an upgrade).
+pub fn record<I>(io: I) -> (Recorded<I>, RecordedHeads) {
+    let read = Arc::new(Mutex::new(Vec::new()));
+    (Recorded { io, read: read.clone() }, RecordedHeads(read))
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for Recorded<I> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let polled = Pin::new(&mut self.io).poll_read(cx, buf);
+        if matches!(polled, Poll::Ready(Ok(()))) && Arc::strong_count(&self.read) > 1 {
+            let mut read = self.read.lock().unwrap();
+            read.extend_from_slice(&buf.filled()[before..]);
+            if read.len() > 2 * RECORDED_BYTES {
+                let stale = read.len() - RECORDED_BYTES;
+                read.drain(..stale);
+            }
+        }
+        polled
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for Recorded<I> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+}
+
+impl RecordedHeads {
+    /// Names of the request read with `method`, `target` and `headers`, spelled as read
+    ///
+    /// `None` when its head is no longer kept, or was not read here.
+    pub fn request(&self, method: &str, target: &str, headers: &HeaderMap) -> Option<Vec<String>> {
+        self.find(format!("{} {} HTTP/1.", method, target).as_bytes(), b"", headers)
+    }
+
+    /// Names of the response read with `status` and `headers`, spelled as read
+    pub fn response(&self, status: u16, headers: &HeaderMap) -> Option<Vec<String>> {
+        self.find(b"HTTP/1.", format!(" {}", status).as_bytes(), headers)
+    }
+
+    /// Finds the first head kept that starts with `start`, one byte (the minor
+    /// version) and `then`, and has the names of `headers`
+    fn find(&self, start: &[u8], then: &[u8], headers: &HeaderMap) -> Option<Vec<String>> {
+        let mut wanted: Vec<&str> = headers.iter().map(|(name, _)| name.as_str()).collect();
+        wanted.sort_unstable();
+        let mut read = self.0.lock().unwrap();
+        let mut at = 0;
+        while let Some(found) = read[at..].windows(start.len()).position(|window| window == start) {
+            at += found;
+            let rest = &read[at + start.len()..];
+            if rest.get(1..1 + then.len()) == Some(then) {
+                if let Some((names, end)) = head_names(&read[at..]) {
+                    let mut lowercase: Vec<String> = names.iter().map(|name| name.to_ascii_lowercase()).collect();
+                    lowercase.sort_unstable();
+                    if lowercase == wanted {
+                        read.drain(..at + end);
+                        let mut spellings: HashMap<String, VecDeque<String>> = HashMap::new();
+                        for name in names {
+                            spellings.entry(name.to_ascii_lowercase()).or_default().push_back(name);
+                        }
+                        return Some(spelled(headers, spellings));
+                    }
+                }
+            }
+            at += 1;
+        }
+        None
+    }
+}
+
+/// Header names of the head starting `read`, with the length of that head
+fn head_names(read: &[u8]) -> Option<(Vec<String>, usize)> {
+    let mut names = Vec::new();
+    let mut lines = read.split_inclusive(|&byte| byte == b'\n');
+    let mut length = lines.next()?.len();
+    for line in lines {
+        length += line.len();
+        if !line.ends_with(b"\n") {
+            return None;
+        }
+        let line = line.strip_suffix(b"\r\n").or_else(|| line.strip_suffix(b"\n"))?;
+        if line.is_empty() {
+            return Some((names, length));
+        }
+        let colon = line.iter().position(|&byte| byte == b':')?;
+        names.push(std::str::from_utf8(&line[..colon]).ok()?.to_string());
+    }
+    None
+}
+
+/// Names of `headers` in iteration order, each taking the next of `spellings` for it or else lowercase
+fn spelled(headers: &HeaderMap, mut spellings: HashMap<String, VecDeque<String>>) -> Vec<String> {
+    headers
+        .keys()
+        .flat_map(|name| headers.get_all(name).iter().map(move |_| name))
+        .map(|name| {
+            spellings.get_mut(name.as_str()).and_then(VecDeque::pop_front).unwrap_or_else(|| name.as_str().to_string())
+        })
+        .collect()
+}
+
+/// Whether any name is spelled other than lowercase
+pub fn has_uppercase<'a>(mut names: impl Iterator<Item = &'a str>) -> bool {
+    names.any(|name| name.bytes().any(|byte| byte.is_ascii_uppercase()))
+}
+
+/// Names of `headers` in iteration order, spelled as in the message they were read from
+///
+/// `version` and `extensions` are that message's; names come as its
+/// [`HeaderNames`] spell them if it has those. Otherwise names hyper kept no
+/// spelling for come lowercase, as do all names of messages past HTTP/1.1.
+pub async fn names(version: Version, headers: &HeaderMap, extensions: &Extensions) -> Vec<String> {
+    if let Some(HeaderNames(names)) = extensions.get() {
+        return names.clone();
+    }
+    let spellings = match version {
+        Version::HTTP_09 | Version::HTTP_10 | Version::HTTP_11 => written_names(headers, extensions).await.unwrap_or_default(),
+        _ => HashMap::new(),
+    };
+    spelled(headers, spellings)
+}
+
+/// Spellings of each lowercase name as hyper writes `headers` with `extensions`
+async fn written_names(headers: &HeaderMap, extensions: &Extensions) -> Option<HashMap<String, VecDeque<String>>> {
+    let (mut ours, theirs) = tokio::io::duplex(HEAD_BUFFER_BYTES);
+    let (mut sender, connection) = hyper::client::conn::http1::handshake::<_, Empty<Bytes>>(TokioIo::new(theirs)).await.ok()?;
+    let mut request = Request::new(Empty::<Bytes>::new());
+    *request.headers_mut() = headers.clone();
+    *request.extensions_mut() = extensions.clone();
+    let _response = sender.send_request(request);
+
+    let read_head = async {
+        let mut head = Vec::new();
+        let mut buf = [0; 4096];
+        while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+            match ours.read(&mut buf).await {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => head.extend_from_slice(&buf[..n]),
+            }
+        }
+        Some(head)
+    };
+    let head = tokio::select! {
+        head = read_head => head?,
+        _ = connection => return None,
+    };
+
+    let mut spellings: HashMap<String, VecDeque<String>> = HashMap::new();
+    for line in String::from_utf8_lossy(&head).split("\r\n").skip(1) {
+        if let Some((name, _)) = line.split_once(':') {
+            spellings.entry(name.to_ascii_lowercase()).or_default().push_back(name.to_string());
+        }
+    }
+    Some(spellings)
+}
+
+/// Extensions of a message that make hyper spell its header names as in `names`
+///
+/// Empty when every name is lowercase anyway, or one is not a valid name. The
+/// extensions for the last few spellings are kept, so the same names cost no
+/// second trip through hyper.
+pub async fn extensions<'a>(names: impl Iterator<Item = &'a str> + Clone) -> Extensions {
+    if !has_uppercase(names.clone()) {
+        return Extensions::new();
+    }
+    let key: Vec<String> = names.clone().map(str::to_string).collect();
+    if let Some(built) = BUILT.lock().unwrap().get(&key) {
+        return built.clone();
+    }
+    let extensions = build_extensions(names).await;
+    let mut built = BUILT.lock().unwrap();
+    if built.len() >= MAX_BUILT {
+        built.clear();
+    }
+    built.insert(key, extensions.clone());
+    extensions
+}
+
+/// Extensions hyper gives a head spelled as `names`
+async fn build_extensions<'a>(names: impl Iterator<Item = &'a str>) -> Extensions {
+    let mut head = b"GET / HTTP/1.1\r\n".to_vec();
+    let mut count = 0;
+    for name in names {
+        head.extend_from_slice(name.as_bytes());
+        head.extend_from_slice(b":\r\n");
+        count += 1;
+    }
+    head.extend_from_slice(b"\r\n");
+
+    let (mut ours, theirs) = tokio::io::duplex(head.len().max(HEAD_BUFFER_BYTES));
+    if ours.write_all(&head).await.is_err() {
+        return Extensions::new();
+    }
+    drop(ours);
+
+    let read = Arc::new(Mutex::new(None));
+    let service = {
+        let read = read.clone();
+        service_fn(move |request: Request<Incoming>| {
+            *read.lock().unwrap() = Some(request.extensions().clone());
+            async { Ok::<_, Infallible>(Response::new(Empty::<Bytes>::new())) }
+        })
+    };
+    let _ = hyper::server::conn::http1::Builder::new()
+        .preserve_header_case(true)
+        .max_headers(count + 1)
+        .max_buf_size(head.len().max(8192))
+        .keep_alive(false)
+        .serve_connection(TokioIo::new(theirs), service)
+        .await;
+    let extensions = read.lock().unwrap().take();
+    extensions.unwrap_or_default()
+}
@@ -0,0 +1,145 @@
+//! Heartbeats on a tunnel connection (see `tunnel_protocol::HEARTBEAT_HEADER`).
+//!
+//! On a connection that agreed to them, each end sends a PING every
+//! TUNNEL_HEARTBEAT_SECS and drops the connection when one goes unanswered for
+//! TUNNEL_HEARTBEAT_TIMEOUT_SECS, so a peer that silently went away (e.g. its
+//! NAT mapping expired) is noticed without waiting for a request to time out.
+//! Each end answers every PING with a PONG as soon as it reads it. Any bytes
+//! read count as a sign of life too: a PONG queued behind a large frame on a
+//! slow link is waited for as long as the frame keeps arriving.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::{watch, Notify};
+use tokio::time::{sleep_until, Instant};
+
+/// Heartbeat state of one tunnel connection, shared by the tasks reading and writing it
+///
+/// The default sends no PINGs and never expires, for connections that did not agree to heartbeats.
+#[derive(Default)]
+pub struct Heartbeat {
+    interval: Option<Duration>,  // Between our PINGs (None: heartbeats are off)
+    timeout: Duration,           // For the PONG to a PING
+    next_ping: Mutex<Option<Instant>>,  // None: heartbeats are off
+    unanswered: watch::Sender<Option<Instant>>,  // When the oldest PING not answered yet was sent
+    pong_owed: AtomicBool,       // A PING arrived and was not answered yet
+    ping_received: Notify,
+}
+
+impl Heartbeat {
+    /// PINGs every `interval` (TUNNEL_HEARTBEAT_SECS), each to be answered within `timeout`
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        Self {
+            interval: Some(interval),
+            timeout,
+            next_ping: Mutex::new(Some(Instant::now() + interval)),
+            ..Self::default()
+        }
+    }
+
+    /// Whether PINGs are sent, and so must be read and answered at any time
+    pub fn is_on(&self) -> bool {
+        self.interval.is_some()
+    }
+
+    /// Waits until the next PING is due, one interval after the last; forever when heartbeats are off
+    pub async fn ping_due(&self) {
+        let next_ping = *self.next_ping.lock().unwrap();
+        match next_ping {
+            Some(next_ping) => sleep_until(next_ping).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Records a PING written to the other end
+    pub fn ping_sent(&self) {
+        let now = Instant::now();
+        *self.next_ping.lock().unwrap() = self.interval.map(|interval| now + interval);
+        self.unanswered.send_if_modified(|sent| {
+            if sent.is_some() {
+                return false;
+            }
+            *sent = Some(now);
+            true
+        });
+    }
+
+    /// Records a PONG from the other end, which answers every PING sent before it
+    pub fn pong_received(&self) {
+        self.unanswered.send_if_modified(|sent| sent.take().is_some());
+    }
+
+    /// Records bytes read from the other end: an unanswered PING then expires one timeout from now
+    pub fn data_received(&self) {
+        self.unanswered.send_if_modified(|sent| match sent {
+            Some(sent) => {
+                *sent = Instant::now();
+                true
+            }
+            None => false,
+        });
+    }
+
+    /// Wraps the reading half of the connection, so every byte read counts as `data_received`
+    pub fn reader<R>(&self, inner: R) -> LivenessReader<'_, R> {
+        LivenessReader { inner, heartbeat: self }
+    }
+
+    /// Records a PING from the other end, to be answered once `pong_due` returns
+    pub fn ping_received(&self) {
+        self.pong_owed.store(true, Ordering::Relaxed);
+        self.ping_received.notify_one();
+    }
+
+    /// Waits until a PONG is owed to the other end
+    pub async fn pong_due(&self) {
+        while !self.pong_owed.swap(false, Ordering::Relaxed) {
+            self.ping_received.notified().await;
+        }
+    }
+
+    /// Waits until a PING has gone unanswered for the timeout
+    pub async fn expired(&self) {
+        let mut unanswered = self.unanswered.subscribe();
+        loop {
+            let sent = *unanswered.borrow_and_update();
+            match sent {
+                Some(sent) => tokio::select! {
+                    () = sleep_until(sent + self.timeout) => return,
+                    _ = unanswered.changed() => {}
+                },
+                // Never fails: the sender lives as long as `self`
+                None => {
+                    let _ = unanswered.changed().await;
+                }
+            }
+        }
+    }
+
+    /// Error a connection is dropped with once `expired` returned
+    pub fn expired_error(&self) -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, format!("no PONG within {:?} of a PING", self.timeout))
+    }
+}
+
+/// Reader of a connection that tells its `Heartbeat` about the bytes read (see `Heartbeat::reader`)
+pub struct LivenessReader<'a, R> {
+    inner: R,
+    heartbeat: &'a Heartbeat,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for LivenessReader<'_, R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let polled = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > before {
+            self.heartbeat.data_received();
+        }
+        polled
+    }
+}
@@ -0,0 +1,36 @@
+//! Shared building blocks for the speedforce tunnel binaries.
+//!
+//! - [`client`]: server address parsing, TLS setup and the HTTP Upgrade handshake
+//! - [`config`]: layered settings (command line, config file, environment) and `--check-config` support
+//! - [`dedup`]: repeated error lines collapsed into counts
+//! - [`header_case`]: header names spelled as sent over HTTP/1.1
+//! - [`heartbeat`]: PING/PONG frames that notice a tunnel whose other end went away
+//! - [`logging`]: log files with rotation, a separate access log and runtime level changes
+//! - [`server`]: the routing table of connected tunnels and the per-connection worker
+//! - [`framing`]: typed JSON messages on top of tunnel-protocol frames
+//! - [`progress`]: per-phase timings of requests going through a tunnel
+//! - [`redact`]: rules hiding tokens and personal data in logs and captured requests
+//! - [`rules`]: parsing of the line-based rules files
+//! - [`stream`]: the plain/TLS transport stream used by the client
+//! - [`tls`]: TLS version, ALPN, cipher suite and session resumption options
+//! - [`transport`]: TCP and frame coalescing options for the tunnel connection
+//! - [`writes`]: timing of the writes to a tunnel connection, to spot a saturated socket
+//!
+//! Both binaries are thin wrappers around this crate, so either side of the
+//! tunnel can also be embedded in another program.
+
+pub mod client;
+pub mod config;
+pub mod dedup;
+pub mod framing;
+pub mod header_case;
+pub mod heartbeat;
+pub mod logging;
+pub mod progress;
+pub mod redact;
+pub mod rules;
+pub mod server;
+pub mod stream;
+pub mod tls;
+pub mod transport;
+pub mod writes;
@@ -0,0 +1,329 @@
+//! Log output shared by both binaries: a level filter that can be changed at
+//! runtime, optional rotating log files, and a separate access log.
+//!
+//! Access log lines are events with the [`ACCESS_TARGET`] target. Without
+//! `ACCESS_LOG_FILE` they are written with the diagnostics, subject to the same
+//! level filter; with it they go only to that file, regardless of the level.
+
+use serde::{Serialize, Serializer};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{error, warn, Level};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::{filter_fn, FilterExt, Targets};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+use crate::config::serialize_secs;
+use crate::dedup;
+
+/// Target of access log events, e.g. `info!(target: ACCESS_TARGET, ...)`
+pub const ACCESS_TARGET: &str = "access";
+
+/// When log files are rotated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    Daily,
+    Size(u64),  // Once the file would exceed this many bytes
+}
+
+impl Serialize for LogRotation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            LogRotation::Never => serializer.serialize_str("never"),
+            LogRotation::Hourly => serializer.serialize_str("hourly"),
+            LogRotation::Daily => serializer.serialize_str("daily"),
+            LogRotation::Size(bytes) => serializer.serialize_str(&format!("size:{}", bytes)),
+        }
+    }
+}
+
+/// Logging configuration
+#[derive(Debug, Clone, Serialize)]
+pub struct LogOptions {
+    pub level: String,                   // Filter directives, e.g. "info" or "info,tunnel_core=debug"
+    pub file: Option<PathBuf>,           // Diagnostics log file (None: stdout)
+    pub access_file: Option<PathBuf>,    // Access log file (None: written with the diagnostics)
+    pub rotation: LogRotation,
+    pub max_files: usize,                // Rotated files kept per log (0: keep all)
+    #[serde(rename = "dedup_secs", serialize_with = "serialize_secs")]
+    pub dedup_window: Duration,          // Repeats of an error line within this long are counted, not logged (zero: off)
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            file: None,
+            access_file: None,
+            rotation: LogRotation::Never,
+            max_files: 7,
+            dedup_window: dedup::DEFAULT_WINDOW,
+        }
+    }
+}
+
+impl LogOptions {
+    /// Settings read by `from_source`
+    pub const KEYS: [&'static str; 8] = [
+        "LOG_LEVEL", "RUST_LOG", "LOG_FILE", "ACCESS_LOG_FILE", "LOG_ROTATION", "LOG_MAX_BYTES", "LOG_MAX_FILES",
+        "LOG_DEDUP_SECS",
+    ];
+
+    /// Reads the logging settings from a key lookup
+    ///
+    /// `RUST_LOG` is accepted as an alias for `LOG_LEVEL`. `LOG_MAX_BYTES` is
+    /// only meaningful with `LOG_ROTATION=size`.
+    pub fn from_source(get: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let mut options = Self::default();
+
+        if let Some(level) = get("LOG_LEVEL").or_else(|| get("RUST_LOG")) {
+            EnvFilter::try_new(&level).map_err(|e| format!("Invalid LOG_LEVEL: {} ({})", level, e))?;
+            options.level = level;
+        }
+        options.file = get("LOG_FILE").map(PathBuf::from);
+        options.access_file = get("ACCESS_LOG_FILE").map(PathBuf::from);
+
+        let max_bytes = match get("LOG_MAX_BYTES") {
+            Some(value) => value.trim().parse::<u64>().ok().filter(|bytes| *bytes > 0)
+                .ok_or_else(|| format!("Invalid LOG_MAX_BYTES: {}", value))?,
+            None => 100 * 1024 * 1024,
+        };
+        if let Some(value) = get("LOG_ROTATION") {
+            options.rotation = match value.trim() {
+                "never" => LogRotation::Never,
+                "hourly" => LogRotation::Hourly,
+                "daily" => LogRotation::Daily,
+                "size" => LogRotation::Size(max_bytes),
+                _ => return Err(format!("Invalid LOG_ROTATION: {} (expected never, hourly, daily or size)", value)),
+            };
+        }
+        if let Some(value) = get("LOG_MAX_FILES") {
+            options.max_files = value.trim().parse()
+                .map_err(|_| format!("Invalid LOG_MAX_FILES: {}", value))?;
+        }
+        if let Some(value) = get("LOG_DEDUP_SECS") {
+            let secs: u64 = value.trim().parse()
+                .map_err(|_| format!("Invalid LOG_DEDUP_SECS: {}", value))?;
+            options.dedup_window = Duration::from_secs(secs);
+        }
+
+        Ok(options)
+    }
+}
+
+/// Handle for changing the log level of a running process
+#[derive(Clone)]
+pub struct LogHandle {
+    filter: reload::Handle<EnvFilter, Registry>,
+    configured: String,  // Level from the settings, restored by `reset`
+}
+
+impl LogHandle {
+    /// Current filter directives
+    pub fn level(&self) -> String {
+        self.filter
+            .with_current(|filter| filter.to_string())
+            .unwrap_or_else(|_| self.configured.clone())
+    }
+
+    /// Level from the settings, restored by `reset`
+    pub fn configured(&self) -> &str {
+        &self.configured
+    }
+
+    /// Replaces the filter, e.g. with "debug" or "info,tunnel_core=trace"
+    pub fn set_level(&self, directives: &str) -> Result<(), String> {
+        let filter = EnvFilter::try_new(directives)
+            .map_err(|e| format!("Invalid log level {}: {}", directives, e))?;
+        self.filter.reload(filter).map_err(|e| format!("Failed to change log level: {}", e))
+    }
+
+    /// Restores the configured level
+    pub fn reset(&self) -> Result<(), String> {
+        self.set_level(&self.configured)
+    }
+
+    /// Switches between the configured level and `debug` on every SIGUSR1
+    #[cfg(unix)]
+    pub fn toggle_debug_on_signal(&self) -> io::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut signals = signal(SignalKind::user_defined1())?;
+        let handle = self.clone();
+        tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                let target = if handle.level() == "debug" { handle.configured.clone() } else { "debug".to_string() };
+                match handle.set_level(&target) {
+                    Ok(()) => warn!("Log level changed to {} (SIGUSR1)", target),
+                    Err(e) => error!("{}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Keeps background log writers running; drop it last to flush buffered lines
+pub struct LogGuard {
+    _writers: Vec<WorkerGuard>,
+}
+
+/// Installs the global subscriber
+///
+/// # Returns
+/// * `Ok((handle, guard))` on success; keep `guard` alive for the life of the process
+/// * `Err` if a log file cannot be opened
+pub fn init(options: &LogOptions) -> Result<(LogHandle, LogGuard), String> {
+    let mut guards = Vec::new();
+
+    let (filter, filter_handle) = reload::Layer::new(
+        EnvFilter::try_new(&options.level).map_err(|e| format!("Invalid LOG_LEVEL: {}", e))?,
+    );
+
+    let diagnostics_writer = match &options.file {
+        Some(path) => {
+            let (writer, guard) = tracing_appender::non_blocking(open_log(path, options)?);
+            guards.push(guard);
+            BoxMakeWriter::new(writer)
+        }
+        None => BoxMakeWriter::new(io::stdout),
+    };
+    let separate_access_log = options.access_file.is_some();
+    let diagnostics = fmt::layer()
+        .with_writer(diagnostics_writer)
+        .with_ansi(options.file.is_none())
+        .with_filter(filter.and(filter_fn(move |meta| {
+            !separate_access_log || meta.target() != ACCESS_TARGET
+        })));
+
+    let access = match &options.access_file {
+        Some(path) => {
+            let (writer, guard) = tracing_appender::non_blocking(open_log(path, options)?);
+            guards.push(guard);
+            let layer = fmt::layer()
+                .with_writer(writer)
+                .with_ansi(false)
+                .with_target(false)
+                .with_filter(Targets::new().with_target(ACCESS_TARGET, Level::INFO));
+            Some(layer)
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(diagnostics)
+        .with(access)
+        .try_init()
+        .map_err(|e| format!("Failed to install logger: {}", e))?;
+
+    dedup::ERRORS.set_window(options.dedup_window);
+
+    let handle = LogHandle { filter: filter_handle, configured: options.level.clone() };
+    Ok((handle, LogGuard { _writers: guards }))
+}
+
+/// Opens a log file with the configured rotation
+fn open_log(path: &Path, options: &LogOptions) -> Result<Box<dyn Write + Send>, String> {
+    let fail = |e: &dyn std::fmt::Display| format!("Failed to open log file {}: {}", path.display(), e);
+
+    let rotation = match options.rotation {
+        LogRotation::Never => {
+            return Ok(Box::new(SizeRotatingFile::open(path, u64::MAX, 0).map_err(|e| fail(&e))?));
+        }
+        LogRotation::Size(max_bytes) => {
+            return Ok(Box::new(SizeRotatingFile::open(path, max_bytes, options.max_files).map_err(|e| fail(&e))?));
+        }
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+    };
+
+    // server.log -> server.2024-01-31.log (daily) or server.2024-01-31-13.log (hourly)
+    let directory = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    fs::create_dir_all(directory).map_err(|e| fail(&e))?;
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("log");
+    let mut builder = RollingFileAppender::builder().rotation(rotation).filename_prefix(stem);
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        builder = builder.filename_suffix(extension);
+    }
+    if options.max_files > 0 {
+        builder = builder.max_log_files(options.max_files);
+    }
+    Ok(Box::new(builder.build(directory).map_err(|e| fail(&e))?))
+}
+
+/// Append-only log file that rotates once it would exceed a size
+///
+/// On rotation `path` becomes `path.1`, `path.1` becomes `path.2`, and so on;
+/// files beyond `max_files` are deleted (0 keeps all).
+pub struct SizeRotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    written: u64,
+}
+
+impl SizeRotatingFile {
+    /// Opens (or creates) `path` for appending
+    pub fn open(path: &Path, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let written = file.metadata()?.len();
+        Ok(Self { path: path.to_path_buf(), max_bytes, max_files, file, written })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        // Find the highest existing index so nothing is overwritten when max_files is 0
+        let mut last = 1;
+        while self.rotated_path(last).exists() {
+            last += 1;
+        }
+        if self.max_files > 0 && last > self.max_files {
+            for index in self.max_files..last {
+                let _ = fs::remove_file(self.rotated_path(index));
+            }
+            last = self.max_files;
+        }
+        for index in (1..last).rev() {
+            fs::rename(self.rotated_path(index), self.rotated_path(index + 1))?;
+        }
+        fs::rename(&self.path, self.rotated_path(1))?;
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written > 0 && self.written.saturating_add(buf.len() as u64) > self.max_bytes {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -0,0 +1,238 @@
+//! Where a request going through a tunnel spends its time.
+//!
+//! The server creates a [`RequestProgress`] per request and the tunnel worker
+//! moves it through the [`Phase`]s, so a slow request can be blamed on the
+//! right party and a hung one shows where it is stuck. Bytes are counted as
+//! they arrive ([`Transfer`]), so a large upload or download can be watched.
+//! [`Activity`] tells how long a request has been waiting, for timeouts
+//! that depend on the phase.
+
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Stage of a request's round trip through a tunnel, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    RequestRead,       // Reading the public request body
+    Queue,             // Waiting for the worker (and an in-flight slot)
+    TunnelWrite,       // Writing the request frame to the client
+    ClientProcessing,  // Waiting for the first byte of the response
+    ResponseRead,      // Reading the rest of the response
+    Done,
+}
+
+impl Phase {
+    /// Phases a request spends time in, in order
+    pub const ALL: [Phase; 5] = [
+        Phase::RequestRead, Phase::Queue, Phase::TunnelWrite, Phase::ClientProcessing, Phase::ResponseRead,
+    ];
+
+    /// Name used in logs and the admin API
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::RequestRead => "request_read",
+            Phase::Queue => "queue",
+            Phase::TunnelWrite => "tunnel_write",
+            Phase::ClientProcessing => "client_processing",
+            Phase::ResponseRead => "response_read",
+            Phase::Done => "done",
+        }
+    }
+}
+
+/// Time spent in each phase
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct PhaseTimings {
+    pub request_read_ms: u64,
+    pub queue_ms: u64,
+    pub tunnel_write_ms: u64,
+    pub client_processing_ms: u64,
+    pub response_read_ms: u64,
+}
+
+impl PhaseTimings {
+    fn get(&self, phase: Phase) -> u64 {
+        match phase {
+            Phase::RequestRead => self.request_read_ms,
+            Phase::Queue => self.queue_ms,
+            Phase::TunnelWrite => self.tunnel_write_ms,
+            Phase::ClientProcessing => self.client_processing_ms,
+            Phase::ResponseRead => self.response_read_ms,
+            Phase::Done => 0,
+        }
+    }
+
+    fn get_mut(&mut self, phase: Phase) -> Option<&mut u64> {
+        match phase {
+            Phase::RequestRead => Some(&mut self.request_read_ms),
+            Phase::Queue => Some(&mut self.queue_ms),
+            Phase::TunnelWrite => Some(&mut self.tunnel_write_ms),
+            Phase::ClientProcessing => Some(&mut self.client_processing_ms),
+            Phase::ResponseRead => Some(&mut self.response_read_ms),
+            Phase::Done => None,
+        }
+    }
+
+    /// Phase the request spent the most time in (the earliest on a tie)
+    pub fn slowest(&self) -> Phase {
+        Phase::ALL
+            .into_iter()
+            .rev()
+            .max_by_key(|phase| self.get(*phase))
+            .unwrap_or(Phase::RequestRead)
+    }
+}
+
+/// Bytes moved so far for one request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Transfer {
+    pub request_bytes: u64,          // Request body bytes read from the visitor
+    pub request_total: Option<u64>,  // Request Content-Length (None: not declared)
+    pub response_bytes: u64,         // Response frame bytes read from the client
+    pub response_total: Option<u64>, // Response frame length (None: not started)
+}
+
+/// Where a request is and when it last moved on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Activity {
+    pub phase: Phase,
+    pub queued_at: Option<Instant>, // When the request last entered the queue (None: not yet)
+    pub last_progress: Instant,     // When it last changed phase or moved response bytes
+}
+
+struct State {
+    phase: Phase,
+    phase_started: Instant,
+    queued_at: Option<Instant>,
+    last_progress: Instant,
+    timings: PhaseTimings,
+    transfer: Transfer,
+}
+
+/// Phase and timings of one request, shared by the server handler and the tunnel worker
+pub struct RequestProgress {
+    started: Instant,
+    state: Mutex<State>,
+    phase_changed: Notify,
+}
+
+impl Default for RequestProgress {
+    fn default() -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            state: Mutex::new(State {
+                phase: Phase::RequestRead,
+                phase_started: now,
+                queued_at: None,
+                last_progress: now,
+                timings: PhaseTimings::default(),
+                transfer: Transfer::default(),
+            }),
+            phase_changed: Notify::new(),
+        }
+    }
+}
+
+impl RequestProgress {
+    /// Starts in the `RequestRead` phase
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ends the current phase and starts `phase`; a finished request stays `Done`
+    pub fn enter(&self, phase: Phase) {
+        let mut state = self.state.lock().unwrap();
+        if state.phase == Phase::Done {
+            return;
+        }
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.phase_started).as_millis() as u64;
+        let current = state.phase;
+        if let Some(total) = state.timings.get_mut(current) {
+            *total += elapsed;
+        }
+        state.phase = phase;
+        state.phase_started = now;
+        state.last_progress = now;
+        if phase == Phase::Queue {
+            state.queued_at = Some(now);
+        }
+        drop(state);
+        self.phase_changed.notify_waiters();
+    }
+
+    /// Ends the current phase
+    pub fn finish(&self) {
+        self.enter(Phase::Done);
+    }
+
+    /// Current phase
+    pub fn phase(&self) -> Phase {
+        self.state.lock().unwrap().phase
+    }
+
+    /// Time since the request was created
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Current phase and when the request last made progress
+    pub fn activity(&self) -> Activity {
+        let state = self.state.lock().unwrap();
+        Activity { phase: state.phase, queued_at: state.queued_at, last_progress: state.last_progress }
+    }
+
+    /// Resolves once the request enters another phase after this call
+    ///
+    /// Call it before checking [`activity`](Self::activity), so a change in
+    /// between is not missed.
+    pub fn phase_changed(&self) -> tokio::sync::futures::Notified<'_> {
+        self.phase_changed.notified()
+    }
+
+    /// Records the declared request body length
+    pub fn set_request_total(&self, total: Option<u64>) {
+        self.state.lock().unwrap().transfer.request_total = total;
+    }
+
+    /// Counts request body bytes read from the visitor
+    pub fn add_request_bytes(&self, bytes: u64) {
+        self.state.lock().unwrap().transfer.request_bytes += bytes;
+    }
+
+    /// Starts counting a response frame of `total` bytes, replacing any earlier frame's count
+    pub fn start_response(&self, total: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.last_progress = Instant::now();
+        state.transfer.response_bytes = 0;
+        state.transfer.response_total = Some(total);
+    }
+
+    /// Records how much of the response frame has been read
+    pub fn set_response_bytes(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        if bytes > state.transfer.response_bytes {
+            state.last_progress = Instant::now();
+        }
+        state.transfer.response_bytes = bytes;
+    }
+
+    /// Bytes moved so far
+    pub fn transfer(&self) -> Transfer {
+        self.state.lock().unwrap().transfer
+    }
+
+    /// Time per phase, counting the current phase up to now
+    pub fn timings(&self) -> PhaseTimings {
+        let state = self.state.lock().unwrap();
+        let mut timings = state.timings;
+        if let Some(total) = timings.get_mut(state.phase) {
+            *total += state.phase_started.elapsed().as_millis() as u64;
+        }
+        timings
+    }
+}
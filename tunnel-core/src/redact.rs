@@ -0,0 +1,172 @@
+//! Redaction of tokens and personal data in what the binaries show: access
+//! logs, request listings and captured requests.
+//!
+//! Rules are read from REDACT_RULES_FILE, one per line:
+//!
+//! ```text
+//! header  x-api-key
+//! json    password
+//! json    customer.card.number
+//! regex   (?i)token=[^&\s]+
+//! ```
+//!
+//! `header` hides a header value; `json` a JSON body field, by name at any
+//! depth or by dotted path from the root (arrays are looked through);
+//! `regex` any match in paths, header values and bodies. Blank lines and
+//! lines starting with `#` are ignored. Every redacted value
+//! becomes `[redacted]`. Authorization, Proxy-Authorization, Cookie and
+//! Set-Cookie headers are always redacted, with or without a file.
+
+use regex::Regex;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::path::Path;
+
+use crate::rules;
+
+/// Replaces every redacted value
+pub const REDACTED: &str = "[redacted]";
+
+/// Headers redacted without any rule
+const ALWAYS_REDACTED: [&str; 4] = ["authorization", "proxy-authorization", "cookie", "set-cookie"];
+
+/// Redaction rules
+#[derive(Debug)]
+pub struct Redactor {
+    headers: Vec<String>,         // Lowercase names
+    json_fields: Vec<Vec<String>>, // One segment: any depth; more: from the root
+    patterns: Vec<Regex>,
+}
+
+impl Default for Redactor {
+    fn default() -> Self {
+        Self {
+            headers: ALWAYS_REDACTED.iter().map(|name| name.to_string()).collect(),
+            json_fields: Vec::new(),
+            patterns: Vec::new(),
+        }
+    }
+}
+
+impl Redactor {
+    /// Reads the rules from `path`
+    pub fn load(path: &Path) -> Result<Self, String> {
+        rules::load(path, "REDACT_RULES_FILE", Self::parse)
+    }
+
+    /// Parses the contents of a rules file
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut redactor = Self::default();
+
+        for line in rules::lines(contents) {
+            let Some((kind, argument)) = line.text.split_once(char::is_whitespace).map(|(kind, rest)| (kind, rest.trim())) else {
+                return Err(line.error("expected '<header|json|regex> <value>'"));
+            };
+            match kind {
+                "header" => redactor.headers.push(argument.to_ascii_lowercase()),
+                "json" => {
+                    let segments = argument.split('.').map(str::to_string).collect::<Vec<_>>();
+                    if segments.iter().any(|segment| segment.is_empty()) {
+                        return Err(line.error(format!("invalid JSON field path '{}'", argument)));
+                    }
+                    redactor.json_fields.push(segments);
+                }
+                "regex" => {
+                    let pattern = Regex::new(argument).map_err(|e| line.error(format!("invalid regex: {}", e)))?;
+                    redactor.patterns.push(pattern);
+                }
+                _ => return Err(line.error(format!("unknown rule '{}' (expected header, json or regex)", kind))),
+            }
+        }
+
+        Ok(redactor)
+    }
+
+    /// Rules beyond the headers always redacted
+    pub fn len(&self) -> usize {
+        self.headers.len() - ALWAYS_REDACTED.len() + self.json_fields.len() + self.patterns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// `text` with every regex match replaced
+    pub fn text<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for pattern in &self.patterns {
+            if let Cow::Owned(replaced) = pattern.replace_all(&text, REDACTED) {
+                text = Cow::Owned(replaced);
+            }
+        }
+        text
+    }
+
+    /// The value of header `name` as text, redacted
+    pub fn header_value(&self, name: &str, value: &[u8]) -> String {
+        if self.headers.iter().any(|redacted| name.eq_ignore_ascii_case(redacted)) {
+            return REDACTED.to_string();
+        }
+        self.text(&String::from_utf8_lossy(value)).into_owned()
+    }
+
+    /// Header values as text, redacted
+    pub fn headers<V: AsRef<[u8]>>(&self, headers: &[(String, V)]) -> Vec<(String, String)> {
+        headers.iter().map(|(name, value)| (name.clone(), self.header_value(name, value.as_ref()))).collect()
+    }
+
+    /// `body` with JSON fields and regex matches redacted
+    ///
+    /// A body that is not JSON only gets the regexes, and only if it is UTF-8.
+    pub fn body<'a>(&self, body: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.json_fields.is_empty() && self.patterns.is_empty() {
+            return Cow::Borrowed(body);
+        }
+        let mut body = Cow::Borrowed(body);
+        if !self.json_fields.is_empty() {
+            if let Ok(mut json) = serde_json::from_slice::<Value>(&body) {
+                if self.redact_json(&mut json, &mut Vec::new()) {
+                    body = Cow::Owned(serde_json::to_vec(&json).unwrap_or_default());
+                }
+            }
+        }
+        if let Ok(Cow::Owned(text)) = std::str::from_utf8(&body).map(|text| self.text(text)) {
+            body = Cow::Owned(text.into_bytes());
+        }
+        body
+    }
+
+    /// Redacts the fields of `value` (found at `path`) that match a rule; returns whether any did
+    fn redact_json(&self, value: &mut Value, path: &mut Vec<String>) -> bool {
+        let mut redacted = false;
+        match value {
+            Value::Object(fields) => {
+                for (name, field) in fields.iter_mut() {
+                    path.push(name.clone());
+                    if self.json_fields.iter().any(|rule| matches_field(rule, path)) {
+                        *field = Value::String(REDACTED.to_string());
+                        redacted = true;
+                    } else {
+                        redacted |= self.redact_json(field, path);
+                    }
+                    path.pop();
+                }
+            }
+            Value::Array(items) => {
+                for item in items {
+                    redacted |= self.redact_json(item, path);
+                }
+            }
+            _ => {}
+        }
+        redacted
+    }
+}
+
+/// Whether the field at `path` matches `rule`: by name at any depth, or by the whole path
+fn matches_field(rule: &[String], path: &[String]) -> bool {
+    match rule {
+        [name] => path.last() == Some(name),
+        _ => rule == path,
+    }
+}
@@ -0,0 +1,41 @@
+//! Line-based rules files (REDACT_RULES_FILE, RESPONSE_HEADER_RULES_FILE, ...).
+//!
+//! Each rule is one line of whitespace-separated fields. Blank lines and lines
+//! starting with `#` are skipped, and errors name the line they are about.
+
+use std::fmt::Display;
+use std::path::Path;
+use std::str::SplitWhitespace;
+
+/// One rule of a rules file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RuleLine<'a> {
+    pub number: usize,  // 1-based, counting skipped lines
+    pub text: &'a str,  // Trimmed
+}
+
+impl<'a> RuleLine<'a> {
+    /// Its whitespace-separated fields
+    pub fn fields(&self) -> SplitWhitespace<'a> {
+        self.text.split_whitespace()
+    }
+
+    /// `message`, prefixed with the line number
+    pub fn error(&self, message: impl Display) -> String {
+        format!("line {}: {}", self.number, message)
+    }
+}
+
+/// The rules in `contents`, skipping blank lines and comments
+pub fn lines(contents: &str) -> impl Iterator<Item = RuleLine<'_>> {
+    contents.lines().enumerate()
+        .map(|(index, line)| RuleLine { number: index + 1, text: line.trim() })
+        .filter(|line| !line.text.is_empty() && !line.text.starts_with('#'))
+}
+
+/// Reads the rules file at `path`, set with `setting`, and parses it with `parse`
+pub fn load<T>(path: &Path, setting: &str, parse: impl FnOnce(&str) -> Result<T, String>) -> Result<T, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {} {}: {}", setting, path.display(), e))?;
+    parse(&contents).map_err(|e| format!("Invalid {} {}: {}", setting, path.display(), e))
+}
@@ -0,0 +1,1208 @@
+//! Server side of the tunnel: the routing table holding the active client
+//! connection, the worker task that owns a connection's I/O, and the
+//! supervisor that cleans up after a worker however it ends.
+
+use bytes::{Bytes, BytesMut};
+use serde::Serialize;
+use crate::config::serialize_millis;
+use crate::framing::{write_body_pieces, write_credits, BodyInlet, BodyPiece, Credits};
+use crate::heartbeat::Heartbeat;
+use crate::progress::{Phase, RequestProgress};
+use crate::writes::{WriteMonitor, WriteStats};
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, BufReader};
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot, Notify, RwLock, Semaphore, SemaphorePermit};
+use tokio::time::{timeout, timeout_at, Instant};
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+use tunnel_protocol::{
+    classify_frame, decode_body_frame, decode_response_frame, inflate_frame, is_binary_frame, is_body_frame, is_compressed_frame, read_frame_into,
+    response_id, write_tagged_request, BodyFrame, Compression, CompressionMonitor, CompressionStats, ControlFrame, Frame, FrameWriter, HeaderLimits, Schedule, StatsReport, FRAME_HEADER_LEN, MAX_FRAME_LEN, PING_FRAME, PONG_FRAME, ProtocolError,
+    RESPONSE_ID_PREFIX_LEN,
+};
+
+/// Source of unique tunnel connection IDs
+static NEXT_TUNNEL_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Error from a round trip through a tunnel connection
+#[derive(Debug, Error)]
+pub enum TunnelError {
+    #[error("Tunnel connection closed")]
+    Closed,
+
+    #[error("Tunnel queue full")]
+    QueueFull,
+
+    #[error("Too many requests in flight")]
+    InFlightLimit,
+
+    #[error("Tunnel client is shutting down")]
+    Draining,
+
+    #[error("Tunnel worker disappeared")]
+    WorkerGone,
+
+    #[error("Tunnel write failed: {0}")]
+    Write(#[source] io::Error),
+
+    #[error("Tunnel read failed: {0}")]
+    Read(#[source] io::Error),
+}
+
+impl TunnelError {
+    /// Whether the request can be retried on another connection
+    ///
+    /// Only true when the request never reached the worker; once it has been
+    /// written the client may already be processing it.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, TunnelError::Closed | TunnelError::QueueFull | TunnelError::InFlightLimit | TunnelError::Draining)
+    }
+
+    /// Whether the connection went away before the request was written to the
+    /// client, so it can be sent again on the client's next connection
+    ///
+    /// `WorkerGone` qualifies only while `progress` shows the request still
+    /// queued, i.e. the worker ended before taking it.
+    pub fn lost_before_delivery(&self, progress: &RequestProgress) -> bool {
+        match self {
+            TunnelError::Closed | TunnelError::Draining => true,
+            TunnelError::WorkerGone => progress.phase() == Phase::Queue,
+            _ => false,
+        }
+    }
+}
+
+/// What happens to a request arriving while a tunnel is at its in-flight cap
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    Queue,   // Wait up to the queue timeout for a slot, then 503
+    Reject,  // 503 right away
+}
+
+/// Limits on requests waiting for a tunnel worker
+///
+/// The worker handles one request at a time, or a few when the client
+/// multiplexes, so a stalled tunnel makes requests pile up; the queue bounds
+/// how many (and their buffered bodies) can wait, and how long a request
+/// waits for room before it is rejected.
+///
+/// `max_in_flight` caps the requests a tunnel holds at once, queued or being
+/// handled by the client, so a slow local machine is not buried in work.
+///
+/// A write to the tunnel taking longer than `write_stall` is logged and
+/// counted as a stall (see `WriteMonitor`).
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct QueueOptions {
+    pub depth: usize,                 // Requests queued per tunnel connection
+    #[serde(serialize_with = "serialize_millis")]
+    pub send_timeout: Duration,       // Wait for room in a full queue, or for an in-flight slot
+    pub max_in_flight: Option<usize>, // Requests per tunnel connection (None: no cap)
+    pub overflow: OverflowPolicy,     // What happens to requests above max_in_flight
+    #[serde(serialize_with = "serialize_millis")]
+    pub write_stall: Duration,        // Writes to the tunnel taking longer count as stalls
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        Self {
+            depth: 64,
+            send_timeout: Duration::from_secs(1),
+            max_in_flight: None,
+            overflow: OverflowPolicy::Queue,
+            write_stall: Duration::from_secs(1),
+        }
+    }
+}
+
+impl QueueOptions {
+    /// Settings read by `from_source`
+    pub const KEYS: [&'static str; 5] = [
+        "TUNNEL_QUEUE_DEPTH", "TUNNEL_QUEUE_TIMEOUT_MS", "TUNNEL_MAX_IN_FLIGHT", "TUNNEL_OVERFLOW", "TUNNEL_WRITE_STALL_MS",
+    ];
+
+    /// Reads the queue settings from a key lookup
+    ///
+    /// `TUNNEL_MAX_IN_FLIGHT=0` (the default) means no cap.
+    pub fn from_source(get: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let mut options = Self::default();
+
+        if let Some(value) = get("TUNNEL_QUEUE_DEPTH") {
+            options.depth = value.trim().parse().ok().filter(|depth| *depth > 0)
+                .ok_or_else(|| format!("Invalid TUNNEL_QUEUE_DEPTH: {} (expected a positive number)", value))?;
+        }
+        if let Some(value) = get("TUNNEL_QUEUE_TIMEOUT_MS") {
+            let millis: u64 = value.trim().parse()
+                .map_err(|_| format!("Invalid TUNNEL_QUEUE_TIMEOUT_MS: {}", value))?;
+            options.send_timeout = Duration::from_millis(millis);
+        }
+        if let Some(value) = get("TUNNEL_MAX_IN_FLIGHT") {
+            let max: usize = value.trim().parse()
+                .map_err(|_| format!("Invalid TUNNEL_MAX_IN_FLIGHT: {}", value))?;
+            options.max_in_flight = Some(max).filter(|max| *max > 0);
+        }
+        if let Some(value) = get("TUNNEL_OVERFLOW") {
+            options.overflow = match value.trim() {
+                "queue" => OverflowPolicy::Queue,
+                "reject" => OverflowPolicy::Reject,
+                _ => return Err(format!("Invalid TUNNEL_OVERFLOW: {} (expected queue or reject)", value)),
+            };
+        }
+        if let Some(value) = get("TUNNEL_WRITE_STALL_MS") {
+            let millis: u64 = value.trim().parse().ok().filter(|millis| *millis > 0)
+                .ok_or_else(|| format!("Invalid TUNNEL_WRITE_STALL_MS: {} (expected a positive number)", value))?;
+            options.write_stall = Duration::from_millis(millis);
+        }
+
+        Ok(options)
+    }
+}
+
+/// Request sent to the tunnel worker
+pub struct TunnelWorkerRequest {
+    pub payload: Bytes,
+    pub body: Option<mpsc::Receiver<BodyPiece>>,  // Streamed after the payload, whose message has `stream` set
+    pub progress: Arc<RequestProgress>,  // Moved through the phases by the worker
+    pub response_tx: oneshot::Sender<Result<TunnelReply, TunnelError>>,
+}
+
+/// Response from the client
+#[derive(Debug)]
+pub struct TunnelReply {
+    pub payload: Bytes,
+    pub body: Option<mpsc::Receiver<BodyPiece>>,  // Pieces of a streamed body (the message has `stream` set)
+}
+
+/// Latest statistics reported by the client of a connection
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerStats {
+    #[serde(flatten)]
+    pub report: StatsReport,
+    pub reported_at: u64,  // Unix timestamp (seconds)
+}
+
+/// What a tunnel worker consumes: queued requests, and where to put the client's stats reports and GOAWAY
+pub struct WorkerInbox {
+    requests: mpsc::Receiver<TunnelWorkerRequest>,
+    peer_stats: Arc<Mutex<Option<PeerStats>>>,
+    draining: Arc<AtomicBool>,
+    heartbeat: Heartbeat,  // Off unless the client agreed to heartbeats
+    compression: Option<Compression>,  // Of the frames written (None: off)
+    cancel: bool,  // Tell the client about requests given up on (see CANCEL_HEADER)
+    stream_credit: Option<usize>,  // Body frames of one body the client takes ahead of credit (see STREAM_CREDIT_HEADER; None: not agreed)
+    writes: Arc<WriteMonitor>,
+    compressed: Arc<CompressionMonitor>,  // Ratio of the frames written compressed
+}
+
+impl WorkerInbox {
+    /// Has the worker send PINGs and drop the connection when the client stops answering them
+    ///
+    /// Only for clients that agreed to heartbeats (see `HEARTBEAT_HEADER`).
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// Has the worker compress the frames it writes with `compression`
+    ///
+    /// Only for clients that agreed to it (see `COMPRESSION_HEADER`).
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Has a multiplexed worker send CANCEL for requests given up on while the client works on them
+    ///
+    /// Only for clients that take them (see `CANCEL_HEADER`).
+    pub fn with_cancel(mut self) -> Self {
+        self.cancel = true;
+        self
+    }
+
+    /// Has a multiplexed worker send streamed bodies against credit, `chunks` frames of a body ahead of it,
+    /// and grant the client credit for the bodies it streams
+    ///
+    /// Only for clients that agreed to it, along with cancel (see `STREAM_CREDIT_HEADER`).
+    pub fn with_stream_credit(mut self, chunks: usize) -> Self {
+        self.stream_credit = Some(chunks);
+        self
+    }
+}
+
+/// Handle to communicate with the tunnel worker of one client connection
+pub struct TunnelConnection {
+    pub id: u64,
+    pub labels: BTreeMap<String, String>,  // Labels sent by the client at handshake
+    pub connected_at: SystemTime,
+    pub span: Span,  // Log span carrying the tunnel ID and labels
+    pub peer_header_limits: Option<HeaderLimits>,  // Limits the client announced for requests (None: not announced)
+    pub token: Option<String>,  // Username the client authenticated with (None: no tunnel authentication)
+    pub visitor_auth: Option<String>,  // "username:password" visitors must present (None: public)
+    pub https_only: bool,  // Plain-HTTP visitors are redirected to HTTPS
+    pub cors_origins: Vec<String>,  // Origins the server answers CORS for, or `*` (empty: left to the local service)
+    pub schedule: Option<Schedule>,  // Windows visitors are routed in (None: always)
+    pub max_concurrent: usize,  // Requests the client handles at once (1: it does not multiplex)
+    pub binary_frames: bool,  // The client takes binary frames (see ENCODING_HEADER)
+    pub stream_bodies: bool,  // Bodies may be streamed in BODY_CHUNK frames (see STREAM_HEADER)
+    pub stream_trailers: bool,  // A streamed body's BODY_END frame may carry its trailers (see STREAM_TRAILERS_HEADER)
+    pub stream_credit: bool,  // Streamed bodies are sent against credit (see STREAM_CREDIT_HEADER)
+    pub heartbeat: bool,  // Both ends send PINGs (see HEARTBEAT_HEADER)
+    pub user_agent: Option<String>,  // User-Agent of the upgrade request (None: not sent)
+    pub compression: Option<Compression>,  // Agreed at upgrade (see COMPRESSION_HEADER; None: off)
+    pub cancel: bool,  // Requests given up on are cancelled on the client (see CANCEL_HEADER)
+    request_tx: mpsc::Sender<TunnelWorkerRequest>,
+    send_timeout: Duration,
+    max_in_flight: Option<usize>,
+    in_flight: Semaphore,  // One permit per request allowed in flight, when capped
+    overflow: OverflowPolicy,
+    peer_stats: Arc<Mutex<Option<PeerStats>>>,
+    draining: Arc<AtomicBool>,  // Set once the client sent GOAWAY
+    writes: Arc<WriteMonitor>,  // Timing of the worker's writes
+    compressed: Arc<CompressionMonitor>,  // Ratio of the worker's compressed frames
+}
+
+impl TunnelConnection {
+    /// Creates a connection handle and the inbox its worker consumes
+    pub fn new(labels: BTreeMap<String, String>, queue: &QueueOptions) -> (Self, WorkerInbox) {
+        let id = NEXT_TUNNEL_ID.fetch_add(1, Ordering::Relaxed);
+        let span = info_span!("tunnel", id, labels = %format_labels(&labels));
+        let (request_tx, requests) = mpsc::channel(queue.depth);
+        let peer_stats = Arc::new(Mutex::new(None));
+        let draining = Arc::new(AtomicBool::new(false));
+        let writes = Arc::new(WriteMonitor::new(queue.write_stall));
+        let compressed = Arc::new(CompressionMonitor::default());
+
+        let conn = Self {
+            id,
+            labels,
+            connected_at: SystemTime::now(),
+            span,
+            peer_header_limits: None,
+            token: None,
+            visitor_auth: None,
+            https_only: false,
+            cors_origins: Vec::new(),
+            schedule: None,
+            max_concurrent: 1,
+            binary_frames: false,
+            stream_bodies: false,
+            stream_trailers: false,
+            stream_credit: false,
+            heartbeat: false,
+            user_agent: None,
+            compression: None,
+            cancel: false,
+            request_tx,
+            send_timeout: queue.send_timeout,
+            max_in_flight: queue.max_in_flight,
+            in_flight: Semaphore::new(queue.max_in_flight.unwrap_or(0)),
+            overflow: queue.overflow,
+            peer_stats: peer_stats.clone(),
+            draining: draining.clone(),
+            writes: writes.clone(),
+            compressed: compressed.clone(),
+        };
+        let inbox = WorkerInbox {
+            requests,
+            peer_stats,
+            draining,
+            heartbeat: Heartbeat::default(),
+            compression: None,
+            cancel: false,
+            stream_credit: None,
+            writes,
+            compressed,
+        };
+        (conn, inbox)
+    }
+
+    /// Latest statistics reported by the client, if it has sent any
+    pub fn peer_stats(&self) -> Option<PeerStats> {
+        self.peer_stats.lock().unwrap().clone()
+    }
+
+    /// Frames waiting to be written and how long writes to the client take
+    pub fn write_stats(&self) -> WriteStats {
+        self.writes.stats(self.request_tx.max_capacity() - self.request_tx.capacity())
+    }
+
+    /// Compression of the frames written to the client (None: compression is off)
+    pub fn compression_stats(&self) -> Option<CompressionStats> {
+        self.compression.map(|_| self.compressed.stats())
+    }
+
+    /// Whether the worker has ended; requests then fail with `Closed`
+    pub fn is_closed(&self) -> bool {
+        self.request_tx.is_closed()
+    }
+
+    /// Whether the client announced it is shutting down; new requests then fail with `Draining`
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Sends one request payload through the worker and waits for the response payload
+    ///
+    /// Fails with `InFlightLimit` if the connection is at its in-flight cap (right
+    /// away, or once the send timeout passes, depending on the overflow policy),
+    /// and with `QueueFull` if the queue has no room within the send timeout.
+    /// Fails with `Draining` once the client has sent GOAWAY.
+    pub async fn round_trip(&self, payload: Bytes) -> Result<Bytes, TunnelError> {
+        self.round_trip_tracked(payload, Arc::new(RequestProgress::new())).await
+    }
+
+    /// Like `round_trip`, recording the request's phases in `progress`
+    ///
+    /// The body of a streamed response is dropped.
+    pub async fn round_trip_tracked(&self, payload: Bytes, progress: Arc<RequestProgress>) -> Result<Bytes, TunnelError> {
+        self.round_trip_streaming(payload, None, progress).await.map(|reply| reply.payload)
+    }
+
+    /// Like `round_trip_tracked`, streaming `body` after the payload, and
+    /// returning the body of a streamed response with it
+    ///
+    /// Only for connections with `stream_bodies` set.
+    pub async fn round_trip_streaming(
+        &self,
+        payload: Bytes,
+        body: Option<mpsc::Receiver<BodyPiece>>,
+        progress: Arc<RequestProgress>,
+    ) -> Result<TunnelReply, TunnelError> {
+        if self.is_draining() {
+            return Err(TunnelError::Draining);
+        }
+        progress.enter(Phase::Queue);
+        // Held until the response arrives
+        let _permit = match self.max_in_flight {
+            Some(_) => Some(self.acquire_slot().await?),
+            None => None,
+        };
+        let (response_tx, response_rx) = oneshot::channel();
+
+        match timeout(self.send_timeout, self.request_tx.send(TunnelWorkerRequest { payload, body, progress, response_tx })).await {
+            Ok(Ok(())) => {}
+            // The worker closes the queue when the client sends GOAWAY
+            Ok(Err(_)) if self.is_draining() => return Err(TunnelError::Draining),
+            Ok(Err(_)) => return Err(TunnelError::Closed),
+            Err(_) => return Err(TunnelError::QueueFull),
+        }
+
+        match response_rx.await {
+            Ok(result) => result,
+            Err(_) => Err(TunnelError::WorkerGone),
+        }
+    }
+
+    /// Requests currently holding an in-flight slot (None: no cap)
+    pub fn in_flight(&self) -> Option<usize> {
+        self.max_in_flight.map(|max| max - self.in_flight.available_permits())
+    }
+
+    async fn acquire_slot(&self) -> Result<SemaphorePermit<'_>, TunnelError> {
+        match self.overflow {
+            OverflowPolicy::Reject => self.in_flight.try_acquire().map_err(|_| TunnelError::InFlightLimit),
+            OverflowPolicy::Queue => match timeout(self.send_timeout, self.in_flight.acquire()).await {
+                Ok(Ok(permit)) => Ok(permit),
+                Ok(Err(_)) => Err(TunnelError::Closed),
+                Err(_) => Err(TunnelError::InFlightLimit),
+            },
+        }
+    }
+}
+
+/// Formats labels as "k1=v1,k2=v2" for log fields
+pub fn format_labels(labels: &BTreeMap<String, String>) -> String {
+    labels
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Routing table of connected tunnels
+///
+/// Holds a single active client: registering a new connection replaces the
+/// previous one (last client connected wins).
+#[derive(Default)]
+pub struct TunnelRegistry {
+    active: RwLock<Option<Arc<TunnelConnection>>>,
+    registered: Notify,  // Wakes requests waiting for a connection
+    lost_at: Mutex<Option<Instant>>,  // When the active connection was last removed (None: one is active, or none ever was)
+    workers: WorkerCounters,
+}
+
+impl TunnelRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the connection requests should currently be routed to
+    pub async fn active(&self) -> Option<Arc<TunnelConnection>> {
+        self.active.read().await.clone()
+    }
+
+    /// Makes `conn` the active connection, returning the one it replaced
+    pub async fn register(&self, conn: Arc<TunnelConnection>) -> Option<Arc<TunnelConnection>> {
+        let replaced = self.active.write().await.replace(conn);
+        *self.lost_at.lock().unwrap() = None;
+        self.registered.notify_waiters();
+        replaced
+    }
+
+    /// When the client last went away, if no connection has replaced it since
+    pub fn lost_at(&self) -> Option<Instant> {
+        *self.lost_at.lock().unwrap()
+    }
+
+    /// Treats the client as just gone if none is active, so requests wait for it as after a disconnect
+    ///
+    /// For a server taking over from one the client is still connected to.
+    pub async fn mark_lost(&self) {
+        if self.active.read().await.is_none() {
+            *self.lost_at.lock().unwrap() = Some(Instant::now());
+        }
+    }
+
+    /// Waits until a connection other than the one with ID `previous` is active
+    ///
+    /// Returns None if none is by `until`.
+    pub async fn wait_for_connection(&self, previous: Option<u64>, until: Instant) -> Option<Arc<TunnelConnection>> {
+        loop {
+            // Enabled before checking, so a registration in between is not missed
+            let registered = self.registered.notified();
+            tokio::pin!(registered);
+            registered.as_mut().enable();
+
+            if let Some(conn) = self.active().await.filter(|conn| Some(conn.id) != previous) {
+                return Some(conn);
+            }
+            timeout_at(until, registered).await.ok()?;
+        }
+    }
+
+    /// Removes `conn` if it is still the active connection
+    ///
+    /// Returns false when a newer client has already replaced it.
+    pub async fn remove(&self, conn: &Arc<TunnelConnection>) -> bool {
+        let mut active = self.active.write().await;
+        match &*active {
+            Some(current) if Arc::ptr_eq(current, conn) => {
+                *active = None;
+                *self.lost_at.lock().unwrap() = Some(Instant::now());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// How many workers have been started and how they ended
+    pub fn worker_stats(&self) -> WorkerStats {
+        let counters = &self.workers;
+        WorkerStats {
+            started: counters.started.load(Ordering::Relaxed),
+            closed: counters.closed.load(Ordering::Relaxed),
+            drained: counters.drained.load(Ordering::Relaxed),
+            disconnected: counters.disconnected.load(Ordering::Relaxed),
+            failed: counters.failed.load(Ordering::Relaxed),
+            protocol_errors: counters.protocol_errors.load(Ordering::Relaxed),
+            unresponsive: counters.unresponsive.load(Ordering::Relaxed),
+            panicked: counters.panicked.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Default)]
+struct WorkerCounters {
+    started: AtomicU64,
+    closed: AtomicU64,
+    drained: AtomicU64,
+    disconnected: AtomicU64,
+    failed: AtomicU64,
+    protocol_errors: AtomicU64,
+    unresponsive: AtomicU64,
+    panicked: AtomicU64,
+}
+
+/// Snapshot of the worker counters of a registry
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct WorkerStats {
+    pub started: u64,
+    pub closed: u64,        // Every handle dropped, e.g. replaced by a newer client
+    pub drained: u64,       // Client sent GOAWAY and every queued request was answered
+    pub disconnected: u64,  // Client closed the connection
+    pub failed: u64,        // I/O error on the connection
+    pub protocol_errors: u64,  // Client sent a frame that does not decode or has no place
+    pub unresponsive: u64,  // Client stopped answering PINGs
+    pub panicked: u64,
+}
+
+/// How a tunnel worker ended
+#[derive(Debug)]
+pub enum WorkerExit {
+    Closed,
+    Drained,
+    Disconnected,
+    Failed(io::Error),
+    Protocol(String),  // Client sent a frame that does not decode or has no place (see `ProtocolError::is_peer_fault`)
+    Unresponsive,  // No PONG to a PING within TUNNEL_HEARTBEAT_TIMEOUT_SECS
+    Panicked(String),
+}
+
+/// Runs `worker` for `conn` on its own task and cleans up after it
+///
+/// However the worker ends, including by panicking, `conn` is removed from
+/// the registry (unless a newer client already replaced it), the exit is
+/// counted in the registry's worker stats and logged in the connection's span.
+pub async fn supervise<W>(registry: &TunnelRegistry, conn: &Arc<TunnelConnection>, worker: W) -> WorkerExit
+where
+    W: Future<Output = WorkerExit> + Send + 'static,
+{
+    let counters = &registry.workers;
+    counters.started.fetch_add(1, Ordering::Relaxed);
+
+    let exit = match tokio::spawn(worker.instrument(conn.span.clone())).await {
+        Ok(exit) => exit,
+        Err(e) if e.is_panic() => WorkerExit::Panicked(panic_message(e.into_panic())),
+        Err(_) => WorkerExit::Closed,  // Cancelled: the runtime is shutting down
+    };
+
+    let removed = registry.remove(conn).await;
+    let _span = conn.span.enter();
+    match &exit {
+        WorkerExit::Closed => {
+            counters.closed.fetch_add(1, Ordering::Relaxed);
+        }
+        WorkerExit::Drained => {
+            counters.drained.fetch_add(1, Ordering::Relaxed);
+            info!("Tunnel drained");
+        }
+        WorkerExit::Disconnected => {
+            counters.disconnected.fetch_add(1, Ordering::Relaxed);
+        }
+        WorkerExit::Failed(e) => {
+            counters.failed.fetch_add(1, Ordering::Relaxed);
+            warn!("Tunnel connection failed: {}", e);
+        }
+        WorkerExit::Protocol(message) => {
+            counters.protocol_errors.fetch_add(1, Ordering::Relaxed);
+            error!("Client broke the tunnel protocol: {}; dropping the connection", message);
+        }
+        WorkerExit::Unresponsive => {
+            counters.unresponsive.fetch_add(1, Ordering::Relaxed);
+            warn!("Client stopped answering heartbeats; dropping the connection");
+        }
+        WorkerExit::Panicked(message) => {
+            counters.panicked.fetch_add(1, Ordering::Relaxed);
+            error!("Tunnel worker panicked: {}; dropping the connection", message);
+        }
+    }
+    if removed {
+        info!("Client disconnected");
+    }
+    exit
+}
+
+/// Extracts the message of a panic payload
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .unwrap_or_else(|| "unknown panic".to_string()),
+    }
+}
+
+/// Worker task that owns the I/O of one tunnel connection
+///
+/// Writes each queued request frame and reads the matching response frame,
+/// one at a time, into a buffer reused across frames. Between requests it
+/// reads the client's control frames (stats reports, GOAWAY, heartbeats) as
+/// they arrive. Returns when the connection breaks, every handle is dropped,
+/// the client sent GOAWAY and every request queued before it was answered, or
+/// it stopped answering PINGs.
+/// Usually run through [`supervise`].
+pub async fn run_worker<S: AsyncRead + AsyncWrite>(
+    io: S,
+    mut inbox: WorkerInbox,
+    coalesce_bytes: usize,
+) -> WorkerExit {
+    let heartbeat = std::mem::take(&mut inbox.heartbeat);
+    let (read_half, write_half) = tokio::io::split(io);
+    let mut reader = BufReader::new(heartbeat.reader(read_half));
+    let mut writer = FrameWriter::new(write_half, coalesce_bytes)
+        .with_compression(inbox.compression)
+        .with_compression_monitor(inbox.compressed.clone());
+    let mut read_buf = BytesMut::new();
+    let writes = inbox.writes.clone();
+
+    loop {
+        // Requests first, so one queued before the client went away still gets its error
+        let req = tokio::select! {
+            biased;
+            req = inbox.requests.recv() => match req {
+                Some(req) => req,
+                None => break,
+            },
+            () = heartbeat.expired() => return WorkerExit::Unresponsive,
+            () = heartbeat.pong_due() => {
+                if let Err(e) = writes.timed(write_heartbeat(&mut writer, PONG_FRAME)).await {
+                    return worker_exit(&e);
+                }
+                continue;
+            }
+            () = heartbeat.ping_due() => {
+                heartbeat.ping_sent();
+                if let Err(e) = writes.timed(write_heartbeat(&mut writer, PING_FRAME)).await {
+                    return worker_exit(&e);
+                }
+                continue;
+            }
+            filled = reader.fill_buf() => {
+                match filled.map(|buf| buf.is_empty()) {
+                    Ok(false) => {}
+                    Ok(true) => return WorkerExit::Disconnected,
+                    Err(e) => return worker_exit(&e.into()),
+                }
+                let read = match read_frame_into(&mut reader, &mut read_buf, inbox.compression).await {
+                    Ok(()) if handle_control_frame(&read_buf, &mut inbox, &heartbeat) => Ok(()),
+                    Ok(()) => Err(ProtocolError::Unexpected("frame from an idle client".to_string())),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = read {
+                    return worker_exit(&e);
+                }
+                continue;
+            }
+        };
+
+        // The server gave up on the request while it was queued; the client never sees it
+        if req.response_tx.is_closed() {
+            continue;
+        }
+
+        // Write request to tunnel
+        req.progress.enter(Phase::TunnelWrite);
+        // Flush before waiting: the response cannot arrive while the request sits in a buffer
+        let written = writes.timed(async {
+            writer.write_frame(&req.payload).await?;
+            writer.flush().await
+        }).await;
+        if let Err(e) = written {
+            let exit = worker_exit(&e);
+            let _ = req.response_tx.send(Err(TunnelError::Write(e.into())));
+            return exit;
+        }
+
+        // Read response from tunnel, recording any stats reports sent ahead of it;
+        // the first bytes to arrive end the client's processing time. Heartbeats go on meanwhile.
+        req.progress.enter(Phase::ClientProcessing);
+        let mut unresponsive = false;
+        let read = {
+            let read = async {
+                reader.fill_buf().await?;
+                req.progress.enter(Phase::ResponseRead);
+                read_response_frame(&mut reader, &mut read_buf, &mut inbox, &heartbeat, &req.progress).await
+            };
+            tokio::pin!(read);
+            loop {
+                tokio::select! {
+                    read = &mut read => break read,
+                    () = heartbeat.expired() => {
+                        unresponsive = true;
+                        break Err(heartbeat.expired_error().into());
+                    }
+                    () = heartbeat.pong_due() => {
+                        if let Err(e) = writes.timed(write_heartbeat(&mut writer, PONG_FRAME)).await {
+                            break Err(e);
+                        }
+                    }
+                    () = heartbeat.ping_due() => {
+                        heartbeat.ping_sent();
+                        if let Err(e) = writes.timed(write_heartbeat(&mut writer, PING_FRAME)).await {
+                            break Err(e);
+                        }
+                    }
+                }
+            }
+        };
+        match read {
+            Ok(()) => {
+                req.progress.finish();
+                // The split-off payload shares read_buf's allocation, which is
+                // reclaimed on the next read once the handler has dropped it
+                let _ = req.response_tx.send(Ok(TunnelReply { payload: read_buf.split().freeze(), body: None }));
+            }
+            Err(e) => {
+                let exit = if unresponsive { WorkerExit::Unresponsive } else { worker_exit(&e) };
+                let _ = req.response_tx.send(Err(TunnelError::Read(e.into())));
+                return exit;
+            }
+        }
+    }
+    if inbox.draining.load(Ordering::Relaxed) {
+        WorkerExit::Drained
+    } else {
+        WorkerExit::Closed
+    }
+}
+
+/// Reads frames into `buf` until one is not a control frame, counting the bytes in `progress`
+async fn read_response_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut BytesMut,
+    inbox: &mut WorkerInbox,
+    heartbeat: &Heartbeat,
+    progress: &RequestProgress,
+) -> Result<(), ProtocolError> {
+    loop {
+        read_frame_tracked(reader, buf, inbox.compression, progress).await?;
+        if !handle_control_frame(buf, inbox, heartbeat) {
+            return Ok(());
+        }
+    }
+}
+
+/// Like `read_frame_into`, recording the payload bytes read as they arrive
+async fn read_frame_tracked<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut BytesMut,
+    compression: Option<Compression>,
+    progress: &RequestProgress,
+) -> Result<(), ProtocolError> {
+    let mut len_bytes = [0u8; FRAME_HEADER_LEN];
+    reader.read_exact(&mut len_bytes).await?;
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(ProtocolError::FrameTooLarge { len, max: MAX_FRAME_LEN });
+    }
+
+    buf.clear();
+    buf.resize(len, 0);
+    progress.start_response(len as u64);
+    read_payload_tracked(reader, buf, 0, progress).await?;
+    inflate_frame(buf, compression)
+}
+
+/// Fills `buf` from offset `filled` on, recording the payload bytes read as they arrive
+async fn read_payload_tracked<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut BytesMut,
+    mut filled: usize,
+    progress: &RequestProgress,
+) -> Result<(), ProtocolError> {
+    let len = buf.len();
+    while filled < len {
+        let read = reader.read(&mut buf[filled..]).await?;
+        if read == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        filled += read;
+        progress.set_response_bytes(filled as u64);
+    }
+    Ok(())
+}
+
+/// Request written to a multiplexing client and not answered yet
+struct Pending {
+    response_tx: Option<oneshot::Sender<Result<TunnelReply, TunnelError>>>,  // Taken once a streamed response's message arrived
+    progress: Arc<RequestProgress>,
+    responding: bool,  // Its response has started arriving
+    cancelled: bool,  // Given up on, and the client told so; its response is read and dropped
+    body_gone: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,  // Resolves once nobody reads its streamed response body
+}
+
+/// Worker task for a client that multiplexes requests (see `MULTIPLEX_HEADER`)
+///
+/// Like [`run_worker`], but writes up to `max_concurrent` requests without
+/// waiting for their responses, each tagged with an ID the client copies into
+/// its response, so a slow request does not hold up the others. Requests are
+/// written while responses are read, and each response goes to the request
+/// with its ID, in whatever order they arrive.
+///
+/// With `stream_window` set (see `STREAM_HEADER`), the pieces of streamed request
+/// bodies are written as they come, between other requests, and a streamed
+/// response holds its request's slot until its last piece has been read. Up to
+/// that many pieces of a body are in flight at once (TUNNEL_STREAM_WINDOW_CHUNKS).
+/// With credit on as well (see `WorkerInbox::with_stream_credit`), request
+/// bodies are written against the client's credit, and the pieces of response
+/// bodies are handed on without waiting for their visitor, which is granted
+/// credit as it reads them: a slow visitor holds up only its own response.
+///
+/// With cancel on (see `WorkerInbox::with_cancel`), a request given up on
+/// before its response started arriving is cancelled on the client, which
+/// still answers it; that response is read and dropped. So is a streamed
+/// response whose body nobody reads any more, which the client then ends.
+pub async fn run_multiplexed_worker<S: AsyncRead + AsyncWrite>(
+    io: S,
+    inbox: WorkerInbox,
+    coalesce_bytes: usize,
+    max_concurrent: usize,
+    stream_window: Option<usize>,
+) -> WorkerExit {
+    let WorkerInbox { mut requests, peer_stats, draining, heartbeat, compression, cancel, stream_credit, writes, compressed } = inbox;
+    let (read_half, write_half) = tokio::io::split(io);
+    let mut reader = BufReader::new(heartbeat.reader(read_half));
+    let mut writer = FrameWriter::new(write_half, coalesce_bytes).with_compression(compression).with_compression_monitor(compressed);
+    let pending = Mutex::new(HashMap::<u64, Pending>::new());
+    let slots = Semaphore::new(max_concurrent.max(1));  // One permit per request the client may still take
+    let goaway = Notify::new();  // Tells the writer to close the queue
+    let written_all = AtomicBool::new(false);  // The queue is closed and every request in it written
+    let credits = stream_credit.map(|chunks| Arc::new(Credits::new(chunks)));  // For the request bodies written
+    let (grants_tx, mut grants_rx) = mpsc::unbounded_channel();  // Credit to grant for the response bodies read
+
+    let write = async {
+        // Pieces of every streamed request body, tagged with the request's ID
+        let window = stream_window.unwrap_or(1);
+        let (pieces_tx, mut pieces_rx) = mpsc::channel::<(u64, BodyPiece)>(window);
+        let mut pieces_tx = Some(pieces_tx);
+        let mut permit = None;
+        let mut next_id = 0;
+        loop {
+            // New requests before body pieces, so a large upload does not hold them up
+            let req = tokio::select! {
+                biased;
+                () = goaway.notified() => {
+                    requests.close();
+                    continue;
+                }
+                () = heartbeat.pong_due() => {
+                    writes.timed(write_heartbeat(&mut writer, PONG_FRAME)).await?;
+                    continue;
+                }
+                () = heartbeat.ping_due() => {
+                    // Counted from now, so a write stuck on a dead connection still times out
+                    heartbeat.ping_sent();
+                    writes.timed(write_heartbeat(&mut writer, PING_FRAME)).await?;
+                    continue;
+                }
+                Some(grant) = grants_rx.recv() => {
+                    writes.timed(write_credits(&mut writer, grant, &mut grants_rx)).await?;
+                    continue;
+                }
+                id = abandoned_request(&pending), if cancel => {
+                    debug!("Cancelling request {} on the client", id);
+                    writes.timed(write_heartbeat(&mut writer, &ControlFrame::Cancel { id }.encode())).await?;
+                    continue;
+                }
+                acquired = slots.acquire(), if permit.is_none() => {
+                    permit = Some(acquired.expect("slots are never closed"));
+                    continue;
+                }
+                req = requests.recv(), if permit.is_some() && pieces_tx.is_some() => match req {
+                    Some(req) => req,
+                    None => {
+                        pieces_tx = None;
+                        continue;
+                    }
+                },
+                piece = pieces_rx.recv() => match piece {
+                    Some(piece) => {
+                        let written = writes.timed(write_body_pieces(&mut writer, piece, &mut pieces_rx, window, &mut HashSet::new())).await?;
+                        writes.pieces_done(written);
+                        continue;
+                    }
+                    // The queue is closed and every body written
+                    None => return Ok::<_, ProtocolError>(()),
+                },
+            };
+            // The server gave up on the request while it was queued; the client never sees it
+            if req.response_tx.is_closed() {
+                continue;
+            }
+
+            next_id += 1;
+            let id = next_id;
+            let progress = req.progress.clone();
+            progress.enter(Phase::TunnelWrite);
+            let entry = Pending { response_tx: Some(req.response_tx), progress: req.progress, responding: false, cancelled: false, body_gone: None };
+            pending.lock().unwrap().insert(id, entry);
+            // Given back when the response arrives
+            permit.take().expect("requests are only taken with a slot").forget();
+            writes.timed(async {
+                write_tagged_request(&mut writer, id, &req.payload).await?;
+                writer.flush().await
+            }).await?;
+            // Its body follows the request, as its pieces come
+            if let (Some(mut body), Some(pieces_tx)) = (req.body, pieces_tx.clone()) {
+                let writes = writes.clone();
+                let credit = credits.as_ref().map(|credits| credits.open(id));
+                tokio::spawn(async move {
+                    let mut ended = false;
+                    while !ended {
+                        let piece = body.recv().await.unwrap_or(BodyPiece::End { complete: false, sha256: None, trailers: Vec::new() });
+                        ended = matches!(piece, BodyPiece::End { .. });
+                        if let Some(credit) = &credit {
+                            if !credit.spend().await {
+                                return;
+                            }
+                        }
+                        writes.piece_queued();
+                        if pieces_tx.send((id, piece)).await.is_err() {
+                            writes.pieces_done(1);
+                            return;
+                        }
+                    }
+                });
+            }
+            // Unless the response already started arriving
+            if pending.lock().unwrap().get(&id).is_some_and(|entry| !entry.responding) {
+                progress.enter(Phase::ClientProcessing);
+            }
+        }
+    };
+
+    let read = async {
+        let mut read_buf = BytesMut::new();
+        // Streamed response bodies being read, by request ID
+        let mut streams = HashMap::<u64, BodyInlet>::new();
+        loop {
+            let mut len_bytes = [0u8; FRAME_HEADER_LEN];
+            reader.read_exact(&mut len_bytes).await?;
+            let len = u32::from_be_bytes(len_bytes) as usize;
+            if len > MAX_FRAME_LEN {
+                return Err(ProtocolError::FrameTooLarge { len, max: MAX_FRAME_LEN });
+            }
+            read_buf.clear();
+            read_buf.resize(len, 0);
+            let mut head = len.min(RESPONSE_ID_PREFIX_LEN);
+            reader.read_exact(&mut read_buf[..head]).await?;
+            // A COMPRESSED frame is read whole, then handled as the payload it stands for
+            // (refused unread without agreed compression)
+            let mut filled = head;
+            if is_compressed_frame(&read_buf[..head]) {
+                if compression.is_some() {
+                    reader.read_exact(&mut read_buf[head..]).await?;
+                }
+                inflate_frame(&mut read_buf, compression)?;
+                head = read_buf.len().min(RESPONSE_ID_PREFIX_LEN);
+                filled = read_buf.len();
+            }
+
+            if stream_window.is_some() && is_body_frame(&read_buf[..head]) {
+                reader.read_exact(&mut read_buf[filled..]).await?;
+                let frame = read_buf.split().freeze();
+                let (id, piece) = match decode_body_frame(&frame)? {
+                    BodyFrame::Chunk { id, data } => (id, BodyPiece::Data(frame.slice_ref(data))),
+                    BodyFrame::End { id, complete, sha256, trailers } => (id, BodyPiece::End { complete, sha256: sha256.map(str::to_string), trailers }),
+                };
+                let end = matches!(piece, BodyPiece::End { .. });
+                let Some(stream) = streams.get(&id) else {
+                    return Err(ProtocolError::Unexpected(format!("body frame for unknown response {}", id)));
+                };
+                stream.put(piece).await?;
+                if !end {
+                    continue;
+                }
+                streams.remove(&id);
+                let entry = pending.lock().unwrap().remove(&id).expect("only this task removes requests");
+                slots.add_permits(1);
+                entry.progress.finish();
+                if written_all.load(Ordering::Relaxed) && pending.lock().unwrap().is_empty() {
+                    return Ok::<_, ProtocolError>(());
+                }
+                continue;
+            }
+
+            let Some(id) = response_id(&read_buf[..head]) else {
+                reader.read_exact(&mut read_buf[filled..]).await?;
+                match record_control_frame(&read_buf, &heartbeat, &peer_stats, credits.as_deref()) {
+                    Some(true) => {
+                        if start_draining(&draining) {
+                            goaway.notify_one();
+                        }
+                    }
+                    Some(false) => {}
+                    None => return Err(ProtocolError::Unexpected("response without a request ID".to_string())),
+                }
+                continue;
+            };
+
+            let progress = pending.lock().unwrap().get_mut(&id).filter(|entry| entry.response_tx.is_some() || (entry.cancelled && !entry.responding)).map(|entry| {
+                entry.responding = true;
+                entry.progress.clone()
+            });
+            let Some(progress) = progress else {
+                return Err(ProtocolError::Unexpected(format!("response to unknown request {}", id)));
+            };
+            progress.enter(Phase::ResponseRead);
+            progress.start_response(len as u64);
+            progress.set_response_bytes(filled.min(len) as u64);
+            read_payload_tracked(&mut reader, &mut read_buf, filled, &progress).await?;
+
+            // The split-off payload shares read_buf's allocation, as in `run_worker`
+            let payload = read_buf.split().freeze();
+            let streamed = stream_window.filter(|_| {
+                is_binary_frame(&payload) && decode_response_frame(&payload).is_ok_and(|(response, _)| response.stream)
+            });
+            if let Some(window) = streamed {
+                let (inlet, body) = match credits {
+                    Some(_) => BodyInlet::credited(id, window, grants_tx.clone()),
+                    None => BodyInlet::channel(window),
+                };
+                let response_tx = pending.lock().unwrap().get_mut(&id).and_then(|entry| {
+                    entry.body_gone = entry.response_tx.is_some().then(|| Box::pin(inlet.closed()) as _);
+                    entry.response_tx.take()
+                });
+                streams.insert(id, inlet);
+                if let Some(response_tx) = response_tx {
+                    let _ = response_tx.send(Ok(TunnelReply { payload, body: Some(body) }));
+                }
+                continue;
+            }
+
+            let entry = pending.lock().unwrap().remove(&id).expect("only this task removes requests");
+            slots.add_permits(1);
+            entry.progress.finish();
+            if let Some(response_tx) = entry.response_tx {
+                let _ = response_tx.send(Ok(TunnelReply { payload, body: None }));
+            }
+            if written_all.load(Ordering::Relaxed) && pending.lock().unwrap().is_empty() {
+                return Ok(());
+            }
+        }
+    };
+
+    tokio::pin!(write, read);
+    let mut writing = true;
+    let mut unresponsive = false;
+    let failure = loop {
+        tokio::select! {
+            written = &mut write, if writing => match written {
+                Ok(()) => {
+                    writing = false;
+                    written_all.store(true, Ordering::Relaxed);
+                    if pending.lock().unwrap().is_empty() {
+                        break None;
+                    }
+                }
+                Err(e) => break Some((e, true)),
+            },
+            read = &mut read => match read {
+                Ok(()) => break None,
+                Err(e) => break Some((e, false)),
+            },
+            () = heartbeat.expired() => {
+                unresponsive = true;
+                break Some((heartbeat.expired_error().into(), false));
+            }
+        }
+    };
+
+    let Some((e, in_write)) = failure else {
+        return if draining.load(Ordering::Relaxed) { WorkerExit::Drained } else { WorkerExit::Closed };
+    };
+    // Streamed response bodies end without their last piece, so they are not taken for whole
+    for (_, entry) in pending.lock().unwrap().drain() {
+        let e = io::Error::new(e.kind(), e.to_string());
+        if let Some(response_tx) = entry.response_tx {
+            let _ = response_tx.send(Err(if in_write { TunnelError::Write(e) } else { TunnelError::Read(e) }));
+        }
+    }
+    if unresponsive {
+        return WorkerExit::Unresponsive;
+    }
+    worker_exit(&e)
+}
+
+/// Waits for a written request whose requester gave up before its response started arriving,
+/// or stopped reading its streamed response body, and marks it cancelled
+fn abandoned_request(pending: &Mutex<HashMap<u64, Pending>>) -> impl Future<Output = u64> + '_ {
+    std::future::poll_fn(move |cx| {
+        let mut pending = pending.lock().unwrap();
+        for (id, entry) in pending.iter_mut().filter(|(_, entry)| !entry.cancelled) {
+            let gone = match (&mut entry.response_tx, &mut entry.body_gone) {
+                (Some(tx), _) if !entry.responding => tx.poll_closed(cx).is_ready(),
+                (None, Some(body_gone)) => body_gone.as_mut().poll(cx).is_ready(),
+                _ => false,
+            };
+            if gone {
+                entry.response_tx = None;
+                entry.body_gone = None;
+                entry.cancelled = true;
+                return Poll::Ready(*id);
+            }
+        }
+        Poll::Pending
+    })
+}
+
+/// Records a stats report, GOAWAY, PING or PONG from the client; false if `frame` is a data frame
+///
+/// On GOAWAY the queue is closed: requests already in it are still sent,
+/// and the worker ends once they are answered.
+fn handle_control_frame(frame: &[u8], inbox: &mut WorkerInbox, heartbeat: &Heartbeat) -> bool {
+    let Some(goaway) = record_control_frame(frame, heartbeat, &inbox.peer_stats, None) else {
+        return false;
+    };
+    if goaway && start_draining(&inbox.draining) {
+        inbox.requests.close();
+    }
+    true
+}
+
+/// Records a stats report, PING, PONG or CREDIT (for `credits`) from the client
+///
+/// # Returns
+/// * `None` if `frame` is a data frame
+/// * `Some(true)` for a GOAWAY, left to the caller
+/// * `Some(false)` for any other control frame
+fn record_control_frame(frame: &[u8], heartbeat: &Heartbeat, peer_stats: &Mutex<Option<PeerStats>>, credits: Option<&Credits>) -> Option<bool> {
+    let control = match classify_frame(frame) {
+        Ok(Frame::Data(_)) => return None,
+        Ok(Frame::Control(control)) => control,
+        // A bad control frame is no reason to drop the connection
+        Err(e) => {
+            debug!("Ignoring invalid control frame: {}", e);
+            return Some(false);
+        }
+    };
+    match control {
+        ControlFrame::Stats(report) => {
+            let reported_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            *peer_stats.lock().unwrap() = Some(PeerStats { report, reported_at });
+        }
+        ControlFrame::Goaway {} => return Some(true),
+        ControlFrame::Ping {} => heartbeat.ping_received(),
+        ControlFrame::Pong {} => heartbeat.pong_received(),
+        // Only the server sends them
+        ControlFrame::Cancel { .. } => debug!("Ignoring CANCEL from the client"),
+        ControlFrame::Credit { id, chunks } => match credits {
+            Some(credits) => credits.grant(id, chunks),
+            None => debug!("Ignoring CREDIT from the client"),
+        },
+    }
+    Some(false)
+}
+
+/// Writes a PING, PONG or CANCEL, flushed so it goes out right away
+async fn write_heartbeat<W: AsyncWrite + Unpin>(writer: &mut FrameWriter<W>, frame: &[u8]) -> Result<(), ProtocolError> {
+    writer.write_frame(frame).await?;
+    writer.flush().await
+}
+
+/// Marks the connection as draining after a GOAWAY; false if it already was
+fn start_draining(draining: &AtomicBool) -> bool {
+    if draining.swap(true, Ordering::Relaxed) {
+        return false;
+    }
+    info!("Client is shutting down; draining the tunnel");
+    true
+}
+
+/// Classifies the error that ended a worker
+fn worker_exit(e: &ProtocolError) -> WorkerExit {
+    match e {
+        ProtocolError::Closed(_) => WorkerExit::Disconnected,
+        e if e.is_peer_fault() => WorkerExit::Protocol(e.to_string()),
+        e => WorkerExit::Failed(io::Error::new(e.kind(), e.to_string())),
+    }
+}
@@ -0,0 +1,73 @@
+//! Client-side transport stream for the tunnel connection.
+
+use tokio::net::TcpStream;
+
+/// Stream type that can be either TLS or plain TCP
+pub enum TunnelStream {
+    Tls(Box<tokio_rustls::client::TlsStream<TcpStream>>), // Boxed: over 1 KiB, against 40 bytes for Plain
+    Plain(TcpStream),
+}
+
+impl tokio::io::AsyncRead for TunnelStream {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            TunnelStream::Tls(s) => std::pin::Pin::new(s).poll_read(cx, buf),
+            TunnelStream::Plain(s) => std::pin::Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl tokio::io::AsyncWrite for TunnelStream {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            TunnelStream::Tls(s) => std::pin::Pin::new(s).poll_write(cx, buf),
+            TunnelStream::Plain(s) => std::pin::Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            TunnelStream::Tls(s) => std::pin::Pin::new(s).poll_write_vectored(cx, bufs),
+            TunnelStream::Plain(s) => std::pin::Pin::new(s).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            TunnelStream::Tls(s) => s.is_write_vectored(),
+            TunnelStream::Plain(s) => s.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            TunnelStream::Tls(s) => std::pin::Pin::new(s).poll_flush(cx),
+            TunnelStream::Plain(s) => std::pin::Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            TunnelStream::Tls(s) => std::pin::Pin::new(s).poll_shutdown(cx),
+            TunnelStream::Plain(s) => std::pin::Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
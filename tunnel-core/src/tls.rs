@@ -0,0 +1,440 @@
+//! TLS protocol options shared by both ends: minimum version, ALPN, cipher
+//! suites and session resumption, applied on top of the rustls defaults; plus
+//! server certificate pins and client certificates.
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::{Resumption, WebPkiServerVerifier};
+use rustls::crypto::{aws_lc_rs, verify_tls12_signature, verify_tls13_signature, CryptoProvider, WebPkiSupportedAlgorithms};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::{NoServerSessionStorage, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{
+    ClientConfig, DigitallySignedStruct, DistinguishedName, OtherError, RootCertStore, ServerConfig, SignatureScheme,
+    SupportedProtocolVersion,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Protocol versions allowed with TLS_MIN_VERSION=1.3
+static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+/// Lowest TLS version a connection may negotiate
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// TLS tuning for tunnel and public connections
+#[derive(Debug, Clone, Serialize)]
+pub struct TlsOptions {
+    pub min_version: TlsVersion,
+    pub alpn: Vec<String>,           // ALPN protocols in preference order (empty: no ALPN)
+    pub cipher_suites: Vec<String>,  // Allowed suites by rustls name (empty: provider defaults)
+    pub session_resumption: bool,    // Session tickets/cache for faster reconnects
+}
+
+impl Default for TlsOptions {
+    fn default() -> Self {
+        Self {
+            min_version: TlsVersion::Tls12,
+            alpn: Vec::new(),
+            cipher_suites: Vec::new(),
+            session_resumption: true,
+        }
+    }
+}
+
+impl TlsOptions {
+    /// Settings read by `from_source`
+    pub const KEYS: [&'static str; 4] = ["TLS_MIN_VERSION", "TLS_ALPN", "TLS_CIPHER_SUITES", "TLS_SESSION_RESUMPTION"];
+
+    /// Reads TLS_MIN_VERSION, TLS_ALPN, TLS_CIPHER_SUITES and TLS_SESSION_RESUMPTION from a key lookup
+    ///
+    /// Cipher suite names are checked against the crypto provider, and the
+    /// combination is rejected if no allowed suite works with the allowed versions.
+    pub fn from_source(get: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let mut options = Self::default();
+
+        if let Some(value) = get("TLS_MIN_VERSION") {
+            options.min_version = match value.trim() {
+                "1.2" => TlsVersion::Tls12,
+                "1.3" => TlsVersion::Tls13,
+                _ => return Err(format!("Invalid TLS_MIN_VERSION: {} (expected 1.2 or 1.3)", value)),
+            };
+        }
+        if let Some(value) = get("TLS_ALPN") {
+            options.alpn = split_list(&value);
+        }
+        if let Some(value) = get("TLS_CIPHER_SUITES") {
+            let available = suite_names(&aws_lc_rs::default_provider());
+            for name in split_list(&value) {
+                let Some(canonical) = available.iter().find(|known| known.eq_ignore_ascii_case(&name)) else {
+                    return Err(format!(
+                        "Invalid TLS_CIPHER_SUITES: unknown suite {} (available: {})",
+                        name, available.join(", ")
+                    ));
+                };
+                options.cipher_suites.push(canonical.clone());
+            }
+        }
+        if let Some(value) = get("TLS_SESSION_RESUMPTION") {
+            options.session_resumption = value.trim().parse()
+                .map_err(|_| format!("Invalid TLS_SESSION_RESUMPTION: {} (expected true or false)", value))?;
+        }
+
+        // Catch suite/version combinations that leave nothing to negotiate
+        ClientConfig::builder_with_provider(Arc::new(options.provider()))
+            .with_protocol_versions(options.protocol_versions())
+            .map_err(|e| format!("Invalid TLS settings: {}", e))?;
+
+        Ok(options)
+    }
+
+    /// Builds a client config trusting `roots`
+    ///
+    /// Reuse the result across connections: the session cache lives in the config.
+    pub fn client_config(&self, roots: RootCertStore) -> Result<ClientConfig, rustls::Error> {
+        self.pinned_client_config(roots, &[], None)
+    }
+
+    /// Like `client_config`, also requiring the server certificate to match one
+    /// of `pins` (if any) and presenting `client_cert` when the server asks for one
+    pub fn pinned_client_config(
+        &self,
+        roots: RootCertStore,
+        pins: &[CertPin],
+        client_cert: Option<&ClientCert>,
+    ) -> Result<ClientConfig, rustls::Error> {
+        let provider = Arc::new(self.provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(self.protocol_versions())?;
+        let builder = if pins.is_empty() {
+            builder.with_root_certificates(roots)
+        } else {
+            let inner = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|e| rustls::Error::General(e.to_string()))?;
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier { inner, pins: pins.to_vec() }))
+        };
+        let mut config = match client_cert {
+            Some(cert) => builder.with_client_auth_cert(cert.chain.clone(), cert.key.clone_key())?,
+            None => builder.with_no_client_auth(),
+        };
+
+        config.alpn_protocols = self.alpn_protocols();
+        if !self.session_resumption {
+            config.resumption = Resumption::disabled();
+        }
+        Ok(config)
+    }
+
+    /// Builds a server config presenting `cert_chain`
+    ///
+    /// With resumption enabled the server issues stateless session tickets in
+    /// addition to its in-memory session cache; disabled, it offers neither.
+    pub fn server_config(
+        &self,
+        cert_chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Result<ServerConfig, rustls::Error> {
+        let config = ServerConfig::builder_with_provider(Arc::new(self.provider()))
+            .with_protocol_versions(self.protocol_versions())?
+            .with_no_client_auth()
+            .with_single_cert(cert_chain, key)?;
+        self.finish_server_config(config)
+    }
+
+    /// Builds a server config presenting whichever certificate `resolver` picks for a connection
+    ///
+    /// With `client_certs`, clients are asked for a certificate, which they may
+    /// decline. Any certificate is accepted as long as the client proves it holds
+    /// its key: it identifies the client (see `CertPin`) rather than vouching for it.
+    pub fn server_config_with_resolver(
+        &self,
+        resolver: Arc<dyn ResolvesServerCert>,
+        client_certs: bool,
+    ) -> Result<ServerConfig, rustls::Error> {
+        let provider = self.provider();
+        let algorithms = provider.signature_verification_algorithms;
+        let builder = ServerConfig::builder_with_provider(Arc::new(provider))
+            .with_protocol_versions(self.protocol_versions())?;
+        let builder = if client_certs {
+            builder.with_client_cert_verifier(Arc::new(AnyClientCert { algorithms }))
+        } else {
+            builder.with_no_client_auth()
+        };
+        let config = builder.with_cert_resolver(resolver);
+        self.finish_server_config(config)
+    }
+
+    /// Pairs `cert_chain` with `key` for a certificate resolver, checking that they match
+    pub fn certified_key(
+        &self,
+        cert_chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Result<CertifiedKey, rustls::Error> {
+        CertifiedKey::from_der(cert_chain, key, &self.provider())
+    }
+
+    /// Applies ALPN and session resumption to a server config
+    fn finish_server_config(&self, mut config: ServerConfig) -> Result<ServerConfig, rustls::Error> {
+        config.alpn_protocols = self.alpn_protocols();
+        if self.session_resumption {
+            config.ticketer = aws_lc_rs::Ticketer::new()?;
+        } else {
+            config.session_storage = Arc::new(NoServerSessionStorage {});
+            config.send_tls13_tickets = 0;
+        }
+        Ok(config)
+    }
+
+    /// One-line description for startup logs
+    pub fn summary(&self) -> String {
+        format!(
+            "min_version={}, alpn={}, cipher_suites={}, session_resumption={}",
+            match self.min_version {
+                TlsVersion::Tls12 => "1.2",
+                TlsVersion::Tls13 => "1.3",
+            },
+            if self.alpn.is_empty() { "none".to_string() } else { self.alpn.join(",") },
+            if self.cipher_suites.is_empty() { "default".to_string() } else { self.cipher_suites.join(",") },
+            self.session_resumption,
+        )
+    }
+
+    /// The default provider restricted to the configured cipher suites
+    fn provider(&self) -> CryptoProvider {
+        let mut provider = aws_lc_rs::default_provider();
+        if !self.cipher_suites.is_empty() {
+            provider.cipher_suites.retain(|suite| {
+                self.cipher_suites.contains(&format!("{:?}", suite.suite()))
+            });
+        }
+        provider
+    }
+
+    fn protocol_versions(&self) -> &'static [&'static SupportedProtocolVersion] {
+        match self.min_version {
+            TlsVersion::Tls12 => rustls::ALL_VERSIONS,
+            TlsVersion::Tls13 => TLS13_ONLY,
+        }
+    }
+
+    fn alpn_protocols(&self) -> Vec<Vec<u8>> {
+        self.alpn.iter().map(|protocol| protocol.as_bytes().to_vec()).collect()
+    }
+}
+
+/// SHA-256 fingerprint of a server certificate the client accepts (SERVER_CERT_PIN)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CertPin([u8; 32]);
+
+impl CertPin {
+    /// Parses `sha256:` and 64 hex digits, which may be colon-separated as
+    /// `openssl x509 -fingerprint -sha256` prints them
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("{} (expected sha256: and 64 hex digits)", value);
+        let (algorithm, fingerprint) = value.trim().split_once(':').ok_or_else(invalid)?;
+        if !algorithm.eq_ignore_ascii_case("sha256") {
+            return Err(invalid());
+        }
+        let digits: Vec<u8> = fingerprint.bytes().filter(|b| *b != b':').collect();
+        if digits.len() != 64 {
+            return Err(invalid());
+        }
+        let mut pin = [0u8; 32];
+        for (byte, pair) in pin.iter_mut().zip(digits.chunks(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+        }
+        Ok(Self(pin))
+    }
+
+    /// Fingerprint of a DER certificate
+    pub fn of(cert: &CertificateDer<'_>) -> Self {
+        Self(Sha256::digest(cert.as_ref()).into())
+    }
+}
+
+impl fmt::Display for CertPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("sha256:")?;
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+impl Serialize for CertPin {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for CertPin {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Self::parse(&value).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Parses a comma-separated list of pins, e.g. the current and the next certificate during a rotation
+pub fn parse_cert_pins(value: &str) -> Result<Vec<CertPin>, String> {
+    split_list(value).iter().map(|pin| CertPin::parse(pin)).collect()
+}
+
+/// Verifies the server certificate as usual, then requires it to match a pin
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<CertPin>,
+}
+
+/// Server certificate that passed verification but matches no pin
+#[derive(Debug)]
+struct PinMismatch(CertPin);
+
+impl fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "server certificate {} matches no SERVER_CERT_PIN", self.0)
+    }
+}
+
+impl std::error::Error for PinMismatch {}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        let presented = CertPin::of(end_entity);
+        if !self.pins.contains(&presented) {
+            return Err(rustls::Error::Other(OtherError(Arc::new(PinMismatch(presented)))));
+        }
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Certificate chain and key a client presents to servers that ask for one
+#[derive(Debug)]
+pub struct ClientCert {
+    chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+}
+
+impl ClientCert {
+    /// Reads a PEM certificate chain and its private key (CLIENT_CERT_FILE, CLIENT_KEY_FILE)
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self, String> {
+        let chain = CertificateDer::pem_file_iter(cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("Failed to read CLIENT_CERT_FILE {}: {}", cert_path.display(), e))?;
+        if chain.is_empty() {
+            return Err(format!("CLIENT_CERT_FILE {} contains no certificates", cert_path.display()));
+        }
+        let key = PrivateKeyDer::from_pem_file(key_path)
+            .map_err(|e| format!("Failed to read CLIENT_KEY_FILE {}: {}", key_path.display(), e))?;
+        Ok(Self { chain, key })
+    }
+
+    /// Fingerprint of the certificate, as servers see it
+    pub fn pin(&self) -> CertPin {
+        CertPin::of(&self.chain[0])
+    }
+}
+
+/// Accepts every client certificate whose key the client proves to hold
+#[derive(Debug)]
+struct AnyClientCert {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ClientCertVerifier for AnyClientCert {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+}
+
+/// Names of the cipher suites a provider supports, e.g. `TLS13_AES_128_GCM_SHA256`
+fn suite_names(provider: &CryptoProvider) -> Vec<String> {
+    provider.cipher_suites.iter().map(|suite| format!("{:?}", suite.suite())).collect()
+}
+
+/// Splits a comma-separated list, dropping empty entries
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
@@ -0,0 +1,219 @@
+//! Socket and framing options for the tunnel connection, shared by both ends.
+
+use crate::config::{serialize_opt_millis, serialize_opt_secs, serialize_secs};
+use serde::Serialize;
+use socket2::{SockRef, TcpKeepalive};
+use std::io;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tunnel_protocol::{Compression, HeaderLimits};
+
+/// Default for TUNNEL_STREAM_THRESHOLD_BYTES
+pub const DEFAULT_STREAM_THRESHOLD_BYTES: usize = 1024 * 1024;
+
+/// Default for TUNNEL_STREAM_WINDOW_CHUNKS
+pub const DEFAULT_STREAM_WINDOW_CHUNKS: usize = 8;
+
+/// Default for TUNNEL_HEARTBEAT_SECS
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Default for TUNNEL_HEARTBEAT_TIMEOUT_SECS
+pub const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Transport tuning for the tunnel connection
+#[derive(Debug, Clone, Serialize)]
+pub struct TransportOptions {
+    pub tcp_nodelay: bool,                 // Disable Nagle so small frames are sent immediately
+    pub send_buffer_bytes: Option<usize>,  // SO_SNDBUF (None: kernel default)
+    pub coalesce_bytes: usize,             // Buffer frames up to this many bytes into one write (0: off)
+    pub header_limits: HeaderLimits,       // Enforced on received messages, announced to the peer
+    #[serde(serialize_with = "serialize_opt_secs")]
+    pub tcp_keepalive: Option<Duration>,   // Idle time before the first keepalive probe (None: SO_KEEPALIVE off)
+    #[serde(serialize_with = "serialize_opt_secs")]
+    pub tcp_keepalive_interval: Option<Duration>,  // Between unanswered probes (None: kernel default)
+    #[serde(serialize_with = "serialize_opt_millis")]
+    pub tcp_user_timeout: Option<Duration>,  // TCP_USER_TIMEOUT, Linux only (None: kernel default)
+    pub stream_threshold_bytes: usize,     // Bodies larger than this are streamed, where both ends agreed to it
+    pub stream_window_chunks: usize,       // Chunks of one streamed body in flight at once, and written per flush
+    #[serde(serialize_with = "serialize_opt_secs")]
+    pub heartbeat_interval: Option<Duration>,  // Between PINGs, where both ends agreed to heartbeats (None: off)
+    #[serde(serialize_with = "serialize_secs")]
+    pub heartbeat_timeout: Duration,       // Wait for the PONG to a PING before dropping the connection
+    pub compression: Option<Compression>,  // Compress frames, where both ends agreed to it (None: off)
+}
+
+impl Default for TransportOptions {
+    fn default() -> Self {
+        Self {
+            tcp_nodelay: true,
+            send_buffer_bytes: None,
+            coalesce_bytes: 0,
+            header_limits: HeaderLimits::default(),
+            tcp_keepalive: None,
+            tcp_keepalive_interval: None,
+            tcp_user_timeout: None,
+            stream_threshold_bytes: DEFAULT_STREAM_THRESHOLD_BYTES,
+            stream_window_chunks: DEFAULT_STREAM_WINDOW_CHUNKS,
+            heartbeat_interval: Some(DEFAULT_HEARTBEAT_INTERVAL),
+            heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+            compression: None,
+        }
+    }
+}
+
+impl TransportOptions {
+    /// Settings read by `from_source`
+    pub const KEYS: [&'static str; 13] = [
+        "TUNNEL_TCP_NODELAY", "TUNNEL_SEND_BUFFER_BYTES", "TUNNEL_COALESCE_BYTES", "TUNNEL_MAX_HEADERS", "TUNNEL_MAX_HEADER_BYTES",
+        "TUNNEL_TCP_KEEPALIVE_SECS", "TUNNEL_TCP_KEEPALIVE_INTERVAL_SECS", "TUNNEL_TCP_USER_TIMEOUT_MS", "TUNNEL_STREAM_THRESHOLD_BYTES",
+        "TUNNEL_STREAM_WINDOW_CHUNKS", "TUNNEL_HEARTBEAT_SECS", "TUNNEL_HEARTBEAT_TIMEOUT_SECS",
+        "TUNNEL_COMPRESSION",
+    ];
+
+    /// Reads the `KEYS` settings from a key lookup
+    ///
+    /// `TUNNEL_TCP_KEEPALIVE_SECS=0` and `TUNNEL_TCP_USER_TIMEOUT_MS=0` (the defaults) leave them off,
+    /// as `TUNNEL_HEARTBEAT_SECS=0` does heartbeats.
+    pub fn from_source(get: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let mut options = Self::default();
+
+        if let Some(value) = get("TUNNEL_TCP_NODELAY") {
+            options.tcp_nodelay = value.trim().parse()
+                .map_err(|_| format!("Invalid TUNNEL_TCP_NODELAY: {} (expected true or false)", value))?;
+        }
+        if let Some(value) = get("TUNNEL_SEND_BUFFER_BYTES") {
+            let bytes: usize = value.trim().parse()
+                .map_err(|_| format!("Invalid TUNNEL_SEND_BUFFER_BYTES: {}", value))?;
+            options.send_buffer_bytes = Some(bytes);
+        }
+        if let Some(value) = get("TUNNEL_COALESCE_BYTES") {
+            options.coalesce_bytes = value.trim().parse()
+                .map_err(|_| format!("Invalid TUNNEL_COALESCE_BYTES: {}", value))?;
+        }
+        if let Some(value) = get("TUNNEL_MAX_HEADERS") {
+            options.header_limits.max_count = value.trim().parse().ok().filter(|count| *count > 0)
+                .ok_or_else(|| format!("Invalid TUNNEL_MAX_HEADERS: {}", value))?;
+        }
+        if let Some(value) = get("TUNNEL_MAX_HEADER_BYTES") {
+            options.header_limits.max_bytes = value.trim().parse().ok().filter(|bytes| *bytes > 0)
+                .ok_or_else(|| format!("Invalid TUNNEL_MAX_HEADER_BYTES: {}", value))?;
+        }
+        if let Some(value) = get("TUNNEL_TCP_KEEPALIVE_SECS") {
+            let secs: u64 = value.trim().parse()
+                .map_err(|_| format!("Invalid TUNNEL_TCP_KEEPALIVE_SECS: {}", value))?;
+            options.tcp_keepalive = Some(Duration::from_secs(secs)).filter(|time| !time.is_zero());
+        }
+        if let Some(value) = get("TUNNEL_TCP_KEEPALIVE_INTERVAL_SECS") {
+            let secs: u64 = value.trim().parse().ok().filter(|secs| *secs > 0)
+                .ok_or_else(|| format!("Invalid TUNNEL_TCP_KEEPALIVE_INTERVAL_SECS: {} (expected a positive number)", value))?;
+            if options.tcp_keepalive.is_none() {
+                return Err("TUNNEL_TCP_KEEPALIVE_INTERVAL_SECS requires TUNNEL_TCP_KEEPALIVE_SECS".to_string());
+            }
+            if !cfg!(any(
+                target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios", target_os = "freebsd",
+                target_os = "netbsd", windows,
+            )) {
+                return Err("TUNNEL_TCP_KEEPALIVE_INTERVAL_SECS is not supported on this platform".to_string());
+            }
+            options.tcp_keepalive_interval = Some(Duration::from_secs(secs));
+        }
+        if let Some(value) = get("TUNNEL_TCP_USER_TIMEOUT_MS") {
+            let millis: u64 = value.trim().parse()
+                .map_err(|_| format!("Invalid TUNNEL_TCP_USER_TIMEOUT_MS: {}", value))?;
+            options.tcp_user_timeout = Some(Duration::from_millis(millis)).filter(|timeout| !timeout.is_zero());
+            if options.tcp_user_timeout.is_some() && !cfg!(any(target_os = "linux", target_os = "android")) {
+                return Err("TUNNEL_TCP_USER_TIMEOUT_MS is only supported on Linux".to_string());
+            }
+        }
+        if let Some(value) = get("TUNNEL_STREAM_THRESHOLD_BYTES") {
+            options.stream_threshold_bytes = value.trim().parse().ok().filter(|bytes| *bytes > 0)
+                .ok_or_else(|| format!("Invalid TUNNEL_STREAM_THRESHOLD_BYTES: {} (expected a positive number)", value))?;
+        }
+        if let Some(value) = get("TUNNEL_STREAM_WINDOW_CHUNKS") {
+            options.stream_window_chunks = value.trim().parse().ok().filter(|chunks| *chunks > 0)
+                .ok_or_else(|| format!("Invalid TUNNEL_STREAM_WINDOW_CHUNKS: {} (expected a positive number)", value))?;
+        }
+        if let Some(value) = get("TUNNEL_HEARTBEAT_SECS") {
+            let secs: u64 = value.trim().parse()
+                .map_err(|_| format!("Invalid TUNNEL_HEARTBEAT_SECS: {}", value))?;
+            options.heartbeat_interval = Some(Duration::from_secs(secs)).filter(|interval| !interval.is_zero());
+        }
+        if let Some(value) = get("TUNNEL_HEARTBEAT_TIMEOUT_SECS") {
+            let secs: u64 = value.trim().parse().ok().filter(|secs| *secs > 0)
+                .ok_or_else(|| format!("Invalid TUNNEL_HEARTBEAT_TIMEOUT_SECS: {} (expected a positive number)", value))?;
+            options.heartbeat_timeout = Duration::from_secs(secs);
+        }
+        if let Some(value) = get("TUNNEL_COMPRESSION") {
+            options.compression = match value.trim() {
+                "off" | "" => None,
+                name => Some(Compression::parse(name)
+                    .ok_or_else(|| format!("Invalid TUNNEL_COMPRESSION: {} (expected zstd, gzip or off)", value))?),
+            };
+        }
+
+        Ok(options)
+    }
+
+    /// Applies the socket options to a connected stream
+    pub fn apply_to_stream(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.tcp_nodelay)?;
+        self.apply_to_socket(SockRef::from(stream))
+    }
+
+    /// Applies the send buffer size and keepalive options to a listener; accepted sockets inherit them
+    ///
+    /// TCP_NODELAY is not inherited and has to be set per accepted stream.
+    pub fn apply_to_listener(&self, listener: &TcpListener) -> io::Result<()> {
+        self.apply_to_socket(SockRef::from(listener))
+    }
+
+    /// Options set the same way on listeners and streams
+    fn apply_to_socket(&self, socket: SockRef<'_>) -> io::Result<()> {
+        if let Some(bytes) = self.send_buffer_bytes {
+            socket.set_send_buffer_size(bytes)?;
+        }
+        // Dead peers (e.g. a NAT mapping that expired) are then detected by the kernel
+        if let Some(time) = self.tcp_keepalive {
+            let keepalive = TcpKeepalive::new().with_time(time);
+            #[cfg(any(
+                target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios", target_os = "freebsd",
+                target_os = "netbsd", windows,
+            ))]
+            let keepalive = match self.tcp_keepalive_interval {
+                Some(interval) => keepalive.with_interval(interval),
+                None => keepalive,
+            };
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(timeout) = self.tcp_user_timeout {
+            socket.set_tcp_user_timeout(Some(timeout))?;
+        }
+        Ok(())
+    }
+
+    /// One-line description for startup logs
+    pub fn summary(&self) -> String {
+        format!(
+            "tcp_nodelay={}, send_buffer={}, coalesce={}, max_headers={}, max_header_bytes={}, keepalive={}, user_timeout={}, stream_threshold={}B, stream_window={}, heartbeat={}, compression={}",
+            self.tcp_nodelay,
+            self.send_buffer_bytes.map_or("default".to_string(), |b| format!("{}B", b)),
+            if self.coalesce_bytes == 0 { "off".to_string() } else { format!("{}B", self.coalesce_bytes) },
+            self.header_limits.max_count,
+            self.header_limits.max_bytes,
+            match (self.tcp_keepalive, self.tcp_keepalive_interval) {
+                (None, _) => "off".to_string(),
+                (Some(time), None) => format!("{:?}", time),
+                (Some(time), Some(interval)) => format!("{:?}/{:?}", time, interval),
+            },
+            self.tcp_user_timeout.map_or("default".to_string(), |timeout| format!("{:?}", timeout)),
+            self.stream_threshold_bytes,
+            self.stream_window_chunks,
+            match self.heartbeat_interval {
+                Some(interval) => format!("{:?}/{:?}", interval, self.heartbeat_timeout),
+                None => "off".to_string(),
+            },
+            self.compression.map_or("off", Compression::name),
+        )
+    }
+}
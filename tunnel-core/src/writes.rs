@@ -0,0 +1,84 @@
+//! Write timing of a tunnel connection.
+//!
+//! The worker times every write to the tunnel, flush included, so a socket
+//! that stopped taking data (a client or link not keeping up) shows in the
+//! admin API as stalled writes and queued frames, rather than only as slow
+//! requests. A write taking longer than TUNNEL_WRITE_STALL_MS is a stall.
+
+use serde::Serialize;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+
+/// Write timing shared by a tunnel worker and the handle of its connection
+pub struct WriteMonitor {
+    stall_after: Duration,        // TUNNEL_WRITE_STALL_MS
+    created: Instant,             // Origin of `writing_since_us`
+    writing_since_us: AtomicU64,  // Start of the write in progress, in µs after `created`, plus one (0: not writing)
+    last_write_us: AtomicU64,     // Duration of the last finished write
+    stalls: AtomicU64,            // Writes that took longer than `stall_after`
+    queued_pieces: AtomicUsize,   // Body pieces handed to the worker and not written yet
+}
+
+/// Snapshot of a `WriteMonitor`, for the admin API
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct WriteStats {
+    pub queued_frames: usize,  // Requests and body pieces waiting for the worker to write them
+    pub last_write_ms: u64,    // Duration of the last finished write, flush included
+    pub stalled: bool,         // A write has been going on for longer than TUNNEL_WRITE_STALL_MS
+    pub stalls: u64,           // Writes that took longer than TUNNEL_WRITE_STALL_MS
+}
+
+impl WriteMonitor {
+    /// Counts writes taking longer than `stall_after` as stalls
+    pub fn new(stall_after: Duration) -> Self {
+        Self {
+            stall_after,
+            created: Instant::now(),
+            writing_since_us: AtomicU64::new(0),
+            last_write_us: AtomicU64::new(0),
+            stalls: AtomicU64::new(0),
+            queued_pieces: AtomicUsize::new(0),
+        }
+    }
+
+    /// Runs `write`, recording how long it took and logging a stall
+    pub async fn timed<T, E>(&self, write: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+        let started = Instant::now();
+        let since = started.duration_since(self.created).as_micros() as u64 + 1;
+        self.writing_since_us.store(since, Ordering::Relaxed);
+        let result = write.await;
+        let took = started.elapsed();
+        self.writing_since_us.store(0, Ordering::Relaxed);
+        self.last_write_us.store(took.as_micros() as u64, Ordering::Relaxed);
+        if took > self.stall_after {
+            self.stalls.fetch_add(1, Ordering::Relaxed);
+            warn!("Write to the tunnel took {:?}; the client or its link is not keeping up", took);
+        }
+        result
+    }
+
+    /// Records a body piece handed to the worker
+    pub fn piece_queued(&self) {
+        self.queued_pieces.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records `count` body pieces written, or given up on
+    pub fn pieces_done(&self, count: usize) {
+        self.queued_pieces.fetch_sub(count, Ordering::Relaxed);
+    }
+
+    /// Current figures, with `queued_requests` waiting in the worker's queue
+    pub fn stats(&self, queued_requests: usize) -> WriteStats {
+        let since = self.writing_since_us.load(Ordering::Relaxed);
+        let writing_for = (since > 0).then(|| self.created.elapsed().saturating_sub(Duration::from_micros(since - 1)));
+        WriteStats {
+            queued_frames: queued_requests + self.queued_pieces.load(Ordering::Relaxed),
+            last_write_ms: self.last_write_us.load(Ordering::Relaxed) / 1000,
+            stalled: writing_for.is_some_and(|writing_for| writing_for > self.stall_after),
+            stalls: self.stalls.load(Ordering::Relaxed),
+        }
+    }
+}
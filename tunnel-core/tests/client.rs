@@ -0,0 +1,368 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tunnel_core::client::{parse_host_port, parse_server_addr, send_upgrade_request, ServerConfig, UpgradeError};
+use tunnel_protocol::{Compression, HeaderLimits};
+
+#[test]
+fn https_url_defaults_to_port_443_with_tls() {
+    let config = parse_server_addr("https://tunnel.example.com", None, Vec::new()).unwrap();
+    assert_eq!(config.addr, "tunnel.example.com:443");
+    assert_eq!(config.hostname, "tunnel.example.com");
+    assert!(config.use_tls);
+}
+
+#[test]
+fn http_url_defaults_to_port_80_without_tls() {
+    let config = parse_server_addr("http://localhost/", None, Vec::new()).unwrap();
+    assert_eq!(config.addr, "localhost:80");
+    assert!(!config.use_tls);
+}
+
+#[test]
+fn bare_address_defaults_to_port_7000() {
+    let config = parse_server_addr("localhost", None, Vec::new()).unwrap();
+    assert_eq!(config.addr, "localhost:7000");
+    assert!(!config.use_tls);
+
+    let config = parse_server_addr("localhost:8080", None, Vec::new()).unwrap();
+    assert_eq!(config.addr, "localhost:8080");
+}
+
+#[test]
+fn ipv6_host_is_unbracketed() {
+    assert_eq!(parse_host_port("[::1]:9000", 80).unwrap(), ("::1".to_string(), 9000));
+    assert_eq!(parse_host_port("[::1]", 80).unwrap(), ("::1".to_string(), 80));
+}
+
+#[test]
+fn invalid_port_is_rejected() {
+    assert!(parse_host_port("localhost:http", 80).is_err());
+    assert!(parse_host_port("[::1:80", 80).is_err());
+}
+
+#[test]
+fn public_url_leaves_out_the_default_port() {
+    let url = |addr: &str| parse_server_addr(addr, None, Vec::new()).unwrap().public_url();
+    assert_eq!(url("https://tunnel.example.com"), "https://tunnel.example.com");
+    assert_eq!(url("https://tunnel.example.com:8443"), "https://tunnel.example.com:8443");
+    assert_eq!(url("http://localhost"), "http://localhost");
+    assert_eq!(url("127.0.0.1:7000"), "http://127.0.0.1:7000");
+}
+
+/// Settings for upgrade requests to `example.com`
+fn example_config() -> ServerConfig {
+    parse_server_addr("example.com", None, Vec::new()).unwrap()
+}
+
+/// Reads the upgrade request from `server`, then writes `response`
+async fn answer_upgrade(mut server: tokio::io::DuplexStream, response: &'static str) -> String {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 512];
+    while !request.ends_with(b"\r\n\r\n") {
+        let n = server.read(&mut buf).await.unwrap();
+        assert!(n > 0, "client closed before finishing the request");
+        request.extend_from_slice(&buf[..n]);
+    }
+    server.write_all(response.as_bytes()).await.unwrap();
+    String::from_utf8(request).unwrap()
+}
+
+#[tokio::test]
+async fn upgrade_request_carries_auth_and_labels() {
+    let (mut client, server) = tokio::io::duplex(4096);
+    let server = tokio::spawn(answer_upgrade(
+        server,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: tunnel\r\nConnection: Upgrade\r\n\r\n",
+    ));
+
+    let labels = vec![("env".to_string(), "dev".to_string())];
+    let config = parse_server_addr("example.com", Some("user:pass".to_string()), labels).unwrap();
+    send_upgrade_request(&mut client, &config).await.unwrap();
+
+    let request = server.await.unwrap();
+    assert!(request.starts_with("GET /tunnel HTTP/1.1\r\n"));
+    assert!(request.contains("Host: example.com\r\n"));
+    assert!(request.contains("Authorization: Basic dXNlcjpwYXNz\r\n"));
+    assert!(request.contains("x-tunnel-label: env=dev\r\n"));
+    assert!(request.contains("x-tunnel-header-limits: count=100; bytes=65536\r\n"));
+}
+
+#[tokio::test]
+async fn bytes_sent_right_after_the_upgrade_response_are_left_on_the_stream() {
+    let (mut client, server) = tokio::io::duplex(4096);
+    let server = tokio::spawn(answer_upgrade(
+        server,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: tunnel\r\nConnection: Upgrade\r\n\r\nfirst frame",
+    ));
+
+    send_upgrade_request(&mut client, &example_config()).await.unwrap();
+    server.await.unwrap();
+
+    let mut rest = [0u8; 11];
+    client.read_exact(&mut rest).await.unwrap();
+    assert_eq!(&rest, b"first frame");
+}
+
+#[tokio::test]
+async fn upgrade_request_uses_the_configured_path_and_secret() {
+    let (mut client, server) = tokio::io::duplex(4096);
+    let server = tokio::spawn(answer_upgrade(
+        server,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: tunnel\r\nConnection: Upgrade\r\n\r\n",
+    ));
+
+    let mut config = example_config();
+    config.path = "/t/4f9a1c".to_string();
+    config.upgrade_secret = Some("s3cret!".to_string());
+    send_upgrade_request(&mut client, &config).await.unwrap();
+
+    let request = server.await.unwrap();
+    assert!(request.starts_with("GET /t/4f9a1c HTTP/1.1\r\n"));
+    assert!(request.contains("x-tunnel-secret: s3cret!\r\n"));
+}
+
+#[tokio::test]
+async fn upgrade_request_carries_visitor_credentials() {
+    let (mut client, server) = tokio::io::duplex(4096);
+    let server = tokio::spawn(answer_upgrade(
+        server,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: tunnel\r\nConnection: Upgrade\r\n\r\n",
+    ));
+
+    let mut config = example_config();
+    config.visitor_auth = Some("guest:hunter2".to_string());
+    send_upgrade_request(&mut client, &config).await.unwrap();
+
+    let request = server.await.unwrap();
+    assert!(request.contains("x-tunnel-visitor-auth: Z3Vlc3Q6aHVudGVyMg==\r\n"));
+    assert!(!request.contains("Authorization:"));
+}
+
+#[tokio::test]
+async fn upgrade_request_asks_for_https_only_when_set() {
+    let (mut client, server) = tokio::io::duplex(4096);
+    let server = tokio::spawn(answer_upgrade(
+        server,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: tunnel\r\nConnection: Upgrade\r\n\r\n",
+    ));
+
+    let mut config = example_config();
+    config.https_only = true;
+    send_upgrade_request(&mut client, &config).await.unwrap();
+
+    let request = server.await.unwrap();
+    assert!(request.contains("x-tunnel-https-only: true\r\n"));
+}
+
+#[tokio::test]
+async fn upgrade_request_lists_the_cors_origins() {
+    let (mut client, server) = tokio::io::duplex(4096);
+    let server = tokio::spawn(answer_upgrade(
+        server,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: tunnel\r\nConnection: Upgrade\r\n\r\n",
+    ));
+
+    let mut config = example_config();
+    config.cors_origins = vec!["https://app.example.com".to_string(), "http://localhost:5173".to_string()];
+    send_upgrade_request(&mut client, &config).await.unwrap();
+
+    let request = server.await.unwrap();
+    assert!(request.contains("x-tunnel-cors: https://app.example.com,http://localhost:5173\r\n"));
+}
+
+#[tokio::test]
+async fn rejected_upgrade_is_an_error() {
+    let (mut client, server) = tokio::io::duplex(4096);
+    tokio::spawn(answer_upgrade(server, "HTTP/1.1 401 Unauthorized\r\n\r\n"));
+
+    let err = send_upgrade_request(&mut client, &example_config())
+        .await
+        .unwrap_err();
+    assert!(matches!(err, UpgradeError::Unauthorized { message: None }), "{}", err);
+    assert_eq!(err.to_string(), "Authentication failed: Invalid credentials");
+    assert!(!err.is_retryable());
+}
+
+/// Answers the upgrade request from `server` with `head` and `body`, then keeps the connection open for `linger_ms`
+async fn refuse_upgrade(mut server: tokio::io::DuplexStream, head: &'static str, body: Vec<u8>, linger_ms: u64) {
+    let mut buf = [0u8; 512];
+    let mut request = Vec::new();
+    while !request.ends_with(b"\r\n\r\n") {
+        let n = server.read(&mut buf).await.unwrap();
+        request.extend_from_slice(&buf[..n]);
+    }
+    server.write_all(head.as_bytes()).await.unwrap();
+    server.write_all(&body).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(linger_ms)).await;
+}
+
+#[tokio::test]
+async fn refusals_carry_the_message_in_their_body() {
+    // Framed by Content-Length, on a connection the server keeps open
+    let (mut client, server) = tokio::io::duplex(4096);
+    let body = br#"{"error":"token expired"}"#.to_vec();
+    tokio::spawn(refuse_upgrade(server, "HTTP/1.1 401 Unauthorized\r\nContent-Type: application/json\r\nContent-Length: 25\r\n\r\n", body, 5000));
+    let started = std::time::Instant::now();
+    let err = send_upgrade_request(&mut client, &example_config()).await.unwrap_err();
+    assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    assert!(matches!(&err, UpgradeError::Unauthorized { message: Some(m) } if m == "token expired"), "{:?}", err);
+    assert_eq!(err.to_string(), "Authentication failed: token expired");
+
+    // Chunked and gzip-compressed by a proxy
+    let gzip = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\xab\x56\x4a\x2d\x2a\xca\x2f\x52\xb2\x52\x2a\xc9\xcf\x4e\xcd\x53\x48\xad\x28\xc8\x2c\x4a\x4d\x51\xaa\x05\x00\xe0\x3a\x20\x2c\x19\x00\x00\x00";
+    let mut chunked = Vec::new();
+    for chunk in gzip.chunks(16) {
+        chunked.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+        chunked.extend_from_slice(chunk);
+        chunked.extend_from_slice(b"\r\n");
+    }
+    chunked.extend_from_slice(b"0\r\n\r\n");
+    let (mut client, server) = tokio::io::duplex(4096);
+    let head = "HTTP/1.1 403 Forbidden\r\nTransfer-Encoding: chunked\r\nContent-Encoding: gzip\r\n\r\n";
+    tokio::spawn(refuse_upgrade(server, head, chunked, 5000));
+    let err = send_upgrade_request(&mut client, &example_config()).await.unwrap_err();
+    assert_eq!(err.to_string(), "Upgrade failed: HTTP/1.1 403 Forbidden (token expired)");
+
+    // Raw DEFLATE, where a zlib wrapper was expected
+    let raw = b"\xab\x56\x4a\x2d\x2a\xca\x2f\x52\xb2\x52\x2a\xc9\xcf\x4e\xcd\x53\x48\xad\x28\xc8\x2c\x4a\x4d\x51\xaa\x05\x00".to_vec();
+    let (mut client, server) = tokio::io::duplex(4096);
+    tokio::spawn(refuse_upgrade(server, "HTTP/1.1 403 Forbidden\r\nContent-Encoding: deflate\r\nContent-Length: 27\r\n\r\n", raw, 5000));
+    let err = send_upgrade_request(&mut client, &example_config()).await.unwrap_err();
+    assert_eq!(err.to_string(), "Upgrade failed: HTTP/1.1 403 Forbidden (token expired)");
+
+    // Unframed HTML, delimited by the server closing the connection
+    let (mut client, server) = tokio::io::duplex(4096);
+    let page = b"<html><head><title>502 Bad Gateway</title></head>\n<body><h1>502 Bad Gateway</h1><hr>nginx</body></html>".to_vec();
+    tokio::spawn(refuse_upgrade(server, "HTTP/1.1 502 Bad Gateway\r\nContent-Type: text/html\r\n\r\n", page, 0));
+    let err = send_upgrade_request(&mut client, &example_config()).await.unwrap_err();
+    assert!(
+        matches!(&err, UpgradeError::Rejected { status: Some(502), message: Some(m), .. } if m == "502 Bad Gateway 502 Bad Gateway nginx"),
+        "{:?}",
+        err
+    );
+    assert!(err.is_retryable());
+}
+
+#[tokio::test]
+async fn upgrade_rejections_are_classified_by_status() {
+    for (response, status, retryable) in [
+        ("HTTP/1.1 404 Not Found\r\n\r\n", 404, false),
+        ("HTTP/1.1 429 Too Many Requests\r\n\r\n", 429, true),
+        ("HTTP/1.1 503 Service Unavailable\r\n\r\n", 503, true),
+    ] {
+        let (mut client, server) = tokio::io::duplex(4096);
+        tokio::spawn(answer_upgrade(server, response));
+
+        let err = send_upgrade_request(&mut client, &example_config())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, UpgradeError::Rejected { status: Some(s), .. } if s == status), "{}", err);
+        assert_eq!(err.is_retryable(), retryable, "{}", err);
+    }
+}
+
+#[tokio::test]
+async fn closed_connection_during_upgrade_is_retryable() {
+    let (mut client, mut server) = tokio::io::duplex(4096);
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        let _ = server.read(&mut buf).await;
+    });
+
+    let err = send_upgrade_request(&mut client, &example_config())
+        .await
+        .unwrap_err();
+    assert!(err.is_retryable(), "{}", err);
+}
+
+#[tokio::test]
+async fn stats_interval_is_read_from_the_upgrade_response() {
+    for (response, expected) in [
+        ("HTTP/1.1 101 Switching Protocols\r\nUpgrade: tunnel\r\nConnection: Upgrade\r\nX-Tunnel-Stats: 15\r\n\r\n", Some(15)),
+        ("HTTP/1.1 101 Switching Protocols\r\nUpgrade: tunnel\r\nConnection: Upgrade\r\n\r\n", None),
+    ] {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(answer_upgrade(server, response));
+
+        let handshake = send_upgrade_request(&mut client, &example_config()).await.unwrap();
+        assert_eq!(handshake.stats_interval, expected.map(std::time::Duration::from_secs));
+        assert_eq!(handshake.header_limits, None);
+        server.await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn header_limits_are_read_from_the_upgrade_response() {
+    let (mut client, server) = tokio::io::duplex(4096);
+    let server = tokio::spawn(answer_upgrade(
+        server,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: tunnel\r\nConnection: Upgrade\r\nX-Tunnel-Header-Limits: count=10; bytes=2048\r\n\r\n",
+    ));
+
+    let handshake = send_upgrade_request(&mut client, &example_config()).await.unwrap();
+    assert_eq!(handshake.header_limits, Some(HeaderLimits { max_count: 10, max_bytes: 2048 }));
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn heartbeats_are_offered_when_set_and_agreed_when_echoed() {
+    const PLAIN: &str = "HTTP/1.1 101 Switching Protocols\r\nUpgrade: tunnel\r\nConnection: Upgrade\r\n\r\n";
+    const ECHOED: &str = "HTTP/1.1 101 Switching Protocols\r\nUpgrade: tunnel\r\nConnection: Upgrade\r\nX-Tunnel-Heartbeat: true\r\n\r\n";
+    for (offered, response, agreed) in [(false, ECHOED, false), (true, PLAIN, false), (true, ECHOED, true)] {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(answer_upgrade(server, response));
+
+        let mut config = example_config();
+        config.heartbeat = offered;
+        let handshake = send_upgrade_request(&mut client, &config).await.unwrap();
+        let request = server.await.unwrap().to_lowercase();
+        assert_eq!(request.contains("x-tunnel-heartbeat: true"), offered);
+        assert_eq!(handshake.heartbeat, agreed);
+    }
+}
+
+#[tokio::test]
+async fn upgrade_request_carries_the_user_agent_when_set() {
+    for user_agent in [Some("speedforce-client/1.2.3 (linux; x86_64)"), None] {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(answer_upgrade(
+            server,
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: tunnel\r\nConnection: Upgrade\r\n\r\n",
+        ));
+
+        let mut config = example_config();
+        config.user_agent = user_agent.map(str::to_string);
+        send_upgrade_request(&mut client, &config).await.unwrap();
+        let request = server.await.unwrap();
+        match user_agent {
+            Some(user_agent) => assert!(request.contains(&format!("\r\nUser-Agent: {}\r\n", user_agent)), "{}", request),
+            None => assert!(!request.to_lowercase().contains("user-agent"), "{}", request),
+        }
+    }
+}
+
+#[tokio::test]
+async fn compression_is_offered_when_set_and_agreed_when_echoed() {
+    const PLAIN: &str = "HTTP/1.1 101 Switching Protocols\r\nUpgrade: tunnel\r\nConnection: Upgrade\r\n\r\n";
+    const ZSTD: &str = "HTTP/1.1 101 Switching Protocols\r\nUpgrade: tunnel\r\nConnection: Upgrade\r\nX-Tunnel-Compression: zstd\r\n\r\n";
+    const GZIP: &str = "HTTP/1.1 101 Switching Protocols\r\nUpgrade: tunnel\r\nConnection: Upgrade\r\nX-Tunnel-Compression: gzip\r\n\r\n";
+    for (offered, response, agreed) in [
+        (None, ZSTD, None),
+        (Some(Compression::Zstd), PLAIN, None),
+        (Some(Compression::Zstd), GZIP, None),
+        (Some(Compression::Zstd), ZSTD, Some(Compression::Zstd)),
+        (Some(Compression::Gzip), GZIP, Some(Compression::Gzip)),
+    ] {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(answer_upgrade(server, response));
+
+        let mut config = example_config();
+        config.transport.compression = offered;
+        let handshake = send_upgrade_request(&mut client, &config).await.unwrap();
+        let request = server.await.unwrap().to_lowercase();
+        match offered {
+            Some(offered) => assert!(request.contains(&format!("x-tunnel-compression: {}\r\n", offered)), "{}", request),
+            None => assert!(!request.contains("x-tunnel-compression"), "{}", request),
+        }
+        assert_eq!(handshake.compression, agreed);
+    }
+}
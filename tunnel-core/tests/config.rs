@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tunnel_core::config::{check_report, parse_tunnel_path, parse_upgrade_secret, ConfigSource, Mode, Origin};
+
+const KEYS: [&str; 3] = ["LOCAL_PORT", "LOCAL_HOST", "TUNNEL_AUTH"];
+
+fn args(list: &[&str]) -> Vec<String> {
+    list.iter().map(|arg| arg.to_string()).collect()
+}
+
+fn env(pairs: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+    let map: HashMap<String, String> = pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    move |key| map.get(key).cloned()
+}
+
+fn config_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("tunnel-core-{}-{}.conf", name, std::process::id()));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn command_line_beats_file_beats_environment() {
+    let path = config_file("precedence", "LOCAL_PORT=4000\nLOCAL_HOST=file-host\n");
+    let (source, mode) = ConfigSource::load(
+        KEYS.to_vec(),
+        "TEST_CONFIG",
+        args(&["--local-port", "5000"]),
+        env(&[
+            ("TEST_CONFIG", path.to_str().unwrap()),
+            ("LOCAL_PORT", "3000"),
+            ("LOCAL_HOST", "env-host"),
+            ("TUNNEL_AUTH", "user:pass"),
+        ]),
+    )
+    .unwrap();
+
+    assert_eq!(mode, Mode::Run);
+    assert_eq!(source.get("LOCAL_PORT").as_deref(), Some("5000"));
+    assert_eq!(source.get("LOCAL_HOST").as_deref(), Some("file-host"));
+    assert_eq!(source.get("TUNNEL_AUTH").as_deref(), Some("user:pass"));
+
+    let explicit = source.explicit_settings();
+    assert_eq!(explicit[0], ("LOCAL_PORT", "5000".to_string(), Origin::Cli));
+    assert_eq!(explicit[1], ("LOCAL_HOST", "file-host".to_string(), Origin::File));
+    assert_eq!(explicit[2], ("TUNNEL_AUTH", "<redacted>".to_string(), Origin::Env));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn flags_accept_inline_values_and_modes() {
+    let (source, mode) = ConfigSource::load(
+        KEYS.to_vec(),
+        "TEST_CONFIG",
+        args(&["--local-host=example", "--check-config"]),
+        env(&[]),
+    )
+    .unwrap();
+    assert_eq!(mode, Mode::CheckConfig);
+    assert_eq!(source.get("LOCAL_HOST").as_deref(), Some("example"));
+
+    let (_, mode) = ConfigSource::load(KEYS.to_vec(), "TEST_CONFIG", args(&["-h"]), env(&[])).unwrap();
+    assert_eq!(mode, Mode::Help);
+
+    let (_, mode) = ConfigSource::load(KEYS.to_vec(), "TEST_CONFIG", args(&["status"]), env(&[])).unwrap();
+    assert_eq!(mode, Mode::Status { json: false });
+    let (source, mode) =
+        ConfigSource::load(KEYS.to_vec(), "TEST_CONFIG", args(&["status", "--local-port", "4000", "--json"]), env(&[])).unwrap();
+    assert_eq!(mode, Mode::Status { json: true });
+    assert_eq!(source.get("LOCAL_PORT").as_deref(), Some("4000"));
+}
+
+#[test]
+fn config_flag_overrides_config_variable() {
+    let path = config_file("flag", "LOCAL_PORT=4000\n");
+    let (source, _) = ConfigSource::load(
+        KEYS.to_vec(),
+        "TEST_CONFIG",
+        args(&["--config", path.to_str().unwrap()]),
+        env(&[("TEST_CONFIG", "/nonexistent")]),
+    )
+    .unwrap();
+    assert_eq!(source.file_path(), Some(path.as_path()));
+    assert_eq!(source.get("LOCAL_PORT").as_deref(), Some("4000"));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn invalid_command_lines_are_rejected() {
+    for (list, expected) in [
+        (&["--local-prot", "1"][..], "Unknown option --local-prot"),
+        (&["--local-port"][..], "Missing value for --local-port"),
+        (&["--bogus"][..], "Unknown option --bogus"),
+        (&["3000"][..], "Unexpected argument '3000'"),
+        (&["--json"][..], "--json only applies to the status command"),
+        (&["--json", "status"][..], "Unexpected argument 'status'"),
+    ] {
+        let err = ConfigSource::load(KEYS.to_vec(), "TEST_CONFIG", args(list), env(&[])).err().unwrap();
+        assert!(err.contains(expected), "{}", err);
+    }
+}
+
+#[test]
+fn unknown_file_setting_is_rejected() {
+    let path = config_file("unknown", "LOCAL_PROT=4000\n");
+    let err = ConfigSource::load(
+        KEYS.to_vec(),
+        "TEST_CONFIG",
+        args(&["--config", path.to_str().unwrap()]),
+        env(&[]),
+    )
+    .err()
+    .unwrap();
+    assert!(err.contains("unknown setting LOCAL_PROT"), "{}", err);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn reload_picks_up_file_changes_only() {
+    let path = config_file("reload", "LOCAL_PORT=4000\n");
+    let (source, _) = ConfigSource::load(
+        KEYS.to_vec(),
+        "TEST_CONFIG",
+        args(&["--config", path.to_str().unwrap(), "--local-host", "cli-host"]),
+        env(&[]),
+    )
+    .unwrap();
+
+    std::fs::write(&path, "LOCAL_PORT=4001\nLOCAL_HOST=file-host\n").unwrap();
+    let reloaded = source.reload_file().unwrap();
+    assert_eq!(reloaded.get("LOCAL_PORT").as_deref(), Some("4001"));
+    assert_eq!(reloaded.get("LOCAL_HOST").as_deref(), Some("cli-host"));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn check_report_lists_origins_and_effective_settings() {
+    let (source, _) = ConfigSource::load(
+        KEYS.to_vec(),
+        "TEST_CONFIG",
+        args(&["--local-port", "5000"]),
+        env(&[("TUNNEL_AUTH", "user:pass")]),
+    )
+    .unwrap();
+
+    let report = check_report(&source, &serde_json::json!({ "local_port": 5000 }));
+    assert!(report.contains("LOCAL_PORT=5000  (command line)"), "{}", report);
+    assert!(report.contains("TUNNEL_AUTH=<redacted>  (environment)"), "{}", report);
+    assert!(!report.contains("user:pass"), "{}", report);
+    assert!(report.contains("\"local_port\": 5000"), "{}", report);
+}
+
+#[test]
+fn tunnel_paths_and_upgrade_secrets_are_validated() {
+    assert_eq!(parse_tunnel_path("/tunnel").unwrap(), "/tunnel");
+    assert_eq!(parse_tunnel_path(" /t/4f9a-1c_2.x~ ").unwrap(), "/t/4f9a-1c_2.x~");
+    for invalid in ["", "/", "tunnel", "/tunnel/", "/a//b", "/a/../b", "/:id", "/*rest", "/a?b", "/a b"] {
+        assert!(parse_tunnel_path(invalid).is_err(), "{:?}", invalid);
+    }
+
+    assert!(parse_upgrade_secret("Zx8-q!v3").is_ok());
+    assert!(parse_upgrade_secret("").is_err());
+    assert!(parse_upgrade_secret("two words").is_err());
+    assert!(parse_upgrade_secret("line\r\nbreak").is_err());
+}
@@ -0,0 +1,43 @@
+use std::time::Duration;
+use tunnel_core::dedup::LogDedup;
+
+#[test]
+fn repeats_are_counted_until_the_window_passes() {
+    let dedup = LogDedup::new(Duration::from_millis(100));
+
+    assert_eq!(dedup.admit("Tunnel error: broken pipe"), Some(0));
+    for _ in 0..5 {
+        assert_eq!(dedup.admit("Tunnel error: broken pipe"), None);
+    }
+    // Other messages are tracked on their own
+    assert_eq!(dedup.admit("Tunnel request timeout"), Some(0));
+
+    std::thread::sleep(Duration::from_millis(150));
+    assert_eq!(dedup.admit("Tunnel error: broken pipe"), Some(5));
+    assert_eq!(dedup.admit("Tunnel error: broken pipe"), None);
+}
+
+#[test]
+fn counts_of_messages_that_stopped_are_reported_once() {
+    let dedup = LogDedup::new(Duration::from_millis(100));
+    dedup.admit("a");
+    dedup.admit("a");
+    dedup.admit("a");
+    dedup.admit("b");
+    assert!(dedup.take_expired().is_empty());
+
+    std::thread::sleep(Duration::from_millis(150));
+    assert_eq!(dedup.take_expired(), vec![("a".to_string(), 2)]);
+    assert!(dedup.take_expired().is_empty());
+
+    // Forgotten, so the next occurrence is logged right away
+    assert_eq!(dedup.admit("a"), Some(0));
+}
+
+#[test]
+fn a_zero_window_logs_everything() {
+    let dedup = LogDedup::new(Duration::from_secs(10));
+    dedup.set_window(Duration::ZERO);
+    assert_eq!(dedup.admit("a"), Some(0));
+    assert_eq!(dedup.admit("a"), Some(0));
+}
@@ -0,0 +1,117 @@
+use bytes::Bytes;
+use std::collections::HashSet;
+use tunnel_core::framing::{recv_message, send_message, write_body_pieces, BodyPiece, MessageError};
+use tokio::io::AsyncReadExt;
+use tunnel_protocol::{decode_body_frame, read_frame, write_frame, BodyFrame, Compression, FrameWriter, TunnelRequest, STREAM_PREFIX_LEN};
+
+#[tokio::test]
+async fn message_round_trips_through_a_frame() {
+    let (writer, mut reader) = tokio::io::duplex(4096);
+    let mut writer = FrameWriter::new(writer, 0);
+
+    let request = TunnelRequest {
+        method: "POST".to_string(),
+        path: "/api?x=1".to_string(),
+        headers: vec![("content-type".to_string(), "text/plain".into())],
+        body: tunnel_protocol::encode_body(b"hello"),
+        deadline_ms: Some(1500),
+        ..TunnelRequest::default()
+    };
+    send_message(&mut writer, &request).await.unwrap();
+
+    let received: TunnelRequest = recv_message(&mut reader).await.unwrap();
+    assert_eq!(received.method, request.method);
+    assert_eq!(received.path, request.path);
+    assert_eq!(received.headers, request.headers);
+    assert_eq!(received.body, request.body);
+    assert_eq!(received.deadline_ms, request.deadline_ms);
+}
+
+#[tokio::test]
+async fn invalid_json_is_reported_as_deserialize_error() {
+    let (mut writer, mut reader) = tokio::io::duplex(4096);
+    write_frame(&mut writer, b"not json").await.unwrap();
+
+    let err = recv_message::<_, TunnelRequest>(&mut reader).await.unwrap_err();
+    assert!(matches!(err, MessageError::Decode(_)), "{}", err);
+    assert!(err.to_string().starts_with("Failed to deserialize message"), "{}", err);
+}
+
+#[tokio::test]
+async fn closed_stream_is_reported_as_read_error() {
+    let (writer, mut reader) = tokio::io::duplex(4096);
+    drop(writer);
+
+    let err = recv_message::<_, TunnelRequest>(&mut reader).await.unwrap_err();
+    assert!(matches!(err, MessageError::Read(_)), "{}", err);
+    assert!(err.to_string().starts_with("Failed to read frame"), "{}", err);
+}
+
+#[tokio::test]
+async fn coalesced_messages_arrive_after_flush() {
+    let (writer, mut reader) = tokio::io::duplex(4096);
+    let mut writer = FrameWriter::new(writer, 1024);
+
+    for path in ["/a", "/b"] {
+        let request = TunnelRequest {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            ..TunnelRequest::default()
+        };
+        send_message(&mut writer, &request).await.unwrap();
+    }
+    writer.flush().await.unwrap();
+
+    let first: TunnelRequest = recv_message(&mut reader).await.unwrap();
+    let second: TunnelRequest = recv_message(&mut reader).await.unwrap();
+    assert_eq!(first.path, "/a");
+    assert_eq!(second.path, "/b");
+}
+
+#[tokio::test]
+async fn queued_body_pieces_are_written_up_to_the_window() {
+    let (writer, mut reader) = tokio::io::duplex(4096);
+    let mut writer = FrameWriter::new(writer, 0);
+    let (pieces_tx, mut pieces_rx) = tokio::sync::mpsc::channel(8);
+    for i in 1..5u8 {
+        pieces_tx.send((7, BodyPiece::Data(Bytes::from(vec![i; 10])))).await.unwrap();
+    }
+
+    let first = (7, BodyPiece::Data(Bytes::from_static(b"first")));
+    write_body_pieces(&mut writer, first, &mut pieces_rx, 3, &mut HashSet::new()).await.unwrap();
+    let mut written = Vec::new();
+    for _ in 0..3 {
+        let frame = read_frame(&mut reader).await.unwrap();
+        let BodyFrame::Chunk { id: 7, data } = decode_body_frame(&frame).unwrap() else { panic!("not a chunk of body 7") };
+        written.push(data.to_vec());
+    }
+    assert_eq!(written, [b"first".to_vec(), vec![1; 10], vec![2; 10]]);
+
+    // The rest wait for the next call
+    assert_eq!(pieces_rx.try_recv().unwrap(), (7, BodyPiece::Data(Bytes::from(vec![3; 10]))));
+}
+
+#[tokio::test]
+async fn bodies_listed_as_uncompressed_skip_compression_until_they_end() {
+    let (writer, mut reader) = tokio::io::duplex(1 << 20);
+    let mut writer = FrameWriter::new(writer, 0).with_compression(Some(Compression::Zstd));
+    let (pieces_tx, mut pieces_rx) = tokio::sync::mpsc::channel(8);
+    let text = Bytes::from(b"compressible text ".repeat(1_000));
+    pieces_tx.send((8, BodyPiece::Data(text.clone()))).await.unwrap();
+    pieces_tx.send((7, BodyPiece::End { complete: true, sha256: None, trailers: Vec::new() })).await.unwrap();
+
+    let mut uncompressed = HashSet::from([7]);
+    write_body_pieces(&mut writer, (7, BodyPiece::Data(text.clone())), &mut pieces_rx, 8, &mut uncompressed).await.unwrap();
+    assert!(uncompressed.is_empty());
+
+    // Body 7 went out as it is, body 8 compressed
+    let mut sizes = Vec::new();
+    for _ in 0..2 {
+        let len = reader.read_u32().await.unwrap() as usize;
+        let mut frame = vec![0; len];
+        reader.read_exact(&mut frame).await.unwrap();
+        sizes.push(len);
+    }
+    assert_eq!(sizes[0], STREAM_PREFIX_LEN + text.len());
+    assert!(sizes[1] < text.len() / 4, "{} bytes", sizes[1]);
+}
@@ -0,0 +1,67 @@
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::http::Extensions;
+use hyper::Version;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tunnel_core::header_case::{extensions, has_uppercase, names, record, HeaderNames};
+
+fn headers(names: &[&str]) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for name in names {
+        map.append(name.parse::<hyper::header::HeaderName>().unwrap(), HeaderValue::from_static("1"));
+    }
+    map
+}
+
+#[tokio::test]
+async fn spellings_survive_a_trip_through_extensions() {
+    let sent = ["X-Custom-Header", "content-type", "Set-Cookie", "set-COOKIE"];
+    let extensions = extensions(sent.iter().copied()).await;
+
+    assert_eq!(names(Version::HTTP_11, &headers(&sent), &extensions).await, sent);
+}
+
+#[tokio::test]
+async fn names_without_a_spelling_come_lowercase() {
+    let headers = headers(&["X-Custom-Header", "Accept"]);
+
+    assert_eq!(names(Version::HTTP_11, &headers, &Extensions::new()).await, ["x-custom-header", "accept"]);
+    let only_one = extensions(["X-Custom-Header"].into_iter()).await;
+    assert_eq!(names(Version::HTTP_11, &headers, &only_one).await, ["X-Custom-Header", "accept"]);
+}
+
+#[tokio::test]
+async fn lowercase_names_need_no_extensions() {
+    assert!(!has_uppercase(["content-type", "x-custom-header"].into_iter()));
+    assert!(has_uppercase(["content-type", "X-Custom-Header"].into_iter()));
+    assert!(extensions(["content-type"].into_iter()).await.is_empty());
+}
+
+#[tokio::test]
+async fn names_come_from_header_names_or_lowercase_past_http1() {
+    let sent = ["X-Custom-Header"];
+    let spelled = extensions(sent.iter().copied()).await;
+    assert_eq!(names(Version::HTTP_2, &headers(&sent), &spelled).await, ["x-custom-header"]);
+
+    let mut carried = Extensions::new();
+    carried.insert(HeaderNames(vec!["X-CUSTOM-header".to_string()]));
+    assert_eq!(names(Version::HTTP_2, &headers(&sent), &carried).await, ["X-CUSTOM-header"]);
+}
+
+#[tokio::test]
+async fn recorded_heads_give_names_as_read_in_order() {
+    let (mut ours, theirs) = tokio::io::duplex(4096);
+    let (mut recorded, heads) = record(theirs);
+    ours.write_all(b"POST /a HTTP/1.1\r\nX-One: 1\r\nContent-Length: 4\r\n\r\nbody").await.unwrap();
+    ours.write_all(b"GET /b HTTP/1.1\r\nx-TWO: 2\r\nX-Two: 3\n\r\n").await.unwrap();
+    ours.write_all(b"HTTP/1.1 204 No Content\r\nSERVER: here\r\n\r\n").await.unwrap();
+    drop(ours);
+    recorded.read_to_end(&mut Vec::new()).await.unwrap();
+
+    let first = headers(&["x-one", "content-length"]);
+    assert_eq!(heads.request("POST", "/a", &first).unwrap(), ["X-One", "Content-Length"]);
+    assert_eq!(heads.request("GET", "/b", &headers(&["x-two", "x-two"])).unwrap(), ["x-TWO", "X-Two"]);
+    assert_eq!(heads.response(204, &headers(&["server"])).unwrap(), ["SERVER"]);
+    // Heads found are forgotten, as are heads with other names
+    assert!(heads.request("POST", "/a", &first).is_none());
+    assert!(heads.response(204, &headers(&["date"])).is_none());
+}
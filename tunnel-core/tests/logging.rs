@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use tracing::info;
+use tunnel_core::logging::{init, LogOptions, LogRotation, SizeRotatingFile, ACCESS_TARGET};
+
+fn options(pairs: &[(&str, &str)]) -> Result<LogOptions, String> {
+    let map: HashMap<&str, &str> = pairs.iter().copied().collect();
+    LogOptions::from_source(|key| map.get(key).map(|value| value.to_string()))
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tunnel-core-logging-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn settings_are_parsed() {
+    let defaults = options(&[]).unwrap();
+    assert_eq!(defaults.level, "info");
+    assert_eq!(defaults.rotation, LogRotation::Never);
+    assert!(defaults.file.is_none());
+    assert_eq!(defaults.dedup_window, std::time::Duration::from_secs(10));
+
+    let parsed = options(&[
+        ("RUST_LOG", "debug"),
+        ("LOG_FILE", "/var/log/server.log"),
+        ("LOG_ROTATION", "size"),
+        ("LOG_MAX_BYTES", "1024"),
+        ("LOG_MAX_FILES", "3"),
+        ("LOG_DEDUP_SECS", "0"),
+    ])
+    .unwrap();
+    assert_eq!(parsed.level, "debug");
+    assert_eq!(parsed.file, Some(PathBuf::from("/var/log/server.log")));
+    assert_eq!(parsed.rotation, LogRotation::Size(1024));
+    assert_eq!(parsed.max_files, 3);
+    assert!(parsed.dedup_window.is_zero());
+
+    // LOG_LEVEL wins over RUST_LOG
+    let parsed = options(&[("LOG_LEVEL", "warn"), ("RUST_LOG", "debug")]).unwrap();
+    assert_eq!(parsed.level, "warn");
+}
+
+#[test]
+fn invalid_settings_are_rejected() {
+    for (pairs, expected) in [
+        (&[("LOG_LEVEL", "info,=[")][..], "Invalid LOG_LEVEL"),
+        (&[("LOG_ROTATION", "weekly")][..], "Invalid LOG_ROTATION"),
+        (&[("LOG_MAX_BYTES", "0")][..], "Invalid LOG_MAX_BYTES"),
+        (&[("LOG_DEDUP_SECS", "soon")][..], "Invalid LOG_DEDUP_SECS"),
+    ] {
+        let err = options(pairs).unwrap_err();
+        assert!(err.contains(expected), "{}", err);
+    }
+}
+
+#[test]
+fn size_rotation_keeps_the_newest_files() {
+    let dir = temp_dir("rotation");
+    let path = dir.join("client.log");
+    let mut file = SizeRotatingFile::open(&path, 10, 2).unwrap();
+
+    for line in ["first-----\n", "second----\n", "third-----\n", "fourth----\n"] {
+        file.write_all(line.as_bytes()).unwrap();
+    }
+    file.flush().unwrap();
+
+    let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+    assert_eq!(read("client.log"), "fourth----\n");
+    assert_eq!(read("client.log.1"), "third-----\n");
+    assert_eq!(read("client.log.2"), "second----\n");
+    assert!(!dir.join("client.log.3").exists());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn access_log_is_separate_and_ignores_the_level() {
+    let dir = temp_dir("init");
+    let options = LogOptions {
+        file: Some(dir.join("server.log")),
+        access_file: Some(dir.join("access.log")),
+        ..LogOptions::default()
+    };
+    let (handle, guard) = init(&options).unwrap();
+
+    info!("diagnostic at info");
+    info!(target: ACCESS_TARGET, "GET /first 200 1ms");
+
+    handle.set_level("warn").unwrap();
+    assert_eq!(handle.level(), "warn");
+    info!("diagnostic after raising the level");
+    info!(target: ACCESS_TARGET, "GET /second 200 1ms");
+
+    handle.reset().unwrap();
+    assert_eq!(handle.level(), "info");
+    assert!(handle.set_level("info,=[").is_err());
+
+    // Flushes the background writers
+    drop(guard);
+
+    let diagnostics = std::fs::read_to_string(dir.join("server.log")).unwrap();
+    assert!(diagnostics.contains("diagnostic at info"), "{}", diagnostics);
+    assert!(!diagnostics.contains("after raising"), "{}", diagnostics);
+    assert!(!diagnostics.contains("GET /"), "{}", diagnostics);
+
+    let access = std::fs::read_to_string(dir.join("access.log")).unwrap();
+    assert!(access.contains("GET /first 200"), "{}", access);
+    assert!(access.contains("GET /second 200"), "{}", access);
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,78 @@
+use tunnel_core::redact::{Redactor, REDACTED};
+
+const RULES: &str = "
+# Credentials
+header  X-Api-Key
+json    password
+json    customer.card.number
+regex   (?i)token=[^&\\s]+
+";
+
+fn body(redactor: &Redactor, json: serde_json::Value) -> serde_json::Value {
+    serde_json::from_slice(&redactor.body(json.to_string().as_bytes())).unwrap()
+}
+
+#[test]
+fn rules_are_parsed() {
+    let redactor = Redactor::parse(RULES).unwrap();
+    assert_eq!(redactor.len(), 4);
+    assert!(Redactor::default().is_empty());
+
+    let e = Redactor::parse("header").unwrap_err();
+    assert_eq!(e, "line 1: expected '<header|json|regex> <value>'");
+    let e = Redactor::parse("\njson customer..card").unwrap_err();
+    assert_eq!(e, "line 2: invalid JSON field path 'customer..card'");
+    let e = Redactor::parse("regex (unclosed").unwrap_err();
+    assert!(e.starts_with("line 1: invalid regex: "), "{}", e);
+    let e = Redactor::parse("query token").unwrap_err();
+    assert_eq!(e, "line 1: unknown rule 'query' (expected header, json or regex)");
+}
+
+#[test]
+fn credential_headers_are_always_redacted() {
+    let redactor = Redactor::default();
+    let headers = [
+        ("Authorization".to_string(), b"Bearer abc".to_vec()),
+        ("cookie".to_string(), b"session=1".to_vec()),
+        ("x-api-key".to_string(), b"k-123".to_vec()),
+    ];
+    let redacted = redactor.headers(&headers);
+    assert_eq!(redacted[0].1, REDACTED);
+    assert_eq!(redacted[1].1, REDACTED);
+    assert_eq!(redacted[2].1, "k-123");
+
+    let redactor = Redactor::parse(RULES).unwrap();
+    assert_eq!(redactor.header_value("x-api-key", b"k-123"), REDACTED);
+    assert_eq!(redactor.header_value("referer", b"/cb?token=abc&x=1"), "/cb?[redacted]&x=1");
+}
+
+#[test]
+fn regexes_apply_to_text_and_plain_bodies() {
+    let redactor = Redactor::parse(RULES).unwrap();
+    assert_eq!(redactor.text("/callback?Token=s3cret&state=ok"), "/callback?[redacted]&state=ok");
+    assert_eq!(redactor.text("/plain"), "/plain");
+    assert_eq!(&*redactor.body(b"token=abc"), b"[redacted]");
+
+    // Binary bodies pass untouched
+    assert_eq!(&*redactor.body(&[0xff, 0xfe]), &[0xff, 0xfe]);
+}
+
+#[test]
+fn json_fields_are_redacted_by_name_or_path() {
+    let redactor = Redactor::parse(RULES).unwrap();
+
+    // A bare name matches at any depth, in arrays too
+    let redacted = body(&redactor, serde_json::json!({"password": "a", "users": [{"name": "x", "password": "b"}]}));
+    assert_eq!(redacted, serde_json::json!({"password": REDACTED, "users": [{"name": "x", "password": REDACTED}]}));
+
+    // A dotted path only matches from the root
+    let redacted = body(&redactor, serde_json::json!({
+        "customer": {"card": {"number": "4111", "expiry": "12/30"}},
+        "refund": {"card": {"number": "4222"}},
+    }));
+    assert_eq!(redacted["customer"]["card"], serde_json::json!({"number": REDACTED, "expiry": "12/30"}));
+    assert_eq!(redacted["refund"]["card"]["number"], "4222");
+
+    // Nothing to redact leaves the body as it was
+    assert_eq!(&*redactor.body(br#"{ "name": "x" }"#), br#"{ "name": "x" }"#);
+}
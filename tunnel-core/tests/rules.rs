@@ -0,0 +1,27 @@
+use std::path::Path;
+use tunnel_core::rules::{self, RuleLine};
+
+#[test]
+fn blank_lines_and_comments_are_skipped_but_counted() {
+    let contents = "# header\n\n  /api  deny  x-a  \n\t# indented comment\n/b allow y\n";
+    let lines: Vec<RuleLine> = rules::lines(contents).collect();
+    assert_eq!(lines, [RuleLine { number: 3, text: "/api  deny  x-a" }, RuleLine { number: 5, text: "/b allow y" }]);
+    assert_eq!(lines[0].fields().collect::<Vec<_>>(), ["/api", "deny", "x-a"]);
+    assert_eq!(lines[1].error("no header names"), "line 5: no header names");
+}
+
+#[test]
+fn load_names_the_setting_and_file() {
+    let path = Path::new("/nonexistent/rules.txt");
+    let e = rules::load(path, "TEST_RULES_FILE", |_| Ok(())).unwrap_err();
+    assert!(e.starts_with("Failed to read TEST_RULES_FILE /nonexistent/rules.txt: "), "{}", e);
+
+    let path = std::env::temp_dir().join(format!("tunnel-core-rules-{}.txt", std::process::id()));
+    std::fs::write(&path, "bad\n").unwrap();
+    let e = rules::load(&path, "TEST_RULES_FILE", |contents| {
+        Err::<(), _>(rules::lines(contents).next().unwrap().error("expected two fields"))
+    })
+    .unwrap_err();
+    assert_eq!(e, format!("Invalid TEST_RULES_FILE {}: line 1: expected two fields", path.display()));
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,539 @@
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tunnel_core::heartbeat::Heartbeat;
+use tunnel_core::progress::{Phase, RequestProgress};
+use tunnel_core::server::{
+    format_labels, run_worker, supervise, OverflowPolicy, QueueOptions, TunnelConnection, TunnelError, TunnelRegistry, WorkerExit, WorkerStats,
+};
+use tunnel_protocol::{classify_frame, read_frame, write_frame, ControlFrame, Frame, StatsReport, GOAWAY_FRAME, PING_FRAME, PONG_FRAME};
+
+fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[test]
+fn labels_are_formatted_in_key_order() {
+    assert_eq!(format_labels(&labels(&[("team", "web"), ("env", "dev")])), "env=dev,team=web");
+    assert_eq!(format_labels(&BTreeMap::new()), "");
+}
+
+#[test]
+fn connections_get_distinct_ids() {
+    let (a, _rx_a) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    let (b, _rx_b) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    assert_ne!(a.id, b.id);
+}
+
+#[tokio::test]
+async fn last_registered_client_wins() {
+    let registry = TunnelRegistry::new();
+    let (first, _rx1) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    let (second, _rx2) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    let first = Arc::new(first);
+    let second = Arc::new(second);
+
+    assert!(registry.register(first.clone()).await.is_none());
+    let replaced = registry.register(second.clone()).await.unwrap();
+    assert!(Arc::ptr_eq(&replaced, &first));
+    assert!(Arc::ptr_eq(&registry.active().await.unwrap(), &second));
+
+    // A replaced client disconnecting must not evict its successor
+    assert!(!registry.remove(&first).await);
+    assert!(registry.active().await.is_some());
+
+    assert!(registry.remove(&second).await);
+    assert!(registry.active().await.is_none());
+}
+
+#[tokio::test]
+async fn requests_can_wait_for_the_next_connection() {
+    let registry = Arc::new(TunnelRegistry::new());
+    let (first, _rx1) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    let first = Arc::new(first);
+    assert!(registry.lost_at().is_none());
+
+    registry.register(first.clone()).await;
+    registry.remove(&first).await;
+    assert!(registry.lost_at().is_some());
+
+    // Nobody reconnects in time
+    let until = tokio::time::Instant::now() + Duration::from_millis(50);
+    assert!(registry.wait_for_connection(Some(first.id), until).await.is_none());
+
+    let waiting = tokio::spawn({
+        let registry = registry.clone();
+        let until = tokio::time::Instant::now() + Duration::from_secs(5);
+        async move { registry.wait_for_connection(Some(first.id), until).await }
+    });
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let (second, _rx2) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    let second = Arc::new(second);
+    registry.register(second.clone()).await;
+    assert!(Arc::ptr_eq(&waiting.await.unwrap().unwrap(), &second));
+    assert!(registry.lost_at().is_none());
+}
+
+#[tokio::test]
+async fn a_client_can_be_marked_lost_before_it_ever_connected() {
+    let registry = TunnelRegistry::new();
+    registry.mark_lost().await;
+    assert!(registry.lost_at().is_some());
+
+    // Never while one is active
+    let (conn, _rx) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    registry.register(Arc::new(conn)).await;
+    registry.mark_lost().await;
+    assert!(registry.lost_at().is_none());
+}
+
+#[tokio::test]
+async fn worker_relays_request_and_response_frames() {
+    let (server_io, client_io) = tokio::io::duplex(4096);
+    let (conn, rx) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    tokio::spawn(run_worker(server_io, rx, 0));
+
+    // Fake client: echo each request frame back with a prefix
+    tokio::spawn(async move {
+        let (read_half, mut writer) = tokio::io::split(client_io);
+        let mut reader = BufReader::new(read_half);
+        while let Ok(payload) = read_frame(&mut reader).await {
+            let mut response = b"re:".to_vec();
+            response.extend_from_slice(&payload);
+            write_frame(&mut writer, &response).await.unwrap();
+        }
+    });
+
+    assert_eq!(conn.round_trip(Bytes::from_static(b"one")).await.unwrap(), &b"re:one"[..]);
+    assert_eq!(conn.round_trip(Bytes::from_static(b"two")).await.unwrap(), &b"re:two"[..]);
+}
+
+#[tokio::test]
+async fn worker_records_the_phases_of_a_request() {
+    let (server_io, client_io) = tokio::io::duplex(4096);
+    let (conn, rx) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    tokio::spawn(run_worker(server_io, rx, 0));
+
+    // Fake client that takes a while to answer
+    tokio::spawn(async move {
+        let (read_half, mut writer) = tokio::io::split(client_io);
+        let mut reader = BufReader::new(read_half);
+        while let Ok(payload) = read_frame(&mut reader).await {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            write_frame(&mut writer, &payload).await.unwrap();
+        }
+    });
+
+    let progress = Arc::new(RequestProgress::new());
+    conn.round_trip_tracked(Bytes::from_static(b"one"), progress.clone()).await.unwrap();
+    assert_eq!(progress.phase(), Phase::Done);
+    let timings = progress.timings();
+    assert!(timings.client_processing_ms >= 100, "{:?}", timings);
+    assert_eq!(timings.slowest(), Phase::ClientProcessing);
+}
+
+#[tokio::test]
+async fn worker_counts_response_bytes_as_they_arrive() {
+    let (server_io, client_io) = tokio::io::duplex(4096);
+    let (conn, rx) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    tokio::spawn(run_worker(server_io, rx, 0));
+
+    // Fake client that sends the first half of its response, then stalls
+    let (stalled_tx, stalled_rx) = tokio::sync::oneshot::channel();
+    let (resume_tx, resume_rx) = tokio::sync::oneshot::channel::<()>();
+    tokio::spawn(async move {
+        let (read_half, mut writer) = tokio::io::split(client_io);
+        let mut reader = BufReader::new(read_half);
+        read_frame(&mut reader).await.unwrap();
+        writer.write_all(&1000u32.to_be_bytes()).await.unwrap();
+        writer.write_all(&[b'x'; 400]).await.unwrap();
+        stalled_tx.send(()).unwrap();
+        resume_rx.await.unwrap();
+        writer.write_all(&[b'x'; 600]).await.unwrap();
+    });
+
+    let progress = Arc::new(RequestProgress::new());
+    let round_trip = tokio::spawn({
+        let progress = progress.clone();
+        async move { conn.round_trip_tracked(Bytes::from_static(b"download"), progress).await }
+    });
+    stalled_rx.await.unwrap();
+    let transfer = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let transfer = progress.transfer();
+            if transfer.response_bytes == 400 {
+                return transfer;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("partial response was never counted");
+    assert_eq!(transfer.response_total, Some(1000));
+    assert_eq!(progress.phase(), Phase::ResponseRead);
+
+    resume_tx.send(()).unwrap();
+    assert_eq!(round_trip.await.unwrap().unwrap().len(), 1000);
+    assert_eq!(progress.transfer().response_bytes, 1000);
+}
+
+#[tokio::test]
+async fn round_trip_fails_when_tunnel_closes() {
+    let (server_io, client_io) = tokio::io::duplex(4096);
+    let (conn, rx) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    let worker = tokio::spawn(run_worker(server_io, rx, 0));
+    drop(client_io);
+
+    let err = conn.round_trip(Bytes::from_static(b"ping")).await.unwrap_err();
+    assert!(matches!(err, TunnelError::Read(_) | TunnelError::Write(_)), "{}", err);
+    assert!(!err.is_retryable());
+
+    worker.await.unwrap();
+    let err = conn.round_trip(Bytes::from_static(b"ping")).await.unwrap_err();
+    assert!(matches!(err, TunnelError::Closed), "{}", err);
+    assert!(err.is_retryable());
+    assert!(err.lost_before_delivery(&RequestProgress::new()));
+}
+
+#[test]
+fn only_requests_never_written_count_as_lost_before_delivery() {
+    let progress = RequestProgress::new();
+    progress.enter(Phase::Queue);
+    assert!(TunnelError::WorkerGone.lost_before_delivery(&progress));
+    progress.enter(Phase::TunnelWrite);
+    assert!(!TunnelError::WorkerGone.lost_before_delivery(&progress));
+    assert!(!TunnelError::Read(std::io::ErrorKind::UnexpectedEof.into()).lost_before_delivery(&progress));
+    assert!(!TunnelError::QueueFull.lost_before_delivery(&progress));
+}
+
+#[tokio::test]
+async fn supervisor_cleans_up_after_a_panicking_worker() {
+    let registry = TunnelRegistry::new();
+    let (conn, rx) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    let conn = Arc::new(conn);
+    registry.register(conn.clone()).await;
+
+    let worker = async move {
+        let _rx = rx;
+        panic!("worker bug");
+    };
+    let exit = supervise(&registry, &conn, worker).await;
+    assert!(matches!(&exit, WorkerExit::Panicked(message) if message == "worker bug"), "{:?}", exit);
+    assert!(registry.active().await.is_none());
+
+    // Requests still queued on the dead connection fail instead of hanging
+    let err = conn.round_trip(Bytes::from_static(b"ping")).await.unwrap_err();
+    assert!(matches!(err, TunnelError::Closed), "{}", err);
+
+    let stats = registry.worker_stats();
+    assert_eq!(stats, WorkerStats { started: 1, panicked: 1, ..WorkerStats::default() });
+}
+
+#[tokio::test]
+async fn supervisor_counts_disconnects() {
+    let registry = TunnelRegistry::new();
+    let (server_io, client_io) = tokio::io::duplex(4096);
+    let (conn, rx) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    let conn = Arc::new(conn);
+    registry.register(conn.clone()).await;
+
+    let supervisor = supervise(&registry, &conn, run_worker(server_io, rx, 0));
+    drop(client_io);
+    let (exit, err) = tokio::join!(supervisor, conn.round_trip(Bytes::from_static(b"ping")));
+    assert!(matches!(exit, WorkerExit::Disconnected), "{:?}", exit);
+    assert!(err.is_err());
+    assert!(registry.active().await.is_none());
+    assert_eq!(registry.worker_stats().disconnected, 1);
+}
+
+#[tokio::test]
+async fn supervisor_counts_protocol_errors() {
+    let registry = TunnelRegistry::new();
+    let (server_io, mut client_io) = tokio::io::duplex(4096);
+    let (conn, rx) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    let conn = Arc::new(conn);
+    registry.register(conn.clone()).await;
+
+    // A response nobody asked for
+    write_frame(&mut client_io, br#"{"status":200,"headers":[],"body":""}"#).await.unwrap();
+    let exit = supervise(&registry, &conn, run_worker(server_io, rx, 0)).await;
+    assert!(matches!(&exit, WorkerExit::Protocol(message) if message.contains("idle client")), "{:?}", exit);
+    assert_eq!(registry.worker_stats(), WorkerStats { started: 1, protocol_errors: 1, ..WorkerStats::default() });
+}
+
+#[tokio::test]
+async fn full_queue_rejects_requests_after_the_send_timeout() {
+    let queue = QueueOptions { depth: 1, send_timeout: Duration::from_millis(50), ..QueueOptions::default() };
+    let (conn, _rx) = TunnelConnection::new(BTreeMap::new(), &queue);
+    let conn = Arc::new(conn);
+
+    // Nothing consumes the queue, as with a stalled worker: the first request
+    // fills it and waits for a response that never comes
+    let waiting = conn.clone();
+    tokio::spawn(async move { waiting.round_trip(Bytes::from_static(b"first")).await });
+    tokio::task::yield_now().await;
+
+    let err = conn.round_trip(Bytes::from_static(b"second")).await.unwrap_err();
+    assert!(matches!(err, TunnelError::QueueFull), "{}", err);
+    assert!(err.is_retryable());
+}
+
+#[test]
+fn queue_settings_are_parsed() {
+    let get = |pairs: &'static [(&'static str, &'static str)]| {
+        move |key: &str| pairs.iter().find(|(k, _)| *k == key).map(|(_, v)| v.to_string())
+    };
+    let queue = QueueOptions::from_source(get(&[("TUNNEL_QUEUE_DEPTH", "8"), ("TUNNEL_QUEUE_TIMEOUT_MS", "250")])).unwrap();
+    assert_eq!(queue.depth, 8);
+    assert_eq!(queue.send_timeout, Duration::from_millis(250));
+
+    assert!(QueueOptions::from_source(get(&[("TUNNEL_QUEUE_DEPTH", "0")])).is_err());
+    assert!(QueueOptions::from_source(get(&[("TUNNEL_QUEUE_TIMEOUT_MS", "soon")])).is_err());
+
+    let queue = QueueOptions::from_source(get(&[("TUNNEL_MAX_IN_FLIGHT", "4"), ("TUNNEL_OVERFLOW", "reject")])).unwrap();
+    assert_eq!(queue.max_in_flight, Some(4));
+    assert_eq!(queue.overflow, OverflowPolicy::Reject);
+
+    let queue = QueueOptions::from_source(get(&[("TUNNEL_MAX_IN_FLIGHT", "0")])).unwrap();
+    assert_eq!(queue.max_in_flight, None);
+    assert_eq!(queue.overflow, OverflowPolicy::Queue);
+    assert!(QueueOptions::from_source(get(&[("TUNNEL_OVERFLOW", "drop")])).is_err());
+
+    assert_eq!(QueueOptions::default().write_stall, Duration::from_secs(1));
+    let queue = QueueOptions::from_source(get(&[("TUNNEL_WRITE_STALL_MS", "250")])).unwrap();
+    assert_eq!(queue.write_stall, Duration::from_millis(250));
+    assert!(QueueOptions::from_source(get(&[("TUNNEL_WRITE_STALL_MS", "0")])).is_err());
+}
+
+/// Starts a request that holds its in-flight slot until the worker answers, which it never does
+async fn occupy_slot(conn: &Arc<TunnelConnection>) {
+    let waiting = conn.clone();
+    tokio::spawn(async move { waiting.round_trip(Bytes::from_static(b"first")).await });
+    tokio::task::yield_now().await;
+}
+
+#[tokio::test]
+async fn in_flight_cap_rejects_immediately_with_the_reject_policy() {
+    let queue = QueueOptions { max_in_flight: Some(1), overflow: OverflowPolicy::Reject, ..QueueOptions::default() };
+    let (conn, _rx) = TunnelConnection::new(BTreeMap::new(), &queue);
+    let conn = Arc::new(conn);
+    occupy_slot(&conn).await;
+    assert_eq!(conn.in_flight(), Some(1));
+
+    // Well before the 1s send timeout
+    let err = tokio::time::timeout(Duration::from_millis(100), conn.round_trip(Bytes::from_static(b"second")))
+        .await
+        .expect("request waited for a slot")
+        .unwrap_err();
+    assert!(matches!(err, TunnelError::InFlightLimit), "{}", err);
+    assert!(err.is_retryable());
+}
+
+#[tokio::test]
+async fn in_flight_cap_waits_for_a_slot_with_the_queue_policy() {
+    let queue = QueueOptions { send_timeout: Duration::from_millis(50), max_in_flight: Some(1), ..QueueOptions::default() };
+    let (conn, rx) = TunnelConnection::new(BTreeMap::new(), &queue);
+    let conn = Arc::new(conn);
+    occupy_slot(&conn).await;
+
+    let err = conn.round_trip(Bytes::from_static(b"second")).await.unwrap_err();
+    assert!(matches!(err, TunnelError::InFlightLimit), "{}", err);
+
+    // Once the worker answers the first request its slot goes to the next one
+    let (server_io, client_io) = tokio::io::duplex(4096);
+    let echo = tokio::spawn(async move {
+        let mut client_io = BufReader::new(client_io);
+        while let Ok(frame) = read_frame(&mut client_io).await {
+            write_frame(client_io.get_mut(), &frame).await.unwrap();
+        }
+    });
+    tokio::spawn(run_worker(server_io, rx, 0));
+    let response = conn.round_trip(Bytes::from_static(b"third")).await.unwrap();
+    assert_eq!(response.as_ref(), b"third");
+    assert_eq!(conn.in_flight(), Some(0));
+    echo.abort();
+}
+
+#[tokio::test]
+async fn worker_records_stats_reports_sent_ahead_of_a_response() {
+    let (server_io, client_io) = tokio::io::duplex(4096);
+    let (conn, rx) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    tokio::spawn(run_worker(server_io, rx, 0));
+    assert!(conn.peer_stats().is_none());
+
+    tokio::spawn(async move {
+        let (read_half, mut writer) = tokio::io::split(client_io);
+        let mut reader = BufReader::new(read_half);
+        while let Ok(payload) = read_frame(&mut reader).await {
+            let report = StatsReport { requests: 7, ..StatsReport::default() };
+            write_frame(&mut writer, &ControlFrame::Stats(report).encode()).await.unwrap();
+            write_frame(&mut writer, &payload).await.unwrap();
+        }
+    });
+
+    assert_eq!(conn.round_trip(Bytes::from_static(b"one")).await.unwrap(), &b"one"[..]);
+    assert_eq!(conn.peer_stats().unwrap().report.requests, 7);
+}
+
+#[tokio::test]
+async fn worker_answers_queued_requests_then_drains_after_goaway() {
+    let registry = TunnelRegistry::new();
+    let (server_io, client_io) = tokio::io::duplex(4096);
+    let (conn, rx) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    let conn = Arc::new(conn);
+    registry.register(conn.clone()).await;
+
+    // Fake client that announces it is shutting down ahead of its first response
+    tokio::spawn(async move {
+        let (read_half, mut writer) = tokio::io::split(client_io);
+        let mut reader = BufReader::new(read_half);
+        while let Ok(payload) = read_frame(&mut reader).await {
+            write_frame(&mut writer, GOAWAY_FRAME).await.unwrap();
+            write_frame(&mut writer, &payload).await.unwrap();
+        }
+    });
+
+    let requests = async {
+        assert_eq!(conn.round_trip(Bytes::from_static(b"one")).await.unwrap(), &b"one"[..]);
+        assert!(conn.is_draining());
+        let err = conn.round_trip(Bytes::from_static(b"two")).await.unwrap_err();
+        assert!(matches!(err, TunnelError::Draining), "{}", err);
+        assert!(err.is_retryable());
+    };
+    let (exit, ()) = tokio::join!(supervise(&registry, &conn, run_worker(server_io, rx, 0)), requests);
+    assert!(matches!(exit, WorkerExit::Drained), "{:?}", exit);
+    assert!(registry.active().await.is_none());
+    assert_eq!(registry.worker_stats().drained, 1);
+}
+
+#[tokio::test]
+async fn idle_worker_reads_control_frames() {
+    let (server_io, client_io) = tokio::io::duplex(4096);
+    let (conn, rx) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    let worker = tokio::spawn(run_worker(server_io, rx, 0));
+
+    // No request is waiting for these
+    let (_read_half, mut writer) = tokio::io::split(client_io);
+    let report = StatsReport { requests: 3, ..StatsReport::default() };
+    write_frame(&mut writer, &ControlFrame::Stats(report).encode()).await.unwrap();
+    write_frame(&mut writer, GOAWAY_FRAME).await.unwrap();
+
+    let exit = worker.await.unwrap();
+    assert!(matches!(exit, WorkerExit::Drained), "{:?}", exit);
+    assert_eq!(conn.peer_stats().unwrap().report.requests, 3);
+    let err = conn.round_trip(Bytes::from_static(b"ping")).await.unwrap_err();
+    assert!(matches!(err, TunnelError::Draining), "{}", err);
+}
+
+#[tokio::test]
+async fn idle_worker_notices_a_disconnect() {
+    let (server_io, client_io) = tokio::io::duplex(4096);
+    let (_conn, rx) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    let worker = tokio::spawn(run_worker(server_io, rx, 0));
+    drop(client_io);
+
+    let exit = tokio::time::timeout(Duration::from_secs(1), worker).await.expect("worker kept waiting").unwrap();
+    assert!(matches!(exit, WorkerExit::Disconnected), "{:?}", exit);
+}
+
+#[tokio::test]
+async fn worker_drops_a_client_that_stops_answering_pings() {
+    let registry = TunnelRegistry::new();
+    let (server_io, client_io) = tokio::io::duplex(4096);
+    let (conn, rx) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    let conn = Arc::new(conn);
+    registry.register(conn.clone()).await;
+    let rx = rx.with_heartbeat(Heartbeat::new(Duration::from_millis(100), Duration::from_millis(200)));
+
+    // Fake client that answers three PINGs, then goes quiet without closing
+    tokio::spawn(async move {
+        let (read_half, mut writer) = tokio::io::split(client_io);
+        let mut reader = BufReader::new(read_half);
+        for _ in 0..3 {
+            assert_eq!(classify_frame(&read_frame(&mut reader).await.unwrap()).unwrap(), Frame::Control(ControlFrame::Ping {}));
+            write_frame(&mut writer, PONG_FRAME).await.unwrap();
+        }
+        std::future::pending::<()>().await;
+    });
+
+    let started = std::time::Instant::now();
+    let exit = tokio::time::timeout(Duration::from_secs(5), supervise(&registry, &conn, run_worker(server_io, rx, 0)))
+        .await
+        .expect("worker kept waiting");
+    assert!(matches!(exit, WorkerExit::Unresponsive), "{:?}", exit);
+    assert!(started.elapsed() >= Duration::from_millis(4 * 100 + 200), "{:?}", started.elapsed());
+    assert_eq!(registry.worker_stats().unresponsive, 1);
+}
+
+#[tokio::test]
+async fn worker_answers_pings_while_waiting_for_a_response() {
+    let (server_io, client_io) = tokio::io::duplex(4096);
+    let (conn, rx) = TunnelConnection::new(BTreeMap::new(), &QueueOptions::default());
+    let rx = rx.with_heartbeat(Heartbeat::new(Duration::from_millis(100), Duration::from_millis(200)));
+    tokio::spawn(run_worker(server_io, rx, 0));
+
+    // Fake client whose response takes several heartbeats, answering and sending PINGs meanwhile
+    tokio::spawn(async move {
+        let (read_half, mut writer) = tokio::io::split(client_io);
+        let mut reader = BufReader::new(read_half);
+        let request = read_frame(&mut reader).await.unwrap();
+        for _ in 0..4 {
+            assert_eq!(classify_frame(&read_frame(&mut reader).await.unwrap()).unwrap(), Frame::Control(ControlFrame::Ping {}));
+            write_frame(&mut writer, PONG_FRAME).await.unwrap();
+            write_frame(&mut writer, PING_FRAME).await.unwrap();
+            assert_eq!(read_frame(&mut reader).await.unwrap(), PONG_FRAME);
+        }
+        write_frame(&mut writer, &request).await.unwrap();
+    });
+
+    assert_eq!(conn.round_trip(Bytes::from_static(b"slow")).await.unwrap(), &b"slow"[..]);
+}
+
+#[tokio::test]
+async fn writes_to_a_client_not_reading_are_reported_as_stalled() {
+    // Room for a small part of the request only, until the client reads
+    let (server_io, client_io) = tokio::io::duplex(1024);
+    let queue = QueueOptions { write_stall: Duration::from_millis(50), ..QueueOptions::default() };
+    let (conn, rx) = TunnelConnection::new(BTreeMap::new(), &queue);
+    let conn = Arc::new(conn);
+    tokio::spawn(run_worker(server_io, rx, 0));
+    let idle = conn.write_stats();
+    assert_eq!((idle.queued_frames, idle.stalled, idle.stalls), (0, false, 0));
+
+    let requests: Vec<_> = (0..2)
+        .map(|_| {
+            let conn = conn.clone();
+            tokio::spawn(async move { conn.round_trip(Bytes::from(vec![b'x'; 64 * 1024])).await })
+        })
+        .collect();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !conn.write_stats().stalled {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("write never stalled");
+    // The second request waits behind the first
+    assert_eq!(conn.write_stats().queued_frames, 1);
+
+    // The client catches up and answers both
+    tokio::spawn(async move {
+        let (read_half, mut writer) = tokio::io::split(client_io);
+        let mut reader = BufReader::new(read_half);
+        loop {
+            read_frame(&mut reader).await.unwrap();
+            write_frame(&mut writer, b"{}").await.unwrap();
+        }
+    });
+    for request in requests {
+        request.await.unwrap().unwrap();
+    }
+    let stats = conn.write_stats();
+    assert_eq!((stats.queued_frames, stats.stalled), (0, false));
+    assert!(stats.stalls >= 1, "{:?}", stats);
+}
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use tunnel_core::tls::{parse_cert_pins, CertPin, TlsOptions, TlsVersion};
+
+fn options(pairs: &[(&str, &str)]) -> Result<TlsOptions, String> {
+    let map: HashMap<&str, &str> = pairs.iter().copied().collect();
+    TlsOptions::from_source(|key| map.get(key).map(|value| value.to_string()))
+}
+
+#[test]
+fn defaults_allow_tls12_with_resumption() {
+    let options = options(&[]).unwrap();
+    assert_eq!(options.min_version, TlsVersion::Tls12);
+    assert!(options.alpn.is_empty());
+    assert!(options.cipher_suites.is_empty());
+    assert!(options.session_resumption);
+}
+
+#[test]
+fn settings_are_parsed() {
+    let options = options(&[
+        ("TLS_MIN_VERSION", "1.3"),
+        ("TLS_ALPN", "h2, http/1.1"),
+        ("TLS_CIPHER_SUITES", "tls13_aes_256_gcm_sha384"),
+        ("TLS_SESSION_RESUMPTION", "false"),
+    ])
+    .unwrap();
+    assert_eq!(options.min_version, TlsVersion::Tls13);
+    assert_eq!(options.alpn, ["h2", "http/1.1"]);
+    assert_eq!(options.cipher_suites, ["TLS13_AES_256_GCM_SHA384"]);
+    assert!(!options.session_resumption);
+}
+
+#[test]
+fn invalid_settings_are_rejected() {
+    for (pairs, expected) in [
+        (&[("TLS_MIN_VERSION", "1.1")][..], "Invalid TLS_MIN_VERSION"),
+        (&[("TLS_CIPHER_SUITES", "TLS_RSA_WITH_RC4_128_MD5")][..], "unknown suite"),
+        (&[("TLS_SESSION_RESUMPTION", "maybe")][..], "Invalid TLS_SESSION_RESUMPTION"),
+        // TLS 1.3 only, but only a TLS 1.2 suite allowed
+        (
+            &[("TLS_MIN_VERSION", "1.3"), ("TLS_CIPHER_SUITES", "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256")][..],
+            "Invalid TLS settings",
+        ),
+    ] {
+        let err = options(pairs).unwrap_err();
+        assert!(err.contains(expected), "{}", err);
+    }
+}
+
+#[test]
+fn cert_pins_are_parsed() {
+    let hex = "ab".repeat(32);
+    let pin = CertPin::parse(&format!("sha256:{}", hex)).unwrap();
+    assert_eq!(pin.to_string(), format!("sha256:{}", hex));
+
+    // As printed by `openssl x509 -fingerprint -sha256`
+    let openssl = format!("SHA256:{}", vec!["AB"; 32].join(":"));
+    assert_eq!(CertPin::parse(&openssl).unwrap(), pin);
+
+    let other = format!("sha256:{}", "cd".repeat(32));
+    assert_eq!(parse_cert_pins(&format!("{}, {}", pin, other)).unwrap().len(), 2);
+}
+
+#[test]
+fn invalid_cert_pins_are_rejected() {
+    for value in [
+        "".to_string(),
+        "ab".repeat(32),
+        format!("sha1:{}", "ab".repeat(32)),
+        format!("sha256:{}", "ab".repeat(31)),
+        format!("sha256:{}zz", "ab".repeat(31)),
+    ] {
+        assert!(CertPin::parse(&value).is_err(), "{}", value);
+    }
+}
@@ -0,0 +1,99 @@
+use socket2::SockRef;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tunnel_core::transport::TransportOptions;
+use tunnel_protocol::Compression;
+
+fn options(pairs: &[(&str, &str)]) -> Result<TransportOptions, String> {
+    TransportOptions::from_source(|key| pairs.iter().find(|(k, _)| *k == key).map(|(_, v)| v.to_string()))
+}
+
+#[test]
+fn keepalive_settings_are_parsed() {
+    let defaults = options(&[]).unwrap();
+    assert_eq!(defaults.tcp_keepalive, None);
+    assert_eq!(defaults.tcp_user_timeout, None);
+
+    let transport = options(&[("TUNNEL_TCP_KEEPALIVE_SECS", "30"), ("TUNNEL_TCP_KEEPALIVE_INTERVAL_SECS", "5")]).unwrap();
+    assert_eq!(transport.tcp_keepalive, Some(Duration::from_secs(30)));
+    assert_eq!(transport.tcp_keepalive_interval, Some(Duration::from_secs(5)));
+    assert!(transport.summary().contains("keepalive=30s/5s"), "{}", transport.summary());
+
+    assert_eq!(options(&[("TUNNEL_TCP_KEEPALIVE_SECS", "0")]).unwrap().tcp_keepalive, None);
+    assert!(options(&[("TUNNEL_TCP_KEEPALIVE_SECS", "soon")]).is_err());
+    assert!(options(&[("TUNNEL_TCP_KEEPALIVE_INTERVAL_SECS", "5")]).is_err());
+    assert!(options(&[("TUNNEL_TCP_KEEPALIVE_SECS", "30"), ("TUNNEL_TCP_KEEPALIVE_INTERVAL_SECS", "0")]).is_err());
+    assert_eq!(options(&[("TUNNEL_TCP_USER_TIMEOUT_MS", "0")]).unwrap().tcp_user_timeout, None);
+    assert!(options(&[("TUNNEL_TCP_USER_TIMEOUT_MS", "-1")]).is_err());
+}
+
+#[test]
+fn stream_threshold_is_parsed() {
+    assert_eq!(options(&[]).unwrap().stream_threshold_bytes, 1024 * 1024);
+    let transport = options(&[("TUNNEL_STREAM_THRESHOLD_BYTES", "65536")]).unwrap();
+    assert_eq!(transport.stream_threshold_bytes, 65536);
+    assert!(transport.summary().contains("stream_threshold=65536B"), "{}", transport.summary());
+    assert!(options(&[("TUNNEL_STREAM_THRESHOLD_BYTES", "0")]).is_err());
+    assert!(options(&[("TUNNEL_STREAM_THRESHOLD_BYTES", "1MB")]).is_err());
+}
+
+#[test]
+fn stream_window_is_parsed() {
+    assert_eq!(options(&[]).unwrap().stream_window_chunks, 8);
+    let transport = options(&[("TUNNEL_STREAM_WINDOW_CHUNKS", "64")]).unwrap();
+    assert_eq!(transport.stream_window_chunks, 64);
+    assert!(transport.summary().contains("stream_window=64"), "{}", transport.summary());
+    assert!(options(&[("TUNNEL_STREAM_WINDOW_CHUNKS", "0")]).is_err());
+    assert!(options(&[("TUNNEL_STREAM_WINDOW_CHUNKS", "many")]).is_err());
+}
+
+#[test]
+fn heartbeat_settings_are_parsed() {
+    let defaults = options(&[]).unwrap();
+    assert_eq!(defaults.heartbeat_interval, Some(Duration::from_secs(15)));
+    assert_eq!(defaults.heartbeat_timeout, Duration::from_secs(10));
+    assert!(defaults.summary().contains("heartbeat=15s/10s"), "{}", defaults.summary());
+
+    let transport = options(&[("TUNNEL_HEARTBEAT_SECS", "0")]).unwrap();
+    assert_eq!(transport.heartbeat_interval, None);
+    assert!(transport.summary().contains("heartbeat=off"), "{}", transport.summary());
+    assert_eq!(options(&[("TUNNEL_HEARTBEAT_TIMEOUT_SECS", "3")]).unwrap().heartbeat_timeout, Duration::from_secs(3));
+    assert!(options(&[("TUNNEL_HEARTBEAT_SECS", "often")]).is_err());
+    assert!(options(&[("TUNNEL_HEARTBEAT_TIMEOUT_SECS", "0")]).is_err());
+}
+
+#[test]
+fn compression_is_parsed() {
+    let defaults = options(&[]).unwrap();
+    assert_eq!(defaults.compression, None);
+    assert!(defaults.summary().contains("compression=off"), "{}", defaults.summary());
+
+    assert_eq!(options(&[("TUNNEL_COMPRESSION", "zstd")]).unwrap().compression, Some(Compression::Zstd));
+    assert_eq!(options(&[("TUNNEL_COMPRESSION", "Gzip")]).unwrap().compression, Some(Compression::Gzip));
+    assert_eq!(options(&[("TUNNEL_COMPRESSION", "off")]).unwrap().compression, None);
+    assert!(options(&[("TUNNEL_COMPRESSION", "brotli")]).is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn keepalive_options_are_set_on_streams_and_inherited_from_listeners() {
+    let transport = options(&[
+        ("TUNNEL_TCP_KEEPALIVE_SECS", "30"),
+        ("TUNNEL_TCP_KEEPALIVE_INTERVAL_SECS", "5"),
+        ("TUNNEL_TCP_USER_TIMEOUT_MS", "20000"),
+    ])
+    .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    transport.apply_to_listener(&listener).unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    transport.apply_to_stream(&stream).unwrap();
+    let (accepted, _) = listener.accept().await.unwrap();
+
+    for socket in [SockRef::from(&stream), SockRef::from(&accepted)] {
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+        assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(5));
+        assert_eq!(socket.tcp_user_timeout().unwrap(), Some(Duration::from_secs(20)));
+    }
+}
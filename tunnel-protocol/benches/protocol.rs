@@ -0,0 +1,148 @@
+//! Benchmarks for the protocol hot path: framing, message codecs and body encoding.
+//!
+//! Run with `cargo bench -p tunnel-protocol`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use serde::{Deserialize, Serialize};
+use bytes::BytesMut;
+use tunnel_protocol::{
+    decode_body, decode_frame_bytes, decode_request_frame, decode_tunnel_request, encode_binary_into, encode_body,
+    read_frame, write_frame, TunnelRequest,
+};
+
+/// Body sizes covering webhooks, typical API responses and large downloads
+const BODY_SIZES: [usize; 3] = [1024, 64 * 1024, 1024 * 1024];
+
+/// Request with a raw (not base64) body, as a binary codec would carry it
+#[derive(Serialize, Deserialize)]
+struct RawBodyRequest {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    #[serde(with = "serde_bytes")]
+    body: Vec<u8>,
+}
+
+fn body(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+fn headers() -> Vec<(String, String)> {
+    [
+        ("host", "example.com"),
+        ("user-agent", "curl/8.0"),
+        ("accept", "*/*"),
+        ("content-type", "application/octet-stream"),
+        ("x-request-id", "0b7c7f5e-6c1d-4a55-9d1e-3f1f3c2b9a10"),
+    ]
+    .iter()
+    .map(|(name, value)| (name.to_string(), value.to_string()))
+    .collect()
+}
+
+fn tunnel_request(body: &[u8]) -> TunnelRequest {
+    TunnelRequest {
+        method: "POST".to_string(),
+        path: "/api/v1/webhook?source=bench".to_string(),
+        headers: headers().into_iter().map(|(name, value)| (name, value.into())).collect(),
+        body: encode_body(body),
+        deadline_ms: Some(30_000),
+        ..TunnelRequest::default()
+    }
+}
+
+fn raw_body_request(body: &[u8]) -> RawBodyRequest {
+    RawBodyRequest {
+        method: "POST".to_string(),
+        path: "/api/v1/webhook?source=bench".to_string(),
+        headers: headers(),
+        body: body.to_vec(),
+    }
+}
+
+fn framing(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let mut group = c.benchmark_group("frame");
+
+    for size in BODY_SIZES {
+        let payload = body(size);
+        let mut framed = Vec::new();
+        runtime.block_on(write_frame(&mut framed, &payload)).unwrap();
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("write_frame", size), &payload, |b, payload| {
+            b.iter(|| {
+                let mut out = Vec::with_capacity(payload.len() + 4);
+                runtime.block_on(write_frame(&mut out, payload)).unwrap();
+                out
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("read_frame", size), &framed, |b, framed| {
+            b.iter(|| runtime.block_on(read_frame(&mut &framed[..])).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("decode_frame_bytes", size), &framed, |b, framed| {
+            b.iter(|| decode_frame_bytes(black_box(framed)).unwrap().1)
+        });
+    }
+    group.finish();
+}
+
+fn codecs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec");
+
+    for size in BODY_SIZES {
+        let request = tunnel_request(&body(size));
+        let json = serde_json::to_vec(&request).unwrap();
+        let msgpack = rmp_serde::to_vec(&request).unwrap();
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("json_encode", size), &request, |b, request| {
+            b.iter(|| serde_json::to_vec(black_box(request)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("json_decode", size), &json, |b, json| {
+            b.iter(|| decode_tunnel_request(black_box(json)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("msgpack_encode", size), &request, |b, request| {
+            b.iter(|| rmp_serde::to_vec(black_box(request)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("msgpack_decode", size), &msgpack, |b, msgpack| {
+            b.iter(|| rmp_serde::from_slice::<TunnelRequest>(black_box(msgpack)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// Full message encode + decode, base64 body in JSON vs raw body in a binary frame or a binary codec
+fn bodies(c: &mut Criterion) {
+    let mut group = c.benchmark_group("body");
+
+    for size in BODY_SIZES {
+        let payload = body(size);
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("base64_json", size), &payload, |b, payload| {
+            b.iter(|| {
+                let encoded = serde_json::to_vec(&tunnel_request(payload)).unwrap();
+                let decoded = decode_tunnel_request(&encoded).unwrap();
+                decode_body(&decoded.body).unwrap()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("binary_frame", size), &payload, |b, payload| {
+            b.iter(|| {
+                let mut encoded = BytesMut::new();
+                encode_binary_into(&mut encoded, &tunnel_request(&[]), payload).unwrap();
+                decode_request_frame(&encoded).unwrap().1.unwrap().to_vec()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("raw_msgpack", size), &payload, |b, payload| {
+            b.iter(|| {
+                let encoded = rmp_serde::to_vec(&raw_body_request(payload)).unwrap();
+                rmp_serde::from_slice::<RawBodyRequest>(&encoded).unwrap().body
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, framing, codecs, bodies);
+criterion_main!(benches);
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tunnel_protocol::decode_frame_bytes;
+
+fuzz_target!(|data: &[u8]| {
+    // Walk the buffer frame by frame, as a reader of a byte stream would
+    let mut rest = data;
+    while let Ok((payload, consumed)) = decode_frame_bytes(rest) {
+        assert_eq!(consumed, payload.len() + 4);
+        rest = &rest[consumed..];
+    }
+});
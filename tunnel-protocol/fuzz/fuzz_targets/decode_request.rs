@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tunnel_protocol::{decode_body, decode_tunnel_request};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(request) = decode_tunnel_request(data) {
+        let _ = decode_body(&request.body);
+    }
+});
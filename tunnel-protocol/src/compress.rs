@@ -0,0 +1,289 @@
+//! Compressed frames: any frame payload, compressed with zstd or gzip.
+//!
+//! On connections that agreed to it with `COMPRESSION_HEADER`, either end may
+//! send a frame payload of at least COMPRESS_MIN_BYTES as
+//!
+//! ```text
+//! COMPRESSED: [1 byte: COMPRESSED_MARKER][1 byte: algorithm][compressed payload]
+//! ```
+//!
+//! which stands for the payload it decompresses to: a JSON message, a binary
+//! frame or a body frame alike. A payload that does not shrink is sent as is.
+//! Bodies that are compressed already are not tried at all (`should_compress`),
+//! and a direction whose frames stop shrinking stops being compressed for a
+//! while (`CompressionMonitor`).
+
+use bytes::{BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::{DecodeError, ProtocolError, MAX_FRAME_LEN};
+
+/// Upgrade header with which the client offers compression (`zstd` or `gzip`);
+/// the server echoes the algorithm when it agrees, and both ends use it
+pub const COMPRESSION_HEADER: &str = "x-tunnel-compression";
+
+/// First byte of a COMPRESSED frame
+pub const COMPRESSED_MARKER: u8 = 3;
+
+/// Bytes in front of the compressed data: the marker and the algorithm
+pub const COMPRESSED_PREFIX_LEN: usize = 2;
+
+/// Smallest payload worth compressing
+pub const COMPRESS_MIN_BYTES: usize = 512;
+
+/// zstd level: fast, and most of the gain on JSON
+const ZSTD_LEVEL: i32 = 3;
+
+/// Algorithm of a COMPRESSED frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+    Gzip,
+}
+
+impl Compression {
+    /// Parses `zstd` or `gzip`, in any case
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "zstd" => Some(Self::Zstd),
+            "gzip" => Some(Self::Gzip),
+            _ => None,
+        }
+    }
+
+    /// Name in `COMPRESSION_HEADER` and settings
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    fn id(self) -> u8 {
+        match self {
+            Self::Zstd => 1,
+            Self::Gzip => 2,
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Media types whose bodies are compressed already, by prefix; `image/svg+xml` is text
+const COMPRESSED_TYPES: [&str; 7] = [
+    "image/", "video/", "audio/", "application/zip", "application/gzip", "application/zstd", "application/x-7z-compressed",
+];
+
+/// Leading bytes of archive formats: gzip, zip, zstd, 7z, xz, bzip2, rar
+const ARCHIVE_MAGIC: [&[u8]; 7] = [
+    b"\x1f\x8b", b"PK\x03\x04", b"\x28\xb5\x2f\xfd", b"7z\xbc\xaf\x27\x1c", b"\xfd7zXZ\x00", b"BZh", b"Rar!\x1a\x07",
+];
+
+/// Overrides of the media types `should_compress` skips (COMPRESS_TYPES, NO_COMPRESS_TYPES)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CompressTypes {
+    pub compress: Vec<String>,  // Media-type prefixes compressed even if skipped by default, e.g. `image/bmp`
+    pub skip: Vec<String>,      // Media-type prefixes never compressed; win over `compress`
+}
+
+impl CompressTypes {
+    /// Parses a comma-separated list of media-type prefixes, e.g. `image/bmp, text/`
+    pub fn parse_list(value: &str) -> Result<Vec<String>, String> {
+        value
+            .split(',')
+            .map(|prefix| prefix.trim().to_ascii_lowercase())
+            .filter(|prefix| !prefix.is_empty())
+            .map(|prefix| match prefix.contains('/') {
+                true => Ok(prefix),
+                false => Err(format!("'{}' is not a media type prefix (expected e.g. image/ or image/bmp)", prefix)),
+            })
+            .collect()
+    }
+}
+
+/// Whether a body is worth compressing, from its Content-Type, Content-Encoding and first bytes
+///
+/// Bodies with a Content-Encoding other than `identity` never are; images
+/// (but SVG), video, audio and archives are not unless `types` says so, and
+/// `application/octet-stream` is not when `body_start` is an archive.
+pub fn should_compress(content_type: Option<&str>, content_encoding: Option<&str>, body_start: &[u8], types: &CompressTypes) -> bool {
+    if content_encoding.map(str::trim).is_some_and(|encoding| !encoding.is_empty() && !encoding.eq_ignore_ascii_case("identity")) {
+        return false;
+    }
+    let media_type = content_type.unwrap_or_default().split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    let listed = |prefixes: &[String]| prefixes.iter().any(|prefix| media_type.starts_with(prefix.as_str()));
+    if listed(&types.skip) {
+        return false;
+    }
+    if listed(&types.compress) {
+        return true;
+    }
+    if media_type == "image/svg+xml" {
+        return true;
+    }
+    if COMPRESSED_TYPES.iter().any(|prefix| media_type.starts_with(prefix)) {
+        return false;
+    }
+    !(media_type == "application/octet-stream" && ARCHIVE_MAGIC.iter().any(|magic| body_start.starts_with(magic)))
+}
+
+/// Payload over which the saving of compression is judged
+pub const ADAPTIVE_WINDOW_BYTES: u64 = 1024 * 1024;
+
+/// Saving below which compression is turned off, in percent of the payload
+pub const ADAPTIVE_MIN_SAVING_PERCENT: u64 = 10;
+
+/// Payload sent uncompressed before compression is tried again
+pub const ADAPTIVE_PROBE_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Compression figures of one direction of a tunnel
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CompressionStats {
+    pub enabled: bool,     // Frames are compressed; off while they do not shrink enough
+    pub ratio: f64,        // Bytes sent per payload byte over the frames tried (1.0: none tried yet)
+    pub bytes_saved: u64,  // Payload bytes not sent thanks to compression
+}
+
+/// Ratio of the compressed frames of one direction, and whether compressing is still worth it
+///
+/// Over every ADAPTIVE_WINDOW_BYTES of payload tried, compression is turned
+/// off when it saved less than ADAPTIVE_MIN_SAVING_PERCENT, e.g. for traffic
+/// compressed already, and tried again once ADAPTIVE_PROBE_BYTES went out
+/// uncompressed. Shared by a `FrameWriter` and whoever reports on it.
+#[derive(Debug, Default)]
+pub struct CompressionMonitor(Mutex<Adaptive>);
+
+#[derive(Debug, Default)]
+struct Adaptive {
+    disabled: bool,
+    bytes_in: u64,      // Payload of the frames tried
+    bytes_out: u64,     // Bytes sent for them
+    window_in: u64,     // The same, since the window began
+    window_out: u64,
+    plain_since: u64,   // Payload sent uncompressed since compression was turned off
+}
+
+impl CompressionMonitor {
+    /// Whether the next frame is to be compressed; counts `len` bytes sent as is otherwise
+    pub fn try_next(&self, len: usize) -> bool {
+        let mut adaptive = self.0.lock().unwrap();
+        if !adaptive.disabled {
+            return true;
+        }
+        adaptive.plain_since += len as u64;
+        if adaptive.plain_since >= ADAPTIVE_PROBE_BYTES {
+            adaptive.disabled = false;
+            debug!("Trying compression again after {} bytes sent uncompressed", adaptive.plain_since);
+        }
+        false
+    }
+
+    /// Records a frame of `len` bytes that went out in `sent` bytes once compression was tried
+    pub fn record(&self, len: usize, sent: usize) {
+        let mut adaptive = self.0.lock().unwrap();
+        let (len, sent) = (len as u64, sent as u64);
+        adaptive.bytes_in += len;
+        adaptive.bytes_out += sent;
+        adaptive.window_in += len;
+        adaptive.window_out += sent;
+        if adaptive.window_in < ADAPTIVE_WINDOW_BYTES {
+            return;
+        }
+        let saved = adaptive.window_in.saturating_sub(adaptive.window_out) * 100 / adaptive.window_in;
+        if saved < ADAPTIVE_MIN_SAVING_PERCENT {
+            warn!("Compression saved {}% of the last {} bytes; sending frames uncompressed", saved, adaptive.window_in);
+            adaptive.disabled = true;
+            adaptive.plain_since = 0;
+        }
+        adaptive.window_in = 0;
+        adaptive.window_out = 0;
+    }
+
+    /// Current figures
+    pub fn stats(&self) -> CompressionStats {
+        let adaptive = self.0.lock().unwrap();
+        CompressionStats {
+            enabled: !adaptive.disabled,
+            ratio: match adaptive.bytes_in {
+                0 => 1.0,
+                bytes_in => adaptive.bytes_out as f64 / bytes_in as f64,
+            },
+            bytes_saved: adaptive.bytes_in.saturating_sub(adaptive.bytes_out),
+        }
+    }
+}
+
+/// Whether a frame payload is a COMPRESSED frame
+pub fn is_compressed_frame(payload: &[u8]) -> bool {
+    payload.first() == Some(&COMPRESSED_MARKER)
+}
+
+/// Compresses `payload` into `out` as a COMPRESSED frame
+///
+/// # Returns
+/// * `Ok(true)` once `out` holds a frame smaller than `payload`
+/// * `Ok(false)` if it would not be smaller; send `payload` as is then
+pub fn compress_frame(compression: Compression, payload: &[u8], out: &mut Vec<u8>) -> io::Result<bool> {
+    out.clear();
+    out.extend_from_slice(&[COMPRESSED_MARKER, compression.id()]);
+    match compression {
+        Compression::Zstd => zstd::stream::copy_encode(payload, &mut *out, ZSTD_LEVEL)?,
+        Compression::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(&mut *out, flate2::Compression::fast());
+            encoder.write_all(payload)?;
+            encoder.finish()?;
+        }
+    }
+    Ok(out.len() < payload.len())
+}
+
+/// Decompresses a COMPRESSED frame into `out`, replacing its contents
+///
+/// # Returns
+/// * `Ok(())` once `out` holds the payload the frame stands for
+/// * `Err(DecodeError::FrameTooLarge)` if that payload exceeds `MAX_FRAME_LEN`
+/// * `Err(DecodeError::InvalidCompressedFrame)` if the frame is not a valid COMPRESSED frame
+pub fn decompress_frame(frame: &[u8], out: &mut BytesMut) -> Result<(), DecodeError> {
+    let Some(&[COMPRESSED_MARKER, algorithm]) = frame.first_chunk::<COMPRESSED_PREFIX_LEN>() else {
+        return Err(DecodeError::InvalidCompressedFrame("not a compressed frame"));
+    };
+    let data = &frame[COMPRESSED_PREFIX_LEN..];
+    let decoder: Box<dyn Read + '_> = match algorithm {
+        1 => Box::new(zstd::stream::Decoder::new(data).map_err(|_| DecodeError::InvalidCompressedFrame("corrupt zstd data"))?),
+        2 => Box::new(flate2::read::GzDecoder::new(data)),
+        _ => return Err(DecodeError::InvalidCompressedFrame("unknown algorithm")),
+    };
+    out.clear();
+    // One byte past the limit tells a payload at the limit from one beyond it
+    io::copy(&mut decoder.take(MAX_FRAME_LEN as u64 + 1), &mut (&mut *out).writer())
+        .map_err(|_| DecodeError::InvalidCompressedFrame("corrupt compressed data"))?;
+    if out.len() > MAX_FRAME_LEN {
+        return Err(DecodeError::FrameTooLarge { len: out.len(), max: MAX_FRAME_LEN });
+    }
+    Ok(())
+}
+
+/// Replaces a COMPRESSED frame in `buf` by the payload it stands for; other frames are left as they are
+///
+/// `compression` is what the connection agreed to (None: off). Without it a
+/// COMPRESSED frame is refused rather than inflated, so a peer cannot make
+/// this end decompress frames of up to MAX_FRAME_LEN it never agreed to.
+pub fn inflate_frame(buf: &mut BytesMut, compression: Option<Compression>) -> Result<(), ProtocolError> {
+    if !is_compressed_frame(buf) {
+        return Ok(());
+    }
+    if compression.is_none() {
+        return Err(ProtocolError::Unexpected("compressed frame without agreed compression".to_string()));
+    }
+    let frame = buf.split();
+    Ok(decompress_frame(&frame, buf)?)
+}
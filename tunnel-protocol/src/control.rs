@@ -0,0 +1,71 @@
+//! Control frames: metadata about the connection rather than HTTP traffic.
+//!
+//! Every frame is either data (a `TunnelRequest`, a `TunnelResponse` or a
+//! piece of a streamed body) or control. A control frame is a JSON object with
+//! one field, named after the kind of frame:
+//!
+//! ```text
+//! {"stats":{...}}  {"goaway":{}}  {"ping":{}}  {"pong":{}}  {"cancel":{"id":3}}  {"credit":{"id":3,"chunks":2}}
+//! ```
+//!
+//! Messages start with `{"id":`, `{"method":` or `{"status":`, and the other
+//! frames with a marker byte, so the first field tells control frames apart.
+//! Tunnel registration is not among them: it happens in the HTTP upgrade.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{DecodeError, StatsReport};
+
+/// A control frame, as serialized on the wire
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ControlFrame {
+    /// Client stats, when the server asked for them with `STATS_HEADER`
+    Stats(StatsReport),
+    /// The client is shutting down (see `GOAWAY_FRAME`)
+    Goaway {},
+    /// Heartbeat, to be answered with a PONG (see `PING_FRAME`)
+    Ping {},
+    /// Answer to every PING sent before it (see `PONG_FRAME`)
+    Pong {},
+    /// The server gave up on request `id`: its visitor went away or it timed out (see `CANCEL_HEADER`)
+    Cancel { id: u64 },
+    /// The sender may send `chunks` more body frames of the body of request `id` (see `STREAM_CREDIT_HEADER`)
+    Credit { id: u64, chunks: u64 },
+}
+
+/// First bytes of each kind of control frame
+const CONTROL_PREFIXES: [&[u8]; 6] = [br#"{"stats":"#, br#"{"goaway":"#, br#"{"ping":"#, br#"{"pong":"#, br#"{"cancel":"#, br#"{"credit":"#];
+
+impl ControlFrame {
+    /// The frame payload
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("control frames serialize")
+    }
+}
+
+/// A frame payload, told apart by its first bytes
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame<'a> {
+    /// A request, response or body frame, of either encoding, left undecoded
+    Data(&'a [u8]),
+    Control(ControlFrame),
+}
+
+/// Whether a frame payload is a control frame
+pub fn is_control_frame(payload: &[u8]) -> bool {
+    CONTROL_PREFIXES.iter().any(|prefix| payload.starts_with(prefix))
+}
+
+/// Tells a data frame from a control frame, decoding the latter
+///
+/// # Returns
+/// * `Ok(Frame::Data(payload))` for a data frame
+/// * `Ok(Frame::Control(frame))` for a control frame
+/// * `Err(DecodeError::InvalidMessage)` for a control frame that does not decode
+pub fn classify_frame(payload: &[u8]) -> Result<Frame<'_>, DecodeError> {
+    if !is_control_frame(payload) {
+        return Ok(Frame::Data(payload));
+    }
+    serde_json::from_slice(payload).map(Frame::Control).map_err(DecodeError::InvalidMessage)
+}
@@ -0,0 +1,95 @@
+//! Errors reading and writing frames on a tunnel connection.
+//!
+//! `ProtocolError` tells a connection the peer closed from one carrying
+//! frames this end cannot read, which calls for a different reaction: the
+//! first is routine and worth reconnecting after, the second points at a
+//! version mismatch or corruption. It converts to and from `io::Error`, so
+//! code built on `io::Result` can pass it through with `?` and classify it
+//! again where the difference matters.
+
+use std::io;
+use thiserror::Error;
+
+use crate::DecodeError;
+
+/// Error from reading or writing frames
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    /// The peer closed the connection, or it was reset
+    #[error("Connection closed: {0}")]
+    Closed(#[source] io::Error),
+
+    /// Length prefix (or decompressed size) exceeds `MAX_FRAME_LEN`
+    #[error("Frame of {len} bytes exceeds the {max} byte limit")]
+    FrameTooLarge { len: usize, max: usize },
+
+    /// Frame that does not decode
+    #[error("Corrupt frame: {0}")]
+    Corrupt(#[source] DecodeError),
+
+    /// Frame that decodes but has no place here (e.g. a response to no request)
+    #[error("Unexpected frame: {0}")]
+    Unexpected(String),
+
+    /// Message that does not serialize
+    #[error("Failed to encode message: {0}")]
+    Encode(#[source] serde_json::Error),
+
+    /// Any other I/O failure (e.g. a timeout)
+    #[error(transparent)]
+    Io(io::Error),
+}
+
+impl ProtocolError {
+    /// Whether the connection is unusable because of what the peer sent
+    /// rather than because it went away: reconnecting likely fails the same way
+    pub fn is_peer_fault(&self) -> bool {
+        matches!(self, ProtocolError::FrameTooLarge { .. } | ProtocolError::Corrupt(_) | ProtocolError::Unexpected(_))
+    }
+
+    /// Kind of the `io::Error` this converts to
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            ProtocolError::Closed(e) | ProtocolError::Io(e) => e.kind(),
+            _ => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl From<DecodeError> for ProtocolError {
+    fn from(e: DecodeError) -> Self {
+        match e {
+            DecodeError::FrameTooLarge { len, max } => ProtocolError::FrameTooLarge { len, max },
+            e => ProtocolError::Corrupt(e),
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted => ProtocolError::Closed(e),
+            // One of ours, passed through code working with `io::Error`
+            io::ErrorKind::InvalidData if e.get_ref().is_some_and(|inner| inner.is::<ProtocolError>() || inner.is::<DecodeError>()) => {
+                let inner = e.into_inner().expect("checked above");
+                match inner.downcast::<ProtocolError>() {
+                    Ok(e) => *e,
+                    Err(inner) => (*inner.downcast::<DecodeError>().expect("checked above")).into(),
+                }
+            }
+            _ => ProtocolError::Io(e),
+        }
+    }
+}
+
+impl From<ProtocolError> for io::Error {
+    fn from(e: ProtocolError) -> Self {
+        match e {
+            ProtocolError::Closed(e) | ProtocolError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
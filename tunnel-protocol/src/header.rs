@@ -0,0 +1,158 @@
+//! Header values as raw bytes.
+//!
+//! HTTP header values are bytes, usually but not always UTF-8 (Latin-1
+//! filenames and cookies are not). In messages a UTF-8 value is a JSON string
+//! and any other value an object holding it base64-encoded:
+//!
+//! ```text
+//! "headers": [["content-type", "text/plain"], ["x-latin1", {"base64": "6XTp"}]]
+//! ```
+//!
+//! so one list carries every header in order, repeated names included. Names
+//! are spelled as they were sent over HTTP/1.1 (`X-Custom-Header`), so compare
+//! them case-insensitively. Trailers travel the same way.
+
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::{decode_body, encode_body};
+
+/// Key of the object a non-UTF-8 value travels in
+const BASE64_KEY: &str = "base64";
+
+/// Fields that may not follow the body (RFC 9110 section 6.5.1): framing,
+/// routing, request modifiers, authentication, response control and content
+/// metadata, plus connection-specific fields
+const NOT_TRAILERS: [&str; 35] = [
+    "age", "authorization", "cache-control", "connection", "content-encoding", "content-length", "content-range",
+    "content-type", "cookie", "date", "expect", "expires", "host", "if-match", "if-modified-since", "if-none-match",
+    "if-range", "if-unmodified-since", "keep-alive", "location", "max-forwards", "pragma", "proxy-authenticate",
+    "proxy-authorization", "proxy-connection", "range", "retry-after", "set-cookie", "te", "trailer",
+    "transfer-encoding", "upgrade", "vary", "warning", "www-authenticate",
+];
+
+/// Whether a field named `name` may be sent as a trailer
+pub fn allowed_in_trailers(name: &str) -> bool {
+    !NOT_TRAILERS.iter().any(|field| name.eq_ignore_ascii_case(field))
+}
+
+/// A header or trailer value, byte for byte
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct HeaderValueBytes(Vec<u8>);
+
+impl HeaderValueBytes {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The value as text, if it is UTF-8
+    pub fn to_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl From<Vec<u8>> for HeaderValueBytes {
+    fn from(value: Vec<u8>) -> Self {
+        Self(value)
+    }
+}
+
+impl From<&[u8]> for HeaderValueBytes {
+    fn from(value: &[u8]) -> Self {
+        Self(value.to_vec())
+    }
+}
+
+impl From<String> for HeaderValueBytes {
+    fn from(value: String) -> Self {
+        Self(value.into_bytes())
+    }
+}
+
+impl From<&str> for HeaderValueBytes {
+    fn from(value: &str) -> Self {
+        Self(value.as_bytes().to_vec())
+    }
+}
+
+impl AsRef<[u8]> for HeaderValueBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl PartialEq<str> for HeaderValueBytes {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for HeaderValueBytes {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl fmt::Debug for HeaderValueBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.to_str() {
+            Some(text) => fmt::Debug::fmt(text, f),
+            None => fmt::Debug::fmt(&String::from_utf8_lossy(&self.0), f),
+        }
+    }
+}
+
+impl Serialize for HeaderValueBytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.to_str() {
+            Some(text) => serializer.serialize_str(text),
+            None => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(BASE64_KEY, &encode_body(&self.0))?;
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for HeaderValueBytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = HeaderValueBytes;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a string or {\"base64\": string}")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        Ok(value.into())
+    }
+
+    fn visit_string<E: de::Error>(self, value: String) -> Result<Self::Value, E> {
+        Ok(value.into())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut value = None;
+        while let Some(key) = map.next_key::<String>()? {
+            if key != BASE64_KEY {
+                return Err(de::Error::unknown_field(&key, &[BASE64_KEY]));
+            }
+            let encoded: String = map.next_value()?;
+            value = Some(decode_body(&encoded).map_err(de::Error::custom)?);
+        }
+        value.map(HeaderValueBytes).ok_or_else(|| de::Error::missing_field(BASE64_KEY))
+    }
+}
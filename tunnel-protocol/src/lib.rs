@@ -1,84 +1,644 @@
+mod binary;
+mod compress;
+mod control;
+mod error;
+mod header;
+mod pool;
+mod schedule;
+mod stream;
+mod validate;
+mod vectors;
+
+pub use binary::{
+    binary_head, decode_request_frame, decode_response_frame, encode_binary_into, is_binary_frame, split_binary_frame, BINARY_ENCODING,
+    BINARY_FRAME_MARKER, BINARY_PREFIX_LEN, ENCODING_HEADER,
+};
+pub use compress::{
+    compress_frame, decompress_frame, inflate_frame, is_compressed_frame, should_compress, CompressTypes, Compression, CompressionMonitor,
+    CompressionStats, ADAPTIVE_MIN_SAVING_PERCENT, ADAPTIVE_PROBE_BYTES, ADAPTIVE_WINDOW_BYTES, COMPRESSED_MARKER, COMPRESSED_PREFIX_LEN,
+    COMPRESSION_HEADER, COMPRESS_MIN_BYTES,
+};
+pub use control::{classify_frame, is_control_frame, ControlFrame, Frame};
+pub use error::ProtocolError;
+pub use header::{allowed_in_trailers, HeaderValueBytes};
+pub use pool::BufferPool;
+pub use schedule::{Schedule, SCHEDULE_HEADER};
+pub use stream::{
+    body_chunk_prefix, decode_body_frame, encode_body_end, is_body_frame, BodyFrame, BODY_CHUNK_BYTES, BODY_CHUNK_MARKER, BODY_END_MARKER,
+    STREAM_CREDIT_HEADER, STREAM_HEADER, STREAM_PREFIX_LEN, STREAM_TRAILERS_HEADER,
+};
+pub use validate::{validate_headers, validate_method, validate_path, HeaderLimits, ValidationError, HEADER_LIMITS_HEADER};
+pub use vectors::{verify_roundtrip, FrameKind, RoundtripError, TestVector, TEST_VECTORS};
+
+use bytes::{Bytes, BytesMut};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, IoSlice};
+use std::sync::Arc;
+use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use std::io;
+
+/// Size of the big-endian length prefix in front of every frame
+pub const FRAME_HEADER_LEN: usize = 4;
+
+/// Largest frame payload accepted by the decoders (256 MiB).
+///
+/// Comfortably above a 100 MiB body once base64-encoded and wrapped in JSON, and
+/// low enough that a corrupt or hostile length prefix cannot make a reader
+/// allocate gigabytes before any payload byte arrives.
+pub const MAX_FRAME_LEN: usize = 256 * 1024 * 1024;
+
+/// Error returned by the pure frame and message decoders.
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    /// Input ended before the frame did
+    #[error("Truncated frame: need {needed} bytes, have {available}")]
+    Truncated { needed: usize, available: usize },
+
+    /// Length prefix exceeds `MAX_FRAME_LEN`
+    #[error("Frame of {len} bytes exceeds the {max} byte limit")]
+    FrameTooLarge { len: usize, max: usize },
+
+    /// Payload is not a valid JSON message
+    #[error("Malformed message JSON: {0}")]
+    InvalidMessage(#[source] serde_json::Error),
+
+    /// Payload is not a valid BODY_CHUNK or BODY_END frame
+    #[error("Malformed body frame: {0}")]
+    InvalidBodyFrame(&'static str),
+
+    /// Payload is not a valid COMPRESSED frame
+    #[error("Malformed compressed frame: {0}")]
+    InvalidCompressedFrame(&'static str),
+}
+
+impl From<DecodeError> for io::Error {
+    fn from(e: DecodeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
 
 /// Represents an HTTP request being forwarded from server to client through the tunnel.
 ///
 /// The server receives an HTTP request and converts it into this format for transmission
 /// over the TCP tunnel connection. The body is base64-encoded to support binary data.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct TunnelRequest {
+    /// Tag the client copies into its response, set on connections that
+    /// multiplex requests (see `MULTIPLEX_HEADER`); always the first field
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+
     /// HTTP method (GET, POST, PUT, DELETE, etc.)
     pub method: String,
 
     /// Full path including query string (e.g., "/api/v1/webhook?x=1")
     pub path: String,
 
-    /// Header name-value pairs
-    pub headers: Vec<(String, String)>,
+    /// Header name-value pairs in the order they came, repeated names
+    /// included; names spelled as the visitor sent them over HTTP/1.1
+    pub headers: Vec<(String, HeaderValueBytes)>,
 
-    /// Base64-encoded body bytes (supports binary data)
+    /// Base64-encoded body bytes (supports binary data); empty in binary
+    /// frames, which carry the body after the message
     pub body: String,
+
+    /// The body follows in BODY_CHUNK frames (see `STREAM_HEADER`); set in binary frames only
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub stream: bool,
+
+    /// Milliseconds the server will still wait for the response when it sends the
+    /// request; the client gives up on the local service after that. Absent from
+    /// older servers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline_ms: Option<u64>,
+
+    /// HTTP version the visitor used (e.g. "HTTP/1.1"). Absent from older servers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+
+    /// Trailer fields that followed the body, values as in `headers`; never
+    /// sent with streamed bodies
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trailers: Vec<(String, HeaderValueBytes)>,
 }
 
 /// Represents an HTTP response being sent from client back to server through the tunnel.
 ///
 /// The client receives a response from the local HTTP service and converts it into this
 /// format for transmission back to the server. The body is base64-encoded to support binary data.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct TunnelResponse {
+    /// The `id` of the request this answers, on connections that multiplex
+    /// requests; always the first field, so the server can read it with
+    /// `response_id` before the rest of the frame has arrived
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+
     /// HTTP status code (200, 404, 500, etc.)
     pub status: u16,
 
-    /// Header name-value pairs
-    pub headers: Vec<(String, String)>,
+    /// Header name-value pairs in the order they came, repeated names
+    /// included; names spelled as the local service sent them over HTTP/1.1
+    pub headers: Vec<(String, HeaderValueBytes)>,
+
+    /// Milliseconds the local service took, from sending it the request to the
+    /// end of its response body. Absent from older clients and from responses
+    /// the client made up itself (errors).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_duration_ms: Option<u64>,
 
-    /// Base64-encoded body bytes (supports binary data)
+    /// SHA-256 of the decoded body in lowercase hex, sent when the request
+    /// carried `BODY_SHA256_HEADER`; the server answers 502 instead of passing
+    /// on a body that does not match it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_sha256: Option<String>,
+
+    /// The body follows in BODY_CHUNK frames (see `STREAM_HEADER`); set in binary frames only
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub stream: bool,
+
+    /// HTTP version the local service answered with (e.g. "HTTP/1.1"). Absent
+    /// from older clients and from responses the client made up itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+
+    /// Trailer fields to send after the body, values as in `headers`; never
+    /// sent with streamed bodies
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trailers: Vec<(String, HeaderValueBytes)>,
+
+    /// Base64-encoded body bytes (supports binary data); always the last field.
+    /// Empty in binary frames, which carry the body after the message
     pub body: String,
 }
 
+/// Statistics the client reports about its side of the tunnel.
+///
+/// Sent only when the server asks for them with `STATS_HEADER` in its upgrade
+/// response, as a `ControlFrame::Stats` frame of its own, right before a
+/// response. The counters cover the client's lifetime, not just the current
+/// connection.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct StatsReport {
+    /// Requests forwarded to the local service
+    pub requests: u64,
+
+    /// Requests answered with a 5xx status, by the local service or by the client itself
+    pub errors: u64,
+
+    /// Local round-trip latency percentiles over recent requests, in milliseconds
+    pub latency_p50_ms: u64,
+    pub latency_p90_ms: u64,
+    pub latency_p99_ms: u64,
+
+    /// Seconds since the client started
+    pub uptime_secs: u64,
+
+    /// Resident memory of the client process, where the platform reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rss_bytes: Option<u64>,
+
+    /// Times memory use went over the client's `MEMORY_LIMIT_BYTES`
+    #[serde(default)]
+    pub memory_warnings: u64,
+
+    /// Request bodies that did not match their `BODY_SHA256_HEADER`
+    #[serde(default)]
+    pub checksum_mismatches: u64,
+
+    /// Captured requests the client dropped to keep its history within `CAPTURE_MAX_TOTAL_BYTES`
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub capture_evictions: u64,
+
+    /// Compression of the frames the client sends on the current connection (None: compression is off)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<CompressionStats>,
+
+    /// Responses the local service tagged with `TAG_HEADER`, by tag key
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tagged: BTreeMap<String, u64>,
+}
+
+/// Frame a client sends when it is shutting down (GOAWAY)
+///
+/// The server routes no new requests to the connection, answering them with
+/// 503 and `Retry-After` instead, and closes it once the requests already
+/// queued are answered. Like `ControlFrame::Stats` it may arrive ahead of a response.
+pub const GOAWAY_FRAME: &[u8] = br#"{"goaway":{}}"#;
+
+/// Frame either end sends on an interval, on connections that agreed to it
+/// with `HEARTBEAT_HEADER`, to check the other end is still there
+///
+/// It is answered with `PONG_FRAME`; the sender drops the connection when
+/// none arrives in time. Like GOAWAY it may arrive between or ahead of other frames.
+pub const PING_FRAME: &[u8] = br#"{"ping":{}}"#;
+
+/// Answer to a `PING_FRAME`
+pub const PONG_FRAME: &[u8] = br#"{"pong":{}}"#;
+
+/// Upgrade header with which the client offers heartbeats (`true`); the
+/// server echoes it when it agrees. Both ends then answer every PING with a
+/// PONG, whether or not they send PINGs of their own.
+pub const HEARTBEAT_HEADER: &str = "x-tunnel-heartbeat";
+
+/// Upgrade response header with which the server asks the client for
+/// `StatsReport`s; the value is the reporting interval in seconds.
+pub const STATS_HEADER: &str = "x-tunnel-stats";
+
+/// Upgrade response header with the ID the server gave the tunnel connection
+/// (the one in its logs and admin API). With `LOCAL_TUNNEL_HEADERS` enabled
+/// the client passes it to the local service in a header of the same name.
+pub const TUNNEL_ID_HEADER: &str = "x-tunnel-id";
+
+/// Upgrade response header with the address the server sees the tunnel
+/// client connect from; passed on like `TUNNEL_ID_HEADER`.
+pub const CLIENT_ADDR_HEADER: &str = "x-tunnel-client-addr";
+
+/// Request header with the tunnel round-trip time the client measured at
+/// upgrade, in milliseconds, added with `LOCAL_TUNNEL_HEADERS` enabled.
+pub const LATENCY_HEADER: &str = "x-tunnel-latency-ms";
+
+/// Upgrade header with which the client offers to handle requests
+/// concurrently; the value is how many it takes at once. The server echoes it
+/// in its upgrade response when it agrees, then writes up to that many
+/// requests without waiting for their responses, each tagged with an `id`
+/// the client copies into its `TunnelResponse`. Responses may come back in
+/// any order. Without the echo, requests are answered one at a time.
+pub const MULTIPLEX_HEADER: &str = "x-tunnel-multiplex";
+
+/// Upgrade header with which a multiplexing client says it takes
+/// `ControlFrame::Cancel` frames (`true`). The server then tells it about
+/// requests it gave up on (visitor gone, timed out), and the client aborts
+/// them and answers them anyway, so every request still gets one response.
+pub const CANCEL_HEADER: &str = "x-tunnel-cancel";
+
+/// Most bytes of a frame `response_id` needs to see: a binary frame's prefix and `{"id":` with 20 digits and `,`
+pub const RESPONSE_ID_PREFIX_LEN: usize = 32;
+
 /// Writes a length-prefixed frame to a writer.
 ///
 /// Frame format: [4 bytes: u32 big-endian length][N bytes: payload]
 ///
+/// Header and payload go out in a single vectored write, followed by one flush.
+///
 /// # Arguments
 /// * `writer` - The async writer to write to
 /// * `payload` - The bytes to write
 ///
 /// # Returns
 /// * `Ok(())` on success
-/// * `Err` if writing fails
+/// * `Err` if writing fails or the payload exceeds `MAX_FRAME_LEN`
 pub async fn write_frame<W: AsyncWrite + Unpin>(
     writer: &mut W,
     payload: &[u8]
-) -> io::Result<()> {
-    let len = payload.len() as u32;
-    writer.write_all(&len.to_be_bytes()).await?;
-    writer.write_all(payload).await?;
+) -> Result<(), ProtocolError> {
+    let header = frame_header(payload)?;
+    write_all_vectored(writer, &mut [IoSlice::new(&header), IoSlice::new(payload)]).await?;
     writer.flush().await?;
     Ok(())
 }
 
+/// Frame writer that can coalesce small frames into a single write.
+///
+/// With a coalesce limit of 0, every frame is written and flushed immediately,
+/// exactly like `write_frame`. With a limit, frames are buffered while the
+/// buffered total stays within it and go out together with the next large frame
+/// or on `flush`. Callers must `flush` before waiting for the peer to answer.
+///
+/// With compression, frames of COMPRESS_MIN_BYTES and more are sent as
+/// COMPRESSED frames where that makes them smaller; one written in pieces is
+/// held until complete for that, unless larger than MAX_HELD_FOR_COMPRESSION.
+pub struct FrameWriter<W> {
+    writer: W,
+    pending: BytesMut,
+    coalesce_limit: usize,
+    compression: Option<Compression>,
+    compressed: Vec<u8>,  // Scratch buffer for COMPRESSED frames
+    held: Option<(BytesMut, usize)>,  // Frame being written in pieces, to be compressed once it has this many bytes
+    skip_next: bool,  // The next frame goes out as it is (see `skip_compression`)
+    monitor: Arc<CompressionMonitor>,  // Whether compressing still pays off
+}
+
+/// Largest frame written in pieces that is held to be compressed; larger ones go out as they are
+pub const MAX_HELD_FOR_COMPRESSION: usize = 1024 * 1024;
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    /// Wraps `writer`; frames are coalesced while buffered bytes stay within `coalesce_limit`
+    pub fn new(writer: W, coalesce_limit: usize) -> Self {
+        Self {
+            writer,
+            pending: BytesMut::new(),
+            coalesce_limit,
+            compression: None,
+            compressed: Vec::new(),
+            held: None,
+            skip_next: false,
+            monitor: Arc::default(),
+        }
+    }
+
+    /// Compresses frames with `compression`, as agreed with the peer (see `COMPRESSION_HEADER`; None: off)
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Records the ratio of compressed frames in `monitor`, e.g. one the admin API reads too
+    pub fn with_compression_monitor(mut self, monitor: Arc<CompressionMonitor>) -> Self {
+        self.monitor = monitor;
+        self
+    }
+
+    /// Compression figures of the frames written (None: compression is off)
+    pub fn compression_stats(&self) -> Option<CompressionStats> {
+        self.compression.map(|_| self.monitor.stats())
+    }
+
+    /// Writes the next frame, whole or begun with `start_frame`, without trying to compress it
+    ///
+    /// For payloads known not to shrink, e.g. bodies compressed already (see `should_compress`).
+    pub fn skip_compression(&mut self) {
+        self.skip_next = true;
+    }
+
+    /// Returns the underlying writer
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Writes a frame, buffering it if it fits within the coalesce limit
+    ///
+    /// # Returns
+    /// * `Ok(())` once the frame is written or buffered
+    /// * `Err` if writing fails or the payload exceeds `MAX_FRAME_LEN`
+    pub async fn write_frame(&mut self, payload: &[u8]) -> Result<(), ProtocolError> {
+        let skip = std::mem::take(&mut self.skip_next);
+        let Some(compression) = self.compression.filter(|_| !skip && payload.len() >= COMPRESS_MIN_BYTES) else {
+            return self.write_plain_frame(payload).await;
+        };
+        if !self.monitor.try_next(payload.len()) {
+            return self.write_plain_frame(payload).await;
+        }
+        let mut compressed = std::mem::take(&mut self.compressed);
+        let result = match compress_frame(compression, payload, &mut compressed) {
+            Ok(true) => {
+                self.monitor.record(payload.len(), compressed.len());
+                self.write_plain_frame(&compressed).await
+            }
+            Ok(false) => {
+                self.monitor.record(payload.len(), payload.len());
+                self.write_plain_frame(payload).await
+            }
+            Err(e) => Err(e.into()),
+        };
+        // Kept for the next frame unless a large one grew it
+        if compressed.capacity() <= MAX_HELD_FOR_COMPRESSION {
+            self.compressed = compressed;
+        }
+        result
+    }
+
+    /// Writes a frame as it is, buffering it if it fits within the coalesce limit
+    async fn write_plain_frame(&mut self, payload: &[u8]) -> Result<(), ProtocolError> {
+        let header = frame_header(payload)?;
+
+        if self.pending.len() + FRAME_HEADER_LEN + payload.len() <= self.coalesce_limit {
+            self.pending.extend_from_slice(&header);
+            self.pending.extend_from_slice(payload);
+            return Ok(());
+        }
+
+        // Buffered frames, header and payload in one vectored write
+        write_all_vectored(
+            &mut self.writer,
+            &mut [IoSlice::new(&self.pending), IoSlice::new(&header), IoSlice::new(payload)],
+        )
+        .await?;
+        self.pending.clear();
+        Ok(self.writer.flush().await?)
+    }
+
+    /// Starts a frame of `len` bytes whose payload follows in `write_payload` calls
+    ///
+    /// For payloads too large to hold in memory at once. Buffered frames go out
+    /// first; the caller must then write exactly `len` bytes, or the stream is
+    /// corrupt.
+    ///
+    /// # Returns
+    /// * `Ok(())` once the length prefix is written
+    /// * `Err` if writing fails or `len` exceeds `MAX_FRAME_LEN`
+    pub async fn start_frame(&mut self, len: usize) -> Result<(), ProtocolError> {
+        let header = frame_len_header(len)?;
+        let skip = std::mem::take(&mut self.skip_next);
+        if self.compression.is_some() && !skip && (COMPRESS_MIN_BYTES..=MAX_HELD_FOR_COMPRESSION).contains(&len) {
+            // Held whole only while compressing pays off; `write_frame` tells the monitor then
+            if self.monitor.try_next(len) {
+                self.held = Some((BytesMut::with_capacity(len), len));
+                return Ok(());
+            }
+        }
+        write_all_vectored(&mut self.writer, &mut [IoSlice::new(&self.pending), IoSlice::new(&header)]).await?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Writes the next piece of the payload of a frame begun with `start_frame`
+    pub async fn write_payload(&mut self, chunk: &[u8]) -> Result<(), ProtocolError> {
+        let Some((held, len)) = &mut self.held else {
+            return Ok(self.writer.write_all(chunk).await?);
+        };
+        held.extend_from_slice(chunk);
+        if held.len() < *len {
+            return Ok(());
+        }
+        let (held, _) = self.held.take().expect("checked above");
+        self.write_frame(&held).await
+    }
+
+    /// Writes out any buffered frames and flushes the underlying writer
+    pub async fn flush(&mut self) -> Result<(), ProtocolError> {
+        if !self.pending.is_empty() {
+            self.writer.write_all(&self.pending).await?;
+            self.pending.clear();
+        }
+        Ok(self.writer.flush().await?)
+    }
+}
+
+/// Builds the length prefix for `payload`, rejecting payloads above `MAX_FRAME_LEN`
+fn frame_header(payload: &[u8]) -> Result<[u8; FRAME_HEADER_LEN], ProtocolError> {
+    frame_len_header(payload.len())
+}
+
+/// Builds the length prefix for a payload of `len` bytes, rejecting lengths above `MAX_FRAME_LEN`
+fn frame_len_header(len: usize) -> Result<[u8; FRAME_HEADER_LEN], ProtocolError> {
+    if len > MAX_FRAME_LEN {
+        return Err(ProtocolError::FrameTooLarge { len, max: MAX_FRAME_LEN });
+    }
+    Ok((len as u32).to_be_bytes())
+}
+
+/// Writes every slice, retrying after partial vectored writes
+async fn write_all_vectored<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    mut bufs: &mut [IoSlice<'_>],
+) -> io::Result<()> {
+    // Skip leading empty slices (e.g. no pending frames)
+    IoSlice::advance_slices(&mut bufs, 0);
+
+    while !bufs.is_empty() {
+        let written = writer.write_vectored(bufs).await?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        IoSlice::advance_slices(&mut bufs, written);
+    }
+    Ok(())
+}
+
 /// Reads a length-prefixed frame from a reader.
 ///
 /// Frame format: [4 bytes: u32 big-endian length][N bytes: payload]
 ///
+/// As on a connection without compression, a COMPRESSED frame is refused;
+/// read those with `read_frame_into`.
+///
 /// # Arguments
 /// * `reader` - The async reader to read from
 ///
 /// # Returns
-/// * `Ok(Vec<u8>)` containing the payload on success
-/// * `Err` if reading fails or connection is closed
+/// * `Ok(Bytes)` containing the payload on success
+/// * `Err(ProtocolError::Closed)` if the connection is closed
+/// * `Err(ProtocolError::FrameTooLarge)` if the length exceeds `MAX_FRAME_LEN`
+/// * `Err(ProtocolError::Unexpected)` if it is a COMPRESSED frame
+/// * `Err` if reading fails otherwise
 pub async fn read_frame<R: AsyncRead + Unpin>(
     reader: &mut R
-) -> io::Result<Vec<u8>> {
-    let mut len_bytes = [0u8; 4];
+) -> Result<Bytes, ProtocolError> {
+    let mut payload = BytesMut::new();
+    read_frame_into(reader, &mut payload, None).await?;
+    Ok(payload.freeze())
+}
+
+/// Reads a length-prefixed frame into a reusable buffer.
+///
+/// `buf` is cleared first and holds exactly the payload afterwards. Reading
+/// every frame of a connection into the same buffer reuses its allocation.
+/// A COMPRESSED frame is decompressed, so `buf` holds the payload it stands for.
+///
+/// # Arguments
+/// * `reader` - The async reader to read from
+/// * `buf` - Buffer receiving the payload
+/// * `compression` - Compression the connection agreed to (None: off)
+///
+/// # Returns
+/// * `Ok(())` on success
+/// * `Err(ProtocolError::Closed)` if the connection is closed
+/// * `Err(ProtocolError::FrameTooLarge)` if the length exceeds `MAX_FRAME_LEN`
+/// * `Err(ProtocolError::Corrupt)` if a COMPRESSED frame does not decompress
+/// * `Err(ProtocolError::Unexpected)` if a COMPRESSED frame arrives with compression off
+/// * `Err` if reading fails otherwise
+pub async fn read_frame_into<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut BytesMut,
+    compression: Option<Compression>,
+) -> Result<(), ProtocolError> {
+    let mut len_bytes = [0u8; FRAME_HEADER_LEN];
     reader.read_exact(&mut len_bytes).await?;
     let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(ProtocolError::FrameTooLarge { len, max: MAX_FRAME_LEN });
+    }
+
+    buf.clear();
+    buf.resize(len, 0);
+    reader.read_exact(buf).await?;
+    inflate_frame(buf, compression)
+}
+
+/// Decodes one length-prefixed frame from the start of a byte buffer.
+///
+/// Pure counterpart of `read_frame` for buffered input and fuzzing: never panics
+/// and never allocates, whatever the input.
+///
+/// # Arguments
+/// * `input` - Bytes starting with a frame header
+///
+/// # Returns
+/// * `Ok((payload, consumed))` with the payload and the total bytes the frame occupies
+/// * `Err(DecodeError::Truncated)` if `input` holds less than one full frame
+/// * `Err(DecodeError::FrameTooLarge)` if the length exceeds `MAX_FRAME_LEN`
+pub fn decode_frame_bytes(input: &[u8]) -> Result<(&[u8], usize), DecodeError> {
+    let Some(len_bytes) = input.first_chunk::<FRAME_HEADER_LEN>() else {
+        return Err(DecodeError::Truncated { needed: FRAME_HEADER_LEN, available: input.len() });
+    };
+
+    let len = u32::from_be_bytes(*len_bytes) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(DecodeError::FrameTooLarge { len, max: MAX_FRAME_LEN });
+    }
 
-    let mut payload = vec![0u8; len];
-    reader.read_exact(&mut payload).await?;
-    Ok(payload)
+    let consumed = FRAME_HEADER_LEN + len;
+    match input.get(FRAME_HEADER_LEN..consumed) {
+        Some(payload) => Ok((payload, consumed)),
+        None => Err(DecodeError::Truncated { needed: consumed, available: input.len() }),
+    }
+}
+
+/// Decodes a frame payload into a `TunnelRequest`.
+///
+/// # Returns
+/// * `Ok(TunnelRequest)` on success
+/// * `Err(DecodeError::InvalidMessage)` if the payload is not a valid request
+pub fn decode_tunnel_request(payload: &[u8]) -> Result<TunnelRequest, DecodeError> {
+    serde_json::from_slice(payload).map_err(DecodeError::InvalidMessage)
+}
+
+/// Decodes a frame payload into a `TunnelResponse`.
+///
+/// # Returns
+/// * `Ok(TunnelResponse)` on success
+/// * `Err(DecodeError::InvalidMessage)` if the payload is not a valid response
+pub fn decode_tunnel_response(payload: &[u8]) -> Result<TunnelResponse, DecodeError> {
+    serde_json::from_slice(payload).map_err(DecodeError::InvalidMessage)
+}
+
+/// The `id` at the start of a `TunnelResponse` frame (`{"id":N,...`), if it has one
+///
+/// Only the first `RESPONSE_ID_PREFIX_LEN` bytes are looked at; in a binary
+/// frame the JSON starts after the `BINARY_PREFIX_LEN` bytes in front of it.
+pub fn response_id(frame: &[u8]) -> Option<u64> {
+    let json = if is_binary_frame(frame) { frame.get(BINARY_PREFIX_LEN..)? } else { frame };
+    let rest = json.strip_prefix(br#"{"id":"#)?;
+    let digits = rest.iter().take_while(|byte| byte.is_ascii_digit()).count();
+    if digits == 0 || !matches!(rest.get(digits), Some(b',' | b'}')) {
+        return None;
+    }
+    std::str::from_utf8(&rest[..digits]).ok()?.parse().ok()
+}
+
+/// Writes `payload`, a serialized `TunnelRequest` without an `id`, as one frame tagged with `id`
+///
+/// The tag is spliced in front of the JSON rather than serializing the request
+/// again; in a binary frame the head length grows to match.
+pub async fn write_tagged_request<W: AsyncWrite + Unpin>(writer: &mut FrameWriter<W>, id: u64, payload: &[u8]) -> Result<(), ProtocolError> {
+    let (json, body) = if is_binary_frame(payload) { split_binary_frame(payload)? } else { (payload, &[][..]) };
+    let Some(fields) = json.strip_prefix(b"{") else {
+        return Err(ProtocolError::Io(io::Error::new(io::ErrorKind::InvalidInput, "request payload is not a JSON object")));
+    };
+    let tag = format!(r#"{{"id":{}{}"#, id, if fields.starts_with(b"}") { "" } else { "," });
+    let json_len = tag.len() + fields.len();
+    if is_binary_frame(payload) {
+        writer.start_frame(BINARY_PREFIX_LEN + json_len + body.len()).await?;
+        writer.write_payload(&[BINARY_FRAME_MARKER]).await?;
+        writer.write_payload(&(json_len as u32).to_be_bytes()).await?;
+    } else {
+        writer.start_frame(json_len).await?;
+    }
+    writer.write_payload(tag.as_bytes()).await?;
+    writer.write_payload(fields).await?;
+    writer.write_payload(body).await
 }
 
 /// Encodes binary body bytes as base64 string.
@@ -93,6 +653,11 @@ pub fn encode_body(body_bytes: &[u8]) -> String {
     STANDARD.encode(body_bytes)
 }
 
+/// Length of `len` body bytes once encoded by `encode_body`
+pub fn encoded_body_len(len: usize) -> usize {
+    len.div_ceil(3) * 4
+}
+
 /// Decodes base64 string to binary body bytes.
 ///
 /// # Arguments
@@ -105,3 +670,186 @@ pub fn decode_body(encoded: &str) -> Result<Vec<u8>, base64::DecodeError> {
     use base64::{Engine as _, engine::general_purpose::STANDARD};
     STANDARD.decode(encoded)
 }
+
+/// Request header carrying the SHA-256 of the request body, in lowercase hex.
+///
+/// With `TUNNEL_BODY_SHA256` enabled the server adds it to every forwarded
+/// request; the client checks the body it decoded against it before calling
+/// the local service, which may check it too, and answers with the checksum
+/// of the response body in `TunnelResponse::body_sha256`.
+pub const BODY_SHA256_HEADER: &str = "x-tunnel-body-sha256";
+
+/// SHA-256 of `body` as lowercase hex, the value of `BODY_SHA256_HEADER`
+pub fn body_sha256(body: &[u8]) -> String {
+    let mut hasher = BodySha256::default();
+    hasher.update(body);
+    hasher.finish()
+}
+
+/// `body_sha256` of a body fed in pieces, for bodies never held whole
+#[derive(Default)]
+pub struct BodySha256(sha2::Sha256);
+
+impl BodySha256 {
+    pub fn update(&mut self, chunk: &[u8]) {
+        use sha2::Digest;
+        self.0.update(chunk);
+    }
+
+    /// SHA-256 of everything fed so far, as lowercase hex
+    pub fn finish(self) -> String {
+        use sha2::Digest;
+        self.0.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+impl TunnelResponse {
+    /// JSON of this response split where its body goes, for writing a body
+    /// too large to hold in memory: the encoded body belongs between the parts.
+    ///
+    /// `body` must be empty.
+    pub fn json_around_body(&self) -> Result<(Vec<u8>, &'static [u8]), serde_json::Error> {
+        debug_assert!(self.body.is_empty());
+        let mut json = serde_json::to_vec(self)?;
+        // `body` is the last field, so the JSON ends with `"body":""}`
+        const TAIL: &[u8] = b"\"}";
+        json.truncate(json.len() - TAIL.len());
+        Ok((json, TAIL))
+    }
+
+    /// The `key=value` tags the local service set with `TAG_HEADER`, malformed ones left out
+    pub fn tags(&self) -> Vec<(String, String)> {
+        self.headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(TAG_HEADER))
+            .filter_map(|(_, value)| value.to_str())
+            .flat_map(|value| value.split(','))
+            .filter_map(|tag| parse_label(tag).ok())
+            .collect()
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+/// Path of the upgrade endpoint unless both ends set `TUNNEL_PATH`
+pub const DEFAULT_TUNNEL_PATH: &str = "/tunnel";
+
+/// Upgrade request header carrying the preshared `TUNNEL_UPGRADE_SECRET`.
+///
+/// A server with a secret set treats an upgrade request without it like any
+/// other public request, so the endpoint cannot be told apart from the site.
+pub const UPGRADE_SECRET_HEADER: &str = "x-tunnel-secret";
+
+/// Upgrade request header carrying the base64 `username:password` visitors
+/// must present (HTTP Basic auth) for requests through this tunnel.
+///
+/// The server answers visitor requests without these credentials with 401
+/// and strips the Authorization header from those it forwards. A value that
+/// does not decode to `username:password` fails the upgrade with 400.
+pub const VISITOR_AUTH_HEADER: &str = "x-tunnel-visitor-auth";
+
+/// Upgrade request header asking the server to serve this tunnel over HTTPS
+/// only (`true`).
+///
+/// The server redirects visitor requests that arrived over plain HTTP to the
+/// same URL on `https://` with 308, and adds Strict-Transport-Security to the
+/// responses it sends over HTTPS.
+pub const HTTPS_ONLY_HEADER: &str = "x-tunnel-https-only";
+
+/// Upgrade request header asking the server to handle CORS for this tunnel:
+/// `*` for any origin, or a comma-separated list of origins (see
+/// [`parse_cors_origins`]).
+///
+/// The server answers preflight requests itself and adds the CORS headers to
+/// responses for allowed origins. A malformed value fails the upgrade with 400.
+pub const CORS_HEADER: &str = "x-tunnel-cors";
+
+/// Upgrade request header carrying one `key=value` tunnel label.
+///
+/// The client sends one header per label; the server records them for the
+/// lifetime of the tunnel connection (logs, admin API).
+pub const LABEL_HEADER: &str = "x-tunnel-label";
+
+/// Response header the local service sets to tag a request with `key=value`
+/// pairs (comma-separated, or one header each), e.g. an order ID or event type.
+///
+/// The client records the tags in its access log, captures and stats; the
+/// server logs them too and strips the header before the visitor sees it.
+/// Malformed tags (see [`parse_label`]) are ignored.
+pub const TAG_HEADER: &str = "x-tunnel-tag";
+
+/// Tags as written in access logs: ` [key=value key=value]`, or nothing without tags
+pub fn format_tags(tags: &[(String, String)]) -> String {
+    if tags.is_empty() {
+        return String::new();
+    }
+    let tags: Vec<String> = tags.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+    format!(" [{}]", tags.join(" "))
+}
+
+/// Parses a CORS origin list: `*` (any origin), or comma-separated origins
+/// such as `https://app.example.com,http://localhost:5173`.
+///
+/// Origins are `scheme://host[:port]` without a path, and come back in
+/// lowercase. An empty list is an error.
+pub fn parse_cors_origins(value: &str) -> Result<Vec<String>, String> {
+    let origins: Vec<String> = value
+        .split(',')
+        .map(|origin| origin.trim().to_ascii_lowercase())
+        .filter(|origin| !origin.is_empty())
+        .collect();
+    if origins.is_empty() {
+        return Err("Empty CORS origin list".to_string());
+    }
+    if origins.len() > 1 && origins.iter().any(|origin| origin == "*") {
+        return Err("'*' cannot be combined with other CORS origins".to_string());
+    }
+    for origin in origins.iter().filter(|origin| *origin != "*") {
+        let host = origin
+            .strip_prefix("https://")
+            .or_else(|| origin.strip_prefix("http://"))
+            .ok_or_else(|| format!("Invalid CORS origin '{}': expected http:// or https://", origin))?;
+        let valid_host = !host.is_empty()
+            && host.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | ':' | '[' | ']'));
+        if !valid_host {
+            return Err(format!("Invalid CORS origin '{}': expected scheme://host[:port]", origin));
+        }
+    }
+    Ok(origins)
+}
+
+/// Parses a `key=value` tunnel label.
+///
+/// Keys must be non-empty and may only contain ASCII letters, digits, `-`, `_` and `.`.
+/// Values may not contain control characters (they travel in an HTTP header).
+///
+/// # Arguments
+/// * `label` - The label string to parse
+///
+/// # Returns
+/// * `Ok((key, value))` on success
+/// * `Err` with a description if the label is malformed
+pub fn parse_label(label: &str) -> Result<(String, String), String> {
+    let (key, value) = label
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid label '{}': expected key=value", label))?;
+
+    let key = key.trim();
+    let valid_key = !key.is_empty()
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    if !valid_key {
+        return Err(format!("Invalid label key '{}'", key));
+    }
+
+    if value.chars().any(|c| c.is_control()) {
+        return Err(format!("Invalid label value for '{}': control characters are not allowed", key));
+    }
+
+    Ok((key.to_string(), value.trim().to_string()))
+}
@@ -0,0 +1,61 @@
+//! Reusable byte buffers for building and reading frames.
+
+use bytes::BytesMut;
+use std::sync::Mutex;
+
+/// Pool of reusable `BytesMut` buffers.
+///
+/// Serializing a message into a pooled buffer and returning the buffer once the
+/// frame is written avoids a fresh allocation per message. Buffers that grew
+/// beyond `max_capacity` (one large body) are dropped instead of pooled, so the
+/// pool never pins more than `max_pooled * max_capacity` bytes.
+pub struct BufferPool {
+    buffers: Mutex<Vec<BytesMut>>,
+    max_pooled: usize,
+    max_capacity: usize,
+}
+
+impl BufferPool {
+    /// Creates an empty pool (usable in a `static`)
+    pub const fn new(max_pooled: usize, max_capacity: usize) -> Self {
+        Self {
+            buffers: Mutex::new(Vec::new()),
+            max_pooled,
+            max_capacity,
+        }
+    }
+
+    /// Takes an empty buffer from the pool, or allocates one if the pool is empty
+    pub fn get(&self) -> BytesMut {
+        self.buffers
+            .lock()
+            .ok()
+            .and_then(|mut buffers| buffers.pop())
+            .unwrap_or_default()
+    }
+
+    /// Drops every pooled buffer, e.g. to give memory back under pressure
+    pub fn clear(&self) {
+        if let Ok(mut buffers) = self.buffers.lock() {
+            buffers.clear();
+        }
+    }
+
+    /// Returns a buffer to the pool
+    ///
+    /// A buffer whose contents were split off and frozen into `Bytes` can be
+    /// returned right away: its allocation is reclaimed on the next use once
+    /// those `Bytes` have been dropped.
+    pub fn put(&self, mut buf: BytesMut) {
+        if buf.capacity() > self.max_capacity {
+            return;
+        }
+        buf.clear();
+
+        if let Ok(mut buffers) = self.buffers.lock() {
+            if buffers.len() < self.max_pooled {
+                buffers.push(buf);
+            }
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Weekly time windows a tunnel is reachable in (`TUNNEL_SCHEDULE`).
+
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Upgrade request header restricting the tunnel to a weekly schedule (see
+/// [`Schedule::parse`]).
+///
+/// Outside its windows the server answers visitor requests with 503 instead of
+/// forwarding them; the tunnel itself stays connected. A malformed value fails
+/// the upgrade with 400.
+pub const SCHEDULE_HEADER: &str = "x-tunnel-schedule";
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// One window: a set of weekdays and the minutes of the day it spans
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Window {
+    days: [bool; 7],  // Indexed from Monday
+    start: u32,       // Minute of the day the window opens
+    end: u32,         // Minute it closes; at or before `start`, on the next day
+}
+
+impl Window {
+    fn contains(&self, day: usize, minute: u32) -> bool {
+        if self.start < self.end {
+            return self.days[day] && (self.start..self.end).contains(&minute);
+        }
+        // Overnight: the part after midnight belongs to the day the window opened
+        (self.days[day] && minute >= self.start) || (self.days[(day + 6) % 7] && minute < self.end)
+    }
+}
+
+/// Weekly schedule such as `mon-fri 09:00-18:00, sat 10:00-13:00 utc+02:00`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    windows: Vec<Window>,
+    utc_offset: i32,  // Minutes added to UTC to get the time the windows are in
+}
+
+impl Schedule {
+    /// Parses comma-separated windows of `<days> <HH:MM>-<HH:MM>`, optionally
+    /// followed by the UTC offset they are in (`utc+02:00`, `utc-05:30`;
+    /// default UTC).
+    ///
+    /// Days are `daily`, one of `mon` .. `sun`, or a range such as `mon-fri`
+    /// (which may wrap, e.g. `fri-mon`). A window ending at or before its
+    /// start runs past midnight (`22:00-02:00`); `24:00` ends at midnight.
+    /// The offset is fixed: switching to daylight saving time means updating it.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut value = value.trim();
+        let mut utc_offset = 0;
+        if let Some((windows, last)) = value.rsplit_once(char::is_whitespace) {
+            if last.to_ascii_lowercase().starts_with("utc") {
+                utc_offset = parse_offset(&last[3..]).ok_or_else(|| format!("Invalid UTC offset '{}': expected utc+HH:MM", last))?;
+                value = windows;
+            }
+        }
+
+        let windows = value
+            .split(',')
+            .map(str::trim)
+            .filter(|window| !window.is_empty())
+            .map(parse_window)
+            .collect::<Result<Vec<_>, _>>()?;
+        if windows.is_empty() {
+            return Err("Empty schedule".to_string());
+        }
+        Ok(Self { windows, utc_offset })
+    }
+
+    /// Whether `at` falls in one of the windows
+    pub fn contains(&self, at: SystemTime) -> bool {
+        let utc = at.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+        let local = utc + i64::from(self.utc_offset) * 60;
+        let days = local.div_euclid(86_400);
+        // 1970-01-01 was a Thursday
+        let day = (days + 3).rem_euclid(7) as usize;
+        let minute = (local.rem_euclid(86_400) / 60) as u32;
+        self.windows.iter().any(|window| window.contains(day, minute))
+    }
+}
+
+/// Canonical form, which [`Schedule::parse`] reads back
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, window) in self.windows.iter().enumerate() {
+            if index > 0 {
+                f.write_str(", ")?;
+            }
+            write_days(f, &window.days)?;
+            // A window ending at midnight reads back only as `24:00`, not as an empty `00:00-00:00`
+            let end = if window.end == 0 { MINUTES_PER_DAY } else { window.end };
+            write!(f, " {}-{}", format_minute(window.start), format_minute(end))?;
+        }
+        if self.utc_offset != 0 {
+            let sign = if self.utc_offset < 0 { '-' } else { '+' };
+            let offset = self.utc_offset.unsigned_abs();
+            write!(f, " utc{}{:02}:{:02}", sign, offset / 60, offset % 60)?;
+        }
+        Ok(())
+    }
+}
+
+/// Serialized in its canonical form, as in effective-configuration dumps
+impl Serialize for Schedule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+fn parse_window(window: &str) -> Result<Window, String> {
+    let invalid = |reason: &str| format!("Invalid schedule window '{}': {}", window, reason);
+    let fields: Vec<&str> = window.split_whitespace().collect();
+    let [days, times] = fields[..] else {
+        return Err(invalid("expected '<days> <HH:MM>-<HH:MM>'"));
+    };
+    let days = parse_days(&days.to_ascii_lowercase()).ok_or_else(|| invalid("expected daily, a day or a day range such as mon-fri"))?;
+    let (start, end) = times.split_once('-').ok_or_else(|| invalid("expected <HH:MM>-<HH:MM>"))?;
+    let start = parse_minute(start).filter(|&start| start < MINUTES_PER_DAY).ok_or_else(|| invalid("expected a start time from 00:00 to 23:59"))?;
+    let end = parse_minute(end).ok_or_else(|| invalid("expected an end time from 00:00 to 24:00"))?;
+    if start == end {
+        return Err(invalid("window is empty"));
+    }
+    // `24:00` is midnight at the end of the day, the same as `00:00` after an overnight start
+    let end = end % MINUTES_PER_DAY;
+    Ok(Window { days, start, end })
+}
+
+fn parse_days(days: &str) -> Option<[bool; 7]> {
+    let day = |name: &str| DAY_NAMES.iter().position(|&day| day == name);
+    let mut set = [false; 7];
+    if days == "daily" {
+        return Some([true; 7]);
+    }
+    let (first, last) = match days.split_once('-') {
+        Some((first, last)) => (day(first)?, day(last)?),
+        None => (day(days)?, day(days)?),
+    };
+    let mut index = first;
+    loop {
+        set[index] = true;
+        if index == last {
+            return Some(set);
+        }
+        index = (index + 1) % 7;
+    }
+}
+
+/// Minutes since midnight of `HH:MM`, up to `24:00`
+fn parse_minute(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    let minute = hours * 60 + minutes;
+    (minutes < 60 && minute <= MINUTES_PER_DAY).then_some(minute)
+}
+
+/// Minutes of `+HH:MM` or `-HH:MM` (empty: UTC itself), up to 14 hours either way
+fn parse_offset(offset: &str) -> Option<i32> {
+    if offset.is_empty() {
+        return Some(0);
+    }
+    let sign = match offset.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+    let minutes = parse_minute(&offset[1..]).filter(|&minutes| minutes <= 14 * 60)?;
+    Some(sign * minutes as i32)
+}
+
+fn format_minute(minute: u32) -> String {
+    format!("{:02}:{:02}", minute / 60, minute % 60)
+}
+
+fn write_days(f: &mut fmt::Formatter<'_>, days: &[bool; 7]) -> fmt::Result {
+    if days.iter().all(|&day| day) {
+        return f.write_str("daily");
+    }
+    // A set parsed from a range is one run of days, possibly wrapping past Sunday
+    let first = (0..7).find(|&index| days[index] && !days[(index + 6) % 7]).unwrap_or(0);
+    let count = days.iter().filter(|&&day| day).count();
+    let last = (first + count - 1) % 7;
+    if first == last {
+        f.write_str(DAY_NAMES[first])
+    } else {
+        write!(f, "{}-{}", DAY_NAMES[first], DAY_NAMES[last])
+    }
+}
@@ -0,0 +1,132 @@
+//! Body frames: a body too large to send in one frame, sent in pieces after its message.
+//!
+//! On connections that agreed to it with `STREAM_HEADER`, a request or
+//! response whose message has `stream` set is a binary frame with an empty
+//! body, followed by any number of BODY_CHUNK frames and one BODY_END frame,
+//! each tagged with the `id` of the request:
+//!
+//! ```text
+//! BODY_CHUNK: [1 byte: BODY_CHUNK_MARKER][8 bytes: u64 big-endian id][body bytes]
+//! BODY_END:   [1 byte: BODY_END_MARKER][8 bytes: u64 big-endian id][1 byte: complete][SHA-256 hex, optional][trailers, optional]
+//! ```
+//!
+//! Frames of other requests may come in between. `complete` is 0 when the
+//! sender gave up on the body (e.g. the visitor's upload broke off): the
+//! receiver must not treat what arrived as the whole body. The SHA-256, when
+//! present, is the `body_sha256` of the whole body. The trailers, when the
+//! body had any, are a JSON list of `[name, value]` pairs as in `trailers` of
+//! a message; they are only sent to a peer that agreed to them with
+//! `STREAM_TRAILERS_HEADER`, since an older one would read them as part of
+//! the SHA-256.
+//!
+//! With `STREAM_CREDIT_HEADER` agreed, the frames of each body are sent
+//! against credit, so a receiver never has to stop reading the connection
+//! for one slow body. Without it, a receiver whose queue for a body is full
+//! stops reading until it drains, holding up every other frame.
+
+use crate::{DecodeError, HeaderValueBytes};
+
+/// Upgrade header with which the client offers to stream bodies (`true`);
+/// the server echoes it when it agrees. Needs binary frames and multiplexing,
+/// whose request IDs tag the body frames.
+pub const STREAM_HEADER: &str = "x-tunnel-stream";
+
+/// Upgrade header with which the client offers to take and send trailers on
+/// BODY_END frames (`true`), along with `STREAM_HEADER`; the server echoes it
+/// when it agrees
+pub const STREAM_TRAILERS_HEADER: &str = "x-tunnel-stream-trailers";
+
+/// Upgrade header with which the client offers credit for streamed bodies,
+/// along with `STREAM_HEADER`; the value is how many body frames of one body
+/// it takes ahead of credit. The server echoes it with its own number when it
+/// agrees. Each end then sends at most the other's number of BODY_CHUNK and
+/// BODY_END frames of a body until `ControlFrame::Credit` for that body lets
+/// it send more, and grants a chunk of credit for each BODY_CHUNK it passes on.
+/// The server only agrees along with `CANCEL_HEADER`: it grants no more credit
+/// for a response body nobody reads any more, and cancels the request instead,
+/// whereupon the client ends the body with a BODY_END, credit or not.
+pub const STREAM_CREDIT_HEADER: &str = "x-tunnel-stream-credit";
+
+/// First byte of a BODY_CHUNK frame
+pub const BODY_CHUNK_MARKER: u8 = 1;
+
+/// First byte of a BODY_END frame
+pub const BODY_END_MARKER: u8 = 2;
+
+/// Bytes in front of the data of a BODY_CHUNK frame: the marker and the request id
+pub const STREAM_PREFIX_LEN: usize = 9;
+
+/// Body bytes sent per BODY_CHUNK frame
+pub const BODY_CHUNK_BYTES: usize = 64 * 1024;
+
+/// A BODY_CHUNK or BODY_END frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BodyFrame<'a> {
+    Chunk { id: u64, data: &'a [u8] },
+    End { id: u64, complete: bool, sha256: Option<&'a str>, trailers: Vec<(String, HeaderValueBytes)> },
+}
+
+impl BodyFrame<'_> {
+    /// The request the frame belongs to
+    pub fn id(&self) -> u64 {
+        match self {
+            BodyFrame::Chunk { id, .. } | BodyFrame::End { id, .. } => *id,
+        }
+    }
+}
+
+/// Whether a frame payload is a BODY_CHUNK or BODY_END frame
+pub fn is_body_frame(payload: &[u8]) -> bool {
+    matches!(payload.first(), Some(&(BODY_CHUNK_MARKER | BODY_END_MARKER)))
+}
+
+/// The start of a BODY_CHUNK frame for request `id`; the data follows it
+pub fn body_chunk_prefix(id: u64) -> [u8; STREAM_PREFIX_LEN] {
+    let mut prefix = [BODY_CHUNK_MARKER; STREAM_PREFIX_LEN];
+    prefix[1..].copy_from_slice(&id.to_be_bytes());
+    prefix
+}
+
+/// A BODY_END frame for request `id`
+pub fn encode_body_end(id: u64, complete: bool, sha256: Option<&str>, trailers: &[(String, HeaderValueBytes)]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(STREAM_PREFIX_LEN + 1 + sha256.map_or(0, str::len));
+    payload.push(BODY_END_MARKER);
+    payload.extend_from_slice(&id.to_be_bytes());
+    payload.push(u8::from(complete));
+    payload.extend_from_slice(sha256.unwrap_or_default().as_bytes());
+    if !trailers.is_empty() {
+        serde_json::to_writer(&mut payload, trailers).expect("header lists serialize");
+    }
+    payload
+}
+
+/// Decodes a BODY_CHUNK or BODY_END frame
+///
+/// # Returns
+/// * `Ok(frame)` on success
+/// * `Err(DecodeError::Truncated)` if the payload is shorter than its fixed fields
+/// * `Err(DecodeError::InvalidBodyFrame)` if it is not a body frame, its SHA-256 is not text or its trailers are not a header list
+pub fn decode_body_frame(payload: &[u8]) -> Result<BodyFrame<'_>, DecodeError> {
+    let Some(prefix) = payload.first_chunk::<STREAM_PREFIX_LEN>() else {
+        return Err(DecodeError::Truncated { needed: STREAM_PREFIX_LEN, available: payload.len() });
+    };
+    let id = u64::from_be_bytes(prefix[1..].try_into().expect("8 bytes"));
+    let rest = &payload[STREAM_PREFIX_LEN..];
+    match prefix[0] {
+        BODY_CHUNK_MARKER => Ok(BodyFrame::Chunk { id, data: rest }),
+        BODY_END_MARKER => {
+            let Some((&complete, rest)) = rest.split_first() else {
+                return Err(DecodeError::Truncated { needed: STREAM_PREFIX_LEN + 1, available: payload.len() });
+            };
+            // The hex digits of the SHA-256 never include the '[' starting the trailers
+            let (sha256, trailers) = rest.split_at(rest.iter().position(|&byte| byte == b'[').unwrap_or(rest.len()));
+            let sha256 = std::str::from_utf8(sha256).map_err(|_| DecodeError::InvalidBodyFrame("SHA-256 is not text"))?;
+            let trailers = match trailers {
+                [] => Vec::new(),
+                json => serde_json::from_slice(json).map_err(|_| DecodeError::InvalidBodyFrame("trailers are not a header list"))?,
+            };
+            Ok(BodyFrame::End { id, complete: complete != 0, sha256: Some(sha256).filter(|sha256| !sha256.is_empty()), trailers })
+        }
+        _ => Err(DecodeError::InvalidBodyFrame("not a body frame")),
+    }
+}
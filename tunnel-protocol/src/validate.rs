@@ -0,0 +1,137 @@
+//! RFC 7230 syntax checks for the methods and headers carried in messages,
+//! and the header limits each side announces at handshake.
+//!
+//! Both ends check before handing a message to their HTTP stack, so input that
+//! stack would reject or rewrite (e.g. an unparseable method becoming GET) is
+//! refused up front instead of being forwarded in a different shape.
+
+use serde::Serialize;
+use thiserror::Error;
+
+/// Why a method or header cannot be forwarded
+#[derive(Debug, Error, PartialEq)]
+pub enum ValidationError {
+    #[error("Invalid method: {0:?}")]
+    InvalidMethod(String),
+
+    #[error("Invalid request target: {0:?}")]
+    InvalidTarget(String),
+
+    #[error("Invalid header name: {0:?}")]
+    InvalidHeaderName(String),
+
+    #[error("Invalid value for header {0}")]
+    InvalidHeaderValue(String),
+
+    #[error("Too many headers: {count} (limit {max})")]
+    TooManyHeaders { count: usize, max: usize },
+
+    #[error("Headers too large: {bytes} bytes (limit {max})")]
+    HeadersTooLarge { bytes: usize, max: usize },
+}
+
+/// Upgrade header with which each side announces the `HeaderLimits` it
+/// enforces on the messages it receives, e.g. `count=100; bytes=65536`.
+/// The client sends it in the upgrade request, the server in the response.
+pub const HEADER_LIMITS_HEADER: &str = "x-tunnel-header-limits";
+
+/// Limits on the headers of one message
+///
+/// The receiver rejects messages above its limits; the sender checks against
+/// the receiver's announced limits first, so an oversized message fails with a
+/// clear status instead of a rejection from the far side.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct HeaderLimits {
+    pub max_count: usize,  // Header lines, counting each value of a repeated header
+    pub max_bytes: usize,  // Sum of name and value lengths
+}
+
+impl Default for HeaderLimits {
+    fn default() -> Self {
+        Self {
+            max_count: 100,
+            max_bytes: 64 * 1024,
+        }
+    }
+}
+
+impl HeaderLimits {
+    /// Checks `headers` against the limits
+    pub fn check<V: AsRef<[u8]>>(&self, headers: &[(String, V)]) -> Result<(), ValidationError> {
+        if headers.len() > self.max_count {
+            return Err(ValidationError::TooManyHeaders { count: headers.len(), max: self.max_count });
+        }
+        let bytes: usize = headers.iter().map(|(name, value)| name.len() + value.as_ref().len()).sum();
+        if bytes > self.max_bytes {
+            return Err(ValidationError::HeadersTooLarge { bytes, max: self.max_bytes });
+        }
+        Ok(())
+    }
+
+    /// Value for `HEADER_LIMITS_HEADER`
+    pub fn to_header_value(&self) -> String {
+        format!("count={}; bytes={}", self.max_count, self.max_bytes)
+    }
+
+    /// Parses a `HEADER_LIMITS_HEADER` value; missing or unknown parts are ignored
+    pub fn from_header_value(value: &str) -> Self {
+        let mut limits = Self::default();
+        for part in value.split(';') {
+            match part.trim().split_once('=') {
+                Some(("count", count)) => limits.max_count = count.trim().parse().unwrap_or(limits.max_count),
+                Some(("bytes", bytes)) => limits.max_bytes = bytes.trim().parse().unwrap_or(limits.max_bytes),
+                _ => {}
+            }
+        }
+        limits
+    }
+}
+
+/// `tchar` from RFC 7230 section 3.2.6
+fn is_tchar(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+/// Whether `value` is a `token`: one or more `tchar`s
+fn is_token(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(is_tchar)
+}
+
+/// Checks that a method is a `token` (RFC 7230 section 3.1.1)
+pub fn validate_method(method: &str) -> Result<(), ValidationError> {
+    if is_token(method) {
+        Ok(())
+    } else {
+        Err(ValidationError::InvalidMethod(method.to_string()))
+    }
+}
+
+/// Checks that a path is an `origin-form` request target (RFC 7230 section
+/// 5.3.1): a `/`, then visible ASCII characters other than `#`.
+///
+/// Absolute-form targets are reduced to this form by the server, which moves
+/// their host into the Host header; asterisk- and authority-form targets
+/// (`OPTIONS *`, `CONNECT host:port`) cannot be forwarded.
+pub fn validate_path(path: &str) -> Result<(), ValidationError> {
+    let visible = |byte: u8| (b'!'..=b'~').contains(&byte) && byte != b'#';
+    if path.starts_with('/') && path.bytes().all(visible) {
+        Ok(())
+    } else {
+        Err(ValidationError::InvalidTarget(path.to_string()))
+    }
+}
+
+/// Checks every header name is a `token` and every value a `field-value`
+/// (RFC 7230 section 3.2): visible characters, spaces, tabs and non-ASCII
+/// bytes, but no control characters such as CR, LF or NUL.
+pub fn validate_headers<V: AsRef<[u8]>>(headers: &[(String, V)]) -> Result<(), ValidationError> {
+    for (name, value) in headers {
+        if !is_token(name) {
+            return Err(ValidationError::InvalidHeaderName(name.clone()));
+        }
+        if !value.as_ref().iter().all(|&byte| byte == b'\t' || (byte >= b' ' && byte != 0x7f)) {
+            return Err(ValidationError::InvalidHeaderValue(name.clone()));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,187 @@
+//! Conformance vectors: canonical payloads of every frame kind, as this crate encodes them.
+//!
+//! Another implementation of the protocol can check that its encoder writes
+//! each `TEST_VECTORS` payload byte for byte and that its decoder reads them
+//! back; `verify_roundtrip` checks that a payload decodes here and encodes back
+//! to the same bytes, which also guards the wire format against refactors.
+//! Payloads are given without their 4-byte length prefix.
+//!
+//! COMPRESSED frames are left out: their bytes depend on the compressor's
+//! version, and only what they decompress to is part of the protocol.
+
+use bytes::BytesMut;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    body_chunk_prefix, classify_frame, decode_body_frame, decode_request_frame, decode_response_frame, encode_binary_into, encode_body_end,
+    BodyFrame, ControlFrame, DecodeError, Frame, GOAWAY_FRAME, PING_FRAME, PONG_FRAME,
+};
+
+/// What a frame payload holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameKind {
+    Request,    // `TunnelRequest`, JSON or binary
+    Response,   // `TunnelResponse`, JSON or binary
+    Stats,      // `ControlFrame::Stats`
+    Goaway,
+    Ping,
+    Pong,
+    Cancel,     // `ControlFrame::Cancel`
+    Credit,     // `ControlFrame::Credit`
+    BodyChunk,
+    BodyEnd,
+}
+
+/// One canonical frame payload
+#[derive(Debug, Clone, Copy)]
+pub struct TestVector {
+    pub name: &'static str,
+    pub kind: FrameKind,
+    pub payload: &'static [u8],
+}
+
+/// Canonical payloads, covering optional fields, both message encodings and every control frame
+pub const TEST_VECTORS: &[TestVector] = &[
+    TestVector {
+        name: "request_json",
+        kind: FrameKind::Request,
+        payload: br#"{"method":"GET","path":"/hello?name=world","headers":[["host","example.com"],["accept","*/*"]],"body":""}"#,
+    },
+    TestVector {
+        name: "request_json_all_fields",
+        kind: FrameKind::Request,
+        payload: br#"{"id":3,"method":"POST","path":"/webhook","headers":[["content-type","application/json"],["x-latin1",{"base64":"6XTp"}]],"body":"eyJvayI6dHJ1ZX0=","deadline_ms":30000}"#,
+    },
+    TestVector {
+        name: "response_json",
+        kind: FrameKind::Response,
+        payload: br#"{"status":404,"headers":[["content-type","text/plain"]],"body":"bm90IGZvdW5k"}"#,
+    },
+    TestVector {
+        name: "response_json_all_fields",
+        kind: FrameKind::Response,
+        payload: br#"{"id":3,"status":200,"headers":[["x-latin1",{"base64":"6XTp"}]],"local_duration_ms":5,"body_sha256":"4062edaf750fb8074e7e83e0c9028c94e32468a8b6f1614774328ef045150f93","body":"eyJvayI6dHJ1ZX0="}"#,
+    },
+    TestVector {
+        name: "request_binary",
+        kind: FrameKind::Request,
+        // Marker, head length (107), head, then the body as is
+        payload: b"\x00\x00\x00\x00\x6b{\"id\":7,\"method\":\"POST\",\"path\":\"/upload\",\"headers\":[[\"content-type\",\"application/octet-stream\"]],\"body\":\"\"}\x00\xff\r\n",
+    },
+    TestVector {
+        name: "response_binary",
+        kind: FrameKind::Response,
+        payload: b"\x00\x00\x00\x00\x60{\"id\":7,\"status\":201,\"headers\":[[\"content-type\",\"text/plain\"]],\"local_duration_ms\":12,\"body\":\"\"}created",
+    },
+    TestVector {
+        name: "request_binary_streamed",
+        kind: FrameKind::Request,
+        payload: b"\x00\x00\x00\x00\x4c{\"id\":9,\"method\":\"PUT\",\"path\":\"/large\",\"headers\":[],\"body\":\"\",\"stream\":true}",
+    },
+    TestVector {
+        name: "body_chunk",
+        kind: FrameKind::BodyChunk,
+        payload: b"\x01\x00\x00\x00\x00\x00\x00\x00\x09chunk",
+    },
+    TestVector {
+        name: "body_end",
+        kind: FrameKind::BodyEnd,
+        payload: b"\x02\x00\x00\x00\x00\x00\x00\x00\x09\x01406effb1e9c59672c66a598c2b21e331b23b16c54024e96d6df3e7c173549791",
+    },
+    TestVector {
+        name: "body_end_trailers",
+        kind: FrameKind::BodyEnd,
+        payload: b"\x02\x00\x00\x00\x00\x00\x00\x00\x09\x01[[\"grpc-status\",\"0\"],[\"x-latin1\",{\"base64\":\"6XTp\"}]]",
+    },
+    TestVector {
+        name: "body_end_incomplete",
+        kind: FrameKind::BodyEnd,
+        payload: b"\x02\x00\x00\x00\x00\x00\x00\x00\x09\x00",
+    },
+    TestVector {
+        name: "stats",
+        kind: FrameKind::Stats,
+        payload: br#"{"stats":{"requests":120,"errors":2,"latency_p50_ms":14,"latency_p90_ms":48,"latency_p99_ms":210,"uptime_secs":3600,"rss_bytes":9437184,"memory_warnings":0,"checksum_mismatches":0}}"#,
+    },
+    TestVector { name: "goaway", kind: FrameKind::Goaway, payload: GOAWAY_FRAME },
+    TestVector { name: "ping", kind: FrameKind::Ping, payload: PING_FRAME },
+    TestVector { name: "pong", kind: FrameKind::Pong, payload: PONG_FRAME },
+    TestVector { name: "cancel", kind: FrameKind::Cancel, payload: br#"{"cancel":{"id":42}}"# },
+    TestVector { name: "credit", kind: FrameKind::Credit, payload: br#"{"credit":{"id":9,"chunks":2}}"# },
+];
+
+/// Error returned by `verify_roundtrip`
+#[derive(Debug, Error)]
+pub enum RoundtripError {
+    /// Payload does not decode as its kind
+    #[error("Does not decode: {0}")]
+    Decode(#[from] DecodeError),
+
+    /// Payload decodes, but is not a frame of that kind (e.g. a BODY_END given as a BODY_CHUNK)
+    #[error("Not a {0:?} frame")]
+    WrongKind(FrameKind),
+
+    /// Payload decodes, but encodes back to other bytes (e.g. fields out of order)
+    #[error("Encodes back as {}", String::from_utf8_lossy(.0))]
+    Mismatch(Vec<u8>),
+}
+
+/// Checks that `payload` decodes as a `kind` frame and encodes back to the same bytes
+///
+/// # Returns
+/// * `Ok(())` if the payload is canonical
+/// * `Err(RoundtripError)` saying where it is not
+pub fn verify_roundtrip(kind: FrameKind, payload: &[u8]) -> Result<(), RoundtripError> {
+    let encoded = match kind {
+        FrameKind::Request => {
+            let (request, body) = decode_request_frame(payload)?;
+            encode_message(&request, body)
+        }
+        FrameKind::Response => {
+            let (response, body) = decode_response_frame(payload)?;
+            encode_message(&response, body)
+        }
+        FrameKind::Stats | FrameKind::Goaway | FrameKind::Ping | FrameKind::Pong | FrameKind::Cancel | FrameKind::Credit => match classify_frame(payload)? {
+            Frame::Control(control) if control_kind(&control) == kind => control.encode(),
+            _ => return Err(RoundtripError::WrongKind(kind)),
+        },
+        FrameKind::BodyChunk => match decode_body_frame(payload)? {
+            BodyFrame::Chunk { id, data } => [&body_chunk_prefix(id)[..], data].concat(),
+            BodyFrame::End { .. } => return Err(RoundtripError::WrongKind(kind)),
+        },
+        FrameKind::BodyEnd => match decode_body_frame(payload)? {
+            BodyFrame::End { id, complete, sha256, trailers } => encode_body_end(id, complete, sha256, &trailers),
+            BodyFrame::Chunk { .. } => return Err(RoundtripError::WrongKind(kind)),
+        },
+    };
+    if encoded != payload {
+        return Err(RoundtripError::Mismatch(encoded));
+    }
+    Ok(())
+}
+
+/// The kind of a control frame
+fn control_kind(control: &ControlFrame) -> FrameKind {
+    match control {
+        ControlFrame::Stats(_) => FrameKind::Stats,
+        ControlFrame::Goaway {} => FrameKind::Goaway,
+        ControlFrame::Ping {} => FrameKind::Ping,
+        ControlFrame::Pong {} => FrameKind::Pong,
+        ControlFrame::Cancel { .. } => FrameKind::Cancel,
+        ControlFrame::Credit { .. } => FrameKind::Credit,
+    }
+}
+
+/// Encodes a message as JSON, or as a binary frame when it came with a body of its own
+fn encode_message<T: Serialize>(message: &T, body: Option<&[u8]>) -> Vec<u8> {
+    match body {
+        Some(body) => {
+            let mut buf = BytesMut::new();
+            encode_binary_into(&mut buf, message, body).expect("messages serialize");
+            buf.to_vec()
+        }
+        None => serde_json::to_vec(message).expect("messages serialize"),
+    }
+}
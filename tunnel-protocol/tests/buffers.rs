@@ -0,0 +1,95 @@
+use bytes::{BufMut, BytesMut};
+use tunnel_protocol::{
+    decode_tunnel_response, encode_body, encoded_body_len, read_frame, read_frame_into, write_frame, BufferPool, FrameWriter,
+    TunnelResponse,
+};
+
+#[test]
+fn pool_reuses_returned_buffers() {
+    let pool = BufferPool::new(2, 1024);
+
+    let mut buf = pool.get();
+    buf.put_slice(b"payload");
+    let capacity = buf.capacity();
+    pool.put(buf);
+
+    let buf = pool.get();
+    assert!(buf.is_empty());
+    assert_eq!(buf.capacity(), capacity);
+}
+
+#[test]
+fn pool_drops_oversized_buffers() {
+    let pool = BufferPool::new(2, 16);
+    pool.put(BytesMut::with_capacity(4096));
+    assert_eq!(pool.get().capacity(), 0);
+}
+
+#[test]
+fn pool_clear_releases_pooled_buffers() {
+    let pool = BufferPool::new(2, 1024);
+    pool.put(BytesMut::with_capacity(512));
+    pool.clear();
+    assert_eq!(pool.get().capacity(), 0);
+}
+
+#[tokio::test]
+async fn read_frame_into_replaces_buffer_contents() {
+    let mut input = Vec::new();
+    write_frame(&mut input, b"first frame").await.unwrap();
+    write_frame(&mut input, b"second").await.unwrap();
+
+    let mut reader = &input[..];
+    let mut buf = BytesMut::new();
+    read_frame_into(&mut reader, &mut buf, None).await.unwrap();
+    assert_eq!(&buf[..], b"first frame");
+    read_frame_into(&mut reader, &mut buf, None).await.unwrap();
+    assert_eq!(&buf[..], b"second");
+}
+
+#[tokio::test]
+async fn frame_writer_coalesces_small_frames_until_flush() {
+    let mut writer = FrameWriter::new(Vec::new(), 64);
+    writer.write_frame(b"one").await.unwrap();
+    writer.write_frame(b"two").await.unwrap();
+    assert!(writer.get_ref().is_empty());
+
+    writer.flush().await.unwrap();
+    let mut reader = &writer.get_ref()[..];
+    assert_eq!(read_frame(&mut reader).await.unwrap(), &b"one"[..]);
+    assert_eq!(read_frame(&mut reader).await.unwrap(), &b"two"[..]);
+}
+
+#[tokio::test]
+async fn frame_writer_sends_pending_frames_with_a_large_one() {
+    let mut writer = FrameWriter::new(Vec::new(), 16);
+    writer.write_frame(b"small").await.unwrap();
+    writer.write_frame(&[7u8; 100]).await.unwrap();
+
+    let mut reader = &writer.get_ref()[..];
+    assert_eq!(read_frame(&mut reader).await.unwrap(), &b"small"[..]);
+    assert_eq!(read_frame(&mut reader).await.unwrap().len(), 100);
+    assert!(reader.is_empty());
+}
+
+#[tokio::test]
+async fn frame_can_be_written_in_pieces_around_a_streamed_body() {
+    let response = TunnelResponse { status: 200, headers: vec![("a".into(), "b".into())], ..TunnelResponse::default() };
+    let (head, tail) = response.json_around_body().unwrap();
+    let body = encode_body(b"streamed body");
+    assert_eq!(body.len(), encoded_body_len(13));
+
+    let mut writer = FrameWriter::new(Vec::new(), 64);
+    writer.write_frame(b"before").await.unwrap();
+    writer.start_frame(head.len() + body.len() + tail.len()).await.unwrap();
+    writer.write_payload(&head).await.unwrap();
+    writer.write_payload(body.as_bytes()).await.unwrap();
+    writer.write_payload(tail).await.unwrap();
+    writer.flush().await.unwrap();
+
+    let mut reader = &writer.get_ref()[..];
+    assert_eq!(read_frame(&mut reader).await.unwrap(), &b"before"[..]);
+    let decoded = decode_tunnel_response(&read_frame(&mut reader).await.unwrap()).unwrap();
+    assert_eq!((decoded.status, decoded.headers, decoded.body), (200, response.headers, body));
+    assert!(reader.is_empty());
+}
@@ -0,0 +1,231 @@
+use bytes::BytesMut;
+use tunnel_protocol::{
+    compress_frame, decompress_frame, is_compressed_frame, read_frame, read_frame_into, should_compress, CompressTypes, Compression, DecodeError, FrameWriter, ProtocolError,
+    ADAPTIVE_PROBE_BYTES, ADAPTIVE_WINDOW_BYTES, COMPRESS_MIN_BYTES, FRAME_HEADER_LEN, MAX_FRAME_LEN,
+};
+/// Reads the next frame as a connection that agreed to compression does
+async fn read_agreed(reader: &mut &[u8]) -> BytesMut {
+    let mut buf = BytesMut::new();
+    read_frame_into(reader, &mut buf, Some(Compression::Zstd)).await.unwrap();
+    buf
+}
+
+
+/// JSON-like text, which compresses well
+fn json(len: usize) -> Vec<u8> {
+    let mut json = br#"{"headers":[["content-type","application/json"]],"body":""#.repeat(len / 50 + 1);
+    json.truncate(len);
+    json
+}
+
+/// Pseudo-random bytes, which do not compress
+fn noise(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+#[test]
+fn compression_names_are_parsed() {
+    assert_eq!(Compression::parse("zstd"), Some(Compression::Zstd));
+    assert_eq!(Compression::parse(" GZIP "), Some(Compression::Gzip));
+    assert_eq!(Compression::parse("brotli"), None);
+    assert_eq!(Compression::Zstd.to_string(), "zstd");
+}
+
+#[test]
+fn frames_round_trip_with_either_algorithm() {
+    for compression in [Compression::Zstd, Compression::Gzip] {
+        let payload = json(10_000);
+        let mut frame = Vec::new();
+        assert!(compress_frame(compression, &payload, &mut frame).unwrap());
+        assert!(is_compressed_frame(&frame));
+        assert!(frame.len() < payload.len() / 4, "{}: {} bytes", compression, frame.len());
+
+        let mut out = BytesMut::from(&b"stale"[..]);
+        decompress_frame(&frame, &mut out).unwrap();
+        assert_eq!(&out[..], &payload[..]);
+    }
+}
+
+#[test]
+fn incompressible_payloads_are_left_alone() {
+    let mut frame = Vec::new();
+    assert!(!compress_frame(Compression::Zstd, &noise(4096), &mut frame).unwrap());
+}
+
+#[test]
+fn damaged_or_oversized_frames_are_rejected() {
+    let mut out = BytesMut::new();
+    let mut frame = Vec::new();
+    compress_frame(Compression::Gzip, &json(10_000), &mut frame).unwrap();
+
+    let mut truncated = frame.clone();
+    truncated.truncate(frame.len() / 2);
+    assert!(matches!(decompress_frame(&truncated, &mut out), Err(DecodeError::InvalidCompressedFrame(_))));
+
+    let mut unknown = frame.clone();
+    unknown[1] = 9;
+    assert!(matches!(decompress_frame(&unknown, &mut out), Err(DecodeError::InvalidCompressedFrame(_))));
+    assert!(matches!(decompress_frame(b"{}", &mut out), Err(DecodeError::InvalidCompressedFrame(_))));
+
+    // Small on the wire, too large once decompressed
+    compress_frame(Compression::Zstd, &vec![0u8; MAX_FRAME_LEN + 1], &mut frame).unwrap();
+    assert!(matches!(decompress_frame(&frame, &mut out), Err(DecodeError::FrameTooLarge { .. })));
+}
+
+#[tokio::test]
+async fn frame_writer_compresses_what_is_worth_it() {
+    let mut writer = FrameWriter::new(Vec::new(), 0).with_compression(Some(Compression::Zstd));
+    let (small, large, random) = (json(COMPRESS_MIN_BYTES - 1), json(50_000), noise(5_000));
+    writer.write_frame(&small).await.unwrap();
+    writer.write_frame(&large).await.unwrap();
+    writer.write_frame(&random).await.unwrap();
+    // Written in pieces, held until complete
+    writer.start_frame(large.len()).await.unwrap();
+    for piece in large.chunks(7_000) {
+        writer.write_payload(piece).await.unwrap();
+    }
+    writer.flush().await.unwrap();
+    let written = writer.get_ref().len();
+    assert!(written < small.len() + random.len() + large.len() / 2, "{} bytes written", written);
+
+    let mut reader = &writer.get_ref()[..];
+    for payload in [&small, &large, &random, &large] {
+        assert_eq!(read_agreed(&mut reader).await, &payload[..]);
+    }
+    assert!(reader.is_empty());
+}
+
+#[tokio::test]
+async fn compressible_frames_keep_being_compressed() {
+    let mut writer = FrameWriter::new(Vec::new(), 0).with_compression(Some(Compression::Zstd));
+    assert_eq!(FrameWriter::new(Vec::new(), 0).compression_stats(), None);
+    let frame = json(64 * 1024);
+    for _ in 0..2 * ADAPTIVE_WINDOW_BYTES / frame.len() as u64 {
+        writer.write_frame(&frame).await.unwrap();
+    }
+    let stats = writer.compression_stats().unwrap();
+    assert!(stats.enabled);
+    assert!(stats.ratio < 0.1, "{:?}", stats);
+    assert!(stats.bytes_saved > ADAPTIVE_WINDOW_BYTES, "{:?}", stats);
+}
+
+#[tokio::test]
+async fn compression_stops_while_frames_do_not_shrink() {
+    let mut writer = FrameWriter::new(Vec::new(), 0).with_compression(Some(Compression::Zstd));
+    let (random, text) = (noise(64 * 1024), json(64 * 1024));
+    let frames = |bytes: u64| bytes / random.len() as u64;
+
+    // A window of incompressible frames turns it off; they go out as they are
+    for _ in 0..frames(ADAPTIVE_WINDOW_BYTES) {
+        writer.write_frame(&random).await.unwrap();
+    }
+    let stats = writer.compression_stats().unwrap();
+    assert!(!stats.enabled);
+    assert_eq!(stats.bytes_saved, 0);
+
+    // Compressible frames are not tried until the probe...
+    let written = writer.get_ref().len();
+    for _ in 0..frames(ADAPTIVE_PROBE_BYTES) {
+        writer.write_frame(&text).await.unwrap();
+    }
+    assert_eq!(writer.get_ref().len() - written, frames(ADAPTIVE_PROBE_BYTES) as usize * (text.len() + FRAME_HEADER_LEN));
+    assert!(writer.compression_stats().unwrap().enabled);
+
+    // ...which finds it pays off again
+    for _ in 0..frames(ADAPTIVE_WINDOW_BYTES) {
+        writer.write_frame(&text).await.unwrap();
+    }
+    let stats = writer.compression_stats().unwrap();
+    assert!(stats.enabled);
+    assert!(stats.bytes_saved > ADAPTIVE_WINDOW_BYTES / 2, "{:?}", stats);
+
+    let mut reader = &writer.get_ref()[..];
+    while !reader.is_empty() {
+        let payload = read_agreed(&mut reader).await;
+        assert!(payload == random || payload == text);
+    }
+}
+
+#[test]
+fn bodies_compressed_already_are_not_tried() {
+    let defaults = CompressTypes::default();
+    let check = |content_type, content_encoding, body_start: &[u8], types: &CompressTypes| {
+        should_compress(content_type, content_encoding, body_start, types)
+    };
+    assert!(check(Some("application/json; charset=utf-8"), None, b"{", &defaults));
+    assert!(check(Some("text/html"), Some("identity"), b"", &defaults));
+    assert!(check(None, None, b"", &defaults));
+    assert!(!check(Some("application/json"), Some("gzip"), b"", &defaults));
+    assert!(!check(Some("text/css"), Some(" BR "), b"", &defaults));
+    for media_type in ["image/png", "Image/JPEG", "video/mp4", "audio/ogg", "application/zip", "application/gzip", "application/x-7z-compressed"] {
+        assert!(!check(Some(media_type), None, b"", &defaults), "{}", media_type);
+    }
+    assert!(check(Some("image/svg+xml"), None, b"<svg", &defaults));
+    assert!(!check(Some("application/octet-stream"), None, b"PK\x03\x04rest", &defaults));
+    assert!(!check(Some("application/octet-stream"), None, b"\x1f\x8b\x08", &defaults));
+    assert!(check(Some("application/octet-stream"), None, b"plain data", &defaults));
+
+    // Listed types change the decision; a skip entry wins over a compress one
+    let types = CompressTypes {
+        compress: CompressTypes::parse_list("image/bmp, application/json").unwrap(),
+        skip: CompressTypes::parse_list("application/json, text/event-stream").unwrap(),
+    };
+    assert!(check(Some("image/bmp"), None, b"", &types));
+    assert!(!check(Some("image/png"), None, b"", &types));
+    assert!(!check(Some("application/json"), None, b"", &types));
+    assert!(!check(Some("text/event-stream"), None, b"", &types));
+    assert!(!check(Some("image/bmp"), Some("gzip"), b"", &types));
+    assert_eq!(CompressTypes::parse_list(" Image/BMP ,,").unwrap(), ["image/bmp"]);
+    assert!(CompressTypes::parse_list("image").unwrap_err().contains("'image'"));
+}
+
+#[tokio::test]
+async fn skipping_compression_covers_the_next_frame_only() {
+    let mut writer = FrameWriter::new(Vec::new(), 0).with_compression(Some(Compression::Zstd));
+    let large = json(50_000);
+    writer.skip_compression();
+    writer.write_frame(&large).await.unwrap();
+    let plain = writer.get_ref().len();
+    assert_eq!(plain, 4 + large.len());
+
+    // Also for a frame written in pieces
+    writer.skip_compression();
+    writer.start_frame(large.len()).await.unwrap();
+    for piece in large.chunks(7_000) {
+        writer.write_payload(piece).await.unwrap();
+    }
+    writer.write_frame(&large).await.unwrap();
+    writer.flush().await.unwrap();
+    let written = writer.get_ref().len() - plain;
+    assert!(written < 4 + large.len() + large.len() / 2, "{} bytes written", written);
+
+    let mut reader = &writer.get_ref()[..];
+    for _ in 0..3 {
+        assert_eq!(read_agreed(&mut reader).await, &large[..]);
+    }
+    assert!(!is_compressed_frame(&writer.get_ref()[4 + large.len() + 4..]));
+}
+
+#[tokio::test]
+async fn compressed_frames_are_refused_without_agreed_compression() {
+    let mut writer = FrameWriter::new(Vec::new(), 0).with_compression(Some(Compression::Gzip));
+    writer.write_frame(&json(50_000)).await.unwrap();
+    writer.flush().await.unwrap();
+    assert!(is_compressed_frame(&writer.get_ref()[FRAME_HEADER_LEN..]));
+
+    let err = read_frame(&mut &writer.get_ref()[..]).await.unwrap_err();
+    assert!(matches!(err, ProtocolError::Unexpected(_)), "{:?}", err);
+    assert!(err.is_peer_fault());
+    let mut buf = BytesMut::new();
+    assert!(read_frame_into(&mut &writer.get_ref()[..], &mut buf, None).await.is_err());
+    read_frame_into(&mut &writer.get_ref()[..], &mut buf, Some(Compression::Gzip)).await.unwrap();
+    assert_eq!(buf, json(50_000));
+}
@@ -0,0 +1,306 @@
+use bytes::BytesMut;
+use tunnel_protocol::{
+    body_chunk_prefix, classify_frame, decode_body_frame, decode_frame_bytes, decode_request_frame, decode_response_frame, decode_tunnel_request, decode_tunnel_response,
+    encode_binary_into, encode_body, encode_body_end, format_tags, is_binary_frame, is_body_frame, is_control_frame, read_frame, response_id, write_tagged_request, BodyFrame, ControlFrame, DecodeError, Frame, FrameWriter,
+    HeaderValueBytes, StatsReport, TunnelRequest, TunnelResponse, GOAWAY_FRAME, MAX_FRAME_LEN, PING_FRAME, PONG_FRAME,
+};
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+#[test]
+fn decodes_frame_and_reports_consumed_bytes() {
+    let mut input = frame(b"hello");
+    input.extend_from_slice(b"next");
+
+    let (payload, consumed) = decode_frame_bytes(&input).unwrap();
+    assert_eq!(payload, b"hello");
+    assert_eq!(consumed, 9);
+}
+
+#[test]
+fn empty_payload_is_a_valid_frame() {
+    let (payload, consumed) = decode_frame_bytes(&[0, 0, 0, 0]).unwrap();
+    assert!(payload.is_empty());
+    assert_eq!(consumed, 4);
+}
+
+#[test]
+fn truncated_header_is_an_error() {
+    match decode_frame_bytes(&[0, 0]) {
+        Err(DecodeError::Truncated { needed: 4, available: 2 }) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn truncated_payload_is_an_error() {
+    let input = frame(b"hello");
+    match decode_frame_bytes(&input[..6]) {
+        Err(DecodeError::Truncated { needed: 9, available: 6 }) => {}
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn oversized_length_is_rejected_before_reading_payload() {
+    let input = u32::MAX.to_be_bytes();
+    match decode_frame_bytes(&input) {
+        Err(DecodeError::FrameTooLarge { len, max }) => {
+            assert_eq!(len, u32::MAX as usize);
+            assert_eq!(max, MAX_FRAME_LEN);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn read_frame_rejects_oversized_length_without_allocating() {
+    let input = u32::MAX.to_be_bytes();
+    let err = read_frame(&mut &input[..]).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn decodes_valid_request_and_response() {
+    let request = decode_tunnel_request(br#"{"method":"GET","path":"/","headers":[["a","b"]],"body":""}"#).unwrap();
+    assert_eq!(request.method, "GET");
+    assert_eq!(request.headers, vec![("a".to_string(), "b".into())]);
+    assert_eq!(request.deadline_ms, None);
+
+    let request = decode_tunnel_request(br#"{"method":"GET","path":"/","headers":[],"body":"","deadline_ms":1500}"#).unwrap();
+    assert_eq!(request.deadline_ms, Some(1500));
+
+    let response = decode_tunnel_response(br#"{"status":204,"headers":[],"body":""}"#).unwrap();
+    assert_eq!(response.status, 204);
+}
+
+#[test]
+fn malformed_messages_are_errors() {
+    let inputs: [&[u8]; 6] = [
+        b"",
+        b"\xff\xfe",
+        b"{",
+        b"[]",
+        br#"{"method":"GET","path":"/","headers":[]}"#,
+        br#"{"method":"GET","path":"/","headers":[["only-name"]],"body":""}"#,
+    ];
+    for input in inputs {
+        assert!(
+            matches!(decode_tunnel_request(input), Err(DecodeError::InvalidMessage(_))),
+            "accepted {:?}",
+            String::from_utf8_lossy(input)
+        );
+    }
+    assert!(decode_tunnel_response(br#"{"status":70000,"headers":[],"body":""}"#).is_err());
+}
+
+#[test]
+fn stats_frames_are_told_apart_from_responses() {
+    let report = StatsReport { requests: 10, errors: 1, latency_p50_ms: 12, rss_bytes: Some(4096), ..StatsReport::default() };
+    let stats = ControlFrame::Stats(report.clone()).encode();
+    assert_eq!(classify_frame(&stats).unwrap(), Frame::Control(ControlFrame::Stats(report)));
+
+    let response = serde_json::to_vec(&TunnelResponse { status: 200, ..TunnelResponse::default() }).unwrap();
+    assert_eq!(classify_frame(&response).unwrap(), Frame::Data(&response));
+
+    assert!(matches!(classify_frame(br#"{"stats":{}}"#), Err(DecodeError::InvalidMessage(_))));
+}
+
+#[test]
+fn heartbeat_frames_are_told_apart_from_messages() {
+    assert_eq!(classify_frame(PING_FRAME).unwrap(), Frame::Control(ControlFrame::Ping {}));
+    assert_eq!(classify_frame(PONG_FRAME).unwrap(), Frame::Control(ControlFrame::Pong {}));
+    assert_eq!(classify_frame(GOAWAY_FRAME).unwrap(), Frame::Control(ControlFrame::Goaway {}));
+    let request = br#"{"id":1,"method":"GET"}"#;
+    assert_eq!(classify_frame(request).unwrap(), Frame::Data(request));
+}
+
+#[test]
+fn control_frames_are_told_apart_from_data_frames() {
+    let stats = ControlFrame::Stats(StatsReport { requests: 3, ..StatsReport::default() }).encode();
+    assert!(stats.starts_with(br#"{"stats":{"requests":3,"#), "{}", String::from_utf8_lossy(&stats));
+    assert_eq!(ControlFrame::Goaway {}.encode(), GOAWAY_FRAME);
+    assert_eq!(ControlFrame::Ping {}.encode(), PING_FRAME);
+    assert_eq!(ControlFrame::Pong {}.encode(), PONG_FRAME);
+    for control in [ControlFrame::Stats(StatsReport::default()), ControlFrame::Goaway {}, ControlFrame::Ping {}, ControlFrame::Pong {}] {
+        assert_eq!(classify_frame(&control.encode()).unwrap(), Frame::Control(control));
+    }
+
+    let mut binary = BytesMut::new();
+    encode_binary_into(&mut binary, &TunnelResponse { id: Some(1), status: 200, ..TunnelResponse::default() }, b"{\"ping\":{}}").unwrap();
+    let data = [&br#"{"id":1,"method":"GET","path":"/","headers":[],"body":""}"#[..], br#"{"status":200,"headers":[],"body":""}"#, &binary, &body_chunk_prefix(1)];
+    for payload in data {
+        assert!(!is_control_frame(payload));
+        assert_eq!(classify_frame(payload).unwrap(), Frame::Data(payload));
+    }
+
+    assert!(matches!(classify_frame(br#"{"goaway":true}"#), Err(DecodeError::InvalidMessage(_))));
+}
+
+#[test]
+fn non_utf8_header_values_travel_as_base64() {
+    let response = TunnelResponse {
+        status: 200,
+        headers: vec![("x-name".to_string(), b"caf\xe9"[..].into()), ("x-plain".to_string(), "cafe".into())],
+        ..TunnelResponse::default()
+    };
+    let json = String::from_utf8(serde_json::to_vec(&response).unwrap()).unwrap();
+    assert!(json.contains(r#""headers":[["x-name",{"base64":"Y2Fm6Q=="}],["x-plain","cafe"]]"#), "{}", json);
+
+    let decoded = decode_tunnel_response(json.as_bytes()).unwrap();
+    assert_eq!(decoded.headers, response.headers);
+    assert_eq!(decoded.headers[0].1.to_str(), None);
+    assert_eq!(decoded.headers[1].1.to_str(), Some("cafe"));
+
+    for headers in [r#"[["x",{"base64":"%%"}]]"#, r#"[["x",{"hex":"e9"}]]"#, r#"[["x",{}]]"#, r#"[["x",7]]"#] {
+        let message = format!(r#"{{"status":200,"headers":{},"body":""}}"#, headers);
+        assert!(decode_tunnel_response(message.as_bytes()).is_err(), "accepted {}", headers);
+    }
+}
+
+#[test]
+fn header_order_survives_non_utf8_values() {
+    let headers: Vec<(String, HeaderValueBytes)> =
+        [("set-cookie", &b"a=1"[..]), ("x-name", b"caf\xe9"), ("set-cookie", b"b=2"), ("x-name", b"na\xefve"), ("set-cookie", b"c=3")]
+            .iter()
+            .map(|(name, value)| (name.to_string(), (*value).into()))
+            .collect();
+    let request = TunnelRequest { method: "GET".to_string(), path: "/".to_string(), headers: headers.clone(), ..TunnelRequest::default() };
+
+    let decoded = decode_tunnel_request(&serde_json::to_vec(&request).unwrap()).unwrap();
+    assert_eq!(decoded.headers, headers);
+}
+
+#[test]
+fn local_duration_is_optional() {
+    let response = decode_tunnel_response(br#"{"status":200,"headers":[],"body":""}"#).unwrap();
+    assert_eq!(response.local_duration_ms, None);
+
+    let mut response = TunnelResponse { status: 200, local_duration_ms: Some(42), ..TunnelResponse::default() };
+    let json = serde_json::to_vec(&response).unwrap();
+    assert_eq!(decode_tunnel_response(&json).unwrap().local_duration_ms, Some(42));
+
+    // Left out when unknown, and never after the body (see json_around_body)
+    response.local_duration_ms = None;
+    assert_eq!(serde_json::to_string(&response).unwrap(), r#"{"status":200,"headers":[],"body":""}"#);
+    response.local_duration_ms = Some(7);
+    let (head, tail) = response.json_around_body().unwrap();
+    assert_eq!(String::from_utf8(head).unwrap() + std::str::from_utf8(tail).unwrap(), r#"{"status":200,"headers":[],"local_duration_ms":7,"body":""}"#);
+}
+
+#[tokio::test]
+async fn request_ids_lead_their_frames() {
+    let mut response = TunnelResponse { id: Some(42), status: 200, ..TunnelResponse::default() };
+    let json = serde_json::to_vec(&response).unwrap();
+    assert_eq!(response_id(&json), Some(42));
+    assert_eq!(decode_tunnel_response(&json).unwrap().id, Some(42));
+    response.id = None;
+    assert_eq!(response_id(&serde_json::to_vec(&response).unwrap()), None);
+    assert_eq!(response_id(br#"{"id":7}"#), Some(7));
+    for frame in [&br#"{"stats":{}}"#[..], br#"{"id":,"#, br#"{"id":12a,"#, br#"{"id":99999999999999999999999,"#] {
+        assert_eq!(response_id(frame), None, "{}", String::from_utf8_lossy(frame));
+    }
+
+    // The server tags a request serialized without an ID
+    let payload = br#"{"method":"GET","path":"/","headers":[],"body":""}"#;
+    let mut writer = FrameWriter::new(Vec::new(), 0);
+    write_tagged_request(&mut writer, 3, payload).await.unwrap();
+    write_tagged_request(&mut writer, 4, b"{}").await.unwrap();
+    let mut reader = &writer.get_ref()[..];
+    let request = decode_tunnel_request(&read_frame(&mut reader).await.unwrap()).unwrap();
+    assert_eq!((request.id, request.method.as_str()), (Some(3), "GET"));
+    assert_eq!(read_frame(&mut reader).await.unwrap(), &br#"{"id":4}"#[..]);
+    assert!(write_tagged_request(&mut writer, 5, b"[]").await.is_err());
+}
+
+#[tokio::test]
+async fn binary_frames_carry_bodies_as_they_are() {
+    let body = [0u8, 159, 146, 150, b'{', 255];
+    let request = TunnelRequest {
+        method: "POST".to_string(),
+        path: "/upload".to_string(),
+        headers: vec![("content-type".to_string(), "application/octet-stream".into())],
+        deadline_ms: Some(500),
+        ..TunnelRequest::default()
+    };
+    let mut payload = BytesMut::new();
+    encode_binary_into(&mut payload, &request, &body).unwrap();
+    let (decoded, raw) = decode_request_frame(&payload).unwrap();
+    assert_eq!((decoded.path.as_str(), decoded.deadline_ms, raw), ("/upload", Some(500), Some(&body[..])));
+
+    // JSON frames still decode, their body left base64
+    let json = serde_json::to_vec(&TunnelRequest { body: encode_body(&body), ..request }).unwrap();
+    let (decoded, raw) = decode_request_frame(&json).unwrap();
+    assert_eq!((decoded.body, raw), (encode_body(&body), None));
+
+    // The server tags binary requests like JSON ones
+    let mut writer = FrameWriter::new(Vec::new(), 0);
+    write_tagged_request(&mut writer, 9, &payload).await.unwrap();
+    let tagged = read_frame(&mut &writer.get_ref()[..]).await.unwrap();
+    let (decoded, raw) = decode_request_frame(&tagged).unwrap();
+    assert_eq!((decoded.id, decoded.method.as_str(), raw), (Some(9), "POST", Some(&body[..])));
+
+    let response = TunnelResponse { id: Some(12), status: 200, ..TunnelResponse::default() };
+    let mut payload = BytesMut::new();
+    encode_binary_into(&mut payload, &response, &body).unwrap();
+    assert_eq!(response_id(&payload), Some(12));
+    let (decoded, raw) = decode_response_frame(&payload).unwrap();
+    assert_eq!((decoded.status, raw), (200, Some(&body[..])));
+
+    // A head running past the end of the frame is refused
+    payload.truncate(10);
+    assert!(matches!(decode_response_frame(&payload), Err(DecodeError::Truncated { .. })));
+}
+
+#[test]
+fn body_frames_carry_their_request_id() {
+    let mut chunk = body_chunk_prefix(0x0102_0304_0506_0708).to_vec();
+    chunk.extend_from_slice(b"\x00\xffdata");
+    assert_eq!(&chunk[..9], &[1, 1, 2, 3, 4, 5, 6, 7, 8]);
+    assert!(is_body_frame(&chunk) && !is_binary_frame(&chunk));
+    assert_eq!(decode_body_frame(&chunk).unwrap(), BodyFrame::Chunk { id: 0x0102_0304_0506_0708, data: b"\x00\xffdata" });
+
+    let sha256 = "ab".repeat(32);
+    let end = encode_body_end(7, true, Some(&sha256), &[]);
+    assert!(is_body_frame(&end));
+    assert_eq!(decode_body_frame(&end).unwrap(), BodyFrame::End { id: 7, complete: true, sha256: Some(&sha256), trailers: Vec::new() });
+    let end = encode_body_end(7, false, None, &[]);
+    assert_eq!(end.len(), 10);
+    assert_eq!(decode_body_frame(&end).unwrap(), BodyFrame::End { id: 7, complete: false, sha256: None, trailers: Vec::new() });
+
+    // Trailers follow the SHA-256, if any
+    let trailers = vec![("grpc-status".to_string(), "0".into()), ("x-raw".to_string(), b"\xe9"[..].into())];
+    let end = encode_body_end(7, true, Some(&sha256), &trailers);
+    assert_eq!(decode_body_frame(&end).unwrap(), BodyFrame::End { id: 7, complete: true, sha256: Some(&sha256), trailers: trailers.clone() });
+    let end = encode_body_end(7, true, None, &trailers);
+    assert_eq!(decode_body_frame(&end).unwrap(), BodyFrame::End { id: 7, complete: true, sha256: None, trailers });
+    assert!(matches!(decode_body_frame(b"\x02\x00\x00\x00\x00\x00\x00\x00\x07\x01[oops"), Err(DecodeError::InvalidBodyFrame(_))));
+    assert_eq!(decode_body_frame(&end).unwrap().id(), 7);
+
+    // Neither JSON nor binary messages are body frames
+    assert!(!is_body_frame(b"{}") && !is_body_frame(&[0, 0, 0, 0, 2]) && !is_body_frame(&[]));
+    assert!(matches!(decode_body_frame(b"{\"id\":1234}"), Err(DecodeError::InvalidBodyFrame(_))));
+    assert!(matches!(decode_body_frame(&chunk[..5]), Err(DecodeError::Truncated { .. })));
+    assert!(matches!(decode_body_frame(&end[..9]), Err(DecodeError::Truncated { .. })));
+}
+
+#[test]
+fn response_tags_are_parsed_from_their_headers() {
+    let response = TunnelResponse {
+        status: 200,
+        headers: vec![
+            ("X-Tunnel-Tag".to_string(), "order=A-1042,event=paid".into()),
+            ("x-tunnel-tag".to_string(), "no equals sign".into()),
+            ("x-other".to_string(), "ignored=yes".into()),
+        ],
+        ..TunnelResponse::default()
+    };
+    let tags = response.tags();
+    assert_eq!(tags, vec![("order".to_string(), "A-1042".to_string()), ("event".to_string(), "paid".to_string())]);
+    assert_eq!(format_tags(&tags), " [order=A-1042 event=paid]");
+    assert_eq!(format_tags(&[]), "");
+}
@@ -0,0 +1,57 @@
+use bytes::BytesMut;
+use std::io;
+use tunnel_protocol::{read_frame, read_frame_into, DecodeError, ProtocolError, MAX_FRAME_LEN};
+
+#[tokio::test]
+async fn end_of_input_is_a_closed_connection() {
+    let err = read_frame(&mut &[0u8, 0][..]).await.unwrap_err();
+    assert!(matches!(err, ProtocolError::Closed(_)), "{:?}", err);
+    assert!(!err.is_peer_fault());
+
+    // Mid-payload too
+    let err = read_frame_into(&mut &[0u8, 0, 0, 4, b'a'][..], &mut BytesMut::new(), None).await.unwrap_err();
+    assert!(matches!(err, ProtocolError::Closed(_)), "{:?}", err);
+}
+
+#[tokio::test]
+async fn oversized_length_is_the_peers_fault() {
+    let input = u32::MAX.to_be_bytes();
+    let err = read_frame(&mut &input[..]).await.unwrap_err();
+    assert!(matches!(err, ProtocolError::FrameTooLarge { len, max } if len == u32::MAX as usize && max == MAX_FRAME_LEN), "{:?}", err);
+    assert!(err.is_peer_fault());
+}
+
+#[test]
+fn decode_errors_are_corrupt_frames() {
+    let err = ProtocolError::from(DecodeError::InvalidBodyFrame("too short"));
+    assert!(matches!(err, ProtocolError::Corrupt(DecodeError::InvalidBodyFrame(_))), "{:?}", err);
+    assert!(err.is_peer_fault());
+
+    let err = ProtocolError::from(DecodeError::FrameTooLarge { len: 10, max: 5 });
+    assert!(matches!(err, ProtocolError::FrameTooLarge { len: 10, max: 5 }), "{:?}", err);
+}
+
+#[test]
+fn survives_a_round_trip_through_io_error() {
+    let err = io::Error::from(ProtocolError::from(DecodeError::InvalidCompressedFrame("bad")));
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(matches!(ProtocolError::from(err), ProtocolError::Corrupt(DecodeError::InvalidCompressedFrame("bad"))));
+
+    let err = io::Error::from(ProtocolError::Unexpected("response to unknown request 7".to_string()));
+    assert!(matches!(ProtocolError::from(err), ProtocolError::Unexpected(message) if message.contains("7")));
+
+    // A DecodeError converted straight to io::Error too
+    assert!(ProtocolError::from(io::Error::from(DecodeError::InvalidBodyFrame("x"))).is_peer_fault());
+}
+
+#[test]
+fn other_io_errors_keep_their_kind() {
+    let err = ProtocolError::from(io::Error::from(io::ErrorKind::ConnectionReset));
+    assert!(matches!(err, ProtocolError::Closed(_)));
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+
+    let err = ProtocolError::from(io::Error::new(io::ErrorKind::TimedOut, "slow"));
+    assert!(matches!(err, ProtocolError::Io(_)));
+    assert!(!err.is_peer_fault());
+    assert_eq!(io::Error::from(err).kind(), io::ErrorKind::TimedOut);
+}
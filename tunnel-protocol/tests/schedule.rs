@@ -0,0 +1,92 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tunnel_protocol::Schedule;
+
+/// 2024-01-01, a Monday, at `hours:minutes` UTC plus `days`
+fn at(days: u64, hours: u64, minutes: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(1_704_067_200 + days * 86_400 + hours * 3_600 + minutes * 60)
+}
+
+#[test]
+fn working_hours_cover_weekdays_only() {
+    let schedule = Schedule::parse("mon-fri 09:00-18:00").unwrap();
+    assert!(schedule.contains(at(0, 9, 0)));
+    assert!(schedule.contains(at(4, 17, 59)));
+    assert!(!schedule.contains(at(0, 8, 59)));
+    assert!(!schedule.contains(at(0, 18, 0)));
+    assert!(!schedule.contains(at(5, 12, 0)));
+    assert!(!schedule.contains(at(6, 12, 0)));
+}
+
+#[test]
+fn overnight_windows_end_on_the_next_day() {
+    let schedule = Schedule::parse("fri 22:00-02:00").unwrap();
+    assert!(schedule.contains(at(4, 23, 0)));
+    assert!(schedule.contains(at(5, 1, 59)));
+    assert!(!schedule.contains(at(5, 2, 0)));
+    assert!(!schedule.contains(at(4, 1, 0)));
+    assert!(!schedule.contains(at(5, 23, 0)));
+
+    let schedule = Schedule::parse("sun 00:00-24:00").unwrap();
+    assert!(schedule.contains(at(6, 0, 0)));
+    assert!(schedule.contains(at(6, 23, 59)));
+    assert!(!schedule.contains(at(7, 0, 0)));
+}
+
+#[test]
+fn offsets_shift_every_window() {
+    // 09:00-18:00 at UTC+02:00 is 07:00-16:00 UTC, and at UTC-05:00 Monday 20:00 is Tuesday 01:00 UTC
+    let schedule = Schedule::parse("mon 09:00-18:00, tue 10:00-11:00 UTC+02:00").unwrap();
+    assert!(schedule.contains(at(0, 7, 0)));
+    assert!(!schedule.contains(at(0, 16, 0)));
+    assert!(schedule.contains(at(1, 8, 30)));
+
+    let schedule = Schedule::parse("mon 20:00-21:00 utc-05:00").unwrap();
+    assert!(schedule.contains(at(1, 1, 0)));
+    assert!(!schedule.contains(at(0, 20, 0)));
+}
+
+#[test]
+fn day_ranges_may_wrap_past_sunday() {
+    let schedule = Schedule::parse("fri-mon 12:00-13:00").unwrap();
+    for day in [0, 4, 5, 6] {
+        assert!(schedule.contains(at(day, 12, 30)), "day {}", day);
+    }
+    for day in [1, 2, 3] {
+        assert!(!schedule.contains(at(day, 12, 30)), "day {}", day);
+    }
+}
+
+#[test]
+fn schedules_display_in_canonical_form() {
+    for (value, canonical) in [
+        ("MON-FRI 09:00-18:00", "mon-fri 09:00-18:00"),
+        ("daily 00:00-24:00", "daily 00:00-24:00"),
+        ("sat 22:00-24:00,sun 10:00-12:00 utc+00:00", "sat 22:00-24:00, sun 10:00-12:00"),
+        ("fri-mon 12:00-13:00 utc-05:30", "fri-mon 12:00-13:00 utc-05:30"),
+        ("mon-sun 08:00-09:00", "daily 08:00-09:00"),
+    ] {
+        let schedule = Schedule::parse(value).unwrap();
+        assert_eq!(schedule.to_string(), canonical, "{}", value);
+        assert_eq!(Schedule::parse(canonical).unwrap(), schedule, "{}", value);
+    }
+}
+
+#[test]
+fn malformed_schedules_are_rejected() {
+    for value in [
+        "",
+        " , ",
+        "09:00-18:00",
+        "weekdays 09:00-18:00",
+        "mon-fri 9:00-18:00",
+        "mon-fri 09:00-18:60",
+        "mon-fri 09:00-25:00",
+        "mon-fri 24:00-02:00",
+        "mon-fri 09:00-09:00",
+        "mon-fri 09:00",
+        "mon-fri 09:00-18:00 utc+2",
+        "mon-fri 09:00-18:00 utc+15:00",
+    ] {
+        assert!(Schedule::parse(value).is_err(), "{:?}", value);
+    }
+}
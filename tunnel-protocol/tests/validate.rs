@@ -0,0 +1,90 @@
+use tunnel_protocol::{parse_cors_origins, validate_headers, validate_method, validate_path, HeaderLimits, ValidationError};
+
+fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+}
+
+#[test]
+fn methods_must_be_tokens() {
+    for method in ["GET", "PROPFIND", "M-SEARCH", "X_CUSTOM!"] {
+        assert_eq!(validate_method(method), Ok(()), "{}", method);
+    }
+    for method in ["", "GE T", "GET\r\n", "(GET)", "GÉT"] {
+        assert_eq!(validate_method(method), Err(ValidationError::InvalidMethod(method.to_string())), "{:?}", method);
+    }
+}
+
+#[test]
+fn paths_must_be_origin_form() {
+    for path in ["/", "/a/b?c=d&e=%20", "/~user/!$'()*+,;=:@"] {
+        assert_eq!(validate_path(path), Ok(()), "{}", path);
+    }
+    for path in ["", "*", "example.com:443", "http://example.com/", "/a b", "/a#frag", "/a\r\nX: y", "/é"] {
+        assert_eq!(validate_path(path), Err(ValidationError::InvalidTarget(path.to_string())), "{:?}", path);
+    }
+}
+
+#[test]
+fn header_names_must_be_tokens() {
+    assert_eq!(validate_headers(&headers(&[("x-request-id", "1"), ("Content-Type", "text/plain")])), Ok(()));
+    for name in ["", "x request", "x:y", "x\ny", "é"] {
+        assert_eq!(
+            validate_headers(&headers(&[(name, "value")])),
+            Err(ValidationError::InvalidHeaderName(name.to_string())),
+            "{:?}",
+            name
+        );
+    }
+}
+
+#[test]
+fn header_values_reject_control_characters() {
+    for value in ["", "a b\tc", "naïve", "\"quoted\", list; q=0.5"] {
+        assert_eq!(validate_headers(&headers(&[("x-test", value)])), Ok(()), "{:?}", value);
+    }
+    for value in ["a\r\nInjected: yes", "a\nb", "nul\0", "del\x7f"] {
+        assert_eq!(
+            validate_headers(&headers(&[("x-test", value)])),
+            Err(ValidationError::InvalidHeaderValue("x-test".to_string())),
+            "{:?}",
+            value
+        );
+    }
+}
+
+#[test]
+fn header_limits_count_lines_and_bytes() {
+    let limits = HeaderLimits { max_count: 2, max_bytes: 10 };
+    assert_eq!(limits.check(&headers(&[("a", "1234"), ("b", "1234")])), Ok(()));
+    assert_eq!(
+        limits.check(&headers(&[("a", "1"), ("a", "2"), ("a", "3")])),
+        Err(ValidationError::TooManyHeaders { count: 3, max: 2 })
+    );
+    assert_eq!(
+        limits.check(&headers(&[("a", "12345"), ("b", "12345")])),
+        Err(ValidationError::HeadersTooLarge { bytes: 12, max: 10 })
+    );
+}
+
+#[test]
+fn header_limits_round_trip_through_the_handshake_header() {
+    let limits = HeaderLimits { max_count: 20, max_bytes: 4096 };
+    assert_eq!(HeaderLimits::from_header_value(&limits.to_header_value()), limits);
+
+    // Unknown or malformed parts keep the defaults
+    let parsed = HeaderLimits::from_header_value("count=abc; bytes=512; future=1");
+    assert_eq!(parsed, HeaderLimits { max_bytes: 512, ..HeaderLimits::default() });
+}
+
+#[test]
+fn cors_origins_are_a_wildcard_or_a_list_of_origins() {
+    assert_eq!(parse_cors_origins(" * "), Ok(vec!["*".to_string()]));
+    assert_eq!(
+        parse_cors_origins("https://App.example.com, http://localhost:5173,"),
+        Ok(vec!["https://app.example.com".to_string(), "http://localhost:5173".to_string()])
+    );
+    assert_eq!(parse_cors_origins("http://[::1]:8080"), Ok(vec!["http://[::1]:8080".to_string()]));
+    for value in ["", " , ", "*,https://a.com", "a.com", "ftp://a.com", "https://", "https://a.com/path", "https://a .com"] {
+        assert!(parse_cors_origins(value).is_err(), "{:?}", value);
+    }
+}
@@ -0,0 +1,59 @@
+use tunnel_protocol::{
+    classify_frame, decode_body_frame, decode_request_frame, decode_response_frame, verify_roundtrip, BodyFrame, ControlFrame, Frame, FrameKind, RoundtripError,
+    TEST_VECTORS,
+};
+
+#[test]
+fn every_vector_round_trips() {
+    for vector in TEST_VECTORS {
+        if let Err(e) = verify_roundtrip(vector.kind, vector.payload) {
+            panic!("{}: {}", vector.name, e);
+        }
+    }
+}
+
+#[test]
+fn vector_names_are_unique() {
+    for (i, vector) in TEST_VECTORS.iter().enumerate() {
+        assert!(TEST_VECTORS[..i].iter().all(|other| other.name != vector.name), "{}", vector.name);
+    }
+}
+
+#[test]
+fn vectors_decode_to_what_they_stand_for() {
+    let payload = |name: &str| TEST_VECTORS.iter().find(|vector| vector.name == name).unwrap().payload;
+
+    let (request, body) = decode_request_frame(payload("request_binary")).unwrap();
+    assert_eq!((request.id, request.method.as_str(), request.path.as_str()), (Some(7), "POST", "/upload"));
+    assert_eq!(body, Some(&b"\x00\xff\r\n"[..]));
+    assert!(decode_request_frame(payload("request_binary_streamed")).unwrap().0.stream);
+
+    let (response, body) = decode_response_frame(payload("response_json_all_fields")).unwrap();
+    assert_eq!((response.id, response.status, response.local_duration_ms, body), (Some(3), 200, Some(5), None));
+    assert_eq!(response.body, "eyJvayI6dHJ1ZX0=");
+
+    let Ok(Frame::Control(ControlFrame::Stats(report))) = classify_frame(payload("stats")) else {
+        panic!("the stats vector is not a stats frame");
+    };
+    assert_eq!(report.latency_p99_ms, 210);
+    assert_eq!(decode_body_frame(payload("body_chunk")).unwrap(), BodyFrame::Chunk { id: 9, data: b"chunk" });
+    assert_eq!(decode_body_frame(payload("body_end_incomplete")).unwrap(), BodyFrame::End { id: 9, complete: false, sha256: None, trailers: Vec::new() });
+    let trailers = vec![("grpc-status".to_string(), "0".into()), ("x-latin1".to_string(), b"\xe9t\xe9"[..].into())];
+    assert_eq!(decode_body_frame(payload("body_end_trailers")).unwrap(), BodyFrame::End { id: 9, complete: true, sha256: None, trailers });
+}
+
+#[test]
+fn non_canonical_payloads_are_caught() {
+    // Same message, fields out of order
+    let reordered = br#"{"path":"/","method":"GET","headers":[],"body":""}"#;
+    assert!(matches!(verify_roundtrip(FrameKind::Request, reordered), Err(RoundtripError::Mismatch(_))));
+    // Optional fields written out with their defaults
+    let defaults = br#"{"status":200,"headers":[],"trailers":[],"stream":false,"body":""}"#;
+    assert!(matches!(verify_roundtrip(FrameKind::Response, defaults), Err(RoundtripError::Mismatch(_))));
+
+    assert!(matches!(verify_roundtrip(FrameKind::Request, br#"{"status":200}"#), Err(RoundtripError::Decode(_))));
+    assert!(matches!(verify_roundtrip(FrameKind::Ping, br#"{"pong":{}}"#), Err(RoundtripError::WrongKind(FrameKind::Ping))));
+    assert!(matches!(verify_roundtrip(FrameKind::Pong, br#"{"pong":{"late":true}}"#), Err(RoundtripError::Mismatch(_))));
+    let end = TEST_VECTORS.iter().find(|vector| vector.kind == FrameKind::BodyEnd).unwrap().payload;
+    assert!(matches!(verify_roundtrip(FrameKind::BodyChunk, end), Err(RoundtripError::WrongKind(FrameKind::BodyChunk))));
+}
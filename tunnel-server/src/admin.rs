@@ -0,0 +1,430 @@
+use axum::{
+    extract::{Path, RawQuery, Request, State},
+    http::{header, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::api_keys::Scope;
+use crate::history::ConnectionRecord;
+use crate::requests::{InFlightRequest, SlowCounts};
+use crate::traffic::{TunnelTraffic, Windows};
+use crate::usage::{self, TokenUsage};
+use crate::ServerState;
+use tracing::warn;
+use tunnel_core::logging::LogHandle;
+use tunnel_core::server::{PeerStats, TunnelConnection, WorkerStats};
+use tunnel_core::writes::WriteStats;
+use tunnel_protocol::{Compression, CompressionStats};
+
+/// Builds the admin API router (served on ADMIN_ADDR, separate from public traffic)
+pub fn router(state: ServerState) -> Router {
+    Router::new()
+        .route("/api/status", get(status))
+        .route("/api/tunnels", get(list_tunnels))
+        .route("/api/workers", get(worker_stats))
+        .route("/api/requests", get(in_flight_requests))
+        .route("/api/slow-requests", get(slow_requests))
+        .route("/api/traffic", get(traffic))
+        .route("/api/tokens", get(token_usage))
+        .route("/api/tokens/:name/history", get(token_history))
+        .route("/api/tokens/:name/certificate", delete(unbind_certificate))
+        .route("/api/log-level", get(get_log_level).put(set_log_level).delete(reset_log_level))
+        .route_layer(middleware::from_fn_with_state(state.clone(), authorize))
+        .with_state(state)
+}
+
+/// Checks the `Authorization: Bearer <key>` of a request against ADMIN_API_KEYS_FILE
+///
+/// GET requests need a `read` key, anything else a `manage` key. Without
+/// configured keys every request is let through.
+async fn authorize(State(state): State<ServerState>, request: Request, next: Next) -> Response {
+    if state.api_keys.is_empty() {
+        return next.run(request).await;
+    }
+
+    let needed = match *request.method() {
+        Method::GET | Method::HEAD => Scope::Read,
+        _ => Scope::Manage,
+    };
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match presented.and_then(|key| state.api_keys.find(key.trim())) {
+        Some(key) if key.scope >= needed => next.run(request).await,
+        Some(key) => {
+            warn!("Admin API key {} may not {} {}", key.name, request.method(), request.uri().path());
+            (StatusCode::FORBIDDEN, "This API key is read-only").into_response()
+        }
+        None => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer realm=\"admin\"")],
+            "A valid admin API key is required",
+        )
+            .into_response(),
+    }
+}
+
+/// Summary for scripts and health checks (`tunnel-server status`)
+#[derive(Serialize)]
+struct Status {
+    ready: bool,  // A tunnel client is connected, so public requests can be served
+    tunnel_id: Option<u64>,
+    connected_at: Option<u64>,  // Unix timestamp (seconds) of the active tunnel
+    requests: RequestCounts,
+    uptime_secs: u64,
+}
+
+#[derive(Serialize)]
+struct RequestCounts {
+    total: u64,  // Since the server started
+    in_flight: usize,
+    checksum_mismatches: u64,  // Responses refused for not matching their body checksum (TUNNEL_BODY_SHA256)
+}
+
+async fn status(State(state): State<ServerState>) -> Json<Status> {
+    let active = state.registry.active().await;
+    let tunnel = active.as_deref().map(TunnelInfo::from);
+    Json(Status {
+        ready: tunnel.is_some(),
+        tunnel_id: tunnel.as_ref().map(|tunnel| tunnel.id),
+        connected_at: tunnel.as_ref().map(|tunnel| tunnel.connected_at),
+        requests: RequestCounts {
+            total: state.requests.total(),
+            in_flight: state.requests.in_flight().len(),
+            checksum_mismatches: state.requests.checksum_mismatches(),
+        },
+        uptime_secs: state.started.elapsed().as_secs(),
+    })
+}
+
+/// Fetches `/api/status` from the admin API on `admin_addr`, for `tunnel-server status`
+///
+/// A wildcard bind address such as `0.0.0.0:9000` is reached on loopback.
+/// `token` is presented as the bearer API key when the admin API requires one.
+pub async fn query_status(admin_addr: &str, token: Option<&str>) -> Result<Value, String> {
+    let mut stream = match admin_addr.parse::<SocketAddr>() {
+        Ok(mut addr) => {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr.ip() {
+                    IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                });
+            }
+            TcpStream::connect(addr).await
+        }
+        Err(_) => TcpStream::connect(admin_addr).await,
+    }
+    .map_err(|e| format!("Failed to connect to admin API on {}: {}", admin_addr, e))?;
+
+    let mut request = format!("GET /api/status HTTP/1.0\r\nHost: {}\r\n", admin_addr);
+    if let Some(token) = token {
+        request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await
+        .map_err(|e| format!("Failed to send status request: {}", e))?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await
+        .map_err(|e| format!("Failed to read status response: {}", e))?;
+
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n")
+        .ok_or_else(|| "Malformed response from admin API".to_string())?;
+    let status_line = head.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some("200") => serde_json::from_str(body).map_err(|e| format!("Invalid status from admin API: {}", e)),
+        Some("401") | Some("403") => Err(format!("Admin API refused the request ({}); check ADMIN_API_TOKEN", status_line)),
+        _ => Err(format!("Admin API answered {}", status_line)),
+    }
+}
+
+/// Connected tunnel as reported by the admin API
+#[derive(Serialize)]
+struct TunnelInfo {
+    id: u64,
+    labels: BTreeMap<String, String>,
+    connected_at: u64,  // Unix timestamp (seconds)
+    token: String,  // Name its usage is counted under (see `/api/tokens`)
+    stats: Option<PeerStats>,  // Latest report from the client (None: none received yet)
+    in_flight: Option<usize>,  // Requests queued or being handled (None: TUNNEL_MAX_IN_FLIGHT unset)
+    draining: bool,  // Client sent GOAWAY; new requests get 503
+    visitor_auth: bool,  // Visitors must present the credentials the client set
+    https_only: bool,  // Plain-HTTP visitors are redirected to HTTPS
+    cors_origins: Vec<String>,  // Origins the server answers CORS for, or `*` (empty: left to the local service)
+    schedule: Option<String>,  // Windows visitors are routed in (None: always)
+    max_concurrent: usize,  // Requests sent to the client at once (1: it does not multiplex)
+    binary_frames: bool,  // Bodies travel as is rather than base64 in JSON
+    stream_bodies: bool,  // Large bodies are streamed in body frames
+    heartbeat: bool,  // Both ends send PINGs and drop the connection when unanswered
+    user_agent: Option<String>,  // Sent by the client at upgrade (None: not sent)
+    compression: Option<CompressionInfo>,  // None: off
+    cancel: bool,  // Requests given up on are cancelled on the client
+    writes: WriteStats,  // Frames waiting for the worker and how long its writes take
+}
+
+/// Compression of a tunnel, by direction
+#[derive(Serialize)]
+struct CompressionInfo {
+    algorithm: Compression,
+    to_client: CompressionStats,  // Frames the server writes
+    from_client: Option<CompressionStats>,  // From the client's latest stats report (None: none received yet)
+}
+
+impl From<&TunnelConnection> for TunnelInfo {
+    fn from(conn: &TunnelConnection) -> Self {
+        let stats = conn.peer_stats();
+        let compression = conn.compression.zip(conn.compression_stats()).map(|(algorithm, to_client)| CompressionInfo {
+            algorithm,
+            to_client,
+            from_client: stats.as_ref().and_then(|stats| stats.report.compression),
+        });
+        Self {
+            id: conn.id,
+            labels: conn.labels.clone(),
+            connected_at: conn
+                .connected_at
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            token: usage::token_name(conn).to_string(),
+            stats,
+            in_flight: conn.in_flight(),
+            draining: conn.is_draining(),
+            visitor_auth: conn.visitor_auth.is_some(),
+            https_only: conn.https_only,
+            cors_origins: conn.cors_origins.clone(),
+            schedule: conn.schedule.as_ref().map(ToString::to_string),
+            max_concurrent: conn.max_concurrent,
+            binary_frames: conn.binary_frames,
+            stream_bodies: conn.stream_bodies,
+            heartbeat: conn.heartbeat,
+            user_agent: conn.user_agent.clone(),
+            compression,
+            cancel: conn.cancel,
+            writes: conn.write_stats(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TunnelList {
+    tunnels: Vec<TunnelInfo>,
+}
+
+/// Lists connected tunnels, optionally filtered by `?label=key=value` (repeatable, all must match)
+async fn list_tunnels(
+    State(state): State<ServerState>,
+    RawQuery(query): RawQuery,
+) -> Result<Json<TunnelList>, (StatusCode, String)> {
+    let filters = parse_label_filters(query.as_deref().unwrap_or(""))
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    let active = state.registry.active().await;
+    let tunnels = active
+        .iter()
+        .filter(|conn| {
+            filters
+                .iter()
+                .all(|(key, value)| conn.labels.get(key) == Some(value))
+        })
+        .map(|conn| TunnelInfo::from(conn.as_ref()))
+        .collect();
+
+    Ok(Json(TunnelList { tunnels }))
+}
+
+/// Counts tunnel workers by how they ended, e.g. to alert on panics
+async fn worker_stats(State(state): State<ServerState>) -> Json<WorkerStats> {
+    Json(state.registry.worker_stats())
+}
+
+/// Slow requests so far and those currently in flight past the threshold
+#[derive(Serialize)]
+struct SlowRequests {
+    threshold_ms: Option<u64>,  // None: TUNNEL_SLOW_REQUEST_MS=0, nothing is tracked as slow
+    slow: SlowCounts,
+    hung: Vec<InFlightRequest>,
+}
+
+async fn slow_requests(State(state): State<ServerState>) -> Json<SlowRequests> {
+    Json(SlowRequests {
+        threshold_ms: state.requests.slow_threshold().map(|threshold| threshold.as_millis() as u64),
+        slow: state.requests.slow_counts(),
+        hung: state.requests.hung(),
+    })
+}
+
+/// Recent traffic of all tunnels together and of each one
+#[derive(Serialize)]
+struct Traffic {
+    global: Windows,
+    tunnels: Vec<TunnelTrafficInfo>,  // Tunnels with requests in the last 15 minutes
+}
+
+#[derive(Serialize)]
+struct TunnelTrafficInfo {
+    #[serde(flatten)]
+    traffic: TunnelTraffic,
+    connected: bool,  // Still the active tunnel
+}
+
+/// Request rates, error rates and latency percentiles over the last 1, 5 and 15 minutes
+async fn traffic(State(state): State<ServerState>) -> Json<Traffic> {
+    let active = state.registry.active().await.map(|conn| conn.id);
+    let tunnels = state.traffic.tunnels()
+        .into_iter()
+        .map(|traffic| TunnelTrafficInfo { connected: Some(traffic.id) == active, traffic })
+        .collect();
+    Json(Traffic { global: state.traffic.global(), tunnels })
+}
+
+#[derive(Serialize)]
+struct RequestList {
+    requests: Vec<InFlightRequest>,
+}
+
+/// Requests in flight with their phase and bytes moved, for watching large transfers
+async fn in_flight_requests(State(state): State<ServerState>) -> Json<RequestList> {
+    Json(RequestList { requests: state.requests.in_flight() })
+}
+
+/// Usage of one tunnel credential
+#[derive(Serialize)]
+struct TokenInfo {
+    name: String,
+    #[serde(flatten)]
+    usage: TokenUsage,
+}
+
+#[derive(Serialize)]
+struct TokenList {
+    tokens: Vec<TokenInfo>,
+}
+
+/// Usage of every tunnel credential seen; `?unused_days=N` keeps those not connected for N days
+async fn token_usage(
+    State(state): State<ServerState>,
+    RawQuery(query): RawQuery,
+) -> Result<Json<TokenList>, (StatusCode, String)> {
+    let params: Vec<(String, String)> = serde_urlencoded::from_str(query.as_deref().unwrap_or(""))
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid query string: {}", e)))?;
+    let unused_days = match params.iter().find(|(name, _)| name == "unused_days") {
+        Some((_, days)) => Some(days.parse::<u64>()
+            .map_err(|_| (StatusCode::BAD_REQUEST, format!("Invalid unused_days: {}", days)))?),
+        None => None,
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let tokens = state.usage.list()
+        .into_iter()
+        .filter(|(_, usage)| match unused_days {
+            Some(days) => usage.last_connected_at.is_none_or(|at| now.saturating_sub(at) >= days * 24 * 60 * 60),
+            None => true,
+        })
+        .map(|(name, usage)| TokenInfo { name, usage })
+        .collect();
+
+    Ok(Json(TokenList { tokens }))
+}
+
+/// Recent connections of one tunnel credential
+#[derive(Serialize)]
+struct TokenHistory {
+    name: String,
+    connections: Vec<ConnectionRecord>,  // Most recent first
+}
+
+/// Recent connections of a credential, with how each one ended
+async fn token_history(State(state): State<ServerState>, Path(name): Path<String>) -> Result<Json<TokenHistory>, (StatusCode, String)> {
+    match state.history.connections(&name) {
+        Some(connections) => Ok(Json(TokenHistory { name, connections })),
+        None => Err((StatusCode::NOT_FOUND, format!("No connections with credential {}", name))),
+    }
+}
+
+/// Releases a credential from its client certificate (TOKEN_CERT_BINDING), e.g. after the client's key was replaced
+async fn unbind_certificate(State(state): State<ServerState>, Path(name): Path<String>) -> Result<StatusCode, (StatusCode, String)> {
+    match state.usage.unbind_certificate(&name) {
+        Some(pin) => {
+            warn!("Credential {} released from client certificate {} (admin API)", name, pin);
+            Ok(StatusCode::NO_CONTENT)
+        }
+        None => Err((StatusCode::NOT_FOUND, format!("Credential {} is not bound to a client certificate", name))),
+    }
+}
+
+/// Extracts `label=key=value` pairs from a query string
+fn parse_label_filters(query: &str) -> Result<Vec<(String, String)>, String> {
+    let params: Vec<(String, String)> = serde_urlencoded::from_str(query)
+        .map_err(|e| format!("Invalid query string: {}", e))?;
+
+    params
+        .into_iter()
+        .filter(|(name, _)| name == "label")
+        .map(|(_, label)| tunnel_protocol::parse_label(&label))
+        .collect()
+}
+
+/// Current and configured log filter
+#[derive(Serialize)]
+struct LogLevel {
+    level: String,
+    configured: String,
+}
+
+/// Body of `PUT /api/log-level`
+#[derive(Deserialize)]
+struct SetLogLevel {
+    level: String,
+}
+
+type LogLevelResult = Result<Json<LogLevel>, (StatusCode, String)>;
+
+/// Returns the log handle, or 503 when the server was started without one
+fn log_handle(state: &ServerState) -> Result<&LogHandle, (StatusCode, String)> {
+    state.log_handle.as_ref().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Log level control is not available".to_string(),
+    ))
+}
+
+fn log_level(handle: &LogHandle) -> Json<LogLevel> {
+    Json(LogLevel {
+        level: handle.level(),
+        configured: handle.configured().to_string(),
+    })
+}
+
+/// Reports the current log filter
+async fn get_log_level(State(state): State<ServerState>) -> LogLevelResult {
+    Ok(log_level(log_handle(&state)?))
+}
+
+/// Replaces the log filter, e.g. `{"level":"debug"}`, until reset or restart
+async fn set_log_level(State(state): State<ServerState>, Json(body): Json<SetLogLevel>) -> LogLevelResult {
+    let handle = log_handle(&state)?;
+    handle.set_level(&body.level).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    warn!("Log level changed to {} (admin API)", body.level);
+    Ok(log_level(handle))
+}
+
+/// Restores the configured log filter
+async fn reset_log_level(State(state): State<ServerState>) -> LogLevelResult {
+    let handle = log_handle(&state)?;
+    handle.reset().map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    warn!("Log level reset to {} (admin API)", handle.configured());
+    Ok(log_level(handle))
+}
@@ -0,0 +1,96 @@
+//! API keys for the admin API, read from ADMIN_API_KEYS_FILE.
+//!
+//! One key per line: `<scope> <key> [name]`. A `read` key may only make GET
+//! requests, e.g. for a dashboard; a `manage` key may also change settings
+//! such as the log level. Blank lines and lines starting with `#` are ignored.
+//! Without any keys the admin API is open, as before keys existed, and should
+//! only be bound to a private address.
+
+use std::path::Path;
+use tunnel_core::rules;
+
+/// Shortest key accepted, so that keys cannot be guessed
+pub const MIN_KEY_LEN: usize = 16;
+
+/// What a key may do; `Manage` includes everything `Read` may do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    Read,
+    Manage,
+}
+
+impl Scope {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "read" => Some(Scope::Read),
+            "manage" => Some(Scope::Manage),
+            _ => None,
+        }
+    }
+}
+
+/// One admin API key
+pub struct ApiKey {
+    pub name: String,  // Shown in logs; the line number when the file gives none
+    pub scope: Scope,
+    key: String,
+}
+
+/// Every admin API key the server accepts
+#[derive(Default)]
+pub struct ApiKeys {
+    keys: Vec<ApiKey>,
+}
+
+impl ApiKeys {
+    /// Reads the keys from `path`
+    pub fn load(path: &Path) -> Result<Self, String> {
+        rules::load(path, "ADMIN_API_KEYS_FILE", Self::parse)
+    }
+
+    /// Parses the contents of a keys file
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut keys: Vec<ApiKey> = Vec::new();
+
+        for line in rules::lines(contents) {
+            let mut fields = line.fields();
+            let (Some(scope), Some(key)) = (fields.next(), fields.next()) else {
+                return Err(line.error("expected '<scope> <key> [name]'"));
+            };
+            let scope = Scope::parse(scope)
+                .ok_or_else(|| line.error(format!("unknown scope '{}' (expected read or manage)", scope)))?;
+            if key.len() < MIN_KEY_LEN {
+                return Err(line.error(format!("keys must be at least {} characters", MIN_KEY_LEN)));
+            }
+            if keys.iter().any(|existing| existing.key == key) {
+                return Err(line.error("duplicate key"));
+            }
+            let name = fields.next().map(str::to_string).unwrap_or_else(|| format!("line {}", line.number));
+            keys.push(ApiKey { name, scope, key: key.to_string() });
+        }
+
+        Ok(Self { keys })
+    }
+
+    /// Whether no keys are configured, leaving the admin API open
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// The key equal to `presented`, if any
+    pub fn find(&self, presented: &str) -> Option<&ApiKey> {
+        // Every key is compared in full, so timing does not reveal how much of one matched
+        self.keys
+            .iter()
+            .fold(None, |found, key| if constant_time_eq(key.key.as_bytes(), presented.as_bytes()) { Some(key) } else { found })
+    }
+}
+
+/// Compares two byte strings in time that depends only on their lengths
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
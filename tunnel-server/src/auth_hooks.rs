@@ -0,0 +1,222 @@
+//! External authorization hooks, read from AUTH_HOOK_RULES_FILE.
+//!
+//! One rule per line: `<path-prefix> <hook-url>`. Before a request whose path
+//! starts with the prefix is forwarded, the server POSTs a JSON description of
+//! it to the hook:
+//!
+//! ```json
+//! {"method": "GET", "path": "/admin/users?page=2", "visitor_addr": "203.0.113.7", "headers": {"authorization": "Bearer ..."}}
+//! ```
+//!
+//! A 2xx answer lets the request through; 401 or 403 is passed on to the
+//! visitor (with the hook's body and WWW-Authenticate header); anything else,
+//! or no answer within AUTH_HOOK_TIMEOUT_MS, refuses the request with 503. The
+//! first matching rule applies. Blank lines and lines starting with `#` are
+//! ignored; paths are compared as for internal-only routes.
+//!
+//! Answers are cached for AUTH_HOOK_CACHE_SECS per hook and exact request
+//! description, so a visitor repeating a request does not call the hook again.
+
+use axum::http::HeaderMap;
+use reqwest::Url;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tunnel_core::rules;
+
+use crate::internal_routes::{canonical_path, under_prefix};
+
+/// Default for AUTH_HOOK_CACHE_SECS
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// Default for AUTH_HOOK_TIMEOUT_MS
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Most answers kept; a full cache is pruned of expired ones, then cleared
+const MAX_CACHED: usize = 10_000;
+
+/// One line of the rules file
+#[derive(Debug)]
+pub struct AuthHookRule {
+    pub path_prefix: String,
+    pub url: Url,
+}
+
+/// What a hook decided about a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Deny {
+        status: u16,                         // 401 or 403
+        www_authenticate: Option<String>,    // The hook's challenge, for 401
+        body: String,
+    },
+    Unavailable(String),  // The hook failed or gave no usable answer; never cached
+}
+
+/// Request description posted to a hook
+#[derive(Serialize)]
+struct HookRequest<'a> {
+    method: &'a str,
+    path: &'a str,
+    visitor_addr: Option<IpAddr>,
+    headers: BTreeMap<String, String>,  // Repeated headers joined with ", "
+}
+
+/// Every authorization hook the server consults, with their cached answers
+#[derive(Debug)]
+pub struct AuthHooks {
+    rules: Vec<AuthHookRule>,
+    http: reqwest::Client,
+    cache_ttl: Duration,  // Zero: answers are not cached
+    cache: Mutex<HashMap<(usize, String), (Verdict, Instant)>>,  // By rule index and request description
+}
+
+impl Default for AuthHooks {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl AuthHooks {
+    fn new(rules: Vec<AuthHookRule>) -> Self {
+        Self {
+            rules,
+            http: Self::http_client(DEFAULT_TIMEOUT),
+            cache_ttl: DEFAULT_CACHE_TTL,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn http_client(timeout: Duration) -> reqwest::Client {
+        // Redirects are not followed: a hook answering 3xx is misconfigured
+        reqwest::Client::builder()
+            .timeout(timeout)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("HTTP client with default TLS settings")
+    }
+
+    /// Reads the rules from `path`
+    pub fn load(path: &Path) -> Result<Self, String> {
+        rules::load(path, "AUTH_HOOK_RULES_FILE", Self::parse)
+    }
+
+    /// Parses the contents of a rules file
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut rules = Vec::new();
+
+        for line in rules::lines(contents) {
+            let fields: Vec<&str> = line.fields().collect();
+            let [path_prefix, url] = fields[..] else {
+                return Err(line.error("expected '<path-prefix> <hook-url>'"));
+            };
+            if !path_prefix.starts_with('/') {
+                return Err(line.error("path prefix must start with '/'"));
+            }
+            let url = Url::parse(url)
+                .ok()
+                .filter(|url| matches!(url.scheme(), "http" | "https"))
+                .ok_or_else(|| line.error(format!("invalid hook URL '{}' (expected http:// or https://)", url)))?;
+            rules.push(AuthHookRule { path_prefix: canonical_path(path_prefix), url });
+        }
+
+        Ok(Self::new(rules))
+    }
+
+    /// Sets how long answers are cached (zero: not at all)
+    pub fn with_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// Sets how long the server waits for a hook to answer
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.http = Self::http_client(timeout);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Asks the hook for `target` (path and query), if any, about a request; None: no hook applies
+    pub async fn check(&self, method: &str, target: &str, visitor: Option<IpAddr>, headers: &HeaderMap) -> Option<Verdict> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        let path = canonical_path(target);
+        let (index, rule) = self.rules.iter().enumerate().find(|(_, rule)| under_prefix(&path, &rule.path_prefix))?;
+
+        let mut described = BTreeMap::<String, String>::new();
+        for (name, value) in headers {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            described.entry(name.as_str().to_string())
+                .and_modify(|joined| {
+                    joined.push_str(", ");
+                    joined.push_str(&value);
+                })
+                .or_insert_with(|| value.into_owned());
+        }
+        let description = HookRequest { method, path: target, visitor_addr: visitor, headers: described };
+        let body = serde_json::to_string(&description).expect("request description serializes");
+
+        let key = (index, body);
+        if let Some(verdict) = self.cached(&key) {
+            return Some(verdict);
+        }
+        let verdict = self.call(&rule.url, key.1.clone()).await;
+        if !matches!(verdict, Verdict::Unavailable(_)) {
+            self.remember(key, verdict.clone());
+        }
+        Some(verdict)
+    }
+
+    async fn call(&self, url: &Url, body: String) -> Verdict {
+        let response = match self.http.post(url.clone()).header("content-type", "application/json").body(body).send().await {
+            Ok(response) => response,
+            Err(e) => return Verdict::Unavailable(format!("Authorization hook {} failed: {}", url, e)),
+        };
+        let status = response.status().as_u16();
+        match status {
+            200..=299 => Verdict::Allow,
+            401 | 403 => {
+                let www_authenticate = response.headers()
+                    .get("www-authenticate")
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string);
+                let body = response.text().await.unwrap_or_default();
+                Verdict::Deny { status, www_authenticate, body }
+            }
+            _ => Verdict::Unavailable(format!("Authorization hook {} answered {}", url, status)),
+        }
+    }
+
+    fn cached(&self, key: &(usize, String)) -> Option<Verdict> {
+        let cache = self.cache.lock().unwrap();
+        let (verdict, expires) = cache.get(key)?;
+        (Instant::now() < *expires).then(|| verdict.clone())
+    }
+
+    fn remember(&self, key: (usize, String), verdict: Verdict) {
+        if self.cache_ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHED {
+            cache.retain(|_, (_, expires)| now < *expires);
+            if cache.len() >= MAX_CACHED {
+                cache.clear();
+            }
+        }
+        cache.insert(key, (verdict, now + self.cache_ttl));
+    }
+}
@@ -0,0 +1,99 @@
+//! CORS answered at the server for tunnels whose client asked for it
+//! (CORS_ORIGINS), so the local service needs no CORS setup of its own.
+//!
+//! Preflight requests are answered without reaching the client. Responses to
+//! requests from an allowed origin get the CORS headers, replacing any the
+//! local service set. With `*` any origin is allowed, without credentials;
+//! listed origins are echoed back and may send credentials.
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode},
+};
+
+use crate::ERROR_CODE_HEADER;
+
+/// How long browsers may cache a preflight answer, in seconds
+const PREFLIGHT_MAX_AGE: &str = "600";
+
+fn is_wildcard(origins: &[String]) -> bool {
+    origins.iter().any(|origin| origin == "*")
+}
+
+fn is_allowed(origins: &[String], origin: &HeaderValue) -> bool {
+    let Ok(origin) = origin.to_str() else {
+        return false;
+    };
+    origins.iter().any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+}
+
+/// Sets Access-Control-Allow-Origin (and -Credentials) for an allowed `origin`
+fn allow_origin(origins: &[String], origin: &HeaderValue, headers: &mut HeaderMap) {
+    if is_wildcard(origins) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        headers.remove(header::ACCESS_CONTROL_ALLOW_CREDENTIALS);
+    } else {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+}
+
+/// Answers a preflight request (OPTIONS with Origin and Access-Control-Request-Method)
+///
+/// Returns None for any other request, which is forwarded as usual. A
+/// preflight from an origin that is not allowed gets 403.
+pub fn preflight(origins: &[String], request: &Request<Body>) -> Option<Response<Body>> {
+    let headers = request.headers();
+    let origin = headers.get(header::ORIGIN)?;
+    let requested_method = headers.get(header::ACCESS_CONTROL_REQUEST_METHOD)?;
+    if request.method() != Method::OPTIONS {
+        return None;
+    }
+
+    if !is_allowed(origins, origin) {
+        return Some(
+            Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .header(ERROR_CODE_HEADER, "cors-origin-denied")
+                .body(Body::from("Origin not allowed"))
+                .unwrap(),
+        );
+    }
+
+    let mut response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(header::ACCESS_CONTROL_ALLOW_METHODS, requested_method)
+        .header(header::ACCESS_CONTROL_MAX_AGE, PREFLIGHT_MAX_AGE);
+    if let Some(requested_headers) = headers.get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+        response = response.header(header::ACCESS_CONTROL_ALLOW_HEADERS, requested_headers);
+    }
+    let mut response = response.body(Body::empty()).unwrap();
+    allow_origin(origins, origin, response.headers_mut());
+    Some(response)
+}
+
+/// Adds the CORS headers to the response to a request from `origin`, if it is allowed
+///
+/// Every header of the response is exposed to the page.
+pub fn add_headers(origins: &[String], origin: Option<&HeaderValue>, response: &mut Response<Body>) {
+    let Some(origin) = origin.filter(|origin| is_allowed(origins, origin)) else {
+        return;
+    };
+    let headers = response.headers_mut();
+    let exposed = headers
+        .keys()
+        .map(|name| name.as_str())
+        .filter(|name| !name.starts_with("access-control-"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    match HeaderValue::from_str(&exposed) {
+        Ok(exposed) if !exposed.is_empty() => {
+            headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, exposed);
+        }
+        _ => {
+            headers.remove(header::ACCESS_CONTROL_EXPOSE_HEADERS);
+        }
+    }
+    allow_origin(origins, origin, headers);
+}
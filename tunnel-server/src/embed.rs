@@ -0,0 +1,205 @@
+//! Builder for running the tunnel server inside another application.
+//!
+//! `TunnelServer::builder()` takes what the binary reads from HTTP_ADDR,
+//! TUNNEL_AUTH, TUNNEL_PATH and the transport settings; anything else is set
+//! on the [`ServerState`] through `configure`. The built server either serves
+//! on its own (`serve`), or hands out its [`router`](TunnelServer::router) to
+//! merge into an existing Axum app: the app's routes are matched first and
+//! every other request is forwarded through the tunnel. Serve the merged app
+//! with [`http::serve`](crate::http::serve), or `axum::serve` with
+//! `into_make_service_with_connect_info::<SocketAddr>()`, so visitor addresses
+//! reach the access log and per-visitor limits; only `http::serve` keeps the
+//! case of header names.
+//!
+//! An app that forwards only some of its routes mounts
+//! [`tunnel_routes`](crate::tunnel_routes) instead and wraps itself in a
+//! [`TunnelLayer`] naming the path prefixes to forward; the rest of its
+//! requests reach its own routes as before.
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{Request, Response};
+use axum::Router;
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tower::{Layer, Service, ServiceExt};
+use tunnel_core::config::parse_tunnel_path;
+use tunnel_core::transport::TransportOptions;
+use tunnel_protocol::DEFAULT_TUNNEL_PATH;
+
+use crate::internal_routes::under_prefix;
+use crate::{admin, http, http_handler, router, ServerState};
+
+/// Default for HTTP_ADDR
+pub const DEFAULT_HTTP_ADDR: &str = "0.0.0.0:8080";
+
+type Configure = Box<dyn FnOnce(ServerState) -> ServerState + Send>;
+
+/// Tunnel server set up in code rather than from the environment
+pub struct TunnelServer {
+    addr: String,
+    transport: TransportOptions,
+    state: ServerState,
+}
+
+/// Why [`TunnelServerBuilder::build`] failed
+#[derive(Debug, Error)]
+pub enum BuildError {
+    #[error("{0}")]
+    TunnelPath(String),
+    #[error("Tunnel credentials must be in format 'username:password'")]
+    Credentials,
+}
+
+/// Settings of a [`TunnelServer`] being built
+pub struct TunnelServerBuilder {
+    addr: String,               // Listened on by `serve`
+    auth: Option<String>,       // username:password required of tunnel clients (None: any client)
+    tunnel_path: String,        // Route of the upgrade endpoint
+    transport: TransportOptions,
+    configure: Vec<Configure>,  // Applied to the state in order
+}
+
+impl TunnelServer {
+    pub fn builder() -> TunnelServerBuilder {
+        TunnelServerBuilder {
+            addr: DEFAULT_HTTP_ADDR.to_string(),
+            auth: None,
+            tunnel_path: DEFAULT_TUNNEL_PATH.to_string(),
+            transport: TransportOptions::default(),
+            configure: Vec::new(),
+        }
+    }
+
+    /// Shared state, e.g. for the tunnel registry
+    pub fn state(&self) -> &ServerState {
+        &self.state
+    }
+
+    /// The upgrade endpoint plus forwarding of every other request, to serve or merge
+    pub fn router(&self) -> Router {
+        router(self.state.clone())
+    }
+
+    /// The admin API, to serve on a listener of its own
+    pub fn admin_router(&self) -> Router {
+        admin::router(self.state.clone())
+    }
+
+    /// Binds the address set with `bind` and serves [`router`](Self::router) until the listener fails
+    pub async fn serve(self) -> io::Result<()> {
+        let listener = TcpListener::bind(&self.addr).await?;
+        self.transport.apply_to_listener(&listener)?;
+        http::serve(listener, self.router(), self.transport.tcp_nodelay, std::future::pending()).await;
+        Ok(())
+    }
+}
+
+impl TunnelServerBuilder {
+    /// Sets the address `serve` listens on (default 0.0.0.0:8080)
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.addr = addr.into();
+        self
+    }
+
+    /// Requires tunnel clients to authenticate with `credentials` (username:password)
+    pub fn auth(mut self, credentials: impl Into<String>) -> Self {
+        self.auth = Some(credentials.into());
+        self
+    }
+
+    /// Serves the upgrade endpoint at `path` rather than /tunnel
+    pub fn tunnel_path(mut self, path: impl Into<String>) -> Self {
+        self.tunnel_path = path.into();
+        self
+    }
+
+    /// Replaces the default transport options
+    pub fn transport(mut self, transport: TransportOptions) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Changes the state further once built, e.g. with `ServerState::with_queue`
+    pub fn configure(mut self, configure: impl FnOnce(ServerState) -> ServerState + Send + 'static) -> Self {
+        self.configure.push(Box::new(configure));
+        self
+    }
+
+    /// Builds the server; fails on an invalid tunnel path or credentials
+    pub fn build(self) -> Result<TunnelServer, BuildError> {
+        let tunnel_path = parse_tunnel_path(&self.tunnel_path).map_err(BuildError::TunnelPath)?;
+        if self.auth.as_ref().is_some_and(|auth| !auth.contains(':')) {
+            return Err(BuildError::Credentials);
+        }
+        let state = ServerState::new(self.auth, &self.transport).with_tunnel_path(tunnel_path);
+        let state = self.configure.into_iter().fold(state, |state, configure| configure(state));
+        Ok(TunnelServer { addr: self.addr, transport: self.transport, state })
+    }
+}
+
+/// Layer forwarding the requests under some path prefixes through the tunnel
+///
+/// A prefix covers whole path segments: `/preview` matches `/preview` and
+/// `/preview/page` but not `/previewer`. Paths are forwarded as they are,
+/// prefix included; every other request goes to the wrapped service.
+#[derive(Clone)]
+pub struct TunnelLayer {
+    state: ServerState,
+    prefixes: Arc<[String]>,
+}
+
+impl TunnelLayer {
+    /// Forwards requests whose path is under one of `prefixes` (e.g. `/preview`) through `state`'s tunnel
+    pub fn new<P: Into<String>>(state: ServerState, prefixes: impl IntoIterator<Item = P>) -> Self {
+        Self { state, prefixes: prefixes.into_iter().map(Into::into).collect() }
+    }
+
+    fn forwards(&self, path: &str) -> bool {
+        self.prefixes.iter().any(|prefix| under_prefix(path, prefix))
+    }
+}
+
+impl<S> Layer<S> for TunnelLayer {
+    type Service = TunnelService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TunnelService { inner, layer: self.clone() }
+    }
+}
+
+/// Service made by [`TunnelLayer`]
+#[derive(Clone)]
+pub struct TunnelService<S> {
+    inner: S,
+    layer: TunnelLayer,
+}
+
+impl<S> Service<Request<Body>> for TunnelService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, Infallible>> + Send>>;
+
+    // Readiness is checked per request, on a clone of the inner service
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        if self.layer.forwards(request.uri().path()) {
+            let state = self.layer.state.clone();
+            return Box::pin(async move { Ok(http_handler(State(state), request).await) });
+        }
+        Box::pin(self.inner.clone().oneshot(request))
+    }
+}
@@ -0,0 +1,198 @@
+//! Zero-downtime upgrades: a new server process takes over the listening sockets.
+//!
+//! On SIGUSR2 the server starts its binary again (the file now on disk, so a
+//! freshly installed version) with the same arguments, and the new process
+//! inherits the HTTP and admin listeners instead of binding its own. Once it
+//! is ready, the old process stops accepting, finishes the requests it has
+//! (up to UPGRADE_DRAIN_SECS) and exits, which closes its tunnel. The client
+//! then reconnects to the new process, where requests wait for it as after any
+//! disconnect (TUNNEL_RECONNECT_GRACE_MS). As both processes share the same
+//! sockets, no connection waiting to be accepted is dropped.
+
+use socket2::SockRef;
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::process::Command;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Environment variable naming the descriptors a new process inherits, `pid=P,http=N[,admin=N],ready=N`
+pub const FDS_ENV: &str = "TUNNEL_HANDOVER_FDS";
+
+/// How long a new process may take to get ready before the upgrade is abandoned
+pub const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Listening sockets that can be handed over
+pub struct Listeners {
+    pub http: TcpListener,
+    pub admin: Option<TcpListener>,
+}
+
+impl Listeners {
+    /// Copies of the sockets of `http` and `admin`, kept for a successor while the originals are served
+    pub fn copy(http: &tokio::net::TcpListener, admin: Option<&tokio::net::TcpListener>) -> io::Result<Self> {
+        let copy = |listener: &tokio::net::TcpListener| SockRef::from(listener).try_clone().map(TcpListener::from);
+        Ok(Self { http: copy(http)?, admin: admin.map(copy).transpose()? })
+    }
+
+    /// Sets whether the sockets are passed on to processes started from now on
+    fn set_inheritable(&self, inheritable: bool) -> io::Result<()> {
+        SockRef::from(&self.http).set_cloexec(!inheritable)?;
+        if let Some(admin) = &self.admin {
+            SockRef::from(admin).set_cloexec(!inheritable)?;
+        }
+        Ok(())
+    }
+}
+
+/// Descriptor numbers in FDS_ENV
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HandoverFds {
+    pub pid: u32,            // Process that handed them over, the parent of the one taking them
+    pub http: RawFd,
+    pub admin: Option<RawFd>,
+    pub ready: RawFd,        // Socket the new process writes to once it is ready
+}
+
+impl HandoverFds {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid {}: {}", FDS_ENV, value);
+        let (mut pid, mut http, mut admin, mut ready) = (None, None, None, None);
+        for field in value.split(',') {
+            let (name, number) = field.split_once('=').ok_or_else(invalid)?;
+            let number = number.parse::<u32>().map_err(|_| invalid())?;
+            match name {
+                "pid" => pid = Some(number),
+                "http" => http = Some(number as RawFd),
+                "admin" => admin = Some(number as RawFd),
+                "ready" => ready = Some(number as RawFd),
+                _ => return Err(invalid()),
+            }
+        }
+        match (pid, http, ready) {
+            (Some(pid), Some(http), Some(ready)) => Ok(Self { pid, http, admin, ready }),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Value of FDS_ENV
+    pub fn to_env(&self) -> String {
+        let admin = self.admin.map(|fd| format!(",admin={}", fd)).unwrap_or_default();
+        format!("pid={},http={}{},ready={}", self.pid, self.http, admin, self.ready)
+    }
+}
+
+/// Takes over the sockets named in FDS_ENV, if this process was started by an upgrade
+pub fn inherit() -> Result<Option<(Listeners, Ready)>, String> {
+    let Some(value) = std::env::var_os(FDS_ENV) else {
+        return Ok(None);
+    };
+    let fds = HandoverFds::parse(&value.to_string_lossy())?;
+    // Otherwise the variable was inherited from further up, and the descriptors are not ours
+    if fds.pid != std::os::unix::process::parent_id() {
+        return Ok(None);
+    }
+
+    // SAFETY: our parent opened these descriptors for this process alone (checked
+    // through the pid above) and nothing else here refers to them; each is adopted once
+    let adopt = |fd: RawFd| unsafe { OwnedFd::from_raw_fd(fd) };
+    let listener = |fd: RawFd| -> Result<TcpListener, String> {
+        let listener = TcpListener::from(adopt(fd));
+        SockRef::from(&listener).set_cloexec(true)
+            .and_then(|()| listener.set_nonblocking(true))
+            .map_err(|e| format!("Inherited listener {} unusable: {}", fd, e))?;
+        Ok(listener)
+    };
+    let listeners = Listeners { http: listener(fds.http)?, admin: fds.admin.map(listener).transpose()? };
+    let ready = UnixStream::from(adopt(fds.ready));
+    SockRef::from(&ready).set_cloexec(true).map_err(|e| format!("Inherited socket {} unusable: {}", fds.ready, e))?;
+    Ok(Some((listeners, Ready(ready))))
+}
+
+/// Tells the process that handed its listeners over that this one serves now
+pub struct Ready(UnixStream);
+
+impl Ready {
+    pub fn send(mut self) -> io::Result<()> {
+        self.0.write_all(b"1")
+    }
+}
+
+/// Starts the server binary again with the same arguments, handing it `listeners`
+///
+/// Returns the new process ID once it is ready; fails, leaving this process in
+/// charge, if it exits first (e.g. a configuration the new version rejects)
+/// or takes longer than READY_TIMEOUT.
+pub async fn spawn_successor(listeners: &Listeners) -> Result<u32, String> {
+    let exe = std::env::current_exe().map_err(|e| format!("Cannot locate the server binary: {}", e))?;
+    let (ready, successor_end) = UnixStream::pair().map_err(|e| e.to_string())?;
+    let fds = HandoverFds {
+        pid: std::process::id(),
+        http: listeners.http.as_raw_fd(),
+        admin: listeners.admin.as_ref().map(|admin| admin.as_raw_fd()),
+        ready: successor_end.as_raw_fd(),
+    };
+
+    // Inheritable just around the spawn, so nothing else started later gets them
+    let set_inheritable = |inheritable: bool| {
+        listeners.set_inheritable(inheritable).and_then(|()| SockRef::from(&successor_end).set_cloexec(!inheritable))
+    };
+    set_inheritable(true).map_err(|e| e.to_string())?;
+    let spawned = Command::new(&exe).args(std::env::args_os().skip(1)).env(FDS_ENV, fds.to_env()).spawn();
+    if let Err(e) = set_inheritable(false) {
+        warn!("Failed to make listeners private again: {}", e);
+    }
+    let mut successor = spawned.map_err(|e| format!("Failed to start {}: {}", exe.display(), e))?;
+    drop(successor_end);
+    let pid = successor.id();
+
+    tokio::task::spawn_blocking(move || {
+        let mut ready = ready;
+        let mut byte = [0u8; 1];
+        let read = ready.set_read_timeout(Some(READY_TIMEOUT)).and_then(|()| ready.read(&mut byte));
+        match read {
+            Ok(1) => Ok(pid),
+            Ok(_) => {
+                let status = successor.wait().map(|status| status.to_string()).unwrap_or_default();
+                Err(format!("New server process {} exited before it was ready ({})", pid, status))
+            }
+            Err(e) => {
+                let _ = successor.kill();
+                let _ = successor.wait();
+                Err(format!("New server process {} not ready in time ({}); stopped it", pid, e))
+            }
+        }
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Hands `listeners` to a new server process on each SIGUSR2 until one takes over
+///
+/// `before` runs ahead of each attempt (e.g. to save state the new process loads).
+/// Never returns if signals cannot be received.
+pub async fn upgrade_on_signal(listeners: &Listeners, before: impl Fn()) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::user_defined2()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!("SIGUSR2 upgrades unavailable: {}", e);
+            return std::future::pending().await;
+        }
+    };
+    while signals.recv().await.is_some() {
+        info!("Upgrade requested; starting a new server process");
+        before();
+        match spawn_successor(listeners).await {
+            Ok(pid) => {
+                info!("New server process {} is ready; draining this one", pid);
+                return;
+            }
+            Err(e) => error!("Upgrade failed: {}", e),
+        }
+    }
+    std::future::pending().await
+}
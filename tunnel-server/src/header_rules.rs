@@ -0,0 +1,112 @@
+//! Response header rules, read from RESPONSE_HEADER_RULES_FILE.
+//!
+//! One rule per line: `<path-prefix> <allow|deny> <header>[,<header>...]`.
+//! Every rule whose prefix matches the request path applies to the response
+//! from the tunnel client: `deny` removes the listed headers, `allow` removes
+//! every header not listed. A name ending in `*` matches by prefix, e.g.
+//! `x-debug-*`. Names are case-insensitive. Blank lines and lines starting
+//! with `#` are ignored.
+//!
+//! Content-Length and Transfer-Encoding describe the body and are never removed.
+
+use std::path::Path;
+use tunnel_core::rules;
+use tunnel_protocol::HeaderValueBytes;
+
+/// What a rule does with the headers it lists
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,  // Only the listed headers are kept
+    Deny,   // The listed headers are removed
+}
+
+/// One line of the rules file
+#[derive(Debug)]
+pub struct HeaderRule {
+    pub path_prefix: String,
+    pub action: Action,
+    names: Vec<String>,  // Lowercase; a trailing '*' matches by prefix
+}
+
+impl HeaderRule {
+    fn lists(&self, name: &str) -> bool {
+        self.names.iter().any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        })
+    }
+
+    fn keeps(&self, name: &str) -> bool {
+        match self.action {
+            Action::Allow => self.lists(name),
+            Action::Deny => !self.lists(name),
+        }
+    }
+}
+
+/// Every response header rule the server applies
+#[derive(Debug, Default)]
+pub struct HeaderRules {
+    rules: Vec<HeaderRule>,
+}
+
+impl HeaderRules {
+    /// Reads the rules from `path`
+    pub fn load(path: &Path) -> Result<Self, String> {
+        rules::load(path, "RESPONSE_HEADER_RULES_FILE", Self::parse)
+    }
+
+    /// Parses the contents of a rules file
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut rules = Vec::new();
+
+        for line in rules::lines(contents) {
+            let mut fields = line.fields();
+            let (Some(path_prefix), Some(action)) = (fields.next(), fields.next()) else {
+                return Err(line.error("expected '<path-prefix> <allow|deny> <header>[,<header>...]'"));
+            };
+            if !path_prefix.starts_with('/') {
+                return Err(line.error("path prefix must start with '/'"));
+            }
+            let action = match action {
+                "allow" => Action::Allow,
+                "deny" => Action::Deny,
+                _ => return Err(line.error(format!("unknown action '{}' (expected allow or deny)", action))),
+            };
+            let names = fields
+                .flat_map(|field| field.split(','))
+                .map(|name| name.trim().to_ascii_lowercase())
+                .filter(|name| !name.is_empty())
+                .collect::<Vec<_>>();
+            if names.is_empty() {
+                return Err(line.error("no header names"));
+            }
+            if let Some(name) = names.iter().find(|name| name.trim_end_matches('*').contains('*')) {
+                return Err(line.error(format!("'*' is only allowed at the end of a name ('{}')", name)));
+            }
+            rules.push(HeaderRule { path_prefix: path_prefix.to_string(), action, names });
+        }
+
+        Ok(Self { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Removes the headers the rules matching `path` do not let through
+    pub fn apply(&self, path: &str, headers: &mut Vec<(String, HeaderValueBytes)>) {
+        let matching: Vec<&HeaderRule> = self.rules.iter().filter(|rule| path.starts_with(&rule.path_prefix)).collect();
+        if matching.is_empty() {
+            return;
+        }
+        headers.retain(|(name, _)| {
+            let name = name.to_ascii_lowercase();
+            name == "content-length" || name == "transfer-encoding" || matching.iter().all(|rule| rule.keeps(&name))
+        });
+    }
+}
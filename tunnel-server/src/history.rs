@@ -0,0 +1,125 @@
+//! Recent connections of each tunnel credential, for diagnosing flapping clients.
+//!
+//! Every tunnel opened is recorded under its credential (as in `usage`) with
+//! when it connected, from where and with which client; when it ends, with how long it lasted,
+//! why it ended and how many bytes went through it. The last
+//! MAX_CONNECTIONS_PER_TOKEN are kept per credential, in memory only.
+
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tunnel_core::server::WorkerExit;
+
+/// Connections kept per credential, the oldest dropped first
+pub const MAX_CONNECTIONS_PER_TOKEN: usize = 50;
+
+/// Why a tunnel connection ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectReason {
+    Closed,        // Dropped by the server, e.g. replaced by a newer client
+    Drained,       // Client sent GOAWAY and every queued request was answered
+    Disconnected,  // Client closed the connection
+    Failed,        // I/O error on the connection
+    ProtocolError, // Client sent a frame that does not decode or has no place
+    Unresponsive,  // Client stopped answering PINGs
+    Panicked,
+}
+
+/// One tunnel opened with a credential
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionRecord {
+    pub tunnel_id: u64,
+    pub source_ip: Option<IpAddr>,
+    pub user_agent: Option<String>,         // Sent by the client at upgrade
+    pub connected_at: u64,                  // Unix timestamp (seconds)
+    pub disconnected_at: Option<u64>,       // Unix timestamp (seconds; None: still connected)
+    pub duration_ms: Option<u64>,           // Connected for (None: still connected)
+    pub reason: Option<DisconnectReason>,   // None: still connected
+    pub error: Option<String>,              // What failed, broke the protocol or panicked
+    pub request_bytes: u64,                 // Request body bytes sent through it
+    pub response_bytes: u64,                // Response bytes received through it
+    #[serde(skip)]
+    started: Instant,
+}
+
+/// Recent connections of every credential seen
+#[derive(Default)]
+pub struct ConnectionHistory {
+    tokens: Mutex<BTreeMap<String, VecDeque<ConnectionRecord>>>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl ConnectionHistory {
+    /// Records tunnel `tunnel_id` opened with `token` from `source` by a client calling itself `user_agent`
+    pub fn connected(&self, token: &str, tunnel_id: u64, source: Option<IpAddr>, user_agent: Option<String>) {
+        let mut tokens = self.tokens.lock().unwrap();
+        let connections = tokens.entry(token.to_string()).or_default();
+        if connections.len() == MAX_CONNECTIONS_PER_TOKEN {
+            connections.pop_front();
+        }
+        connections.push_back(ConnectionRecord {
+            tunnel_id,
+            source_ip: source,
+            user_agent,
+            connected_at: unix_now(),
+            disconnected_at: None,
+            duration_ms: None,
+            reason: None,
+            error: None,
+            request_bytes: 0,
+            response_bytes: 0,
+            started: Instant::now(),
+        });
+    }
+
+    /// Records how tunnel `tunnel_id` of `token` ended
+    pub fn disconnected(&self, token: &str, tunnel_id: u64, exit: &WorkerExit) {
+        let (reason, error) = match exit {
+            WorkerExit::Closed => (DisconnectReason::Closed, None),
+            WorkerExit::Drained => (DisconnectReason::Drained, None),
+            WorkerExit::Disconnected => (DisconnectReason::Disconnected, None),
+            WorkerExit::Failed(e) => (DisconnectReason::Failed, Some(e.to_string())),
+            WorkerExit::Protocol(message) => (DisconnectReason::ProtocolError, Some(message.clone())),
+            WorkerExit::Unresponsive => (DisconnectReason::Unresponsive, None),
+            WorkerExit::Panicked(message) => (DisconnectReason::Panicked, Some(message.clone())),
+        };
+        self.update(token, tunnel_id, |record| {
+            record.disconnected_at = Some(unix_now());
+            record.duration_ms = Some(record.started.elapsed().as_millis() as u64);
+            record.reason = Some(reason);
+            record.error = error;
+        });
+    }
+
+    /// Adds the bytes of one request through tunnel `tunnel_id` of `token`
+    pub fn record_bytes(&self, token: &str, tunnel_id: u64, request_bytes: u64, response_bytes: u64) {
+        if request_bytes == 0 && response_bytes == 0 {
+            return;
+        }
+        self.update(token, tunnel_id, |record| {
+            record.request_bytes += request_bytes;
+            record.response_bytes += response_bytes;
+        });
+    }
+
+    fn update(&self, token: &str, tunnel_id: u64, change: impl FnOnce(&mut ConnectionRecord)) {
+        let mut tokens = self.tokens.lock().unwrap();
+        let record = tokens.get_mut(token)
+            .and_then(|connections| connections.iter_mut().rev().find(|record| record.tunnel_id == tunnel_id));
+        if let Some(record) = record {
+            change(record);
+        }
+    }
+
+    /// Recent connections of `token`, most recent first (None: never connected)
+    pub fn connections(&self, token: &str) -> Option<Vec<ConnectionRecord>> {
+        let tokens = self.tokens.lock().unwrap();
+        Some(tokens.get(token)?.iter().rev().cloned().collect())
+    }
+}
@@ -0,0 +1,110 @@
+//! Plain HTTP/1.1 on the public listener.
+//!
+//! Connections are served with hyper directly rather than `axum::serve`, so
+//! header names keep the spelling visitors sent them with (hyper's
+//! `preserve_header_case`, see `tunnel_core::header_case`). [`tls::serve`]
+//! serves TLS connections the same way.
+//!
+//! [`tls::serve`]: crate::tls::serve
+
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::{Extensions, Request};
+use axum::Router;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use std::future::Future;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::time::{sleep, Duration};
+use tower::ServiceExt;
+use tracing::{debug, error};
+use tunnel_core::header_case::{self, HeaderNames};
+
+/// Serves `app` on `listener`, speaking HTTP/1.1 with upgrades
+///
+/// Accept errors are logged and retried. Once `shutdown` resolves, stops
+/// accepting and returns when every connection has finished its current
+/// request (upgraded tunnel connections are not waited for).
+pub async fn serve<F: Future<Output = ()>>(listener: TcpListener, app: Router, tcp_nodelay: bool, shutdown: F) {
+    accept(listener, tcp_nodelay, shutdown, move |stream, peer, closing| {
+        serve_connection(stream, peer, app.clone(), |_| {}, closing)
+    })
+    .await;
+}
+
+/// Accepts connections on `listener` until `shutdown`, running `connection` on a task of its own for each
+///
+/// `connection` gets a receiver that turns true once the server is closing;
+/// this returns when every connection task has dropped it.
+pub(crate) async fn accept<F, C, S>(listener: TcpListener, tcp_nodelay: bool, shutdown: F, connection: C)
+where
+    F: Future<Output = ()>,
+    C: Fn(TcpStream, SocketAddr, watch::Receiver<bool>) -> S,
+    S: Future<Output = ()> + Send + 'static,
+{
+    let (closing_tx, _) = watch::channel(false);
+    tokio::pin!(shutdown);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            () = &mut shutdown => break,
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Usually out of file descriptors; back off instead of spinning
+                error!("Failed to accept connection: {}", e);
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let _ = stream.set_nodelay(tcp_nodelay);
+        tokio::spawn(connection(stream, peer, closing_tx.subscribe()));
+    }
+
+    drop(listener);
+    let _ = closing_tx.send(true);
+    closing_tx.closed().await;
+}
+
+/// Serves `app` on one connection from `peer` until it closes, or finishes its current request once `closing`
+///
+/// Each request gets the peer address as `ConnectInfo` and its header names as
+/// spelled in its head as `HeaderNames`, plus what `extend` adds.
+pub(crate) async fn serve_connection<I, E>(io: I, peer: SocketAddr, app: Router, extend: E, mut closing: watch::Receiver<bool>)
+where
+    I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    E: Fn(&mut Extensions) + Clone + Send + Sync + 'static,
+{
+    let (io, heads) = header_case::record(io);
+    let service = service_fn(move |request: Request<Incoming>| {
+        let mut request = request.map(Body::new);
+        let target = request.uri().to_string();
+        if let Some(names) = heads.request(request.method().as_str(), &target, request.headers()) {
+            request.extensions_mut().insert(HeaderNames(names));
+        }
+        request.extensions_mut().insert(ConnectInfo(peer));
+        extend(request.extensions_mut());
+        app.clone().oneshot(request)
+    });
+    let connection = http1::Builder::new()
+        .preserve_header_case(true)
+        .serve_connection(TokioIo::new(io), service)
+        .with_upgrades();
+    tokio::pin!(connection);
+    let served = tokio::select! {
+        served = connection.as_mut() => served,
+        _ = async { closing.wait_for(|closing| *closing).await.map(|_| ()) } => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(e) = served {
+        debug!("Connection from {} ended with error: {}", peer, e);
+    }
+}
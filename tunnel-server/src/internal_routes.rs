@@ -0,0 +1,157 @@
+//! Internal-only routes, read from INTERNAL_ROUTES_FILE.
+//!
+//! One rule per line: `<path-prefix> <network>[,<network>...]`, where a network
+//! is a CIDR block (`10.0.0.0/8`, `fd00::/8`) or a single address. A request
+//! whose path is under the prefix is only forwarded when the visitor's
+//! address is in one of the networks of every such rule; other visitors get
+//! 403. Blank lines and lines starting with `#` are ignored.
+//!
+//! A prefix covers whole segments: `/admin` (or `/admin/`) matches `/admin`
+//! and `/admin/users`, not `/administrator`. Paths are compared once
+//! percent-decoded, with `\` read as `/`, repeated slashes collapsed and dot
+//! segments resolved, so `/%61dmin` or `//admin/../admin` cannot slip past a
+//! rule for `/admin`. The visitor's address is that of its
+//! connection; behind a reverse proxy, every visitor has the proxy's.
+
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::path::Path;
+use tunnel_core::rules;
+
+/// One line of the rules file
+#[derive(Debug)]
+pub struct InternalRoute {
+    pub path_prefix: String,
+    networks: Vec<IpNet>,
+}
+
+impl InternalRoute {
+    fn admits(&self, visitor: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(&visitor))
+    }
+}
+
+/// Every route the server keeps off the public internet
+#[derive(Debug, Default)]
+pub struct InternalRoutes {
+    routes: Vec<InternalRoute>,
+}
+
+impl InternalRoutes {
+    /// Reads the rules from `path`
+    pub fn load(path: &Path) -> Result<Self, String> {
+        rules::load(path, "INTERNAL_ROUTES_FILE", Self::parse)
+    }
+
+    /// Parses the contents of a rules file
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut routes = Vec::new();
+
+        for line in rules::lines(contents) {
+            let mut fields = line.fields();
+            let Some(path_prefix) = fields.next() else {
+                continue;
+            };
+            if !path_prefix.starts_with('/') {
+                return Err(line.error("path prefix must start with '/'"));
+            }
+            let networks = fields
+                .flat_map(|field| field.split(','))
+                .map(str::trim)
+                .filter(|network| !network.is_empty())
+                .map(|network| parse_network(network).map_err(|e| line.error(e)))
+                .collect::<Result<Vec<_>, _>>()?;
+            if networks.is_empty() {
+                return Err(line.error("expected '<path-prefix> <network>[,<network>...]'"));
+            }
+            // Compared like request paths, so a prefix written with `//` or `%2F` still matches
+            let path_prefix = canonical_path(path_prefix);
+            routes.push(InternalRoute { path_prefix, networks });
+        }
+
+        Ok(Self { routes })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    /// Whether a visitor at `visitor` (None: unknown) may reach `path`
+    pub fn admits(&self, path: &str, visitor: Option<IpAddr>) -> bool {
+        if self.routes.is_empty() {
+            return true;
+        }
+        let path = canonical_path(path);
+        let mut matching = self.routes.iter().filter(|route| under_prefix(&path, &route.path_prefix)).peekable();
+        if matching.peek().is_none() {
+            return true;
+        }
+        // An IPv4 visitor on a dual-stack listener shows up as ::ffff:a.b.c.d
+        let Some(visitor) = visitor.map(|visitor| visitor.to_canonical()) else {
+            return false;
+        };
+        matching.all(|route| route.admits(visitor))
+    }
+}
+
+/// Parses a CIDR block (`10.0.0.0/8`, `fd00::/8`) or a single address
+pub fn parse_network(network: &str) -> Result<IpNet, String> {
+    network.parse::<IpNet>()
+        .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("invalid network '{}' (expected a CIDR block or an address)", network))
+}
+
+/// Whether `path` is `prefix` or below it, by whole segments; a trailing `/` on `prefix` does not matter
+pub(crate) fn under_prefix(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix.trim_end_matches('/')).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// `path` (query aside) as the local service most likely resolves it
+pub(crate) fn canonical_path(path: &str) -> String {
+    let path = path.split('?').next().unwrap_or_default();
+    let decoded = percent_decode(path).replace('\\', "/");
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let trailing_slash = decoded.ends_with('/') || decoded.ends_with("/.") || decoded.ends_with("/..");
+    let mut canonical = format!("/{}", segments.join("/"));
+    if trailing_slash && !segments.is_empty() {
+        canonical.push('/');
+    }
+    canonical
+}
+
+/// Decodes every `%XX` escape; invalid UTF-8 is replaced rather than rejected
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes.get(i + 1..i + 3)
+            .filter(|hex| bytes[i] == b'%' && hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escape {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,76 @@
+//! What visitors get while no tunnel client is connected: a plain 503 by
+//! default, a landing page (NO_TUNNEL_PAGE_FILE) or a redirect
+//! (NO_TUNNEL_REDIRECT_URL), so a mistyped or expired tunnel URL looks
+//! intentional.
+//!
+//! Every variant carries `X-Tunnel-Error: no-tunnel` and `Cache-Control:
+//! no-store`, so a browser reaches the tunnel once it is back.
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderValue, Response, StatusCode},
+};
+use std::path::Path;
+
+use crate::ERROR_CODE_HEADER;
+
+/// Response to requests that find no tunnel client
+#[derive(Debug, Clone, Default)]
+pub enum Landing {
+    #[default]
+    Unavailable,           // 503 with a short text body
+    Page(Bytes),           // HTML page served with 404
+    Redirect(HeaderValue), // 302 to this URL
+}
+
+impl Landing {
+    /// Reads the HTML page served with 404 from `path`
+    pub fn load_page(path: &Path) -> Result<Self, String> {
+        let page = std::fs::read(path)
+            .map_err(|e| format!("Failed to read NO_TUNNEL_PAGE_FILE {}: {}", path.display(), e))?;
+        Ok(Landing::Page(page.into()))
+    }
+
+    /// Redirects visitors to `url`, which must be an absolute http(s) URL
+    pub fn redirect(url: &str) -> Result<Self, String> {
+        let url = url.trim();
+        let has_host = url.split_once("://")
+            .is_some_and(|(scheme, rest)| matches!(scheme, "http" | "https") && !rest.is_empty() && !rest.starts_with('/'));
+        if !has_host {
+            return Err(format!("Invalid NO_TUNNEL_REDIRECT_URL: {} (expected an http:// or https:// URL)", url));
+        }
+        HeaderValue::from_str(url)
+            .map(Landing::Redirect)
+            .map_err(|_| format!("Invalid NO_TUNNEL_REDIRECT_URL: {} (not a valid header value)", url))
+    }
+
+    /// Short description for the startup log
+    pub fn summary(&self) -> String {
+        match self {
+            Landing::Unavailable => "503".to_string(),
+            Landing::Page(page) => format!("landing page ({} bytes)", page.len()),
+            Landing::Redirect(url) => format!("redirect to {}", url.to_str().unwrap_or_default()),
+        }
+    }
+
+    /// Builds the response for one visitor
+    pub fn response(&self) -> Response<Body> {
+        let response = Response::builder()
+            .header(ERROR_CODE_HEADER, "no-tunnel")
+            .header(header::CACHE_CONTROL, "no-store");
+        match self {
+            Landing::Unavailable => response
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::from("No tunnel client connected")),
+            Landing::Page(page) => response
+                .status(StatusCode::NOT_FOUND)
+                .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                .body(Body::from(page.clone())),
+            Landing::Redirect(url) => response
+                .status(StatusCode::FOUND)
+                .header(header::LOCATION, url)
+                .body(Body::empty()),
+        }
+        .unwrap()
+    }
+}
@@ -0,0 +1,1420 @@
+//! Tunnel server: public HTTP endpoint that forwards every request through
+//! the connected tunnel client.
+//!
+//! The binary reads its configuration from the environment and serves
+//! [`router`] (and optionally [`admin::router`]); embedders and tests can
+//! serve them on listeners of their own, or set a server up with
+//! [`TunnelServer::builder`]. An app forwarding only some of its routes
+//! mounts [`tunnel_routes`] and wraps itself in a [`TunnelLayer`].
+
+pub mod admin;
+pub mod api_keys;
+pub mod auth_hooks;
+pub mod cors;
+pub mod embed;
+#[cfg(unix)]
+pub mod handover;
+pub mod header_rules;
+pub mod history;
+pub mod http;
+pub mod internal_routes;
+pub mod landing;
+pub mod requests;
+pub mod settings;
+pub mod streaming;
+pub mod templates;
+pub mod timeouts;
+pub mod tls;
+pub mod traffic;
+pub mod usage;
+pub mod visitors;
+pub mod warmup;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, State},
+    http::{uri::Authority, Method, Request, Response, StatusCode, Uri, Version, header, HeaderMap},
+    routing::{any, get},
+    Router,
+};
+use http_body_util::BodyExt;
+use hyper_util::rt::TokioIo;
+use ipnet::IpNet;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::SystemTime;
+use thiserror::Error;
+use tokio::time::{Duration, Instant};
+use tracing::{debug, error, info, warn, Instrument};
+use tunnel_core::error_dedup;
+use tunnel_core::framing::{encode_binary_message, encode_message, MessageError, MESSAGE_BUFFERS};
+use tunnel_core::header_case;
+use tunnel_core::heartbeat::Heartbeat;
+use tunnel_core::logging::{LogHandle, ACCESS_TARGET};
+use tunnel_core::progress::RequestProgress;
+use tunnel_core::redact::Redactor;
+use tunnel_core::server::{run_multiplexed_worker, run_worker, supervise, QueueOptions, TunnelConnection, TunnelError, TunnelRegistry, TunnelReply};
+use tunnel_core::transport::TransportOptions;
+use tunnel_protocol::{
+    allowed_in_trailers, body_sha256, decode_body, decode_request_frame, decode_response_frame, encode_body, format_tags, is_binary_frame, parse_cors_origins, parse_label,
+    validate_headers, validate_method, validate_path, Compression, DecodeError,
+    HeaderLimits, HeaderValueBytes, Schedule, TunnelRequest, ValidationError, BINARY_ENCODING, BODY_SHA256_HEADER, CANCEL_HEADER, CLIENT_ADDR_HEADER, COMPRESSION_HEADER, CORS_HEADER, DEFAULT_TUNNEL_PATH,
+    ENCODING_HEADER, HEADER_LIMITS_HEADER, HEARTBEAT_HEADER, HTTPS_ONLY_HEADER, LABEL_HEADER, MULTIPLEX_HEADER, SCHEDULE_HEADER, STATS_HEADER, STREAM_CREDIT_HEADER, STREAM_HEADER, STREAM_TRAILERS_HEADER, TAG_HEADER, TUNNEL_ID_HEADER, UPGRADE_SECRET_HEADER, VISITOR_AUTH_HEADER,
+};
+
+use crate::api_keys::{constant_time_eq, ApiKeys};
+use crate::auth_hooks::{AuthHooks, Verdict};
+use crate::header_rules::HeaderRules;
+use crate::history::ConnectionHistory;
+use crate::internal_routes::InternalRoutes;
+use crate::landing::Landing;
+use crate::requests::RequestTracker;
+use crate::streaming::{send_request_body, StreamedBody, TrailedBody};
+use crate::templates::ResponseTemplates;
+use crate::timeouts::Timeouts;
+use crate::traffic::TrafficStats;
+use crate::usage::UsageStore;
+use crate::visitors::{SlotBody, VisitorLimit};
+use crate::warmup::Warmup;
+
+pub use crate::embed::{BuildError, TunnelLayer, TunnelServer, TunnelServerBuilder, TunnelService};
+
+/// Response header naming why the server itself answered a request, for
+/// visitors that retry on some failures only
+pub const ERROR_CODE_HEADER: &str = "x-tunnel-error";
+
+/// Response extension carrying the milliseconds the local service took, as reported by the client
+#[derive(Debug, Clone, Copy)]
+pub struct LocalDuration(pub u64);
+
+/// Response extension carrying the tags the local service set with `TAG_HEADER`
+#[derive(Debug, Clone)]
+pub struct ResponseTags(pub Vec<(String, String)>);
+
+/// Strict-Transport-Security sent over HTTPS for HTTPS-only tunnels, unless the local service sets its own
+const HSTS_VALUE: &str = "max-age=31536000";
+
+/// Default for TUNNEL_STATS_INTERVAL_SECS
+pub const DEFAULT_STATS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Default for TUNNEL_SLOW_REQUEST_MS
+pub const DEFAULT_SLOW_REQUEST: Duration = Duration::from_secs(5);
+
+/// Default for UPGRADE_DRAIN_SECS
+pub const DEFAULT_UPGRADE_DRAIN: Duration = Duration::from_secs(30);
+
+/// Application state shared across handlers
+#[derive(Clone)]
+pub struct ServerState {
+    pub registry: Arc<TunnelRegistry>,
+    tunnel_auth: Option<String>, // username:password for Basic Auth
+    coalesce_bytes: usize,       // Frame coalescing limit for tunnel workers (0: off)
+    stream_threshold_bytes: usize, // Bodies larger than this are streamed to clients that agreed to it
+    stream_window_chunks: usize,   // Chunks of one streamed body in flight at once
+    heartbeat_interval: Option<Duration>, // Between PINGs to clients that agreed to heartbeats (None: off)
+    heartbeat_timeout: Duration, // Wait for the PONG to a PING before dropping the connection
+    compression: bool,           // Agree to compress frames with clients offering it
+    queue: QueueOptions,         // Request queue limits per tunnel connection
+    header_limits: HeaderLimits, // Enforced on responses from clients, announced at upgrade
+    stats_interval: Option<Duration>, // Stats report interval requested from clients (None: no reports)
+    log_handle: Option<LogHandle>, // Runtime log level control for the admin API
+    requests: Arc<RequestTracker>, // Requests in flight, for slow request logging and the admin API
+    traffic: Arc<TrafficStats>,  // Request rates, errors and latencies over recent windows, for the admin API
+    body_checksum: bool,         // Add BODY_SHA256_HEADER to forwarded requests
+    api_keys: Arc<ApiKeys>,      // Keys accepted by the admin API (empty: no authentication)
+    tunnel_path: String,         // Route of the upgrade endpoint
+    upgrade_secret: Option<String>, // Required in UPGRADE_SECRET_HEADER to upgrade (None: not required)
+    started: Instant,            // For the uptime reported by the admin API
+    reconnect_grace: Option<Duration>, // How long requests wait for a lost client to reconnect (None: 503/502 right away)
+    header_rules: Arc<HeaderRules>, // Response headers removed per route before they leave the server
+    templates: Arc<ResponseTemplates>, // Response bodies changed per route before they leave the server
+    internal_routes: Arc<InternalRoutes>, // Routes only some visitor networks may reach
+    auth_hooks: Arc<AuthHooks>,  // External services deciding whether some routes' requests are forwarded
+    landing: Landing,            // Response while no tunnel client is connected
+    timeouts: Timeouts,          // Per-phase limits on public requests
+    redactor: Arc<Redactor>,     // Applied to request paths in the access log and request listings
+    usage: Arc<UsageStore>,      // Connections and bytes per tunnel credential, for the admin API
+    history: Arc<ConnectionHistory>, // Recent connections per tunnel credential, for the admin API
+    cert_binding: bool,          // Bind each credential to the client certificate first used with it
+    visitors: Arc<VisitorLimit>, // Requests in flight per visitor address
+    trusted_proxies: Arc<[IpNet]>, // Peers whose X-Forwarded-Proto is believed (empty: none)
+    warmup: Option<Warmup>,      // Probe a new tunnel must answer before visitors are routed to it (None: routed right away)
+}
+
+impl ServerState {
+    pub fn new(tunnel_auth: Option<String>, transport: &TransportOptions) -> Self {
+        Self {
+            registry: Arc::new(TunnelRegistry::new()),
+            tunnel_auth,
+            coalesce_bytes: transport.coalesce_bytes,
+            stream_threshold_bytes: transport.stream_threshold_bytes,
+            stream_window_chunks: transport.stream_window_chunks,
+            heartbeat_interval: transport.heartbeat_interval,
+            heartbeat_timeout: transport.heartbeat_timeout,
+            compression: transport.compression.is_some(),
+            queue: QueueOptions::default(),
+            header_limits: transport.header_limits,
+            stats_interval: Some(DEFAULT_STATS_INTERVAL),
+            log_handle: None,
+            requests: Arc::new(RequestTracker::new(Some(DEFAULT_SLOW_REQUEST))),
+            traffic: Arc::new(TrafficStats::default()),
+            body_checksum: false,
+            api_keys: Arc::new(ApiKeys::default()),
+            tunnel_path: DEFAULT_TUNNEL_PATH.to_string(),
+            upgrade_secret: None,
+            started: Instant::now(),
+            reconnect_grace: None,
+            header_rules: Arc::new(HeaderRules::default()),
+            templates: Arc::new(ResponseTemplates::default()),
+            internal_routes: Arc::new(InternalRoutes::default()),
+            auth_hooks: Arc::new(AuthHooks::default()),
+            landing: Landing::default(),
+            timeouts: Timeouts::default(),
+            redactor: Arc::new(Redactor::default()),
+            usage: Arc::new(UsageStore::default()),
+            history: Arc::new(ConnectionHistory::default()),
+            cert_binding: false,
+            visitors: Arc::new(VisitorLimit::default()),
+            trusted_proxies: Arc::new([]),
+            warmup: None,
+        }
+    }
+
+    /// Replaces the default request queue limits for new tunnel connections
+    pub fn with_queue(mut self, queue: QueueOptions) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    /// Sets how often clients are asked to report their stats (None: never)
+    pub fn with_stats_interval(mut self, interval: Option<Duration>) -> Self {
+        self.stats_interval = interval;
+        self
+    }
+
+    /// Sets how long a request may take before it is logged as slow (None: never)
+    pub fn with_slow_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.requests = Arc::new(RequestTracker::new(threshold));
+        self
+    }
+
+    /// Sets how long requests are held for the client's next connection when its tunnel goes away (None: never)
+    pub fn with_reconnect_grace(mut self, grace: Option<Duration>) -> Self {
+        self.reconnect_grace = grace;
+        self
+    }
+
+    /// Replaces the (empty) response header rules
+    pub fn with_header_rules(mut self, rules: HeaderRules) -> Self {
+        self.header_rules = Arc::new(rules);
+        self
+    }
+
+    /// Replaces the (empty) response templates
+    pub fn with_templates(mut self, templates: ResponseTemplates) -> Self {
+        self.templates = Arc::new(templates);
+        self
+    }
+
+    /// Replaces the (empty) internal-only routes
+    ///
+    /// Visitor addresses are only known when the router is served with connect
+    /// info; without one, internal routes are refused to everyone.
+    pub fn with_internal_routes(mut self, routes: InternalRoutes) -> Self {
+        self.internal_routes = Arc::new(routes);
+        self
+    }
+
+    /// Replaces the (empty) authorization hooks
+    pub fn with_auth_hooks(mut self, hooks: AuthHooks) -> Self {
+        self.auth_hooks = Arc::new(hooks);
+        self
+    }
+
+    /// Replaces the default request timeouts (30 s overall, no per-phase limits)
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Replaces the plain 503 visitors get while no tunnel client is connected
+    pub fn with_landing(mut self, landing: Landing) -> Self {
+        self.landing = landing;
+        self
+    }
+
+    /// Replaces the default redaction (credential headers only) of logged and listed requests
+    pub fn with_redactor(mut self, redactor: Redactor) -> Self {
+        self.redactor = Arc::new(redactor);
+        self
+    }
+
+    /// Replaces the in-memory usage counts, e.g. with ones saved to TOKEN_USAGE_FILE
+    pub fn with_usage(mut self, usage: Arc<UsageStore>) -> Self {
+        self.usage = usage;
+        self
+    }
+
+    /// Sets whether credentials are bound to the client certificate first used with them
+    ///
+    /// Only has an effect behind `tls::serve` with client certificates requested;
+    /// without a certificate, upgrades are then refused.
+    pub fn with_cert_binding(mut self, enabled: bool) -> Self {
+        self.cert_binding = enabled;
+        self
+    }
+
+    /// Caps the requests one visitor address has in flight (None: no cap)
+    ///
+    /// Only has an effect when the router is served with connect info.
+    pub fn with_visitor_max_in_flight(mut self, max: Option<usize>) -> Self {
+        self.visitors = Arc::new(VisitorLimit::new(max));
+        self
+    }
+
+    /// Believes X-Forwarded-Proto from peers in `networks`, e.g. a TLS-terminating reverse proxy (empty: from none)
+    ///
+    /// Only has an effect when the router is served with connect info.
+    pub fn with_trusted_proxies(mut self, networks: Vec<IpNet>) -> Self {
+        self.trusted_proxies = networks.into();
+        self
+    }
+
+    /// Sets whether forwarded requests carry the SHA-256 of their body for the client to check
+    pub fn with_body_checksum(mut self, enabled: bool) -> Self {
+        self.body_checksum = enabled;
+        self
+    }
+
+    /// Requires one of `keys` on every admin API request
+    pub fn with_api_keys(mut self, keys: ApiKeys) -> Self {
+        self.api_keys = Arc::new(keys);
+        self
+    }
+
+    /// Moves the upgrade endpoint to `path` (see `parse_tunnel_path`); `/tunnel` is then forwarded like any other path
+    pub fn with_tunnel_path(mut self, path: String) -> Self {
+        self.tunnel_path = path;
+        self
+    }
+
+    /// Requires `secret` in `UPGRADE_SECRET_HEADER` on upgrade requests (None: not required)
+    pub fn with_upgrade_secret(mut self, secret: Option<String>) -> Self {
+        self.upgrade_secret = secret;
+        self
+    }
+
+    /// Sets the probe a new tunnel must answer before visitors are routed to it (None: routed right away)
+    pub fn with_warmup(mut self, warmup: Option<Warmup>) -> Self {
+        self.warmup = warmup;
+        self
+    }
+
+    /// Lets the admin API change the log level through `handle`
+    pub fn with_log_handle(mut self, handle: LogHandle) -> Self {
+        self.log_handle = Some(handle);
+        self
+    }
+}
+
+/// Error from forwarding one public request through the tunnel
+#[derive(Debug, Error)]
+pub enum ForwardError {
+    #[error("Failed to read request body: {0}")]
+    RequestBody(#[source] axum::Error),
+
+    #[error(transparent)]
+    InvalidRequest(ValidationError),
+
+    #[error("Request headers exceed the tunnel client's limits: {0}")]
+    RequestHeaderLimits(#[source] ValidationError),
+
+    #[error(transparent)]
+    Encode(MessageError),
+
+    #[error(transparent)]
+    Tunnel(#[from] TunnelError),
+
+    #[error("Invalid tunnel response: {0}")]
+    InvalidResponse(#[source] DecodeError),
+
+    #[error("Invalid tunnel response: {0}")]
+    InvalidResponseHeaders(#[source] ValidationError),
+
+    #[error("Invalid tunnel response: status {0}")]
+    InvalidStatus(u16),
+
+    #[error("Failed to decode response body: {0}")]
+    ResponseBody(#[source] base64::DecodeError),
+
+    #[error("Response body does not match its checksum")]
+    ResponseChecksum,
+}
+
+impl ForwardError {
+    /// Whether the tunnel connection is broken and should be dropped
+    ///
+    /// A request the server could not read, validate, encode or queue, or a
+    /// response with a status or headers it cannot send on or a body not matching
+    /// its checksum (the frame itself was intact), leaves the tunnel usable.
+    pub fn breaks_tunnel(&self) -> bool {
+        !matches!(
+            self,
+            ForwardError::RequestBody(_)
+                | ForwardError::InvalidRequest(_)
+                | ForwardError::RequestHeaderLimits(_)
+                | ForwardError::Encode(_)
+                | ForwardError::Tunnel(TunnelError::QueueFull | TunnelError::InFlightLimit | TunnelError::Draining)
+                | ForwardError::InvalidResponseHeaders(_)
+                | ForwardError::InvalidStatus(_)
+                | ForwardError::ResponseChecksum
+        )
+    }
+
+    /// Whether the request never reached the client and could be retried on another tunnel
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ForwardError::Tunnel(e) if e.is_retryable())
+    }
+
+    /// Status code returned to the visitor
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ForwardError::RequestBody(_) | ForwardError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            ForwardError::RequestHeaderLimits(_) => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            _ if self.is_retryable() => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+/// Builds the public router: the upgrade endpoint (`/tunnel` by default) plus forwarding of every other request
+pub fn router(state: ServerState) -> Router {
+    Router::new()
+        .route(&state.tunnel_path.clone(), get(tunnel_upgrade_handler))
+        .fallback(any(http_handler))
+        .with_state(state)
+}
+
+/// Builds just the upgrade endpoint, for apps that forward only some of their routes (see [`TunnelLayer`])
+pub fn tunnel_routes(state: ServerState) -> Router {
+    Router::new()
+        .route(&state.tunnel_path.clone(), get(tunnel_upgrade_handler))
+        .with_state(state)
+}
+
+/// Extracts Basic Auth credentials from Authorization header
+/// Returns Some(username:password) if valid Basic Auth header is present
+fn extract_basic_auth(headers: &HeaderMap) -> Option<String> {
+    let auth_header = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+
+    if !auth_header.starts_with("Basic ") {
+        return None;
+    }
+
+    let encoded = auth_header.strip_prefix("Basic ")?;
+    let decoded = tunnel_protocol::decode_body(encoded).ok()?;
+    let credentials = String::from_utf8(decoded).ok()?;
+
+    Some(credentials)
+}
+
+/// Reads the visitor credentials the client asked for, if any
+fn extract_visitor_auth(headers: &HeaderMap) -> Result<Option<String>, String> {
+    let Some(value) = headers.get(VISITOR_AUTH_HEADER) else {
+        return Ok(None);
+    };
+    let credentials = value.to_str().ok()
+        .and_then(|encoded| decode_body(encoded).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .filter(|credentials| credentials.contains(':'))
+        .ok_or_else(|| format!("Invalid {} header: expected base64 'username:password'", VISITOR_AUTH_HEADER))?;
+    Ok(Some(credentials))
+}
+
+/// Reads whether the client asked for its tunnel to be HTTPS-only
+fn extract_https_only(headers: &HeaderMap) -> Result<bool, String> {
+    let Some(value) = headers.get(HTTPS_ONLY_HEADER) else {
+        return Ok(false);
+    };
+    value.to_str().ok()
+        .and_then(|value| value.trim().parse().ok())
+        .ok_or_else(|| format!("Invalid {} header: expected true or false", HTTPS_ONLY_HEADER))
+}
+
+/// Reads the origins the client wants CORS handled for (empty: CORS is left to the local service)
+fn extract_cors_origins(headers: &HeaderMap) -> Result<Vec<String>, String> {
+    let Some(value) = headers.get(CORS_HEADER) else {
+        return Ok(Vec::new());
+    };
+    value.to_str()
+        .map_err(|_| format!("Invalid {} header: not UTF-8", CORS_HEADER))
+        .and_then(|value| parse_cors_origins(value).map_err(|e| format!("Invalid {} header: {}", CORS_HEADER, e)))
+}
+
+/// Reads the windows the client wants visitors routed in (None: always)
+fn extract_schedule(headers: &HeaderMap) -> Result<Option<Schedule>, String> {
+    let Some(value) = headers.get(SCHEDULE_HEADER) else {
+        return Ok(None);
+    };
+    value.to_str()
+        .map_err(|_| format!("Invalid {} header: not UTF-8", SCHEDULE_HEADER))
+        .and_then(|value| Schedule::parse(value).map_err(|e| format!("Invalid {} header: {}", SCHEDULE_HEADER, e)))
+        .map(Some)
+}
+
+/// Most requests written to a multiplexing client at once, whatever it offers
+const MAX_MULTIPLEX: usize = 1024;
+
+/// Reads how many requests the client takes at once (1: it does not multiplex)
+fn extract_multiplex(headers: &HeaderMap) -> usize {
+    headers.get(MULTIPLEX_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .map_or(1, |max| max.clamp(1, MAX_MULTIPLEX))
+}
+
+/// Reads whether the client offered binary frames
+fn extract_binary_frames(headers: &HeaderMap) -> bool {
+    headers.get(ENCODING_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case(BINARY_ENCODING))
+}
+
+/// Reads whether the client offered to stream large bodies
+fn extract_stream_bodies(headers: &HeaderMap) -> bool {
+    headers.get(STREAM_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("true"))
+}
+
+/// Reads whether the client offered to send and take trailers with streamed bodies
+fn extract_stream_trailers(headers: &HeaderMap) -> bool {
+    headers.get(STREAM_TRAILERS_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("true"))
+}
+
+/// Reads how many body frames of one body the client takes ahead of credit, if it offered credit
+fn extract_stream_credit(headers: &HeaderMap) -> Option<usize> {
+    headers.get(STREAM_CREDIT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .filter(|chunks| *chunks > 0)
+}
+
+/// Reads whether the client offered heartbeats
+fn extract_heartbeat(headers: &HeaderMap) -> bool {
+    headers.get(HEARTBEAT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("true"))
+}
+
+/// Reads whether the client takes CANCEL frames
+fn extract_cancel(headers: &HeaderMap) -> bool {
+    headers.get(CANCEL_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("true"))
+}
+
+/// Reads the compression algorithm the client offered, if any it knows
+fn extract_compression(headers: &HeaderMap) -> Option<Compression> {
+    Compression::parse(headers.get(COMPRESSION_HEADER)?.to_str().ok()?)
+}
+
+/// Longest client User-Agent kept for logs and the admin API
+const MAX_USER_AGENT_LEN: usize = 256;
+
+/// Reads the client's User-Agent, cut at MAX_USER_AGENT_LEN bytes
+fn extract_user_agent(headers: &HeaderMap) -> Option<String> {
+    let user_agent = headers.get(header::USER_AGENT)?.to_str().ok()?.trim();
+    // Visible ASCII only, so any byte is a boundary
+    Some(user_agent[..user_agent.len().min(MAX_USER_AGENT_LEN)].to_string()).filter(|user_agent| !user_agent.is_empty())
+}
+
+/// Whether a request declares a body: any Transfer-Encoding, or a Content-Length other than 0
+fn declares_body(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::TRANSFER_ENCODING)
+        || headers.get_all(header::CONTENT_LENGTH).iter().any(|length| length.as_bytes().trim_ascii() != b"0")
+}
+
+/// Collects `key=value` labels sent by the client in the upgrade request
+/// Malformed labels are logged and skipped rather than rejecting the tunnel
+fn extract_labels(headers: &HeaderMap) -> BTreeMap<String, String> {
+    let mut labels = BTreeMap::new();
+
+    for value in headers.get_all(LABEL_HEADER) {
+        let Ok(value) = value.to_str() else {
+            warn!("Ignoring non-UTF8 tunnel label");
+            continue;
+        };
+        match parse_label(value) {
+            Ok((key, value)) => {
+                labels.insert(key, value);
+            }
+            Err(e) => warn!("Ignoring tunnel label: {}", e),
+        }
+    }
+
+    labels
+}
+
+/// Handles HTTP Upgrade requests to establish tunnel connections
+///
+/// The peer address is only known when the router is served with connect info
+/// (`into_make_service_with_connect_info`, `http::serve` or `tls::serve`).
+async fn tunnel_upgrade_handler(
+    State(state): State<ServerState>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    request: Request<Body>,
+) -> Response<Body> {
+    // Without the secret this is just another public request; nothing reveals the endpoint
+    if let Some(secret) = &state.upgrade_secret {
+        let presented = request.headers().get(UPGRADE_SECRET_HEADER).map(|value| value.as_bytes()).unwrap_or_default();
+        if !constant_time_eq(presented, secret.as_bytes()) {
+            if request.headers().contains_key(header::UPGRADE) {
+                error_dedup!("Upgrade request without a valid {}; forwarding it", UPGRADE_SECRET_HEADER);
+            }
+            return http_handler(State(state), request).await;
+        }
+    }
+
+    // The upgrade request's headers stay in memory for the whole connection
+    let upgrade_headers: Vec<(String, &[u8])> =
+        request.headers().iter().map(|(name, value)| (name.to_string(), value.as_bytes())).collect();
+    if let Err(e) = state.header_limits.check(&upgrade_headers) {
+        error_dedup!("Rejected upgrade: {}", e);
+        return Response::builder()
+            .status(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+            .body(Body::from(e.to_string()))
+            .unwrap();
+    }
+
+    // Check authentication if enabled
+    let mut token = None;
+    if let Some(ref expected_auth) = state.tunnel_auth {
+        match extract_basic_auth(request.headers()) {
+            Some(provided_auth) if provided_auth == *expected_auth => {
+                // Authentication successful
+                info!("Client authenticated successfully");
+                token = provided_auth.split(':').next().map(str::to_string);
+            }
+            Some(_) => {
+                // Invalid credentials
+                error_dedup!("Authentication failed: Invalid credentials");
+                return Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header(header::WWW_AUTHENTICATE, "Basic realm=\"tunnel\"")
+                    .body(Body::from("Invalid credentials"))
+                    .unwrap();
+            }
+            None => {
+                // Missing Authorization header
+                error_dedup!("Authentication failed: Missing Authorization header");
+                return Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header(header::WWW_AUTHENTICATE, "Basic realm=\"tunnel\"")
+                    .body(Body::from("Authorization required"))
+                    .unwrap();
+            }
+        }
+    }
+
+    // A leaked credential alone is not enough: the client must also hold the key it was first used with
+    if state.cert_binding {
+        let name = token.as_deref().unwrap_or(usage::ANONYMOUS);
+        let presented = request.extensions().get::<tls::ClientCertificate>().map(|cert| cert.0);
+        match state.usage.bind_certificate(name, presented) {
+            Ok(true) => info!("Credential {} bound to client certificate {}", name, presented.unwrap()),
+            Ok(false) => {}
+            Err(e) => {
+                error_dedup!("Rejected upgrade: {}", e);
+                return Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::from(e))
+                    .unwrap();
+            }
+        }
+    }
+
+    // Check for upgrade headers
+    let upgrade_header = request.headers().get(header::UPGRADE);
+    let connection_header = request.headers().get(header::CONNECTION);
+
+    let is_upgrade = upgrade_header
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.eq_ignore_ascii_case("tunnel"))
+        .unwrap_or(false);
+
+    let has_upgrade_connection = connection_header
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_lowercase().contains("upgrade"))
+        .unwrap_or(false);
+
+    if !is_upgrade || !has_upgrade_connection {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from("Missing or invalid Upgrade headers"))
+            .unwrap();
+    }
+
+    // Body bytes would be read as the first tunnel frames (request smuggling); bytes
+    // pipelined after the headers are kept by hyper and read by the worker instead
+    if declares_body(request.headers()) {
+        error_dedup!("Rejected upgrade: request has a body");
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .header(header::CONNECTION, "close")
+            .body(Body::from("Upgrade request must not have a body"))
+            .unwrap();
+    }
+
+    let headers = request.headers();
+    let visitor_options = extract_visitor_auth(headers).and_then(|visitor_auth| {
+        Ok((visitor_auth, extract_https_only(headers)?, extract_cors_origins(headers)?, extract_schedule(headers)?))
+    });
+    let (visitor_auth, https_only, cors_origins, schedule) = match visitor_options {
+        Ok(visitor_options) => visitor_options,
+        Err(e) => {
+            error_dedup!("Rejected upgrade: {}", e);
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(e))
+                .unwrap();
+        }
+    };
+    let labels = extract_labels(request.headers());
+    let client_header_limits = request.headers()
+        .get(HEADER_LIMITS_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(HeaderLimits::from_header_value);
+    let max_concurrent = extract_multiplex(request.headers());
+    let binary_frames = extract_binary_frames(request.headers());
+    // Body frames are tagged with the request ID, so streaming needs multiplexing too
+    let stream_bodies = binary_frames && max_concurrent > 1 && extract_stream_bodies(request.headers());
+    let stream_trailers = stream_bodies && extract_stream_trailers(request.headers());
+    // CANCEL names the request by its ID, so it needs multiplexing too
+    let cancel = max_concurrent > 1 && extract_cancel(request.headers());
+    // Response bodies nobody reads any more are cancelled rather than credited
+    let stream_credit = extract_stream_credit(request.headers()).filter(|_| stream_bodies && cancel);
+    let heartbeat = state.heartbeat_interval.filter(|_| extract_heartbeat(request.headers()));
+    let user_agent = extract_user_agent(request.headers());
+    let compression = extract_compression(request.headers()).filter(|_| state.compression);
+
+    // Attempt to upgrade the connection
+    let upgrade_result = hyper::upgrade::on(request);
+
+    let (mut conn, mut request_rx) = TunnelConnection::new(labels, &state.queue);
+    if let Some(interval) = heartbeat {
+        request_rx = request_rx.with_heartbeat(Heartbeat::new(interval, state.heartbeat_timeout));
+    }
+    if let Some(compression) = compression {
+        request_rx = request_rx.with_compression(compression);
+    }
+    if cancel {
+        request_rx = request_rx.with_cancel();
+    }
+    if let Some(chunks) = stream_credit {
+        request_rx = request_rx.with_stream_credit(chunks);
+    }
+    conn.peer_header_limits = client_header_limits;
+    conn.token = token;
+    conn.visitor_auth = visitor_auth;
+    conn.https_only = https_only;
+    conn.cors_origins = cors_origins;
+    conn.schedule = schedule;
+    conn.max_concurrent = max_concurrent;
+    conn.binary_frames = binary_frames;
+    conn.stream_bodies = stream_bodies;
+    conn.stream_trailers = stream_trailers;
+    conn.stream_credit = stream_credit.is_some();
+    conn.heartbeat = heartbeat.is_some();
+    conn.user_agent = user_agent;
+    conn.compression = compression;
+    conn.cancel = cancel;
+    let conn = Arc::new(conn);
+
+    // Send 101 Switching Protocols response, asking for stats reports if enabled
+    let mut response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::UPGRADE, "tunnel")
+        .header(header::CONNECTION, "Upgrade")
+        .header(HEADER_LIMITS_HEADER, state.header_limits.to_header_value())
+        .header(TUNNEL_ID_HEADER, conn.id);
+    if let Some(ConnectInfo(addr)) = peer {
+        response = response.header(CLIENT_ADDR_HEADER, addr.to_string());
+    }
+    if let Some(interval) = state.stats_interval {
+        response = response.header(STATS_HEADER, interval.as_secs());
+    }
+    if conn.max_concurrent > 1 {
+        response = response.header(MULTIPLEX_HEADER, conn.max_concurrent);
+    }
+    if conn.binary_frames {
+        response = response.header(ENCODING_HEADER, BINARY_ENCODING);
+    }
+    if conn.stream_bodies {
+        response = response.header(STREAM_HEADER, "true");
+    }
+    if conn.stream_trailers {
+        response = response.header(STREAM_TRAILERS_HEADER, "true");
+    }
+    if conn.stream_credit {
+        response = response.header(STREAM_CREDIT_HEADER, state.stream_window_chunks);
+    }
+    if conn.heartbeat {
+        response = response.header(HEARTBEAT_HEADER, "true");
+    }
+    if let Some(compression) = conn.compression {
+        response = response.header(COMPRESSION_HEADER, compression.name());
+    }
+    let response = response.body(Body::empty()).unwrap();
+
+    // Spawn task to handle the upgraded connection
+    let task_span = conn.span.clone();
+    tokio::spawn(async move {
+        match upgrade_result.await {
+            Ok(upgraded) => {
+                info!("Client upgraded to tunnel protocol ({})", conn.user_agent.as_deref().unwrap_or("no user agent"));
+                if conn.visitor_auth.is_some() {
+                    info!("Visitor authentication required by the client");
+                }
+                if conn.https_only {
+                    info!("HTTPS-only requested by the client");
+                }
+                if !conn.cors_origins.is_empty() {
+                    info!("CORS handled for {}", conn.cors_origins.join(", "));
+                }
+                if let Some(schedule) = &conn.schedule {
+                    info!("Visitors routed only within {}", schedule);
+                }
+                if conn.max_concurrent > 1 {
+                    info!("Multiplexing up to {} requests", conn.max_concurrent);
+                }
+                if conn.binary_frames {
+                    info!("Exchanging binary frames");
+                }
+                if conn.stream_bodies {
+                    let credit = if conn.stream_credit { ", against credit" } else { "" };
+                    info!("Streaming large bodies, {} chunks in flight at most{}", state.stream_window_chunks, credit);
+                }
+                if let Some(interval) = heartbeat {
+                    info!("Sending heartbeats every {:?}", interval);
+                }
+                if let Some(compression) = conn.compression {
+                    info!("Compressing frames with {}", compression);
+                }
+
+                let source = peer.map(|ConnectInfo(addr)| addr.ip());
+                state.usage.record_connection(usage::token_name(&conn), source);
+                state.history.connected(usage::token_name(&conn), conn.id, source, conn.user_agent.clone());
+
+                // Update active client; with a warm-up probe, once the probe (sent
+                // through the worker started below) is answered
+                let warming_up = if state.warmup.is_some() {
+                    let (state, conn) = (state.clone(), conn.clone());
+                    Some(tokio::spawn(async move { activate(&state, &conn).await }.in_current_span()))
+                } else {
+                    activate(&state, &conn).await;
+                    None
+                };
+
+                // Run worker to handle the actual I/O; the supervisor removes
+                // the connection from the registry however the worker ends
+                let io = TokioIo::new(upgraded);
+                let exit = if conn.max_concurrent > 1 {
+                    let stream_window = conn.stream_bodies.then_some(state.stream_window_chunks);
+                    let worker = run_multiplexed_worker(io, request_rx, state.coalesce_bytes, conn.max_concurrent, stream_window);
+                    supervise(&state.registry, &conn, worker).await
+                } else {
+                    let worker = run_worker(io, request_rx, state.coalesce_bytes);
+                    supervise(&state.registry, &conn, worker).await
+                };
+                state.history.disconnected(usage::token_name(&conn), conn.id, &exit);
+                if let Some(warming_up) = warming_up {
+                    warming_up.abort();
+                }
+            }
+            Err(e) => {
+                error!("Failed to upgrade connection: {}", e);
+            }
+        }
+    }.instrument(task_span));
+
+    response
+}
+
+/// Makes `conn` the active connection, after its warm-up probe if one is configured
+async fn activate(state: &ServerState, conn: &Arc<TunnelConnection>) {
+    if let Some(warmup) = &state.warmup {
+        info!("Probing {} before routing visitors to the tunnel", warmup.path);
+        if !warmup.probe(conn).await {
+            return;
+        }
+    }
+    if state.registry.register(conn.clone()).await.is_some() {
+        info!("Replaced old client connection");
+    }
+    // The worker may have ended while the probe was being answered
+    if conn.is_closed() {
+        state.registry.remove(conn).await;
+    }
+}
+
+/// Handles all HTTP requests by forwarding them through the tunnel, writing one access log line each
+pub(crate) async fn http_handler(
+    State(state): State<ServerState>,
+    request: Request<Body>,
+) -> Response<Body> {
+    let started = Instant::now();
+    let method = request.method().clone();
+    let path = state.redactor.text(request.uri().path()).into_owned();
+
+    let mut response = dispatch(state, request).await;
+
+    let elapsed = started.elapsed().as_millis() as u64;
+    let tags = response.extensions().get::<ResponseTags>().map(|ResponseTags(tags)| format_tags(tags)).unwrap_or_default();
+    let Some(LocalDuration(local)) = response.extensions().get::<LocalDuration>().copied() else {
+        info!(target: ACCESS_TARGET, "{} {} {} {}ms{}", method, path, response.status().as_u16(), elapsed, tags);
+        return response;
+    };
+    info!(target: ACCESS_TARGET, "{} {} {} {}ms (local {}ms){}", method, path, response.status().as_u16(), elapsed, local, tags);
+
+    // Lets browser dev tools split the time between the local service and the way through the tunnel
+    let timing = format!("local;desc=\"Local service\";dur={}, tunnel;desc=\"Tunnel\";dur={}", local, elapsed.saturating_sub(local));
+    if let Ok(timing) = header::HeaderValue::from_str(&timing) {
+        response.headers_mut().append(header::HeaderName::from_static("server-timing"), timing);
+    }
+    response
+}
+
+/// Forwards a request to the active tunnel and maps failures to error responses
+async fn dispatch(state: ServerState, request: Request<Body>) -> Response<Body> {
+    // Check if client is connected; if it just went away, give it a moment to come back
+    let active = match (state.registry.active().await, state.reconnect_grace, state.registry.lost_at()) {
+        (Some(client), _, _) => Some(client),
+        (None, Some(grace), Some(lost_at)) => state.registry.wait_for_connection(None, lost_at + grace).await,
+        (None, _, _) => None,
+    };
+    let Some(client) = active else {
+        return state.landing.response();
+    };
+
+    // The client asked for visitors to reach it only within these windows
+    if client.schedule.as_ref().is_some_and(|schedule| !schedule.contains(SystemTime::now())) {
+        return Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(ERROR_CODE_HEADER, "outside-schedule")
+            .body(Body::from("Tunnel is outside its scheduled hours"))
+            .unwrap();
+    }
+
+    // Some routes are only for visitors from the networks the operator listed
+    if !state.internal_routes.admits(request.uri().path(), visitor_addr(&request)) {
+        return Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .header(ERROR_CODE_HEADER, "internal-route")
+            .body(Body::from("This route is only reachable from internal networks"))
+            .unwrap();
+    }
+
+    // HTTPS-only tunnels never forward a plain-HTTP request; over HTTPS, browsers are told to stay there
+    if !client.https_only {
+        return dispatch_to(state, client, request).await;
+    }
+    if !is_https(&request, &state.trusted_proxies) {
+        return https_redirect(&request);
+    }
+    let mut response = dispatch_to(state, client, request).await;
+    if !response.headers().contains_key(header::STRICT_TRANSPORT_SECURITY) {
+        response.headers_mut().insert(header::STRICT_TRANSPORT_SECURITY, header::HeaderValue::from_static(HSTS_VALUE));
+    }
+    response
+}
+
+/// Address of the visitor's connection, when the router is served with connect info
+fn visitor_addr(request: &Request<Body>) -> Option<IpAddr> {
+    request.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip())
+}
+
+/// Whether the visitor's request reached us over HTTPS: natively, or through a
+/// TLS-terminating reverse proxy among `trusted_proxies` that says so in X-Forwarded-Proto
+fn is_https(request: &Request<Body>, trusted_proxies: &[IpNet]) -> bool {
+    if request.extensions().get::<tls::ServedOverTls>().is_some() {
+        return true;
+    }
+    // Anyone else could claim it to skip the redirect
+    let Some(peer) = visitor_addr(request).map(|addr| addr.to_canonical()) else {
+        return false;
+    };
+    trusted_proxies.iter().any(|network| network.contains(&peer))
+        && request.headers()
+            .get("x-forwarded-proto")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"))
+}
+
+/// Sends a plain-HTTP visitor to the same URL on the default HTTPS port (308 keeps the method and body)
+fn https_redirect(request: &Request<Body>) -> Response<Body> {
+    let host = request.uri().host().map(str::to_string).or_else(|| {
+        let authority = request.headers().get(header::HOST)?.to_str().ok()?.parse::<Authority>().ok()?;
+        Some(authority.host().to_string())
+    });
+    let Some(host) = host else {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from("Missing Host header"))
+            .unwrap();
+    };
+    Response::builder()
+        .status(StatusCode::PERMANENT_REDIRECT)
+        .header(header::LOCATION, format!("https://{}{}", host, origin_form(request.uri())))
+        .header(ERROR_CODE_HEADER, "https-required")
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Forwards a request to `client`, answering CORS preflights for it if the client asked the server to
+async fn dispatch_to(state: ServerState, client: Arc<TunnelConnection>, request: Request<Body>) -> Response<Body> {
+    if client.cors_origins.is_empty() {
+        return authorize_and_forward(state, client, request).await;
+    }
+    // Preflights never carry the visitor's credentials, so they are answered before checking them
+    if let Some(response) = cors::preflight(&client.cors_origins, &request) {
+        return response;
+    }
+    let origin = request.headers().get(header::ORIGIN).cloned();
+    let cors_origins = client.cors_origins.clone();
+    let mut response = authorize_and_forward(state, client, request).await;
+    cors::add_headers(&cors_origins, origin.as_ref(), &mut response);
+    response
+}
+
+/// Forwards a request to `client`, answering for it when the visitor lacks credentials or the tunnel fails
+async fn authorize_and_forward(state: ServerState, client: Arc<TunnelConnection>, request: Request<Body>) -> Response<Body> {
+    // The client asked for its tunnel to be protected; it never sees these requests
+    if let Some(expected) = &client.visitor_auth {
+        let presented = extract_basic_auth(request.headers()).unwrap_or_default();
+        if !constant_time_eq(presented.as_bytes(), expected.as_bytes()) {
+            return Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(header::WWW_AUTHENTICATE, "Basic realm=\"tunnel\"")
+                .header(ERROR_CODE_HEADER, "visitor-auth-required")
+                .body(Body::from("Authorization required"))
+                .unwrap();
+        }
+    }
+
+    // A single visitor may not take up the whole tunnel; the slot is held until the response body ends
+    let visitor_slot = match visitor_addr(&request) {
+        Some(visitor) => match state.visitors.acquire(visitor) {
+            Some(slot) => Some(slot),
+            None => {
+                debug!("Visitor {} at VISITOR_MAX_IN_FLIGHT; refusing request", visitor);
+                return Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .header(header::RETRY_AFTER, "1")
+                    .header(ERROR_CODE_HEADER, "visitor-concurrency")
+                    .body(Body::from("Too many concurrent requests from this address"))
+                    .unwrap();
+            }
+        },
+        None => None,
+    };
+
+    // The operator's own service may decide who reaches some routes
+    let verdict = state.auth_hooks
+        .check(request.method().as_str(), &origin_form(request.uri()), visitor_addr(&request), request.headers())
+        .await;
+    match verdict {
+        None | Some(Verdict::Allow) => {}
+        Some(Verdict::Deny { status, www_authenticate, body }) => {
+            let mut response = Response::builder()
+                .status(status)
+                .header(ERROR_CODE_HEADER, "auth-hook-denied");
+            if let Some(challenge) = www_authenticate {
+                response = response.header(header::WWW_AUTHENTICATE, challenge);
+            }
+            return response.body(Body::from(body)).unwrap();
+        }
+        Some(Verdict::Unavailable(reason)) => {
+            error_dedup!("{}", reason);
+            return Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(ERROR_CODE_HEADER, "auth-hook-unavailable")
+                .body(Body::from("Authorization service unavailable"))
+                .unwrap();
+        }
+    }
+
+    // Tracked until this function returns, so a timed-out request is logged as slow too
+    let tracked = state.requests.track(client.id, request.method().as_str(), &state.redactor.text(request.uri().path()));
+
+    // Forward request through tunnel until it completes or one of the timeouts expires
+    let started = Instant::now();
+    let progress = tracked.progress();
+    let result = tokio::select! {
+        result = forward_request(client.clone(), request, started, &state, progress.clone()) => Ok(result),
+        expired = state.timeouts.expired(started, &progress) => Err(expired),
+    };
+    let transfer = progress.transfer();
+    state.usage.record_bytes(usage::token_name(&client), transfer.request_bytes, transfer.response_bytes);
+    state.history.record_bytes(usage::token_name(&client), client.id, transfer.request_bytes, transfer.response_bytes);
+    let response = match result {
+        Ok(Ok(response)) => response,
+        // The client is restarting: ask the visitor to retry rather than wait for it
+        Ok(Err(ForwardError::Tunnel(TunnelError::Draining))) => Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(header::RETRY_AFTER, "1")
+            .header(ERROR_CODE_HEADER, "tunnel-draining")
+            .body(Body::from("Tunnel client is restarting; retry shortly"))
+            .unwrap(),
+        Ok(Err(e)) => {
+            client.span.in_scope(|| error_dedup!("Tunnel error: {}", e));
+
+            // Clean up broken connection from active client slot
+            if e.breaks_tunnel() && state.registry.remove(&client).await {
+                client.span.in_scope(|| info!("Removing broken client connection"));
+            }
+
+            Response::builder()
+                .status(e.status())
+                .body(Body::from(e.to_string()))
+                .unwrap()
+        }
+        Err(expired) => {
+            client.span.in_scope(|| error_dedup!("{}", expired.message()));
+
+            // Clean up a connection stuck on this request from active client slot
+            if expired.breaks_tunnel() && state.registry.remove(&client).await {
+                client.span.in_scope(|| info!("Removing timed-out client connection"));
+            }
+
+            Response::builder()
+                .status(StatusCode::GATEWAY_TIMEOUT)
+                .header(ERROR_CODE_HEADER, expired.code())
+                .body(Body::from(expired.message()))
+                .unwrap()
+        }
+    };
+    state.traffic.record(client.id, response.status().as_u16(), started.elapsed());
+    match visitor_slot {
+        Some(slot) => response.map(|body| Body::new(SlotBody::new(body, slot))),
+        None => response,
+    }
+}
+
+/// Path and query of a request target, whatever its form
+///
+/// An absolute-form target (`GET http://host/path HTTP/1.1`, sent by some
+/// proxies and old probes) is reduced to `/path`. Asterisk- and authority-form
+/// targets come out without a leading `/` and fail `validate_path`.
+fn origin_form(uri: &Uri) -> String {
+    match uri.query() {
+        Some(query) => format!("{}?{}", uri.path(), query),
+        None => uri.path().to_string(),
+    }
+}
+
+/// Header pairs to forward for a request, named as in `names` (see `tunnel_core::header_case::names`)
+///
+/// The host of an absolute-form target replaces any Host header (RFC 7230
+/// section 5.4), and becomes the Host header of an HTTP/1.0 request that had
+/// none. Userinfo in the target is never forwarded.
+fn request_headers(uri: &Uri, headers: &HeaderMap, names: Vec<String>) -> Vec<(String, HeaderValueBytes)> {
+    let mut pairs: Vec<(String, HeaderValueBytes)> = names.into_iter().zip(headers.iter()).map(|(name, (_, value))| (name, value.as_bytes().into())).collect();
+    if let (Some(_), Some(host)) = (uri.scheme(), uri.host()) {
+        let host = match uri.port_u16() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        pairs.retain(|(name, _)| !name.eq_ignore_ascii_case("host"));
+        pairs.insert(0, ("host".to_string(), host.into()));
+    }
+    pairs
+}
+
+/// Converts a header map into name-value pairs with raw values
+/// Every value of a repeated header (e.g. Set-Cookie) is kept, in the order it was received
+fn header_pairs(headers: &HeaderMap) -> Vec<(String, HeaderValueBytes)> {
+    headers
+        .iter()
+        .map(|(name, value)| (name.as_str().to_string(), value.as_bytes().into()))
+        .collect()
+}
+
+/// Sends a request payload through `client`
+///
+/// If the tunnel goes away before the request was written to the client (e.g.
+/// the request was still queued), it is held for up to TUNNEL_RECONNECT_GRACE_MS
+/// and sent again on the client's next connection.
+async fn round_trip_resending(
+    state: &ServerState,
+    mut client: Arc<TunnelConnection>,
+    mut payload: Bytes,
+    progress: &Arc<RequestProgress>,
+) -> Result<TunnelReply, TunnelError> {
+    let resend_until = state.reconnect_grace.map(|grace| Instant::now() + grace);
+    loop {
+        let result = client.round_trip_streaming(payload.clone(), None, progress.clone()).await;
+        let (Err(e), Some(until)) = (&result, resend_until) else {
+            return result;
+        };
+        if !e.lost_before_delivery(progress) {
+            return result;
+        }
+        client.span.in_scope(|| debug!("Holding request for the next tunnel connection: {}", e));
+        match state.registry.wait_for_connection(Some(client.id), until).await {
+            Some(next) => client = next,
+            None => return result,
+        }
+        // The client may have been replaced by one that only takes JSON
+        if !client.binary_frames && is_binary_frame(&payload) {
+            match binary_request_to_json(&payload) {
+                Some(json) => payload = json,
+                None => return result,
+            }
+        }
+    }
+}
+
+/// Re-encodes a request built as a binary frame as JSON
+fn binary_request_to_json(payload: &[u8]) -> Option<Bytes> {
+    let (mut request, body) = decode_request_frame(payload).ok()?;
+    request.body = encode_body(body?);
+    serde_json::to_vec(&request).ok().map(Bytes::from)
+}
+
+/// Reads a request body chunk by chunk, counting the bytes in `progress` as they arrive
+///
+/// Stops once more than `limit` bytes were read, returning them with the rest of the body.
+async fn read_body(mut body: Body, progress: &RequestProgress, limit: Option<usize>) -> Result<(Vec<u8>, Option<Body>, Option<HeaderMap>), axum::Error> {
+    let mut bytes = Vec::new();
+    let mut trailers = None;
+    while let Some(frame) = body.frame().await {
+        match frame?.into_data() {
+            Ok(data) => {
+                progress.add_request_bytes(data.len() as u64);
+                bytes.extend_from_slice(&data);
+            }
+            Err(frame) => trailers = frame.into_trailers().ok(),
+        }
+        if limit.is_some_and(|limit| bytes.len() > limit) {
+            return Ok((bytes, Some(body), None));
+        }
+    }
+    Ok((bytes, None, trailers))
+}
+
+/// Forwards an HTTP request through the tunnel and returns the response
+async fn forward_request(
+    client: Arc<TunnelConnection>,
+    request: Request<Body>,
+    started: Instant,
+    state: &ServerState,
+    progress: Arc<RequestProgress>,
+) -> Result<Response<Body>, ForwardError> {
+    // Extract request components; only the server may set the body checksum, and
+    // visitor credentials are for the server alone
+    let method = request.method().to_string();
+    let version = format!("{:?}", request.version());
+    let path = origin_form(request.uri());
+    let route = request.uri().path().to_string();
+    let keeps_case = request.version() < Version::HTTP_2;
+    let names = header_case::names(request.version(), request.headers(), request.extensions()).await;
+    let mut headers = request_headers(request.uri(), request.headers(), names);
+    headers.retain(|(name, _)| !name.eq_ignore_ascii_case(BODY_SHA256_HEADER));
+    if client.visitor_auth.is_some() {
+        headers.retain(|(name, _)| !name.eq_ignore_ascii_case("authorization"));
+    }
+    let head = request.method() == Method::HEAD;
+
+    // hyper has parsed these already; this keeps the tunnel free of anything the client would reject
+    validate_method(&method).map_err(ForwardError::InvalidRequest)?;
+    validate_path(&path).map_err(ForwardError::InvalidRequest)?;
+    validate_headers(&headers).map_err(ForwardError::InvalidRequest)?;
+
+    // Read request body
+    let declared_length = request.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    progress.set_request_total(declared_length);
+    // Where the client agreed to it, a body past the threshold is streamed: one
+    // known to be larger right away, one of unknown length once it grows past it
+    let limit = client.stream_bodies.then_some(state.stream_threshold_bytes);
+    let (body_bytes, rest, trailers) = match (limit, declared_length) {
+        (Some(limit), Some(length)) if length > limit as u64 => (Vec::new(), Some(request.into_body()), None),
+        _ => read_body(request.into_body(), &progress, limit).await.map_err(ForwardError::RequestBody)?,
+    };
+    let streamed = rest.is_some();
+
+    // Request templates need the whole body; a streamed one passes unchanged
+    let body_bytes = if streamed || state.templates.is_empty() {
+        body_bytes
+    } else {
+        let length = body_bytes.len();
+        let body_bytes = state.templates.apply_request(&route, &headers, body_bytes);
+        if body_bytes.len() != length {
+            for (_, value) in headers.iter_mut().filter(|(name, _)| name.eq_ignore_ascii_case("content-length")) {
+                *value = body_bytes.len().to_string().into();
+            }
+        }
+        body_bytes
+    };
+
+    // A streamed body's checksum comes with its end
+    if state.body_checksum && !streamed {
+        headers.push((BODY_SHA256_HEADER.to_string(), body_sha256(&body_bytes).into()));
+    }
+    if let Some(limits) = &client.peer_header_limits {
+        limits.check(&headers).map_err(ForwardError::RequestHeaderLimits)?;
+    }
+
+    // Construct tunnel request
+    let mut tunnel_req = TunnelRequest {
+        method,
+        path,
+        headers,
+        version: Some(version),
+        trailers: trailers.as_ref().map(header_pairs).unwrap_or_default(),
+        stream: streamed,
+        deadline_ms: state.timeouts.client_budget(started).map(|budget| budget.as_millis() as u64),
+        ..TunnelRequest::default()
+    };
+
+    // Serialize in a pooled buffer: the body as is in a binary frame, base64 in JSON
+    let mut payload_buf = if streamed {
+        encode_binary_message(&tunnel_req, &[])
+    } else if client.binary_frames {
+        encode_binary_message(&tunnel_req, &body_bytes)
+    } else {
+        tunnel_req.body = encode_body(&body_bytes);
+        encode_message(&tunnel_req)
+    }
+    .map_err(ForwardError::Encode)?;
+    drop(tunnel_req);
+
+    // Send request through the tunnel worker and wait for the response
+    let payload = payload_buf.split().freeze();
+    let result = match rest {
+        Some(rest) => {
+            let pieces = send_request_body(body_bytes, rest, progress.clone(), state.body_checksum, client.stream_trailers, state.stream_window_chunks);
+            client.round_trip_streaming(payload, Some(pieces), progress.clone()).await
+        }
+        None => round_trip_resending(state, client.clone(), payload, &progress).await,
+    };
+    MESSAGE_BUFFERS.put(payload_buf);
+    let reply = result?;
+
+    // Deserialize tunnel response
+    let (mut tunnel_resp, raw_body) = decode_response_frame(&reply.payload)
+        .map_err(ForwardError::InvalidResponse)?;
+
+    // Only 100-999 can be sent on
+    let status = StatusCode::from_u16(tunnel_resp.status).map_err(|_| ForwardError::InvalidStatus(tunnel_resp.status))?;
+
+    // Tags are for tunnel tooling, not for the visitor
+    let tags = tunnel_resp.tags();
+    let mut response_headers = std::mem::take(&mut tunnel_resp.headers);
+    validate_headers(&response_headers)
+        .and_then(|()| state.header_limits.check(&response_headers))
+        .map_err(ForwardError::InvalidResponseHeaders)?;
+    response_headers.retain(|(name, _)| !name.eq_ignore_ascii_case(TAG_HEADER));
+    state.header_rules.apply(&route, &mut response_headers);
+
+    // Decode response body, unless it came in a binary frame or follows in body frames
+    let response_body = match raw_body {
+        _ if reply.body.is_some() => Vec::new(),
+        Some(body) => body.to_vec(),
+        None => decode_body(&tunnel_resp.body).map_err(ForwardError::ResponseBody)?,
+    };
+    if tunnel_resp.body_sha256.as_ref().is_some_and(|expected| *expected != body_sha256(&response_body)) {
+        state.requests.record_checksum_mismatch();
+        return Err(ForwardError::ResponseChecksum);
+    }
+    let response_body = if head || reply.body.is_some() {
+        response_body
+    } else {
+        state.templates.apply(&route, tunnel_resp.status, &response_headers, response_body)
+    };
+
+    // Build HTTP response
+    let mut response_builder = Response::builder().status(status);
+
+    // The client's framing headers describe the body the local service sent, which need not
+    // match the decoded body (e.g. it was chunked), so the length is taken from the body itself.
+    // A HEAD response has no body and its Content-Length describes the GET response, so the
+    // client's value is kept; 1xx, 204 and 304 responses get none, nor do streamed ones.
+    let set_length = !head && reply.body.is_none() && !matches!(status.as_u16(), 100..=199 | 204 | 304);
+
+    // Trailers go out after a chunked body, listed in a Trailer header (as HTTP/1.1 requires),
+    // to visitors that accept them; a streamed body's come with its end, and reach HTTP/1.1
+    // visitors only where the local service listed them
+    let trailers = if set_length { trailer_map(&tunnel_resp.trailers) } else { HeaderMap::new() };
+    let set_length = set_length && trailers.is_empty();
+    if !trailers.is_empty() && !response_headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("trailer")) {
+        let names: Vec<&str> = trailers.keys().map(|name| name.as_str()).collect();
+        response_headers.push(("trailer".to_string(), names.join(", ").into()));
+    }
+
+    // Append (never insert) so repeated headers keep every value in order, and their names as spelled
+    let mut names = Vec::with_capacity(response_headers.len());
+    for (name, value) in response_headers {
+        if name.eq_ignore_ascii_case("transfer-encoding")
+            || (!head && name.eq_ignore_ascii_case("content-length"))
+        {
+            continue;
+        }
+        response_builder = response_builder.header(name.as_str(), value.into_bytes());
+        names.push(name);
+    }
+    if set_length {
+        response_builder = response_builder.header(header::CONTENT_LENGTH, response_body.len());
+    }
+    if let Some(local) = tunnel_resp.local_duration_ms {
+        response_builder = response_builder.extension(LocalDuration(local));
+    }
+    if !tags.is_empty() {
+        response_builder = response_builder.extension(ResponseTags(tags));
+    }
+
+    let body = match reply.body {
+        _ if head => Body::empty(),
+        // Its bytes are counted once it was passed on, after this returned
+        Some(pieces) => {
+            let (usage, history, token, id) = (state.usage.clone(), state.history.clone(), usage::token_name(&client).to_string(), client.id);
+            Body::new(StreamedBody::new(pieces, state.body_checksum, state.requests.clone(), move |bytes| {
+                usage.record_bytes(&token, 0, bytes);
+                history.record_bytes(&token, id, 0, bytes);
+            }))
+        }
+        None if !trailers.is_empty() => Body::new(TrailedBody::new(Bytes::from(response_body), trailers)),
+        None => Body::from(response_body),
+    };
+    let mut response = response_builder.body(body).unwrap();
+    if keeps_case {
+        response.extensions_mut().extend(header_case::extensions(names.iter().map(String::as_str)).await);
+    }
+    Ok(response)
+}
+
+/// Trailer fields from the client as a header map; invalid names or values, and
+/// fields that may not be sent as trailers, are left out
+fn trailer_map(trailers: &[(String, HeaderValueBytes)]) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for (name, value) in trailers {
+        if !allowed_in_trailers(name) {
+            debug!("Dropping trailer {}: not allowed after the body", name);
+            continue;
+        }
+        match (header::HeaderName::from_bytes(name.as_bytes()), header::HeaderValue::from_bytes(value.as_bytes())) {
+            (Ok(name), Ok(value)) => {
+                map.append(name, value);
+            }
+            _ => debug!("Dropping invalid trailer {}", name),
+        }
+    }
+    map
+}
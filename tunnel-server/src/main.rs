@@ -1,56 +1,121 @@
-use axum::{
-    body::Body,
-    extract::State,
-    http::{Request, Response, StatusCode, header, HeaderMap},
-    routing::{any, get},
-    Router,
-};
-use hyper::upgrade::Upgraded;
-use hyper_util::rt::TokioIo;
+use serde_json::{json, Value};
 use std::env;
+use tracing::{error, info, warn};
+use std::process;
 use std::sync::Arc;
-use tokio::io::BufReader;
-use tokio::sync::{mpsc, RwLock, oneshot};
-use tokio::time::{timeout, Duration};
-use tracing::{error, info};
-use tunnel_protocol::{decode_body, encode_body, read_frame, write_frame, TunnelRequest, TunnelResponse};
+use tokio::sync::watch;
+use tunnel_core::config::{check_report, usage, ConfigSource, Mode};
+use tunnel_core::redact::Redactor;
+use tunnel_core::{dedup, logging};
+use tunnel_server::api_keys::ApiKeys;
+use tunnel_server::auth_hooks::AuthHooks;
+use tunnel_server::header_rules::HeaderRules;
+use tunnel_server::internal_routes::InternalRoutes;
+use tunnel_server::landing::Landing;
+use tunnel_server::settings::ServerSettings;
+use tunnel_server::templates::ResponseTemplates;
+use tunnel_server::usage::UsageStore;
+#[cfg(unix)]
+use tunnel_server::handover;
+use tunnel_server::{admin, http, tls, ServerState};
 
-/// Request sent to the tunnel worker
-struct TunnelWorkerRequest {
-    payload: Vec<u8>,
-    response_tx: oneshot::Sender<Result<Vec<u8>, String>>,
-}
-
-/// Handle to communicate with the tunnel worker
-#[derive(Clone)]
-struct TunnelConnection {
-    request_tx: mpsc::UnboundedSender<TunnelWorkerRequest>,
-}
-
-/// Application state shared across handlers
-#[derive(Clone)]
-struct ServerState {
-    active_client: Arc<RwLock<Option<Arc<TunnelConnection>>>>,
-    tunnel_auth: Option<String>, // username:password for Basic Auth
-}
+#[tokio::main]
+async fn main() {
+    // Resolve configuration from command line, config file and environment
+    let keys = ServerSettings::keys();
+    let (source, mode) = match ConfigSource::load(keys.clone(), "SERVER_CONFIG", env::args().skip(1), |key| env::var(key).ok()) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+    if mode == Mode::Help {
+        print!("{}", usage("tunnel-server", "SERVER_CONFIG", &keys));
+        return;
+    }
 
-impl ServerState {
-    fn new(tunnel_auth: Option<String>) -> Self {
-        Self {
-            active_client: Arc::new(RwLock::new(None)),
-            tunnel_auth,
+    // Load the certificate, API keys, header, route, hook and redaction rules, landing page and saved usage as part of validation (e.g. unreadable TLS_KEY_FILE)
+    let validated = ServerSettings::from_source(&source).and_then(|settings| {
+        let cert_file = settings.tls_cert_file.clone().zip(settings.tls_key_file.clone());
+        let certs = match (cert_file, &settings.tls_cert_dir) {
+            (None, None) => None,
+            (cert_file, cert_dir) => {
+                let store = tls::CertStore::load(cert_file, cert_dir.clone(), &settings.tls)?
+                    .with_client_certs(settings.token_cert_binding);
+                let store = Arc::new(store);
+                Some((store.clone(), store.acceptor()?))
+            }
+        };
+        let api_keys = match &settings.admin_api_keys_file {
+            Some(path) => ApiKeys::load(path)?,
+            None => ApiKeys::default(),
+        };
+        let header_rules = match &settings.response_header_rules_file {
+            Some(path) => HeaderRules::load(path)?,
+            None => HeaderRules::default(),
+        };
+        let templates = match &settings.response_templates_file {
+            Some(path) => ResponseTemplates::load(path)?,
+            None => ResponseTemplates::default(),
+        };
+        let internal_routes = match &settings.internal_routes_file {
+            Some(path) => InternalRoutes::load(path)?,
+            None => InternalRoutes::default(),
+        };
+        let auth_hooks = match &settings.auth_hook_rules_file {
+            Some(path) => AuthHooks::load(path)?.with_cache_ttl(settings.auth_hook_cache).with_timeout(settings.auth_hook_timeout),
+            None => AuthHooks::default(),
+        };
+        let landing = match (&settings.no_tunnel_page_file, &settings.no_tunnel_redirect_url) {
+            (Some(path), _) => Landing::load_page(path)?,
+            (None, Some(url)) => Landing::redirect(url)?,
+            (None, None) => Landing::default(),
+        };
+        let redactor = match &settings.redact_rules_file {
+            Some(path) => Redactor::load(path)?,
+            None => Redactor::default(),
+        };
+        let token_usage = match &settings.token_usage_file {
+            Some(path) => UsageStore::load(path)?,
+            None => UsageStore::default(),
+        };
+        Ok((settings, certs, api_keys, header_rules, templates, internal_routes, auth_hooks, landing, redactor, token_usage))
+    });
+    let (settings, certs, api_keys, header_rules, templates, internal_routes, auth_hooks, landing, redactor, token_usage) = match validated {
+        Ok(validated) => validated,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            process::exit(1);
         }
+    };
+    if mode == Mode::CheckConfig {
+        print!("{}", check_report(&source, &settings));
+        return;
+    }
+    if let Mode::Status { json } = mode {
+        process::exit(print_status(&settings, json).await);
     }
-}
 
-#[tokio::main]
-async fn main() {
-    // Initialize tracing
-    tracing_subscriber::fmt::init();
+    // Initialize tracing; keep the guard so buffered log lines are flushed on exit
+    let (log_handle, _log_guard) = match logging::init(&settings.log) {
+        Ok(initialized) => initialized,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    #[cfg(unix)]
+    if let Err(e) = log_handle.toggle_debug_on_signal() {
+        warn!("SIGUSR1 log level toggle unavailable: {}", e);
+    }
+    tokio::spawn(dedup::report_suppressed());
 
-    // Parse configuration from environment variables
-    let http_addr = env::var("HTTP_ADDR").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
-    let tunnel_auth = env::var("TUNNEL_AUTH").ok();
+    let ServerSettings {
+        http_addr, tunnel_path, tunnel_auth, upgrade_secret, admin_addr, transport, queue, timeouts, stats_interval, slow_request,
+        reconnect_grace, warmup, body_sha256, visitor_max_in_flight, trusted_proxies, token_usage_file, token_cert_binding, upgrade_drain,
+        tls: tls_options, ..
+    } = settings;
 
     // Log authentication status
     if tunnel_auth.is_some() {
@@ -58,319 +123,252 @@ async fn main() {
     } else {
         info!("Tunnel authentication disabled");
     }
-
-    // Initialize shared state
-    let state = ServerState::new(tunnel_auth);
-
-    // Build HTTP router
-    let app = Router::new()
-        .route("/tunnel", get(tunnel_upgrade_handler))
-        .fallback(any(http_handler))
-        .with_state(state);
-
-    // Start HTTP server
-    info!("Server running on {}", http_addr);
-    let listener = tokio::net::TcpListener::bind(&http_addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
-}
-
-/// Extracts Basic Auth credentials from Authorization header
-/// Returns Some(username:password) if valid Basic Auth header is present
-fn extract_basic_auth(headers: &HeaderMap) -> Option<String> {
-    let auth_header = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
-
-    if !auth_header.starts_with("Basic ") {
-        return None;
+    info!(
+        "Tunnel endpoint: {} ({})",
+        tunnel_path,
+        if upgrade_secret.is_some() { "upgrade secret required" } else { "no upgrade secret" }
+    );
+
+    if !header_rules.is_empty() {
+        info!("Response header rules: {}", header_rules.len());
     }
-
-    let encoded = auth_header.strip_prefix("Basic ")?;
-    let decoded = tunnel_protocol::decode_body(encoded).ok()?;
-    let credentials = String::from_utf8(decoded).ok()?;
-
-    Some(credentials)
-}
-
-/// Handles HTTP Upgrade requests to establish tunnel connections
-async fn tunnel_upgrade_handler(
-    State(state): State<ServerState>,
-    request: Request<Body>,
-) -> Response<Body> {
-    // Check authentication if enabled
-    if let Some(ref expected_auth) = state.tunnel_auth {
-        match extract_basic_auth(request.headers()) {
-            Some(provided_auth) if provided_auth == *expected_auth => {
-                // Authentication successful
-                info!("Client authenticated successfully");
-            }
-            Some(_) => {
-                // Invalid credentials
-                error!("Authentication failed: Invalid credentials");
-                return Response::builder()
-                    .status(StatusCode::UNAUTHORIZED)
-                    .header(header::WWW_AUTHENTICATE, "Basic realm=\"tunnel\"")
-                    .body(Body::from("Invalid credentials"))
-                    .unwrap();
-            }
-            None => {
-                // Missing Authorization header
-                error!("Authentication failed: Missing Authorization header");
-                return Response::builder()
-                    .status(StatusCode::UNAUTHORIZED)
-                    .header(header::WWW_AUTHENTICATE, "Basic realm=\"tunnel\"")
-                    .body(Body::from("Authorization required"))
-                    .unwrap();
-            }
-        }
+    if !templates.is_empty() {
+        info!("Response templates: {}", templates.len());
     }
-
-    // Check for upgrade headers
-    let upgrade_header = request.headers().get(header::UPGRADE);
-    let connection_header = request.headers().get(header::CONNECTION);
-
-    let is_upgrade = upgrade_header
-        .and_then(|v| v.to_str().ok())
-        .map(|v| v.eq_ignore_ascii_case("tunnel"))
-        .unwrap_or(false);
-
-    let has_upgrade_connection = connection_header
-        .and_then(|v| v.to_str().ok())
-        .map(|v| v.to_lowercase().contains("upgrade"))
-        .unwrap_or(false);
-
-    if !is_upgrade || !has_upgrade_connection {
-        return Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body(Body::from("Missing or invalid Upgrade headers"))
-            .unwrap();
+    if !internal_routes.is_empty() {
+        info!("Internal-only routes: {}", internal_routes.len());
     }
-
-    // Attempt to upgrade the connection
-    let upgrade_result = hyper::upgrade::on(request);
-
-    // Send 101 Switching Protocols response
-    let response = Response::builder()
-        .status(StatusCode::SWITCHING_PROTOCOLS)
-        .header(header::UPGRADE, "tunnel")
-        .header(header::CONNECTION, "Upgrade")
-        .body(Body::empty())
-        .unwrap();
-
-    // Spawn task to handle the upgraded connection
-    tokio::spawn(async move {
-        match upgrade_result.await {
-            Ok(upgraded) => {
-                info!("Client upgraded to tunnel protocol");
-
-                // Create channel for communicating with worker
-                let (request_tx, request_rx) = mpsc::unbounded_channel();
-
-                let new_conn = Arc::new(TunnelConnection { request_tx });
-
-                // Update active client
-                let mut active = state.active_client.write().await;
-                if active.is_some() {
-                    info!("Replaced old client connection");
-                }
-                *active = Some(new_conn.clone());
-                drop(active);
-
-                // Spawn worker to handle the actual I/O
-                tunnel_worker(upgraded, request_rx).await;
-
-                // Worker exited, remove from active clients
-                let mut active = state.active_client.write().await;
-                if let Some(current) = &*active {
-                    if Arc::ptr_eq(current, &new_conn) {
-                        *active = None;
-                        info!("Client disconnected");
-                    }
-                }
-            }
-            Err(e) => {
-                error!("Failed to upgrade connection: {}", e);
-            }
+    if !auth_hooks.is_empty() {
+        info!("Authorization hooks: {}", auth_hooks.len());
+    }
+    if !matches!(landing, Landing::Unavailable) {
+        info!("Without a tunnel client: {}", landing.summary());
+    }
+    if !redactor.is_empty() {
+        info!("Redaction rules: {}", redactor.len());
+    }
+    if let Some(warmup) = &warmup {
+        info!("New tunnels must answer {} before visitors are routed to them", warmup.path);
+    }
+    if let Some(max) = visitor_max_in_flight {
+        info!("Requests in flight per visitor address: at most {}", max);
+    }
+    if token_cert_binding {
+        info!("Credentials bound to the client certificate first used with them");
+        if token_usage_file.is_none() {
+            warn!("TOKEN_CERT_BINDING without TOKEN_USAGE_FILE: bindings are lost on restart");
         }
+    }
+    let token_usage = Arc::new(token_usage);
+    let usage_saver = token_usage_file.map(|path| {
+        info!("Token usage saved to {}", path.display());
+        let token_usage = token_usage.clone();
+        tokio::spawn(async move { token_usage.save_periodically().await })
     });
 
-    response
-}
-
-/// Worker task that handles I/O for a tunnel connection
-async fn tunnel_worker(
-    upgraded: Upgraded,
-    mut request_rx: mpsc::UnboundedReceiver<TunnelWorkerRequest>,
-) {
-    let io = TokioIo::new(upgraded);
-    let (read_half, write_half) = tokio::io::split(io);
-    let mut reader = BufReader::new(read_half);
-    let mut writer = write_half;
-
-    while let Some(req) = request_rx.recv().await {
-        // Write request to tunnel
-        if let Err(e) = write_frame(&mut writer, &req.payload).await {
-            let _ = req.response_tx.send(Err(format!("Tunnel write failed: {}", e)));
-            break;
+    // Listeners handed over by the server this one replaces (SIGUSR2 upgrade), if any
+    #[cfg(unix)]
+    let (inherited, ready) = match handover::inherit() {
+        Ok(Some((listeners, ready))) => (Some(listeners), Some(ready)),
+        Ok(None) => (None, None),
+        Err(e) => {
+            error!("{}", e);
+            return;
         }
+    };
+    #[cfg(unix)]
+    let (inherited_admin, inherited_http) = match inherited {
+        Some(listeners) => (listeners.admin, Some(listeners.http)),
+        None => (None, None),
+    };
+    #[cfg(not(unix))]
+    let (inherited_admin, inherited_http) = (None::<std::net::TcpListener>, None::<std::net::TcpListener>);
 
-        // Read response from tunnel
-        match read_frame(&mut reader).await {
-            Ok(response_payload) => {
-                let _ = req.response_tx.send(Ok(response_payload));
-            }
+    // Initialize shared state
+    let api_keys_count = api_keys.len();
+    let state = ServerState::new(tunnel_auth, &transport)
+        .with_queue(queue)
+        .with_timeouts(timeouts)
+        .with_stats_interval(stats_interval)
+        .with_slow_threshold(slow_request)
+        .with_reconnect_grace(reconnect_grace)
+        .with_warmup(warmup)
+        .with_body_checksum(body_sha256)
+        .with_visitor_max_in_flight(visitor_max_in_flight)
+        .with_trusted_proxies(trusted_proxies)
+        .with_tunnel_path(tunnel_path)
+        .with_upgrade_secret(upgrade_secret)
+        .with_api_keys(api_keys)
+        .with_header_rules(header_rules)
+        .with_templates(templates)
+        .with_internal_routes(internal_routes)
+        .with_auth_hooks(auth_hooks)
+        .with_landing(landing)
+        .with_redactor(redactor)
+        .with_usage(token_usage.clone())
+        .with_cert_binding(token_cert_binding)
+        .with_log_handle(log_handle);
+
+    // Its client is still connected to the old process; requests wait for it to come over
+    #[cfg(unix)]
+    if inherited_http.is_some() {
+        info!("Taking over from server process {}", std::os::unix::process::parent_id());
+        state.registry.mark_lost().await;
+    }
+
+    // Bind (or take over) the admin listener if configured
+    let mut admin_listener = None;
+    if let Some(admin_addr) = &admin_addr {
+        let bound = match inherited_admin {
+            Some(listener) => tokio::net::TcpListener::from_std(listener),
+            None => tokio::net::TcpListener::bind(admin_addr).await,
+        };
+        match bound {
+            Ok(listener) => admin_listener = Some(listener),
             Err(e) => {
-                let _ = req.response_tx.send(Err(format!("Tunnel read failed: {}", e)));
-                break;
+                error!("Failed to bind ADMIN_ADDR {}: {}", admin_addr, e);
+                return;
             }
         }
     }
-}
 
-/// Handles all HTTP requests by forwarding them through the tunnel
-async fn http_handler(
-    State(state): State<ServerState>,
-    request: Request<Body>,
-) -> Response<Body> {
-    // Check if client is connected
-    let client_lock = state.active_client.read().await;
-    let client = match &*client_lock {
-        Some(c) => c.clone(),
-        None => {
-            drop(client_lock);
-            return Response::builder()
-                .status(StatusCode::SERVICE_UNAVAILABLE)
-                .body(Body::from("No tunnel client connected"))
-                .unwrap();
+    // Bind (or take over) the HTTP listener
+    let bound = match inherited_http {
+        Some(listener) => tokio::net::TcpListener::from_std(listener),
+        None => tokio::net::TcpListener::bind(&http_addr).await,
+    };
+    let listener = match bound {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind HTTP_ADDR {}: {}", http_addr, e);
+            return;
         }
     };
-    drop(client_lock);
-
-    // Forward request through tunnel with timeout
-    match timeout(
-        Duration::from_secs(30),
-        forward_request(client.clone(), request)
-    ).await {
-        Ok(Ok(response)) => response,
-        Ok(Err(msg)) => {
-            error!("Tunnel error: {}", msg);
+    if let Err(e) = transport.apply_to_listener(&listener) {
+        error!("Failed to set socket options on {}: {}", http_addr, e);
+        return;
+    }
 
-            // Clean up broken connection from active client slot
-            let mut active = state.active_client.write().await;
-            if let Some(current) = &*active {
-                if Arc::ptr_eq(current, &client) {
-                    info!("Removing broken client connection");
-                    *active = None;
-                }
+    // SIGUSR2: hand the listeners to a new server process, then drain and exit
+    let (upgraded_tx, upgraded_rx) = watch::channel(false);
+    #[cfg(unix)]
+    {
+        let listeners = match handover::Listeners::copy(&listener, admin_listener.as_ref()) {
+            Ok(listeners) => listeners,
+            Err(e) => {
+                error!("Failed to keep the listeners for upgrades: {}", e);
+                return;
             }
-            drop(active);
-
-            Response::builder()
-                .status(StatusCode::BAD_GATEWAY)
-                .body(Body::from(msg))
-                .unwrap()
-        }
-        Err(_) => {
-            error!("Tunnel request timeout");
-
-            // Clean up timed-out connection from active client slot
-            let mut active = state.active_client.write().await;
-            if let Some(current) = &*active {
-                if Arc::ptr_eq(current, &client) {
-                    info!("Removing timed-out client connection");
-                    *active = None;
+        };
+        let saved_usage = token_usage.clone();
+        tokio::spawn(async move {
+            // The new process resumes from the counts saved here; those of the drain are not carried over
+            handover::upgrade_on_signal(&listeners, || {
+                if let Err(e) = saved_usage.save() {
+                    warn!("{}", e);
                 }
+            })
+            .await;
+            if let Some(saver) = usage_saver {
+                saver.abort();
             }
-            drop(active);
-
-            Response::builder()
-                .status(StatusCode::GATEWAY_TIMEOUT)
-                .body(Body::from("Tunnel request timeout"))
-                .unwrap()
+            let _ = upgraded_tx.send(true);
+        });
+    }
+    // Upgrades need SIGUSR2 and inherited file descriptors: the server only ever drains on exit
+    #[cfg(not(unix))]
+    let _ = (upgraded_tx, usage_saver);
+
+    // Start admin API if configured
+    if let (Some(admin_addr), Some(admin_listener)) = (admin_addr, admin_listener) {
+        let admin_app = admin::router(state.clone());
+        if api_keys_count == 0 {
+            warn!("Admin API running on {} without authentication (ADMIN_API_KEYS_FILE unset)", admin_addr);
+        } else {
+            info!("Admin API running on {} ({} API keys)", admin_addr, api_keys_count);
         }
+        let shutdown = upgraded(upgraded_rx.clone());
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(admin_listener, admin_app).with_graceful_shutdown(shutdown).await {
+                error!("Admin API stopped: {}", e);
+            }
+        });
     }
-}
-
-/// Forwards an HTTP request through the tunnel and returns the response
-async fn forward_request(
-    client: Arc<TunnelConnection>,
-    request: Request<Body>,
-) -> Result<Response<Body>, String> {
-    // Extract request components
-    let method = request.method().to_string();
-    let path = request.uri()
-        .path_and_query()
-        .map(|pq| pq.as_str())
-        .unwrap_or("/")
-        .to_string();
 
-    let headers: Vec<(String, String)> = request
-        .headers()
-        .iter()
-        .map(|(name, value)| {
-            (
-                name.as_str().to_string(),
-                value.to_str().unwrap_or("").to_string(),
-            )
-        })
-        .collect();
-
-    // Read request body
-    let body_bytes = match axum::body::to_bytes(request.into_body(), usize::MAX).await {
-        Ok(bytes) => bytes.to_vec(),
-        Err(e) => return Err(format!("Failed to read request body: {}", e)),
-    };
+    // Build HTTP router
+    let app = tunnel_server::router(state);
 
-    // Construct tunnel request
-    let tunnel_req = TunnelRequest {
-        method,
-        path,
-        headers,
-        body: encode_body(&body_bytes),
+    // Start HTTP server
+    info!("Server running on {} ({})", http_addr, transport.summary());
+    #[cfg(unix)]
+    if let Some(ready) = ready {
+        if let Err(e) = ready.send() {
+            warn!("Failed to tell the old server process to stop: {}", e);
+        }
+    }
+    let shutdown = upgraded(upgraded_rx.clone());
+    let serve = async {
+        match certs {
+            Some((store, acceptor)) => {
+                info!("Native TLS enabled ({}; {})", store.summary(), tls_options.summary());
+                tokio::spawn(tls::watch(store));
+                tls::serve(listener, acceptor, app, transport.tcp_nodelay, shutdown).await;
+            }
+            None => {
+                http::serve(listener, app, transport.tcp_nodelay, shutdown).await;
+            }
+        }
     };
-
-    // Serialize to JSON
-    let payload = match serde_json::to_vec(&tunnel_req) {
-        Ok(p) => p,
-        Err(e) => return Err(format!("Failed to serialize request: {}", e)),
+    // After an upgrade, requests still unanswered once UPGRADE_DRAIN_SECS pass are cut off
+    let drain_deadline = async {
+        upgraded(upgraded_rx).await;
+        tokio::time::sleep(upgrade_drain).await;
     };
-
-    // Create oneshot channel for response
-    let (response_tx, response_rx) = oneshot::channel();
-
-    // Send request to worker
-    let worker_req = TunnelWorkerRequest {
-        payload,
-        response_tx,
-    };
-
-    if client.request_tx.send(worker_req).is_err() {
-        return Err("Tunnel connection closed".to_string());
+    tokio::select! {
+        () = serve => info!("Requests drained; exiting"),
+        () = drain_deadline => warn!("Requests still in flight after UPGRADE_DRAIN_SECS; exiting"),
     }
+}
 
-    // Wait for response
-    let response_payload = match response_rx.await {
-        Ok(Ok(payload)) => payload,
-        Ok(Err(e)) => return Err(e),
-        Err(_) => return Err("Tunnel worker disappeared".to_string()),
-    };
+/// Resolves once a new server process has taken over (SIGUSR2, Unix only)
+async fn upgraded(mut upgraded: watch::Receiver<bool>) {
+    if upgraded.wait_for(|upgraded| *upgraded).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
 
-    // Deserialize tunnel response
-    let tunnel_resp: TunnelResponse = match serde_json::from_slice(&response_payload) {
-        Ok(r) => r,
-        Err(e) => return Err(format!("Invalid tunnel response: {}", e)),
+/// `tunnel-server status [--json]`: asks the running server through its admin API
+///
+/// Returns the exit code: 0 when a tunnel client is connected, 1 when none is
+/// or the server cannot be reached.
+async fn print_status(settings: &ServerSettings, json: bool) -> i32 {
+    let Some(admin_addr) = &settings.admin_addr else {
+        eprintln!("The status command needs ADMIN_ADDR to reach the running server");
+        return 1;
     };
-
-    // Decode response body
-    let response_body = match decode_body(&tunnel_resp.body) {
-        Ok(b) => b,
-        Err(e) => return Err(format!("Failed to decode response body: {}", e)),
+    let status = match admin::query_status(admin_addr, settings.admin_api_token.as_deref()).await {
+        Ok(status) => status,
+        Err(e) => {
+            if json {
+                println!("{}", json!({ "ready": false, "error": e }));
+            } else {
+                eprintln!("{}", e);
+            }
+            return 1;
+        }
     };
+    let ready = status["ready"] == Value::Bool(true);
 
-    // Build HTTP response
-    let mut response_builder = Response::builder().status(tunnel_resp.status);
-
-    for (name, value) in tunnel_resp.headers {
-        response_builder = response_builder.header(name, value);
+    if json {
+        println!("{}", status);
+    } else {
+        let tunnel = match (&status["tunnel_id"], &status["connected_at"]) {
+            (Value::Number(id), Value::Number(since)) => format!("tunnel {} connected since {}", id, since),
+            _ => "no tunnel client connected".to_string(),
+        };
+        println!("State:        {} ({})", if ready { "ready" } else { "waiting" }, tunnel);
+        println!("Requests:     {} ({} in flight)", status["requests"]["total"], status["requests"]["in_flight"]);
+        println!("Uptime:       {}s", status["uptime_secs"]);
     }
-
-    Ok(response_builder.body(Body::from(response_body)).unwrap())
+    if ready { 0 } else { 1 }
 }
@@ -0,0 +1,193 @@
+//! Requests currently going through a tunnel, and the slow ones among them.
+//!
+//! Every forwarded request is tracked from the moment it is dispatched until
+//! its handler finishes, however it finishes (including a timeout). A request
+//! that took longer than the slow threshold is logged with the time spent in
+//! each phase and counted by the phase it spent most time in; one still in
+//! flight past the threshold is reported as hung by the admin API, which also
+//! lists every request in flight with the bytes it has moved so far.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+use tunnel_core::progress::{Phase, PhaseTimings, RequestProgress, Transfer};
+
+struct Tracked {
+    tunnel_id: u64,
+    method: String,
+    path: String,
+    progress: Arc<RequestProgress>,
+}
+
+/// Slow requests seen so far, by the phase each spent most time in
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SlowCounts {
+    pub total: u64,
+    pub request_read: u64,
+    pub queue: u64,
+    pub tunnel_write: u64,
+    pub client_processing: u64,
+    pub response_read: u64,
+}
+
+/// Request in flight, as reported by the admin API
+#[derive(Debug, Clone, Serialize)]
+pub struct InFlightRequest {
+    pub id: u64,
+    pub tunnel_id: u64,
+    pub method: String,
+    pub path: String,
+    pub age_ms: u64,
+    pub phase: Phase,
+    pub timings: PhaseTimings,
+    pub transfer: Transfer,
+}
+
+/// Requests in flight through any tunnel
+pub struct RequestTracker {
+    slow_threshold: Option<Duration>,  // None: slow requests are neither logged nor counted
+    next_id: AtomicU64,
+    in_flight: Mutex<HashMap<u64, Tracked>>,
+    slow: [AtomicU64; 5],  // Indexed like Phase::ALL
+    checksum_mismatches: AtomicU64,  // Responses refused for not matching their body checksum
+}
+
+impl RequestTracker {
+    pub fn new(slow_threshold: Option<Duration>) -> Self {
+        Self {
+            slow_threshold,
+            next_id: AtomicU64::new(1),
+            in_flight: Mutex::new(HashMap::new()),
+            slow: Default::default(),
+            checksum_mismatches: AtomicU64::new(0),
+        }
+    }
+
+    pub fn slow_threshold(&self) -> Option<Duration> {
+        self.slow_threshold
+    }
+
+    /// Starts tracking a request; it stays tracked until the guard is dropped
+    pub fn track(self: &Arc<Self>, tunnel_id: u64, method: &str, path: &str) -> TrackedRequest {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let progress = Arc::new(RequestProgress::new());
+        let tracked = Tracked {
+            tunnel_id,
+            method: method.to_string(),
+            path: path.to_string(),
+            progress: progress.clone(),
+        };
+        self.in_flight.lock().unwrap().insert(id, tracked);
+        TrackedRequest { tracker: self.clone(), id, progress }
+    }
+
+    /// Requests tracked since the server started, finished or not
+    pub fn total(&self) -> u64 {
+        self.next_id.load(Ordering::Relaxed) - 1
+    }
+
+    /// Counts a response whose body did not match its checksum
+    pub fn record_checksum_mismatch(&self) {
+        self.checksum_mismatches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Responses refused so far for not matching their body checksum
+    pub fn checksum_mismatches(&self) -> u64 {
+        self.checksum_mismatches.load(Ordering::Relaxed)
+    }
+
+    /// Every request in flight, oldest first
+    pub fn in_flight(&self) -> Vec<InFlightRequest> {
+        self.in_flight_for(Duration::ZERO)
+    }
+
+    /// Requests in flight for longer than the slow threshold, oldest first
+    pub fn hung(&self) -> Vec<InFlightRequest> {
+        match self.slow_threshold {
+            Some(threshold) => self.in_flight_for(threshold),
+            None => Vec::new(),
+        }
+    }
+
+    /// Requests in flight for at least `min_age`, oldest first
+    fn in_flight_for(&self, min_age: Duration) -> Vec<InFlightRequest> {
+        let mut requests: Vec<InFlightRequest> = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, tracked)| tracked.progress.elapsed() >= min_age)
+            .map(|(id, tracked)| InFlightRequest {
+                id: *id,
+                tunnel_id: tracked.tunnel_id,
+                method: tracked.method.clone(),
+                path: tracked.path.clone(),
+                age_ms: tracked.progress.elapsed().as_millis() as u64,
+                phase: tracked.progress.phase(),
+                timings: tracked.progress.timings(),
+                transfer: tracked.progress.transfer(),
+            })
+            .collect();
+        requests.sort_by_key(|request| std::cmp::Reverse(request.age_ms));
+        requests
+    }
+
+    /// Slow requests that have finished so far
+    pub fn slow_counts(&self) -> SlowCounts {
+        let [request_read, queue, tunnel_write, client_processing, response_read] =
+            self.slow.each_ref().map(|count| count.load(Ordering::Relaxed));
+        SlowCounts {
+            total: request_read + queue + tunnel_write + client_processing + response_read,
+            request_read,
+            queue,
+            tunnel_write,
+            client_processing,
+            response_read,
+        }
+    }
+
+    fn complete(&self, id: u64) {
+        let Some(tracked) = self.in_flight.lock().unwrap().remove(&id) else {
+            return;
+        };
+        tracked.progress.finish();
+
+        let elapsed = tracked.progress.elapsed();
+        if self.slow_threshold.is_some_and(|threshold| elapsed >= threshold) {
+            let timings = tracked.progress.timings();
+            let slowest = timings.slowest();
+            if let Some(index) = Phase::ALL.iter().position(|phase| *phase == slowest) {
+                self.slow[index].fetch_add(1, Ordering::Relaxed);
+            }
+            warn!(
+                tunnel = tracked.tunnel_id,
+                "Slow request: {} {} took {}ms, mostly {} (request read {}ms, queue {}ms, tunnel write {}ms, client {}ms, response read {}ms)",
+                tracked.method, tracked.path, elapsed.as_millis(), slowest.as_str(),
+                timings.request_read_ms, timings.queue_ms, timings.tunnel_write_ms, timings.client_processing_ms, timings.response_read_ms,
+            );
+        }
+    }
+}
+
+/// A request being tracked; completes it when dropped
+pub struct TrackedRequest {
+    tracker: Arc<RequestTracker>,
+    id: u64,
+    progress: Arc<RequestProgress>,
+}
+
+impl TrackedRequest {
+    /// Progress to hand to the tunnel worker
+    pub fn progress(&self) -> Arc<RequestProgress> {
+        self.progress.clone()
+    }
+}
+
+impl Drop for TrackedRequest {
+    fn drop(&mut self) {
+        self.tracker.complete(self.id);
+    }
+}
@@ -0,0 +1,254 @@
+//! Server settings resolved from the command line, config file and environment.
+
+use ipnet::IpNet;
+use serde::{Serialize, Serializer};
+use std::path::PathBuf;
+use std::time::Duration;
+use tunnel_core::config::{
+    parse_tunnel_path, parse_upgrade_secret, serialize_millis, serialize_opt_millis, serialize_opt_secs, serialize_redacted, serialize_secs,
+    ConfigSource,
+};
+use tunnel_core::logging::LogOptions;
+use tunnel_core::server::QueueOptions;
+use tunnel_core::tls::TlsOptions;
+use tunnel_core::transport::TransportOptions;
+use tunnel_protocol::DEFAULT_TUNNEL_PATH;
+
+use crate::auth_hooks;
+use crate::embed::DEFAULT_HTTP_ADDR;
+use crate::internal_routes::parse_network;
+use crate::timeouts::Timeouts;
+use crate::warmup::Warmup;
+use crate::{DEFAULT_SLOW_REQUEST, DEFAULT_STATS_INTERVAL, DEFAULT_UPGRADE_DRAIN};
+
+/// Effective server configuration
+#[derive(Serialize)]
+pub struct ServerSettings {
+    pub http_addr: String,           // Bind address for public HTTP and tunnel connections
+    pub tunnel_path: String,         // Upgrade endpoint for tunnel clients
+    #[serde(serialize_with = "serialize_redacted")]
+    pub tunnel_auth: Option<String>, // username:password for Basic Auth
+    #[serde(serialize_with = "serialize_redacted")]
+    pub upgrade_secret: Option<String>, // Preshared secret required at upgrade (None: not required)
+    pub admin_addr: Option<String>,  // Bind address for the admin API (None: disabled)
+    pub admin_api_keys_file: Option<PathBuf>, // Keys the admin API requires (None: no authentication)
+    #[serde(serialize_with = "serialize_redacted")]
+    pub admin_api_token: Option<String>, // Key `tunnel-server status` presents to the admin API
+    pub transport: TransportOptions,
+    pub queue: QueueOptions,
+    pub timeouts: Timeouts,
+    #[serde(rename = "stats_interval_secs", serialize_with = "serialize_opt_secs")]
+    pub stats_interval: Option<Duration>, // Client stats report interval (None: reports disabled)
+    #[serde(rename = "slow_request_ms", serialize_with = "serialize_opt_millis")]
+    pub slow_request: Option<Duration>,   // Requests taking longer are logged as slow (None: disabled)
+    #[serde(rename = "reconnect_grace_ms", serialize_with = "serialize_opt_millis")]
+    pub reconnect_grace: Option<Duration>, // Requests wait this long for a lost client to reconnect (None: disabled)
+    pub warmup: Option<Warmup>,           // Probe a new tunnel must answer before it is routed (None: routed right away)
+    pub body_sha256: bool,                // Forwarded requests carry the SHA-256 of their body
+    pub visitor_max_in_flight: Option<usize>, // Requests one visitor address may have in flight (None: no cap)
+    #[serde(serialize_with = "serialize_networks")]
+    pub trusted_proxies: Vec<IpNet>,      // Peers whose X-Forwarded-Proto is believed (empty: none)
+    pub response_header_rules_file: Option<PathBuf>, // Per-route response header allow/deny rules (None: headers pass as is)
+    pub response_templates_file: Option<PathBuf>, // Per-route templates applied to response bodies (None: bodies pass as is)
+    pub internal_routes_file: Option<PathBuf>, // Routes only listed visitor networks may reach (None: every route is public)
+    pub auth_hook_rules_file: Option<PathBuf>, // Routes whose requests an external service authorizes (None: none)
+    #[serde(rename = "auth_hook_cache_secs", serialize_with = "serialize_secs")]
+    pub auth_hook_cache: Duration,        // How long hook answers are reused (zero: not cached)
+    #[serde(rename = "auth_hook_timeout_ms", serialize_with = "serialize_millis")]
+    pub auth_hook_timeout: Duration,      // Longest wait for a hook's answer
+    pub no_tunnel_page_file: Option<PathBuf>, // HTML page served with 404 while no client is connected (None: 503)
+    pub no_tunnel_redirect_url: Option<String>, // Where visitors are redirected while no client is connected (None: 503)
+    pub redact_rules_file: Option<PathBuf>, // Redaction rules for logged and listed request paths (None: none)
+    pub token_usage_file: Option<PathBuf>, // Where usage per tunnel credential is saved (None: kept in memory)
+    pub token_cert_binding: bool,         // Bind each credential to the first client certificate used with it
+    #[serde(rename = "upgrade_drain_secs", serialize_with = "serialize_secs")]
+    pub upgrade_drain: Duration,          // Longest the old process finishes its requests after an upgrade (SIGUSR2)
+    pub tls_cert_file: Option<PathBuf>, // PEM certificate chain for native TLS (None: plain HTTP)
+    pub tls_key_file: Option<PathBuf>,  // PEM private key matching the certificate
+    pub tls_cert_dir: Option<PathBuf>,  // Per-hostname certificates picked by SNI (None: TLS_CERT_FILE only)
+    pub tls: TlsOptions,
+    pub log: LogOptions,
+}
+
+impl ServerSettings {
+    /// Every setting the server understands
+    pub fn keys() -> Vec<&'static str> {
+        let mut keys = vec![
+            "HTTP_ADDR", "TUNNEL_PATH", "TUNNEL_AUTH", "TUNNEL_UPGRADE_SECRET", "ADMIN_ADDR", "ADMIN_API_KEYS_FILE", "ADMIN_API_TOKEN",
+            "TLS_CERT_FILE",
+            "TLS_KEY_FILE", "TLS_CERT_DIR",
+        ];
+        keys.extend(TransportOptions::KEYS);
+        keys.extend(QueueOptions::KEYS);
+        keys.extend(Timeouts::KEYS);
+        keys.push("TUNNEL_STATS_INTERVAL_SECS");
+        keys.push("TUNNEL_SLOW_REQUEST_MS");
+        keys.push("TUNNEL_RECONNECT_GRACE_MS");
+        keys.extend(Warmup::KEYS);
+        keys.push("TUNNEL_BODY_SHA256");
+        keys.push("VISITOR_MAX_IN_FLIGHT");
+        keys.push("TRUSTED_PROXIES");
+        keys.push("RESPONSE_HEADER_RULES_FILE");
+        keys.push("RESPONSE_TEMPLATES_FILE");
+        keys.push("INTERNAL_ROUTES_FILE");
+        keys.push("AUTH_HOOK_RULES_FILE");
+        keys.push("AUTH_HOOK_CACHE_SECS");
+        keys.push("AUTH_HOOK_TIMEOUT_MS");
+        keys.push("NO_TUNNEL_PAGE_FILE");
+        keys.push("NO_TUNNEL_REDIRECT_URL");
+        keys.push("REDACT_RULES_FILE");
+        keys.push("TOKEN_USAGE_FILE");
+        keys.push("TOKEN_CERT_BINDING");
+        keys.push("UPGRADE_DRAIN_SECS");
+        keys.extend(TlsOptions::KEYS);
+        keys.extend(LogOptions::KEYS);
+        keys
+    }
+
+    /// Resolves and validates the settings
+    pub fn from_source(source: &ConfigSource) -> Result<Self, String> {
+        let tunnel_auth = source.get("TUNNEL_AUTH");
+        if let Some(auth) = &tunnel_auth {
+            if !auth.contains(':') {
+                return Err("TUNNEL_AUTH must be in format 'username:password'".to_string());
+            }
+        }
+
+        let tls_cert_file = source.get("TLS_CERT_FILE").map(PathBuf::from);
+        let tls_key_file = source.get("TLS_KEY_FILE").map(PathBuf::from);
+        if tls_cert_file.is_some() != tls_key_file.is_some() {
+            return Err("TLS_CERT_FILE and TLS_KEY_FILE must be set together".to_string());
+        }
+
+        let stats_interval = match source.get("TUNNEL_STATS_INTERVAL_SECS") {
+            Some(value) => {
+                let secs: u64 = value.trim().parse()
+                    .map_err(|_| format!("Invalid TUNNEL_STATS_INTERVAL_SECS: {}", value))?;
+                (secs > 0).then(|| Duration::from_secs(secs))
+            }
+            None => Some(DEFAULT_STATS_INTERVAL),
+        };
+
+        let slow_request = match source.get("TUNNEL_SLOW_REQUEST_MS") {
+            Some(value) => {
+                let millis: u64 = value.trim().parse()
+                    .map_err(|_| format!("Invalid TUNNEL_SLOW_REQUEST_MS: {}", value))?;
+                (millis > 0).then(|| Duration::from_millis(millis))
+            }
+            None => Some(DEFAULT_SLOW_REQUEST),
+        };
+
+        let reconnect_grace = match source.get("TUNNEL_RECONNECT_GRACE_MS") {
+            Some(value) => {
+                let millis: u64 = value.trim().parse()
+                    .map_err(|_| format!("Invalid TUNNEL_RECONNECT_GRACE_MS: {}", value))?;
+                (millis > 0).then(|| Duration::from_millis(millis))
+            }
+            None => None,
+        };
+
+        let body_sha256 = match source.get("TUNNEL_BODY_SHA256") {
+            Some(value) => value.trim().parse()
+                .map_err(|_| format!("Invalid TUNNEL_BODY_SHA256: {} (expected true or false)", value))?,
+            None => false,
+        };
+
+        let visitor_max_in_flight = match source.get("VISITOR_MAX_IN_FLIGHT") {
+            Some(value) => {
+                let max: usize = value.trim().parse()
+                    .map_err(|_| format!("Invalid VISITOR_MAX_IN_FLIGHT: {}", value))?;
+                (max > 0).then_some(max)
+            }
+            None => None,
+        };
+
+        let trusted_proxies = source.get("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|network| !network.is_empty())
+            .map(parse_network)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Invalid TRUSTED_PROXIES: {}", e))?;
+
+        let token_cert_binding = match source.get("TOKEN_CERT_BINDING") {
+            Some(value) => value.trim().parse()
+                .map_err(|_| format!("Invalid TOKEN_CERT_BINDING: {} (expected true or false)", value))?,
+            None => false,
+        };
+        if token_cert_binding && tls_cert_file.is_none() && source.get("TLS_CERT_DIR").is_none() {
+            return Err("TOKEN_CERT_BINDING requires native TLS (TLS_CERT_FILE or TLS_CERT_DIR)".to_string());
+        }
+
+        let upgrade_drain = match source.get("UPGRADE_DRAIN_SECS") {
+            Some(value) => Duration::from_secs(value.trim().parse()
+                .map_err(|_| format!("Invalid UPGRADE_DRAIN_SECS: {}", value))?),
+            None => DEFAULT_UPGRADE_DRAIN,
+        };
+
+        let auth_hook_cache = match source.get("AUTH_HOOK_CACHE_SECS") {
+            Some(value) => Duration::from_secs(value.trim().parse()
+                .map_err(|_| format!("Invalid AUTH_HOOK_CACHE_SECS: {}", value))?),
+            None => auth_hooks::DEFAULT_CACHE_TTL,
+        };
+
+        let auth_hook_timeout = match source.get("AUTH_HOOK_TIMEOUT_MS") {
+            Some(value) => {
+                let millis: u64 = value.trim().parse()
+                    .ok()
+                    .filter(|&millis| millis > 0)
+                    .ok_or_else(|| format!("Invalid AUTH_HOOK_TIMEOUT_MS: {} (expected milliseconds above 0)", value))?;
+                Duration::from_millis(millis)
+            }
+            None => auth_hooks::DEFAULT_TIMEOUT,
+        };
+
+        let no_tunnel_page_file = source.get("NO_TUNNEL_PAGE_FILE").map(PathBuf::from);
+        let no_tunnel_redirect_url = source.get("NO_TUNNEL_REDIRECT_URL");
+        if no_tunnel_page_file.is_some() && no_tunnel_redirect_url.is_some() {
+            return Err("NO_TUNNEL_PAGE_FILE and NO_TUNNEL_REDIRECT_URL cannot be set together".to_string());
+        }
+
+        Ok(Self {
+            http_addr: source.get("HTTP_ADDR").unwrap_or_else(|| DEFAULT_HTTP_ADDR.to_string()),
+            tunnel_path: source.get("TUNNEL_PATH").map_or(Ok(DEFAULT_TUNNEL_PATH.to_string()), |path| parse_tunnel_path(&path))?,
+            tunnel_auth,
+            upgrade_secret: source.get("TUNNEL_UPGRADE_SECRET").map(|secret| parse_upgrade_secret(&secret)).transpose()?,
+            admin_addr: source.get("ADMIN_ADDR"),
+            admin_api_keys_file: source.get("ADMIN_API_KEYS_FILE").map(PathBuf::from),
+            admin_api_token: source.get("ADMIN_API_TOKEN"),
+            transport: TransportOptions::from_source(|key| source.get(key))?,
+            queue: QueueOptions::from_source(|key| source.get(key))?,
+            timeouts: Timeouts::from_source(|key| source.get(key))?,
+            stats_interval,
+            slow_request,
+            reconnect_grace,
+            warmup: Warmup::from_source(|key| source.get(key))?,
+            body_sha256,
+            visitor_max_in_flight,
+            trusted_proxies,
+            response_header_rules_file: source.get("RESPONSE_HEADER_RULES_FILE").map(PathBuf::from),
+            response_templates_file: source.get("RESPONSE_TEMPLATES_FILE").map(PathBuf::from),
+            internal_routes_file: source.get("INTERNAL_ROUTES_FILE").map(PathBuf::from),
+            auth_hook_rules_file: source.get("AUTH_HOOK_RULES_FILE").map(PathBuf::from),
+            auth_hook_cache,
+            auth_hook_timeout,
+            no_tunnel_page_file,
+            no_tunnel_redirect_url,
+            redact_rules_file: source.get("REDACT_RULES_FILE").map(PathBuf::from),
+            token_usage_file: source.get("TOKEN_USAGE_FILE").map(PathBuf::from),
+            token_cert_binding,
+            upgrade_drain,
+            tls_cert_file,
+            tls_key_file,
+            tls_cert_dir: source.get("TLS_CERT_DIR").map(PathBuf::from),
+            tls: TlsOptions::from_source(|key| source.get(key))?,
+            log: LogOptions::from_source(|key| source.get(key))?,
+        })
+    }
+}
+
+/// Serializes networks as their CIDR notation
+fn serialize_networks<S: Serializer>(networks: &[IpNet], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(networks.iter().map(ToString::to_string))
+}
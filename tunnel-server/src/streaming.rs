@@ -0,0 +1,212 @@
+//! Bodies streamed through the tunnel in body frames, on connections that agreed
+//! to it (see `tunnel_protocol::STREAM_HEADER`).
+//!
+//! A visitor's request body past TUNNEL_STREAM_THRESHOLD_BYTES is sent on as it
+//! arrives rather than read whole first, and a streamed response is passed on
+//! to the visitor as its pieces come, chunked rather than with a Content-Length.
+//! A request streamed to a client is not held for its next connection if the
+//! tunnel goes away: its body cannot be read again. Trailers of either body
+//! travel with its end, on connections that agreed to it (see
+//! `tunnel_protocol::STREAM_TRAILERS_HEADER`).
+
+use axum::body::{Body, Bytes};
+use axum::http::HeaderMap;
+use http_body_util::BodyExt;
+use hyper::body::Frame;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::sync::mpsc;
+use tracing::{debug, Instrument};
+use tunnel_core::framing::BodyPiece;
+use tunnel_core::progress::RequestProgress;
+use tunnel_protocol::{BodySha256, BODY_CHUNK_BYTES};
+
+use crate::requests::RequestTracker;
+use crate::{header_pairs, trailer_map};
+
+/// Sends `read`, the start of a request body already read (and counted), then
+/// the rest of `body` as it arrives, counting it in `progress`, at most `window`
+/// pieces ahead of the tunnel
+///
+/// The last piece is complete once the body ended, carrying its SHA-256 if
+/// `checksum` is set and its trailers if `trailers` is, and incomplete if the
+/// visitor's upload broke off.
+pub fn send_request_body(
+    read: Vec<u8>,
+    mut body: Body,
+    progress: Arc<RequestProgress>,
+    checksum: bool,
+    trailers: bool,
+    window: usize,
+) -> mpsc::Receiver<BodyPiece> {
+    let (pieces_tx, pieces_rx) = mpsc::channel(window);
+    tokio::spawn(async move {
+        let mut hasher = checksum.then(BodySha256::default);
+        if !send_data(&pieces_tx, &mut hasher, Bytes::from(read)).await {
+            return;
+        }
+        let mut received = HeaderMap::new();
+        let complete = loop {
+            match body.frame().await {
+                Some(Ok(frame)) => {
+                    let data = match frame.into_data() {
+                        Ok(data) => data,
+                        Err(frame) => {
+                            received.extend(frame.into_trailers().unwrap_or_default());
+                            continue;
+                        }
+                    };
+                    progress.add_request_bytes(data.len() as u64);
+                    if !send_data(&pieces_tx, &mut hasher, data).await {
+                        return;
+                    }
+                }
+                Some(Err(e)) => {
+                    debug!("Request body broke off: {}", e);
+                    break false;
+                }
+                None => break true,
+            }
+        };
+        let sha256 = hasher.filter(|_| complete).map(BodySha256::finish);
+        let trailers = if trailers && complete { header_pairs(&received) } else { Vec::new() };
+        let _ = pieces_tx.send(BodyPiece::End { complete, sha256, trailers }).await;
+    }.in_current_span());
+    pieces_rx
+}
+
+/// Sends `data` in pieces of at most BODY_CHUNK_BYTES; false once nobody takes them
+async fn send_data(pieces_tx: &mpsc::Sender<BodyPiece>, hasher: &mut Option<BodySha256>, mut data: Bytes) -> bool {
+    if let Some(hasher) = hasher {
+        hasher.update(&data);
+    }
+    while !data.is_empty() {
+        let piece = data.split_to(data.len().min(BODY_CHUNK_BYTES));
+        if pieces_tx.send(BodyPiece::Data(piece)).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// A streamed response body, passed on to the visitor as its pieces arrive
+///
+/// It fails, so the visitor sees the response break off rather than end, if the
+/// client gave up on the body, the tunnel closed before its end, or it does not
+/// match the SHA-256 the client sent with its end (checked when `checksum` is
+/// set). Trailers sent with its end follow the last piece. `on_end` gets the
+/// bytes passed on once the body ends or is dropped.
+pub struct StreamedBody {
+    pieces: mpsc::Receiver<BodyPiece>,
+    hasher: Option<BodySha256>,  // None: not checked
+    requests: Arc<RequestTracker>,  // Counts checksum mismatches
+    bytes: u64,
+    ended: bool,
+    on_end: Option<Box<dyn FnOnce(u64) + Send>>,
+}
+
+impl StreamedBody {
+    pub fn new(
+        pieces: mpsc::Receiver<BodyPiece>,
+        checksum: bool,
+        requests: Arc<RequestTracker>,
+        on_end: impl FnOnce(u64) + Send + 'static,
+    ) -> Self {
+        Self {
+            pieces,
+            hasher: checksum.then(BodySha256::default),
+            requests,
+            bytes: 0,
+            ended: false,
+            on_end: Some(Box::new(on_end)),
+        }
+    }
+
+    fn end(&mut self, result: Result<(), &'static str>) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+        self.ended = true;
+        match result {
+            Ok(()) => Poll::Ready(None),
+            Err(reason) => Poll::Ready(Some(Err(io::Error::new(io::ErrorKind::InvalidData, reason)))),
+        }
+    }
+}
+
+impl hyper::body::Body for StreamedBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+        if self.ended {
+            return Poll::Ready(None);
+        }
+        match ready!(self.pieces.poll_recv(cx)) {
+            Some(BodyPiece::Data(data)) => {
+                self.bytes += data.len() as u64;
+                if let Some(hasher) = &mut self.hasher {
+                    hasher.update(&data);
+                }
+                Poll::Ready(Some(Ok(Frame::data(data))))
+            }
+            Some(BodyPiece::End { complete: true, sha256, trailers }) => {
+                let actual = self.hasher.take().map(BodySha256::finish);
+                if sha256.is_some_and(|expected| actual.is_some_and(|actual| actual != expected)) {
+                    self.requests.record_checksum_mismatch();
+                    return self.end(Err("Response body does not match its checksum"));
+                }
+                let trailers = trailer_map(&trailers);
+                if !trailers.is_empty() {
+                    self.ended = true;
+                    return Poll::Ready(Some(Ok(Frame::trailers(trailers))));
+                }
+                self.end(Ok(()))
+            }
+            Some(BodyPiece::End { complete: false, .. }) => self.end(Err("Tunnel client could not send the whole response body")),
+            None => self.end(Err("Tunnel closed before the end of the response body")),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.ended
+    }
+}
+
+impl Drop for StreamedBody {
+    fn drop(&mut self) {
+        if let Some(on_end) = self.on_end.take() {
+            on_end(self.bytes);
+        }
+    }
+}
+
+/// Buffered response body followed by trailers
+///
+/// Its size is left unknown so hyper chunk-encodes it, the only HTTP/1.1
+/// framing that can carry trailers.
+pub struct TrailedBody {
+    data: Option<Bytes>,
+    trailers: Option<HeaderMap>,
+}
+
+impl TrailedBody {
+    pub fn new(data: Bytes, trailers: HeaderMap) -> Self {
+        Self { data: Some(data).filter(|data| !data.is_empty()), trailers: Some(trailers) }
+    }
+}
+
+impl hyper::body::Body for TrailedBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+        if let Some(data) = self.data.take() {
+            return Poll::Ready(Some(Ok(Frame::data(data))));
+        }
+        Poll::Ready(self.trailers.take().map(|trailers| Ok(Frame::trailers(trailers))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.data.is_none() && self.trailers.is_none()
+    }
+}
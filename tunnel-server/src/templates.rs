@@ -0,0 +1,162 @@
+//! Response and request templates, read from RESPONSE_TEMPLATES_FILE.
+//!
+//! One rule per line: `<path-prefix> <html|json-error|json-request> <template-file>`.
+//! Every rule whose prefix matches the request path applies, in file order:
+//! `html` inserts the template into HTML responses before `</body>` (e.g. a
+//! preview bar `<script>`), `json-error` replaces the body of JSON responses
+//! with a 4xx or 5xx status with the template, and `json-request` replaces the
+//! body of JSON requests with it before they go through the tunnel. In the
+//! JSON kinds, `{{body}}` stands for the original body as a JSON value (a
+//! string when it is not JSON, `null` when empty); `{{status}}` stands for the
+//! status code in response templates. Template files are read at startup,
+//! relative to the rules file. Blank lines and lines starting with `#` are
+//! ignored; paths are compared as for internal-only routes.
+//!
+//! Compressed (Content-Encoding) and streamed bodies pass unchanged.
+
+use serde_json::Value;
+use std::path::Path;
+use tunnel_core::rules;
+use tunnel_protocol::HeaderValueBytes;
+
+use crate::internal_routes::{canonical_path, under_prefix};
+
+/// What a rule does with the bodies it matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Html,         // Template inserted before `</body>` of text/html responses
+    JsonError,    // Template replaces the body of JSON error responses
+    JsonRequest,  // Template replaces the body of JSON requests
+}
+
+/// One line of the rules file, with its template
+#[derive(Debug)]
+pub struct TemplateRule {
+    pub path_prefix: String,
+    pub kind: Kind,
+    template: String,
+}
+
+/// Every response template the server applies
+#[derive(Debug, Default)]
+pub struct ResponseTemplates {
+    rules: Vec<TemplateRule>,
+}
+
+impl ResponseTemplates {
+    /// Reads the rules from `path`, and the templates they name
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        rules::load(path, "RESPONSE_TEMPLATES_FILE", |contents| Self::parse(contents, dir))
+    }
+
+    /// Parses the contents of a rules file, reading templates relative to `dir`
+    pub fn parse(contents: &str, dir: &Path) -> Result<Self, String> {
+        let mut rules = Vec::new();
+
+        for line in rules::lines(contents) {
+            let fields: Vec<&str> = line.fields().collect();
+            let [path_prefix, kind, file] = fields[..] else {
+                return Err(line.error("expected '<path-prefix> <html|json-error|json-request> <template-file>'"));
+            };
+            if !path_prefix.starts_with('/') {
+                return Err(line.error("path prefix must start with '/'"));
+            }
+            let kind = match kind {
+                "html" => Kind::Html,
+                "json-error" => Kind::JsonError,
+                "json-request" => Kind::JsonRequest,
+                _ => return Err(line.error(format!("unknown template kind '{}' (expected html, json-error or json-request)", kind))),
+            };
+            let file = dir.join(file);
+            let template = std::fs::read_to_string(&file)
+                .map_err(|e| line.error(format!("failed to read {}: {}", file.display(), e)))?;
+            rules.push(TemplateRule { path_prefix: canonical_path(path_prefix), kind, template });
+        }
+
+        Ok(Self { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Rules whose prefix covers `path`, in file order
+    fn matching<'a>(&'a self, path: &str) -> impl Iterator<Item = &'a TemplateRule> {
+        let path = canonical_path(path);
+        self.rules.iter().filter(move |rule| under_prefix(&path, &rule.path_prefix))
+    }
+
+    /// Applies the templates matching `path` to a response with `status`, `headers` and `body`
+    pub fn apply(&self, path: &str, status: u16, headers: &[(String, HeaderValueBytes)], mut body: Vec<u8>) -> Vec<u8> {
+        if self.rules.is_empty() {
+            return body;
+        }
+        let Some(media_type) = media_type(headers) else {
+            return body;
+        };
+        let html = media_type == "text/html";
+        let json = is_json(&media_type);
+
+        for rule in self.matching(path) {
+            let template = rule.template.replace("{{status}}", &status.to_string());
+            match rule.kind {
+                Kind::Html if html => {
+                    let at = rfind_ignore_case(&body, b"</body").unwrap_or(body.len());
+                    body.splice(at..at, template.into_bytes());
+                }
+                Kind::JsonError if json && status >= 400 => {
+                    body = template.replace("{{body}}", &json_value(&body).to_string()).into_bytes();
+                }
+                _ => {}
+            }
+        }
+        body
+    }
+
+    /// Applies the `json-request` templates matching `path` to a request with `headers` and `body`
+    pub fn apply_request(&self, path: &str, headers: &[(String, HeaderValueBytes)], mut body: Vec<u8>) -> Vec<u8> {
+        if self.rules.is_empty() || !media_type(headers).is_some_and(|media_type| is_json(&media_type)) {
+            return body;
+        }
+        for rule in self.matching(path).filter(|rule| rule.kind == Kind::JsonRequest) {
+            body = rule.template.replace("{{body}}", &json_value(&body).to_string()).into_bytes();
+        }
+        body
+    }
+}
+
+/// Media type of a body with `headers`, lowercase and without parameters (None: it has a Content-Encoding)
+fn media_type(headers: &[(String, HeaderValueBytes)]) -> Option<String> {
+    let header = |wanted: &str| {
+        headers.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(wanted))
+            .map(|(_, value)| String::from_utf8_lossy(value.as_bytes()).trim().to_ascii_lowercase())
+    };
+    if header("content-encoding").is_some_and(|encoding| encoding != "identity") {
+        return None;
+    }
+    let content_type = header("content-type").unwrap_or_default();
+    Some(content_type.split(';').next().unwrap_or_default().trim().to_string())
+}
+
+fn is_json(media_type: &str) -> bool {
+    media_type == "application/json" || media_type.ends_with("+json")
+}
+
+/// `body` as a JSON value: parsed when it is JSON, a string when not, `null` when empty
+fn json_value(body: &[u8]) -> Value {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Value::Null;
+    }
+    serde_json::from_slice(body).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).into_owned()))
+}
+
+/// Position of the last `needle` (lowercase ASCII) in `haystack`, ignoring case
+fn rfind_ignore_case(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).rposition(|window| window.eq_ignore_ascii_case(needle))
+}
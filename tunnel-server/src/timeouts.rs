@@ -0,0 +1,145 @@
+//! Timeouts for public requests, per phase of the round trip.
+//!
+//! Besides the overall limit (TUNNEL_REQUEST_TIMEOUT_MS, 30 s by default),
+//! a request can be limited in how long it takes to reach the client
+//! (dispatch), how long the client takes to start answering (first byte),
+//! and how long the response may stall between chunks (idle). With the
+//! overall limit off and an idle timeout set, a long download runs as long
+//! as bytes keep moving, while a stuck request still fails fast.
+
+use serde::Serialize;
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant};
+use tunnel_core::config::serialize_opt_millis;
+use tunnel_core::progress::{Phase, RequestProgress};
+
+/// Timeout which ended a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expired {
+    Request,   // The overall limit
+    Dispatch,  // Still waiting to reach the client
+    FirstByte, // The client had not started answering
+    Idle,      // The response stalled
+}
+
+impl Expired {
+    /// Value of the X-Tunnel-Error header on the 504
+    pub fn code(&self) -> &'static str {
+        match self {
+            Expired::Request => "request-timeout",
+            Expired::Dispatch => "dispatch-timeout",
+            Expired::FirstByte => "first-byte-timeout",
+            Expired::Idle => "idle-timeout",
+        }
+    }
+
+    /// Response body and log message
+    pub fn message(&self) -> &'static str {
+        match self {
+            Expired::Request => "Tunnel request timeout",
+            Expired::Dispatch => "Tunnel request timeout waiting to reach the client",
+            Expired::FirstByte => "Tunnel request timeout waiting for the client to respond",
+            Expired::Idle => "Tunnel request timeout: response stalled",
+        }
+    }
+
+    /// Whether the tunnel is stuck on this request and should be dropped
+    ///
+    /// A request that never reached the client says nothing about the
+    /// connection, which may be busy with a long download.
+    pub fn breaks_tunnel(&self) -> bool {
+        *self != Expired::Dispatch
+    }
+}
+
+/// Timeouts applied to every public request (None: no limit)
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Timeouts {
+    #[serde(rename = "request_ms", serialize_with = "serialize_opt_millis")]
+    pub request: Option<Duration>,    // From arrival to the complete response
+    #[serde(rename = "dispatch_ms", serialize_with = "serialize_opt_millis")]
+    pub dispatch: Option<Duration>,   // From entering the queue to being written to the client
+    #[serde(rename = "first_byte_ms", serialize_with = "serialize_opt_millis")]
+    pub first_byte: Option<Duration>, // From being written to the first response byte
+    #[serde(rename = "idle_ms", serialize_with = "serialize_opt_millis")]
+    pub idle: Option<Duration>,       // Between response bytes
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            request: Some(Duration::from_secs(30)),
+            dispatch: None,
+            first_byte: None,
+            idle: None,
+        }
+    }
+}
+
+impl Timeouts {
+    /// Settings read by `from_source`
+    pub const KEYS: [&'static str; 4] = [
+        "TUNNEL_REQUEST_TIMEOUT_MS", "TUNNEL_DISPATCH_TIMEOUT_MS", "TUNNEL_FIRST_BYTE_TIMEOUT_MS", "TUNNEL_IDLE_TIMEOUT_MS",
+    ];
+
+    /// Reads the timeouts from a key lookup; `0` turns a timeout off
+    pub fn from_source(get: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let mut timeouts = Self::default();
+        let targets = [&mut timeouts.request, &mut timeouts.dispatch, &mut timeouts.first_byte, &mut timeouts.idle];
+
+        for (key, target) in Self::KEYS.into_iter().zip(targets) {
+            if let Some(value) = get(key) {
+                let millis: u64 = value.trim().parse()
+                    .map_err(|_| format!("Invalid {}: {}", key, value))?;
+                *target = (millis > 0).then(|| Duration::from_millis(millis));
+            }
+        }
+
+        Ok(timeouts)
+    }
+
+    /// How long the client may work on a request that just left the server (None: no limit)
+    pub fn client_budget(&self, started: Instant) -> Option<Duration> {
+        let remaining = self.request.map(|request| (started + request).saturating_duration_since(Instant::now()));
+        match (remaining, self.first_byte) {
+            (Some(remaining), Some(first_byte)) => Some(remaining.min(first_byte)),
+            (remaining, first_byte) => remaining.or(first_byte),
+        }
+    }
+
+    /// Resolves when `progress` runs out of one of the timeouts, counting the overall limit from `started`
+    pub async fn expired(&self, started: Instant, progress: &RequestProgress) -> Expired {
+        loop {
+            // Deadlines only move later as the request progresses, so waking at
+            // the earliest one and checking again is enough
+            let phase_changed = progress.phase_changed();
+            let activity = progress.activity();
+            let phase_deadline = match activity.phase {
+                Phase::Queue | Phase::TunnelWrite => self.dispatch.zip(activity.queued_at).map(|(timeout, queued_at)| {
+                    (Instant::from_std(queued_at) + timeout, Expired::Dispatch)
+                }),
+                Phase::ClientProcessing => self.first_byte.map(|timeout| {
+                    (Instant::from_std(activity.last_progress) + timeout, Expired::FirstByte)
+                }),
+                Phase::ResponseRead => self.idle.map(|timeout| (Instant::from_std(activity.last_progress) + timeout, Expired::Idle)),
+                Phase::RequestRead | Phase::Done => None,
+            };
+            let request_deadline = self.request.map(|timeout| (started + timeout, Expired::Request));
+            let deadline = match (request_deadline, phase_deadline) {
+                (Some(request), Some(phase)) => Some(if phase.0 < request.0 { phase } else { request }),
+                (request, phase) => request.or(phase),
+            };
+
+            match deadline {
+                Some((at, expired)) if at <= Instant::now() => return expired,
+                Some((at, _)) => {
+                    tokio::select! {
+                        _ = sleep_until(at) => {}
+                        _ = phase_changed => {}
+                    }
+                }
+                None => phase_changed.await,
+            }
+        }
+    }
+}
@@ -0,0 +1,263 @@
+//! Native TLS termination for the public listener, as an alternative to
+//! running the server behind a TLS-terminating reverse proxy.
+//!
+//! The certificate is picked per connection from the SNI name the client
+//! sends (see [`CertStore`]), so one listener can serve several hostnames and
+//! custom domains, and certificates are reloaded when their files change.
+
+use axum::http::Extensions;
+use axum::Router;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tokio::net::TcpListener;
+use tokio::time::{sleep, timeout, Duration};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info};
+use tunnel_core::tls::{CertPin, TlsOptions};
+
+use crate::http;
+
+/// Longest a client may take to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the certificate files are checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Request extension marking a request that arrived over native TLS (see [`serve`])
+#[derive(Debug, Clone, Copy)]
+pub struct ServedOverTls;
+
+/// Request extension with the fingerprint of the certificate the client presented, if any (see [`serve`])
+#[derive(Debug, Clone, Copy)]
+pub struct ClientCertificate(pub CertPin);
+
+/// Loads a PEM certificate chain and private key and builds the TLS acceptor
+pub fn load_acceptor(cert_path: &Path, key_path: &Path, options: &TlsOptions) -> Result<TlsAcceptor, String> {
+    let store = CertStore::load(Some((cert_path.to_path_buf(), key_path.to_path_buf())), None, options)?;
+    Arc::new(store).acceptor()
+}
+
+/// Certificates for the public listener, keyed by hostname
+///
+/// TLS_CERT_DIR holds a `<hostname>.pem` certificate chain and a
+/// `<hostname>.key` private key per hostname; a file name starting with `_.`
+/// is a wildcard (`_.example.com.pem` serves `*.example.com`). Clients whose
+/// SNI name has no certificate of its own, or that send none, get
+/// TLS_CERT_FILE; without it their handshake fails.
+#[derive(Debug)]
+pub struct CertStore {
+    cert_file: Option<(PathBuf, PathBuf)>,  // TLS_CERT_FILE and TLS_KEY_FILE
+    cert_dir: Option<PathBuf>,
+    options: TlsOptions,
+    client_certs: bool,  // Ask clients for a certificate (TOKEN_CERT_BINDING)
+    certs: RwLock<Arc<Certs>>,
+}
+
+#[derive(Debug, Default)]
+struct Certs {
+    default: Option<Arc<CertifiedKey>>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,  // Lowercase hostname, or `*.` and the parent domain
+}
+
+impl CertStore {
+    /// Reads the default certificate and every certificate in `cert_dir`
+    pub fn load(cert_file: Option<(PathBuf, PathBuf)>, cert_dir: Option<PathBuf>, options: &TlsOptions) -> Result<Self, String> {
+        let store = Self { cert_file, cert_dir, options: options.clone(), client_certs: false, certs: RwLock::new(Arc::default()) };
+        store.reload()?;
+        Ok(store)
+    }
+
+    /// Sets whether connecting clients are asked for a certificate, which they may decline
+    pub fn with_client_certs(mut self, requested: bool) -> Self {
+        self.client_certs = requested;
+        self
+    }
+
+    /// Rereads every certificate; on error the certificates in use are kept
+    pub fn reload(&self) -> Result<(), String> {
+        let certs = self.read_certs()?;
+        if certs.default.is_none() && certs.by_name.is_empty() {
+            let dir = self.cert_dir.as_deref().unwrap_or(Path::new(""));
+            return Err(format!("TLS_CERT_DIR {} contains no certificates", dir.display()));
+        }
+        *self.certs.write().unwrap() = Arc::new(certs);
+        Ok(())
+    }
+
+    /// Hostnames with a certificate of their own, sorted
+    pub fn hostnames(&self) -> Vec<String> {
+        let mut hostnames: Vec<String> = self.certs.read().unwrap().by_name.keys().cloned().collect();
+        hostnames.sort();
+        hostnames
+    }
+
+    /// Builds a TLS acceptor that picks certificates from this store
+    pub fn acceptor(self: Arc<Self>) -> Result<TlsAcceptor, String> {
+        let config = self.options.server_config_with_resolver(self.clone(), self.client_certs)
+            .map_err(|e| format!("Invalid TLS settings: {}", e))?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    /// One line describing the certificates in use, for logs
+    pub fn summary(&self) -> String {
+        let certs = self.certs.read().unwrap().clone();
+        let default = if certs.default.is_some() { "default certificate" } else { "no default certificate" };
+        match self.hostnames() {
+            hostnames if hostnames.is_empty() => default.to_string(),
+            hostnames => format!("{}, {} hostnames: {}", default, hostnames.len(), hostnames.join(", ")),
+        }
+    }
+
+    fn read_certs(&self) -> Result<Certs, String> {
+        let mut certs = Certs::default();
+        if let Some((cert_path, key_path)) = &self.cert_file {
+            let chain = read_chain(cert_path, "TLS_CERT_FILE")?;
+            let key = read_key(key_path, "TLS_KEY_FILE")?;
+            let certified = self.options.certified_key(chain, key)
+                .map_err(|e| format!("Invalid TLS certificate or key: {}", e))?;
+            certs.default = Some(Arc::new(certified));
+        }
+
+        if let Some(dir) = &self.cert_dir {
+            for cert_path in self.dir_files()?.into_iter().filter(|path| path.extension().is_some_and(|ext| ext == "pem")) {
+                let Some(stem) = cert_path.file_stem().and_then(|stem| stem.to_str()) else {
+                    continue;
+                };
+                let hostname = match stem.strip_prefix("_.") {
+                    Some(parent) => format!("*.{}", parent),
+                    None => stem.to_string(),
+                };
+                let key_path = cert_path.with_extension("key");
+                let chain = read_chain(&cert_path, "TLS_CERT_DIR certificate")?;
+                let key = read_key(&key_path, "TLS_CERT_DIR key")?;
+                let certified = self.options.certified_key(chain, key)
+                    .map_err(|e| format!("Invalid TLS certificate or key in {}: {}", dir.display(), e))?;
+                certs.by_name.insert(hostname.to_ascii_lowercase(), Arc::new(certified));
+            }
+        }
+        Ok(certs)
+    }
+
+    /// Files in TLS_CERT_DIR, sorted
+    fn dir_files(&self) -> Result<Vec<PathBuf>, String> {
+        let Some(dir) = &self.cert_dir else {
+            return Ok(Vec::new());
+        };
+        let entries = fs::read_dir(dir).map_err(|e| format!("Failed to read TLS_CERT_DIR {}: {}", dir.display(), e))?;
+        let mut files = Vec::new();
+        for entry in entries {
+            let path = entry.map_err(|e| format!("Failed to read TLS_CERT_DIR {}: {}", dir.display(), e))?.path();
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Every certificate file and its modification time, to notice changes
+    fn modified_times(&self) -> Vec<(PathBuf, Option<SystemTime>)> {
+        let mut paths: Vec<PathBuf> = self.cert_file.iter().flat_map(|(cert, key)| [cert.clone(), key.clone()]).collect();
+        paths.extend(self.dir_files().unwrap_or_default());
+        paths
+            .into_iter()
+            .map(|path| {
+                let modified = fs::metadata(&path).and_then(|metadata| metadata.modified()).ok();
+                (path, modified)
+            })
+            .collect()
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.read().unwrap().clone();
+        let by_name = client_hello.server_name().map(str::to_ascii_lowercase).and_then(|name| {
+            certs.by_name.get(&name).or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                certs.by_name.get(&format!("*.{}", parent))
+            }).cloned()
+        });
+        by_name.or_else(|| certs.default.clone())
+    }
+}
+
+/// Reloads the store whenever its certificate files change, until the process exits
+///
+/// A change that fails to load (e.g. a certificate replaced before its key)
+/// is logged and the certificates in use are kept until the next change.
+pub async fn watch(store: Arc<CertStore>) {
+    let mut last = store.modified_times();
+    loop {
+        sleep(POLL_INTERVAL).await;
+        let modified = store.modified_times();
+        if modified == last {
+            continue;
+        }
+        last = modified;
+        match store.reload() {
+            Ok(()) => info!("Reloaded TLS certificates ({})", store.summary()),
+            Err(e) => error!("Keeping current TLS certificates: {}", e),
+        }
+    }
+}
+
+/// Reads a PEM certificate chain; `what` names it in errors
+fn read_chain(path: &Path, what: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let chain = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Failed to read {} {}: {}", what, path.display(), e))?;
+    if chain.is_empty() {
+        return Err(format!("{} {} contains no certificates", what, path.display()));
+    }
+    Ok(chain)
+}
+
+/// Reads a PEM private key; `what` names it in errors
+fn read_key(path: &Path, what: &str) -> Result<PrivateKeyDer<'static>, String> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| format!("Failed to read {} {}: {}", what, path.display(), e))
+}
+
+/// Serves `app` over TLS on `listener`, speaking HTTP/1.1 with upgrades
+///
+/// Each connection is handshaken on its own task so a slow client cannot
+/// stall the accept loop; accept errors are logged and retried. Once
+/// `shutdown` resolves, stops accepting and returns when every connection has
+/// finished its current request (upgraded tunnel connections are not waited for).
+pub async fn serve<F: Future<Output = ()>>(listener: TcpListener, acceptor: TlsAcceptor, app: Router, tcp_nodelay: bool, shutdown: F) {
+    http::accept(listener, tcp_nodelay, shutdown, move |stream, peer, closing| {
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        async move {
+            let stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    debug!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("TLS handshake with {} timed out", peer);
+                    return;
+                }
+            };
+
+            let client_cert = stream.get_ref().1.peer_certificates().and_then(|chain| chain.first()).map(CertPin::of);
+            let extend = move |extensions: &mut Extensions| {
+                extensions.insert(ServedOverTls);
+                if let Some(pin) = client_cert {
+                    extensions.insert(ClientCertificate(pin));
+                }
+            };
+            http::serve_connection(stream, peer, app, extend, closing).await;
+        }
+    })
+    .await;
+}
@@ -0,0 +1,190 @@
+//! Request rates, error rates and latency percentiles over sliding windows.
+//!
+//! Every request forwarded to a tunnel is counted once its response is ready,
+//! under its tunnel and globally, in 10-second slots kept for the longest
+//! window. A slot holds a latency histogram rather than the samples, so memory
+//! does not grow with traffic; percentiles come out as the upper bound of
+//! their histogram bin, within about 20% of the exact value. A tunnel is
+//! forgotten once it has had no requests for the longest window.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// Granularity of the windows
+const SLOT: Duration = Duration::from_secs(10);
+
+/// Slots kept, covering the longest window
+const SLOTS: u64 = 90;
+
+/// Latency histogram bins; bin `b` holds latencies below `2^((b + 1) / 4)` ms, the last one everything above
+const BINS: usize = 80;
+
+/// Largest latency, in milliseconds, counted in each bin
+static BIN_MAX_MS: LazyLock<[u64; BINS]> =
+    LazyLock::new(|| std::array::from_fn(|bin| (2f64.powf((bin + 1) as f64 / 4.0).ceil() as u64).saturating_sub(2)));
+
+fn bin(latency: Duration) -> usize {
+    let millis = latency.as_millis() as u64;
+    BIN_MAX_MS.partition_point(|max| *max < millis).min(BINS - 1)
+}
+
+#[derive(Clone)]
+struct Slot {
+    number: u64,  // Slots since the tracker started; a stale slot is reset before reuse
+    requests: u64,
+    errors: u64,
+    latencies: [u32; BINS],
+}
+
+/// Slots of one tunnel, or of all of them, used as a ring
+struct Series {
+    slots: Vec<Slot>,
+    last: u64,  // Number of the latest slot with a request
+}
+
+impl Series {
+    fn new() -> Self {
+        let empty = Slot { number: u64::MAX, requests: 0, errors: 0, latencies: [0; BINS] };
+        Self { slots: vec![empty; SLOTS as usize], last: 0 }
+    }
+
+    fn record(&mut self, number: u64, error: bool, latency: Duration) {
+        let slot = &mut self.slots[(number % SLOTS) as usize];
+        if slot.number != number {
+            *slot = Slot { number, requests: 0, errors: 0, latencies: [0; BINS] };
+        }
+        slot.requests += 1;
+        slot.errors += u64::from(error);
+        slot.latencies[bin(latency)] += 1;
+        self.last = number;
+    }
+
+    /// Sums the slots from `first` on
+    fn window(&self, first: u64, span: Duration) -> WindowStats {
+        let mut requests = 0;
+        let mut errors = 0;
+        let mut latencies = [0u64; BINS];
+        for slot in self.slots.iter().filter(|slot| slot.number != u64::MAX && slot.number >= first) {
+            requests += slot.requests;
+            errors += slot.errors;
+            for (total, count) in latencies.iter_mut().zip(slot.latencies) {
+                *total += u64::from(count);
+            }
+        }
+        let percentile = |p: u64| {
+            let rank = (requests * p).div_ceil(100).max(1);
+            let mut seen = 0;
+            latencies.iter().position(|count| {
+                seen += count;
+                seen >= rank
+            })
+            .map(|bin| BIN_MAX_MS[bin])
+            .filter(|_| requests > 0)
+        };
+        let round = |value: f64| (value * 1000.0).round() / 1000.0;
+        WindowStats {
+            requests,
+            requests_per_sec: round(requests as f64 / span.as_secs_f64().max(1.0)),
+            errors,
+            error_rate: if requests == 0 { 0.0 } else { round(errors as f64 / requests as f64) },
+            latency_p50_ms: percentile(50),
+            latency_p95_ms: percentile(95),
+            latency_p99_ms: percentile(99),
+        }
+    }
+}
+
+/// Totals over one window
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WindowStats {
+    pub requests: u64,
+    pub requests_per_sec: f64,
+    pub errors: u64,  // Answered with a 5xx, by the local service or the server
+    pub error_rate: f64,  // Share of requests that were errors
+    pub latency_p50_ms: Option<u64>,  // None: no requests in the window
+    pub latency_p95_ms: Option<u64>,
+    pub latency_p99_ms: Option<u64>,
+}
+
+/// Totals over the last minute, 5 minutes and 15 minutes
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Windows {
+    #[serde(rename = "1m")]
+    pub last_minute: WindowStats,
+    #[serde(rename = "5m")]
+    pub last_5_minutes: WindowStats,
+    #[serde(rename = "15m")]
+    pub last_15_minutes: WindowStats,
+}
+
+/// Windows of one tunnel
+#[derive(Debug, Clone, Serialize)]
+pub struct TunnelTraffic {
+    pub id: u64,
+    #[serde(flatten)]
+    pub windows: Windows,
+}
+
+/// Traffic of every tunnel, and of all of them together
+pub struct TrafficStats {
+    started: Instant,
+    series: Mutex<(Series, HashMap<u64, Series>)>,  // Global, then per tunnel id
+}
+
+impl Default for TrafficStats {
+    fn default() -> Self {
+        Self { started: Instant::now(), series: Mutex::new((Series::new(), HashMap::new())) }
+    }
+}
+
+impl TrafficStats {
+    fn slot_number(elapsed: Duration) -> u64 {
+        (elapsed.as_millis() / SLOT.as_millis()) as u64
+    }
+
+    /// Counts a request to `tunnel_id` answered with `status` after `latency`
+    pub fn record(&self, tunnel_id: u64, status: u16, latency: Duration) {
+        let number = Self::slot_number(self.started.elapsed());
+        let error = status >= 500;
+        let mut series = self.series.lock().unwrap();
+        let (global, tunnels) = &mut *series;
+        global.record(number, error, latency);
+        tunnels.entry(tunnel_id).or_insert_with(Series::new).record(number, error, latency);
+    }
+
+    /// Totals of all tunnels together
+    pub fn global(&self) -> Windows {
+        let series = self.series.lock().unwrap();
+        self.windows(&series.0)
+    }
+
+    /// Totals of each tunnel with requests in the longest window, by id
+    pub fn tunnels(&self) -> Vec<TunnelTraffic> {
+        let mut series = self.series.lock().unwrap();
+        let current = Self::slot_number(self.started.elapsed());
+        series.1.retain(|_, tunnel| tunnel.last + SLOTS > current);
+        let mut tunnels: Vec<TunnelTraffic> = series.1
+            .iter()
+            .map(|(id, tunnel)| TunnelTraffic { id: *id, windows: self.windows(tunnel) })
+            .collect();
+        tunnels.sort_by_key(|tunnel| tunnel.id);
+        tunnels
+    }
+
+    fn windows(&self, series: &Series) -> Windows {
+        let elapsed = self.started.elapsed();
+        let current = Self::slot_number(elapsed);
+        // Whole slots before the current one, plus the part of it that has passed
+        let window = |slots: u64| {
+            let first = (current + 1).saturating_sub(slots);
+            series.window(first, elapsed - SLOT * first as u32)
+        };
+        Windows {
+            last_minute: window(6),
+            last_5_minutes: window(30),
+            last_15_minutes: window(SLOTS),
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! Usage of each tunnel credential, for finding stale or leaked ones.
+//!
+//! Clients are counted under the username of the credentials they
+//! authenticated with (`anonymous` without TUNNEL_AUTH): when they last
+//! connected, from which addresses, how many tunnels they opened and how many
+//! bytes went through them. With TOKEN_USAGE_FILE the counts are saved as JSON
+//! every few seconds and survive restarts; without it they are kept in memory.
+//!
+//! With TOKEN_CERT_BINDING, each credential is also bound to the certificate
+//! of the first client that connects with it, and refused with any other.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+use tunnel_core::server::TunnelConnection;
+use tunnel_core::tls::CertPin;
+
+/// Name the usage of clients is counted under when TUNNEL_AUTH is unset
+pub const ANONYMOUS: &str = "anonymous";
+
+/// Distinct source addresses kept per credential, most recent first
+pub const MAX_SOURCE_IPS: usize = 16;
+
+/// How often changed counts are written to TOKEN_USAGE_FILE
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What is known about one credential
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub last_connected_at: Option<u64>, // Unix timestamp (seconds) of the last tunnel opened (None: never)
+    pub source_ips: Vec<IpAddr>,        // Addresses tunnels were opened from, most recent first
+    pub tunnels_opened: u64,
+    pub request_bytes: u64,             // Request body bytes sent to its tunnels
+    pub response_bytes: u64,            // Response bytes received from its tunnels
+    pub client_cert: Option<CertPin>,   // Certificate it is bound to (TOKEN_CERT_BINDING; None: not yet)
+}
+
+/// Contents of TOKEN_USAGE_FILE
+#[derive(Default, Serialize, Deserialize)]
+struct Saved {
+    tokens: BTreeMap<String, TokenUsage>,
+}
+
+/// Usage of every credential seen, saved to a file when one is set
+#[derive(Default)]
+pub struct UsageStore {
+    path: Option<PathBuf>,
+    tokens: Mutex<BTreeMap<String, TokenUsage>>,
+    changed: AtomicBool, // Counts changed since they were last saved
+}
+
+impl UsageStore {
+    /// Resumes from the counts saved in `path`; a missing file starts from none
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let saved = match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str::<Saved>(&contents)
+                .map_err(|e| format!("Invalid TOKEN_USAGE_FILE {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Saved::default(),
+            Err(e) => return Err(format!("Failed to read TOKEN_USAGE_FILE {}: {}", path.display(), e)),
+        };
+        Ok(Self { path: Some(path.to_path_buf()), tokens: Mutex::new(saved.tokens), changed: AtomicBool::new(false) })
+    }
+
+    /// Counts a tunnel opened with `token` from `source`
+    pub fn record_connection(&self, token: &str, source: Option<IpAddr>) {
+        let mut tokens = self.tokens.lock().unwrap();
+        let usage = tokens.entry(token.to_string()).or_default();
+        usage.last_connected_at = Some(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+        usage.tunnels_opened += 1;
+        if let Some(source) = source {
+            usage.source_ips.retain(|ip| *ip != source);
+            usage.source_ips.insert(0, source);
+            usage.source_ips.truncate(MAX_SOURCE_IPS);
+        }
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    /// Checks `presented` against the certificate `token` is bound to, binding it on first use
+    ///
+    /// Returns whether the credential was bound just now; a new binding is
+    /// saved right away, so a restart cannot let another certificate claim it.
+    pub fn bind_certificate(&self, token: &str, presented: Option<CertPin>) -> Result<bool, String> {
+        let Some(presented) = presented else {
+            return Err(format!("Credential {} requires a client certificate", token));
+        };
+        {
+            let mut tokens = self.tokens.lock().unwrap();
+            let usage = tokens.entry(token.to_string()).or_default();
+            match usage.client_cert {
+                Some(bound) if bound == presented => return Ok(false),
+                Some(_) => return Err(format!("Credential {} is bound to another client certificate", token)),
+                None => usage.client_cert = Some(presented),
+            }
+        }
+        self.changed.store(true, Ordering::Relaxed);
+        if let Err(e) = self.save() {
+            warn!("{}", e);
+        }
+        Ok(true)
+    }
+
+    /// Forgets the certificate `token` is bound to, so the next client to connect binds it again
+    ///
+    /// Returns the certificate it was bound to, if any.
+    pub fn unbind_certificate(&self, token: &str) -> Option<CertPin> {
+        let unbound = self.tokens.lock().unwrap().get_mut(token)?.client_cert.take()?;
+        self.changed.store(true, Ordering::Relaxed);
+        if let Err(e) = self.save() {
+            warn!("{}", e);
+        }
+        Some(unbound)
+    }
+
+    /// Adds the bytes of one request through a tunnel opened with `token`
+    pub fn record_bytes(&self, token: &str, request_bytes: u64, response_bytes: u64) {
+        if request_bytes == 0 && response_bytes == 0 {
+            return;
+        }
+        let mut tokens = self.tokens.lock().unwrap();
+        let usage = tokens.entry(token.to_string()).or_default();
+        usage.request_bytes += request_bytes;
+        usage.response_bytes += response_bytes;
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    /// Usage of every credential seen, by name
+    pub fn list(&self) -> BTreeMap<String, TokenUsage> {
+        self.tokens.lock().unwrap().clone()
+    }
+
+    /// Writes the counts to TOKEN_USAGE_FILE if they changed since the last save
+    ///
+    /// The file is replaced in one rename, so a crash never leaves it half written.
+    pub fn save(&self) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if !self.changed.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let saved = Saved { tokens: self.list() };
+        let contents = serde_json::to_vec_pretty(&saved).map_err(|e| e.to_string())?;
+        let temp = path.with_extension("tmp");
+        let result = std::fs::write(&temp, contents).and_then(|()| std::fs::rename(&temp, path));
+        result.map_err(|e| {
+            self.changed.store(true, Ordering::Relaxed);
+            format!("Failed to write TOKEN_USAGE_FILE {}: {}", path.display(), e)
+        })
+    }
+
+    /// Saves the counts every SAVE_INTERVAL, for as long as the server runs
+    pub async fn save_periodically(&self) {
+        let mut interval = tokio::time::interval(SAVE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.save() {
+                warn!("{}", e);
+            }
+        }
+    }
+}
+
+/// Name the usage of `conn` is counted under
+pub fn token_name(conn: &TunnelConnection) -> &str {
+    conn.token.as_deref().unwrap_or(ANONYMOUS)
+}
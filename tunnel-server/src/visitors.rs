@@ -0,0 +1,107 @@
+//! Requests in flight per visitor address (VISITOR_MAX_IN_FLIGHT).
+//!
+//! Separate from the tunnel's own cap (TUNNEL_MAX_IN_FLIGHT) and from any rate
+//! limit: a visitor polling in a tight loop is refused once it holds this many
+//! requests, which leaves the rest of the tunnel's capacity to everyone else.
+//! Visitors are told apart by the address of their connection, so behind a
+//! reverse proxy they all share the proxy's. A request counts until its
+//! response body ended, so a streamed download holds its slot throughout.
+
+use axum::body::{Body, Bytes};
+use hyper::body::{Frame, SizeHint};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// Requests in flight per visitor address, against an optional cap
+#[derive(Debug, Default)]
+pub struct VisitorLimit {
+    max: Option<usize>,  // None: visitors are not counted
+    in_flight: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl VisitorLimit {
+    pub fn new(max: Option<usize>) -> Self {
+        Self { max, in_flight: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn max(&self) -> Option<usize> {
+        self.max
+    }
+
+    /// Counts a request from `visitor` until the slot is dropped; None if it already holds `max`
+    pub fn acquire(self: &Arc<Self>, visitor: IpAddr) -> Option<VisitorSlot> {
+        let Some(max) = self.max else {
+            return Some(VisitorSlot(None));
+        };
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let count = in_flight.entry(visitor).or_insert(0);
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(VisitorSlot(Some((self.clone(), visitor))))
+    }
+
+    /// Requests `visitor` has in flight
+    pub fn in_flight(&self, visitor: IpAddr) -> usize {
+        self.in_flight.lock().unwrap().get(&visitor).copied().unwrap_or(0)
+    }
+
+    fn release(&self, visitor: IpAddr) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&visitor) {
+            *count -= 1;
+            // Addresses only seen once must not pile up
+            if *count == 0 {
+                in_flight.remove(&visitor);
+            }
+        }
+    }
+}
+
+/// One request of a visitor, counted until dropped
+pub struct VisitorSlot(Option<(Arc<VisitorLimit>, IpAddr)>);  // None: not counted
+
+impl Drop for VisitorSlot {
+    fn drop(&mut self) {
+        if let Some((limit, visitor)) = self.0.take() {
+            limit.release(visitor);
+        }
+    }
+}
+
+/// A response body holding its visitor's slot until it ends or is dropped
+pub struct SlotBody {
+    body: Body,
+    slot: Option<VisitorSlot>,
+}
+
+impl SlotBody {
+    pub fn new(body: Body, slot: VisitorSlot) -> Self {
+        Self { body, slot: Some(slot) }
+    }
+}
+
+impl hyper::body::Body for SlotBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let polled = Pin::new(&mut self.body).poll_frame(cx);
+        if matches!(polled, Poll::Ready(None | Some(Err(_)))) || self.body.is_end_stream() {
+            self.slot = None;
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
@@ -0,0 +1,92 @@
+//! Warm-up probe sent through a new tunnel before visitors are routed to it.
+//!
+//! With TUNNEL_WARMUP_PATH set, a freshly upgraded connection is kept out of
+//! the registry while the server sends `GET <path>` through it every
+//! TUNNEL_WARMUP_INTERVAL_MS. The first answer below 500 makes it the active
+//! connection; until then the client's local service is taken to be still
+//! starting (the client answers 502 while it cannot reach it), and visitors
+//! keep going to the previous client, or get the no-tunnel response.
+
+use axum::body::Bytes;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+use tracing::{info, warn};
+use tunnel_core::config::serialize_millis;
+use tunnel_core::error_dedup;
+use tunnel_core::server::{TunnelConnection, TunnelError};
+use tunnel_protocol::{decode_response_frame, TunnelRequest};
+
+/// Default for TUNNEL_WARMUP_INTERVAL_MS
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest wait for the answer to one probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// User-Agent of the probes, so the local service can tell them from visitors
+const PROBE_USER_AGENT: &str = "tunnel-server-warmup";
+
+/// Probe sent through every new tunnel
+#[derive(Debug, Clone, Serialize)]
+pub struct Warmup {
+    pub path: String,  // Request target probed, e.g. `/healthz`
+    #[serde(rename = "interval_ms", serialize_with = "serialize_millis")]
+    pub interval: Duration,  // Between failed probes
+}
+
+impl Warmup {
+    /// Settings read by `from_source`
+    pub const KEYS: [&'static str; 2] = ["TUNNEL_WARMUP_PATH", "TUNNEL_WARMUP_INTERVAL_MS"];
+
+    /// Reads the probe settings from a key lookup (None: no warm-up)
+    pub fn from_source(get: impl Fn(&str) -> Option<String>) -> Result<Option<Self>, String> {
+        let interval = match get("TUNNEL_WARMUP_INTERVAL_MS") {
+            Some(value) => {
+                let millis: u64 = value.trim().parse().ok().filter(|millis| *millis > 0)
+                    .ok_or_else(|| format!("Invalid TUNNEL_WARMUP_INTERVAL_MS: {} (expected milliseconds above 0)", value))?;
+                Duration::from_millis(millis)
+            }
+            None => DEFAULT_INTERVAL,
+        };
+        let Some(path) = get("TUNNEL_WARMUP_PATH") else {
+            return Ok(None);
+        };
+        let path = path.trim().to_string();
+        if !path.starts_with('/') || path.contains(char::is_whitespace) {
+            return Err(format!("Invalid TUNNEL_WARMUP_PATH: {} (expected a path starting with '/')", path));
+        }
+        Ok(Some(Self { path, interval }))
+    }
+
+    /// Probes `conn` until its local service answers; false if the connection goes away first
+    pub async fn probe(&self, conn: &TunnelConnection) -> bool {
+        let request = TunnelRequest {
+            method: "GET".to_string(),
+            path: self.path.clone(),
+            headers: vec![("user-agent".to_string(), PROBE_USER_AGENT.into())],
+            deadline_ms: Some(PROBE_TIMEOUT.as_millis() as u64),
+            ..TunnelRequest::default()
+        };
+        let payload = Bytes::from(serde_json::to_vec(&request).expect("probe request serializes"));
+
+        loop {
+            match timeout(PROBE_TIMEOUT, conn.round_trip(payload.clone())).await {
+                Ok(Ok(response)) => match decode_response_frame(&response) {
+                    Ok((response, _)) if response.status < 500 => {
+                        info!("Warm-up probe {} answered {}; routing visitors to the tunnel", self.path, response.status);
+                        return true;
+                    }
+                    Ok((response, _)) => error_dedup!("Warm-up probe {} answered {}; local service not ready", self.path, response.status),
+                    Err(e) => warn!("Invalid response to warm-up probe {}: {}", self.path, e),
+                },
+                Ok(Err(TunnelError::Closed | TunnelError::WorkerGone | TunnelError::Draining)) => return false,
+                Ok(Err(e)) => error_dedup!("Warm-up probe {} failed: {}", self.path, e),
+                Err(_) => error_dedup!("Warm-up probe {} timed out after {:?}", self.path, PROBE_TIMEOUT),
+            }
+            if conn.is_closed() {
+                return false;
+            }
+            sleep(self.interval).await;
+        }
+    }
+}
@@ -0,0 +1,42 @@
+//! End-to-end throughput through a loopback tunnel:
+//! HTTP client -> tunnel server -> tunnel -> tunnel client -> mock local service and back.
+//!
+//! Run with `cargo bench -p tunnel-tests`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+/// Request body sizes; the mock echoes each body back, so every iteration moves twice this
+const BODY_SIZES: [usize; 3] = [1024, 64 * 1024, 1024 * 1024];
+
+fn loopback(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let (_local, server, _client) = runtime.block_on(async {
+        let local = MockLocal::start().await;
+        let server = TestServer::start(None).await;
+        let client = TestClient::start(server.addr, local.port, None);
+        server.wait_for_new_tunnel(None).await;
+        (local, server, client)
+    });
+    let http = reqwest::Client::new();
+    let url = server.url("/echo");
+
+    let mut group = c.benchmark_group("loopback");
+    for size in BODY_SIZES {
+        let body: Vec<u8> = (0..size).map(|i| (i * 31 % 251) as u8).collect();
+        group.throughput(Throughput::Bytes(2 * size as u64));
+
+        group.bench_with_input(BenchmarkId::new("echo", size), &body, |b, body| {
+            b.to_async(&runtime).iter(|| async {
+                let response = http.post(&url).body(body.clone()).send().await.unwrap();
+                assert_eq!(response.status(), 200);
+                response.bytes().await.unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, loopback);
+criterion_main!(benches);
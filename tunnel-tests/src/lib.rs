@@ -0,0 +1,374 @@
+//! End-to-end test harness: runs a tunnel server, a tunnel client and a mock
+//! local HTTP service in-process, on ephemeral ports, so tests exercise the
+//! real tunnel path (public request -> server -> tunnel -> client -> local).
+//!
+//! Every handle aborts its tasks when dropped.
+
+use axum::body::{Body, Bytes};
+use axum::extract::DefaultBodyLimit;
+use axum::http::{header, HeaderMap, Method, Uri};
+use axum::response::Response;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use rustls::RootCertStore;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+use tunnel_client::capture::{CaptureLog, CaptureOptions};
+use tunnel_client::local::{LocalConfig, LocalService, LocalTarget};
+use tunnel_client::settings::DEFAULT_MAX_CONCURRENT;
+use tunnel_client::status::StatusHandle;
+use tunnel_core::client::{connect_and_upgrade, parse_server_addr, ConnectError, ServerConfig};
+use tunnel_core::redact::Redactor;
+use tunnel_core::tls::TlsOptions;
+use tunnel_core::transport::TransportOptions;
+use tunnel_protocol::{decode_tunnel_request, read_frame, write_frame, TunnelRequest, TunnelResponse};
+use tunnel_server::ServerState;
+
+/// Path of `name` in tests/fixtures
+///
+/// It holds a P-256 test CA (`ca.pem`) and a leaf certificate for
+/// `localhost`/`127.0.0.1` signed by it (`server.pem`, `server.key`), generated
+/// with openssl and valid for ten years, along with the client certificates and
+/// SNI certificates described in the tests using them.
+pub fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+/// Acceptor presenting the `localhost` test certificate
+pub fn fixture_acceptor(options: &TlsOptions) -> TlsAcceptor {
+    tunnel_server::tls::load_acceptor(&fixture("server.pem"), &fixture("server.key"), options).unwrap()
+}
+
+/// Trust roots holding every certificate of the fixtures `cas`, e.g. `ca.pem`
+pub fn fixture_roots(cas: &[&str]) -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    for ca in cas {
+        for cert in CertificateDer::pem_file_iter(fixture(ca)).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+    }
+    roots
+}
+
+/// How long helpers wait for a tunnel to come up before failing the test
+const CONNECT_DEADLINE: Duration = Duration::from_secs(10);
+
+/// In-process tunnel server listening on 127.0.0.1
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub state: ServerState,
+    scheme: &'static str,
+    task: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Starts a server on an ephemeral port
+    pub async fn start(tunnel_auth: Option<&str>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        Self::start_on(listener, tunnel_auth)
+    }
+
+    /// Starts a server on an already bound listener
+    pub fn start_on(listener: TcpListener, tunnel_auth: Option<&str>) -> Self {
+        let state = ServerState::new(tunnel_auth.map(str::to_string), &TransportOptions::default());
+        Self::serve(listener, state)
+    }
+
+    /// Starts a server with non-default state on an ephemeral port
+    pub async fn start_with(state: ServerState) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        Self::serve(listener, state)
+    }
+
+    fn serve(listener: TcpListener, state: ServerState) -> Self {
+        let addr = listener.local_addr().unwrap();
+        let app = tunnel_server::router(state.clone());
+        let task = tokio::spawn(tunnel_server::http::serve(listener, app, true, std::future::pending()));
+        Self { addr, state, scheme: "http", task }
+    }
+
+    /// Starts a server on an ephemeral port terminating TLS with `acceptor`
+    pub async fn start_tls(acceptor: TlsAcceptor) -> Self {
+        Self::start_tls_with(acceptor, ServerState::new(None, &TransportOptions::default())).await
+    }
+
+    /// Starts a server with non-default state on an ephemeral port terminating TLS with `acceptor`
+    pub async fn start_tls_with(acceptor: TlsAcceptor, state: ServerState) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = tunnel_server::router(state.clone());
+        let task = tokio::spawn(tunnel_server::tls::serve(listener, acceptor, app, true, std::future::pending()));
+        Self { addr, state, scheme: "https", task }
+    }
+
+    /// Public URL for `path` on this server
+    pub fn url(&self, path: &str) -> String {
+        format!("{}://{}{}", self.scheme, self.addr, path)
+    }
+
+    /// ID of the currently active tunnel, if any
+    pub async fn tunnel_id(&self) -> Option<u64> {
+        self.state.registry.active().await.map(|conn| conn.id)
+    }
+
+    /// Waits until a tunnel other than `previous` is active and returns its ID
+    pub async fn wait_for_new_tunnel(&self, previous: Option<u64>) -> u64 {
+        tokio::time::timeout(CONNECT_DEADLINE, async {
+            loop {
+                match self.tunnel_id().await {
+                    Some(id) if Some(id) != previous => return id,
+                    _ => tokio::time::sleep(Duration::from_millis(20)).await,
+                }
+            }
+        })
+        .await
+        .expect("tunnel client did not connect in time")
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Connects a stand-in client that answers every request with `response`, passing on the requests it got
+///
+/// It stops once the server closes the tunnel.
+pub async fn start_fake_client(server: &TestServer, response: TunnelResponse) -> mpsc::UnboundedReceiver<TunnelRequest> {
+    let config = parse_server_addr(&format!("http://{}", server.addr), None, Vec::new()).unwrap();
+    let (mut stream, _) = connect_and_upgrade(&config).await.unwrap();
+    let payload = serde_json::to_vec(&response).unwrap();
+    let (requests_tx, requests) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok(frame) = read_frame(&mut stream).await {
+            let _ = requests_tx.send(decode_tunnel_request(&frame).unwrap());
+            if write_frame(&mut stream, &payload).await.is_err() {
+                return;
+            }
+        }
+    });
+    server.wait_for_new_tunnel(None).await;
+    requests
+}
+
+/// Admin API of an in-process server, listening on 127.0.0.1
+pub struct TestAdmin {
+    pub addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl TestAdmin {
+    /// Serves the admin API for `state` on an ephemeral port
+    pub async fn start(state: ServerState) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            axum::serve(listener, tunnel_server::admin::router(state)).await.unwrap();
+        });
+        Self { addr, task }
+    }
+
+    /// URL for `path` on the admin API
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+}
+
+impl Drop for TestAdmin {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// In-process tunnel client forwarding to a local port
+pub struct TestClient {
+    pub status: StatusHandle,
+    pub captures: CaptureLog,
+    pub local: Arc<LocalTarget>,
+    task: JoinHandle<Result<(), ConnectError>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl TestClient {
+    /// Starts a client connecting to `server_addr` and forwarding to 127.0.0.1:`local_port`
+    pub fn start(server_addr: SocketAddr, local_port: u16, tunnel_auth: Option<&str>) -> Self {
+        Self::start_with(server_addr, local_port, tunnel_auth, &[])
+    }
+
+    /// Like `start`, with extra local service or capture settings such as `("LOCAL_TIMEOUT_SECS", "5")`
+    ///
+    /// Offers to multiplex requests, binary frames, streamed bodies and heartbeats, as the client binary does by default.
+    pub fn start_with(
+        server_addr: SocketAddr,
+        local_port: u16,
+        tunnel_auth: Option<&str>,
+        local_settings: &[(&str, &str)],
+    ) -> Self {
+        let mut server_config = parse_server_addr(
+            &format!("http://{}", server_addr),
+            tunnel_auth.map(str::to_string),
+            Vec::new(),
+        )
+        .unwrap();
+        server_config.max_concurrent = DEFAULT_MAX_CONCURRENT;
+        server_config.binary_frames = true;
+        server_config.stream_bodies = true;
+        server_config.heartbeat = true;
+        Self::start_with_config(server_config, local_port, local_settings)
+    }
+
+    /// Like `start_with`, connecting with `server_config` (e.g. with `visitor_auth` set)
+    pub fn start_with_config(server_config: ServerConfig, local_port: u16, local_settings: &[(&str, &str)]) -> Self {
+        let local_port = local_port.to_string();
+        let get = |key: &str| match key {
+            "LOCAL_PORT" => Some(local_port.clone()),
+            _ => local_settings.iter().find(|(k, _)| *k == key).map(|(_, v)| v.to_string()),
+        };
+        let local_config = LocalConfig::from_source(get).unwrap();
+        let local_service = LocalService::new(&local_config).unwrap();
+        let (local, local_rx) = LocalTarget::new(local_config, local_service);
+
+        let status = StatusHandle::new(&server_config);
+        let redactor = get("REDACT_RULES_FILE").map_or_else(Redactor::default, |path| Redactor::load(path.as_ref()).unwrap());
+        let captures = CaptureLog::new(CaptureOptions::from_source(get).unwrap(), Arc::new(redactor));
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let task = tokio::spawn(tunnel_client::run_with_status(server_config, local_rx, status.clone(), captures.clone(), async {
+            let _ = shutdown_rx.await;
+        }));
+        Self { status, captures, local, task, shutdown: Some(shutdown) }
+    }
+
+    /// Waits for the client to give up, returning its permanent failure
+    pub async fn wait_for_exit(&mut self) -> ConnectError {
+        (&mut self.task).await.unwrap().unwrap_err()
+    }
+
+    /// Shuts the client down as on SIGTERM and waits until it has drained the tunnel
+    pub async fn shut_down(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        (&mut self.task).await.unwrap().unwrap();
+    }
+}
+
+impl Drop for TestClient {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Mock local HTTP service that echoes every request back
+///
+/// The response body is the request body, the response content type is the
+/// request content type, and `x-echo-method` / `x-echo-path` / `x-echo-host` / `x-echo-authorization` report what arrived.
+/// Every `x-echo-value` request header is sent back as is, every `x-echo-tag` one as `x-tunnel-tag`, and every
+/// `x-tunnel-*` one as `x-echo-tunnel-*`.
+/// An `x-delay-ms` request header delays the response by that many milliseconds, and an `x-echo-status` one sets its status.
+pub struct MockLocal {
+    pub port: u16,
+    task: JoinHandle<()>,
+}
+
+impl MockLocal {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let app = axum::Router::new()
+            .fallback(echo)
+            .layer(DefaultBodyLimit::disable());
+        let task = tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+        Self { port, task }
+    }
+}
+
+impl Drop for MockLocal {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn echo(method: Method, uri: Uri, headers: HeaderMap, body: Bytes) -> Response {
+    if let Some(delay) = headers.get("x-delay-ms").and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok()) {
+        tokio::time::sleep(Duration::from_millis(delay)).await;
+    }
+    let status = headers.get("x-echo-status").and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok()).unwrap_or(200);
+    let mut response = Response::builder()
+        .status(status)
+        .header("x-echo-method", method.as_str())
+        .header("x-echo-path", uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/"));
+    if let Some(host) = headers.get(header::HOST) {
+        response = response.header("x-echo-host", host);
+    }
+    if let Some(authorization) = headers.get(header::AUTHORIZATION) {
+        response = response.header("x-echo-authorization", authorization);
+    }
+    if let Some(content_type) = headers.get(header::CONTENT_TYPE) {
+        response = response.header(header::CONTENT_TYPE, content_type);
+    }
+    for value in headers.get_all("x-echo-value") {
+        response = response.header("x-echo-value", value);
+    }
+    for value in headers.get_all("x-echo-tag") {
+        response = response.header("x-tunnel-tag", value);
+    }
+    for (name, value) in &headers {
+        if name.as_str().starts_with("x-tunnel-") {
+            response = response.header(format!("x-echo-{}", &name.as_str()[2..]), value);
+        }
+    }
+    response.body(Body::from(body)).unwrap()
+}
+
+/// TCP proxy between client and server whose connections can be cut, to force reconnects
+pub struct TcpProxy {
+    pub addr: SocketAddr,
+    connections: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    task: JoinHandle<()>,
+}
+
+impl TcpProxy {
+    /// Starts a proxy forwarding every accepted connection to `target`
+    pub async fn start(target: SocketAddr) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections: Arc<Mutex<Vec<JoinHandle<()>>>> = Arc::default();
+
+        let tracked = connections.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((mut inbound, _)) = listener.accept().await {
+                let relay = tokio::spawn(async move {
+                    if let Ok(mut outbound) = TcpStream::connect(target).await {
+                        let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                    }
+                });
+                tracked.lock().unwrap().push(relay);
+            }
+        });
+
+        Self { addr, connections, task }
+    }
+
+    /// Closes every connection relayed so far
+    pub fn cut_connections(&self) {
+        for relay in self.connections.lock().unwrap().drain(..) {
+            relay.abort();
+        }
+    }
+}
+
+impl Drop for TcpProxy {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.cut_connections();
+    }
+}
@@ -0,0 +1,71 @@
+//! Admin API keys: read keys may only look, manage keys may also change settings.
+
+use reqwest::StatusCode;
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::api_keys::{ApiKeys, Scope};
+use tunnel_server::ServerState;
+use tunnel_tests::TestAdmin;
+
+const KEYS: &str = "\
+# dashboards
+read   read-key-0123456789   grafana
+manage manage-key-0123456789
+";
+
+#[test]
+fn keys_file_is_parsed() {
+    let keys = ApiKeys::parse(KEYS).unwrap();
+    assert_eq!(keys.len(), 2);
+
+    let read = keys.find("read-key-0123456789").unwrap();
+    assert_eq!((read.name.as_str(), read.scope), ("grafana", Scope::Read));
+    let manage = keys.find("manage-key-0123456789").unwrap();
+    assert_eq!((manage.name.as_str(), manage.scope), ("line 3", Scope::Manage));
+    assert!(keys.find("read-key-012345678").is_none());
+    assert!(keys.find("").is_none());
+
+    for (contents, error) in [
+        ("admin key-0123456789abcdef", "line 1: unknown scope 'admin'"),
+        ("read", "line 1: expected"),
+        ("\nread short", "line 2: keys must be at least 16 characters"),
+        ("read key-0123456789abcdef\nmanage key-0123456789abcdef", "line 2: duplicate key"),
+    ] {
+        let e = ApiKeys::parse(contents).err().unwrap();
+        assert!(e.starts_with(error), "{}: {}", contents, e);
+    }
+    assert!(ApiKeys::parse("# nothing yet\n\n").unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn admin_api_requires_a_key_with_the_right_scope() {
+    let state = ServerState::new(None, &TransportOptions::default()).with_api_keys(ApiKeys::parse(KEYS).unwrap());
+    let admin = TestAdmin::start(state).await;
+    let http = reqwest::Client::new();
+    let tunnels = admin.url("/api/tunnels");
+    let log_level = admin.url("/api/log-level");
+
+    let response = http.get(&tunnels).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers()["www-authenticate"], "Bearer realm=\"admin\"");
+    let response = http.get(&tunnels).bearer_auth("wrong-key-0123456789").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    for key in ["read-key-0123456789", "manage-key-0123456789"] {
+        let response = http.get(&tunnels).bearer_auth(key).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{}", key);
+    }
+
+    // Past authorization, the server has no log handle to change
+    let response = http.delete(&log_level).bearer_auth("read-key-0123456789").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = http.delete(&log_level).bearer_auth("manage-key-0123456789").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn admin_api_without_keys_is_open() {
+    let admin = TestAdmin::start(ServerState::new(None, &TransportOptions::default())).await;
+
+    let response = reqwest::get(admin.url("/api/tunnels")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
@@ -0,0 +1,124 @@
+//! Routes whose requests an external service authorizes (AUTH_HOOK_RULES_FILE).
+
+use axum::body::Body;
+use axum::http::{Response, StatusCode};
+use axum::routing::post;
+use axum::Json;
+use serde_json::Value;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::auth_hooks::AuthHooks;
+use tunnel_server::ServerState;
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+/// Hook letting through requests with `Authorization: Bearer good`, counting its calls and keeping the last description
+async fn start_hook() -> (String, Arc<AtomicUsize>, Arc<Mutex<Value>>) {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let last = Arc::new(Mutex::new(Value::Null));
+    let app = axum::Router::new()
+        .route("/check", post({
+            let (calls, last) = (calls.clone(), last.clone());
+            move |Json(description): Json<Value>| async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                let allowed = description["headers"]["authorization"] == "Bearer good";
+                *last.lock().unwrap() = description;
+                if allowed {
+                    return Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap();
+                }
+                Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header("www-authenticate", "Bearer realm=\"hook\"")
+                    .body(Body::from("bad token"))
+                    .unwrap()
+            }
+        }))
+        .route("/broken", post(|| async { StatusCode::INTERNAL_SERVER_ERROR }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (url, calls, last)
+}
+
+#[test]
+fn malformed_rules_are_rejected() {
+    for (contents, error) in [
+        ("admin http://127.0.0.1/check", "line 1: path prefix must start with '/'"),
+        ("/admin", "line 1: expected '<path-prefix> <hook-url>'"),
+        ("\n/admin ftp://127.0.0.1/check", "line 2: invalid hook URL 'ftp://127.0.0.1/check' (expected http:// or https://)"),
+        ("/admin check", "line 1: invalid hook URL 'check' (expected http:// or https://)"),
+    ] {
+        assert_eq!(AuthHooks::parse(contents).unwrap_err(), error, "{}", contents);
+    }
+    assert_eq!(AuthHooks::parse("# hooks\n/admin http://127.0.0.1/check\n").unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn hook_decides_which_requests_are_forwarded() {
+    let local = MockLocal::start().await;
+    let (hook, calls, last) = start_hook().await;
+    let hooks = AuthHooks::parse(&format!("/admin {}/check\n/status {}/broken\n", hook, hook)).unwrap();
+    let state = ServerState::new(None, &TransportOptions::default()).with_auth_hooks(hooks);
+    let server = TestServer::start_with(state).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+    let http = reqwest::Client::new();
+
+    // Routes without a hook are forwarded as usual; prefixes cover whole segments
+    for path in ["/", "/administrator"] {
+        let response = http.get(server.url(path)).send().await.unwrap();
+        assert_eq!(response.status(), 200, "{}", path);
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+    let response = http.get(server.url("/%61dmin/users?page=2")).send().await.unwrap();
+    assert_eq!(response.status(), 401);
+    assert_eq!(response.headers()["x-tunnel-error"], "auth-hook-denied");
+    assert_eq!(response.headers()["www-authenticate"], "Bearer realm=\"hook\"");
+    assert!(response.headers().get("x-echo-path").is_none());
+    assert_eq!(response.text().await.unwrap(), "bad token");
+
+    let response = http.post(server.url("/admin/users?page=2")).bearer_auth("good").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-echo-path"], "/admin/users?page=2");
+    let description = last.lock().unwrap().clone();
+    assert_eq!(description["method"], "POST");
+    assert_eq!(description["path"], "/admin/users?page=2");
+    assert_eq!(description["visitor_addr"], "127.0.0.1");
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    // The same request again is answered from the cache
+    let response = http.post(server.url("/admin/users?page=2")).bearer_auth("good").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    // A failing hook refuses the request rather than letting it through
+    let response = http.get(server.url("/status")).send().await.unwrap();
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.headers()["x-tunnel-error"], "auth-hook-unavailable");
+}
+
+#[tokio::test]
+async fn answers_expire_from_the_cache() {
+    let local = MockLocal::start().await;
+    let (hook, calls, _) = start_hook().await;
+    let hooks = AuthHooks::parse(&format!("/ {}/check", hook)).unwrap().with_cache_ttl(Duration::from_millis(200));
+    let state = ServerState::new(None, &TransportOptions::default()).with_auth_hooks(hooks);
+    let server = TestServer::start_with(state).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+    let http = reqwest::Client::new();
+
+    for _ in 0..3 {
+        let response = http.get(server.url("/")).bearer_auth("good").send().await.unwrap();
+        assert_eq!(response.status(), 200);
+    }
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let response = http.get(server.url("/")).bearer_auth("good").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,50 @@
+//! Bodies travel in binary frames when the client offers them (TUNNEL_BINARY_FRAMES),
+//! and as base64 in JSON frames otherwise.
+
+use tunnel_core::client::parse_server_addr;
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::ServerState;
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+/// Every byte value, so any text decoding or escaping along the way shows
+fn all_bytes(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 256) as u8).collect()
+}
+
+async fn assert_echoed(server: &TestServer, body: Vec<u8>) {
+    let response = reqwest::Client::new().post(server.url("/upload")).body(body.clone()).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.bytes().await.unwrap().as_ref(), body.as_slice(), "{} bytes", body.len());
+}
+
+#[tokio::test]
+async fn clients_offering_binary_frames_get_them() {
+    let local = MockLocal::start().await;
+    let state = ServerState::new(None, &TransportOptions::default()).with_body_checksum(true);
+    let server = TestServer::start_with(state.clone()).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+    assert!(state.registry.active().await.unwrap().binary_frames);
+
+    for len in [0, 1, 300, 100_000] {
+        assert_echoed(&server, all_bytes(len)).await;
+    }
+}
+
+#[tokio::test]
+async fn other_clients_keep_json_frames() {
+    let dir = tempfile::tempdir().unwrap();
+    let local = MockLocal::start().await;
+    let state = ServerState::new(None, &TransportOptions::default()).with_body_checksum(true);
+    let server = TestServer::start_with(state.clone()).await;
+    let config = parse_server_addr(&format!("http://{}", server.addr), None, Vec::new()).unwrap();
+    let settings = [("LOCAL_SPOOL_THRESHOLD_BYTES", "1000"), ("LOCAL_SPOOL_DIR", dir.path().to_str().unwrap())];
+    let _client = TestClient::start_with_config(config, local.port, &settings);
+    server.wait_for_new_tunnel(None).await;
+    assert!(!state.registry.active().await.unwrap().binary_frames);
+
+    // Held in memory, then spooled to a file
+    for len in [300, 100_000] {
+        assert_echoed(&server, all_bytes(len)).await;
+    }
+}
@@ -0,0 +1,141 @@
+//! Request bodies reach the local service exactly as sent, which HMAC-signed
+//! webhooks depend on, and can carry a checksum the client verifies; the
+//! client's responses then carry one the server verifies.
+
+use tokio::io::{AsyncWriteExt, BufReader};
+use tunnel_core::client::{connect_and_upgrade, parse_server_addr};
+use tunnel_core::transport::TransportOptions;
+use tunnel_protocol::{body_sha256, decode_response_frame, encode_body, read_frame, TunnelRequest, TunnelResponse, BODY_SHA256_HEADER};
+use tunnel_server::{admin, ServerState};
+use tunnel_tests::{MockLocal, TestAdmin, TestClient, TestServer};
+
+/// A JSON body no serializer would produce, with a BOM, CRLF line ends, Latin-1 and a trailing NUL
+const AWKWARD_BODY: &[u8] = b"\xef\xbb\xbf{ \"amount\" :1.50,\r\n  \"name\":\"Jos\xe9\",\"name\":\"dup\" }\r\n\n\0";
+
+#[tokio::test]
+async fn request_bodies_are_forwarded_byte_for_byte() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let response = reqwest::Client::new()
+        .post(server.url("/webhook"))
+        .header("content-type", "application/json; charset=ISO-8859-1")
+        .body(AWKWARD_BODY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "application/json; charset=ISO-8859-1");
+    assert_eq!(response.bytes().await.unwrap().as_ref(), AWKWARD_BODY);
+}
+
+#[tokio::test]
+async fn body_checksum_reaches_the_local_service() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start_with(ServerState::new(None, &TransportOptions::default()).with_body_checksum(true)).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    // A checksum sent by the visitor is replaced by the server's own
+    let response = reqwest::Client::new()
+        .post(server.url("/webhook"))
+        .header(BODY_SHA256_HEADER, "0000")
+        .body(AWKWARD_BODY)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let checksums: Vec<_> = response.headers().get_all("x-echo-tunnel-body-sha256").iter().collect();
+    assert_eq!(checksums, [body_sha256(AWKWARD_BODY).as_str()]);
+    assert_eq!(
+        body_sha256(b"abc"),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+}
+
+#[tokio::test]
+async fn visitor_checksums_are_dropped_when_the_option_is_off() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let response = reqwest::Client::new()
+        .post(server.url("/webhook"))
+        .header(BODY_SHA256_HEADER, "0000")
+        .body("hello")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().get("x-echo-tunnel-body-sha256").is_none());
+}
+
+#[tokio::test]
+async fn client_refuses_a_body_that_does_not_match_its_checksum() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+    let tunnel = server.state.registry.active().await.unwrap();
+
+    for (body, status) in [(&b"hello"[..], 200), (&b"hellO"[..], 502)] {
+        let request = TunnelRequest {
+            method: "POST".to_string(),
+            path: "/".to_string(),
+            headers: vec![(BODY_SHA256_HEADER.to_string(), body_sha256(b"hello").into())],
+            body: encode_body(body),
+            ..TunnelRequest::default()
+        };
+        let response = tunnel.round_trip(serde_json::to_vec(&request).unwrap().into()).await.unwrap();
+        let (response, _) = decode_response_frame(&response).unwrap();
+        assert_eq!(response.status, status);
+        // The echoed body comes back with its own checksum; the client's error response has none
+        let expected = (status == 200).then(|| body_sha256(b"hello"));
+        assert_eq!(response.body_sha256, expected);
+    }
+    assert!(tunnel_client::stats::LocalStats::new().report().checksum_mismatches >= 1);
+}
+
+#[tokio::test]
+async fn server_refuses_a_response_that_does_not_match_its_checksum() {
+    let state = ServerState::new(None, &TransportOptions::default()).with_body_checksum(true);
+    let server = TestServer::start_with(state.clone()).await;
+    let admin = TestAdmin::start(state).await;
+
+    // A fake client whose first response body was damaged on the way
+    let config = parse_server_addr(&format!("http://{}", server.addr), None, Vec::new()).unwrap();
+    let (stream, _) = connect_and_upgrade(&config).await.unwrap();
+    server.wait_for_new_tunnel(None).await;
+    tokio::spawn(async move {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+        for sent in [&b"hellO"[..], b"hello"] {
+            read_frame(&mut reader).await.unwrap();
+            let response = TunnelResponse {
+                status: 200,
+                body_sha256: Some(body_sha256(b"hello")),
+                body: encode_body(sent),
+                ..TunnelResponse::default()
+            };
+            let frame = serde_json::to_vec(&response).unwrap();
+            writer.write_all(&(frame.len() as u32).to_be_bytes()).await.unwrap();
+            writer.write_all(&frame).await.unwrap();
+        }
+        std::future::pending::<()>().await;
+    });
+
+    let response = reqwest::get(server.url("/")).await.unwrap();
+    assert_eq!(response.status(), 502);
+    assert_eq!(response.text().await.unwrap(), "Response body does not match its checksum");
+
+    // The tunnel itself is still fine
+    let response = reqwest::get(server.url("/")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await.unwrap(), "hello");
+
+    let status = admin::query_status(&admin.addr.to_string(), None).await.unwrap();
+    assert_eq!(status["requests"]["checksum_mismatches"], 1);
+}
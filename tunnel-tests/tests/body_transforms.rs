@@ -0,0 +1,81 @@
+//! Request bodies converted between formats before they reach the local
+//! service (LOCAL_TRANSFORM_RULES_FILE).
+
+use serde_json::{json, Value};
+use tunnel_client::transform::{Format, Transforms};
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+const RULES: &str = "\
+# Provider posts forms, the handler reads JSON
+/hooks/forms form json
+/legacy     json form
+";
+
+#[test]
+fn rules_are_parsed() {
+    let transforms = Transforms::parse(RULES).unwrap();
+    assert_eq!(transforms.len(), 2);
+
+    let rule = transforms.find("/hooks/forms/paid?attempt=2", Some(b"application/x-www-form-urlencoded; charset=utf-8")).unwrap();
+    assert_eq!((rule.from, rule.to), (Format::Form, Format::Json));
+    // Only bodies in the format the rule converts from
+    assert!(transforms.find("/hooks/forms/paid", Some(b"application/json")).is_none());
+    assert!(transforms.find("/hooks/forms/paid", None).is_none());
+    assert!(transforms.find("/other", Some(b"application/x-www-form-urlencoded")).is_none());
+    assert!(transforms.find("/legacy/orders", Some(b"application/vnd.api+json")).is_some());
+}
+
+#[test]
+fn invalid_rules_are_rejected() {
+    for (contents, error) in [
+        ("/hooks form", "line 1: expected '<path-prefix> <from> <to>'"),
+        ("hooks form json", "line 1: path prefix must start with '/'"),
+        ("\n/hooks xml json", "line 2: unknown format 'xml' (expected form or json)"),
+        ("/hooks json json", "line 1: converts json to itself"),
+    ] {
+        assert_eq!(Transforms::parse(contents).unwrap_err(), error);
+    }
+}
+
+#[test]
+fn bodies_are_converted_both_ways() {
+    let transforms = Transforms::parse(RULES).unwrap();
+    let to_json = transforms.find("/hooks/forms", Some(b"application/x-www-form-urlencoded")).unwrap();
+    let converted = to_json.apply(b"event=paid&amount=12.50&tag=a&tag=b+c&note=").unwrap();
+    let converted: Value = serde_json::from_slice(&converted).unwrap();
+    assert_eq!(converted, json!({"event": "paid", "amount": "12.50", "tag": ["a", "b c"], "note": ""}));
+
+    let to_form = transforms.find("/legacy", Some(b"application/json")).unwrap();
+    let converted = to_form.apply(br#"{"id":7,"paid":true,"tags":["a","b c"],"note":null}"#).unwrap();
+    assert_eq!(String::from_utf8(converted).unwrap(), "id=7&note=&paid=true&tags=a&tags=b+c");
+
+    assert_eq!(to_form.apply(b"[1]").unwrap_err(), "expected a JSON object");
+    assert_eq!(to_form.apply(br#"{"a":{"b":1}}"#).unwrap_err(), "field a is nested, which forms cannot express");
+}
+
+#[tokio::test]
+async fn local_service_receives_the_converted_body() {
+    let dir = tempfile::tempdir().unwrap();
+    let rules = dir.path().join("transforms.txt");
+    std::fs::write(&rules, RULES).unwrap();
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let rules = rules.to_str().unwrap();
+    let _client = TestClient::start_with(server.addr, local.port, None, &[("LOCAL_TRANSFORM_RULES_FILE", rules)]);
+    server.wait_for_new_tunnel(None).await;
+    let http = reqwest::Client::new();
+
+    let response = http.post(server.url("/hooks/forms")).form(&[("event", "paid"), ("id", "42")]).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "application/json");
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body, json!({"event": "paid", "id": "42"}));
+
+    // Elsewhere the body passes as is
+    let response = http.post(server.url("/other")).form(&[("event", "paid")]).send().await.unwrap();
+    assert_eq!(response.headers()["content-type"], "application/x-www-form-urlencoded");
+    assert_eq!(response.text().await.unwrap(), "event=paid");
+
+    let response = http.post(server.url("/legacy")).header("content-type", "application/json").body("not json").send().await.unwrap();
+    assert_eq!(response.status(), 400);
+}
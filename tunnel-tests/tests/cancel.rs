@@ -0,0 +1,110 @@
+//! Requests the server gives up on are cancelled on multiplexing clients.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tunnel_core::client::parse_server_addr;
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::ServerState;
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+#[tokio::test]
+async fn abandoned_requests_are_cancelled_and_free_their_slot() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let mut config = parse_server_addr(&format!("http://{}", server.addr), None, Vec::new()).unwrap();
+    config.max_concurrent = 2;
+    let client = TestClient::start_with_config(config, local.port, &[]);
+    server.wait_for_new_tunnel(None).await;
+    assert!(server.state.registry.active().await.unwrap().cancel);
+
+    // Visitors that give up on slow requests, taking up every slot of the client
+    let impatient = reqwest::Client::builder().timeout(Duration::from_millis(300)).build().unwrap();
+    for _ in 0..2 {
+        assert!(impatient.get(server.url("/slow")).header("x-delay-ms", "5000").send().await.is_err());
+    }
+
+    // Served right away rather than once the local service answered the slow ones
+    let started = Instant::now();
+    let response = reqwest::get(server.url("/fast")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(started.elapsed() < Duration::from_millis(2000), "{:?}", started.elapsed());
+
+    // The second slow request may still be on its way to being cancelled
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let statuses = loop {
+        let statuses: Vec<_> = client.captures.list().iter().map(|exchange| (exchange.path.clone(), exchange.status)).collect();
+        if statuses.len() == 3 || Instant::now() > deadline {
+            break statuses;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+    assert!(statuses.contains(&("/fast".to_string(), 200)), "{:?}", statuses);
+    assert_eq!(statuses.iter().filter(|(path, status)| path == "/slow" && *status == 499).count(), 2, "{:?}", statuses);
+}
+
+/// Local service answering a request with an endless chunked body, telling on `stopped` once nobody reads it any more
+async fn start_endless_local(stopped: oneshot::Sender<()>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8];
+            stream.read_exact(&mut byte).await.unwrap();
+            head.push(byte[0]);
+        }
+        let mut chunk = format!("{:x}\r\n", 16 * 1024).into_bytes();
+        chunk.extend_from_slice(&[7u8; 16 * 1024]);
+        chunk.extend_from_slice(b"\r\n");
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n").await;
+        while stream.write_all(&chunk).await.is_ok() {}
+        let _ = stopped.send(());
+    });
+    addr
+}
+
+#[tokio::test]
+async fn downloads_their_visitor_drops_are_cancelled() {
+    let (stopped_tx, stopped) = oneshot::channel();
+    let local = start_endless_local(stopped_tx).await;
+    let transport = TransportOptions { stream_threshold_bytes: 10_000, ..TransportOptions::default() };
+    let server = TestServer::start_with(ServerState::new(None, &transport)).await;
+    let mut config = parse_server_addr(&format!("http://{}", server.addr), None, Vec::new()).unwrap();
+    config.max_concurrent = 2;
+    config.binary_frames = true;
+    config.stream_bodies = true;
+    config.transport = transport;
+    let _client = TestClient::start_with_config(config, local.port(), &[]);
+    server.wait_for_new_tunnel(None).await;
+    let conn = server.state.registry.active().await.unwrap();
+    assert!(conn.cancel && conn.stream_credit);
+
+    // Reads part of the download, then goes away
+    let mut visitor = TcpStream::connect(server.addr).await.unwrap();
+    visitor.write_all(b"GET /download HTTP/1.1\r\nhost: tunnel\r\n\r\n").await.unwrap();
+    let mut part = vec![0u8; 256 * 1024];
+    visitor.read_exact(&mut part).await.unwrap();
+    drop(visitor);
+
+    // The local service is no longer read
+    let stopped = tokio::time::timeout(Duration::from_secs(5), stopped).await;
+    assert!(stopped.is_ok_and(|stopped| stopped.is_ok()), "the download was not cancelled");
+}
+
+#[tokio::test]
+async fn clients_that_do_not_multiplex_are_not_sent_cancels() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let mut config = parse_server_addr(&format!("http://{}", server.addr), None, Vec::new()).unwrap();
+    config.max_concurrent = 1;
+    let _client = TestClient::start_with_config(config, local.port, &[]);
+    server.wait_for_new_tunnel(None).await;
+    assert!(!server.state.registry.active().await.unwrap().cancel);
+
+    let response = reqwest::get(server.url("/")).await.unwrap();
+    assert_eq!(response.status(), 200);
+}
@@ -0,0 +1,159 @@
+//! Recent requests captured by the client for inspection, bodies only when asked for.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tunnel_client::capture::{self, CaptureLog, CaptureOptions};
+use tunnel_core::redact::REDACTED;
+use tunnel_protocol::{encode_body, TunnelRequest, TunnelResponse};
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+}
+
+#[test]
+fn capture_options_are_parsed() {
+    let defaults = CaptureOptions::from_source(|_| None).unwrap();
+    assert_eq!(defaults, CaptureOptions { history: 50, bodies: false, max_body_bytes: 16384, max_total_bytes: 1048576 });
+
+    let e = CaptureOptions::from_source(|key| (key == "CAPTURE_BODIES").then(|| "yes".to_string())).unwrap_err();
+    assert_eq!(e, "Invalid CAPTURE_BODIES: yes (expected true or false)");
+    let e = CaptureOptions::from_source(|key| (key == "CAPTURE_HISTORY").then(|| "-1".to_string())).unwrap_err();
+    assert_eq!(e, "Invalid CAPTURE_HISTORY: -1");
+}
+
+#[tokio::test]
+async fn headers_are_captured_with_credentials_redacted() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let response = reqwest::Client::new()
+        .post(server.url("/hook?source=ci"))
+        .header("authorization", "Bearer secret-token")
+        .header("cookie", "session=abc")
+        .header("x-echo-value", "visible")
+        .body("payload")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let captured = client.captures.list();
+    assert_eq!(captured.len(), 1);
+    let exchange = &captured[0];
+    assert_eq!((exchange.method.as_str(), exchange.path.as_str(), exchange.status), ("POST", "/hook?source=ci", 200));
+    assert_eq!(header(&exchange.request_headers, "authorization"), Some(REDACTED));
+    assert_eq!(header(&exchange.request_headers, "cookie"), Some(REDACTED));
+    assert_eq!(header(&exchange.request_headers, "x-echo-value"), Some("visible"));
+    assert_eq!(header(&exchange.response_headers, "x-echo-value"), Some("visible"));
+    assert!(exchange.request_body.is_none());
+    assert!(exchange.response_body.is_none());
+}
+
+#[tokio::test]
+async fn bodies_are_captured_up_to_the_cap() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let settings = [("CAPTURE_BODIES", "true"), ("CAPTURE_MAX_BODY_BYTES", "4"), ("CAPTURE_HISTORY", "2")];
+    let client = TestClient::start_with(server.addr, local.port, None, &settings);
+    server.wait_for_new_tunnel(None).await;
+
+    let http = reqwest::Client::new();
+    for path in ["/one", "/two", "/three"] {
+        http.post(server.url(path)).body("payload").send().await.unwrap();
+    }
+    http.post(server.url("/binary")).body(vec![0xffu8, 0xfe]).send().await.unwrap();
+
+    // Only the newest are kept, newest first
+    let captured = client.captures.list();
+    assert_eq!(captured.iter().map(|exchange| exchange.path.as_str()).collect::<Vec<_>>(), ["/binary", "/three"]);
+    assert!(captured[0].id > captured[1].id);
+
+    let body = captured[1].request_body.as_ref().unwrap();
+    assert_eq!((body.size, body.truncated, body.encoding, body.data.as_str()), (7, true, "utf8", "payl"));
+    let body = captured[1].response_body.as_ref().unwrap();
+    assert_eq!((body.size, body.truncated, body.data.as_str()), (7, true, "payl"));
+    let body = captured[0].request_body.as_ref().unwrap();
+    assert_eq!((body.size, body.truncated, body.encoding, body.data.as_str()), (2, false, "base64", "//4="));
+}
+
+#[test]
+fn captured_requests_stay_within_the_total_cap() {
+    let options = CaptureOptions { bodies: true, max_total_bytes: 120, ..CaptureOptions::default() };
+    let log = CaptureLog::new(options, Arc::default());
+    let record = |path: &str, note: &str, body: &str| {
+        let request = TunnelRequest {
+            method: "POST".into(),
+            path: path.into(),
+            headers: vec![("x-note".into(), note.into())],
+            body: encode_body(body.as_bytes()),
+            ..TunnelRequest::default()
+        };
+        let pending = log.start(&request, None).unwrap();
+        let response = TunnelResponse { status: 200, headers: vec![("x-note".into(), note.into())], ..TunnelResponse::default() };
+        log.finish(pending, &response, body.as_bytes(), None, Duration::ZERO);
+    };
+    let paths = || log.list().into_iter().map(|exchange| exchange.path).collect::<Vec<_>>();
+    let evicted = capture::evictions();
+
+    // 4 + 4 + 2 * (6 + 10) + 2 * 7 = 54 bytes each, headers included
+    for path in ["/one", "/two", "/six"] {
+        record(path, &"n".repeat(10), "payload");
+    }
+    assert_eq!(paths(), ["/six", "/two"]);
+    assert!(capture::evictions() > evicted);
+
+    // Too large on its own: kept by size only
+    record("/big", &"n".repeat(10), &"x".repeat(100));
+    assert_eq!(paths(), ["/big", "/six"]);
+    let body = log.list()[0].request_body.clone().unwrap();
+    assert_eq!((body.size, body.truncated, body.data.as_str()), (100, true, ""));
+
+    // Headers alone too large: not kept, and nothing else dropped for it
+    let evicted = capture::evictions();
+    record("/hdr", &"n".repeat(100), "");
+    assert_eq!(paths(), ["/big", "/six"]);
+    assert!(capture::evictions() > evicted);
+}
+
+#[tokio::test]
+async fn capture_can_be_turned_off() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let client = TestClient::start_with(server.addr, local.port, None, &[("CAPTURE_HISTORY", "0")]);
+    server.wait_for_new_tunnel(None).await;
+
+    assert_eq!(reqwest::get(server.url("/")).await.unwrap().status(), 200);
+    assert!(client.captures.list().is_empty());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn body_capture_is_toggled_on_the_control_socket() {
+    use tunnel_client::control::{execute, ControlContext};
+    use tunnel_core::logging::{self, LogOptions};
+
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+    let (log, _guard) = logging::init(&LogOptions::default()).unwrap();
+    let context = ControlContext { log, status: client.status.clone(), captures: client.captures.clone(), local: client.local.clone() };
+
+    reqwest::Client::new().post(server.url("/before")).body("one").send().await.unwrap();
+    let reply = execute(&context, "capture-bodies on");
+    assert_eq!(reply["ok"], true);
+    assert_eq!(reply["bodies"], true);
+    reqwest::Client::new().post(server.url("/after")).body("two").send().await.unwrap();
+
+    let reply = execute(&context, "requests");
+    let requests = reply["requests"].as_array().unwrap();
+    assert_eq!(requests[0]["path"], "/after");
+    assert_eq!(requests[0]["request_body"]["data"], "two");
+    assert_eq!(requests[1]["path"], "/before");
+    assert!(requests[1]["request_body"].is_null());
+
+    assert_eq!(execute(&context, "capture-bodies maybe")["ok"], false);
+}
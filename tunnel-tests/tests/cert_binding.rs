@@ -0,0 +1,110 @@
+//! Credentials bound to the client certificate first used with them (TOKEN_CERT_BINDING).
+//!
+//! tests/fixtures holds two self-signed P-256 client certificates,
+//! `client.pem`/`client.key` and `other-client.pem`/`other-client.key`.
+
+use rustls::pki_types::ServerName;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tunnel_core::client::{parse_server_addr, send_upgrade_request, UpgradeError};
+use tunnel_core::tls::{CertPin, ClientCert, TlsOptions};
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::tls::CertStore;
+use tunnel_server::usage::UsageStore;
+use tunnel_server::ServerState;
+use tunnel_tests::{fixture, fixture_roots, TestAdmin, TestServer};
+
+fn client_cert(name: &str) -> ClientCert {
+    ClientCert::load(&fixture(&format!("{}.pem", name)), &fixture(&format!("{}.key", name))).unwrap()
+}
+
+async fn start_server() -> (TestServer, Arc<UsageStore>) {
+    let store = CertStore::load(Some((fixture("server.pem"), fixture("server.key"))), None, &TlsOptions::default())
+        .unwrap()
+        .with_client_certs(true);
+    let usage = Arc::new(UsageStore::default());
+    let state = ServerState::new(Some("dev:secret".to_string()), &TransportOptions::default())
+        .with_usage(usage.clone())
+        .with_cert_binding(true);
+    (TestServer::start_tls_with(Arc::new(store).acceptor().unwrap(), state).await, usage)
+}
+
+fn bound_certificate(usage: &UsageStore) -> Option<CertPin> {
+    usage.list().get("dev").and_then(|usage| usage.client_cert)
+}
+
+/// Opens a tunnel as `dev`, presenting `cert`
+async fn upgrade(server: &TestServer, cert: Option<&ClientCert>) -> Result<(), UpgradeError> {
+    let config = TlsOptions::default().pinned_client_config(fixture_roots(&["ca.pem"]), &[], cert).unwrap();
+    let tcp = TcpStream::connect(server.addr).await.unwrap();
+    let server_name = ServerName::try_from("localhost").unwrap();
+    let mut stream = TlsConnector::from(Arc::new(config)).connect(server_name, tcp).await.unwrap();
+    let config = parse_server_addr("localhost", Some("dev:secret".to_string()), Vec::new()).unwrap();
+    send_upgrade_request(&mut stream, &config).await.map(|_| ())
+}
+
+fn assert_forbidden(result: Result<(), UpgradeError>) {
+    match result {
+        Err(e @ UpgradeError::Rejected { status: Some(403), .. }) => assert!(!e.is_retryable()),
+        other => panic!("expected 403, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn credential_is_bound_to_the_first_certificate_used_with_it() {
+    let (server, usage) = start_server().await;
+    let client = client_cert("client");
+
+    upgrade(&server, Some(&client)).await.unwrap();
+    assert_eq!(bound_certificate(&usage), Some(client.pin()));
+    // The same client reconnects as often as it likes
+    upgrade(&server, Some(&client)).await.unwrap();
+
+    // The credential alone is not enough any more
+    assert_forbidden(upgrade(&server, Some(&client_cert("other-client"))).await);
+    assert_forbidden(upgrade(&server, None).await);
+    assert_eq!(bound_certificate(&usage), Some(client.pin()));
+}
+
+#[tokio::test]
+async fn released_credential_binds_to_the_next_certificate() {
+    let (server, usage) = start_server().await;
+    let (client, other) = (client_cert("client"), client_cert("other-client"));
+    upgrade(&server, Some(&client)).await.unwrap();
+
+    assert_eq!(usage.unbind_certificate("dev"), Some(client.pin()));
+    assert_eq!(usage.unbind_certificate("dev"), None);
+    upgrade(&server, Some(&other)).await.unwrap();
+    assert_forbidden(upgrade(&server, Some(&client)).await);
+}
+
+#[tokio::test]
+async fn client_without_certificate_cannot_claim_a_credential() {
+    let (server, usage) = start_server().await;
+    assert_forbidden(upgrade(&server, None).await);
+    assert_eq!(bound_certificate(&usage), None);
+    upgrade(&server, Some(&client_cert("client"))).await.unwrap();
+}
+
+#[tokio::test]
+async fn binding_is_saved_at_once_and_released_through_the_admin_api() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("usage.json");
+    let client = client_cert("client");
+
+    // Written right away, not at the next periodic save
+    let store = UsageStore::load(&path).unwrap();
+    assert_eq!(store.bind_certificate("dev", Some(client.pin())), Ok(true));
+    assert_eq!(bound_certificate(&UsageStore::load(&path).unwrap()), Some(client.pin()));
+
+    let usage = Arc::new(UsageStore::load(&path).unwrap());
+    let state = ServerState::new(Some("dev:secret".to_string()), &TransportOptions::default()).with_usage(usage.clone());
+    let admin = TestAdmin::start(state).await;
+    let url = admin.url("/api/tokens/dev/certificate");
+
+    let http = reqwest::Client::new();
+    assert_eq!(http.delete(&url).send().await.unwrap().status(), 204);
+    assert_eq!(http.delete(&url).send().await.unwrap().status(), 404);
+    assert_eq!(bound_certificate(&UsageStore::load(&path).unwrap()), None);
+}
@@ -0,0 +1,43 @@
+//! Server certificate pinning on the client (SERVER_CERT_PIN).
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName};
+use std::io::ErrorKind;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tunnel_core::tls::{CertPin, TlsOptions};
+use tunnel_tests::{fixture, fixture_acceptor, fixture_roots, TestServer};
+
+fn certificate(name: &str) -> CertificateDer<'static> {
+    CertificateDer::from_pem_file(fixture(name)).unwrap()
+}
+
+async fn connect(server: &TestServer, pins: &[CertPin]) -> std::io::Result<()> {
+    let config = TlsOptions::default().pinned_client_config(fixture_roots(&["ca.pem"]), pins, None).unwrap();
+    let tcp = TcpStream::connect(server.addr).await?;
+    TlsConnector::from(Arc::new(config)).connect(ServerName::try_from("localhost").unwrap(), tcp).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn matching_pin_connects() {
+    let server = TestServer::start_tls(fixture_acceptor(&TlsOptions::default())).await;
+
+    let pin = CertPin::of(&certificate("server.pem"));
+    connect(&server, &[pin]).await.unwrap();
+    // Any of several pins will do, e.g. while rotating certificates
+    let ca_pin = CertPin::of(&certificate("ca.pem"));
+    connect(&server, &[ca_pin, pin]).await.unwrap();
+}
+
+#[tokio::test]
+async fn trusted_certificate_without_matching_pin_is_refused() {
+    let server = TestServer::start_tls(fixture_acceptor(&TlsOptions::default())).await;
+
+    // The CA's own fingerprint does not pin the leaf it signed
+    let e = connect(&server, &[CertPin::of(&certificate("ca.pem"))]).await.unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidData);
+    let presented = CertPin::of(&certificate("server.pem"));
+    assert!(e.to_string().contains(&format!("{} matches no SERVER_CERT_PIN", presented)), "{}", e);
+}
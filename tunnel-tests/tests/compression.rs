@@ -0,0 +1,95 @@
+//! Frames are compressed (TUNNEL_COMPRESSION) when both ends agree to it, in
+//! any framing mode.
+
+use serde_json::Value;
+use tunnel_core::client::{parse_server_addr, ServerConfig};
+use tunnel_core::transport::TransportOptions;
+use tunnel_protocol::Compression;
+use tunnel_server::ServerState;
+use tunnel_tests::{MockLocal, TestAdmin, TestClient, TestServer};
+
+fn transport(compression: Option<Compression>) -> TransportOptions {
+    TransportOptions { compression, stream_threshold_bytes: 100_000, ..TransportOptions::default() }
+}
+
+fn config(server: &TestServer, compression: Option<Compression>) -> ServerConfig {
+    let mut config = parse_server_addr(&format!("http://{}", server.addr), None, Vec::new()).unwrap();
+    config.transport = transport(compression);
+    config
+}
+
+/// JSON-like text, which compresses well
+fn json(len: usize) -> Vec<u8> {
+    let mut json = br#"{"id":12345,"name":"speedforce","tags":["a","b"]},"#.repeat(len / 50 + 1);
+    json.truncate(len);
+    json
+}
+
+/// Every byte value, so any text decoding or escaping along the way shows
+fn all_bytes(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[tokio::test]
+async fn bodies_arrive_intact_in_every_framing_mode() {
+    let local = MockLocal::start().await;
+    for compression in [Compression::Zstd, Compression::Gzip] {
+        // Lockstep JSON frames, then multiplexed binary frames with streamed bodies
+        for multiplexed in [false, true] {
+            let server = TestServer::start_with(ServerState::new(None, &transport(Some(compression)))).await;
+            let mut config = config(&server, Some(compression));
+            if multiplexed {
+                config.max_concurrent = 8;
+                config.binary_frames = true;
+                config.stream_bodies = true;
+            }
+            let _client = TestClient::start_with_config(config, local.port, &[]);
+            server.wait_for_new_tunnel(None).await;
+            assert_eq!(server.state.registry.active().await.unwrap().compression, Some(compression));
+
+            let http = reqwest::Client::new();
+            for body in [json(100), json(5_000), json(400_000), all_bytes(300_000)] {
+                let response = http.post(server.url("/echo")).body(body.clone()).send().await.unwrap();
+                assert_eq!(response.status(), 200);
+                assert_eq!(response.bytes().await.unwrap(), body, "{} multiplexed={}", compression, multiplexed);
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn compression_needs_both_ends() {
+    let local = MockLocal::start().await;
+    for (server_compression, client_compression) in [(None, Some(Compression::Zstd)), (Some(Compression::Gzip), None)] {
+        let server = TestServer::start_with(ServerState::new(None, &transport(server_compression))).await;
+        let _client = TestClient::start_with_config(config(&server, client_compression), local.port, &[]);
+        server.wait_for_new_tunnel(None).await;
+        assert_eq!(server.state.registry.active().await.unwrap().compression, None);
+
+        let response = reqwest::Client::new().post(server.url("/echo")).body(json(5_000)).send().await.unwrap();
+        assert_eq!(response.bytes().await.unwrap(), json(5_000));
+    }
+}
+
+#[tokio::test]
+async fn client_algorithm_is_used_and_shown_in_the_admin_api() {
+    let local = MockLocal::start().await;
+    // The server's own setting only says whether it compresses at all
+    let server = TestServer::start_with(ServerState::new(None, &transport(Some(Compression::Zstd)))).await;
+    let admin = TestAdmin::start(server.state.clone()).await;
+
+    let _client = TestClient::start_with_config(config(&server, Some(Compression::Gzip)), local.port, &[]);
+    server.wait_for_new_tunnel(None).await;
+
+    // The client's figures come with its first stats report, sent ahead of the first response
+    let response = reqwest::Client::new().post(server.url("/echo")).body(json(50_000)).send().await.unwrap();
+    assert_eq!(response.bytes().await.unwrap(), json(50_000));
+
+    let tunnels: Value = reqwest::get(admin.url("/api/tunnels")).await.unwrap().json().await.unwrap();
+    let compression = &tunnels["tunnels"][0]["compression"];
+    assert_eq!(compression["algorithm"], "gzip");
+    assert_eq!(compression["to_client"]["enabled"], true);
+    assert!(compression["to_client"]["bytes_saved"].as_u64().unwrap() > 40_000, "{}", compression);
+    assert!(compression["to_client"]["ratio"].as_f64().unwrap() < 0.2, "{}", compression);
+    assert_eq!(compression["from_client"]["enabled"], true);
+}
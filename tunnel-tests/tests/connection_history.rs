@@ -0,0 +1,53 @@
+//! Recent connections per tunnel credential (`GET /api/tokens/:name/history`).
+
+use serde_json::Value;
+use std::time::Duration;
+use tunnel_tests::{MockLocal, TestAdmin, TestClient, TestServer};
+
+#[tokio::test]
+async fn connections_are_kept_with_how_they_ended() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(Some("alice:secret")).await;
+    let admin = TestAdmin::start(server.state.clone()).await;
+    let mut client = TestClient::start(server.addr, local.port, Some("alice:secret"));
+    let first = server.wait_for_new_tunnel(None).await;
+
+    let response = reqwest::Client::new().post(server.url("/upload")).body(vec![1u8; 1000]).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    client.shut_down().await;
+    let _client = TestClient::start(server.addr, local.port, Some("alice:secret"));
+    let second = server.wait_for_new_tunnel(Some(first)).await;
+
+    // The server may still be recording the end of the first connection
+    let history_url = admin.url("/api/tokens/alice/history");
+    let history = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let history: Value = reqwest::get(&history_url).await.unwrap().json().await.unwrap();
+            if !history["connections"][1]["reason"].is_null() {
+                return history;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("first connection never ended");
+
+    assert_eq!(history["name"], "alice");
+    let connections = history["connections"].as_array().unwrap();
+    assert_eq!(connections.len(), 2);
+    assert_eq!(connections[0]["tunnel_id"], second);
+    assert!(connections[0]["reason"].is_null());
+    assert!(connections[0]["disconnected_at"].is_null());
+
+    let ended = &connections[1];
+    assert_eq!(ended["tunnel_id"], first);
+    assert_eq!(ended["reason"], "drained");
+    assert_eq!(ended["source_ip"], "127.0.0.1");
+    assert_eq!(ended["request_bytes"], 1000);
+    assert!(ended["response_bytes"].as_u64().unwrap() >= 1000);
+    assert!(ended["duration_ms"].is_u64());
+    assert!(ended["disconnected_at"].as_u64().unwrap() >= ended["connected_at"].as_u64().unwrap());
+
+    let unknown = reqwest::get(admin.url("/api/tokens/mallory/history")).await.unwrap();
+    assert_eq!(unknown.status(), 404);
+}
@@ -0,0 +1,35 @@
+//! Who may use the client's control socket, which lists captured requests.
+
+#![cfg(unix)]
+
+use serde_json::json;
+use std::sync::Arc;
+use tunnel_client::capture::{CaptureLog, CaptureOptions};
+use tunnel_client::control::{self, ControlContext};
+use tunnel_client::local::{LocalConfig, LocalService, LocalTarget};
+use tunnel_client::status::StatusHandle;
+use tunnel_core::client::parse_server_addr;
+use tunnel_core::logging::{self, LogOptions};
+use tunnel_core::redact::Redactor;
+
+#[tokio::test]
+async fn only_the_owner_of_the_socket_is_served() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("control.sock");
+    let listener = control::bind(&path).unwrap();
+    // As if the socket belonged to another user; needs root, so there is nothing to check without it
+    if std::os::unix::fs::chown(&path, Some(65534), None).is_err() {
+        return;
+    }
+
+    let (log, _guard) = logging::init(&LogOptions::default()).unwrap();
+    let status = StatusHandle::new(&parse_server_addr("example.com", None, Vec::new()).unwrap());
+    let captures = CaptureLog::new(CaptureOptions::default(), Arc::new(Redactor::default()));
+    let config = LocalConfig::from_source(|_| None).unwrap();
+    let service = LocalService::new(&config).unwrap();
+    let (local, _local_rx) = LocalTarget::new(config, service);
+    tokio::spawn(control::serve(listener, Arc::new(ControlContext { log, status, captures, local })));
+
+    let reply = control::query(&path, "requests").await.unwrap();
+    assert_eq!(reply, json!({ "ok": false, "error": "Control socket is reserved for user 65534" }));
+}
@@ -0,0 +1,133 @@
+//! CORS answered at the server for the origins the client asks for at handshake.
+
+use reqwest::Method;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tunnel_core::client::parse_server_addr;
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+/// Starts a server and a client asking for CORS for `origins`
+async fn start(origins: &[&str], visitor_auth: Option<&str>) -> (TestServer, TestClient, MockLocal) {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let mut config = parse_server_addr(&format!("http://{}", server.addr), None, Vec::new()).unwrap();
+    config.cors_origins = origins.iter().map(|origin| origin.to_string()).collect();
+    config.visitor_auth = visitor_auth.map(str::to_string);
+    let client = TestClient::start_with_config(config, local.port, &[]);
+    server.wait_for_new_tunnel(None).await;
+    (server, client, local)
+}
+
+#[tokio::test]
+async fn preflights_are_answered_without_the_local_service() {
+    let (server, _client, _local) = start(&["https://app.example.com"], Some("guest:hunter2")).await;
+    let http = reqwest::Client::new();
+
+    // Browsers send preflights without credentials
+    let response = http
+        .request(Method::OPTIONS, server.url("/api/items"))
+        .header("origin", "https://app.example.com")
+        .header("access-control-request-method", "PUT")
+        .header("access-control-request-headers", "content-type, x-request-id")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 204);
+    assert!(response.headers().get("x-echo-method").is_none());
+    assert_eq!(response.headers()["access-control-allow-origin"], "https://app.example.com");
+    assert_eq!(response.headers()["access-control-allow-credentials"], "true");
+    assert_eq!(response.headers()["access-control-allow-methods"], "PUT");
+    assert_eq!(response.headers()["access-control-allow-headers"], "content-type, x-request-id");
+    assert_eq!(response.headers()["vary"], "Origin");
+
+    let response = http
+        .request(Method::OPTIONS, server.url("/api/items"))
+        .header("origin", "https://evil.example")
+        .header("access-control-request-method", "PUT")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+    assert_eq!(response.headers()["x-tunnel-error"], "cors-origin-denied");
+    assert!(response.headers().get("access-control-allow-origin").is_none());
+
+    // Without a preflight header OPTIONS is an ordinary request
+    let response = http
+        .request(Method::OPTIONS, server.url("/api/items"))
+        .basic_auth("guest", Some("hunter2"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["x-echo-method"], "OPTIONS");
+}
+
+#[tokio::test]
+async fn responses_to_allowed_origins_get_cors_headers() {
+    let (server, _client, _local) = start(&["https://app.example.com"], None).await;
+    let http = reqwest::Client::new();
+
+    let response = http
+        .get(server.url("/data"))
+        .header("origin", "https://app.example.com")
+        .header("x-echo-value", "ok")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["access-control-allow-origin"], "https://app.example.com");
+    assert_eq!(response.headers()["access-control-allow-credentials"], "true");
+    let exposed = response.headers()["access-control-expose-headers"].to_str().unwrap();
+    assert!(exposed.split(", ").any(|name| name == "x-echo-value"), "{}", exposed);
+
+    let response = http.get(server.url("/data")).header("origin", "https://evil.example").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().get("access-control-allow-origin").is_none());
+
+    let response = http.get(server.url("/data")).send().await.unwrap();
+    assert!(response.headers().get("access-control-allow-origin").is_none());
+}
+
+#[tokio::test]
+async fn wildcard_allows_any_origin_without_credentials() {
+    let (server, _client, _local) = start(&["*"], None).await;
+
+    let response = reqwest::Client::new()
+        .post(server.url("/submit"))
+        .header("origin", "http://localhost:5173")
+        .body("x")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["access-control-allow-origin"], "*");
+    assert!(response.headers().get("access-control-allow-credentials").is_none());
+    assert!(response.headers().get("vary").is_none());
+}
+
+#[tokio::test]
+async fn tunnels_without_cors_forward_preflights() {
+    let (server, _client, _local) = start(&[], None).await;
+
+    let response = reqwest::Client::new()
+        .request(Method::OPTIONS, server.url("/api"))
+        .header("origin", "https://app.example.com")
+        .header("access-control-request-method", "PUT")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["x-echo-method"], "OPTIONS");
+    assert!(response.headers().get("access-control-allow-origin").is_none());
+}
+
+#[tokio::test]
+async fn malformed_cors_origins_fail_the_upgrade() {
+    let server = TestServer::start(None).await;
+    let mut stream = TcpStream::connect(server.addr).await.unwrap();
+    stream
+        .write_all(b"GET /tunnel HTTP/1.1\r\nHost: test\r\nUpgrade: tunnel\r\nConnection: Upgrade\r\nx-tunnel-cors: app.example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = vec![0u8; 1024];
+    let n = stream.read(&mut response).await.unwrap();
+    assert!(response[..n].starts_with(b"HTTP/1.1 400 "), "{}", String::from_utf8_lossy(&response[..n]));
+    assert_eq!(server.tunnel_id().await, None);
+}
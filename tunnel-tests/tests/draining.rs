@@ -0,0 +1,66 @@
+//! A client that is shutting down (GOAWAY) gets no new requests; visitors are told to retry.
+
+use std::time::Duration;
+use tokio::io::BufReader;
+use tunnel_core::client::{connect_and_upgrade, parse_server_addr};
+use tunnel_protocol::{read_frame, write_frame, TunnelResponse, GOAWAY_FRAME};
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+#[tokio::test]
+async fn draining_tunnel_asks_visitors_to_retry() {
+    let server = TestServer::start(None).await;
+    let config = parse_server_addr(&format!("http://{}", server.addr), None, Vec::new()).unwrap();
+    let (stream, _) = connect_and_upgrade(&config).await.unwrap();
+    server.wait_for_new_tunnel(None).await;
+
+    // Fake client: sends GOAWAY as soon as a request arrives, then answers it a little later
+    let fake_client = tokio::spawn(async move {
+        let (read_half, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(read_half);
+        let mut served = 0;
+        while read_frame(&mut reader).await.is_ok() {
+            write_frame(&mut writer, GOAWAY_FRAME).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            let response = TunnelResponse {
+                status: 200,
+                ..TunnelResponse::default()
+            };
+            write_frame(&mut writer, &serde_json::to_vec(&response).unwrap()).await.unwrap();
+            served += 1;
+        }
+        served
+    });
+
+    let http = reqwest::Client::new();
+    let first = tokio::spawn(http.get(server.url("/first")).send());
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let response = http.get(server.url("/second")).send().await.unwrap();
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.headers()["retry-after"], "1");
+    assert_eq!(response.headers()["x-tunnel-error"], "tunnel-draining");
+
+    // The request that was already queued is still answered, then the server closes the tunnel
+    assert_eq!(first.await.unwrap().unwrap().status(), 200);
+    assert_eq!(fake_client.await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn client_shutdown_drains_the_tunnel() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let mut client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+    assert_eq!(reqwest::get(server.url("/")).await.unwrap().status(), 200);
+
+    tokio::time::timeout(Duration::from_secs(5), client.shut_down()).await.expect("client did not drain in time");
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.state.registry.worker_stats().drained == 0 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("server did not count the drained tunnel");
+    assert!(server.tunnel_id().await.is_none());
+}
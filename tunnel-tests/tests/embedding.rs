@@ -0,0 +1,122 @@
+//! A tunnel server built with `TunnelServer::builder` and merged into an app of its own.
+
+use axum::routing::get;
+use axum::Router;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tunnel_core::client::parse_server_addr;
+use tunnel_core::server::QueueOptions;
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::{tunnel_routes, BuildError, ServerState, TunnelLayer, TunnelServer};
+use tunnel_tests::{MockLocal, TestClient};
+
+async fn wait_for_tunnel(state: &ServerState) {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while state.registry.active().await.is_none() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("tunnel client did not connect in time");
+}
+
+#[tokio::test]
+async fn app_routes_are_served_alongside_the_tunnel() {
+    let tunnel = TunnelServer::builder()
+        .auth("user:secret")
+        .tunnel_path("/t/app")
+        .configure(|state| state.with_queue(QueueOptions { depth: 8, ..QueueOptions::default() }))
+        .build()
+        .unwrap();
+    let app = Router::new().route("/health", get(|| async { "ok" })).merge(tunnel.router());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+    });
+
+    // The app's own route answers without a tunnel client; the rest waits for one
+    let health = reqwest::get(format!("http://{}/health", addr)).await.unwrap();
+    assert_eq!(health.status(), 200);
+    assert_eq!(health.text().await.unwrap(), "ok");
+    assert_eq!(reqwest::get(format!("http://{}/page", addr)).await.unwrap().status(), 503);
+
+    let local = MockLocal::start().await;
+    let mut config = parse_server_addr(&format!("http://{}", addr), Some("user:secret".to_string()), Vec::new()).unwrap();
+    config.path = "/t/app".to_string();
+    let _client = TestClient::start_with_config(config, local.port, &[]);
+    wait_for_tunnel(tunnel.state()).await;
+
+    let page = reqwest::get(format!("http://{}/page?x=1", addr)).await.unwrap();
+    assert_eq!(page.status(), 200);
+    assert_eq!(page.headers()["x-echo-path"], "/page?x=1");
+    assert_eq!(reqwest::get(format!("http://{}/health", addr)).await.unwrap().text().await.unwrap(), "ok");
+}
+
+#[test]
+fn invalid_settings_are_rejected() {
+    assert!(matches!(TunnelServer::builder().tunnel_path("tunnel").build(), Err(BuildError::TunnelPath(_))));
+    assert!(matches!(TunnelServer::builder().auth("no-colon").build(), Err(BuildError::Credentials)));
+    assert!(TunnelServer::builder().bind("127.0.0.1:0").build().is_ok());
+}
+
+#[tokio::test]
+async fn layer_forwards_only_the_given_prefixes() {
+    let state = ServerState::new(None, &TransportOptions::default());
+    let app = Router::new()
+        .route("/health", get(|| async { "ok" }))
+        .route("/preview/own", get(|| async { "own" }))
+        .merge(tunnel_routes(state.clone()))
+        .layer(TunnelLayer::new(state.clone(), ["/preview/", "/hooks/"]));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+    });
+
+    let local = MockLocal::start().await;
+    let _client = TestClient::start(addr, local.port, None);
+    wait_for_tunnel(&state).await;
+
+    let url = |path: &str| format!("http://{}{}", addr, path);
+    let forwarded = reqwest::get(url("/preview/page?x=1")).await.unwrap();
+    assert_eq!(forwarded.status(), 200);
+    assert_eq!(forwarded.headers()["x-echo-path"], "/preview/page?x=1");
+    let hook = reqwest::Client::new().post(url("/hooks/github")).body("{}").send().await.unwrap();
+    assert_eq!(hook.headers()["x-echo-method"], "POST");
+
+    // The prefixes win over the app's own routes; the rest of the app is untouched
+    assert!(reqwest::get(url("/preview/own")).await.unwrap().headers().contains_key("x-echo-path"));
+    assert_eq!(reqwest::get(url("/health")).await.unwrap().text().await.unwrap(), "ok");
+    let missing = reqwest::get(url("/elsewhere")).await.unwrap();
+    assert_eq!(missing.status(), 404);
+    assert!(!missing.headers().contains_key("x-echo-path"));
+}
+
+#[tokio::test]
+async fn layer_prefixes_match_whole_segments() {
+    let state = ServerState::new(None, &TransportOptions::default());
+    let app = Router::new()
+        .route("/previewer", get(|| async { "previewer" }))
+        .merge(tunnel_routes(state.clone()))
+        .layer(TunnelLayer::new(state.clone(), ["/preview"]));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+    });
+
+    let local = MockLocal::start().await;
+    let _client = TestClient::start(addr, local.port, None);
+    wait_for_tunnel(&state).await;
+
+    let url = |path: &str| format!("http://{}{}", addr, path);
+    for path in ["/preview", "/preview/", "/preview/page"] {
+        assert_eq!(reqwest::get(url(path)).await.unwrap().headers()["x-echo-path"], path);
+    }
+    assert_eq!(reqwest::get(url("/previewer")).await.unwrap().text().await.unwrap(), "previewer");
+    let other = reqwest::get(url("/preview-x")).await.unwrap();
+    assert_eq!(other.status(), 404);
+    assert!(!other.headers().contains_key("x-echo-path"));
+}
@@ -0,0 +1,92 @@
+//! Pieces of a zero-downtime upgrade (SIGUSR2): listeners handed to a new
+//! process, which waits for the client, while the old one drains.
+
+#![cfg(unix)]
+
+use rustls::pki_types::ServerName;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsConnector;
+use tunnel_core::tls::TlsOptions;
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::handover::{HandoverFds, Listeners};
+use tunnel_server::ServerState;
+use tunnel_tests::{fixture_acceptor, fixture_roots, MockLocal, TestClient, TestServer};
+
+#[test]
+fn handover_variable_round_trips() {
+    let fds = HandoverFds { pid: 4242, http: 5, admin: Some(6), ready: 7 };
+    assert_eq!(fds.to_env(), "pid=4242,http=5,admin=6,ready=7");
+    assert_eq!(HandoverFds::parse(&fds.to_env()).unwrap(), fds);
+
+    let fds = HandoverFds { admin: None, ..fds };
+    assert_eq!(HandoverFds::parse(&fds.to_env()).unwrap(), fds);
+
+    for value in ["pid=1,http=5", "pid=1,http=x,ready=7", "pid=1,http=5,ready=7,other=8", ""] {
+        let e = HandoverFds::parse(value).unwrap_err();
+        assert_eq!(e, format!("Invalid TUNNEL_HANDOVER_FDS: {}", value));
+    }
+}
+
+#[tokio::test]
+async fn listener_copies_accept_on_the_same_socket() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let copies = Listeners::copy(&listener, None).unwrap();
+    assert!(copies.admin.is_none());
+
+    // With the original gone, connections still reach the copy a successor would inherit
+    drop(listener);
+    let _stream = TcpStream::connect(addr).await.unwrap();
+    let (_, peer) = copies.http.accept().unwrap();
+    assert_eq!(peer.ip(), addr.ip());
+}
+
+#[tokio::test]
+async fn new_server_waits_for_the_client_of_the_one_it_replaces() {
+    let local = MockLocal::start().await;
+    let state = ServerState::new(None, &TransportOptions::default()).with_reconnect_grace(Some(Duration::from_secs(5)));
+    state.registry.mark_lost().await;
+    let server = TestServer::start_with(state).await;
+
+    // Arrives before the client moved over, and is answered once it has
+    let request = tokio::spawn(reqwest::get(server.url("/early")));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    let response = request.await.unwrap().unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-echo-path"], "/early");
+}
+
+#[tokio::test]
+async fn tls_listener_drains_in_flight_requests_on_shutdown() {
+    let acceptor = fixture_acceptor(&TlsOptions::default());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // Without a client, requests wait out the grace period and then get the landing response
+    let state = ServerState::new(None, &TransportOptions::default()).with_reconnect_grace(Some(Duration::from_millis(500)));
+    state.registry.mark_lost().await;
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+    let serve = tokio::spawn(tunnel_server::tls::serve(listener, acceptor, tunnel_server::router(state), true, async {
+        let _ = shutdown_rx.await;
+    }));
+
+    let config = Arc::new(TlsOptions::default().client_config(fixture_roots(&["ca.pem"])).unwrap());
+    let tcp = TcpStream::connect(addr).await.unwrap();
+    let mut stream = TlsConnector::from(config).connect(ServerName::try_from("localhost").unwrap(), tcp).await.unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Stops accepting, yet the request in flight is answered before the server returns
+    shutdown_tx.send(()).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(TcpStream::connect(addr).await.is_err());
+    assert!(!serve.is_finished());
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    assert!(String::from_utf8_lossy(&response).starts_with("HTTP/1.1 503"), "{}", String::from_utf8_lossy(&response));
+    tokio::time::timeout(Duration::from_secs(2), serve).await.unwrap().unwrap();
+}
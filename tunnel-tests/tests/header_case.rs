@@ -0,0 +1,75 @@
+//! Header names keep the case they were sent with over HTTP/1.1, both ways.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tunnel_tests::{TestClient, TestServer};
+
+/// Reads a message head, up to and including the blank line
+async fn read_head(stream: &mut TcpStream) -> Option<String> {
+    let mut head = Vec::new();
+    let mut byte = [0; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte).await.ok()? == 0 {
+            return None;
+        }
+        head.push(byte[0]);
+    }
+    Some(String::from_utf8(head).unwrap())
+}
+
+/// A local service answering every request with `X-Custom-Header: pong`, sending
+/// the request heads it got and counting its connections
+async fn start_local() -> (u16, mpsc::UnboundedReceiver<String>, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (heads_tx, heads) = mpsc::unbounded_channel();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counted = connections.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            counted.fetch_add(1, Ordering::SeqCst);
+            let heads_tx = heads_tx.clone();
+            tokio::spawn(async move {
+                while let Some(head) = read_head(&mut stream).await {
+                    let _ = heads_tx.send(head);
+                    let response = b"HTTP/1.1 200 OK\r\nX-Custom-Header: pong\r\nContent-Length: 2\r\n\r\nok";
+                    if stream.write_all(response).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    (port, heads, connections)
+}
+
+#[tokio::test]
+async fn header_names_arrive_as_spelled() {
+    let (local_port, mut heads, connections) = start_local().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local_port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let mut visitor = TcpStream::connect(server.addr).await.unwrap();
+    for _ in 0..2 {
+        let request = format!("GET /case HTTP/1.1\r\nHost: {}\r\nX-Custom-Header: ping\r\nx-lower: 1\r\n\r\n", server.addr);
+        visitor.write_all(request.as_bytes()).await.unwrap();
+
+        let head = read_head(&mut visitor).await.unwrap();
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert!(head.contains("\r\nX-Custom-Header: pong\r\n"), "{}", head);
+        let mut body = [0; 2];
+        visitor.read_exact(&mut body).await.unwrap();
+        assert_eq!(&body, b"ok");
+
+        let local_head = heads.recv().await.unwrap();
+        assert!(local_head.contains("\r\nX-Custom-Header: ping\r\n"), "{}", local_head);
+        assert!(local_head.contains("\r\nx-lower: 1\r\n"), "{}", local_head);
+    }
+
+    // Requests going around reqwest to keep their names still share a connection
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+}
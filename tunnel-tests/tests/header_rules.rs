@@ -0,0 +1,78 @@
+//! Response header rules: headers from the local service removed per route at the server.
+
+use tunnel_core::transport::TransportOptions;
+use tunnel_protocol::HeaderValueBytes;
+use tunnel_server::header_rules::HeaderRules;
+use tunnel_server::ServerState;
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+const RULES: &str = "\
+# everywhere
+/         deny   X-Echo-Method, x-echo-tunnel-*
+/api/     allow  content-type, x-echo-path
+";
+
+#[test]
+fn rules_file_is_parsed() {
+    assert_eq!(HeaderRules::parse(RULES).unwrap().len(), 2);
+    assert!(HeaderRules::parse("").unwrap().is_empty());
+
+    for (contents, error) in [
+        ("/ drop x-powered-by", "line 1: unknown action 'drop'"),
+        ("/", "line 1: expected"),
+        ("/ deny", "line 1: no header names"),
+        ("\napi deny x-powered-by", "line 2: path prefix must start with '/'"),
+        ("/ deny ,", "line 1: no header names"),
+        ("/ deny x-*-id", "line 1: '*' is only allowed at the end"),
+    ] {
+        let e = HeaderRules::parse(contents).unwrap_err();
+        assert!(e.starts_with(error), "{:?}: {}", contents, e);
+    }
+}
+
+#[test]
+fn every_matching_rule_applies() {
+    let rules = HeaderRules::parse(RULES).unwrap();
+    let headers = || {
+        ["Content-Type", "Content-Length", "X-Echo-Method", "X-Echo-Path", "X-Echo-Tunnel-Id", "Server"]
+            .iter()
+            .map(|name| (name.to_string(), HeaderValueBytes::from("v")))
+            .collect::<Vec<_>>()
+    };
+    let names = |headers: Vec<(String, HeaderValueBytes)>| headers.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+
+    let mut site = headers();
+    rules.apply("/index.html", &mut site);
+    assert_eq!(names(site), ["Content-Type", "Content-Length", "X-Echo-Path", "Server"]);
+
+    let mut api = headers();
+    rules.apply("/api/users", &mut api);
+    assert_eq!(names(api), ["Content-Type", "Content-Length", "X-Echo-Path"]);
+}
+
+#[tokio::test]
+async fn headers_are_removed_before_leaving_the_server() {
+    let local = MockLocal::start().await;
+    let state = ServerState::new(None, &TransportOptions::default()).with_header_rules(HeaderRules::parse(RULES).unwrap());
+    let server = TestServer::start_with(state).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let response = reqwest::get(server.url("/page")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().get("x-echo-method").is_none());
+    assert!(response.headers().get("x-echo-tunnel-request-id").is_none());
+    assert_eq!(response.headers()["x-echo-path"], "/page");
+    assert_eq!(response.headers()["x-echo-host"], server.addr.to_string());
+
+    let response = reqwest::Client::new()
+        .post(server.url("/api/items?debug=1"))
+        .body("payload")
+        .send()
+        .await
+        .unwrap();
+    assert!(response.headers().get("x-echo-host").is_none());
+    assert_eq!(response.headers()["x-echo-path"], "/api/items?debug=1");
+    assert_eq!(response.headers()["content-length"], "7");
+    assert_eq!(response.text().await.unwrap(), "payload");
+}
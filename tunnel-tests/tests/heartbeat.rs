@@ -0,0 +1,133 @@
+//! PING/PONG heartbeats (TUNNEL_HEARTBEAT_SECS) keep slow tunnels up and drop
+//! those whose other end went quiet.
+
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tunnel_core::client::{connect_and_upgrade, parse_server_addr, ServerConfig};
+use tunnel_core::transport::TransportOptions;
+use tunnel_protocol::{read_frame, TunnelRequest, PING_FRAME};
+use tunnel_server::ServerState;
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+fn transport() -> TransportOptions {
+    TransportOptions {
+        heartbeat_interval: Some(Duration::from_millis(200)),
+        heartbeat_timeout: Duration::from_millis(300),
+        ..TransportOptions::default()
+    }
+}
+
+/// Client settings offering heartbeats, at most `max_concurrent` requests at once
+fn heartbeat_config(addr: &str, max_concurrent: usize) -> ServerConfig {
+    let mut config = parse_server_addr(&format!("http://{}", addr), None, Vec::new()).unwrap();
+    config.max_concurrent = max_concurrent;
+    config.heartbeat = true;
+    config.transport = transport();
+    config
+}
+
+#[tokio::test]
+async fn slow_requests_keep_the_tunnel_up() {
+    let local = MockLocal::start().await;
+    for max_concurrent in [1, 2] {
+        let server = TestServer::start_with(ServerState::new(None, &transport())).await;
+        let _client = TestClient::start_with_config(heartbeat_config(&server.addr.to_string(), max_concurrent), local.port, &[]);
+        let tunnel_id = server.wait_for_new_tunnel(None).await;
+        assert!(server.state.registry.active().await.unwrap().heartbeat);
+
+        // Every slot busy for several heartbeat timeouts
+        let http = reqwest::Client::new();
+        let requests = (0..max_concurrent).map(|_| {
+            let request = http.get(server.url("/slow")).header("x-delay-ms", "1500");
+            tokio::spawn(async move { request.send().await.unwrap().status() })
+        });
+        for request in requests.collect::<Vec<_>>() {
+            assert_eq!(request.await.unwrap(), 200);
+        }
+
+        assert_eq!(server.tunnel_id().await, Some(tunnel_id));
+        assert_eq!(server.state.registry.worker_stats().unresponsive, 0);
+    }
+}
+
+#[tokio::test]
+async fn large_frames_arriving_slowly_keep_the_tunnel_up() {
+    for max_concurrent in [1, 2] {
+        let server = TestServer::start_with(ServerState::new(None, &transport())).await;
+        let (mut stream, _) = connect_and_upgrade(&heartbeat_config(&server.addr.to_string(), max_concurrent)).await.unwrap();
+        let tunnel_id = server.wait_for_new_tunnel(None).await;
+        let response = tokio::spawn(reqwest::get(server.url("/large")));
+
+        // Fake client that never answers PINGs, sending a 2 MB response over several heartbeat timeouts
+        let request = loop {
+            let frame = read_frame(&mut stream).await.unwrap();
+            if frame != PING_FRAME {
+                break serde_json::from_slice::<TunnelRequest>(&frame).unwrap();
+            }
+        };
+        let id = request.id.map(|id| format!(r#""id":{},"#, id)).unwrap_or_default();
+        let frame = format!(r#"{{{}"status":200,"headers":[],"body":"{}"}}"#, id, "QUFB".repeat(500_000));
+        stream.write_all(&(frame.len() as u32).to_be_bytes()).await.unwrap();
+        for piece in frame.as_bytes().chunks(64 * 1024) {
+            stream.write_all(piece).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let response = response.await.unwrap().unwrap();
+        assert_eq!(response.status(), 200, "max_concurrent={}", max_concurrent);
+        assert_eq!(response.bytes().await.unwrap().len(), 1_500_000);
+        assert_eq!(server.state.registry.worker_stats().unresponsive, 0);
+        assert_eq!(server.tunnel_id().await, Some(tunnel_id));
+    }
+}
+
+#[tokio::test]
+async fn server_drops_a_client_that_stops_reading() {
+    let server = TestServer::start_with(ServerState::new(None, &transport())).await;
+    let (stream, handshake) = connect_and_upgrade(&heartbeat_config(&server.addr.to_string(), 1)).await.unwrap();
+    assert!(handshake.heartbeat);
+    server.wait_for_new_tunnel(None).await;
+
+    // Held open, but never read, so no PONG comes back
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while server.state.registry.worker_stats().unresponsive == 0 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("client was never dropped");
+    assert!(server.state.registry.active().await.is_none());
+    drop(stream);
+}
+
+#[tokio::test]
+async fn client_reconnects_when_the_server_stops_answering() {
+    // Fake server that agrees to heartbeats, then never reads again
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (accepted_tx, mut accepted_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0u8];
+                stream.read_exact(&mut byte).await.unwrap();
+                head.push(byte[0]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: tunnel\r\nConnection: Upgrade\r\nX-Tunnel-Heartbeat: true\r\n\r\n")
+                .await
+                .unwrap();
+            held.push(stream);
+            let _ = accepted_tx.send(());
+        }
+    });
+
+    let local = MockLocal::start().await;
+    let _client = TestClient::start_with_config(heartbeat_config(&addr, 1), local.port, &[]);
+    for _ in 0..2 {
+        tokio::time::timeout(Duration::from_secs(5), accepted_rx.recv()).await.expect("client did not reconnect").unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+//! Tunnels the client asks to be served over HTTPS only.
+
+use std::net::{IpAddr, Ipv4Addr};
+use tunnel_core::client::parse_server_addr;
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::internal_routes::parse_network;
+use tunnel_server::ServerState;
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+/// Address of the reverse proxy the server trusts in these tests
+const PROXY: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+
+/// Starts a server with one HTTPS-only tunnel to `local`, trusting X-Forwarded-Proto from PROXY
+async fn start_https_only(local: &MockLocal) -> (TestServer, TestClient) {
+    let state = ServerState::new(None, &TransportOptions::default()).with_trusted_proxies(vec![parse_network("127.0.0.2").unwrap()]);
+    let server = TestServer::start_with(state).await;
+    let mut config = parse_server_addr(&format!("http://{}", server.addr), None, Vec::new()).unwrap();
+    config.https_only = true;
+    let client = TestClient::start_with_config(config, local.port, &[]);
+    server.wait_for_new_tunnel(None).await;
+    (server, client)
+}
+
+#[tokio::test]
+async fn plain_http_visitors_are_redirected_to_https() {
+    let local = MockLocal::start().await;
+    let (server, _client) = start_https_only(&local).await;
+    let http = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none()).build().unwrap();
+
+    let response = http.post(server.url("/login?next=%2Fhome")).body("secret").send().await.unwrap();
+    assert_eq!(response.status(), 308);
+    assert_eq!(response.headers()["location"], "https://127.0.0.1/login?next=%2Fhome");
+    assert_eq!(response.headers()["x-tunnel-error"], "https-required");
+    assert!(response.headers().get("strict-transport-security").is_none());
+    assert!(response.headers().get("x-echo-path").is_none());
+}
+
+#[tokio::test]
+async fn https_visitors_are_forwarded_with_hsts() {
+    let local = MockLocal::start().await;
+    let (server, _client) = start_https_only(&local).await;
+
+    // As sent by a TLS-terminating reverse proxy
+    let proxy = reqwest::Client::builder().local_address(IpAddr::V4(PROXY)).build().unwrap();
+    let response = proxy
+        .get(server.url("/dashboard"))
+        .header("x-forwarded-proto", "https")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-echo-path"], "/dashboard");
+    assert_eq!(response.headers()["strict-transport-security"], "max-age=31536000");
+}
+
+#[tokio::test]
+async fn forwarded_proto_from_other_peers_is_ignored() {
+    let local = MockLocal::start().await;
+    let (server, _client) = start_https_only(&local).await;
+    let http = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none()).build().unwrap();
+
+    let response = http.get(server.url("/dashboard")).header("x-forwarded-proto", "https").send().await.unwrap();
+    assert_eq!(response.status(), 308);
+    assert_eq!(response.headers()["x-tunnel-error"], "https-required");
+    assert!(response.headers().get("x-echo-path").is_none());
+}
+
+#[tokio::test]
+async fn other_tunnels_serve_plain_http() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let response = reqwest::get(server.url("/")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().get("strict-transport-security").is_none());
+}
@@ -0,0 +1,95 @@
+//! Routes only visitors from listed networks may reach (INTERNAL_ROUTES_FILE).
+
+use std::net::{IpAddr, Ipv4Addr};
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::internal_routes::InternalRoutes;
+use tunnel_server::ServerState;
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+fn ip(addr: &str) -> Option<IpAddr> {
+    Some(addr.parse().unwrap())
+}
+
+#[test]
+fn matching_routes_admit_listed_networks_only() {
+    let routes = InternalRoutes::parse("# internal\n/admin 10.0.0.0/8, 192.0.2.7\n\n/admin/billing 10.1.0.0/16\n").unwrap();
+    assert_eq!(routes.len(), 2);
+
+    assert!(routes.admits("/", ip("203.0.113.1")));
+    assert!(routes.admits("/about?admin=1", ip("203.0.113.1")));
+    assert!(routes.admits("/admin/users", ip("10.9.9.9")));
+    assert!(routes.admits("/admin", ip("192.0.2.7")));
+    assert!(!routes.admits("/admin/users", ip("203.0.113.1")));
+    assert!(!routes.admits("/admin", None));
+
+    // Every matching rule must admit the visitor
+    assert!(routes.admits("/admin/billing/1", ip("10.1.2.3")));
+    assert!(!routes.admits("/admin/billing/1", ip("10.9.9.9")));
+    assert!(!routes.admits("/admin/billing/1", ip("192.0.2.7")));
+
+    // IPv4 visitors of a dual-stack listener
+    assert!(routes.admits("/admin", ip("::ffff:10.0.0.1")));
+}
+
+#[test]
+fn disguised_paths_still_match() {
+    let routes = InternalRoutes::parse("/admin/ 10.0.0.0/8").unwrap();
+    for path in ["/admin", "/admin/", "//admin/x", "/%61dmin/x", "/%2Fadmin/x", "/public/../admin/x", "/./admin/x", "/admin%2fx", "\\admin\\x"] {
+        assert!(!routes.admits(path, ip("203.0.113.1")), "{}", path);
+    }
+    for path in ["/administrator", "/public/admin/x", "/%2561dmin/x"] {
+        assert!(routes.admits(path, ip("203.0.113.1")), "{}", path);
+    }
+}
+
+#[test]
+fn prefixes_match_whole_segments() {
+    for rule in ["/admin 10.0.0.0/8", "/admin/ 10.0.0.0/8"] {
+        let routes = InternalRoutes::parse(rule).unwrap();
+        for path in ["/admin", "/admin/", "/admin/users", "/admin?x=1"] {
+            assert!(!routes.admits(path, ip("203.0.113.1")), "{} {}", rule, path);
+        }
+        for path in ["/administrator", "/admin-panel/x", "/adm"] {
+            assert!(routes.admits(path, ip("203.0.113.1")), "{} {}", rule, path);
+        }
+    }
+}
+
+#[test]
+fn malformed_rules_are_rejected() {
+    for (contents, error) in [
+        ("admin 10.0.0.0/8", "line 1: path prefix must start with '/'"),
+        ("/admin", "line 1: expected '<path-prefix> <network>[,<network>...]'"),
+        ("\n/admin 10.0.0.0/33", "line 2: invalid network '10.0.0.0/33' (expected a CIDR block or an address)"),
+        ("/admin intranet", "line 1: invalid network 'intranet' (expected a CIDR block or an address)"),
+    ] {
+        assert_eq!(InternalRoutes::parse(contents).unwrap_err(), error, "{}", contents);
+    }
+}
+
+#[tokio::test]
+async fn internal_routes_are_refused_to_other_visitors() {
+    let local = MockLocal::start().await;
+    let routes = InternalRoutes::parse("/admin 127.0.0.2").unwrap();
+    let state = ServerState::new(None, &TransportOptions::default()).with_internal_routes(routes);
+    let server = TestServer::start_with(state).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let from = |addr: Ipv4Addr| reqwest::Client::builder().local_address(IpAddr::V4(addr)).build().unwrap();
+    let (public, internal) = (from(Ipv4Addr::new(127, 0, 0, 1)), from(Ipv4Addr::new(127, 0, 0, 2)));
+
+    let response = public.get(server.url("/")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    for path in ["/admin/users", "/%61dmin/users"] {
+        let response = public.get(server.url(path)).send().await.unwrap();
+        assert_eq!(response.status(), 403, "{}", path);
+        assert_eq!(response.headers()["x-tunnel-error"], "internal-route");
+        assert!(response.headers().get("x-echo-path").is_none());
+    }
+
+    let response = internal.get(server.url("/admin/users")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-echo-path"], "/admin/users");
+}
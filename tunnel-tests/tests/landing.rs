@@ -0,0 +1,78 @@
+//! What visitors get while no tunnel client is connected: 503, a landing page or a redirect.
+
+use std::collections::HashMap;
+use tunnel_core::config::ConfigSource;
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::landing::Landing;
+use tunnel_server::settings::ServerSettings;
+use tunnel_server::ServerState;
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+const PAGE: &str = "<h1>Nothing here</h1><p>This tunnel is not running.</p>";
+
+#[tokio::test]
+async fn default_is_a_plain_503() {
+    let server = TestServer::start(None).await;
+    let response = reqwest::get(server.url("/anything")).await.unwrap();
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.headers()["x-tunnel-error"], "no-tunnel");
+    assert_eq!(response.text().await.unwrap(), "No tunnel client connected");
+}
+
+#[tokio::test]
+async fn landing_page_is_served_until_a_client_connects() {
+    let path = std::env::temp_dir().join(format!("tunnel-landing-{}.html", std::process::id()));
+    std::fs::write(&path, PAGE).unwrap();
+    let landing = Landing::load_page(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let local = MockLocal::start().await;
+    let state = ServerState::new(None, &TransportOptions::default()).with_landing(landing);
+    let server = TestServer::start_with(state).await;
+
+    let response = reqwest::get(server.url("/typo")).await.unwrap();
+    assert_eq!(response.status(), 404);
+    assert_eq!(response.headers()["content-type"], "text/html; charset=utf-8");
+    assert_eq!(response.headers()["cache-control"], "no-store");
+    assert_eq!(response.headers()["x-tunnel-error"], "no-tunnel");
+    assert_eq!(response.text().await.unwrap(), PAGE);
+
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+    let response = reqwest::get(server.url("/typo")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-echo-path"], "/typo");
+}
+
+#[tokio::test]
+async fn visitors_can_be_redirected() {
+    let landing = Landing::redirect("https://docs.example.com/tunnels").unwrap();
+    let state = ServerState::new(None, &TransportOptions::default()).with_landing(landing);
+    let server = TestServer::start_with(state).await;
+
+    let http = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none()).build().unwrap();
+    let response = http.post(server.url("/webhook")).body("payload").send().await.unwrap();
+    assert_eq!(response.status(), 302);
+    assert_eq!(response.headers()["location"], "https://docs.example.com/tunnels");
+    assert_eq!(response.headers()["x-tunnel-error"], "no-tunnel");
+}
+
+#[test]
+fn settings_are_validated() {
+    for url in ["docs.example.com", "ftp://example.com", "https://", "https:///path"] {
+        let e = Landing::redirect(url).unwrap_err();
+        assert!(e.starts_with("Invalid NO_TUNNEL_REDIRECT_URL"), "{}: {}", url, e);
+    }
+    assert!(Landing::load_page(std::path::Path::new("/nonexistent/landing.html")).is_err());
+
+    let vars = HashMap::from([
+        ("NO_TUNNEL_PAGE_FILE", "/srv/landing.html"),
+        ("NO_TUNNEL_REDIRECT_URL", "https://docs.example.com"),
+    ]);
+    let (source, _) = ConfigSource::load(ServerSettings::keys(), "SERVER_CONFIG", Vec::<String>::new(), |key| {
+        vars.get(key).map(|value| value.to_string())
+    })
+    .unwrap();
+    let e = ServerSettings::from_source(&source).err().unwrap();
+    assert!(e.contains("cannot be set together"), "{}", e);
+}
@@ -0,0 +1,44 @@
+//! Moving the client to another local port at runtime, over the control socket,
+//! keeps the tunnel (and so the public URL) it is serving.
+
+#![cfg(unix)]
+
+use tunnel_client::control::{execute, ControlContext};
+use tunnel_core::logging::{self, LogOptions};
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+#[tokio::test]
+async fn local_port_is_changed_without_reconnecting() {
+    // The dev server went away from its old port
+    let old_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let server = TestServer::start(None).await;
+    let client = TestClient::start(server.addr, old_port, None);
+    let tunnel_id = server.wait_for_new_tunnel(None).await;
+    let (log, _guard) = logging::init(&LogOptions::default()).unwrap();
+    let context = ControlContext {
+        log,
+        status: client.status.clone(),
+        captures: client.captures.clone(),
+        local: client.local.clone(),
+    };
+
+    let reply = execute(&context, "local-port");
+    assert_eq!(reply["targets"][0], format!("http://127.0.0.1:{}", old_port));
+    assert_eq!(reqwest::get(server.url("/")).await.unwrap().status(), 502);
+
+    // And came back on another one
+    let new_local = MockLocal::start().await;
+    let reply = execute(&context, &format!("local-port {}", new_local.port));
+    assert_eq!(reply["ok"], true);
+    assert_eq!(reply["targets"][0], format!("http://127.0.0.1:{}", new_local.port));
+
+    let response = reqwest::get(server.url("/after")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-echo-path"], "/after");
+    assert_eq!(server.tunnel_id().await, Some(tunnel_id));
+
+    // A bad port list changes nothing
+    let reply = execute(&context, "local-port 3000,nope");
+    assert_eq!(reply["ok"], false);
+    assert_eq!(client.local.base_urls(), vec![format!("http://127.0.0.1:{}", new_local.port)]);
+}
@@ -0,0 +1,110 @@
+//! Changing the log level at runtime through the server admin API and the
+//! client control socket.
+//!
+//! The subscriber is process-global, so every test shares one handle and
+//! holds `LOCK` while it changes the level.
+
+#![cfg(unix)]
+
+use serde_json::Value;
+use std::sync::{Arc, OnceLock};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::sync::Mutex;
+use tunnel_client::capture::{CaptureLog, CaptureOptions};
+use tunnel_client::control::{self, ControlContext};
+use tunnel_client::local::{LocalConfig, LocalService, LocalTarget};
+use tunnel_client::status::StatusHandle;
+use tunnel_core::client::parse_server_addr;
+use tunnel_core::logging::{self, LogGuard, LogHandle, LogOptions};
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::ServerState;
+use tunnel_tests::TestAdmin;
+
+static LOCK: Mutex<()> = Mutex::const_new(());
+
+fn log_handle() -> LogHandle {
+    static LOGGING: OnceLock<(LogHandle, LogGuard)> = OnceLock::new();
+    LOGGING.get_or_init(|| logging::init(&LogOptions::default()).unwrap()).0.clone()
+}
+
+/// Sends one control command and parses the reply
+async fn send(stream: &mut BufReader<UnixStream>, command: &str) -> Value {
+    stream.write_all(format!("{}\n", command).as_bytes()).await.unwrap();
+    let mut reply = String::new();
+    stream.read_line(&mut reply).await.unwrap();
+    serde_json::from_str(&reply).unwrap()
+}
+
+#[tokio::test]
+async fn admin_api_changes_the_server_log_level() {
+    let _lock = LOCK.lock().await;
+    let state = ServerState::new(None, &TransportOptions::default()).with_log_handle(log_handle());
+    let admin = TestAdmin::start(state).await;
+    let url = admin.url("/api/log-level");
+    let http = reqwest::Client::new();
+
+    let level: Value = http.get(&url).send().await.unwrap().json().await.unwrap();
+    assert_eq!(level["level"], "info");
+    assert_eq!(level["configured"], "info");
+
+    let response = http.put(&url).json(&serde_json::json!({"level": "debug"})).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    let level: Value = response.json().await.unwrap();
+    assert_eq!(level["level"], "debug");
+    assert_eq!(log_handle().level(), "debug");
+
+    let response = http.put(&url).json(&serde_json::json!({"level": "info,=["})).send().await.unwrap();
+    assert_eq!(response.status(), 400);
+    assert_eq!(log_handle().level(), "debug");
+
+    let level: Value = http.delete(&url).send().await.unwrap().json().await.unwrap();
+    assert_eq!(level["level"], "info");
+}
+
+#[tokio::test]
+async fn admin_api_without_a_log_handle_is_unavailable() {
+    let admin = TestAdmin::start(ServerState::new(None, &TransportOptions::default())).await;
+    let url = admin.url("/api/log-level");
+    let response = reqwest::get(&url).await.unwrap();
+    assert_eq!(response.status(), 503);
+}
+
+#[tokio::test]
+async fn control_socket_changes_the_client_log_level() {
+    let _lock = LOCK.lock().await;
+    let path = std::env::temp_dir().join(format!("tunnel-control-{}.sock", std::process::id()));
+    let listener = control::bind(&path).unwrap();
+    let server_config = parse_server_addr("127.0.0.1:7000", None, Vec::new()).unwrap();
+    let local_config = LocalConfig::from_source(|_| None).unwrap();
+    let local_service = LocalService::new(&local_config).unwrap();
+    let context = Arc::new(ControlContext {
+        log: log_handle(),
+        status: StatusHandle::new(&server_config),
+        captures: CaptureLog::new(CaptureOptions::default(), Arc::default()),
+        local: LocalTarget::new(local_config, local_service).0,
+    });
+    tokio::spawn(control::serve(listener, context));
+
+    let mut stream = BufReader::new(UnixStream::connect(&path).await.unwrap());
+    let reply = send(&mut stream, "log-level debug").await;
+    assert_eq!(reply["ok"], true);
+    assert_eq!(reply["level"], "debug");
+    assert_eq!(log_handle().level(), "debug");
+
+    let reply = send(&mut stream, "log-level info,=[").await;
+    assert_eq!(reply["ok"], false);
+
+    let reply = send(&mut stream, "log-level reset").await;
+    assert_eq!(reply["level"], "info");
+    assert_eq!(reply["configured"], "info");
+
+    let reply = send(&mut stream, "frobnicate").await;
+    assert_eq!(reply["ok"], false);
+    assert!(reply["error"].as_str().unwrap().contains("Unknown command"));
+
+    // A stale socket from a previous run is replaced
+    drop(stream);
+    assert!(control::bind(&path).is_ok());
+    std::fs::remove_file(&path).unwrap();
+}
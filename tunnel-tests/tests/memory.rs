@@ -0,0 +1,46 @@
+//! Client memory limits: the per-request body budget and the memory watchdog.
+
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+#[tokio::test]
+async fn request_and_response_bodies_share_the_buffer_budget() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start_with(server.addr, local.port, None, &[("LOCAL_MAX_BUFFERED_BYTES", "1000")]);
+    server.wait_for_new_tunnel(None).await;
+    let http = reqwest::Client::new();
+
+    // The echoed body fits twice
+    let response = http.post(server.url("/")).body(vec![b'a'; 400]).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.bytes().await.unwrap().len(), 400);
+
+    // The request fits, but its echo does not fit in what is left
+    let response = http.post(server.url("/")).body(vec![b'a'; 600]).send().await.unwrap();
+    assert_eq!(response.status(), 502);
+
+    // The request alone is over the budget and never reaches the local service
+    let response = http.post(server.url("/")).body(vec![b'a'; 1200]).send().await.unwrap();
+    assert_eq!(response.status(), 413);
+
+    // The tunnel is still usable
+    let response = http.post(server.url("/")).body("ok").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn watchdog_counts_each_time_memory_goes_over_the_limit() {
+    use tunnel_client::memory;
+
+    let before = memory::warnings();
+    assert!(memory::check(1));
+    assert!(memory::check(1));
+    assert_eq!(memory::warnings(), before + 1);
+    assert!(memory::take_pressure());
+    assert!(!memory::take_pressure());
+
+    assert!(!memory::check(u64::MAX));
+    assert!(memory::check(1));
+    assert_eq!(memory::warnings(), before + 2);
+}
@@ -0,0 +1,66 @@
+//! Requests in flight at once over one tunnel connection (TUNNEL_MAX_CONCURRENT).
+
+use std::time::{Duration, Instant};
+use tunnel_core::client::parse_server_addr;
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+/// Sends a slow request, then a fast one while it is in flight; returns how long the fast one took
+async fn fast_behind_slow(server: &TestServer) -> Duration {
+    let http = reqwest::Client::new();
+    let slow = tokio::spawn(http.get(server.url("/slow")).header("x-delay-ms", "1500").send());
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let started = Instant::now();
+    let response = http.get(server.url("/fast")).send().await.unwrap();
+    assert_eq!(response.headers()["x-echo-path"], "/fast");
+    let elapsed = started.elapsed();
+
+    let response = slow.await.unwrap().unwrap();
+    assert_eq!(response.headers()["x-echo-path"], "/slow");
+    elapsed
+}
+
+#[tokio::test]
+async fn slow_requests_do_not_hold_up_others() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+    assert_eq!(server.state.registry.active().await.unwrap().max_concurrent, 32);
+
+    assert!(fast_behind_slow(&server).await < Duration::from_millis(800));
+}
+
+#[tokio::test]
+async fn responses_reach_their_own_requests() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+    let http = reqwest::Client::new();
+
+    // Later requests finish first, so responses come back out of order
+    let requests: Vec<_> = (0..50)
+        .map(|i| {
+            let request = http.get(server.url(&format!("/item/{}", i))).header("x-delay-ms", (250 - i * 5).to_string());
+            tokio::spawn(request.send())
+        })
+        .collect();
+    for (i, request) in requests.into_iter().enumerate() {
+        let response = request.await.unwrap().unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["x-echo-path"], format!("/item/{}", i).as_str());
+    }
+}
+
+#[tokio::test]
+async fn clients_that_do_not_multiplex_answer_one_at_a_time() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let config = parse_server_addr(&format!("http://{}", server.addr), None, Vec::new()).unwrap();
+    let _client = TestClient::start_with_config(config, local.port, &[]);
+    server.wait_for_new_tunnel(None).await;
+    assert_eq!(server.state.registry.active().await.unwrap().max_concurrent, 1);
+
+    assert!(fast_behind_slow(&server).await > Duration::from_millis(1000));
+}
@@ -0,0 +1,72 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+/// Sends `GET <target>` as written (an HTTP client would normalize it) and returns
+/// the status code and the path and query the local service saw
+async fn forward(server: &TestServer, target: &str) -> (u16, Option<String>) {
+    let mut stream = TcpStream::connect(server.addr).await.unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n", target);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+
+    let response = String::from_utf8(response).unwrap();
+    let head = response.split("\r\n\r\n").next().unwrap();
+    let status = head[9..12].parse().unwrap();
+    let path = head
+        .lines()
+        .find_map(|line| line.strip_prefix("x-echo-path: "))
+        .map(str::to_string);
+    (status, path)
+}
+
+#[tokio::test]
+async fn targets_reach_the_local_service_byte_for_byte() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    for target in [
+        "/hook?id=1&id=2&id=1",
+        "/hook?a=1&a=1&&b=",
+        "/a%2Fb%2fc?sig=abc%3D%3d",
+        "/%7e/~?%7E=%41&x=%20+",
+        "/a//b;v=1,2?",
+        "/files/caf%C3%A9?q=%E2%9C%93",
+    ] {
+        assert_eq!(forward(&server, target).await, (200, Some(target.to_string())));
+    }
+}
+
+#[tokio::test]
+async fn targets_the_local_client_would_change_are_refused() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    for target in ["/a/./b", "/a/../b", "/a/%2e%2E/b", "/{id}", "/a\\b"] {
+        assert_eq!(forward(&server, target).await, (400, None), "{}", target);
+    }
+}
+
+#[tokio::test]
+async fn normalize_mode_forwards_normalized_targets() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start_with(server.addr, local.port, None, &[("LOCAL_PATH_MODE", "normalize")]);
+    server.wait_for_new_tunnel(None).await;
+
+    for (target, normalized) in [
+        ("/a/./b/../c", "/a/c"),
+        ("/a/%2e%2E/b", "/b"),
+        ("/%7e%41?%7e=%2f", "/~A?~=%2F"),
+        ("/{id}?x={1}", "/%7Bid%7D?x={1}"),
+        ("/a\\b", "/a/b"),
+        ("/hook?id=1&id=2", "/hook?id=1&id=2"),
+    ] {
+        assert_eq!(forward(&server, target).await, (200, Some(normalized.to_string())), "{}", target);
+    }
+}
@@ -0,0 +1,70 @@
+//! Requests the server accepted are held while the client reconnects, with
+//! TUNNEL_RECONNECT_GRACE_MS set, instead of failing right away.
+
+use std::time::Duration;
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::ServerState;
+use tunnel_tests::{MockLocal, TcpProxy, TestClient, TestServer};
+
+#[tokio::test]
+async fn queued_requests_are_resent_after_a_reconnect() {
+    let local = MockLocal::start().await;
+    let state = ServerState::new(None, &TransportOptions::default()).with_reconnect_grace(Some(Duration::from_secs(5)));
+    let server = TestServer::start_with(state).await;
+    let proxy = TcpProxy::start(server.addr).await;
+    let _client = TestClient::start(proxy.addr, local.port, None);
+    let first = server.wait_for_new_tunnel(None).await;
+
+    // The slow request reaches the client; the second one queues behind it
+    let http = reqwest::Client::new();
+    let slow = tokio::spawn(http.get(server.url("/slow")).header("x-delay-ms", "500").send());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let queued = tokio::spawn(http.get(server.url("/queued")).send());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    proxy.cut_connections();
+
+    // The client may have run the slow request, so it is not sent again
+    assert_eq!(slow.await.unwrap().unwrap().status(), 502);
+    let queued = queued.await.unwrap().unwrap();
+    assert_eq!(queued.status(), 200);
+    assert_eq!(queued.headers()["x-echo-path"], "/queued");
+    assert_ne!(server.tunnel_id().await, Some(first));
+}
+
+#[tokio::test]
+async fn requests_arriving_while_the_client_is_away_wait_for_it() {
+    let local = MockLocal::start().await;
+    let state = ServerState::new(None, &TransportOptions::default()).with_reconnect_grace(Some(Duration::from_secs(5)));
+    let server = TestServer::start_with(state.clone()).await;
+    let proxy = TcpProxy::start(server.addr).await;
+    let client = TestClient::start(proxy.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    // Stop the client so the tunnel goes away without coming back by itself
+    drop(client);
+    proxy.cut_connections();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while state.registry.active().await.is_some() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("tunnel was never removed");
+
+    let waiting = tokio::spawn(reqwest::get(server.url("/while-away")));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let _client = TestClient::start(proxy.addr, local.port, None);
+
+    let response = waiting.await.unwrap().unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-echo-path"], "/while-away");
+}
+
+#[tokio::test]
+async fn without_a_grace_period_requests_fail_right_away() {
+    let state = ServerState::new(None, &TransportOptions::default());
+    let server = TestServer::start_with(state).await;
+    let response = reqwest::get(server.url("/nobody-home")).await.unwrap();
+    assert_eq!(response.status(), 503);
+}
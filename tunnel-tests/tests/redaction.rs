@@ -0,0 +1,69 @@
+//! Redaction rules applied to captured requests on the client and request listings on the server.
+
+use serde_json::Value;
+use std::time::Duration;
+use tunnel_core::redact::{Redactor, REDACTED};
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::ServerState;
+use tunnel_tests::{MockLocal, TestAdmin, TestClient, TestServer};
+
+const RULES: &str = "header x-api-key\njson password\nregex token=[^&]+\n";
+
+#[tokio::test]
+async fn captured_requests_are_redacted() {
+    let rules = std::env::temp_dir().join(format!("tunnel-redact-{}.rules", std::process::id()));
+    std::fs::write(&rules, RULES).unwrap();
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let settings = [("CAPTURE_BODIES", "true"), ("REDACT_RULES_FILE", rules.to_str().unwrap())];
+    let client = TestClient::start_with(server.addr, local.port, None, &settings);
+    server.wait_for_new_tunnel(None).await;
+
+    let response = reqwest::Client::new()
+        .post(server.url("/login?token=abc&next=home"))
+        .header("x-api-key", "k-123")
+        .header("content-type", "application/json")
+        .body(r#"{"user":"ann","password":"hunter2"}"#)
+        .send()
+        .await
+        .unwrap();
+    // Only what is kept is redacted; the local service got the real request
+    assert_eq!(response.text().await.unwrap(), r#"{"user":"ann","password":"hunter2"}"#);
+
+    let exchange = &client.captures.list()[0];
+    assert_eq!(exchange.path, "/login?[redacted]&next=home");
+    let api_key = exchange.request_headers.iter().find(|(name, _)| name == "x-api-key").unwrap();
+    assert_eq!(api_key.1, REDACTED);
+    for body in [&exchange.request_body, &exchange.response_body] {
+        let json: Value = serde_json::from_str(&body.as_ref().unwrap().data).unwrap();
+        assert_eq!(json, serde_json::json!({"user": "ann", "password": REDACTED}));
+    }
+    std::fs::remove_file(rules).unwrap();
+}
+
+#[tokio::test]
+async fn listed_request_paths_are_redacted() {
+    let local = MockLocal::start().await;
+    let state = ServerState::new(None, &TransportOptions::default()).with_redactor(Redactor::parse(RULES).unwrap());
+    let server = TestServer::start_with(state.clone()).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let admin = TestAdmin::start(state).await;
+    let admin_url = admin.url("/api/requests");
+
+    let request = tokio::spawn(reqwest::Client::new().get(server.url("/reset/token=abc")).header("x-delay-ms", "500").send());
+    let listed = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let list: Value = reqwest::get(&admin_url).await.unwrap().json().await.unwrap();
+            if let Some(request) = list["requests"].as_array().and_then(|requests| requests.first()) {
+                return request.clone();
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("request was never listed");
+    assert_eq!(listed["path"], "/reset/[redacted]");
+    assert_eq!(request.await.unwrap().unwrap().status(), 200);
+}
@@ -0,0 +1,43 @@
+//! Tags the local service sets with X-Tunnel-Tag, recorded by the client and
+//! stripped by the server.
+
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+#[tokio::test]
+async fn tags_are_captured_and_reported_but_not_passed_on() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let response = reqwest::Client::new()
+        .post(server.url("/webhook"))
+        .header("x-echo-tag", "order=A-1042, event=payment.succeeded")
+        .header("x-echo-tag", "not a tag")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().get("x-tunnel-tag").is_none());
+
+    let captured = client.captures.list();
+    let expected = vec![("order".to_string(), "A-1042".to_string()), ("event".to_string(), "payment.succeeded".to_string())];
+    assert_eq!(captured[0].tags, expected);
+
+    let stats = server.state.registry.active().await.unwrap().peer_stats().unwrap();
+    assert_eq!(stats.report.tagged.get("order"), Some(&1));
+    assert_eq!(stats.report.tagged.get("event"), Some(&1));
+}
+
+#[tokio::test]
+async fn untagged_responses_have_no_tags() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    reqwest::get(server.url("/")).await.unwrap();
+    assert!(client.captures.list()[0].tags.is_empty());
+    let stats = server.state.registry.active().await.unwrap().peer_stats().unwrap();
+    assert!(stats.report.tagged.is_empty());
+}
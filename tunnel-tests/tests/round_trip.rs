@@ -0,0 +1,373 @@
+use reqwest::header::HeaderValue;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tunnel_core::client::{connect_and_upgrade, parse_server_addr, ConnectError, UpgradeError};
+use tunnel_protocol::{decode_response_frame, encode_body, TunnelRequest, TunnelResponse};
+use tunnel_tests::{start_fake_client, MockLocal, TcpProxy, TestClient, TestServer};
+
+#[tokio::test]
+async fn request_without_client_is_503() {
+    let server = TestServer::start(None).await;
+
+    let response = reqwest::get(server.url("/")).await.unwrap();
+    assert_eq!(response.status(), 503);
+}
+
+#[tokio::test]
+async fn method_path_and_query_reach_local_service() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let response = reqwest::Client::new()
+        .delete(server.url("/api/items/42?force=true&tag=a%20b"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-echo-method"], "DELETE");
+    assert_eq!(response.headers()["x-echo-path"], "/api/items/42?force=true&tag=a%20b");
+}
+
+#[tokio::test]
+async fn binary_body_round_trips_unchanged() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let body: Vec<u8> = (0..=255u8).cycle().take(4096).collect();
+    let response = reqwest::Client::new()
+        .post(server.url("/upload"))
+        .header("content-type", "application/octet-stream")
+        .body(body.clone())
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "application/octet-stream");
+    assert_eq!(response.bytes().await.unwrap().as_ref(), body.as_slice());
+}
+
+#[tokio::test]
+async fn large_payload_round_trips_unchanged() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let body: Vec<u8> = (0..8 * 1024 * 1024).map(|i| (i * 31 % 251) as u8).collect();
+    let response = reqwest::Client::new()
+        .put(server.url("/large"))
+        .body(body.clone())
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    let received = response.bytes().await.unwrap();
+    assert_eq!(received.len(), body.len());
+    assert!(received.as_ref() == body.as_slice());
+}
+
+#[tokio::test]
+async fn unreachable_local_service_is_502() {
+    // Bind and drop a listener to get a port nothing listens on
+    let unused_port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, unused_port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let response = reqwest::get(server.url("/")).await.unwrap();
+    assert_eq!(response.status(), 502);
+}
+
+#[tokio::test]
+async fn wrong_credentials_are_rejected() {
+    let server = TestServer::start(Some("user:secret")).await;
+
+    for auth in [None, Some("user:wrong".to_string())] {
+        let config = parse_server_addr(&format!("http://{}", server.addr), auth, Vec::new()).unwrap();
+        let err = connect_and_upgrade(&config).await.err().unwrap();
+        assert!(matches!(err, ConnectError::Upgrade(UpgradeError::Unauthorized { .. })), "{}", err);
+        assert!(!err.is_retryable());
+    }
+    assert_eq!(server.tunnel_id().await, None);
+}
+
+#[tokio::test]
+async fn client_stops_on_rejected_credentials() {
+    let server = TestServer::start(Some("user:secret")).await;
+    let mut client = TestClient::start(server.addr, 1, Some("user:wrong"));
+
+    // Gives up on the first attempt instead of entering the backoff loop
+    let err = tokio::time::timeout(Duration::from_secs(5), client.wait_for_exit())
+        .await
+        .expect("client kept retrying");
+    assert!(matches!(err, ConnectError::Upgrade(UpgradeError::Unauthorized { .. })), "{}", err);
+}
+
+#[tokio::test]
+async fn correct_credentials_are_accepted() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(Some("user:secret")).await;
+    let _client = TestClient::start(server.addr, local.port, Some("user:secret"));
+    server.wait_for_new_tunnel(None).await;
+
+    let response = reqwest::get(server.url("/ok")).await.unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+#[tokio::test]
+async fn client_connects_once_server_comes_up() {
+    let local = MockLocal::start().await;
+
+    // Reserve the server's port, but start listening only after the client's first attempt failed
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = listener.local_addr().unwrap();
+    drop(listener);
+
+    let _client = TestClient::start(server_addr, local.port, None);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    let server = TestServer::start_on(TcpListener::bind(server_addr).await.unwrap(), None);
+    server.wait_for_new_tunnel(None).await;
+
+    let response = reqwest::get(server.url("/after-retry")).await.unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+#[tokio::test]
+async fn client_reconnects_after_tunnel_drops() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let proxy = TcpProxy::start(server.addr).await;
+    let _client = TestClient::start(proxy.addr, local.port, None);
+    let first = server.wait_for_new_tunnel(None).await;
+
+    proxy.cut_connections();
+    let second = server.wait_for_new_tunnel(Some(first)).await;
+    assert_ne!(first, second);
+
+    let response = reqwest::get(server.url("/after-reconnect")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-echo-path"], "/after-reconnect");
+}
+
+#[tokio::test]
+async fn client_gives_up_at_the_server_deadline() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    // The local service would answer after 10s, well within LOCAL_TIMEOUT_SECS,
+    // but the server only has 200ms left for this request
+    let request = TunnelRequest {
+        method: "GET".to_string(),
+        path: "/slow".to_string(),
+        headers: vec![("x-delay-ms".to_string(), "10000".into())],
+        deadline_ms: Some(200),
+        ..TunnelRequest::default()
+    };
+    let payload = serde_json::to_vec(&request).unwrap();
+    let tunnel = server.state.registry.active().await.unwrap();
+
+    let started = std::time::Instant::now();
+    let response = tunnel.round_trip(payload.into()).await.unwrap();
+    assert_eq!(decode_response_frame(&response).unwrap().0.status, 504);
+    assert!(started.elapsed() < Duration::from_secs(5), "{:?}", started.elapsed());
+}
+
+#[tokio::test]
+async fn client_reports_stats_to_the_server() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    // The first report rides along with the first response
+    let response = reqwest::get(server.url("/")).await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    let stats = server.state.registry.active().await.unwrap().peer_stats().unwrap();
+    assert_eq!(stats.report.requests, 1);
+    assert_eq!(stats.report.errors, 0);
+}
+
+#[tokio::test]
+async fn client_rejects_invalid_methods_and_headers() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+    let tunnel = server.state.registry.active().await.unwrap();
+
+    // Previously an unparseable method was forwarded as GET
+    for (method, headers) in [
+        ("GE T", Vec::new()),
+        ("GET", vec![("x-test".to_string(), "a\r\nInjected: yes".into())]),
+        ("GET", vec![("bad header".to_string(), "value".into())]),
+    ] {
+        let request = TunnelRequest {
+            method: method.to_string(),
+            path: "/".to_string(),
+            headers,
+            ..TunnelRequest::default()
+        };
+        let response = tunnel.round_trip(serde_json::to_vec(&request).unwrap().into()).await.unwrap();
+        assert_eq!(decode_response_frame(&response).unwrap().0.status, 400, "{:?}", request);
+    }
+}
+
+#[tokio::test]
+async fn oversized_request_headers_are_431() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    // Above the client's default 64 KiB limit, which it announced at upgrade
+    let response = reqwest::Client::new()
+        .get(server.url("/"))
+        .header("x-big", "a".repeat(70_000))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 431);
+
+    // The client enforces its limits too, whatever the server sends
+    let request = TunnelRequest {
+        method: "GET".to_string(),
+        path: "/".to_string(),
+        headers: (0..101).map(|i| (format!("x-{}", i), "1".into())).collect(),
+        ..TunnelRequest::default()
+    };
+    let tunnel = server.state.registry.active().await.unwrap();
+    let response = tunnel.round_trip(serde_json::to_vec(&request).unwrap().into()).await.unwrap();
+    assert_eq!(decode_response_frame(&response).unwrap().0.status, 431);
+}
+
+#[tokio::test]
+async fn response_framing_follows_the_forwarded_body() {
+    let server = TestServer::start(None).await;
+    start_fake_client(&server, TunnelResponse {
+        status: 200,
+        headers: vec![
+            ("content-length".to_string(), "999".into()),
+            ("transfer-encoding".to_string(), "chunked".into()),
+            ("x-kept".to_string(), "yes".into()),
+        ],
+        body: encode_body(b"hello"),
+        ..TunnelResponse::default()
+    }).await;
+
+    let http = reqwest::Client::new();
+    let response = http.get(server.url("/")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-length"], "5");
+    assert!(response.headers().get("transfer-encoding").is_none());
+    assert_eq!(response.headers()["x-kept"], "yes");
+    assert_eq!(response.text().await.unwrap(), "hello");
+
+    // HEAD keeps the length of the representation it describes
+    let response = http.head(server.url("/")).send().await.unwrap();
+    assert_eq!(response.headers()["content-length"], "999");
+}
+
+#[tokio::test]
+async fn bodiless_statuses_carry_no_content_length() {
+    let server = TestServer::start(None).await;
+    start_fake_client(&server, TunnelResponse {
+        status: 304,
+        headers: vec![("content-length".to_string(), "999".into())],
+        ..TunnelResponse::default()
+    }).await;
+
+    let response = reqwest::get(server.url("/")).await.unwrap();
+    assert_eq!(response.status(), 304);
+    assert!(response.headers().get("content-length").is_none());
+}
+
+#[tokio::test]
+async fn out_of_range_statuses_are_502() {
+    for status in [0, 99, 1000] {
+        let server = TestServer::start(None).await;
+        start_fake_client(&server, TunnelResponse { status, ..TunnelResponse::default() }).await;
+
+        let response = reqwest::get(server.url("/")).await.unwrap();
+        assert_eq!(response.status(), 502, "status {}", status);
+        // The tunnel is still up
+        assert!(server.state.registry.active().await.is_some());
+    }
+}
+
+/// Sends a raw request to the server and returns the response head
+async fn raw_request(server: &TestServer, request: &str) -> String {
+    let mut stream = TcpStream::connect(server.addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8_lossy(&response).to_lowercase();
+    response.split("\r\n\r\n").next().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn absolute_form_and_http10_requests_are_normalized() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    // The target's host wins over the Host header, and its userinfo is dropped
+    let head = raw_request(
+        &server,
+        "GET http://user:pw@hooks.example.com:8080/notify?id=7 HTTP/1.1\r\nHost: other\r\nConnection: close\r\n\r\n",
+    ).await;
+    assert!(head.starts_with("http/1.1 200"), "{}", head);
+    assert!(head.contains("x-echo-path: /notify?id=7"), "{}", head);
+    assert!(head.contains("x-echo-host: hooks.example.com:8080"), "{}", head);
+
+    // HTTP/1.0 without a Host header gets one from the target
+    let head = raw_request(&server, "GET http://probe.example.com HTTP/1.0\r\n\r\n").await;
+    assert!(head.starts_with("http/1.0 200"), "{}", head);
+    assert!(head.contains("x-echo-path: /\r\n"), "{}", head);
+    assert!(head.contains("x-echo-host: probe.example.com"), "{}", head);
+
+    // Plain HTTP/1.0 still works
+    let head = raw_request(&server, "GET /status HTTP/1.0\r\n\r\n").await;
+    assert!(head.starts_with("http/1.0 200"), "{}", head);
+    assert!(head.contains("content-length: 0"), "{}", head);
+
+    // Targets that cannot be forwarded
+    let head = raw_request(&server, "OPTIONS * HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await;
+    assert!(head.starts_with("http/1.1 400"), "{}", head);
+}
+
+#[tokio::test]
+async fn non_utf8_header_values_survive_the_round_trip() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    // Latin-1 "café" and "naïve", next to a plain value
+    let response = reqwest::Client::new()
+        .get(server.url("/"))
+        .header("x-echo-value", HeaderValue::from_bytes(b"caf\xe9").unwrap())
+        .header("x-echo-value", "plain")
+        .header("x-echo-value", HeaderValue::from_bytes(b"na\xefve").unwrap())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    // In the order they were sent, both ways
+    let values: Vec<&[u8]> = response.headers().get_all("x-echo-value").iter().map(|v| v.as_bytes()).collect();
+    assert_eq!(values, [&b"caf\xe9"[..], b"plain", b"na\xefve"]);
+}
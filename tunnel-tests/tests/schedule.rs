@@ -0,0 +1,55 @@
+//! Visitors routed to a tunnel only within the schedule the client asks for at handshake.
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tunnel_core::client::parse_server_addr;
+use tunnel_protocol::Schedule;
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+/// Starts a server and a client asking to be routed only within `schedule`
+async fn start(schedule: &str) -> (TestServer, TestClient, MockLocal) {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let mut config = parse_server_addr(&format!("http://{}", server.addr), None, Vec::new()).unwrap();
+    config.schedule = Some(Schedule::parse(schedule).unwrap());
+    let client = TestClient::start_with_config(config, local.port, &[]);
+    server.wait_for_new_tunnel(None).await;
+    (server, client, local)
+}
+
+#[tokio::test]
+async fn requests_within_the_schedule_are_forwarded() {
+    let (server, _client, _local) = start("daily 00:00-24:00").await;
+    let response = reqwest::get(server.url("/open")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-echo-path"], "/open");
+}
+
+#[tokio::test]
+async fn requests_outside_the_schedule_are_answered_by_the_server() {
+    // A window opening two hours from now
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    let hour = now / 3600 % 24;
+    let (server, _client, _local) = start(&format!("daily {:02}:00-{:02}:00", (hour + 2) % 24, (hour + 3) % 24)).await;
+
+    let response = reqwest::get(server.url("/closed")).await.unwrap();
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.headers()["x-tunnel-error"], "outside-schedule");
+    assert!(response.headers().get("x-echo-path").is_none());
+    // The tunnel stays up
+    assert!(server.tunnel_id().await.is_some());
+}
+
+#[tokio::test]
+async fn malformed_schedules_fail_the_upgrade() {
+    let server = TestServer::start(None).await;
+    let mut stream = TcpStream::connect(server.addr).await.unwrap();
+    stream
+        .write_all(b"GET /tunnel HTTP/1.1\r\nHost: test\r\nUpgrade: tunnel\r\nConnection: Upgrade\r\nx-tunnel-schedule: weekdays 9-5\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = vec![0u8; 1024];
+    let n = stream.read(&mut response).await.unwrap();
+    assert!(response[..n].starts_with(b"HTTP/1.1 400 "), "{}", String::from_utf8_lossy(&response[..n]));
+    assert_eq!(server.tunnel_id().await, None);
+}
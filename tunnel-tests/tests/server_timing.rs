@@ -0,0 +1,32 @@
+//! Local service latency reported by the client and passed on in Server-Timing.
+
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+/// `dur` of the Server-Timing metric `name`
+fn duration(timing: &str, name: &str) -> u64 {
+    let metric = timing.split(',').map(str::trim).find(|metric| metric.starts_with(&format!("{};", name))).unwrap();
+    metric.split(';').find_map(|param| param.strip_prefix("dur=")).unwrap().parse().unwrap()
+}
+
+#[tokio::test]
+async fn responses_split_the_time_between_local_service_and_tunnel() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let response = reqwest::Client::new().get(server.url("/slow")).header("x-delay-ms", "300").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    let timing = response.headers()["server-timing"].to_str().unwrap();
+    assert!(duration(timing, "local") >= 300, "{}", timing);
+    assert!(duration(timing, "local") < 1000, "{}", timing);
+    assert!(duration(timing, "tunnel") < 300, "{}", timing);
+}
+
+#[tokio::test]
+async fn responses_made_up_by_the_server_carry_no_timing() {
+    let server = TestServer::start(None).await;
+    let response = reqwest::get(server.url("/")).await.unwrap();
+    assert_eq!(response.status(), 503);
+    assert!(response.headers().get("server-timing").is_none());
+}
@@ -0,0 +1,67 @@
+//! Slow request tracking: requests in flight past the threshold show up as
+//! hung in the admin API, and are counted by their slowest phase once done.
+
+use serde_json::Value;
+use std::time::Duration;
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::ServerState;
+use tunnel_tests::{MockLocal, TestAdmin, TestClient, TestServer};
+
+#[tokio::test]
+async fn slow_requests_are_reported_while_hung_and_counted_by_phase() {
+    let local = MockLocal::start().await;
+    let state = ServerState::new(None, &TransportOptions::default())
+        .with_slow_threshold(Some(Duration::from_millis(200)));
+    let server = TestServer::start_with(state.clone()).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+    let admin = TestAdmin::start(state).await;
+    let admin_url = admin.url("/api/slow-requests");
+
+    let request = tokio::spawn(
+        reqwest::Client::new()
+            .get(server.url("/slow?x=1"))
+            .header("x-delay-ms", "1500")
+            .send(),
+    );
+
+    // The local service is still sleeping, so the request waits on the client
+    let hung = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let report: Value = reqwest::get(&admin_url).await.unwrap().json().await.unwrap();
+            if let Some(hung) = report["hung"].as_array().and_then(|hung| hung.first()) {
+                return hung.clone();
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("request was never reported as hung");
+    assert_eq!(hung["method"], "GET");
+    assert_eq!(hung["path"], "/slow");
+    assert_eq!(hung["phase"], "client_processing");
+    assert!(hung["age_ms"].as_u64().unwrap() >= 200);
+
+    assert_eq!(request.await.unwrap().unwrap().status(), 200);
+    let report: Value = reqwest::get(&admin_url).await.unwrap().json().await.unwrap();
+    assert_eq!(report["threshold_ms"], 200);
+    assert_eq!(report["hung"].as_array().unwrap().len(), 0);
+    assert_eq!(report["slow"]["total"], 1);
+    assert_eq!(report["slow"]["client_processing"], 1);
+}
+
+#[tokio::test]
+async fn fast_requests_are_not_counted() {
+    let local = MockLocal::start().await;
+    let state = ServerState::new(None, &TransportOptions::default());
+    let server = TestServer::start_with(state.clone()).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+    let admin = TestAdmin::start(state).await;
+    let admin_url = admin.url("/api/slow-requests");
+
+    assert_eq!(reqwest::get(server.url("/")).await.unwrap().status(), 200);
+    let report: Value = reqwest::get(&admin_url).await.unwrap().json().await.unwrap();
+    assert_eq!(report["threshold_ms"], 5000);
+    assert_eq!(report["slow"]["total"], 0);
+}
@@ -0,0 +1,113 @@
+//! Certificate selection by SNI on the public listener.
+//!
+//! tests/fixtures/sni holds certificates for `alpha.test` and `*.beta.test`
+//! signed by a second P-256 test CA (`sni-ca.pem`), generated with openssl and
+//! valid for ten years, in the TLS_CERT_DIR layout.
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::ClientConfig;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tunnel_core::tls::TlsOptions;
+use tunnel_server::tls::CertStore;
+use tunnel_tests::{fixture, fixture_roots, TestServer};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tunnel-tests-sni-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn copy_cert(from: &str, to: &Path) {
+    for ext in ["pem", "key"] {
+        let name = format!("{}.{}", from, ext);
+        fs::copy(fixture("sni").join(&name), to.join(&name)).unwrap();
+    }
+}
+
+fn default_cert() -> Option<(PathBuf, PathBuf)> {
+    Some((fixture("server.pem"), fixture("server.key")))
+}
+
+async fn start_server(store: Arc<CertStore>) -> TestServer {
+    TestServer::start_tls(store.acceptor().unwrap()).await
+}
+
+/// The first certificate the server presents for `server_name`, or None if the handshake fails
+async fn presented_cert(server: &TestServer, server_name: &str) -> Option<CertificateDer<'static>> {
+    let config: Arc<ClientConfig> = Arc::new(TlsOptions::default().client_config(fixture_roots(&["ca.pem", "sni-ca.pem"])).unwrap());
+
+    let tcp = TcpStream::connect(server.addr).await.unwrap();
+    let server_name = ServerName::try_from(server_name.to_string()).unwrap();
+    let stream = TlsConnector::from(config).connect(server_name, tcp).await.ok()?;
+    stream.get_ref().1.peer_certificates().map(|certs| certs[0].clone())
+}
+
+fn leaf(path: PathBuf) -> CertificateDer<'static> {
+    CertificateDer::pem_file_iter(path).unwrap().next().unwrap().unwrap()
+}
+
+#[tokio::test]
+async fn certificate_is_picked_by_server_name() {
+    let store = CertStore::load(default_cert(), Some(fixture("sni")), &TlsOptions::default()).unwrap();
+    assert_eq!(store.hostnames(), ["*.beta.test", "alpha.test"]);
+    let server = start_server(Arc::new(store)).await;
+
+    assert_eq!(presented_cert(&server, "alpha.test").await, Some(leaf(fixture("sni/alpha.test.pem"))));
+    assert_eq!(presented_cert(&server, "ALPHA.test").await, Some(leaf(fixture("sni/alpha.test.pem"))));
+    assert_eq!(presented_cert(&server, "app.beta.test").await, Some(leaf(fixture("sni/_.beta.test.pem"))));
+
+    // Names without a certificate of their own get TLS_CERT_FILE
+    assert_eq!(presented_cert(&server, "localhost").await, Some(leaf(fixture("server.pem"))));
+}
+
+#[tokio::test]
+async fn unknown_names_fail_without_a_default_certificate() {
+    let store = CertStore::load(None, Some(fixture("sni")), &TlsOptions::default()).unwrap();
+    let server = start_server(Arc::new(store)).await;
+
+    assert!(presented_cert(&server, "alpha.test").await.is_some());
+    assert_eq!(presented_cert(&server, "localhost").await, None);
+    // A wildcard covers a single label only
+    assert_eq!(presented_cert(&server, "a.b.beta.test").await, None);
+}
+
+#[tokio::test]
+async fn reload_picks_up_new_certificates_and_keeps_old_ones_on_error() {
+    let dir = temp_dir("reload");
+    copy_cert("alpha.test", &dir);
+    let store = Arc::new(CertStore::load(None, Some(dir.clone()), &TlsOptions::default()).unwrap());
+    let server = start_server(store.clone()).await;
+    assert_eq!(presented_cert(&server, "app.beta.test").await, None);
+
+    copy_cert("_.beta.test", &dir);
+    store.reload().unwrap();
+    assert_eq!(presented_cert(&server, "app.beta.test").await, Some(leaf(fixture("sni/_.beta.test.pem"))));
+
+    // A certificate whose key is not there yet is an error; the loaded certificates stay in use
+    fs::copy(fixture("server.pem"), dir.join("localhost.pem")).unwrap();
+    let error = store.reload().unwrap_err();
+    assert!(error.contains("localhost.key"), "{}", error);
+    assert_eq!(store.hostnames(), ["*.beta.test", "alpha.test"]);
+    assert!(presented_cert(&server, "alpha.test").await.is_some());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn mismatched_or_missing_certificates_are_rejected() {
+    let dir = temp_dir("invalid");
+    assert!(CertStore::load(None, Some(dir.clone()), &TlsOptions::default()).unwrap_err().contains("contains no certificates"));
+
+    fs::copy(fixture("sni/alpha.test.pem"), dir.join("alpha.test.pem")).unwrap();
+    fs::copy(fixture("server.key"), dir.join("alpha.test.key")).unwrap();
+    let error = CertStore::load(None, Some(dir.clone()), &TlsOptions::default()).unwrap_err();
+    assert!(error.contains("Invalid TLS certificate or key"), "{}", error);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,80 @@
+//! Bodies above LOCAL_SPOOL_THRESHOLD_BYTES go through temp files instead of memory.
+
+use tunnel_client::local::{LocalConfig, LocalService};
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::ServerState;
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+/// A body that is not the same all the way through, so misplaced chunks show
+fn patterned_body(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[tokio::test]
+async fn large_bodies_are_spooled_and_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let local = MockLocal::start().await;
+    let server = TestServer::start_with(ServerState::new(None, &TransportOptions::default()).with_body_checksum(true)).await;
+    let settings = [
+        ("LOCAL_SPOOL_THRESHOLD_BYTES", "1000"),
+        ("LOCAL_SPOOL_DIR", dir.path().to_str().unwrap()),
+        ("LOCAL_MAX_BUFFERED_BYTES", "2000"),
+        ("CAPTURE_BODIES", "true"),
+    ];
+    let client = TestClient::start_with(server.addr, local.port, None, &settings);
+    server.wait_for_new_tunnel(None).await;
+    let http = reqwest::Client::new();
+
+    // Well over the buffer budget, and over several spool chunks, yet echoed intact
+    for len in [1001, 200_000] {
+        let body = patterned_body(len);
+        let response = http.post(server.url("/upload")).body(body.clone()).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.bytes().await.unwrap().as_ref(), body.as_slice(), "{} bytes", len);
+    }
+
+    // Small bodies stay in memory as before
+    let response = http.post(server.url("/small")).body("ok").send().await.unwrap();
+    assert_eq!(response.bytes().await.unwrap().as_ref(), b"ok");
+
+    // A spooled response body is captured by its size only
+    let captured = client.captures.list();
+    let body = captured[1].response_body.as_ref().unwrap();
+    assert_eq!((body.size, body.truncated, body.data.as_str()), (200_000, true, ""));
+
+    // Spool files are gone once their request is done (the last may outlive the response by a moment)
+    for _ in 0..50 {
+        if std::fs::read_dir(dir.path()).unwrap().count() == 0 {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("spool files left in {}", dir.path().display());
+}
+
+#[tokio::test]
+async fn spooled_bodies_still_respect_the_body_limit() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let settings = [("LOCAL_SPOOL_THRESHOLD_BYTES", "1000"), ("LOCAL_MAX_BODY_BYTES", "5000")];
+    let _client = TestClient::start_with(server.addr, local.port, None, &settings);
+    server.wait_for_new_tunnel(None).await;
+    let http = reqwest::Client::new();
+
+    let response = http.post(server.url("/")).body(patterned_body(4000)).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    let response = http.post(server.url("/")).body(patterned_body(6000)).send().await.unwrap();
+    assert_eq!(response.status(), 502);
+}
+
+#[test]
+fn spool_settings_are_checked() {
+    let config = LocalConfig::from_source(|key| (key == "LOCAL_SPOOL_THRESHOLD_BYTES").then(|| "0".to_string())).unwrap();
+    assert_eq!(config.spool_threshold, None);
+
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let path = file.path().to_str().unwrap().to_string();
+    let config = LocalConfig::from_source(|key| (key == "LOCAL_SPOOL_DIR").then(|| path.clone())).unwrap();
+    let e = LocalService::new(&config).err().unwrap();
+    assert_eq!(e.to_string(), format!("LOCAL_SPOOL_DIR {} is not a directory", path));
+}
@@ -0,0 +1,125 @@
+//! Machine-readable status for scripts: the client's `status` control command
+//! and the server's `/api/status`.
+
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tunnel_client::status::ConnectionState;
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::api_keys::ApiKeys;
+use tunnel_server::{admin, ServerState};
+use tunnel_tests::{MockLocal, TestAdmin, TestClient, TestServer};
+
+#[tokio::test]
+async fn client_status_follows_the_connection() {
+    let local = MockLocal::start().await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = listener.local_addr().unwrap();
+    drop(listener);
+
+    // Nothing is listening yet
+    let client = TestClient::start(server_addr, local.port, None);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let status = client.status.snapshot();
+    assert_eq!(status.state, ConnectionState::Connecting);
+    assert!(!status.ready);
+    assert!(status.last_error.is_some());
+    assert_eq!(status.public_url, format!("http://{}", server_addr));
+
+    let server = TestServer::start_on(TcpListener::bind(server_addr).await.unwrap(), None);
+    let tunnel_id = server.wait_for_new_tunnel(None).await;
+    let status = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let status = client.status.snapshot();
+            if status.ready {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("client never reported ready");
+    assert_eq!(status.state, ConnectionState::Connected);
+    assert_eq!(status.tunnel_id, Some(tunnel_id));
+    assert_eq!(status.connections, 1);
+
+    let response = reqwest::get(server.url("/hello")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(client.status.snapshot().requests, 1);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn client_status_is_served_on_the_control_socket() {
+    use std::sync::Arc;
+    use tunnel_client::control::{self, ControlContext};
+    use tunnel_core::logging::{self, LogOptions};
+
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let mut client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let (log, _guard) = logging::init(&LogOptions::default()).unwrap();
+    let path = std::env::temp_dir().join(format!("tunnel-status-{}.sock", std::process::id()));
+    let listener = control::bind(&path).unwrap();
+    let context = ControlContext {
+        log,
+        status: client.status.clone(),
+        captures: client.captures.clone(),
+        local: client.local.clone(),
+    };
+    tokio::spawn(control::serve(listener, Arc::new(context)));
+
+    let reply = control::query(&path, "status").await.unwrap();
+    assert_eq!(reply["ok"], true);
+    assert_eq!(reply["ready"], true);
+    assert_eq!(reply["state"], "connected");
+    assert_eq!(reply["public_url"], format!("http://{}", server.addr));
+
+    client.shut_down().await;
+    let reply = control::query(&path, "status").await.unwrap();
+    assert_eq!(reply["state"], "stopped");
+    assert_eq!(reply["ready"], false);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn server_status_reports_the_tunnel_and_requests() {
+    let local = MockLocal::start().await;
+    let state = ServerState::new(None, &TransportOptions::default());
+    let admin = TestAdmin::start(state.clone()).await;
+    let admin_addr = admin.addr.to_string();
+
+    let status = admin::query_status(&admin_addr, None).await.unwrap();
+    assert_eq!(status["ready"], false);
+    assert_eq!(status["tunnel_id"], serde_json::Value::Null);
+
+    let server = TestServer::start_with(state).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    let tunnel_id = server.wait_for_new_tunnel(None).await;
+    reqwest::get(server.url("/one")).await.unwrap();
+    reqwest::get(server.url("/two")).await.unwrap();
+
+    let status = admin::query_status(&admin_addr, None).await.unwrap();
+    assert_eq!(status["ready"], true);
+    assert_eq!(status["tunnel_id"], tunnel_id);
+    assert_eq!(status["requests"]["total"], 2);
+    assert_eq!(status["requests"]["in_flight"], 0);
+}
+
+#[tokio::test]
+async fn server_status_presents_the_api_token() {
+    let keys = ApiKeys::parse("read read-key-0123456789").unwrap();
+    let state = ServerState::new(None, &TransportOptions::default()).with_api_keys(keys);
+    let admin = TestAdmin::start(state).await;
+    let admin_addr = admin.addr.to_string();
+
+    let error = admin::query_status(&admin_addr, None).await.unwrap_err();
+    assert!(error.contains("ADMIN_API_TOKEN"), "{}", error);
+    let status = admin::query_status(&admin_addr, Some("read-key-0123456789")).await.unwrap();
+    assert_eq!(status["ready"], false);
+
+    // A wildcard bind address is reached on loopback
+    let port = admin_addr.rsplit_once(':').unwrap().1;
+    assert!(admin::query_status(&format!("0.0.0.0:{}", port), Some("read-key-0123456789")).await.is_ok());
+}
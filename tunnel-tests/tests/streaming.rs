@@ -0,0 +1,323 @@
+//! Bodies past TUNNEL_STREAM_THRESHOLD_BYTES follow their message in body frames
+//! when both ends agree to it (TUNNEL_STREAM_BODIES), and travel whole otherwise.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tunnel_core::client::{connect_and_upgrade, parse_server_addr, ServerConfig};
+use tunnel_core::framing::encode_binary_message;
+use tunnel_core::transport::TransportOptions;
+use tunnel_protocol::{
+    body_chunk_prefix, decode_request_frame, encode_body_end, is_control_frame, read_frame, write_frame, TunnelResponse, BODY_SHA256_HEADER,
+};
+use tunnel_server::{admin, ServerState};
+use tunnel_tests::{MockLocal, TestAdmin, TestClient, TestServer};
+
+const THRESHOLD: usize = 10_000;
+
+fn transport() -> TransportOptions {
+    TransportOptions { stream_threshold_bytes: THRESHOLD, ..TransportOptions::default() }
+}
+
+/// Client settings offering to stream bodies past THRESHOLD
+fn streaming_config(server: &TestServer) -> ServerConfig {
+    let mut config = parse_server_addr(&format!("http://{}", server.addr), None, Vec::new()).unwrap();
+    config.max_concurrent = 8;
+    config.binary_frames = true;
+    config.stream_bodies = true;
+    config.transport = transport();
+    config
+}
+
+/// Every byte value, so any text decoding or escaping along the way shows
+fn all_bytes(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+async fn start(checksum: bool) -> (MockLocal, TestServer, TestClient) {
+    let local = MockLocal::start().await;
+    let state = ServerState::new(None, &transport()).with_body_checksum(checksum);
+    let server = TestServer::start_with(state).await;
+    let client = TestClient::start_with_config(streaming_config(&server), local.port, &[]);
+    server.wait_for_new_tunnel(None).await;
+    (local, server, client)
+}
+
+#[tokio::test]
+async fn large_bodies_are_streamed_both_ways() {
+    let (_local, server, _client) = start(false).await;
+    assert!(server.state.registry.active().await.unwrap().stream_bodies);
+    let http = reqwest::Client::new();
+
+    // Small ones still travel whole, with their length
+    let response = http.post(server.url("/small")).body(all_bytes(500)).send().await.unwrap();
+    assert_eq!(response.content_length(), Some(500));
+    assert_eq!(response.bytes().await.unwrap(), all_bytes(500));
+
+    // Large ones, several at once, arrive chunked
+    let uploads = (0..4).map(|i| {
+        let (http, url) = (http.clone(), server.url(&format!("/large/{}", i)));
+        tokio::spawn(async move {
+            let body = all_bytes(300_000 + i);
+            let response = http.post(url).body(body.clone()).send().await.unwrap();
+            assert_eq!(response.status(), 200);
+            assert!(response.headers().get("content-length").is_none());
+            assert_eq!(response.bytes().await.unwrap(), body);
+        })
+    });
+    for upload in uploads.collect::<Vec<_>>() {
+        upload.await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn large_bodies_arrive_whatever_the_window() {
+    let local = MockLocal::start().await;
+    for window in [1, 64] {
+        let transport = TransportOptions { stream_window_chunks: window, ..transport() };
+        let server = TestServer::start_with(ServerState::new(None, &transport)).await;
+        let mut config = streaming_config(&server);
+        config.transport = transport;
+        let _client = TestClient::start_with_config(config, local.port, &[]);
+        server.wait_for_new_tunnel(None).await;
+
+        let response = reqwest::Client::new().post(server.url("/large")).body(all_bytes(1_000_000)).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.bytes().await.unwrap(), all_bytes(1_000_000));
+    }
+}
+
+#[tokio::test]
+async fn bodies_of_unknown_length_are_streamed_once_they_grow() {
+    let (_local, server, _client) = start(false).await;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("upload");
+    std::fs::write(&path, all_bytes(200_000)).unwrap();
+
+    // Sent chunked, without a Content-Length
+    let file = tokio::fs::File::open(&path).await.unwrap();
+    let response = reqwest::Client::new().post(server.url("/chunked")).body(file).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.bytes().await.unwrap(), all_bytes(200_000));
+
+    // A chunked local response is streamed past the threshold, and sent whole below it
+    let local = start_chunked_local(vec![vec![7u8; 1000]; 50]).await;
+    let server = TestServer::start_with(ServerState::new(None, &transport())).await;
+    let _client = TestClient::start_with_config(streaming_config(&server), local.port(), &[]);
+    server.wait_for_new_tunnel(None).await;
+    let response = reqwest::get(server.url("/")).await.unwrap();
+    assert!(response.headers().get("content-length").is_none());
+    assert_eq!(response.bytes().await.unwrap(), vec![7u8; 50_000]);
+
+    let local = start_chunked_local(vec![vec![7u8; 100]; 5]).await;
+    let server = TestServer::start_with(ServerState::new(None, &transport())).await;
+    let _client = TestClient::start_with_config(streaming_config(&server), local.port(), &[]);
+    server.wait_for_new_tunnel(None).await;
+    let response = reqwest::get(server.url("/")).await.unwrap();
+    assert_eq!(response.content_length(), Some(500));
+}
+
+/// Local service answering every request with `chunks` in a chunked response
+async fn start_chunked_local(chunks: Vec<Vec<u8>>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let chunks = chunks.clone();
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0u8];
+                    if stream.read_exact(&mut byte).await.is_err() {
+                        return;
+                    }
+                    head.push(byte[0]);
+                }
+                let mut response = b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\nconnection: close\r\n\r\n".to_vec();
+                for chunk in &chunks {
+                    response.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+                    response.extend_from_slice(chunk);
+                    response.extend_from_slice(b"\r\n");
+                }
+                response.extend_from_slice(b"0\r\n\r\n");
+                let _ = stream.write_all(&response).await;
+            });
+        }
+    });
+    addr
+}
+
+/// Bytes of the body `start_stalling_local` streams for `/download`
+const DOWNLOAD_BYTES: usize = 64 * 1024 * 1024;
+
+/// Local service streaming DOWNLOAD_BYTES for `/download`, never reading the body
+/// of `/upload` nor answering it, and answering anything else with `ok`
+async fn start_stalling_local() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0u8];
+                    if stream.read_exact(&mut byte).await.is_err() {
+                        return;
+                    }
+                    head.push(byte[0]);
+                }
+                if head.starts_with(b"GET /download ") {
+                    let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\nconnection: close\r\n\r\n").await;
+                    let mut chunk = format!("{:x}\r\n", BODY_CHUNK).into_bytes();
+                    chunk.extend_from_slice(&[7u8; BODY_CHUNK]);
+                    chunk.extend_from_slice(b"\r\n");
+                    for _ in 0..DOWNLOAD_BYTES / BODY_CHUNK {
+                        if stream.write_all(&chunk).await.is_err() {
+                            return;
+                        }
+                    }
+                    let _ = stream.write_all(b"0\r\n\r\n").await;
+                } else if head.starts_with(b"POST /upload ") {
+                    std::future::pending::<()>().await;
+                } else {
+                    let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok").await;
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// Bytes per chunk of the download of `start_stalling_local`
+const BODY_CHUNK: usize = 16 * 1024;
+
+/// Starts a tunnel to `start_stalling_local`, checking that both ends agreed to credit
+async fn start_stalling() -> (TestServer, TestClient) {
+    let local = start_stalling_local().await;
+    let server = TestServer::start_with(ServerState::new(None, &transport())).await;
+    let client = TestClient::start_with_config(streaming_config(&server), local.port(), &[]);
+    server.wait_for_new_tunnel(None).await;
+    assert!(server.state.registry.active().await.unwrap().stream_credit);
+    (server, client)
+}
+
+#[tokio::test]
+async fn a_visitor_not_reading_its_download_holds_up_no_one_else() {
+    let (server, _client) = start_stalling().await;
+
+    // Asks for the download and never reads it, until every queue on its way is full
+    let mut stalled = TcpStream::connect(server.addr).await.unwrap();
+    stalled.write_all(b"GET /download HTTP/1.1\r\nhost: tunnel\r\n\r\n").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let http = reqwest::Client::builder().timeout(Duration::from_secs(10)).build().unwrap();
+    let response = http.get(server.url("/other")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await.unwrap(), "ok");
+    drop(stalled);
+}
+
+#[tokio::test]
+async fn a_local_service_not_reading_an_upload_holds_up_no_one_else() {
+    let (server, _client) = start_stalling().await;
+
+    // An upload the local service never reads, sent until every queue on its way is full
+    let mut stalled = TcpStream::connect(server.addr).await.unwrap();
+    let head = format!("POST /upload HTTP/1.1\r\nhost: tunnel\r\ncontent-length: {}\r\n\r\n", DOWNLOAD_BYTES);
+    stalled.write_all(head.as_bytes()).await.unwrap();
+    let upload = tokio::spawn(async move {
+        let chunk = vec![7u8; BODY_CHUNK];
+        for _ in 0..DOWNLOAD_BYTES / BODY_CHUNK {
+            if stalled.write_all(&chunk).await.is_err() {
+                return;
+            }
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let http = reqwest::Client::builder().timeout(Duration::from_secs(10)).build().unwrap();
+    let response = http.get(server.url("/other")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await.unwrap(), "ok");
+    upload.abort();
+}
+
+#[tokio::test]
+async fn streamed_bodies_carry_their_checksum_at_the_end() {
+    let (_local, server, _client) = start(true).await;
+    let http = reqwest::Client::new();
+
+    // The checksum of a small body is a header the local service sees; that of a
+    // streamed one comes with its end, checked by the client
+    let response = http.post(server.url("/small")).body(all_bytes(500)).send().await.unwrap();
+    assert!(response.headers().contains_key(format!("x-echo-{}", &BODY_SHA256_HEADER[2..])));
+    let response = http.post(server.url("/large")).body(all_bytes(500_000)).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(!response.headers().contains_key(format!("x-echo-{}", &BODY_SHA256_HEADER[2..])));
+    assert_eq!(response.bytes().await.unwrap(), all_bytes(500_000));
+}
+
+#[tokio::test]
+async fn broken_or_damaged_streamed_responses_break_off() {
+    let state = ServerState::new(None, &transport()).with_body_checksum(true);
+    let server = TestServer::start_with(state.clone()).await;
+    let admin = TestAdmin::start(state).await;
+
+    // A fake client whose first response body comes with the wrong checksum, and whose second is given up on
+    let (stream, handshake) = connect_and_upgrade(&streaming_config(&server)).await.unwrap();
+    assert!(handshake.stream_bodies);
+    server.wait_for_new_tunnel(None).await;
+    tokio::spawn(async move {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+        for (complete, sha256) in [(true, Some("00".repeat(32))), (false, None)] {
+            // Skipping the credit granted for the bodies sent
+            let mut frame = read_frame(&mut reader).await.unwrap();
+            while is_control_frame(&frame) {
+                frame = read_frame(&mut reader).await.unwrap();
+            }
+            let id = decode_request_frame(&frame).unwrap().0.id.unwrap();
+            let response = TunnelResponse {
+                id: Some(id),
+                status: 200,
+                stream: true,
+                ..TunnelResponse::default()
+            };
+            let payload = encode_binary_message(&response, &[]).unwrap();
+            write_frame(&mut writer, &payload).await.unwrap();
+            let mut chunk = body_chunk_prefix(id).to_vec();
+            chunk.extend_from_slice(b"hello");
+            write_frame(&mut writer, &chunk).await.unwrap();
+            write_frame(&mut writer, &encode_body_end(id, complete, sha256.as_deref(), &[])).await.unwrap();
+        }
+        std::future::pending::<()>().await;
+    });
+
+    // Before or after the visitor got the head, depending on when the server wrote it
+    for _ in 0..2 {
+        let broke_off = match reqwest::get(server.url("/")).await {
+            Ok(response) => response.bytes().await.is_err(),
+            Err(e) => e.is_request(),
+        };
+        assert!(broke_off);
+    }
+
+    let status = admin::query_status(&admin.addr.to_string(), None).await.unwrap();
+    assert_eq!(status["requests"]["checksum_mismatches"], 1);
+}
+
+#[tokio::test]
+async fn clients_not_offering_it_get_whole_bodies() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start_with(ServerState::new(None, &transport())).await;
+    let mut config = streaming_config(&server);
+    config.stream_bodies = false;
+    let _client = TestClient::start_with_config(config, local.port, &[]);
+    server.wait_for_new_tunnel(None).await;
+    assert!(!server.state.registry.active().await.unwrap().stream_bodies);
+
+    let response = reqwest::Client::new().post(server.url("/large")).body(all_bytes(300_000)).send().await.unwrap();
+    assert_eq!(response.content_length(), Some(300_000));
+    assert_eq!(response.bytes().await.unwrap(), all_bytes(300_000));
+}
@@ -0,0 +1,160 @@
+//! Response and request templates: bodies changed per route at the server.
+
+use std::path::Path;
+use tunnel_core::transport::TransportOptions;
+use tunnel_protocol::HeaderValueBytes;
+use tunnel_server::templates::ResponseTemplates;
+use tunnel_server::ServerState;
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+const RULES: &str = "\
+# preview bar on every page
+/          html          banner.html
+/api/      json-error    error.json
+/hooks     json-request  envelope.json
+";
+
+/// Writes the templates RULES names into a new directory
+fn template_dir() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("banner.html"), "<script src=\"/preview.js\"></script>").unwrap();
+    std::fs::write(dir.path().join("error.json"), r#"{"error":{"status":{{status}},"detail":{{body}}}}"#).unwrap();
+    std::fs::write(dir.path().join("envelope.json"), r#"{"source":"tunnel","event":{{body}}}"#).unwrap();
+    dir
+}
+
+fn headers(content_type: &str) -> Vec<(String, HeaderValueBytes)> {
+    vec![("Content-Type".to_string(), content_type.into())]
+}
+
+#[test]
+fn rules_file_is_parsed() {
+    let dir = template_dir();
+    assert_eq!(ResponseTemplates::parse(RULES, dir.path()).unwrap().len(), 3);
+    assert!(ResponseTemplates::parse("", dir.path()).unwrap().is_empty());
+
+    for (contents, error) in [
+        ("/ banner banner.html", "line 1: unknown template kind 'banner'"),
+        ("/ html", "line 1: expected"),
+        ("/ html banner.html extra", "line 1: expected"),
+        ("\napi html banner.html", "line 2: path prefix must start with '/'"),
+        ("/ html missing.html", "line 1: failed to read"),
+    ] {
+        let e = ResponseTemplates::parse(contents, dir.path()).unwrap_err();
+        assert!(e.starts_with(error), "{:?}: {}", contents, e);
+    }
+    let e = ResponseTemplates::load(Path::new("/nonexistent/templates")).unwrap_err();
+    assert!(e.starts_with("Failed to read RESPONSE_TEMPLATES_FILE"), "{}", e);
+}
+
+#[test]
+fn templates_apply_to_matching_responses_only() {
+    let dir = template_dir();
+    let templates = ResponseTemplates::parse(RULES, dir.path()).unwrap();
+    let apply = |path, status, headers: &[(String, HeaderValueBytes)], body: &str| {
+        String::from_utf8(templates.apply(path, status, headers, body.as_bytes().to_vec())).unwrap()
+    };
+
+    let page = "<html><body><p>Hi</p></BODY></html>";
+    assert_eq!(apply("/", 200, &headers("text/html; charset=utf-8"), page), "<html><body><p>Hi</p><script src=\"/preview.js\"></script></BODY></html>");
+    assert_eq!(apply("/", 200, &headers("text/html"), "<p>Hi</p>"), "<p>Hi</p><script src=\"/preview.js\"></script>");
+    assert_eq!(apply("/", 200, &headers("text/plain"), page), page);
+
+    assert_eq!(apply("/api/orders", 422, &headers("application/json"), r#"{"field":"qty"}"#), r#"{"error":{"status":422,"detail":{"field":"qty"}}}"#);
+    assert_eq!(apply("/api/orders", 500, &headers("application/problem+json"), ""), r#"{"error":{"status":500,"detail":null}}"#);
+    // A body that is not JSON after all is quoted, so the result stays valid JSON
+    assert_eq!(apply("/api/orders", 502, &headers("application/json"), "Bad \"gateway\"\n"), r#"{"error":{"status":502,"detail":"Bad \"gateway\"\n"}}"#);
+    assert_eq!(apply("/api/orders", 200, &headers("application/json"), "{}"), "{}");
+    assert_eq!(apply("/other", 422, &headers("application/json"), "{}"), "{}");
+
+    // Compressed bodies cannot be changed without decompressing them
+    let mut gzipped = headers("text/html");
+    gzipped.push(("Content-Encoding".to_string(), "gzip".into()));
+    assert_eq!(apply("/", 200, &gzipped, page), page);
+}
+
+#[test]
+fn request_templates_apply_to_matching_json_requests_only() {
+    let dir = template_dir();
+    let templates = ResponseTemplates::parse(RULES, dir.path()).unwrap();
+    let apply = |path, headers: &[(String, HeaderValueBytes)], body: &str| {
+        String::from_utf8(templates.apply_request(path, headers, body.as_bytes().to_vec())).unwrap()
+    };
+
+    let event = r#"{"action":"push"}"#;
+    let wrapped = r#"{"source":"tunnel","event":{"action":"push"}}"#;
+    assert_eq!(apply("/hooks/github", &headers("application/json; charset=utf-8"), event), wrapped);
+    assert_eq!(apply("/hooks", &headers("application/vnd.github+json"), event), wrapped);
+    assert_eq!(apply("/hooks/github", &headers("text/plain"), event), event);
+    assert_eq!(apply("/api/orders", &headers("application/json"), event), event);
+
+    let mut gzipped = headers("application/json");
+    gzipped.push(("Content-Encoding".to_string(), "gzip".into()));
+    assert_eq!(apply("/hooks/github", &gzipped, event), event);
+
+    // Request templates leave responses alone
+    assert_eq!(templates.apply("/hooks/github", 500, &headers("application/json"), event.as_bytes().to_vec()), event.as_bytes());
+}
+
+#[test]
+fn template_prefixes_match_whole_canonical_segments() {
+    let dir = template_dir();
+    let templates = ResponseTemplates::parse(RULES, dir.path()).unwrap();
+    let apply = |path| String::from_utf8(templates.apply(path, 500, &headers("application/json"), b"{}".to_vec())).unwrap();
+
+    let templated = r#"{"error":{"status":500,"detail":{}}}"#;
+    for path in ["/api", "/api/orders", "/%61pi/orders", "//api/orders", "/other/../api/orders"] {
+        assert_eq!(apply(path), templated, "{}", path);
+    }
+    for path in ["/apix", "/apis/orders", "/other/api/orders"] {
+        assert_eq!(apply(path), "{}", "{}", path);
+    }
+}
+
+#[tokio::test]
+async fn bodies_are_changed_before_leaving_the_server() {
+    let dir = template_dir();
+    let local = MockLocal::start().await;
+    let templates = ResponseTemplates::parse(RULES, dir.path()).unwrap();
+    let state = ServerState::new(None, &TransportOptions::default()).with_templates(templates);
+    let server = TestServer::start_with(state).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+    let http = reqwest::Client::new();
+
+    let response = http.post(server.url("/page")).header("content-type", "text/html").body("<body>Hi</body>").send().await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "<body>Hi<script src=\"/preview.js\"></script></body>");
+
+    let response = http
+        .post(server.url("/api/orders"))
+        .header("content-type", "application/json")
+        .header("x-echo-status", "404")
+        .body(r#""no such order""#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+    let expected = r#"{"error":{"status":404,"detail":"no such order"}}"#;
+    assert_eq!(response.headers()["content-length"], expected.len().to_string().as_str());
+    assert_eq!(response.text().await.unwrap(), expected);
+}
+
+#[tokio::test]
+async fn request_bodies_are_changed_before_reaching_the_client() {
+    let dir = template_dir();
+    let local = MockLocal::start().await;
+    let templates = ResponseTemplates::parse(RULES, dir.path()).unwrap();
+    let state = ServerState::new(None, &TransportOptions::default()).with_body_checksum(true).with_templates(templates);
+    let server = TestServer::start_with(state).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+    let http = reqwest::Client::new();
+
+    // The local service echoes the body it received, at the length it was told
+    let response = http.post(server.url("/hooks/github")).header("content-type", "application/json").body(r#"{"action":"push"}"#).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await.unwrap(), r#"{"source":"tunnel","event":{"action":"push"}}"#);
+
+    let response = http.post(server.url("/hooks/github")).header("content-type", "text/plain").body("push").send().await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "push");
+}
@@ -0,0 +1,167 @@
+//! Per-phase request timeouts: dispatch, first byte, idle, and the overall limit.
+
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tunnel_core::client::{connect_and_upgrade, parse_server_addr};
+use tunnel_core::stream::TunnelStream;
+use tunnel_core::transport::TransportOptions;
+use tunnel_protocol::{read_frame, TunnelResponse};
+use tunnel_server::timeouts::Timeouts;
+use tunnel_server::ServerState;
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+fn timeouts(request: u64, dispatch: u64, first_byte: u64, idle: u64) -> Timeouts {
+    let settings = [request, dispatch, first_byte, idle].map(|millis| millis.to_string());
+    Timeouts::from_source(|key| Timeouts::KEYS.iter().position(|known| *known == key).map(|i| settings[i].clone())).unwrap()
+}
+
+/// Connects a fake client to `server`, returning its stream and tunnel ID
+async fn fake_client(server: &TestServer) -> (TunnelStream, u64) {
+    let config = parse_server_addr(&format!("http://{}", server.addr), None, Vec::new()).unwrap();
+    let (stream, _) = connect_and_upgrade(&config).await.unwrap();
+    (stream, server.wait_for_new_tunnel(None).await)
+}
+
+/// A response frame whose body is `len` bytes, delivered in `chunks` pieces `pause` apart
+async fn dribble_response(writer: &mut (impl AsyncWriteExt + Unpin), len: usize, chunks: usize, pause: Duration) {
+    let response = TunnelResponse {
+        status: 200,
+        body: tunnel_protocol::encode_body(&vec![b'x'; len]),
+        ..TunnelResponse::default()
+    };
+    let frame = serde_json::to_vec(&response).unwrap();
+    writer.write_all(&(frame.len() as u32).to_be_bytes()).await.unwrap();
+    for chunk in frame.chunks(frame.len().div_ceil(chunks)) {
+        writer.write_all(chunk).await.unwrap();
+        writer.flush().await.unwrap();
+        tokio::time::sleep(pause).await;
+    }
+}
+
+#[test]
+fn timeouts_are_parsed() {
+    let defaults = Timeouts::from_source(|_| None).unwrap();
+    assert_eq!(defaults.request, Some(Duration::from_secs(30)));
+    assert_eq!((defaults.dispatch, defaults.first_byte, defaults.idle), (None, None, None));
+
+    let parsed = timeouts(0, 100, 200, 300);
+    assert_eq!(parsed.request, None);
+    assert_eq!(parsed.dispatch, Some(Duration::from_millis(100)));
+    assert_eq!(parsed.first_byte, Some(Duration::from_millis(200)));
+    assert_eq!(parsed.idle, Some(Duration::from_millis(300)));
+
+    let e = Timeouts::from_source(|key| (key == "TUNNEL_IDLE_TIMEOUT_MS").then(|| "soon".to_string())).unwrap_err();
+    assert_eq!(e, "Invalid TUNNEL_IDLE_TIMEOUT_MS: soon");
+}
+
+#[tokio::test]
+async fn slow_client_fails_at_the_first_byte_timeout() {
+    let local = MockLocal::start().await;
+    let state = ServerState::new(None, &TransportOptions::default()).with_timeouts(timeouts(30_000, 0, 200, 0));
+    let server = TestServer::start_with(state).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let started = std::time::Instant::now();
+    let response = reqwest::Client::new().get(server.url("/slow")).header("x-delay-ms", "2000").send().await.unwrap();
+    assert_eq!(response.status(), 504);
+    assert_eq!(response.headers()["x-tunnel-error"], "first-byte-timeout");
+    assert!(started.elapsed() < Duration::from_secs(1), "{:?}", started.elapsed());
+
+    // The tunnel is stuck on the request, so it is dropped like after the overall timeout
+    assert_eq!(server.tunnel_id().await, None);
+}
+
+#[tokio::test]
+async fn long_download_runs_while_bytes_keep_moving() {
+    let state = ServerState::new(None, &TransportOptions::default()).with_timeouts(timeouts(0, 0, 0, 300));
+    let server = TestServer::start_with(state).await;
+    let (stream, _) = fake_client(&server).await;
+
+    // One second in all, never more than 100 ms without a byte
+    tokio::spawn(async move {
+        let (read_half, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(read_half);
+        while read_frame(&mut reader).await.is_ok() {
+            dribble_response(&mut writer, 10_000, 10, Duration::from_millis(100)).await;
+        }
+    });
+
+    let response = reqwest::get(server.url("/download")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.bytes().await.unwrap().len(), 10_000);
+}
+
+#[tokio::test]
+async fn stalled_response_fails_at_the_idle_timeout() {
+    let state = ServerState::new(None, &TransportOptions::default()).with_timeouts(timeouts(0, 0, 0, 300));
+    let server = TestServer::start_with(state).await;
+    let (stream, _) = fake_client(&server).await;
+
+    // Half the frame, then nothing
+    tokio::spawn(async move {
+        let (read_half, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(read_half);
+        read_frame(&mut reader).await.unwrap();
+        writer.write_all(&1000u32.to_be_bytes()).await.unwrap();
+        writer.write_all(&[b'x'; 500]).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(10)).await;
+    });
+
+    let response = reqwest::get(server.url("/stuck")).await.unwrap();
+    assert_eq!(response.status(), 504);
+    assert_eq!(response.headers()["x-tunnel-error"], "idle-timeout");
+}
+
+#[tokio::test]
+async fn overall_limit_still_applies_to_moving_downloads() {
+    let state = ServerState::new(None, &TransportOptions::default()).with_timeouts(timeouts(400, 0, 0, 300));
+    let server = TestServer::start_with(state).await;
+    let (stream, _) = fake_client(&server).await;
+
+    tokio::spawn(async move {
+        let (read_half, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(read_half);
+        read_frame(&mut reader).await.unwrap();
+        dribble_response(&mut writer, 10_000, 10, Duration::from_millis(100)).await;
+    });
+
+    let response = reqwest::get(server.url("/download")).await.unwrap();
+    assert_eq!(response.status(), 504);
+    assert_eq!(response.headers()["x-tunnel-error"], "request-timeout");
+}
+
+#[tokio::test]
+async fn request_stuck_behind_another_fails_at_the_dispatch_timeout() {
+    let state = ServerState::new(None, &TransportOptions::default()).with_timeouts(timeouts(30_000, 200, 0, 0));
+    let server = TestServer::start_with(state).await;
+    let (stream, tunnel_id) = fake_client(&server).await;
+
+    // Answers each request after 800 ms, reporting the ones it saw
+    let (seen_tx, mut seen_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let (read_half, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(read_half);
+        while let Ok(frame) = read_frame(&mut reader).await {
+            let request: serde_json::Value = serde_json::from_slice(&frame).unwrap();
+            seen_tx.send(request["path"].as_str().unwrap().to_string()).unwrap();
+            tokio::time::sleep(Duration::from_millis(800)).await;
+            dribble_response(&mut writer, 10, 1, Duration::ZERO).await;
+        }
+    });
+
+    let http = reqwest::Client::new();
+    let first = tokio::spawn(http.get(server.url("/first")).send());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let second = http.get(server.url("/second")).send().await.unwrap();
+    assert_eq!(second.status(), 504);
+    assert_eq!(second.headers()["x-tunnel-error"], "dispatch-timeout");
+
+    // The busy tunnel is kept, and the request given up on never reaches the client
+    assert_eq!(first.await.unwrap().unwrap().status(), 200);
+    assert_eq!(server.tunnel_id().await, Some(tunnel_id));
+    assert_eq!(seen_rx.recv().await.unwrap(), "/first");
+    let third = tokio::spawn(http.get(server.url("/third")).send());
+    assert_eq!(seen_rx.recv().await.unwrap(), "/third");
+    assert_eq!(third.await.unwrap().unwrap().status(), 200);
+}
@@ -0,0 +1,112 @@
+//! Native TLS on the public listener, with the test certificates in
+//! tests/fixtures (see `tunnel_tests::fixture`).
+
+use rustls::client::Resumption;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, HandshakeKind, ProtocolVersion};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use tunnel_core::client::{parse_server_addr, send_upgrade_request};
+use tunnel_core::tls::TlsOptions;
+use tunnel_tests::{fixture, fixture_acceptor, fixture_roots, TestServer};
+
+async fn start_server(options: &TlsOptions) -> TestServer {
+    TestServer::start_tls(fixture_acceptor(options)).await
+}
+
+fn client_config(options: &TlsOptions) -> Arc<ClientConfig> {
+    Arc::new(options.client_config(fixture_roots(&["ca.pem"])).unwrap())
+}
+
+async fn connect(server: &TestServer, config: Arc<ClientConfig>) -> std::io::Result<TlsStream<TcpStream>> {
+    let tcp = TcpStream::connect(server.addr).await?;
+    let server_name = ServerName::try_from("localhost").unwrap();
+    TlsConnector::from(config).connect(server_name, tcp).await
+}
+
+/// Sends one request and reads the response, which also processes any session tickets
+async fn get(stream: &mut TlsStream<TcpStream>) -> String {
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+#[tokio::test]
+async fn public_requests_are_served_over_tls() {
+    let server = start_server(&TlsOptions::default()).await;
+    let ca = reqwest::Certificate::from_pem(&std::fs::read(fixture("ca.pem")).unwrap()).unwrap();
+    let http = reqwest::Client::builder().add_root_certificate(ca).build().unwrap();
+
+    // No tunnel client yet, so the request reaches the router and gets 503
+    let url = format!("https://localhost:{}/", server.addr.port());
+    let response = http.get(url).send().await.unwrap();
+    assert_eq!(response.status(), 503);
+}
+
+#[tokio::test]
+async fn tunnel_upgrade_works_over_tls() {
+    let server = start_server(&TlsOptions::default()).await;
+
+    let mut stream = connect(&server, client_config(&TlsOptions::default())).await.unwrap();
+    let config = parse_server_addr("localhost", None, Vec::new()).unwrap();
+    send_upgrade_request(&mut stream, &config).await.unwrap();
+    server.wait_for_new_tunnel(None).await;
+}
+
+#[tokio::test]
+async fn minimum_version_rejects_older_clients() {
+    let tls13_only = TlsOptions::from_source(|key| (key == "TLS_MIN_VERSION").then(|| "1.3".to_string())).unwrap();
+    let server = start_server(&tls13_only).await;
+
+    // Offer only TLS 1.2 suites
+    let tls12_suites = TlsOptions::from_source(|key| {
+        (key == "TLS_CIPHER_SUITES").then(|| "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256".to_string())
+    })
+    .unwrap();
+    assert!(connect(&server, client_config(&tls12_suites)).await.is_err());
+
+    let stream = connect(&server, client_config(&TlsOptions::default())).await.unwrap();
+    assert_eq!(stream.get_ref().1.protocol_version(), Some(ProtocolVersion::TLSv1_3));
+}
+
+#[tokio::test]
+async fn alpn_is_negotiated() {
+    let server_options = TlsOptions::from_source(|key| (key == "TLS_ALPN").then(|| "http/1.1".to_string())).unwrap();
+    let server = start_server(&server_options).await;
+
+    let client_options = TlsOptions::from_source(|key| (key == "TLS_ALPN").then(|| "h2,http/1.1".to_string())).unwrap();
+    let stream = connect(&server, client_config(&client_options)).await.unwrap();
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"http/1.1"[..]));
+}
+
+#[tokio::test]
+async fn sessions_resume_only_when_enabled() {
+    for (enabled, expected) in [("true", HandshakeKind::Resumed), ("false", HandshakeKind::Full)] {
+        let options = TlsOptions::from_source(|key| {
+            (key == "TLS_SESSION_RESUMPTION").then(|| enabled.to_string())
+        })
+        .unwrap();
+        let server = start_server(&options).await;
+        let config = client_config(&TlsOptions::default());
+
+        let mut first = connect(&server, config.clone()).await.unwrap();
+        assert!(get(&mut first).await.starts_with("HTTP/1.1 503"));
+
+        let second = connect(&server, config).await.unwrap();
+        assert_eq!(second.get_ref().1.handshake_kind(), Some(expected), "resumption={}", enabled);
+    }
+}
+
+#[test]
+fn client_can_disable_resumption() {
+    let options = TlsOptions::from_source(|key| (key == "TLS_SESSION_RESUMPTION").then(|| "false".to_string())).unwrap();
+    let config = client_config(&options);
+    assert_eq!(format!("{:?}", config.resumption), format!("{:?}", Resumption::disabled()));
+}
@@ -0,0 +1,83 @@
+//! Usage per tunnel credential, listed by the admin API and saved across restarts.
+
+use serde_json::Value;
+use std::sync::Arc;
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::usage::UsageStore;
+use tunnel_server::ServerState;
+use tunnel_tests::{MockLocal, TestAdmin, TestClient, TestServer};
+
+#[tokio::test]
+async fn usage_is_counted_per_credential() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(Some("alice:secret")).await;
+    let mut client = TestClient::start(server.addr, local.port, Some("alice:secret"));
+    server.wait_for_new_tunnel(None).await;
+
+    let response = reqwest::Client::new().post(server.url("/upload")).body(vec![1u8; 1000]).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    client.shut_down().await;
+    let _client = TestClient::start(server.addr, local.port, Some("alice:secret"));
+    server.wait_for_new_tunnel(None).await;
+
+    let admin = TestAdmin::start(server.state.clone()).await;
+    let tokens: Value = reqwest::get(admin.url("/api/tokens")).await.unwrap().json().await.unwrap();
+    let tokens = tokens["tokens"].as_array().unwrap();
+    assert_eq!(tokens.len(), 1);
+    assert_eq!(tokens[0]["name"], "alice");
+    assert_eq!(tokens[0]["tunnels_opened"], 2);
+    assert_eq!(tokens[0]["source_ips"], serde_json::json!(["127.0.0.1"]));
+    assert_eq!(tokens[0]["request_bytes"], 1000);
+    assert!(tokens[0]["response_bytes"].as_u64().unwrap() > 1000);
+    assert!(tokens[0]["last_connected_at"].as_u64().is_some());
+
+    let tunnels: Value = reqwest::get(admin.url("/api/tunnels")).await.unwrap().json().await.unwrap();
+    assert_eq!(tunnels["tunnels"][0]["token"], "alice");
+}
+
+#[tokio::test]
+async fn unused_credentials_can_be_listed() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let admin = TestAdmin::start(server.state.clone()).await;
+    let tokens: Value = reqwest::get(admin.url("/api/tokens?unused_days=0")).await.unwrap().json().await.unwrap();
+    assert_eq!(tokens["tokens"][0]["name"], "anonymous");
+    let tokens: Value = reqwest::get(admin.url("/api/tokens?unused_days=1")).await.unwrap().json().await.unwrap();
+    assert_eq!(tokens["tokens"].as_array().unwrap().len(), 0);
+
+    let response = reqwest::get(admin.url("/api/tokens?unused_days=soon")).await.unwrap();
+    assert_eq!(response.status(), 400);
+}
+
+#[tokio::test]
+async fn usage_is_saved_and_resumed() {
+    let path = std::env::temp_dir().join(format!("tunnel-token-usage-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    // A missing file starts from nothing, and nothing is written until counts change
+    let store = Arc::new(UsageStore::load(&path).unwrap());
+    assert!(store.list().is_empty());
+    store.save().unwrap();
+    assert!(!path.exists());
+
+    let local = MockLocal::start().await;
+    let state = ServerState::new(Some("bob:secret".to_string()), &TransportOptions::default()).with_usage(store.clone());
+    let server = TestServer::start_with(state).await;
+    let _client = TestClient::start(server.addr, local.port, Some("bob:secret"));
+    server.wait_for_new_tunnel(None).await;
+    assert_eq!(reqwest::get(server.url("/")).await.unwrap().status(), 200);
+
+    // A restarted server resumes from what the running one saved
+    store.save().unwrap();
+    let resumed = UsageStore::load(&path).unwrap().list();
+    assert_eq!(resumed, store.list());
+    assert_eq!(resumed["bob"].tunnels_opened, 1);
+
+    std::fs::write(&path, "not json").unwrap();
+    let e = UsageStore::load(&path).err().unwrap();
+    assert!(e.starts_with(&format!("Invalid TOKEN_USAGE_FILE {}: ", path.display())), "{}", e);
+    std::fs::remove_file(path).unwrap();
+}
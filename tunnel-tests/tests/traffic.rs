@@ -0,0 +1,72 @@
+//! Request rates, error rates and latency percentiles per tunnel (`GET /api/traffic`).
+
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::routing::get;
+use serde_json::Value;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::traffic::TrafficStats;
+use tunnel_server::ServerState;
+use tunnel_tests::{TestAdmin, TestClient, TestServer};
+
+/// Local service answering `/status/<code>` with that status
+async fn start_local() -> u16 {
+    let app = axum::Router::new().route("/status/:code", get(|Path(code): Path<u16>| async move {
+        StatusCode::from_u16(code).unwrap()
+    }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    port
+}
+
+#[test]
+fn percentiles_come_from_the_latency_histogram() {
+    let traffic = TrafficStats::default();
+    assert_eq!(traffic.global().last_minute.latency_p50_ms, None);
+
+    for millis in 1..=100 {
+        traffic.record(1, if millis % 10 == 0 { 502 } else { 200 }, Duration::from_millis(millis));
+    }
+    let window = traffic.global().last_minute;
+    assert_eq!((window.requests, window.errors, window.error_rate), (100, 10, 0.1));
+    // Each percentile is the upper bound of its bin, at most about 20% above the exact value
+    let p50 = window.latency_p50_ms.unwrap();
+    assert!((50..=60).contains(&p50), "{}", p50);
+    let p95 = window.latency_p95_ms.unwrap();
+    assert!((95..=114).contains(&p95), "{}", p95);
+    let p99 = window.latency_p99_ms.unwrap();
+    assert!((99..=119).contains(&p99), "{}", p99);
+    assert_eq!(traffic.global(), traffic.tunnels()[0].windows);
+}
+
+#[tokio::test]
+async fn forwarded_requests_are_counted_per_tunnel() {
+    let port = start_local().await;
+    let state = ServerState::new(None, &TransportOptions::default());
+    let server = TestServer::start_with(state.clone()).await;
+    let admin = TestAdmin::start(state).await;
+    let _client = TestClient::start(server.addr, port, None);
+    let tunnel_id = server.wait_for_new_tunnel(None).await;
+
+    for path in ["/status/200", "/status/200", "/status/404", "/status/500"] {
+        reqwest::get(server.url(path)).await.unwrap();
+    }
+
+    let traffic: Value = reqwest::get(admin.url("/api/traffic")).await.unwrap().json().await.unwrap();
+    let global = &traffic["global"]["1m"];
+    assert_eq!(global["requests"], 4);
+    assert_eq!(global["errors"], 1);
+    assert_eq!(global["error_rate"], 0.25);
+    assert!(global["requests_per_sec"].as_f64().unwrap() > 0.0);
+    assert!(global["latency_p99_ms"].is_u64());
+    assert_eq!(traffic["global"]["15m"], traffic["global"]["1m"]);
+
+    let tunnels = traffic["tunnels"].as_array().unwrap();
+    assert_eq!(tunnels.len(), 1);
+    assert_eq!(tunnels[0]["id"], tunnel_id);
+    assert_eq!(tunnels[0]["connected"], true);
+    assert_eq!(tunnels[0]["5m"]["requests"], 4);
+}
@@ -0,0 +1,233 @@
+//! HTTP trailers and versions cross the tunnel in the request and response messages,
+//! and with the end of a streamed body.
+
+use axum::body::Body;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use http_body_util::BodyExt;
+use hyper::body::HttpBody;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tunnel_core::client::parse_server_addr;
+use tunnel_core::transport::TransportOptions;
+use tunnel_protocol::{encode_body, HeaderValueBytes, TunnelRequest, TunnelResponse};
+use tunnel_server::streaming::TrailedBody;
+use tunnel_server::ServerState;
+use tunnel_tests::{start_fake_client, MockLocal, TestClient, TestServer};
+
+/// Bodies past this are streamed, where both ends agree to it
+const STREAM_THRESHOLD: usize = 10_000;
+
+fn response_with_trailers() -> TunnelResponse {
+    TunnelResponse {
+        status: 200,
+        headers: vec![("content-type".to_string(), "application/grpc-web".into()), ("content-length".to_string(), "5".into())],
+        version: Some("HTTP/2.0".to_string()),
+        trailers: vec![("grpc-status".to_string(), "0".into()), ("grpc-message".to_string(), "ok".into())],
+        body: encode_body(b"hello"),
+        ..TunnelResponse::default()
+    }
+}
+
+/// Sends `request` as is and reads the whole response, lowercased
+async fn raw_exchange(server: &TestServer, request: &[u8]) -> String {
+    let mut stream = TcpStream::connect(server.addr).await.unwrap();
+    stream.write_all(request).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    String::from_utf8_lossy(&response).to_lowercase()
+}
+
+/// A chunked upload of "hello" with an `x-checksum` trailer, asking for trailers in the response
+const UPLOAD: &[u8] = b"POST /upload HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\nTrailer: x-checksum\r\nTE: trailers\r\nConnection: close\r\n\r\n\
+    5\r\nhello\r\n0\r\nx-checksum: abc123\r\n\r\n";
+
+/// Asserts that `response` carries "hello" followed by the trailers the echo services send for `UPLOAD`
+fn assert_echoed_upload(response: &str) {
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("http/1.1 200"), "{}", head);
+    assert!(head.contains("transfer-encoding: chunked"), "{}", head);
+    assert!(body.starts_with("5\r\nhello\r\n0\r\n"), "{}", body);
+    assert!(body.contains("x-echo-x-checksum: abc123\r\n"), "{}", body);
+    assert!(body.contains("grpc-status: 0\r\n"), "{}", body);
+}
+
+/// Starts an HTTP/1.1 local service answering with the request body, followed
+/// by the request's trailers as `x-echo-<name>` and `grpc-status: 0`
+async fn start_http1_echo() -> u16 {
+    async fn echo(request: axum::extract::Request) -> axum::response::Response {
+        let collected = request.into_body().collect().await.unwrap();
+        let mut trailers = HeaderMap::new();
+        for (name, value) in collected.trailers().into_iter().flatten() {
+            trailers.append(HeaderName::try_from(format!("x-echo-{}", name)).unwrap(), value.clone());
+        }
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        let names: Vec<&str> = trailers.keys().map(HeaderName::as_str).collect();
+        axum::response::Response::builder()
+            .header("trailer", names.join(", "))
+            .body(Body::new(TrailedBody::new(collected.to_bytes(), trailers)))
+            .unwrap()
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        axum::serve(listener, axum::Router::new().fallback(echo)).await.unwrap();
+    });
+    port
+}
+
+/// Starts an h2c local service answering like `start_http1_echo`'s
+async fn start_http2_echo() -> u16 {
+    async fn echo(request: hyper::Request<hyper::Body>) -> Result<hyper::Response<hyper::Body>, hyper::Error> {
+        let mut body = request.into_body();
+        let data = hyper::body::to_bytes(&mut body).await?;
+        let mut trailers = hyper::HeaderMap::new();
+        for (name, value) in &body.trailers().await?.unwrap_or_default() {
+            trailers.append(hyper::header::HeaderName::try_from(format!("x-echo-{}", name)).unwrap(), value.clone());
+        }
+        trailers.insert("grpc-status", hyper::header::HeaderValue::from_static("0"));
+        let (mut sender, response) = hyper::Body::channel();
+        tokio::spawn(async move {
+            let _ = sender.send_data(data).await;
+            let _ = sender.send_trailers(trailers).await;
+        });
+        Ok(hyper::Response::new(response))
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let connection = hyper::server::conn::Http::new().http2_only(true).serve_connection(stream, hyper::service::service_fn(echo));
+            tokio::spawn(connection);
+        }
+    });
+    port
+}
+
+#[tokio::test]
+async fn request_trailers_and_version_reach_the_client() {
+    let server = TestServer::start(None).await;
+    let mut requests = start_fake_client(&server, response_with_trailers()).await;
+
+    raw_exchange(
+        &server,
+        b"POST /upload HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\nTrailer: x-checksum, x-latin1\r\nConnection: close\r\n\r\n\
+          5\r\nhello\r\n0\r\nx-checksum: abc123\r\nx-latin1: \xe9t\xe9\r\n\r\n",
+    )
+    .await;
+
+    let request = requests.recv().await.unwrap();
+    assert_eq!(request.version.as_deref(), Some("HTTP/1.1"));
+    assert_eq!(
+        request.trailers,
+        vec![("x-checksum".to_string(), "abc123".into()), ("x-latin1".to_string(), HeaderValueBytes::from(b"\xe9t\xe9".to_vec()))]
+    );
+}
+
+#[tokio::test]
+async fn response_trailers_follow_a_chunked_body() {
+    let server = TestServer::start(None).await;
+    let _requests = start_fake_client(&server, response_with_trailers()).await;
+
+    let response = raw_exchange(&server, b"GET /rpc HTTP/1.1\r\nHost: x\r\nTE: trailers\r\nConnection: close\r\n\r\n").await;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.contains("transfer-encoding: chunked"), "{}", head);
+    assert!(!head.contains("content-length"), "{}", head);
+    assert!(head.contains("trailer: grpc-status, grpc-message"), "{}", head);
+    assert_eq!(body, "5\r\nhello\r\n0\r\ngrpc-status: 0\r\ngrpc-message: ok\r\n\r\n");
+
+    // Visitors that did not ask for trailers still get the body
+    let response = raw_exchange(&server, b"GET /rpc HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await;
+    assert!(response.ends_with("5\r\nhello\r\n0\r\n\r\n"), "{}", response);
+}
+
+#[tokio::test]
+async fn fields_not_allowed_as_trailers_are_dropped() {
+    let server = TestServer::start(None).await;
+    let mut response = response_with_trailers();
+    response.trailers = vec![
+        ("content-length".to_string(), "99".into()),
+        ("x-latin1".to_string(), HeaderValueBytes::from(b"\xe9t\xe9".to_vec())),
+        ("Authorization".to_string(), "Bearer x".into()),
+        ("transfer-encoding".to_string(), "gzip".into()),
+    ];
+    let _requests = start_fake_client(&server, response).await;
+
+    let response = raw_exchange(&server, b"GET /rpc HTTP/1.1\r\nHost: x\r\nTE: trailers\r\nConnection: close\r\n\r\n").await;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.contains("trailer: x-latin1\r\n"), "{}", head);
+    assert_eq!(body, "5\r\nhello\r\n0\r\nx-latin1: \u{fffd}t\u{fffd}\r\n\r\n");
+}
+
+#[tokio::test]
+async fn trailers_cross_an_http1_local_service() {
+    let local_port = start_http1_echo().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local_port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    assert_echoed_upload(&raw_exchange(&server, UPLOAD).await);
+}
+
+#[tokio::test]
+async fn trailers_cross_an_http2_local_service() {
+    let local_port = start_http2_echo().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start_with(server.addr, local_port, None, &[("LOCAL_HTTP_VERSION", "http2")]);
+    server.wait_for_new_tunnel(None).await;
+
+    assert_echoed_upload(&raw_exchange(&server, UPLOAD).await);
+}
+
+#[tokio::test]
+async fn trailers_cross_with_streamed_bodies() {
+    let local_port = start_http1_echo().await;
+    let transport = TransportOptions { stream_threshold_bytes: STREAM_THRESHOLD, ..TransportOptions::default() };
+    let server = TestServer::start_with(ServerState::new(None, &transport)).await;
+    let mut config = parse_server_addr(&format!("http://{}", server.addr), None, Vec::new()).unwrap();
+    config.max_concurrent = 8;
+    config.binary_frames = true;
+    config.stream_bodies = true;
+    config.transport = transport;
+    let _client = TestClient::start_with_config(config, local_port, &[]);
+    server.wait_for_new_tunnel(None).await;
+    assert!(server.state.registry.active().await.unwrap().stream_trailers);
+
+    // Three chunks past the threshold, so the echoed body is streamed back too
+    let chunk = "z".repeat(STREAM_THRESHOLD);
+    let mut upload = b"POST /upload HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\nTrailer: x-checksum\r\nTE: trailers\r\nConnection: close\r\n\r\n".to_vec();
+    for _ in 0..3 {
+        upload.extend_from_slice(format!("{:x}\r\n{}\r\n", chunk.len(), chunk).as_bytes());
+    }
+    upload.extend_from_slice(b"0\r\nx-checksum: abc123\r\n\r\n");
+
+    let response = raw_exchange(&server, &upload).await;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("http/1.1 200"), "{}", head);
+    assert!(head.contains("transfer-encoding: chunked"), "{}", head);
+    assert_eq!(body.matches('z').count(), 3 * STREAM_THRESHOLD);
+    let (_, trailers) = body.rsplit_once("\r\n0\r\n").unwrap();
+    assert!(trailers.contains("x-echo-x-checksum: abc123\r\n"), "{}", trailers);
+    assert!(trailers.contains("grpc-status: 0\r\n"), "{}", trailers);
+}
+
+#[tokio::test]
+async fn client_reports_the_local_services_version() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let request = TunnelRequest {
+        method: "GET".to_string(),
+        path: "/".to_string(),
+        version: Some("HTTP/1.1".to_string()),
+        ..TunnelRequest::default()
+    };
+    let tunnel = server.state.registry.active().await.unwrap();
+    let reply = tunnel.round_trip(serde_json::to_vec(&request).unwrap().into()).await.unwrap();
+    let (response, _) = tunnel_protocol::decode_response_frame(&reply).unwrap();
+    assert_eq!(response.version.as_deref(), Some("HTTP/1.1"));
+}
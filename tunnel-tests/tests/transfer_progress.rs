@@ -0,0 +1,100 @@
+//! Requests in flight are listed by the admin API, and by the client for its
+//! `in-flight` control command, with the bytes they have moved.
+
+use serde_json::Value;
+use std::time::Duration;
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::ServerState;
+use tunnel_tests::{MockLocal, TestAdmin, TestClient, TestServer};
+
+#[tokio::test]
+async fn in_flight_requests_show_their_transfer() {
+    let local = MockLocal::start().await;
+    let state = ServerState::new(None, &TransportOptions::default());
+    let server = TestServer::start_with(state.clone()).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let admin = TestAdmin::start(state).await;
+    let admin_url = admin.url("/api/requests");
+
+    let request = tokio::spawn(
+        reqwest::Client::new()
+            .post(server.url("/upload"))
+            .header("x-delay-ms", "1000")
+            .body(vec![7u8; 50_000])
+            .send(),
+    );
+
+    // The body is in; the local service is still sleeping on it
+    let listed = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let list: Value = reqwest::get(&admin_url).await.unwrap().json().await.unwrap();
+            if let Some(request) = list["requests"].as_array().and_then(|requests| requests.first()) {
+                if request["phase"] == "client_processing" {
+                    return request.clone();
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("request was never listed");
+    assert_eq!(listed["method"], "POST");
+    assert_eq!(listed["path"], "/upload");
+    assert_eq!(listed["transfer"]["request_bytes"], 50_000);
+    assert_eq!(listed["transfer"]["request_total"], 50_000);
+    assert_eq!(listed["transfer"]["response_bytes"], 0);
+    assert_eq!(listed["transfer"]["response_total"], Value::Null);
+
+    assert_eq!(request.await.unwrap().unwrap().status(), 200);
+    let list: Value = reqwest::get(&admin_url).await.unwrap().json().await.unwrap();
+    assert_eq!(list["requests"].as_array().unwrap().len(), 0);
+}
+
+#[tokio::test]
+async fn client_lists_requests_the_local_service_is_working_on() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let request = tokio::spawn(
+        reqwest::Client::new()
+            .post(server.url("/upload?token=secret"))
+            .header("x-delay-ms", "1000")
+            .body(vec![7u8; 50_000])
+            .send(),
+    );
+
+    let listed = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(request) = client.status.in_flight().pop() {
+                if request.request_bytes == 50_000 {
+                    return request;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("request was never listed");
+    assert_eq!(listed.method, "POST");
+    assert_eq!(listed.path, "/upload");
+    assert_eq!(listed.request_total, Some(50_000));
+    assert_eq!(listed.response_bytes, 0);
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(client.status.in_flight()[0].elapsed_ms > listed.elapsed_ms);
+
+    let response = request.await.unwrap().unwrap();
+    assert_eq!(response.bytes().await.unwrap().len(), 50_000);
+    // Unlisted once answered, just after the response went out
+    tokio::time::timeout(Duration::from_secs(1), async {
+        while !client.status.in_flight().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("request stayed listed");
+}
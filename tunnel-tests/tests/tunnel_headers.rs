@@ -0,0 +1,51 @@
+//! Headers that tell the local service which tunnel connection a request came through.
+
+use std::net::SocketAddr;
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+#[tokio::test]
+async fn tunnel_headers_describe_the_connection() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start_with(server.addr, local.port, None, &[("LOCAL_TUNNEL_HEADERS", "true")]);
+    let tunnel_id = server.wait_for_new_tunnel(None).await;
+
+    // A visitor cannot pass off its own values as the tunnel's
+    let response = reqwest::Client::new()
+        .get(server.url("/"))
+        .header("x-tunnel-id", "999")
+        .header("x-tunnel-latency-ms", "0")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let headers = response.headers();
+
+    let ids: Vec<_> = headers.get_all("x-echo-tunnel-id").iter().collect();
+    assert_eq!(ids, [tunnel_id.to_string().as_str()]);
+
+    let client_addr: SocketAddr = headers["x-echo-tunnel-client-addr"].to_str().unwrap().parse().unwrap();
+    assert!(client_addr.ip().is_loopback());
+
+    let latencies: Vec<u64> = headers
+        .get_all("x-echo-tunnel-latency-ms")
+        .iter()
+        .map(|value| value.to_str().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(latencies.len(), 1);
+    assert!(latencies[0] < 5_000);
+}
+
+#[tokio::test]
+async fn tunnel_headers_are_off_by_default() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let response = reqwest::get(server.url("/")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().get("x-echo-tunnel-id").is_none());
+    assert!(response.headers().get("x-echo-tunnel-client-addr").is_none());
+    assert!(response.headers().get("x-echo-tunnel-latency-ms").is_none());
+}
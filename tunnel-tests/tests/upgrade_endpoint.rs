@@ -0,0 +1,51 @@
+//! A moved upgrade endpoint with a preshared secret looks like any other page to visitors.
+
+use tunnel_core::client::{connect_and_upgrade, parse_server_addr, ConnectError, UpgradeError};
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::ServerState;
+use tunnel_tests::TestServer;
+
+const PATH: &str = "/t/4f9a1c";
+const SECRET: &str = "Zx8-q!v3kL0pW2";
+
+async fn start_server() -> TestServer {
+    let state = ServerState::new(None, &TransportOptions::default())
+        .with_tunnel_path(PATH.to_string())
+        .with_upgrade_secret(Some(SECRET.to_string()));
+    TestServer::start_with(state).await
+}
+
+/// Sends an upgrade request to `path` with `secret`, returning the status code it got instead of 101
+async fn try_upgrade(server: &TestServer, path: &str, secret: Option<&str>) -> Result<(), Option<u16>> {
+    let mut config = parse_server_addr(&format!("http://{}", server.addr), None, Vec::new()).unwrap();
+    config.path = path.to_string();
+    config.upgrade_secret = secret.map(str::to_string);
+    match connect_and_upgrade(&config).await {
+        Ok(_) => Ok(()),
+        Err(ConnectError::Upgrade(UpgradeError::Rejected { status, .. })) => Err(status),
+        Err(e) => panic!("unexpected error: {}", e),
+    }
+}
+
+#[tokio::test]
+async fn upgrade_needs_the_configured_path_and_secret() {
+    let server = start_server().await;
+
+    // Without a tunnel every public request gets the same 503, and so do these
+    let page = reqwest::get(server.url("/anything")).await.unwrap();
+    assert_eq!(page.status(), 503);
+    assert_eq!(try_upgrade(&server, "/tunnel", Some(SECRET)).await, Err(Some(503)));
+    assert_eq!(try_upgrade(&server, PATH, None).await, Err(Some(503)));
+    assert_eq!(try_upgrade(&server, PATH, Some("Zx8-q!v3kL0pW3")).await, Err(Some(503)));
+    assert_eq!(server.tunnel_id().await, None);
+
+    try_upgrade(&server, PATH, Some(SECRET)).await.unwrap();
+    server.wait_for_new_tunnel(None).await;
+}
+
+#[tokio::test]
+async fn default_endpoint_needs_no_secret() {
+    let server = TestServer::start(None).await;
+    try_upgrade(&server, "/tunnel", None).await.unwrap();
+    server.wait_for_new_tunnel(None).await;
+}
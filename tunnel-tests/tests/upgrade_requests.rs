@@ -0,0 +1,69 @@
+//! Upgrade requests with a body or oversized headers are refused; bytes pipelined after one reach the tunnel.
+
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tunnel_protocol::{write_frame, ControlFrame, StatsReport};
+use tunnel_tests::TestServer;
+
+const UPGRADE: &str = "GET /tunnel HTTP/1.1\r\nHost: x\r\nUpgrade: tunnel\r\nConnection: Upgrade\r\n";
+
+/// Sends `bytes` on a new connection, returning it with the response's status line read
+async fn send(server: &TestServer, bytes: &[u8]) -> (BufReader<TcpStream>, String) {
+    let mut stream = BufReader::new(TcpStream::connect(server.addr).await.unwrap());
+    stream.write_all(bytes).await.unwrap();
+    let mut status = String::new();
+    stream.read_line(&mut status).await.unwrap();
+    (stream, status)
+}
+
+#[tokio::test]
+async fn upgrade_with_a_body_is_rejected() {
+    let server = TestServer::start(None).await;
+
+    let (_, status) = send(&server, format!("{UPGRADE}Content-Length: 5\r\n\r\nhello").as_bytes()).await;
+    assert!(status.starts_with("HTTP/1.1 400"), "{}", status);
+    let (_, status) = send(&server, format!("{UPGRADE}Transfer-Encoding: chunked\r\n\r\n0\r\n\r\n").as_bytes()).await;
+    assert!(status.starts_with("HTTP/1.1 400"), "{}", status);
+    assert_eq!(server.tunnel_id().await, None);
+
+    // An empty body is no body
+    let (_, status) = send(&server, format!("{UPGRADE}Content-Length: 0\r\n\r\n").as_bytes()).await;
+    assert!(status.starts_with("HTTP/1.1 101"), "{}", status);
+    server.wait_for_new_tunnel(None).await;
+}
+
+#[tokio::test]
+async fn upgrade_with_oversized_headers_is_rejected() {
+    let server = TestServer::start(None).await;
+
+    let padding = "a".repeat(70 * 1024);
+    let (_, status) = send(&server, format!("{UPGRADE}X-Padding: {padding}\r\n\r\n").as_bytes()).await;
+    assert!(status.starts_with("HTTP/1.1 431"), "{}", status);
+    assert_eq!(server.tunnel_id().await, None);
+}
+
+#[tokio::test]
+async fn bytes_pipelined_after_the_upgrade_reach_the_tunnel() {
+    let server = TestServer::start(None).await;
+
+    // A stats report, sent before the 101 arrives
+    let report = StatsReport { requests: 7, ..StatsReport::default() };
+    let mut bytes = format!("{UPGRADE}\r\n").into_bytes();
+    write_frame(&mut bytes, &ControlFrame::Stats(report).encode()).await.unwrap();
+    let (_stream, status) = send(&server, &bytes).await;
+    assert!(status.starts_with("HTTP/1.1 101"), "{}", status);
+    server.wait_for_new_tunnel(None).await;
+
+    let reported = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            match server.state.registry.active().await.and_then(|conn| conn.peer_stats()) {
+                Some(stats) => return stats.report.requests,
+                None => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        }
+    })
+    .await
+    .expect("stats report was lost");
+    assert_eq!(reported, 7);
+}
@@ -0,0 +1,32 @@
+//! The client's User-Agent (TUNNEL_USER_AGENT) is recorded by the server.
+
+use serde_json::Value;
+use tunnel_core::client::parse_server_addr;
+use tunnel_tests::{MockLocal, TestAdmin, TestClient, TestServer};
+
+#[tokio::test]
+async fn user_agent_is_shown_in_the_admin_api() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(Some("alice:secret")).await;
+    let admin = TestAdmin::start(server.state.clone()).await;
+
+    let mut config = parse_server_addr(&format!("http://{}", server.addr), Some("alice:secret".to_string()), Vec::new()).unwrap();
+    config.user_agent = Some("speedforce-client/9.9.9 (linux; riscv64)".to_string());
+    let _client = TestClient::start_with_config(config, local.port, &[]);
+    let tunnel_id = server.wait_for_new_tunnel(None).await;
+
+    let tunnels: Value = reqwest::get(admin.url("/api/tunnels")).await.unwrap().json().await.unwrap();
+    assert_eq!(tunnels["tunnels"][0]["user_agent"], "speedforce-client/9.9.9 (linux; riscv64)");
+    let history: Value = reqwest::get(admin.url("/api/tokens/alice/history")).await.unwrap().json().await.unwrap();
+    assert_eq!(history["connections"][0]["tunnel_id"], tunnel_id);
+    assert_eq!(history["connections"][0]["user_agent"], "speedforce-client/9.9.9 (linux; riscv64)");
+}
+
+#[tokio::test]
+async fn clients_without_a_user_agent_have_none() {
+    let local = MockLocal::start().await;
+    let server = TestServer::start(None).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+    assert_eq!(server.state.registry.active().await.unwrap().user_agent, None);
+}
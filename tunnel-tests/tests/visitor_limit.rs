@@ -0,0 +1,130 @@
+//! Requests in flight capped per visitor address (VISITOR_MAX_IN_FLIGHT).
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+use tunnel_core::client::parse_server_addr;
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::visitors::VisitorLimit;
+use tunnel_server::ServerState;
+use tunnel_tests::{MockLocal, TestClient, TestServer};
+
+fn http_from(addr: Ipv4Addr) -> reqwest::Client {
+    reqwest::Client::builder().local_address(IpAddr::V4(addr)).build().unwrap()
+}
+
+#[test]
+fn slots_are_counted_per_address_until_dropped() {
+    let limit = Arc::new(VisitorLimit::new(Some(2)));
+    let (alice, bob) = (IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)));
+
+    let first = limit.acquire(alice).unwrap();
+    let _second = limit.acquire(alice).unwrap();
+    assert!(limit.acquire(alice).is_none());
+    assert!(limit.acquire(bob).is_some());
+    assert_eq!(limit.in_flight(alice), 2);
+
+    drop(first);
+    assert_eq!(limit.in_flight(alice), 1);
+    assert!(limit.acquire(alice).is_some());
+
+    // Without a cap nothing is counted
+    let unlimited = Arc::new(VisitorLimit::new(None));
+    let _slots: Vec<_> = (0..100).map(|_| unlimited.acquire(alice).unwrap()).collect();
+    assert_eq!(unlimited.in_flight(alice), 0);
+}
+
+#[tokio::test]
+async fn busy_visitors_are_refused_while_others_get_through() {
+    let local = MockLocal::start().await;
+    let state = ServerState::new(None, &TransportOptions::default()).with_visitor_max_in_flight(Some(2));
+    let server = TestServer::start_with(state).await;
+    let _client = TestClient::start(server.addr, local.port, None);
+    server.wait_for_new_tunnel(None).await;
+
+    let poller = http_from(Ipv4Addr::new(127, 0, 0, 1));
+    let slow = |http: &reqwest::Client| http.get(server.url("/poll")).header("x-delay-ms", "1000").send();
+    let held = [tokio::spawn(slow(&poller)), tokio::spawn(slow(&poller))];
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let response = poller.get(server.url("/poll")).send().await.unwrap();
+    assert_eq!(response.status(), 429);
+    assert_eq!(response.headers()["x-tunnel-error"], "visitor-concurrency");
+    assert_eq!(response.headers()["retry-after"], "1");
+    assert!(response.headers().get("x-echo-path").is_none());
+
+    // Another address still has its own allowance
+    let response = http_from(Ipv4Addr::new(127, 0, 0, 2)).get(server.url("/other")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+
+    for request in held {
+        assert_eq!(request.await.unwrap().unwrap().status(), 200);
+    }
+    let response = poller.get(server.url("/poll")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+}
+
+/// Local service streaming a chunked body for `/download`, which ends once
+/// `finish` is notified, and answering anything else with `ok`
+async fn start_download_local(finish: Arc<Notify>) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let finish = finish.clone();
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0u8];
+                    if stream.read_exact(&mut byte).await.is_err() {
+                        return;
+                    }
+                    head.push(byte[0]);
+                }
+                if !head.starts_with(b"GET /download ") {
+                    let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok").await;
+                    return;
+                }
+                let chunk = |len: usize| [format!("{:x}\r\n", len).into_bytes(), vec![7u8; len], b"\r\n".to_vec()].concat();
+                let mut start = b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\nconnection: close\r\n\r\n".to_vec();
+                start.extend_from_slice(&chunk(20_000));
+                let _ = stream.write_all(&start).await;
+                finish.notified().await;
+                let _ = stream.write_all(&[chunk(10_000), b"0\r\n\r\n".to_vec()].concat()).await;
+            });
+        }
+    });
+    port
+}
+
+#[tokio::test]
+async fn streamed_responses_hold_their_slot_until_they_end() {
+    let finish = Arc::new(Notify::new());
+    let local_port = start_download_local(finish.clone()).await;
+    let transport = TransportOptions { stream_threshold_bytes: 10_000, ..TransportOptions::default() };
+    let state = ServerState::new(None, &transport).with_visitor_max_in_flight(Some(1));
+    let server = TestServer::start_with(state).await;
+    let mut config = parse_server_addr(&format!("http://{}", server.addr), None, Vec::new()).unwrap();
+    config.max_concurrent = 8;
+    config.binary_frames = true;
+    config.stream_bodies = true;
+    config.transport = transport;
+    let _client = TestClient::start_with_config(config, local_port, &[]);
+    server.wait_for_new_tunnel(None).await;
+
+    // Its head is here while its body is still on the way
+    let http = reqwest::Client::new();
+    let download = http.get(server.url("/download")).send().await.unwrap();
+    assert_eq!(download.status(), 200);
+    assert!(download.headers().get("content-length").is_none());
+    let response = http.get(server.url("/other")).send().await.unwrap();
+    assert_eq!(response.status(), 429);
+
+    finish.notify_one();
+    assert_eq!(download.bytes().await.unwrap().len(), 30_000);
+    let response = http.get(server.url("/other")).send().await.unwrap();
+    assert_eq!(response.status(), 200);
+}
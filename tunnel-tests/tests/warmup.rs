@@ -0,0 +1,74 @@
+//! New tunnels are probed before visitors are routed to them (TUNNEL_WARMUP_PATH).
+
+use axum::http::{HeaderMap, StatusCode, Uri};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tunnel_core::transport::TransportOptions;
+use tunnel_server::warmup::Warmup;
+use tunnel_server::ServerState;
+use tunnel_tests::{TestClient, TestServer};
+
+/// Local service whose `/healthz` fails until it has been asked `ready_after` times; returns its port,
+/// the probe count and the last probe's User-Agent
+async fn start_slow_starter(ready_after: usize) -> (u16, Arc<AtomicUsize>, Arc<Mutex<String>>) {
+    let probes = Arc::new(AtomicUsize::new(0));
+    let user_agent = Arc::new(Mutex::new(String::new()));
+    let app = axum::Router::new().fallback({
+        let (probes, user_agent) = (probes.clone(), user_agent.clone());
+        move |uri: Uri, headers: HeaderMap| async move {
+            if uri.path() != "/healthz" {
+                return (StatusCode::OK, uri.path().to_string());
+            }
+            *user_agent.lock().unwrap() = headers["user-agent"].to_str().unwrap().to_string();
+            if probes.fetch_add(1, Ordering::SeqCst) + 1 < ready_after {
+                return (StatusCode::SERVICE_UNAVAILABLE, "starting".to_string());
+            }
+            (StatusCode::OK, "ok".to_string())
+        }
+    });
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (port, probes, user_agent)
+}
+
+#[test]
+fn settings_are_validated() {
+    let settings = |pairs: &'static [(&'static str, &'static str)]| {
+        Warmup::from_source(|key| pairs.iter().find(|(name, _)| *name == key).map(|(_, value)| value.to_string()))
+    };
+    assert!(settings(&[]).unwrap().is_none());
+    let warmup = settings(&[("TUNNEL_WARMUP_PATH", "/healthz")]).unwrap().unwrap();
+    assert_eq!((warmup.path.as_str(), warmup.interval), ("/healthz", Duration::from_secs(1)));
+    assert!(settings(&[("TUNNEL_WARMUP_PATH", "healthz")]).unwrap_err().contains("TUNNEL_WARMUP_PATH"));
+    assert!(settings(&[("TUNNEL_WARMUP_PATH", "/"), ("TUNNEL_WARMUP_INTERVAL_MS", "0")]).unwrap_err().contains("TUNNEL_WARMUP_INTERVAL_MS"));
+}
+
+#[tokio::test]
+async fn tunnel_is_routed_once_the_probe_succeeds() {
+    let (port, probes, user_agent) = start_slow_starter(3).await;
+    let warmup = Warmup { path: "/healthz".to_string(), interval: Duration::from_millis(200) };
+    let state = ServerState::new(None, &TransportOptions::default()).with_warmup(Some(warmup));
+    let server = TestServer::start_with(state).await;
+    let _client = TestClient::start(server.addr, port, None);
+
+    // Connected, but the local service is still starting: visitors are not routed to it yet
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while probes.load(Ordering::SeqCst) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(server.tunnel_id().await, None);
+    assert_eq!(reqwest::get(server.url("/page")).await.unwrap().status(), 503);
+
+    server.wait_for_new_tunnel(None).await;
+    assert_eq!(probes.load(Ordering::SeqCst), 3);
+    assert_eq!(*user_agent.lock().unwrap(), "tunnel-server-warmup");
+    let response = reqwest::get(server.url("/page")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await.unwrap(), "/page");
+}